@@ -21,7 +21,7 @@ use smoltcp::{
 };
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
-use crate::{tcp::TcpStream, Packet, Tun, TypeConverter};
+use crate::{icmp::EchoRequest, tcp::TcpStream, Packet, Tun, TypeConverter};
 
 pub struct Traffic {
     rx_bytes: usize,
@@ -64,6 +64,12 @@ pub struct TunDevice<'a, T: Tun> {
     /// (source address, sender)
     udp_req_senders: HashMap<IpEndpoint, Sender<(IpEndpoint, BytesMut)>>,
 
+    /// echo requests go here instead of smoltcp once `relay_icmp` is called
+    icmp_req_sender: Option<Sender<EchoRequest>>,
+    /// raw IP packets answering the echo requests
+    icmp_receiver: Receiver<Vec<u8>>,
+    icmp_sender: Sender<Vec<u8>>,
+
     interface: Option<Interface>,
     channel_buffer_size: usize,
     tcp_tx_buffer_size: usize,
@@ -98,6 +104,7 @@ impl<'a, T: Tun + Clone> TunDevice<'a, T> {
         let mtu = tun.mtu();
         let (tcp_sender, tcp_receiver) = channel(channel_buffer);
         let (udp_sender, udp_receiver) = channel(channel_buffer);
+        let (icmp_sender, icmp_receiver) = channel(channel_buffer);
         let mut device = Self {
             tun,
             traffic: Traffic::new(),
@@ -117,6 +124,9 @@ impl<'a, T: Tun + Clone> TunDevice<'a, T> {
             udp_receiver,
             udp_sender,
             udp_req_senders: Default::default(),
+            icmp_req_sender: None,
+            icmp_receiver,
+            icmp_sender,
             interface: None,
             channel_buffer_size: channel_buffer,
             tcp_tx_buffer_size: mtu * channel_buffer,
@@ -160,15 +170,54 @@ impl<'a, T: Tun + Clone> TunDevice<'a, T> {
         self.udp_tx_buffer_size = tx.max(self.mtu * self.channel_buffer_size);
     }
 
+    /// Bytes of the socket buffers allocated for each tcp connection.
+    pub fn tcp_buffer_size(&self) -> usize {
+        self.tcp_rx_buffer_size + self.tcp_tx_buffer_size
+    }
+
+    /// Bytes of the socket buffers allocated for each udp destination.
+    pub fn udp_buffer_size(&self) -> usize {
+        self.udp_rx_buffer_size + self.udp_tx_buffer_size
+    }
+
     fn allowed(&self, endpoint: impl Into<IpEndpoint>) -> bool {
         let endpoint = endpoint.into();
-        if endpoint.port == 0 || self.black_ip_list.contains(&endpoint.addr) {
+        endpoint.port != 0 && self.allowed_addr(endpoint.addr)
+    }
+
+    fn allowed_addr(&self, addr: IpAddress) -> bool {
+        if self.black_ip_list.contains(&addr) {
             false
-        } else if self.white_ip_list.contains(&endpoint.addr) {
+        } else if self.white_ip_list.contains(&addr) {
             true
         } else {
-            self.allow_private || !is_private_v4(endpoint.addr)
+            self.allow_private || !is_private_v4(addr)
+        }
+    }
+
+    /// Echo requests are taken from the device from now on, instead of answered by smoltcp.
+    /// Returns them and the sender of the raw IP packets answering them.
+    pub fn relay_icmp(&mut self) -> (Receiver<EchoRequest>, Sender<Vec<u8>>) {
+        let (sender, receiver) = channel(self.channel_buffer_size);
+        self.icmp_req_sender.replace(sender);
+        (receiver, self.icmp_sender.clone())
+    }
+
+    /// Hands the packet to the icmp relay if it's an echo request it takes.
+    fn take_echo_request(&self, packet: &T::Packet) -> bool {
+        let Some(sender) = &self.icmp_req_sender else {
+            return false;
+        };
+        let Some(request) = EchoRequest::parse(packet.as_ref()) else {
+            return false;
+        };
+        if !self.allowed_addr(IpAddress::Ipv4(request.dst.into())) {
+            return false;
+        }
+        if sender.try_send(request).is_err() {
+            log::warn!("icmp relay is busy, echo request dropped");
         }
+        true
     }
 
     fn ensure_tcp_socket(&mut self, dst_endpoint: IpEndpoint, src_endpoint: IpEndpoint) {
@@ -432,6 +481,17 @@ impl<'a, T: Tun + Clone> TunDevice<'a, T> {
         }
         self.tcp_response = response;
 
+        while let Ok(data) = self.icmp_receiver.try_recv() {
+            self.traffic.tx_bytes += data.len();
+            let sent = self.tun.allocate_packet(data.len()).and_then(|mut packet| {
+                packet.as_mut().copy_from_slice(data.as_slice());
+                self.tun.send(packet)
+            });
+            if let Err(err) = sent {
+                log::error!("send icmp packet failed:{}", err);
+            }
+        }
+
         let mut response: HashMap<IpEndpoint, Vec<(IpEndpoint, BytesMut)>> = HashMap::new();
         while let Ok((source, target, data)) = self.udp_receiver.try_recv() {
             response.entry(target).or_default().push((source, data));
@@ -594,20 +654,30 @@ impl<'a, T: Tun> smoltcp::phy::TxToken for TxToken<'a, T> {
 }
 
 impl<'b, T: Tun + Clone> Device for TunDevice<'b, T> {
-    type RxToken<'a> = RxToken<T> where Self: 'a;
-    type TxToken<'a> = TxToken<'a, T> where Self: 'a;
+    type RxToken<'a>
+        = RxToken<T>
+    where
+        Self: 'a;
+    type TxToken<'a>
+        = TxToken<'a, T>
+    where
+        Self: 'a;
 
     fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
-        self.tun.receive().unwrap().map(|packet| {
+        loop {
+            let packet = self.tun.receive().unwrap()?;
             self.traffic.rx_bytes += packet.len();
+            if self.take_echo_request(&packet) {
+                continue;
+            }
             self.preprocess_packet(&packet);
             let rx = RxToken { packet };
             let tx = TxToken {
                 tun: self.tun.clone(),
                 traffic: &mut self.traffic,
             };
-            (rx, tx)
-        })
+            return Some((rx, tx));
+        }
     }
 
     fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
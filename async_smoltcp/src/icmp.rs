@@ -0,0 +1,98 @@
+use std::net::Ipv4Addr;
+
+use smoltcp::{
+    phy::ChecksumCapabilities,
+    wire::{Icmpv4Message, Icmpv4Packet, IpProtocol, IpVersion, Ipv4Packet, Ipv4Repr},
+};
+
+/// Bytes of the original datagram quoted by ICMP errors, its header and 8 bytes of the payload.
+const QUOTED_PAYLOAD: usize = 8;
+/// TTL of the replies written to the tun device.
+const REPLY_TTL: u8 = 64;
+/// Length of the header of the replies, which have no options.
+const IPV4_HEADER_LEN: usize = 20;
+
+/// An ICMPv4 echo request read from the tun device, like the probes of ping and tracert.
+#[derive(Debug, Clone)]
+pub struct EchoRequest {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub ttl: u8,
+    pub ident: u16,
+    pub seq: u16,
+    pub data: Vec<u8>,
+    /// header and first payload bytes of the request, quoted by the errors sent back
+    quote: Vec<u8>,
+}
+
+impl EchoRequest {
+    /// Returns the echo request in the IP packet, if it's one.
+    pub fn parse(packet: &[u8]) -> Option<EchoRequest> {
+        if IpVersion::of_packet(packet).ok()? != IpVersion::Ipv4 {
+            return None;
+        }
+        let ip = Ipv4Packet::new_checked(packet).ok()?;
+        if ip.next_header() != IpProtocol::Icmp || ip.frag_offset() != 0 || ip.more_frags() {
+            return None;
+        }
+        let icmp = Icmpv4Packet::new_checked(ip.payload()).ok()?;
+        if icmp.msg_type() != Icmpv4Message::EchoRequest {
+            return None;
+        }
+        let quote_len = ip.header_len() as usize + QUOTED_PAYLOAD;
+        Some(EchoRequest {
+            src: ip.src_addr().into(),
+            dst: ip.dst_addr().into(),
+            ttl: ip.hop_limit(),
+            ident: icmp.echo_ident(),
+            seq: icmp.echo_seq_no(),
+            data: icmp.data().to_vec(),
+            quote: packet[..quote_len].to_vec(),
+        })
+    }
+
+    /// The echo reply of the target to the request.
+    pub fn reply(&self) -> Vec<u8> {
+        let mut packet = self.packet(self.dst, 8 + self.data.len());
+        let mut icmp = Icmpv4Packet::new_unchecked(&mut packet[IPV4_HEADER_LEN..]);
+        icmp.set_msg_type(Icmpv4Message::EchoReply);
+        icmp.set_msg_code(0);
+        icmp.set_echo_ident(self.ident);
+        icmp.set_echo_seq_no(self.seq);
+        icmp.data_mut().copy_from_slice(self.data.as_slice());
+        icmp.fill_checksum();
+        packet
+    }
+
+    /// An error of `kind` from `from` about the request, like time exceeded from a router on
+    /// the path.
+    pub fn error(&self, from: Ipv4Addr, kind: u8, code: u8) -> Vec<u8> {
+        let mut packet = self.packet(from, 8 + self.quote.len());
+        let mut icmp = Icmpv4Packet::new_unchecked(&mut packet[IPV4_HEADER_LEN..]);
+        icmp.set_msg_type(kind.into());
+        icmp.set_msg_code(code);
+        let buffer = icmp.into_inner();
+        // unused, or the next hop mtu of fragmentation needed, which is not relayed
+        buffer[4..8].fill(0);
+        buffer[8..].copy_from_slice(self.quote.as_slice());
+        Icmpv4Packet::new_unchecked(buffer).fill_checksum();
+        packet
+    }
+
+    /// An IP packet from `src` to the sender of the request, with room for the ICMP message.
+    fn packet(&self, src: Ipv4Addr, icmp_len: usize) -> Vec<u8> {
+        let repr = Ipv4Repr {
+            src_addr: src.into(),
+            dst_addr: self.src.into(),
+            next_header: IpProtocol::Icmp,
+            payload_len: icmp_len,
+            hop_limit: REPLY_TTL,
+        };
+        let mut packet = vec![0u8; IPV4_HEADER_LEN + icmp_len];
+        repr.emit(
+            &mut Ipv4Packet::new_unchecked(&mut packet),
+            &ChecksumCapabilities::default(),
+        );
+        packet
+    }
+}
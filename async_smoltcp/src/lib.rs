@@ -7,10 +7,12 @@ use std::{
 use smoltcp::wire::{IpAddress, IpEndpoint, IpListenEndpoint};
 
 pub use device::TunDevice;
+pub use icmp::EchoRequest;
 pub use tcp::{TcpReadHalf, TcpStream, TcpWriteHalf};
 pub use udp::{UdpSocket, UdpWriteHalf};
 
 mod device;
+mod icmp;
 mod tcp;
 mod udp;
 
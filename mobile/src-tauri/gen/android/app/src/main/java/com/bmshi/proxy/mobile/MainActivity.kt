@@ -3,12 +3,28 @@ package com.bmshi.proxy.mobile
 import android.Manifest
 import android.content.Intent
 import android.content.pm.PackageManager
+import android.net.ConnectivityManager
+import android.net.Network
+import android.net.NetworkCapabilities
 import android.net.VpnService
+import android.net.wifi.WifiInfo
+import android.net.wifi.WifiManager
+import android.os.Build
 import android.os.Bundle
+import android.provider.Settings
+import android.security.keystore.KeyGenParameterSpec
+import android.security.keystore.KeyProperties
+import android.util.Base64
 import androidx.activity.result.contract.ActivityResultContracts
+import androidx.annotation.RequiresApi
 import androidx.core.app.NotificationCompat
 import androidx.core.app.NotificationManagerCompat
 import androidx.core.content.ContextCompat
+import java.security.KeyStore
+import javax.crypto.Cipher
+import javax.crypto.KeyGenerator
+import javax.crypto.SecretKey
+import javax.crypto.spec.GCMParameterSpec
 
 class MainActivity : TauriActivity() {
   val requestPermissionLauncher =
@@ -25,24 +41,92 @@ class MainActivity : TauriActivity() {
 
   private external fun onPermissionResult(isGranted: Boolean)
 
+  // empty ssid for other networks, or without the location permission
+  private external fun onWifiChanged(ssid: String)
+
+  private inner class WifiCallback : ConnectivityManager.NetworkCallback {
+    constructor() : super()
+
+    @RequiresApi(Build.VERSION_CODES.S)
+    constructor(flags: Int) : super(flags)
+
+    override fun onCapabilitiesChanged(network: Network, capabilities: NetworkCapabilities) {
+      onWifiChanged(ssidOf(capabilities))
+    }
+
+    override fun onLost(network: Network) {
+      onWifiChanged("")
+    }
+  }
+
+  private var wifiCallback: WifiCallback? = null
+
+  private fun ssidOf(capabilities: NetworkCapabilities): String {
+    if (!capabilities.hasTransport(NetworkCapabilities.TRANSPORT_WIFI)) {
+      return ""
+    }
+    val info = if (Build.VERSION.SDK_INT >= Build.VERSION_CODES.Q) {
+      capabilities.transportInfo as? WifiInfo
+    } else {
+      @Suppress("DEPRECATION")
+      (applicationContext.getSystemService(WIFI_SERVICE) as WifiManager).connectionInfo
+    }
+    val ssid = info?.ssid ?: return ""
+    // quoted unless it is not valid utf-8
+    return if (ssid == WifiManager.UNKNOWN_SSID) "" else ssid.removeSurrounding("\"")
+  }
+
   companion object {
     var mtu: Int = 1500
+    var dnsServers: List<String> = listOf("10.10.11.1")
     private lateinit var instance: MainActivity
     lateinit var notifyBuilder: NotificationCompat.Builder
 
     private external fun initRust()
 
     @JvmStatic
-    fun startVpn(mtu: Int) {
+    fun startVpn(mtu: Int, dnsServers: String) {
       try {
         Logger.info("start vpn in MainActivity")
         MainActivity.mtu = mtu
+        MainActivity.dnsServers = dnsServers.split(",")
         instance.startService()
       } catch (e: Exception) {
         Logger.warn(e.toString())
       }
     }
 
+    // the default network of the app, which the vpn leaves out, is the underlying one
+    @JvmStatic
+    fun watchWifi() {
+      try {
+        if (instance.wifiCallback != null) {
+          return
+        }
+        val callback = if (Build.VERSION.SDK_INT >= Build.VERSION_CODES.S) {
+          instance.WifiCallback(ConnectivityManager.NetworkCallback.FLAG_INCLUDE_LOCATION_INFO)
+        } else {
+          instance.WifiCallback()
+        }
+        val manager = instance.getSystemService(CONNECTIVITY_SERVICE) as ConnectivityManager
+        manager.registerDefaultNetworkCallback(callback)
+        instance.wifiCallback = callback
+      } catch (e: Exception) {
+        Logger.warn(e.toString())
+      }
+    }
+
+    @JvmStatic
+    fun openVpnSettings() {
+      try {
+        val intent = Intent(Settings.ACTION_VPN_SETTINGS)
+        intent.flags = Intent.FLAG_ACTIVITY_NEW_TASK
+        instance.startActivity(intent)
+      } catch (e: Exception) {
+        Logger.warn(e.toString())
+      }
+    }
+
     @JvmStatic
     fun shouldShowRequestPermissionRationaleNative(permission: String): Boolean {
       return try {
@@ -116,6 +200,67 @@ class MainActivity : TauriActivity() {
       }
     }
 
+    private const val KEYSTORE = "AndroidKeyStore"
+    private const val SECRET_KEY_ALIAS = "trojan_secret"
+    private const val SECRET_TRANSFORMATION = "AES/GCM/NoPadding"
+    private const val SECRET_PREFS = "secrets"
+
+    private fun secretKey(): SecretKey {
+      val keyStore = KeyStore.getInstance(KEYSTORE)
+      keyStore.load(null)
+      val entry = keyStore.getEntry(SECRET_KEY_ALIAS, null) as? KeyStore.SecretKeyEntry
+      if (entry != null) {
+        return entry.secretKey
+      }
+      val generator = KeyGenerator.getInstance(KeyProperties.KEY_ALGORITHM_AES, KEYSTORE)
+      generator.init(
+        KeyGenParameterSpec.Builder(
+          SECRET_KEY_ALIAS,
+          KeyProperties.PURPOSE_ENCRYPT or KeyProperties.PURPOSE_DECRYPT
+        )
+          .setBlockModes(KeyProperties.BLOCK_MODE_GCM)
+          .setEncryptionPaddings(KeyProperties.ENCRYPTION_PADDING_NONE)
+          .build()
+      )
+      return generator.generateKey()
+    }
+
+    @JvmStatic
+    fun saveSecret(name: String, data: String): Boolean {
+      return try {
+        val cipher = Cipher.getInstance(SECRET_TRANSFORMATION)
+        cipher.init(Cipher.ENCRYPT_MODE, secretKey())
+        val encrypted = cipher.doFinal(data.toByteArray(Charsets.UTF_8))
+        val value = Base64.encodeToString(cipher.iv, Base64.NO_WRAP) + ":" +
+          Base64.encodeToString(encrypted, Base64.NO_WRAP)
+        val prefs = instance.getSharedPreferences(SECRET_PREFS, MODE_PRIVATE)
+        prefs.edit().putString(name, value).commit()
+      } catch (e: Exception) {
+        Logger.warn(e.toString())
+        false
+      }
+    }
+
+    @JvmStatic
+    fun loadSecret(name: String): String {
+      return try {
+        val prefs = instance.getSharedPreferences(SECRET_PREFS, MODE_PRIVATE)
+        val value = prefs.getString(name, "").toString()
+        val parts = value.split(":")
+        if (parts.size != 2) {
+          return ""
+        }
+        val iv = Base64.decode(parts[0], Base64.NO_WRAP)
+        val encrypted = Base64.decode(parts[1], Base64.NO_WRAP)
+        val cipher = Cipher.getInstance(SECRET_TRANSFORMATION)
+        cipher.init(Cipher.DECRYPT_MODE, secretKey(), GCMParameterSpec(128, iv))
+        String(cipher.doFinal(encrypted), Charsets.UTF_8)
+      } catch (e: Exception) {
+        Logger.warn(e.toString())
+        ""
+      }
+    }
+
     @JvmStatic
     fun loadData(name: String): String {
       try {
@@ -146,6 +291,14 @@ class MainActivity : TauriActivity() {
     initRust()
   }
 
+  override fun onDestroy() {
+    wifiCallback?.let {
+      (getSystemService(CONNECTIVITY_SERVICE) as ConnectivityManager).unregisterNetworkCallback(it)
+    }
+    wifiCallback = null
+    super.onDestroy()
+  }
+
   private fun doActivityResult(resultCode: Int) {
     if (resultCode == RESULT_OK) {
       Logger.info("activity result is ok")
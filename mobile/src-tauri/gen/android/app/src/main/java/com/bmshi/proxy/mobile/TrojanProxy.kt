@@ -18,7 +18,7 @@ import androidx.core.app.NotificationManagerCompat
 
 
 class TrojanProxy : VpnService() {
-  private external fun onStart(fd: Int, dns: String)
+  private external fun onStart(fd: Int, dns: String, lockdown: Boolean)
   private external fun onStop()
 
   private external fun onNetworkChanged(available: Boolean)
@@ -99,15 +99,12 @@ class TrojanProxy : VpnService() {
             val parts = route.split("/")
             builder.addRoute(parts[0], parts[1].toInt())
           }
-          builder.addRoute("10.10.11.1", 32)
-            //.addRoute("8.8.8.8", 32)
-            //.addRoute("212.103.62.58", 32)
-            .addAddress("10.10.10.1", 30)
-            .addDnsServer("10.10.11.1")
-            .addDnsServer("8.8.8.8")
-            .addDnsServer("8.8.4.4")
-            .addDnsServer("1.1.1.1")
-            .addDnsServer("1.0.0.1")
+          // queries to these servers are answered by the split dns in rust
+          for (dns in MainActivity.dnsServers) {
+            builder.addRoute(dns, if (dns.contains(":")) 128 else 32)
+              .addDnsServer(dns)
+          }
+          builder.addAddress("10.10.10.1", 30)
             .addDisallowedApplication(packageName)
             .setSession("gfw")
             .setMtu(MainActivity.mtu)
@@ -116,7 +113,9 @@ class TrojanProxy : VpnService() {
           if (vpn != null) {
             startNetworkMonitor()
             vpnFd = vpn
-            onStart(vpn.fd, "10.10.11.1")
+            // "block connections without VPN" of the always-on vpn
+            val lockdown = Build.VERSION.SDK_INT >= Build.VERSION_CODES.Q && isLockdownEnabled
+            onStart(vpn.fd, MainActivity.dnsServers.joinToString(","), lockdown)
             startForeground(NOTIFICATION_ID, notifyBuilder.build())
           } else {
             Logger.error("establish vpn failed")
@@ -0,0 +1,72 @@
+import Network
+import NetworkExtension
+
+// Extension side of the tunnel, the same addresses as TrojanProxy.kt on Android.
+class PacketTunnelProvider: NEPacketTunnelProvider {
+  private let monitor = NWPathMonitor()
+
+  override func startTunnel(
+    options: [String: NSObject]?, completionHandler: @escaping (Error?) -> Void
+  ) {
+    trojan_init_rust(
+      sharedCallbacks(
+        start: nil, stop: nil,
+        send: { name, data in
+          queueEvent(String(cString: name!), String(cString: data!))
+        }))
+    guard let configuration = protocolConfiguration as? NETunnelProviderProtocol,
+      let options = configuration.providerConfiguration?["options"] as? String,
+      let json = try? JSONSerialization.jsonObject(with: Data(options.utf8)) as? [String: Any]
+    else {
+      completionHandler(NEVPNError(.configurationInvalid))
+      return
+    }
+    let settings = NEPacketTunnelNetworkSettings(
+      tunnelRemoteAddress: configuration.serverAddress ?? "127.0.0.1")
+    // validated by the app, queries to these servers are answered by the split dns in rust
+    let dnsServers = json["dns_servers"] as? String ?? "10.10.11.1"
+    let ipv4 = NEIPv4Settings(addresses: ["10.10.10.1"], subnetMasks: ["255.255.255.252"])
+    ipv4.includedRoutes = [NEIPv4Route.default()]
+    ipv4.excludedRoutes = [
+      NEIPv4Route(destinationAddress: "172.16.0.0", subnetMask: "255.240.0.0"),
+      NEIPv4Route(destinationAddress: "192.168.0.0", subnetMask: "255.255.0.0"),
+    ]
+    settings.ipv4Settings = ipv4
+    let dns = NEDNSSettings(servers: dnsServers.components(separatedBy: ","))
+    dns.matchDomains = [""]
+    settings.dnsSettings = dns
+    settings.mtu = json["mtu"] as? NSNumber ?? 1500
+    setTunnelNetworkSettings(settings) { error in
+      if let error = error {
+        completionHandler(error)
+        return
+      }
+      // the packet flow is left unread, rust reads the utun socket behind it
+      trojan_on_start(dnsServers, options)
+      self.monitor.pathUpdateHandler = { path in
+        trojan_on_network_changed(path.status == .satisfied)
+      }
+      self.monitor.start(queue: DispatchQueue(label: "network"))
+      completionHandler(nil)
+    }
+  }
+
+  override func stopTunnel(
+    with reason: NEProviderStopReason, completionHandler: @escaping () -> Void
+  ) {
+    monitor.cancel()
+    trojan_on_stop()
+    completionHandler()
+  }
+}
+
+// Queues an event for the app and wakes it up, it drains the queue in relayEvents. A suspended
+// app misses all but the last events.
+private func queueEvent(_ name: String, _ data: String) {
+  var events = sharedDefaults.array(forKey: eventKey) as? [[String]] ?? []
+  events.append([name, data])
+  sharedDefaults.set(Array(events.suffix(16)), forKey: eventKey)
+  CFNotificationCenterPostNotification(
+    CFNotificationCenterGetDarwinNotifyCenter(), CFNotificationName(eventNotification as CFString),
+    nil, nil, true)
+}
@@ -0,0 +1,61 @@
+import Foundation
+import Security
+
+// Compiled into both the app and the PacketTunnel extension, which share the app group and the
+// first keychain access group of their entitlements.
+let appGroup = "group.com.bmshi.proxy.mobile"
+let providerBundleIdentifier = "com.bmshi.proxy.mobile.PacketTunnel"
+let eventNotification = "com.bmshi.proxy.mobile.event"
+let eventKey = "events"
+
+let sharedDefaults = UserDefaults(suiteName: appGroup)!
+
+private func secretQuery(_ key: String) -> [String: Any] {
+  [
+    kSecClass as String: kSecClassGenericPassword,
+    kSecAttrService as String: appGroup,
+    kSecAttrAccount as String: key,
+  ]
+}
+
+func sharedCallbacks(
+  start: (@convention(c) (UnsafePointer<CChar>?) -> Void)?,
+  stop: (@convention(c) () -> Void)?,
+  send: (@convention(c) (UnsafePointer<CChar>?, UnsafePointer<CChar>?) -> Void)?
+) -> AppleCallbacks {
+  AppleCallbacks(
+    start_vpn: start,
+    stop_vpn: stop,
+    save_data: { key, value in
+      sharedDefaults.set(String(cString: value!), forKey: String(cString: key!))
+    },
+    load_data: { key in
+      guard let value = sharedDefaults.string(forKey: String(cString: key!)) else {
+        return nil
+      }
+      return strdup(value)
+    },
+    save_secret: { key, value in
+      let query = secretQuery(String(cString: key!))
+      SecItemDelete(query as CFDictionary)
+      var item = query
+      item[kSecValueData as String] = Data(String(cString: value!).utf8)
+      // the extension starts on demand while the device is locked
+      item[kSecAttrAccessible as String] = kSecAttrAccessibleAfterFirstUnlock
+      return SecItemAdd(item as CFDictionary, nil) == errSecSuccess
+    },
+    load_secret: { key in
+      var query = secretQuery(String(cString: key!))
+      query[kSecReturnData as String] = true
+      query[kSecMatchLimit as String] = kSecMatchLimitOne
+      var result: AnyObject?
+      guard SecItemCopyMatching(query as CFDictionary, &result) == errSecSuccess,
+        let data = result as? Data
+      else {
+        return nil
+      }
+      return strdup(String(decoding: data, as: UTF8.self))
+    },
+    send_event: send
+  )
+}
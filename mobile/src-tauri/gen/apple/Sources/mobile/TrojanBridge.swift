@@ -0,0 +1,118 @@
+import Foundation
+import NetworkExtension
+
+// App side of the tunnel, main.mm calls [TrojanBridge register] before starting the app.
+@objc public class TrojanBridge: NSObject {
+  @objc public static func register() {
+    trojan_init_rust(
+      sharedCallbacks(
+        start: { options in startTunnel(String(cString: options!)) },
+        stop: { stopTunnel() },
+        send: nil
+      ))
+    CFNotificationCenterAddObserver(
+      CFNotificationCenterGetDarwinNotifyCenter(), nil,
+      { _, _, _, _, _ in
+        DispatchQueue.main.async { relayEvents() }
+      },
+      eventNotification as CFString, nil, .deliverImmediately)
+  }
+}
+
+private func loadManager(_ done: @escaping (NETunnelProviderManager) -> Void) {
+  NETunnelProviderManager.loadAllFromPreferences { managers, error in
+    if let error = error {
+      NSLog("load vpn configurations failed:%@", error.localizedDescription)
+    }
+    done(managers?.first ?? NETunnelProviderManager())
+  }
+}
+
+private func startTunnel(_ options: String) {
+  let json = try? JSONSerialization.jsonObject(with: Data(options.utf8)) as? [String: Any]
+  loadManager { manager in
+    let configuration = NETunnelProviderProtocol()
+    configuration.providerBundleIdentifier = providerBundleIdentifier
+    configuration.serverAddress = json?["hostname"] as? String ?? ""
+    configuration.providerConfiguration = ["options": options]
+    // the kill switch, nothing leaves outside the tunnel, even while it is down
+    if #available(iOS 14.2, macOS 10.15, *) {
+      configuration.includeAllNetworks = json?["kill_switch"] as? Bool ?? false
+      configuration.excludeLocalNetworks = true
+    }
+    manager.protocolConfiguration = configuration
+    manager.localizedDescription = "trojan"
+    manager.isEnabled = true
+    manager.onDemandRules = onDemandRules(json)
+    manager.isOnDemandEnabled = !(manager.onDemandRules ?? []).isEmpty
+    // the first save asks the user to allow the VPN configuration
+    manager.saveToPreferences { error in
+      trojan_on_permission_result(error == nil)
+      if let error = error {
+        NSLog("save vpn configuration failed:%@", error.localizedDescription)
+        return
+      }
+      manager.loadFromPreferences { _ in
+        do {
+          try manager.connection.startVPNTunnel()
+        } catch {
+          NSLog("start vpn tunnel failed:%@", error.localizedDescription)
+        }
+      }
+    }
+  }
+}
+
+private func stopTunnel() {
+  loadManager { manager in
+    // stopped by hand, or on demand would start it again at once
+    guard manager.isOnDemandEnabled else {
+      manager.connection.stopVPNTunnel()
+      return
+    }
+    manager.isOnDemandEnabled = false
+    manager.saveToPreferences { _ in
+      manager.connection.stopVPNTunnel()
+    }
+  }
+}
+
+// Disconnects on the trusted Wi-Fi networks and connects on the untrusted ones, all of them for
+// "*", the lists were cleaned up by rust. Other networks are left alone.
+private func onDemandRules(_ json: [String: Any]?) -> [NEOnDemandRule] {
+  let ssids = { (key: String) -> [String] in
+    (json?[key] as? String ?? "").split(separator: ",").map(String.init)
+  }
+  var rules: [NEOnDemandRule] = []
+  let trusted = ssids("trusted_ssids")
+  if !trusted.isEmpty {
+    let rule = NEOnDemandRuleDisconnect()
+    rule.interfaceTypeMatch = .wiFi
+    rule.ssidMatch = trusted
+    rules.append(rule)
+  }
+  let untrusted = ssids("untrusted_ssids")
+  if !untrusted.isEmpty {
+    let rule = NEOnDemandRuleConnect()
+    rule.interfaceTypeMatch = .wiFi
+    if !untrusted.contains("*") {
+      rule.ssidMatch = untrusted
+    }
+    rules.append(rule)
+  }
+  if !rules.isEmpty {
+    let rule = NEOnDemandRuleIgnore()
+    rule.interfaceTypeMatch = .any
+    rules.append(rule)
+  }
+  return rules
+}
+
+// Emits the events queued by the extension to the window.
+private func relayEvents() {
+  let events = sharedDefaults.array(forKey: eventKey) as? [[String]] ?? []
+  sharedDefaults.removeObject(forKey: eventKey)
+  for event in events where event.count == 2 {
+    trojan_on_event(event[0], event[1])
+  }
+}
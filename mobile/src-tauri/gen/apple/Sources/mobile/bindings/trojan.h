@@ -0,0 +1,29 @@
+#pragma once
+
+#include <stdbool.h>
+
+// Swift functions handed to the Rust backend, see src/platform/apple.rs.
+typedef struct {
+    // app only, null in the extension
+    void (*start_vpn)(const char *options);
+    void (*stop_vpn)(void);
+    void (*save_data)(const char *key, const char *value);
+    // strdup'ed value, null if missing
+    char *(*load_data)(const char *key);
+    bool (*save_secret)(const char *key, const char *value);
+    // strdup'ed value, null if missing
+    char *(*load_secret)(const char *key);
+    // extension only, null in the app
+    void (*send_event)(const char *name, const char *data);
+} AppleCallbacks;
+
+void trojan_init_rust(AppleCallbacks callbacks);
+
+// app
+void trojan_on_event(const char *name, const char *data);
+void trojan_on_permission_result(bool granted);
+
+// extension
+void trojan_on_start(const char *dns, const char *options);
+void trojan_on_stop(void);
+void trojan_on_network_changed(bool available);
@@ -134,8 +134,11 @@ pub async fn async_run(
     let server_name: ServerName = context.options.hostname.as_str().try_into()?;
 
     let server_addr = SocketAddr::new(server_ip[0], context.options.port);
-    let dns_addr = dns + ":53";
-    let dns_addr: SocketAddr = dns_addr.parse()?;
+    // the servers the VPN handed out, their queries go to the split dns
+    let dns_addrs = dns
+        .split(',')
+        .map(|server| Ok(SocketAddr::new(server.trim().parse()?, 53)))
+        .collect::<types::Result<Vec<_>>>()?;
 
     let pass = digest_pass(&context.options.password);
 
@@ -156,7 +159,9 @@ pub async fn async_run(
     );
 
     let mut device = TunDevice::new(context.options.mtu, session);
-    device.add_white_ip(dns_addr.ip());
+    for dns_addr in &dns_addrs {
+        device.add_white_ip(dns_addr.ip());
+    }
 
     let trusted_addr = (context.options.trusted_dns.clone() + ":53").parse()?;
     let distrusted_addr = (context.options.untrusted_dns.clone() + ":53").parse()?;
@@ -200,7 +205,7 @@ pub async fn async_run(
         }
         for socket in udp_sockets {
             log::info!("accept udp to:{}", socket.peer_addr());
-            if socket.peer_addr_std() == dns_addr {
+            if dns_addrs.contains(&socket.peer_addr_std()) {
                 spawn(start_dns(
                     socket,
                     config.clone(),
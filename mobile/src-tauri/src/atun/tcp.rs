@@ -1,6 +1,7 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use bytes::BytesMut;
+use relay::Options;
 use rustls::{ClientConfig, ServerName};
 use tokio::{io::AsyncWriteExt, spawn};
 
@@ -12,6 +13,9 @@ use crate::atun::{
     proto::{TrojanRequest, CONNECT},
 };
 
+/// Same as the desktop default of --tcp-idle-timeout.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
 pub async fn start_tcp(
     mut local: TcpStream,
     config: Arc<ClientConfig>,
@@ -21,16 +25,16 @@ pub async fn start_tcp(
 ) {
     if local.peer_addr().ip() == server_addr.ip() {
         if let Ok(remote) = tokio::net::TcpStream::connect(local.peer_addr()).await {
-            let (mut local_read, mut local_write) = local.into_split();
-            let (mut remote_read, mut remote_write) = remote.into_split();
+            let (mut local_read, local_write) = local.into_split();
+            let (remote_read, remote_write) = remote.into_split();
             spawn(async move {
-                let _ = tokio::io::copy(&mut local_read, &mut remote_write).await;
-                let _ = remote_write.shutdown().await;
+                let options = Options::new(IDLE_TIMEOUT);
+                relay::copy(&mut local_read, remote_write, options, &()).await;
                 local_read.close();
             });
             spawn(async move {
-                let _ = tokio::io::copy(&mut remote_read, &mut local_write).await;
-                let _ = local_write.shutdown().await;
+                let options = Options::new(IDLE_TIMEOUT);
+                relay::copy(remote_read, local_write, options, &()).await;
             });
         } else {
             let _ = local.shutdown().await;
@@ -54,14 +58,12 @@ pub async fn local_to_remote(mut local: TcpReadHalf, mut remote: TlsClientWriteH
         let _ = remote.shutdown().await;
         return;
     }
-    let _ = tokio::io::copy(&mut local, &mut remote).await;
+    let closed = relay::copy(&mut local, remote, Options::new(IDLE_TIMEOUT), &()).await;
     local.close();
-    let _ = remote.shutdown().await;
-    log::info!("local to remote closed");
+    log::info!("local to remote closed, {}", closed);
 }
 
-pub async fn remote_to_local(mut remote: TlsClientReadHalf, mut local: TcpWriteHalf) {
-    let _ = tokio::io::copy(&mut remote, &mut local).await;
-    log::info!("remote to local closed");
-    let _ = local.shutdown().await;
+pub async fn remote_to_local(remote: TlsClientReadHalf, local: TcpWriteHalf) {
+    let closed = relay::copy(remote, local, Options::new(IDLE_TIMEOUT), &()).await;
+    log::info!("remote to local closed, {}", closed);
 }
@@ -3,6 +3,7 @@ extern crate core;
 use std::{
     collections::HashSet,
     io::{BufRead, BufReader, Cursor},
+    net::IpAddr,
     sync::{atomic::AtomicBool, Arc, RwLock},
 };
 
@@ -11,6 +12,8 @@ use tauri::{Manager, State, Window, Wry};
 
 use crate::types::{EventType, VpnError};
 
+#[cfg(target_os = "android")]
+mod on_demand;
 mod types;
 
 // Learn more about Tauri commands at https://tauri.app/v1/guides/features/command
@@ -38,6 +41,74 @@ pub struct Options {
     pub dns_cache_time: u64,
     pub trusted_dns: String,
     pub untrusted_dns: String,
+    /// Comma separated addresses the VPN hands out as its DNS servers, their queries are
+    /// answered by the split dns on the device.
+    #[serde(default = "default_dns_servers")]
+    pub dns_servers: String,
+    /// Comma separated Wi-Fi networks the VPN is stopped on, like the one at home.
+    #[serde(default)]
+    pub trusted_ssids: String,
+    /// Comma separated Wi-Fi networks the VPN is started on, `*` for any untrusted one.
+    #[serde(default)]
+    pub untrusted_ssids: String,
+    /// Connections outside the VPN are to be blocked, by the always-on lockdown on Android, which
+    /// the user is sent to the settings to turn on, and by routing all networks on Apple.
+    #[serde(default)]
+    pub kill_switch: bool,
+}
+
+fn default_dns_servers() -> String {
+    "10.10.11.1,8.8.8.8,8.8.4.4,1.1.1.1,1.0.0.1".into()
+}
+
+impl Options {
+    /// The valid addresses of `dns_servers` joined by commas, the default ones if none is.
+    pub fn valid_dns_servers(&self) -> String {
+        let parse = |servers: &str| -> Vec<IpAddr> {
+            servers
+                .split(',')
+                .filter_map(|server| {
+                    let server = server.trim();
+                    let addr = server.parse().ok();
+                    if addr.is_none() && !server.is_empty() {
+                        log::error!("invalid dns server:{}", server);
+                    }
+                    addr
+                })
+                .collect()
+        };
+        let mut servers = parse(self.dns_servers.as_str());
+        if servers.is_empty() {
+            servers = parse(default_dns_servers().as_str());
+        }
+        servers
+            .iter()
+            .map(|server| server.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// The names of a comma separated list of networks, without blanks around them.
+    pub fn ssids(list: &str) -> impl Iterator<Item = &str> {
+        list.split(',')
+            .map(str::trim)
+            .filter(|ssid| !ssid.is_empty())
+    }
+
+    /// The options the frontend saved last, with the password from the keystore. None before it
+    /// saves a server.
+    pub fn load() -> Result<Option<Options>, VpnError> {
+        let data = platform::load_data(Context::CONFIG_KEY)?;
+        if data.is_empty() {
+            return Ok(None);
+        }
+        let mut options: Options = serde_json::from_str(data.as_str())?;
+        if options.hostname.is_empty() {
+            return Ok(None);
+        }
+        options.password = platform::load_secret(Context::PASSWORD_KEY)?;
+        Ok(Some(options))
+    }
 }
 
 #[derive(Clone)]
@@ -47,10 +118,30 @@ pub struct Context {
 }
 
 impl Context {
+    /// Keys the frontend saves the options and the password under.
+    pub const CONFIG_KEY: &str = "config";
+    pub const PASSWORD_KEY: &str = "password";
+
     const ADDED_DOMAIN_KEY: &str = "added_domains";
 
     const REMED_DOMAIN_KEY: &str = "remed_domains";
 
+    /// Context with the built in blocked domains.
+    pub fn new(options: Options) -> Self {
+        let domains = include_bytes!("../../../trojan-client/src-tauri/config/domain.txt");
+        let reader = BufReader::new(Cursor::new(domains));
+        let mut blocked_domains = HashSet::new();
+        reader.lines().for_each(|line| {
+            let _ = line.map(|line| {
+                blocked_domains.insert(line);
+            });
+        });
+        Self {
+            options,
+            blocked_domains,
+        }
+    }
+
     pub fn merge_domains(&mut self) -> Result<(), VpnError> {
         let added = self.load_data(Self::ADDED_DOMAIN_KEY)?;
         let mut new_added = Vec::new();
@@ -141,6 +232,12 @@ fn init_window(log_level: String, window: Window<Wry>, state: State<VpnState>) {
     } else {
         platform::init_log(&log_level);
         log::info!("init log with log_level:{}", log_level);
+        if let Err(err) = platform::watch_wifi() {
+            log::error!("watch wifi failed:{:?}", err);
+        }
+        for diagnostic in platform::self_check() {
+            report(diagnostic.check, diagnostic.error);
+        }
         if let Ok(mut state) = state.write() {
             if let Err(err) = state.merge_domains() {
                 log::error!("merge domains failed:{:?}", err);
@@ -153,7 +250,7 @@ fn init_window(log_level: String, window: Window<Wry>, state: State<VpnState>) {
 fn start_vpn(options: Options, window: Window<Wry>) {
     if let Ok(mut state) = window.state::<VpnState>().inner().write() {
         state.options = options;
-        if let Err(err) = platform::start_vpn(state.options.mtu as i32) {
+        if let Err(err) = platform::start_vpn(&state.options) {
             log::error!("start_vpn failed:{:?}", err);
         }
     } else {
@@ -222,6 +319,25 @@ fn load_data(key: String) -> String {
     }
 }
 
+#[tauri::command]
+fn save_secret(key: String, value: String) -> Result<(), String> {
+    platform::save_secret(key, value).map_err(|err| {
+        log::error!("save secret failed:{:?}", err);
+        format!("{:?}", err)
+    })
+}
+
+#[tauri::command]
+fn load_secret(key: String) -> String {
+    match platform::load_secret(key) {
+        Err(err) => {
+            log::error!("load secret failed:{:?}", err);
+            "".into()
+        }
+        Ok(ret) => ret,
+    }
+}
+
 #[tauri::command]
 fn search_domain(key: String, state: State<VpnState>) -> Vec<String> {
     if let Ok(state) = state.read() {
@@ -263,18 +379,7 @@ lazy_static::lazy_static! {
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     std::env::set_var("RUST_BACKTRACE", "full");
-    let domains = include_bytes!("../../../trojan-client/src-tauri/config/domain.txt");
-    let reader = BufReader::new(Cursor::new(domains));
-    let mut blocked_domains = HashSet::new();
-    reader.lines().for_each(|line| {
-        let _ = line.map(|line| {
-            blocked_domains.insert(line);
-        });
-    });
-    let state = RwLock::new(Context {
-        options: Options::default(),
-        blocked_domains,
-    });
+    let state = RwLock::new(Context::new(Options::default()));
     tauri::Builder::default()
         .plugin(tauri_plugin_window::init())
         .plugin(tauri_plugin_shell::init())
@@ -289,6 +394,8 @@ pub fn run() {
             update_notification,
             save_data,
             load_data,
+            save_secret,
+            load_secret,
             search_domain,
             add_domain,
             remove_domain,
@@ -308,8 +415,25 @@ macro_rules! window {
 }
 
 pub fn emit_event<T: Serialize + Clone>(event: EventType, data: T) -> Result<(), types::VpnError> {
+    #[cfg(any(target_os = "ios", target_os = "macos"))]
+    if platform::is_extension() {
+        // the packet tunnel extension has no window, the app emits its events
+        return platform::send_event(event.to_str(), data);
+    }
+    emit_window_event(event.to_str(), data)
+}
+
+/// Logs a problem and shows it in the frontend, with the check that found it.
+pub fn report(check: &'static str, error: String) {
+    log::error!("{} check failed:{}", check, error);
+    if let Err(err) = emit_event(EventType::Diagnostic, types::Diagnostic { check, error }) {
+        log::error!("emit diagnostic failed:{:?}", err);
+    }
+}
+
+pub fn emit_window_event<T: Serialize + Clone>(name: &str, data: T) -> Result<(), types::VpnError> {
     let window = window!();
-    window.emit(event.to_str(), data)?;
+    window.emit(name, data)?;
     Ok(())
 }
 
@@ -323,6 +447,46 @@ pub fn process_vpn(fd: i32, dns: String, running: Arc<AtomicBool>) -> Result<(),
     run_vpn(fd, dns, context, running)
 }
 
+/// Starts or stops the VPN by the on demand rules of the saved options when the platform
+/// reports the Wi-Fi network joined, `ssid` is empty on other networks.
+#[cfg(target_os = "android")]
+pub fn wifi_changed(ssid: String) -> Result<(), types::VpnError> {
+    use std::sync::Mutex;
+
+    use on_demand::{action, Action, OnDemand};
+
+    lazy_static::lazy_static! {
+        static ref ON_DEMAND:Mutex<OnDemand> = Mutex::new(OnDemand::default());
+    }
+
+    let mut on_demand = ON_DEMAND
+        .lock()
+        .map_err(|e| VpnError::WLock(e.to_string()))?;
+    if !on_demand.network_changed(ssid.as_str()) {
+        return Ok(());
+    }
+    let Some(options) = Options::load()? else {
+        return Ok(());
+    };
+    match action(ssid.as_str(), &options) {
+        Some(Action::Connect) if !platform::is_running()? => {
+            log::warn!("start vpn on untrusted wifi {}", ssid);
+            window!()
+                .state::<VpnState>()
+                .inner()
+                .write()
+                .map_err(|e| VpnError::WLock(e.to_string()))?
+                .options = options.clone();
+            platform::start_vpn(&options)
+        }
+        Some(Action::Disconnect) if platform::is_running()? => {
+            log::warn!("stop vpn on trusted wifi {}", ssid);
+            platform::stop_vpn()
+        }
+        _ => Ok(()),
+    }
+}
+
 #[allow(mutable_transmutes)]
 pub unsafe fn get_mut_unchecked<T>(t: &mut Arc<T>) -> &mut T {
     std::mem::transmute(t.as_ref())
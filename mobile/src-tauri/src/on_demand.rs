@@ -0,0 +1,43 @@
+//! Starts the VPN on untrusted Wi-Fi networks and stops it on trusted ones, as the platform reports
+//! the network joined. Only a change of network is acted on, so the VPN started or stopped by hand
+//! stays so until the next one.
+
+use crate::Options;
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Connect,
+    Disconnect,
+}
+
+#[derive(Default)]
+pub struct OnDemand {
+    /// The Wi-Fi network reported last, empty for other networks, None before the first report.
+    ssid: Option<String>,
+}
+
+impl OnDemand {
+    /// Records the Wi-Fi network `ssid` joined, empty for other networks, returns false if it is
+    /// the one reported last.
+    pub fn network_changed(&mut self, ssid: &str) -> bool {
+        if self.ssid.as_deref() == Some(ssid) {
+            false
+        } else {
+            self.ssid.replace(ssid.to_string());
+            true
+        }
+    }
+}
+
+/// What to do on joining the Wi-Fi network `ssid`, empty for other networks.
+pub fn action(ssid: &str, options: &Options) -> Option<Action> {
+    if ssid.is_empty() {
+        None
+    } else if Options::ssids(&options.trusted_ssids).any(|name| name == ssid) {
+        Some(Action::Disconnect)
+    } else if Options::ssids(&options.untrusted_ssids).any(|name| name == ssid || name == "*") {
+        Some(Action::Connect)
+    } else {
+        None
+    }
+}
@@ -11,28 +11,95 @@ use std::{
     thread::JoinHandle,
 };
 
-use smoltcp::wire::{
-    IpAddress, IpProtocol, IpVersion, Ipv4Packet, Ipv6Packet, TcpPacket, UdpPacket,
-};
-
 use async_smoltcp::{Packet as _, Tun};
 use jni::{
-    objects::{JClass, JObject, JString},
+    objects::{JClass, JObject, JObjectArray, JString},
     sys::{jboolean, jint},
     JNIEnv, JavaVM,
 };
 
 use crate::{
-    emit_event, types,
-    types::{EventType, VpnError, VpnStatus},
+    emit_event, report, types,
+    types::{Diagnostic, EventType, VpnError, VpnStatus},
+    Options,
 };
 
+/// Android name of the ABI this library is built for.
+const ABI: &str = if cfg!(target_arch = "aarch64") {
+    "arm64-v8a"
+} else if cfg!(target_arch = "arm") {
+    "armeabi-v7a"
+} else if cfg!(target_arch = "x86_64") {
+    "x86_64"
+} else {
+    "x86"
+};
+
+/// Java methods called by name, a stale or shrunk build only fails on them when they are called.
+const JAVA_METHODS: &[(&str, &str, &str)] = &[
+    (
+        "com/bmshi/proxy/mobile/MainActivity",
+        "startVpn",
+        "(ILjava/lang/String;)V",
+    ),
+    ("com/bmshi/proxy/mobile/MainActivity", "stopVpn", "()V"),
+    ("com/bmshi/proxy/mobile/MainActivity", "watchWifi", "()V"),
+    (
+        "com/bmshi/proxy/mobile/MainActivity",
+        "openVpnSettings",
+        "()V",
+    ),
+    (
+        "com/bmshi/proxy/mobile/MainActivity",
+        "checkSelfPermission",
+        "(Ljava/lang/String;)Z",
+    ),
+    (
+        "com/bmshi/proxy/mobile/MainActivity",
+        "requestPermission",
+        "(Ljava/lang/String;)V",
+    ),
+    (
+        "com/bmshi/proxy/mobile/MainActivity",
+        "shouldShowRequestPermissionRationaleNative",
+        "(Ljava/lang/String;)Z",
+    ),
+    (
+        "com/bmshi/proxy/mobile/MainActivity",
+        "updateNotification",
+        "(Ljava/lang/String;)V",
+    ),
+    (
+        "com/bmshi/proxy/mobile/MainActivity",
+        "saveData",
+        "(Ljava/lang/String;Ljava/lang/String;)V",
+    ),
+    (
+        "com/bmshi/proxy/mobile/MainActivity",
+        "loadData",
+        "(Ljava/lang/String;)Ljava/lang/String;",
+    ),
+    (
+        "com/bmshi/proxy/mobile/MainActivity",
+        "saveSecret",
+        "(Ljava/lang/String;Ljava/lang/String;)Z",
+    ),
+    (
+        "com/bmshi/proxy/mobile/MainActivity",
+        "loadSecret",
+        "(Ljava/lang/String;)Ljava/lang/String;",
+    ),
+    ("com/bmshi/proxy/mobile/TrojanProxy", "syncData", "()V"),
+];
+
 struct AndroidContext {
     jvm: JavaVM,
     running: Arc<AtomicBool>,
     dns: String,
     fd: i32,
     handle: Option<JoinHandle<()>>,
+    /// The options of the last start asked for lockdown.
+    kill_switch: bool,
 }
 
 unsafe impl Sync for AndroidContext {}
@@ -98,6 +165,7 @@ fn init_rust<'local>(env: JNIEnv<'local>) -> Result<(), VpnError> {
         running: Arc::new(AtomicBool::new(false)),
         fd: -1,
         handle: None,
+        kill_switch: false,
     });
     Ok(())
 }
@@ -114,27 +182,67 @@ pub extern "system" fn Java_com_bmshi_proxy_mobile_MainActivity_onPermissionResu
     }
 }
 
+#[no_mangle]
+pub extern "system" fn Java_com_bmshi_proxy_mobile_MainActivity_onWifiChanged<'local>(
+    mut env: JNIEnv<'local>,
+    _: JObject<'local>,
+    ssid: JObject<'local>,
+) {
+    let ssid: JString<'local> = ssid.into();
+    let ssid = env.get_string(&ssid).unwrap();
+    if let Err(err) = crate::wifi_changed(ssid.to_string_lossy().to_string()) {
+        log::error!("onWifiChanged failed:{:?}", err);
+    }
+}
+
 #[no_mangle]
 pub extern "system" fn Java_com_bmshi_proxy_mobile_TrojanProxy_onStart<'local>(
     mut env: JNIEnv<'local>,
     _: JObject<'local>,
     fd: jint,
     dns: JObject<'local>,
+    lockdown: jboolean,
 ) {
     let dns: JString<'local> = dns.into();
     let dns = env.get_string(&dns).unwrap();
-    if let Err(err) = on_vpn_start(fd, dns.to_string_lossy().to_string()) {
+    if let Err(err) = on_vpn_start(fd, dns.to_string_lossy().to_string(), lockdown != 0) {
         log::error!("onStart failed:{:?}", err);
     }
 }
 
-fn on_vpn_start(fd: i32, dns: String) -> Result<(), VpnError> {
+fn on_vpn_start(fd: i32, dns: String, lockdown: bool) -> Result<(), VpnError> {
+    if let Err(err) = check_fd(fd) {
+        report("fd", err);
+        return stop_vpn();
+    }
     let (context, lock) = get_mut_context()?;
     context.fd = fd;
     context.running = Arc::new(AtomicBool::new(true));
     context.dns = dns;
+    let kill_switch = context.kill_switch;
     drop(lock);
-    start_vpn_process()
+    start_vpn_process()?;
+    log::warn!("vpn lockdown:{}", lockdown);
+    emit_event(EventType::LockdownChanged, lockdown)?;
+    if kill_switch && !lockdown {
+        // apps can't turn it on, only the always-on vpn settings
+        open_vpn_settings()?;
+    }
+    Ok(())
+}
+
+fn open_vpn_settings() -> Result<(), VpnError> {
+    log::info!("open vpn settings");
+    let (context, lock) = get_context()?;
+    let mut env = context.jvm.attach_current_thread()?;
+    drop(lock);
+    env.call_static_method(
+        "com/bmshi/proxy/mobile/MainActivity",
+        "openVpnSettings",
+        "()V",
+        &[],
+    )?;
+    Ok(())
 }
 
 pub fn start_vpn_process() -> Result<(), VpnError> {
@@ -146,10 +254,10 @@ pub fn start_vpn_process() -> Result<(), VpnError> {
         let handle = std::thread::spawn(move || {
             if let Err(err) = std::panic::catch_unwind(|| {
                 if let Err(err) = crate::process_vpn(fd, dns, running) {
-                    log::error!("process vpn failed:{:?}", err);
+                    report("vpn", format!("{:?}", err));
                 }
             }) {
-                log::error!("uncaught exception:{:?}", err);
+                report("vpn", super::panic_message(err.as_ref()));
                 if let Err(err) = emit_event(EventType::StatusChanged, VpnStatus::ProcessExit) {
                     log::error!("emit status changed failed:{:?}", err);
                 }
@@ -207,6 +315,135 @@ fn on_network_changed(available: bool) -> Result<(), types::VpnError> {
     )
 }
 
+/// Has MainActivity report the Wi-Fi network joined to `crate::wifi_changed`, from now on.
+pub fn watch_wifi() -> Result<(), VpnError> {
+    log::info!("watch wifi");
+    let (context, lock) = get_context()?;
+    let mut env = context.jvm.attach_current_thread()?;
+    drop(lock);
+    env.call_static_method(
+        "com/bmshi/proxy/mobile/MainActivity",
+        "watchWifi",
+        "()V",
+        &[],
+    )?;
+    Ok(())
+}
+
+/// Problems of the installed build found on startup: the library loaded for an ABI the device
+/// lacks or only emulates, as a wrong split APK does, and the Java methods called by name that
+/// can't be found.
+pub fn self_check() -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let (context, lock) = match get_context() {
+        Ok(context) => context,
+        Err(err) => {
+            diagnostics.push(Diagnostic {
+                check: "jni",
+                error: format!("no jvm, initRust not called:{:?}", err),
+            });
+            return diagnostics;
+        }
+    };
+    let mut env = match context.jvm.attach_current_thread() {
+        Ok(env) => env,
+        Err(err) => {
+            diagnostics.push(Diagnostic {
+                check: "jni",
+                error: format!("attach thread failed:{:?}", err),
+            });
+            return diagnostics;
+        }
+    };
+    drop(lock);
+    match supported_abis(&mut env) {
+        Ok(abis) if abis.first().map(String::as_str) == Some(ABI) => {}
+        Ok(abis) => {
+            let library = loaded_library().unwrap_or_else(|| "unknown".into());
+            let error = if abis.iter().any(|abi| abi == ABI) {
+                format!("{} library {} loaded instead of {}", ABI, library, abis[0])
+            } else {
+                format!(
+                    "{} library {} not supported by the device, only {}",
+                    ABI,
+                    library,
+                    abis.join(",")
+                )
+            };
+            diagnostics.push(Diagnostic {
+                check: "abi",
+                error,
+            });
+        }
+        Err(err) => diagnostics.push(Diagnostic {
+            check: "abi",
+            error: format!("read Build.SUPPORTED_ABIS failed:{:?}", err),
+        }),
+    }
+    for (class, name, sig) in JAVA_METHODS {
+        if let Err(err) = env.get_static_method_id(*class, *name, *sig) {
+            // the lookup leaves a NoSuchMethodError or NoClassDefFoundError pending
+            let _ = env.exception_clear();
+            diagnostics.push(Diagnostic {
+                check: "jni",
+                error: format!("{}.{}{} not found:{}", class, name, sig, err),
+            });
+        }
+    }
+    if diagnostics.is_empty() {
+        log::info!("self check passed, {} library", ABI);
+    }
+    diagnostics
+}
+
+/// The ABIs of the device, the preferred one first.
+fn supported_abis(env: &mut JNIEnv) -> Result<Vec<String>, VpnError> {
+    let abis: JObjectArray = env
+        .get_static_field("android/os/Build", "SUPPORTED_ABIS", "[Ljava/lang/String;")?
+        .l()?
+        .into();
+    let mut result = Vec::new();
+    for index in 0..env.get_array_length(&abis)? {
+        let abi: JString = env.get_object_array_element(&abis, index)?.into();
+        result.push(env.get_string(&abi)?.to_string_lossy().to_string());
+    }
+    Ok(result)
+}
+
+/// Path this library was mapped from, the ABI split APK holding it if not extracted.
+fn loaded_library() -> Option<String> {
+    let maps = std::fs::read_to_string("/proc/self/maps").ok()?;
+    let paths: Vec<_> = maps
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(5))
+        .collect();
+    paths
+        .iter()
+        .find(|path| path.ends_with("libmobile.so"))
+        .or_else(|| paths.iter().find(|path| path.contains("split_config.")))
+        .map(|path| path.to_string())
+}
+
+/// Whether the fd handed over by the vpn service is an open tun.
+fn check_fd(fd: i32) -> Result<(), String> {
+    if fd < 0 {
+        return Err(format!("vpn service gave no fd:{}", fd));
+    }
+    match std::fs::read_link(format!("/proc/self/fd/{}", fd)) {
+        Ok(path) if path.starts_with("/dev/tun") => Ok(()),
+        Ok(path) => Err(format!("vpn fd {} is {}, not a tun", fd, path.display())),
+        Err(err) => Err(format!("vpn fd {} is not open:{}", fd, err)),
+    }
+}
+
+/// Whether the vpn service is up.
+pub fn is_running() -> Result<bool, VpnError> {
+    let (context, lock) = get_context()?;
+    let running = context.running.load(Ordering::SeqCst);
+    drop(lock);
+    Ok(running)
+}
+
 pub fn init_log(log_level: &String) {
     let config = android_logger::Config::default();
     let config = match log_level.as_str() {
@@ -230,16 +467,19 @@ pub fn init_log(log_level: &String) {
     android_logger::init_once(config);
 }
 
-pub fn start_vpn(mtu: i32) -> Result<(), VpnError> {
+pub fn start_vpn(options: &Options) -> Result<(), VpnError> {
     log::info!("start vpn proxy");
-    let (context, lock) = get_context()?;
+    let (context, lock) = get_mut_context()?;
+    context.kill_switch = options.kill_switch;
     let mut env = context.jvm.attach_current_thread()?;
     drop(lock);
+    let dns_servers = options.valid_dns_servers();
+    let dns_servers = env.new_string(dns_servers)?;
     env.call_static_method(
         "com/bmshi/proxy/mobile/MainActivity",
         "startVpn",
-        "(I)V",
-        &[mtu.into()],
+        "(ILjava/lang/String;)V",
+        &[(options.mtu as i32).into(), (&dns_servers).into()],
     )?;
     Ok(())
 }
@@ -348,6 +588,45 @@ pub fn load_data(key: impl AsRef<str>) -> Result<String, VpnError> {
     Ok(value)
 }
 
+pub fn save_secret(key: impl AsRef<str>, content: impl AsRef<str>) -> Result<(), VpnError> {
+    log::info!("save secret:{}", key.as_ref());
+    let (context, lock) = get_context()?;
+    let mut env = context.jvm.attach_current_thread()?;
+    drop(lock);
+    let content = env.new_string(content)?;
+    let key = env.new_string(key)?;
+    let ret = env.call_static_method(
+        "com/bmshi/proxy/mobile/MainActivity",
+        "saveSecret",
+        "(Ljava/lang/String;Ljava/lang/String;)Z",
+        &[(&key).into(), (&content).into()],
+    )?;
+    if ret.z()? {
+        Ok(())
+    } else {
+        Err(VpnError::Keystore)
+    }
+}
+
+pub fn load_secret(key: impl AsRef<str>) -> Result<String, VpnError> {
+    log::info!("load secret:{}", key.as_ref());
+    let (context, lock) = get_context()?;
+    let mut env = context.jvm.attach_current_thread()?;
+    drop(lock);
+    let key = env.new_string(key)?;
+    let ret = env.call_static_method(
+        "com/bmshi/proxy/mobile/MainActivity",
+        "loadSecret",
+        "(Ljava/lang/String;)Ljava/lang/String;",
+        &[(&key).into()],
+    )?;
+
+    let value: JString = ret.l()?.into();
+    let value = env.get_string(&value)?.to_string_lossy().to_string();
+
+    Ok(value)
+}
+
 #[allow(unused)]
 pub fn sync_data() -> Result<(), VpnError> {
     log::info!("sync data");
@@ -438,51 +717,7 @@ impl Packet {
     }
 
     fn info(&self) -> types::Result<()> {
-        let (dst_addr, src_addr, payload, protocol) = match IpVersion::of_packet(self.as_ref())? {
-            IpVersion::Ipv4 => {
-                let packet = Ipv4Packet::new_checked(self.as_ref())?;
-                let dst_addr = packet.dst_addr();
-                let src_addr = packet.src_addr();
-                (
-                    IpAddress::Ipv4(dst_addr),
-                    IpAddress::Ipv4(src_addr),
-                    packet.payload(),
-                    packet.next_header(),
-                )
-            }
-            IpVersion::Ipv6 => {
-                let packet = Ipv6Packet::new_checked(self.as_ref())?;
-                let dst_addr = packet.dst_addr();
-                let src_addr = packet.src_addr();
-                (
-                    IpAddress::Ipv6(dst_addr),
-                    IpAddress::Ipv6(src_addr),
-                    packet.payload(),
-                    packet.next_header(),
-                )
-            }
-        };
-        let (dst_port, src_port, payload) = match protocol {
-            IpProtocol::Udp => {
-                let packet = UdpPacket::new_checked(payload)?;
-                (packet.dst_port(), packet.src_port(), packet.payload())
-            }
-            IpProtocol::Tcp => {
-                let packet = TcpPacket::new_checked(payload)?;
-                (packet.dst_port(), packet.src_port(), packet.payload())
-            }
-            _ => return Ok(()),
-        };
-        log::info!(
-            "send packet {} {}:{} - {}:{} {} bytes",
-            protocol,
-            src_addr,
-            src_port,
-            dst_addr,
-            dst_port,
-            payload.len()
-        );
-        Ok(())
+        super::log_packet(self.as_ref())
     }
 }
 
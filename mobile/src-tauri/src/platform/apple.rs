@@ -0,0 +1,522 @@
+//! Backend of iOS and macOS. The packets are handled by a NetworkExtension packet tunnel provider
+//! running in a process of its own, so the app and the extension both link this library and
+//! register their Swift functions with `trojan_init_rust`. The app saves the options into the VPN
+//! configuration and starts the tunnel, the extension runs the tun on the utun socket from
+//! `trojan_on_start` and relays its events, which the app emits to the window in `trojan_on_event`.
+
+use std::{
+    ffi::{c_char, c_void, CStr, CString},
+    fs::File,
+    io::{ErrorKind, Read, Write},
+    mem::ManuallyDrop,
+    ops::Deref,
+    os::fd::{FromRawFd, OwnedFd},
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use async_smoltcp::{Packet as _, Tun};
+use serde::Serialize;
+
+use crate::{
+    emit_event, emit_window_event,
+    types::{Diagnostic, EventType, VpnError, VpnStatus},
+    Context, Options,
+};
+
+/// Wait before the extension restarts a failed tun.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Functions of the Swift side, declared in gen/apple/Sources/trojan.h.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct AppleCallbacks {
+    /// Saves the VPN configuration with the options json and starts the tunnel, app only.
+    start_vpn: Option<extern "C" fn(options: *const c_char)>,
+    /// Stops the tunnel, app only.
+    stop_vpn: Option<extern "C" fn()>,
+    /// Stores a value in the defaults of the app group shared with the extension.
+    save_data: extern "C" fn(key: *const c_char, value: *const c_char),
+    /// Value copied with strdup, null if missing.
+    load_data: extern "C" fn(key: *const c_char) -> *mut c_char,
+    /// Stores a value in the keychain access group shared with the extension.
+    save_secret: extern "C" fn(key: *const c_char, value: *const c_char) -> bool,
+    /// Value copied with strdup, null if missing.
+    load_secret: extern "C" fn(key: *const c_char) -> *mut c_char,
+    /// Passes an event with its json payload to the app, extension only.
+    send_event: Option<extern "C" fn(name: *const c_char, data: *const c_char)>,
+}
+
+/// The tun the extension runs.
+struct Tunnel {
+    fd: i32,
+    dns: String,
+    context: Context,
+}
+
+struct AppleContext {
+    callbacks: AppleCallbacks,
+    running: Arc<AtomicBool>,
+    network_available: bool,
+    tunnel: Option<Tunnel>,
+    handle: Option<JoinHandle<()>>,
+}
+
+lazy_static::lazy_static! {
+    static ref CONTEXT:RwLock<Option<AppleContext>> = RwLock::new(None);
+}
+
+fn with_context<T>(f: impl FnOnce(&mut AppleContext) -> T) -> Result<T, VpnError> {
+    let mut lock = CONTEXT
+        .write()
+        .map_err(|e| VpnError::WLock(e.to_string()))?;
+    let context = lock.as_mut().ok_or(VpnError::NoPlatformContext)?;
+    Ok(f(context))
+}
+
+fn callbacks() -> Result<AppleCallbacks, VpnError> {
+    let lock = CONTEXT.read().map_err(|e| VpnError::RLock(e.to_string()))?;
+    let context = lock.as_ref().ok_or(VpnError::NoPlatformContext)?;
+    Ok(context.callbacks)
+}
+
+/// Takes a string returned by the Swift side, null is empty.
+fn take_string(value: *mut c_char) -> String {
+    if value.is_null() {
+        return String::new();
+    }
+    let string = unsafe { CStr::from_ptr(value) }
+        .to_string_lossy()
+        .to_string();
+    unsafe { libc::free(value as *mut c_void) };
+    string
+}
+
+unsafe fn borrow_string(value: *const c_char) -> String {
+    if value.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(value).to_string_lossy().to_string()
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn trojan_init_rust(callbacks: AppleCallbacks) {
+    let result = CONTEXT
+        .write()
+        .map_err(|e| VpnError::WLock(e.to_string()))
+        .map(|mut context| {
+            context.replace(AppleContext {
+                callbacks,
+                running: Arc::new(AtomicBool::new(false)),
+                network_available: true,
+                tunnel: None,
+                handle: None,
+            })
+        });
+    if let Err(err) = result {
+        log::error!("init rust failed:{:?}", err);
+    }
+}
+
+/// Called by the extension once the tunnel network settings are applied.
+///
+/// # Safety
+/// `dns` and `options` are null or nul terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn trojan_on_start(dns: *const c_char, options: *const c_char) {
+    let dns = borrow_string(dns);
+    let options = borrow_string(options);
+    if let Err(err) = on_vpn_start(dns, options) {
+        crate::report("start", format!("{:?}", err));
+    }
+}
+
+fn on_vpn_start(dns: String, options: String) -> Result<(), VpnError> {
+    let mut options: Options = serde_json::from_str(options.as_str())?;
+    init_log(&options.log_level);
+    options.password = load_secret(Context::PASSWORD_KEY)?;
+    // the configuration routes all networks into the tunnel for it
+    let lockdown = options.kill_switch;
+    let mut context = Context::new(options);
+    context.merge_domains()?;
+    let fd = find_utun_fd()?;
+    with_context(|apple| {
+        apple.running = Arc::new(AtomicBool::new(true));
+        apple.tunnel.replace(Tunnel { fd, dns, context });
+    })?;
+    start_vpn_process()?;
+    emit_event(EventType::LockdownChanged, lockdown)
+}
+
+/// The utun socket of the tunnel, which NEPacketTunnelFlow does not expose, found as the
+/// descriptor naming a utun interface.
+fn find_utun_fd() -> Result<i32, VpnError> {
+    for fd in 0..1024 {
+        let mut name = [0u8; libc::IFNAMSIZ];
+        let mut len = name.len() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SYSPROTO_CONTROL,
+                libc::UTUN_OPT_IFNAME,
+                name.as_mut_ptr() as *mut c_void,
+                &mut len,
+            )
+        };
+        if ret == 0 && name.starts_with(b"utun") {
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+            if unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            log::info!("found utun socket {}", fd);
+            return Ok(fd);
+        }
+    }
+    log::error!("no utun socket found");
+    Err(std::io::Error::from(ErrorKind::NotFound).into())
+}
+
+/// Starts the tun in the extension, the extension restarts it by itself as the app may be
+/// suspended, so the app does nothing here.
+pub fn start_vpn_process() -> Result<(), VpnError> {
+    let started = with_context(|context| {
+        let Some(tunnel) = &context.tunnel else {
+            return false;
+        };
+        if !context.running.load(Ordering::SeqCst) || context.handle.is_some() {
+            return false;
+        }
+        let fd = tunnel.fd;
+        let dns = tunnel.dns.clone();
+        let vpn_context = tunnel.context.clone();
+        let running = context.running.clone();
+        let handle = std::thread::spawn(move || process_vpn(fd, dns, vpn_context, running));
+        context.handle.replace(handle);
+        true
+    })?;
+    if started {
+        emit_event(EventType::StatusChanged, VpnStatus::VpnStart)?;
+        log::error!("vpn process started");
+    }
+    Ok(())
+}
+
+fn process_vpn(fd: i32, dns: String, context: Context, running: Arc<AtomicBool>) {
+    let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        crate::run_vpn(fd, dns, context, running.clone())
+    }));
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => crate::report("vpn", format!("{:?}", err)),
+        Err(err) => crate::report("vpn", super::panic_message(err.as_ref())),
+    }
+    let restart = with_context(|context| {
+        context.handle.take();
+        context.network_available
+    })
+    .unwrap_or(false);
+    if running.load(Ordering::SeqCst) {
+        if let Err(err) = emit_event(EventType::StatusChanged, VpnStatus::ProcessExit) {
+            log::error!("emit status changed failed:{:?}", err);
+        }
+        if restart {
+            std::thread::sleep(RESTART_DELAY);
+            if let Err(err) = start_vpn_process() {
+                log::error!("restart vpn process failed:{:?}", err);
+            }
+        }
+    }
+}
+
+/// Called by the extension when the tunnel stops.
+#[no_mangle]
+pub extern "C" fn trojan_on_stop() {
+    if let Err(err) = on_vpn_stop() {
+        log::error!("call onStop failed:{:?}", err);
+    }
+}
+
+fn on_vpn_stop() -> Result<(), VpnError> {
+    log::error!("vpn process stopped");
+    with_context(|context| {
+        context.running.store(false, Ordering::SeqCst);
+        context.tunnel.take();
+    })?;
+    emit_event(EventType::StatusChanged, VpnStatus::VpnStop)
+}
+
+/// Called by the extension when the path of the default network changes.
+#[no_mangle]
+pub extern "C" fn trojan_on_network_changed(available: bool) {
+    if let Err(err) = on_network_changed(available) {
+        log::error!("call onNetworkChanged failed:{:?}", err);
+    }
+}
+
+fn on_network_changed(available: bool) -> Result<(), VpnError> {
+    with_context(|context| context.network_available = available)?;
+    emit_event(
+        EventType::StatusChanged,
+        if available {
+            VpnStatus::NetworkAvailable
+        } else {
+            VpnStatus::NetworkLost
+        },
+    )?;
+    if available {
+        start_vpn_process()?;
+    }
+    Ok(())
+}
+
+/// Called by the app with an event the extension sent.
+///
+/// # Safety
+/// `name` and `data` are null or nul terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn trojan_on_event(name: *const c_char, data: *const c_char) {
+    let name = borrow_string(name);
+    let data = borrow_string(data);
+    let result = serde_json::from_str::<serde_json::Value>(data.as_str())
+        .map_err(VpnError::from)
+        .and_then(|data| emit_window_event(name.as_str(), data));
+    if let Err(err) = result {
+        log::error!("emit event {} failed:{:?}", name, err);
+    }
+}
+
+/// Called by the app with the result of saving the VPN configuration, which the user is asked
+/// to allow the first time.
+#[no_mangle]
+pub extern "C" fn trojan_on_permission_result(granted: bool) {
+    log::info!("onPermissionResult:{}", granted);
+    if let Err(err) = emit_event(EventType::PermissionResult, granted) {
+        log::error!("onPermissionResult failed:{:?}", err);
+    }
+}
+
+pub fn is_extension() -> bool {
+    callbacks()
+        .map(|callbacks| callbacks.send_event.is_some())
+        .unwrap_or(false)
+}
+
+pub fn send_event<T: Serialize>(name: &str, data: T) -> Result<(), VpnError> {
+    let send = callbacks()?.send_event.ok_or(VpnError::NoPlatformContext)?;
+    let name = CString::new(name)?;
+    let data = CString::new(serde_json::to_string(&data)?)?;
+    send(name.as_ptr(), data.as_ptr());
+    Ok(())
+}
+
+pub fn init_log(log_level: &String) {
+    let level = match log_level.as_str() {
+        "Trace" | "0" => log::LevelFilter::Trace,
+        "Debug" | "1" => log::LevelFilter::Debug,
+        "Info" | "2" => log::LevelFilter::Info,
+        "Warn" | "3" => log::LevelFilter::Warn,
+        "Error" | "4" => log::LevelFilter::Error,
+        _ => log::LevelFilter::Debug,
+    };
+    // the app calls it for every window, only the first one installs the logger
+    let _ = oslog::OsLogger::new("com.bmshi.proxy.mobile")
+        .level_filter(level)
+        .init();
+}
+
+pub fn start_vpn(options: &Options) -> Result<(), VpnError> {
+    log::info!("start vpn proxy");
+    let start = callbacks()?.start_vpn.ok_or(VpnError::NoPlatformContext)?;
+    // the configuration is readable by other apps, the extension loads the password itself
+    let dns_servers = options.valid_dns_servers();
+    let join = |list: &str| Options::ssids(list).collect::<Vec<_>>().join(",");
+    let options = Options {
+        password: String::new(),
+        dns_servers,
+        // turned into the on demand rules of the configuration
+        trusted_ssids: join(&options.trusted_ssids),
+        untrusted_ssids: join(&options.untrusted_ssids),
+        ..options.clone()
+    };
+    let options = CString::new(serde_json::to_string(&options)?)?;
+    start(options.as_ptr());
+    Ok(())
+}
+
+pub fn stop_vpn() -> Result<(), VpnError> {
+    log::info!("stop vpn proxy");
+    let stop = callbacks()?.stop_vpn.ok_or(VpnError::NoPlatformContext)?;
+    stop();
+    Ok(())
+}
+
+/// The only permission is the VPN configuration, asked for when it is saved.
+pub fn check_self_permission(permission: impl AsRef<str>) -> Result<bool, VpnError> {
+    log::info!("check self permission:{}", permission.as_ref());
+    Ok(true)
+}
+
+pub fn request_permission(permission: impl AsRef<str>) -> Result<(), VpnError> {
+    log::info!("request permission:{}", permission.as_ref());
+    emit_event(EventType::PermissionResult, true)
+}
+
+pub fn should_show_permission_rationale(permission: impl AsRef<str>) -> Result<bool, VpnError> {
+    log::info!("should show permission rationale:{}", permission.as_ref());
+    Ok(false)
+}
+
+/// The on demand rules of the VPN configuration follow the Wi-Fi network instead.
+pub fn watch_wifi() -> Result<(), VpnError> {
+    Ok(())
+}
+
+/// Nothing to check, the bridge is linked in statically, and a missing utun socket is reported
+/// on start.
+pub fn self_check() -> Vec<Diagnostic> {
+    Vec::new()
+}
+
+/// The system shows the VPN status itself.
+pub fn update_notification(_content: impl AsRef<str>) -> Result<(), VpnError> {
+    Ok(())
+}
+
+pub fn save_data(key: impl AsRef<str>, content: impl AsRef<str>) -> Result<(), VpnError> {
+    log::info!("save data:{} - {}", key.as_ref(), content.as_ref());
+    let save = callbacks()?.save_data;
+    let key = CString::new(key.as_ref())?;
+    let content = CString::new(content.as_ref())?;
+    save(key.as_ptr(), content.as_ptr());
+    Ok(())
+}
+
+pub fn load_data(key: impl AsRef<str>) -> Result<String, VpnError> {
+    log::info!("load data:{}", key.as_ref());
+    let load = callbacks()?.load_data;
+    let key = CString::new(key.as_ref())?;
+    Ok(take_string(load(key.as_ptr())))
+}
+
+pub fn save_secret(key: impl AsRef<str>, content: impl AsRef<str>) -> Result<(), VpnError> {
+    log::info!("save secret:{}", key.as_ref());
+    let save = callbacks()?.save_secret;
+    let key = CString::new(key.as_ref())?;
+    let content = CString::new(content.as_ref())?;
+    if save(key.as_ptr(), content.as_ptr()) {
+        Ok(())
+    } else {
+        Err(VpnError::Keystore)
+    }
+}
+
+pub fn load_secret(key: impl AsRef<str>) -> Result<String, VpnError> {
+    log::info!("load secret:{}", key.as_ref());
+    let load = callbacks()?.load_secret;
+    let key = CString::new(key.as_ref())?;
+    Ok(take_string(load(key.as_ptr())))
+}
+
+pub struct Session {
+    file: ManuallyDrop<File>,
+    mtu: usize,
+    show_info: bool,
+}
+
+/// Packet of the utun socket, which prefixes the ip packet with its address family.
+pub struct Packet {
+    data: Vec<u8>,
+}
+
+const FAMILY_LEN: usize = 4;
+
+impl Session {
+    pub fn new(fd: i32, mtu: usize, show_info: bool) -> Self {
+        unsafe {
+            let fd = OwnedFd::from_raw_fd(fd);
+            let file = fd.into();
+            Self {
+                file: ManuallyDrop::new(file),
+                mtu,
+                show_info,
+            }
+        }
+    }
+}
+
+impl Tun for Session {
+    type Packet = Packet;
+
+    fn receive(&self) -> std::io::Result<Option<Self::Packet>> {
+        let mut packet = Packet::new(self.mtu);
+        let mut file = self.file.deref();
+        match file.read(packet.data.as_mut_slice()) {
+            Ok(0) => {
+                log::error!("end of file");
+                Err(ErrorKind::BrokenPipe.into())
+            }
+            Ok(n) if n <= FAMILY_LEN => Ok(None),
+            Ok(n) => {
+                packet.data.truncate(n);
+                Ok(Some(packet))
+            }
+            Err(err)
+                if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::Interrupted =>
+            {
+                Ok(None)
+            }
+            Err(err) => {
+                log::error!("read file failed:{:?}", err);
+                Err(err)
+            }
+        }
+    }
+    fn send(&self, mut packet: Self::Packet) -> std::io::Result<()> {
+        let family = match packet.as_ref().first().map(|byte| byte >> 4) {
+            Some(6) => libc::AF_INET6,
+            _ => libc::AF_INET,
+        };
+        packet.data[..FAMILY_LEN].copy_from_slice(&(family as u32).to_be_bytes());
+        let mut file = self.file.deref();
+        if let Err(err) = file.write_all(packet.data.as_slice()) {
+            log::error!("send packet failed:{}", err);
+            return Err(err);
+        } else if self.show_info {
+            if let Err(err) = super::log_packet(packet.as_ref()) {
+                log::error!("parse return packet failed:{:?}", err);
+            }
+        }
+        Ok(())
+    }
+    fn allocate_packet(&self, len: usize) -> std::io::Result<Self::Packet> {
+        Ok(Packet::new(len))
+    }
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+}
+
+impl Packet {
+    pub fn new(size: usize) -> Self {
+        let data = vec![0u8; size + FAMILY_LEN];
+        Self { data }
+    }
+}
+
+impl async_smoltcp::Packet for Packet {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.data[FAMILY_LEN..]
+    }
+    fn as_ref(&self) -> &[u8] {
+        &self.data[FAMILY_LEN..]
+    }
+    fn len(&self) -> usize {
+        self.data.len() - FAMILY_LEN
+    }
+}
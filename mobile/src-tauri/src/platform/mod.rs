@@ -1,10 +1,77 @@
+use std::any::Any;
+
+use smoltcp::wire::{
+    IpAddress, IpProtocol, IpVersion, Ipv4Packet, Ipv6Packet, TcpPacket, UdpPacket,
+};
+
+use crate::types;
+
 #[cfg(target_os = "android")]
 pub use android::*;
-#[cfg(target_os = "ios")]
-pub use ios::*;
+#[cfg(any(target_os = "ios", target_os = "macos"))]
+pub use apple::*;
 
 #[cfg(target_os = "android")]
 mod android;
-#[cfg(target_os = "ios")]
-mod ios;
+#[cfg(any(target_os = "ios", target_os = "macos"))]
+mod apple;
+
+/// Text of a panic caught from the vpn process.
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => panic
+            .downcast_ref::<String>()
+            .cloned()
+            .unwrap_or_else(|| "unknown panic".into()),
+    }
+}
 
+/// Logs the addresses and payload size of a packet written to the tun.
+fn log_packet(data: &[u8]) -> types::Result<()> {
+    let (dst_addr, src_addr, payload, protocol) = match IpVersion::of_packet(data)? {
+        IpVersion::Ipv4 => {
+            let packet = Ipv4Packet::new_checked(data)?;
+            let dst_addr = packet.dst_addr();
+            let src_addr = packet.src_addr();
+            (
+                IpAddress::Ipv4(dst_addr),
+                IpAddress::Ipv4(src_addr),
+                packet.payload(),
+                packet.next_header(),
+            )
+        }
+        IpVersion::Ipv6 => {
+            let packet = Ipv6Packet::new_checked(data)?;
+            let dst_addr = packet.dst_addr();
+            let src_addr = packet.src_addr();
+            (
+                IpAddress::Ipv6(dst_addr),
+                IpAddress::Ipv6(src_addr),
+                packet.payload(),
+                packet.next_header(),
+            )
+        }
+    };
+    let (dst_port, src_port, payload) = match protocol {
+        IpProtocol::Udp => {
+            let packet = UdpPacket::new_checked(payload)?;
+            (packet.dst_port(), packet.src_port(), packet.payload())
+        }
+        IpProtocol::Tcp => {
+            let packet = TcpPacket::new_checked(payload)?;
+            (packet.dst_port(), packet.src_port(), packet.payload())
+        }
+        _ => return Ok(()),
+    };
+    log::info!(
+        "send packet {} {}:{} - {}:{} {} bytes",
+        protocol,
+        src_addr,
+        src_port,
+        dst_addr,
+        dst_port,
+        payload.len()
+    );
+    Ok(())
+}
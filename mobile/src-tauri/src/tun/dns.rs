@@ -11,6 +11,7 @@ use mio::{
     net::{TcpStream, UdpSocket},
     Token,
 };
+use relay::{read_once, send_all};
 use rustls::{ClientConfig, ClientConnection, Connection, ServerName};
 use smoltcp::{iface::SocketHandle, socket::udp::Socket, wire::IpEndpoint};
 use trust_dns_proto::{op::Message, serialize::binary::BinDecodable};
@@ -21,7 +22,6 @@ use crate::{
         proto::{TrojanRequest, UdpAssociate, UdpParseResultEndpoint, UDP_ASSOCIATE},
         tls_conn::TlsConn,
         udp::UdpSocketRef,
-        utils::{read_once, send_all},
         waker::WakerMode,
     },
     types,
@@ -154,8 +154,9 @@ pub fn run(fd: i32, dns: String, context: Context, running: Arc<AtomicBool>) ->
     let mut udp_server = UdpServer::new(pass.clone());
     let mut tcp_server = TcpServer::new(pass.clone());
 
-    let listener_addr = dns + ":53";
-    let listener_addr: SocketAddr = listener_addr.parse().unwrap();
+    // only the first of the servers the VPN handed out is answered here
+    let dns = dns.split(',').next().unwrap_or_default().trim();
+    let listener_addr = SocketAddr::new(dns.parse()?, 53);
     let mut sockets = Arc::new(SocketSet::new([]));
     let mut device = VpnDevice::new(
         session.clone(),
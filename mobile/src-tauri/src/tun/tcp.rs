@@ -7,20 +7,17 @@ use std::{
 
 use bytes::BytesMut;
 use mio::{event::Event, Poll, Token};
+use relay::{copy_stream, Break, CopyResult};
 use smoltcp::{iface::SocketHandle, socket::tcp::Socket};
 
-use crate::{
-    tun::{
-        device::VpnDevice,
-        idle_pool::IdlePool,
-        proto::{TrojanRequest, CONNECT},
-        resolver::DnsResolver,
-        tls_conn::TlsConn,
-        utils::copy_stream,
-        waker::WakerMode,
-        CHANNEL_CNT, CHANNEL_TCP, MAX_INDEX, MIN_INDEX,
-    },
-    types::{CopyResult, VpnError},
+use crate::tun::{
+    device::VpnDevice,
+    idle_pool::IdlePool,
+    proto::{TrojanRequest, CONNECT},
+    resolver::DnsResolver,
+    tls_conn::TlsConn,
+    waker::WakerMode,
+    CHANNEL_CNT, CHANNEL_TCP, MAX_INDEX, MIN_INDEX,
 };
 
 pub struct TcpStreamRef<'a, 'b> {
@@ -188,15 +185,14 @@ impl Connection {
         match copy_stream(&mut local, &mut self.remote, &mut self.rbuffer) {
             Ok(CopyResult::TxBlock) => log::info!("remote sending blocked"),
             Ok(CopyResult::RxBlock) => log::info!("local reading blocked"),
-            Err(VpnError::RxBreak(err)) => {
+            Err(Break::Rx(err)) => {
                 log::info!("local break with error:{:?}", err);
                 self.close_stream(true, device, poll)
             }
-            Err(VpnError::TxBreak(err)) => {
+            Err(Break::Tx(err)) => {
                 log::info!("remote break with err:{:?}", err);
                 self.close_stream(false, device, poll)
             }
-            _ => unreachable!(),
         }
         if !self.rclosed {
             self.flush_remote(device, poll);
@@ -258,15 +254,14 @@ impl Connection {
         match ret {
             Ok(CopyResult::RxBlock) => log::info!("remote reading blocked"),
             Ok(CopyResult::TxBlock) => log::info!("local sending blocked"),
-            Err(VpnError::RxBreak(err)) => {
+            Err(Break::Rx(err)) => {
                 log::info!("remote connection break with:{:?}", err);
                 self.close_stream(false, device, poll);
             }
-            Err(VpnError::TxBreak(err)) => {
+            Err(Break::Tx(err)) => {
                 log::info!("local connection break with:{:?}", err);
                 self.close_stream(true, device, poll)
             }
-            _ => unreachable!(),
         }
         //smoltcp sending is asynchronous, so send queue should be checked.
         if self.rclosed && !self.lclosed && self.lbuffer.is_empty() && send_size == 0 {
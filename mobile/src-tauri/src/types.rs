@@ -16,20 +16,12 @@ pub enum VpnError {
     Rustls(rustls::Error),
     AddrParse(std::net::AddrParseError),
     DnsProto(trust_dns_proto::error::ProtoError),
-    #[from(ignore)]
-    TxBreak(Option<std::io::Error>),
-    #[from(ignore)]
-    RxBreak(Option<std::io::Error>),
     Resolve,
     InvalidDnsName(rustls::client::InvalidDnsNameError),
     Smoltcp(smoltcp::wire::Error),
     Json(serde_json::Error),
-}
-
-#[allow(dead_code)]
-pub enum CopyResult {
-    RxBlock,
-    TxBlock,
+    Nul(std::ffi::NulError),
+    Keystore,
 }
 
 #[derive(Serialize, Clone)]
@@ -41,10 +33,19 @@ pub enum VpnStatus {
     NetworkLost,
 }
 
+/// A problem found by the self check or stopping the vpn process, shown by the frontend as is.
+#[derive(Serialize, Clone)]
+pub struct Diagnostic {
+    pub check: &'static str,
+    pub error: String,
+}
+
 pub enum EventType {
     StatusChanged,
     PermissionResult,
     UpdateSpeed,
+    LockdownChanged,
+    Diagnostic,
 }
 
 impl EventType {
@@ -53,6 +54,8 @@ impl EventType {
             EventType::StatusChanged => "on_status_changed",
             EventType::PermissionResult => "on_permission_result",
             EventType::UpdateSpeed => "update_speed",
+            EventType::LockdownChanged => "on_lockdown_changed",
+            EventType::Diagnostic => "on_diagnostic",
         }
     }
 }
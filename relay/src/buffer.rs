@@ -0,0 +1,238 @@
+use std::io::{self, ErrorKind, Read, Write};
+
+use bytes::{Buf, BytesMut};
+
+/// A side of a non-blocking relay that went away, with the error if it was not a clean close.
+#[derive(Debug)]
+pub enum Break {
+    Tx(Option<io::Error>),
+    Rx(Option<io::Error>),
+}
+
+pub type Result<T> = std::result::Result<T, Break>;
+
+/// The side a [`copy_stream`] waits for.
+pub enum CopyResult {
+    RxBlock,
+    TxBlock,
+}
+
+/// Where [`pump`] puts what it reads.
+pub trait Sink {
+    /// Takes `data`, false if it can't, which stops the pump.
+    fn push(&mut self, data: &[u8]) -> bool;
+
+    /// Whether the sink is backed up, reading stops until it drains.
+    fn blocked(&self) -> bool;
+}
+
+/// Moves from `from` to `to` through `buffer` until one of them would block.
+pub fn copy_stream(
+    from: &mut impl Read,
+    to: &mut impl Write,
+    buffer: &mut BytesMut,
+) -> Result<CopyResult> {
+    loop {
+        if !send_all(to, buffer)? {
+            return Ok(CopyResult::TxBlock);
+        }
+        if !read_once(from, buffer)? {
+            return Ok(CopyResult::RxBlock);
+        }
+    }
+}
+
+/// Sends what is in `buffer`, false if the writer blocked before all of it was sent.
+pub fn send_all(writer: &mut impl Write, buffer: &mut BytesMut) -> Result<bool> {
+    if buffer.is_empty() {
+        return Ok(true);
+    }
+    log::debug!("start sending {} bytes data", buffer.len());
+    let mut data = buffer.as_ref();
+    let mut offset = 0;
+    let mut ret = Ok(true);
+    while !data.is_empty() {
+        ret = match writer.write(data) {
+            Ok(0) => Err(Break::Tx(None)),
+            Ok(n) => {
+                log::debug!("sent {} bytes", n);
+                offset += n;
+                data = &data[n..];
+                continue;
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(Break::Tx(Some(err))),
+        };
+        break;
+    }
+    if ret.is_err() {
+        buffer.clear();
+    } else if offset != 0 {
+        buffer.advance(offset);
+    }
+    ret
+}
+
+/// Reads once from `reader` and appends to `buffer`, false if the reader would block.
+pub fn read_once(reader: &mut impl Read, buffer: &mut BytesMut) -> Result<bool> {
+    buffer.reserve(1500);
+    let mut nb = buffer.split_off(buffer.len());
+    nb.resize(nb.capacity(), 0);
+    let ret = match reader.read(nb.as_mut()) {
+        Ok(0) => Err(Break::Rx(None)),
+        Ok(n) => {
+            log::debug!("read {} bytes", n);
+            nb.truncate(n);
+            Ok(true)
+        }
+        Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(false),
+        Err(err) => Err(Break::Rx(Some(err))),
+    };
+    if !matches!(ret, Ok(true)) {
+        nb.clear();
+    }
+    buffer.unsplit(nb);
+    ret
+}
+
+/// Writes `data`, keeping what the writer doesn't take right now in `kept` to be sent later.
+pub fn write_or_keep(writer: &mut impl Write, mut data: &[u8], kept: &mut BytesMut) -> Result<()> {
+    while !data.is_empty() {
+        match writer.write(data) {
+            Ok(0) => return Err(Break::Tx(None)),
+            Ok(n) => {
+                log::debug!("sent {} bytes", n);
+                data = &data[n..];
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                log::debug!("write blocked, remaining:{}", data.len());
+                kept.extend_from_slice(data);
+                break;
+            }
+            Err(err) => return Err(Break::Tx(Some(err))),
+        }
+    }
+    Ok(())
+}
+
+/// Reads from `reader` into `sink` through `buffer` until the reader would block or the sink
+/// backs up, returns the bytes moved.
+pub fn pump(reader: &mut impl Read, buffer: &mut [u8], sink: &mut impl Sink) -> Result<usize> {
+    let mut total = 0;
+    loop {
+        match reader.read(buffer) {
+            Ok(0) => return Err(Break::Rx(None)),
+            Ok(n) => {
+                log::debug!("read {} bytes", n);
+                total += n;
+                if !sink.push(&buffer[..n]) || sink.blocked() {
+                    break;
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+            Err(err) => return Err(Break::Rx(Some(err))),
+        }
+    }
+    Ok(total)
+}
+
+mod tests {
+    #[test]
+    fn test_nonblocking() {
+        use std::io::{Cursor, ErrorKind, Read, Write};
+
+        use bytes::BytesMut;
+
+        use super::{copy_stream, pump, write_or_keep, Break, CopyResult, Sink};
+
+        /// Takes `room` bytes before blocking.
+        struct Limited {
+            data: Vec<u8>,
+            room: usize,
+        }
+
+        impl Write for Limited {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                if self.room == 0 {
+                    return Err(ErrorKind::WouldBlock.into());
+                }
+                let n = buf.len().min(self.room);
+                self.room -= n;
+                self.data.extend_from_slice(&buf[..n]);
+                Ok(n)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        impl Sink for Limited {
+            fn push(&mut self, data: &[u8]) -> bool {
+                self.data.extend_from_slice(data);
+                true
+            }
+
+            fn blocked(&self) -> bool {
+                self.data.len() >= self.room
+            }
+        }
+
+        /// Hands out `chunks`, then blocks.
+        struct Chunks(Vec<&'static [u8]>);
+
+        impl Read for Chunks {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if self.0.is_empty() {
+                    return Err(ErrorKind::WouldBlock.into());
+                }
+                let chunk = self.0.remove(0);
+                buf[..chunk.len()].copy_from_slice(chunk);
+                Ok(chunk.len())
+            }
+        }
+
+        let mut to = Limited {
+            data: Vec::new(),
+            room: 4,
+        };
+        let mut buffer = BytesMut::new();
+        let ret = copy_stream(&mut Chunks(vec![b"hello"]), &mut to, &mut buffer);
+        assert!(matches!(ret, Ok(CopyResult::TxBlock)));
+        assert_eq!(
+            (to.data.as_slice(), buffer.as_ref()),
+            (&b"hell"[..], &b"o"[..])
+        );
+        to.room = 10;
+        let ret = copy_stream(&mut Chunks(vec![b" world"]), &mut to, &mut buffer);
+        assert!(matches!(ret, Ok(CopyResult::RxBlock)));
+        assert_eq!(to.data, b"hello world");
+        assert!(buffer.is_empty());
+        let ret = copy_stream(&mut Cursor::new(Vec::new()), &mut to, &mut buffer);
+        assert!(matches!(ret, Err(Break::Rx(None))));
+
+        let mut to = Limited {
+            data: Vec::new(),
+            room: 3,
+        };
+        let mut kept = BytesMut::new();
+        write_or_keep(&mut to, b"hello", &mut kept).unwrap();
+        assert_eq!(
+            (to.data.as_slice(), kept.as_ref()),
+            (&b"hel"[..], &b"lo"[..])
+        );
+
+        let mut sink = Limited {
+            data: Vec::new(),
+            room: 6,
+        };
+        let mut buffer = [0u8; 16];
+        let mut reader = Chunks(vec![b"abc", b"def", b"ghi"]);
+        assert_eq!(pump(&mut reader, &mut buffer, &mut sink).unwrap(), 6);
+        assert_eq!(sink.data, b"abcdef");
+        sink.room = 100;
+        assert_eq!(pump(&mut reader, &mut buffer, &mut sink).unwrap(), 3);
+        let ret = pump(&mut Cursor::new(Vec::new()), &mut buffer, &mut sink);
+        assert!(matches!(ret, Err(Break::Rx(None))));
+    }
+}
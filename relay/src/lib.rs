@@ -0,0 +1,14 @@
+//! Relaying of byte streams between a local endpoint and the server, shared by the client and
+//! server modes so a fix to the copying lands everywhere at once.
+//!
+//! * [`copy`] moves one direction between async endpoints, the modes plug in rate limiting,
+//!   timing jitter and traffic accounting through [`Hooks`]
+//! * [`pump`], [`copy_stream`], [`send_all`], [`read_once`] and [`write_or_keep`] do the same
+//!   for the non-blocking endpoints of the poll based modes, they stop when a side would block
+//!   and leave it to the caller to come back once it is ready
+
+pub use buffer::{copy_stream, pump, read_once, send_all, write_or_keep, Break, CopyResult, Sink};
+pub use stream::{copy, Closed, Hooks, Options};
+
+mod buffer;
+mod stream;
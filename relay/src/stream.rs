@@ -0,0 +1,192 @@
+use std::{fmt, future::Future, io, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::timeout,
+};
+
+const BUFFER_SIZE: usize = 4096;
+
+/// Extension points of a relay direction, all of them do nothing by default.
+pub trait Hooks: Sync {
+    /// Reads more into `buffer` after the first `n` bytes before they are written, returns the
+    /// new length.
+    fn gather<R: AsyncRead + Unpin + Send>(
+        &self,
+        _read: &mut R,
+        _buffer: &mut [u8],
+        n: usize,
+    ) -> impl Future<Output = usize> + Send {
+        async move { n }
+    }
+
+    /// Waits until `size` bytes may be written.
+    fn acquire(&self, _size: usize) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Called with the size of every chunk written.
+    fn on_data(&self, _size: usize) {}
+}
+
+/// A plain copy.
+impl Hooks for () {}
+
+/// Timeouts of a relay direction.
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    idle_timeout: Duration,
+    write_timeout: Option<Duration>,
+}
+
+impl Options {
+    /// Stops once nothing is read for `idle_timeout`.
+    pub fn new(idle_timeout: Duration) -> Options {
+        Options {
+            idle_timeout,
+            write_timeout: None,
+        }
+    }
+
+    /// Also stops when writing a chunk takes longer than `write_timeout`.
+    pub fn write_timeout(mut self, write_timeout: Duration) -> Options {
+        self.write_timeout.replace(write_timeout);
+        self
+    }
+}
+
+/// Why a relay direction stopped.
+#[derive(Debug)]
+pub enum Closed {
+    /// The reader reached its end.
+    Eof,
+    /// Nothing was read within the idle timeout.
+    Idle,
+    Read(io::Error),
+    Write(io::Error),
+}
+
+impl fmt::Display for Closed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Closed::Eof => write!(f, "read shutdown"),
+            Closed::Idle => write!(f, "idle timeout"),
+            Closed::Read(err) => write!(f, "read failed:{}", err),
+            Closed::Write(err) => write!(f, "write failed:{}", err),
+        }
+    }
+}
+
+/// Copies from `read` to `write` until either side stops, then shuts `write` down.
+pub async fn copy<R, W, H>(mut read: R, mut write: W, options: Options, hooks: &H) -> Closed
+where
+    R: AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin,
+    H: Hooks,
+{
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let closed = loop {
+        let n = match timeout(options.idle_timeout, read.read(buffer.as_mut_slice())).await {
+            Ok(Ok(0)) => break Closed::Eof,
+            Ok(Ok(n)) => n,
+            Ok(Err(err)) => break Closed::Read(err),
+            Err(_) => break Closed::Idle,
+        };
+        let n = hooks.gather(&mut read, buffer.as_mut_slice(), n).await;
+        hooks.acquire(n).await;
+        let data = &buffer.as_slice()[..n];
+        let written = match options.write_timeout {
+            Some(limit) => timeout(limit, write.write_all(data))
+                .await
+                .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+            None => write.write_all(data).await,
+        };
+        if let Err(err) = written {
+            break Closed::Write(err);
+        }
+        hooks.on_data(n);
+    };
+    let _ = write.shutdown().await;
+    closed
+}
+
+mod tests {
+    #[tokio::test]
+    async fn test_copy() {
+        use std::{
+            sync::atomic::{AtomicUsize, Ordering},
+            time::Duration,
+        };
+
+        use tokio::io::{duplex, AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+        use super::{copy, Closed, Hooks, Options};
+
+        struct Counter(AtomicUsize, AtomicUsize);
+
+        impl Hooks for Counter {
+            async fn gather<R: AsyncRead + Unpin + Send>(
+                &self,
+                read: &mut R,
+                buffer: &mut [u8],
+                mut n: usize,
+            ) -> usize {
+                // pick up whatever else is there right away
+                while n < buffer.len() {
+                    let more = read.read(&mut buffer[n..]);
+                    match tokio::time::timeout(Duration::from_millis(10), more).await {
+                        Ok(Ok(size)) if size > 0 => n += size,
+                        _ => break,
+                    }
+                }
+                n
+            }
+
+            async fn acquire(&self, _size: usize) {
+                self.1.fetch_add(1, Ordering::SeqCst);
+            }
+
+            fn on_data(&self, size: usize) {
+                self.0.fetch_add(size, Ordering::SeqCst);
+            }
+        }
+
+        let (mut client, read) = duplex(1024);
+        let (write, mut server) = duplex(65536);
+        let counter = Counter(AtomicUsize::new(0), AtomicUsize::new(0));
+        let options = Options::new(Duration::from_secs(5));
+        let relay = copy(read, write, options, &counter);
+        let feed = async {
+            for _ in 0..10 {
+                client.write_all(&[7u8; 1000]).await.unwrap();
+            }
+            client.shutdown().await.unwrap();
+        };
+        let (closed, _) = tokio::join!(relay, feed);
+        assert!(matches!(closed, Closed::Eof));
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, vec![7u8; 10000]);
+        assert_eq!(counter.0.load(Ordering::SeqCst), 10000);
+        // chunks were gathered up to the buffer size
+        assert!(counter.1.load(Ordering::SeqCst) < 10);
+
+        let (_client, read) = duplex(1024);
+        let (write, _server) = duplex(1024);
+        let options = Options::new(Duration::from_millis(50));
+        assert!(matches!(
+            copy(read, write, options, &()).await,
+            Closed::Idle
+        ));
+
+        let (mut client, read) = duplex(1024);
+        let (write, server) = duplex(1024);
+        drop(server);
+        client.write_all(b"hello").await.unwrap();
+        let options = Options::new(Duration::from_secs(5)).write_timeout(Duration::from_secs(1));
+        assert!(matches!(
+            copy(read, write, options, &()).await,
+            Closed::Write(_)
+        ));
+    }
+}
@@ -0,0 +1,101 @@
+//! Global allocator picked by the cargo features, and hooks to look into the heap of long
+//! running servers.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{sys, types::Result};
+
+#[cfg(all(feature = "jemalloc", feature = "mimalloc"))]
+compile_error!("features jemalloc and mimalloc can't be enabled together");
+
+#[cfg(all(feature = "jemalloc", not(windows)))]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[cfg(feature = "mimalloc")]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+static LAST_STATS: Mutex<Option<Instant>> = Mutex::new(None);
+
+fn name() -> &'static str {
+    if cfg!(all(feature = "jemalloc", not(windows))) {
+        "jemalloc"
+    } else if cfg!(feature = "mimalloc") {
+        "mimalloc"
+    } else {
+        "system"
+    }
+}
+
+/// Dumps the heap on SIGUSR1 from now on.
+pub fn start() -> Result<()> {
+    log::warn!("allocator:{}", name());
+    sys::watch_heap_dump()?;
+    Ok(())
+}
+
+/// Logs the heap statistics every minute, and dumps a heap profile if asked to since the last
+/// call.
+pub fn check() {
+    if sys::heap_dump_requested() {
+        log_stats();
+        dump_profile();
+        return;
+    }
+    let mut last = LAST_STATS.lock().unwrap();
+    if last.is_none_or(|time| time.elapsed() > STATS_INTERVAL) {
+        last.replace(Instant::now());
+        log_stats();
+    }
+}
+
+#[cfg(all(feature = "jemalloc", not(windows)))]
+fn log_stats() {
+    use tikv_jemalloc_ctl::{epoch, stats};
+
+    // the statistics are cached until the epoch is advanced
+    if epoch::advance().is_err() {
+        return;
+    }
+    let (Ok(allocated), Ok(resident), Ok(retained)) = (
+        stats::allocated::read(),
+        stats::resident::read(),
+        stats::retained::read(),
+    ) else {
+        return;
+    };
+    log::info!(
+        "heap allocated:{} resident:{} retained:{} fragmentation:{:.1}%",
+        allocated,
+        resident,
+        retained,
+        resident.saturating_sub(allocated) as f64 * 100.0 / resident.max(1) as f64
+    );
+}
+
+#[cfg(not(all(feature = "jemalloc", not(windows))))]
+fn log_stats() {}
+
+/// Writes a profile for jeprof, named after the `prof_prefix` of _RJEM_MALLOC_CONF.
+#[cfg(all(feature = "jemalloc-profiling", not(windows)))]
+fn dump_profile() {
+    // a null file name has jemalloc pick one
+    match unsafe { tikv_jemalloc_ctl::raw::write(b"prof.dump\0", std::ptr::null::<u8>()) } {
+        Ok(_) => log::warn!("heap profile dumped"),
+        Err(err) => log::error!(
+            "dump heap profile failed:{}, is _RJEM_MALLOC_CONF=prof:true set?",
+            err
+        ),
+    }
+}
+
+#[cfg(not(all(feature = "jemalloc-profiling", not(windows))))]
+fn dump_profile() {
+    log::warn!("heap profiles need a build with the jemalloc-profiling feature");
+}
@@ -0,0 +1,134 @@
+use std::{
+    net::{IpAddr, SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use igd_next::{
+    aio::{
+        tokio::{search_gateway, Tokio},
+        Gateway,
+    },
+    PortMappingProtocol, SearchOptions,
+};
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use tokio::{spawn, task::JoinHandle};
+
+use crate::{
+    config::OPTIONS,
+    types::{Result, TrojanError},
+};
+
+const SERVICE_TYPE: &str = "_socks._tcp.local.";
+const LEASE_SECONDS: u32 = 3600;
+
+/// LAN address of this machine, the one used to reach the default gateway.
+fn lan_ip(listen: SocketAddr) -> Result<IpAddr> {
+    if !listen.ip().is_unspecified() {
+        return Ok(listen.ip());
+    }
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect("8.8.8.8:53")?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// The inbound port mapped on the router via UPnP, until `remove` is called.
+pub struct UpnpMapping {
+    port: u16,
+    /// the gateway holding the mapping, once it is added
+    gateway: Arc<Mutex<Option<Gateway<Tokio>>>>,
+    task: JoinHandle<()>,
+}
+
+impl UpnpMapping {
+    /// Starts mapping the port, refused without --inbound-auth as the listener would be open
+    /// to the internet.
+    pub fn start(listen: SocketAddr) -> Result<UpnpMapping> {
+        if OPTIONS.proxy_args().inbound_auth.is_empty() {
+            return Err(TrojanError::Inbound("upnp requires --inbound-auth"));
+        }
+        let gateway = Arc::new(Mutex::new(None));
+        let task = spawn(run_upnp(listen, gateway.clone()));
+        Ok(UpnpMapping {
+            port: listen.port(),
+            gateway,
+            task,
+        })
+    }
+
+    /// Stops renewing the lease and removes the mapping from the router.
+    pub async fn remove(self) {
+        self.task.abort();
+        let gateway = self.gateway.lock().unwrap().take();
+        if let Some(gateway) = gateway {
+            match gateway
+                .remove_port(PortMappingProtocol::TCP, self.port)
+                .await
+            {
+                Ok(()) => log::warn!("upnp mapping of port {} removed", self.port),
+                Err(err) => log::error!("upnp remove port mapping failed:{}", err),
+            }
+        }
+    }
+}
+
+/// Keep the inbound port mapped on the router via UPnP, renewing the lease periodically.
+async fn run_upnp(listen: SocketAddr, mapped: Arc<Mutex<Option<Gateway<Tokio>>>>) {
+    let local_addr = match lan_ip(listen) {
+        Ok(ip) => SocketAddr::new(ip, listen.port()),
+        Err(err) => {
+            log::error!("upnp find lan address failed:{:?}", err);
+            return;
+        }
+    };
+    loop {
+        match search_gateway(SearchOptions::default()).await {
+            Ok(gateway) => {
+                match gateway
+                    .add_port(
+                        PortMappingProtocol::TCP,
+                        local_addr.port(),
+                        local_addr,
+                        LEASE_SECONDS,
+                        "trojan socks5/http",
+                    )
+                    .await
+                {
+                    Ok(()) => {
+                        log::info!(
+                            "upnp mapped port {} to {} on {}",
+                            local_addr.port(),
+                            local_addr,
+                            gateway
+                        );
+                        mapped.lock().unwrap().replace(gateway);
+                    }
+                    Err(err) => log::error!("upnp add port mapping failed:{}", err),
+                }
+            }
+            Err(err) => log::error!("upnp gateway not found:{}", err),
+        }
+        tokio::time::sleep(Duration::from_secs(LEASE_SECONDS as u64 / 2)).await;
+    }
+}
+
+/// Advertise the inbound listener as `_socks._tcp` via mDNS, until the daemon is dropped.
+pub fn advertise_mdns(listen: SocketAddr) -> Result<ServiceDaemon> {
+    let ip = lan_ip(listen)?;
+    let hostname = dns_lookup::get_hostname()?;
+    let daemon = ServiceDaemon::new().map_err(|_| TrojanError::Inbound("mdns daemon failed"))?;
+    let service = ServiceInfo::new(
+        SERVICE_TYPE,
+        hostname.as_str(),
+        format!("{}.local.", hostname).as_str(),
+        ip,
+        listen.port(),
+        &[("protocol", "socks5,http")][..],
+    )
+    .map_err(|_| TrojanError::Inbound("invalid mdns service"))?;
+    daemon
+        .register(service)
+        .map_err(|_| TrojanError::Inbound("mdns register failed"))?;
+    log::warn!("advertising {}:{} as {}", ip, listen.port(), SERVICE_TYPE);
+    Ok(daemon)
+}
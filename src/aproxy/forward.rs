@@ -0,0 +1,279 @@
+//! Local ports forwarded through the server to fixed targets, like `ssh -L`.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use futures::future::BoxFuture;
+use tokio::{
+    io::{split, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    spawn,
+    sync::mpsc::{channel, error::TrySendError, Receiver, Sender},
+    time::{sleep_until, Instant},
+};
+use tokio_rustls::client::TlsStream;
+
+use crate::{
+    aproxy::tcp::{relay, Tunnel},
+    async_utils::AbortOnDrop,
+    config::{Forward, OPTIONS},
+    limiter::{Priority, DOWNLOAD, UPLOAD},
+    memory::{self, Subsystem},
+    metrics::{incr, COUNTERS},
+    proto::{
+        Sock5Address, TrojanRequest, UdpAssociate, UdpParseResult, MAX_PACKET_SIZE, UDP_ASSOCIATE,
+    },
+    types::{Result, TrojanError},
+};
+
+/// Datagrams of a client waiting for its association to the server, more are dropped.
+const QUEUE_SIZE: usize = 64;
+
+/// Opens a stream to the server for a forwarded tcp connection.
+pub type ConnectStream = Box<dyn Fn() -> BoxFuture<'static, Result<Box<dyn Tunnel>>> + Send + Sync>;
+/// Opens a tls connection to the server for a udp association.
+pub type ConnectTls =
+    Box<dyn Fn() -> BoxFuture<'static, Result<TlsStream<TcpStream>>> + Send + Sync>;
+
+struct Forwarder {
+    /// for tcp streams, which take the grpc or mux transport of aproxy if configured
+    connect_stream: ConnectStream,
+    connect_tls: ConnectTls,
+    /// by listen address and whether they are udp
+    running: Mutex<BTreeMap<(String, bool), Running>>,
+}
+
+/// A forward and its listener, which stops when dropped.
+struct Running {
+    forward: Forward,
+    _task: AbortOnDrop<()>,
+}
+
+static FORWARDER: OnceLock<Forwarder> = OnceLock::new();
+
+/// Starts the `--forward` ports, the events api adds and removes more from now on.
+pub fn start(forwards: &[Forward], connect_stream: ConnectStream, connect_tls: ConnectTls) {
+    let forwarder = Forwarder {
+        connect_stream,
+        connect_tls,
+        running: Mutex::new(BTreeMap::new()),
+    };
+    if FORWARDER.set(forwarder).is_err() {
+        return;
+    }
+    for forward in forwards {
+        if let Err(err) = add(forward.clone()) {
+            log::error!("forward {} failed:{:?}", forward.listen, err);
+        }
+    }
+}
+
+/// Forwards the port from now on, in place of the forward listening there before.
+pub fn add(forward: Forward) -> Result<()> {
+    let forwarder = FORWARDER.get().ok_or(TrojanError::Forward(
+        "port forwarding is only supported by aproxy and awintun",
+    ))?;
+    let mut running = forwarder.running.lock().unwrap();
+    let key = (forward.listen.clone(), forward.udp);
+    // the port is free again for the new forward
+    running.remove(&key);
+    let task = if forward.udp {
+        let socket = std::net::UdpSocket::bind(forward.listen.as_str())?;
+        socket.set_nonblocking(true)?;
+        spawn(forward_udp(UdpSocket::from_std(socket)?, forward.clone()))
+    } else {
+        let listener = std::net::TcpListener::bind(forward.listen.as_str())?;
+        listener.set_nonblocking(true)?;
+        spawn(forward_tcp(
+            TcpListener::from_std(listener)?,
+            forward.clone(),
+        ))
+    };
+    log::warn!(
+        "forwarding {} {} to {}",
+        if forward.udp { "udp" } else { "tcp" },
+        forward.listen,
+        forward.target
+    );
+    running.insert(
+        key,
+        Running {
+            forward,
+            _task: AbortOnDrop::new(task),
+        },
+    );
+    Ok(())
+}
+
+/// Stops the forward, connections open already keep going.
+pub fn remove(listen: &str, udp: bool) -> bool {
+    let Some(forwarder) = FORWARDER.get() else {
+        return false;
+    };
+    let removed = forwarder
+        .running
+        .lock()
+        .unwrap()
+        .remove(&(listen.to_string(), udp));
+    if removed.is_some() {
+        log::warn!("forward of {} stopped", listen);
+    }
+    removed.is_some()
+}
+
+pub fn list() -> Vec<Forward> {
+    FORWARDER.get().map_or(vec![], |forwarder| {
+        forwarder
+            .running
+            .lock()
+            .unwrap()
+            .values()
+            .map(|running| running.forward.clone())
+            .collect()
+    })
+}
+
+async fn forward_tcp(listener: TcpListener, forward: Forward) {
+    let forwarder = FORWARDER.get().unwrap();
+    loop {
+        let client = match listener.accept().await {
+            Ok((client, _)) => client,
+            Err(err) => {
+                log::error!("accept on {} failed:{}", forward.listen, err);
+                continue;
+            }
+        };
+        let _ = client.set_nodelay(true);
+        let (remote, target) = ((forwarder.connect_stream)(), forward.target_address());
+        spawn(async move {
+            if let Err(err) = async { relay(client, remote.await?, target).await }.await {
+                log::error!("forward tcp failed:{:?}", err);
+            }
+        });
+    }
+}
+
+/// Every client address gets its own association to the server.
+async fn forward_udp(socket: UdpSocket, forward: Forward) {
+    let socket = Arc::new(socket);
+    let mut clients: HashMap<SocketAddr, Sender<Vec<u8>>> = HashMap::new();
+    let mut datagram = vec![0u8; MAX_PACKET_SIZE];
+    loop {
+        let (size, client) = match socket.recv_from(datagram.as_mut_slice()).await {
+            Ok(received) => received,
+            Err(err) => {
+                log::warn!("receive on {} failed:{}", forward.listen, err);
+                continue;
+            }
+        };
+        let mut data = datagram[..size].to_vec();
+        if let Some(sender) = clients.get(&client) {
+            match sender.try_send(data) {
+                Ok(_) => continue,
+                Err(TrySendError::Full(_)) => {
+                    log::warn!("udp queue of {} is full, datagram dropped", client);
+                    continue;
+                }
+                Err(TrySendError::Closed(returned)) => data = returned,
+            }
+        }
+        clients.retain(|_, sender| !sender.is_closed());
+        let (sender, receiver) = channel(QUEUE_SIZE);
+        let _ = sender.try_send(data);
+        clients.insert(client, sender);
+        let (socket, target) = (socket.clone(), forward.target_address());
+        spawn(async move {
+            if let Err(err) = associate(socket, client, target, receiver).await {
+                log::error!("forward udp of {} failed:{:?}", client, err);
+            }
+        });
+    }
+}
+
+/// Relays the datagrams of one client through a trojan UDP_ASSOCIATE, until it's idle for the
+/// udp timeout or the forward is stopped.
+async fn associate(
+    socket: Arc<UdpSocket>,
+    client: SocketAddr,
+    target: Sock5Address,
+    mut datagrams: Receiver<Vec<u8>>,
+) -> Result<()> {
+    let Some(_memory) = memory::reserve(Subsystem::Udp, memory::UDP_SESSION)
+        .and_then(|memory| memory.and(Subsystem::Tls, memory::TLS_SESSION))
+    else {
+        return Ok(());
+    };
+    let forwarder = FORWARDER.get().unwrap();
+    let mut remote = (forwarder.connect_tls)().await?;
+    let mut frame = BytesMut::new();
+    TrojanRequest::generate(
+        &mut frame,
+        UDP_ASSOCIATE,
+        &SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+    );
+    remote.write_all(frame.as_ref()).await?;
+
+    let (mut remote_read, mut remote_write) = split(remote);
+    let mut buffer = BytesMut::new();
+    let mut deadline = Instant::now() + OPTIONS.udp_idle_duration;
+    loop {
+        tokio::select! {
+            datagram = datagrams.recv() => {
+                let Some(datagram) = datagram else {
+                    break;
+                };
+                frame.clear();
+                Sock5Address::generate_address(&mut frame, &target);
+                frame.put_u16(datagram.len() as u16);
+                frame.put_slice(b"\r\n");
+                frame.put_slice(datagram.as_slice());
+                UPLOAD
+                    .acquire(datagram.len(), Priority::of_packet(target.port(), datagram.len()))
+                    .await;
+                if remote_write.write_all(frame.as_ref()).await.is_err() {
+                    incr(&COUNTERS.udp_remote_failed);
+                    break;
+                }
+                deadline = Instant::now() + OPTIONS.udp_idle_duration;
+            }
+            ret = remote_read.read_buf(&mut buffer) => {
+                if !matches!(ret, Ok(n) if n > 0) {
+                    break;
+                }
+                loop {
+                    match UdpAssociate::parse(buffer.as_ref()) {
+                        UdpParseResult::Continued => break,
+                        UdpParseResult::Packet(packet) => {
+                            let payload = &packet.payload[..packet.length];
+                            DOWNLOAD
+                                .acquire(
+                                    payload.len(),
+                                    Priority::of_packet(packet.address.port(), payload.len()),
+                                )
+                                .await;
+                            if socket.send_to(payload, client).await.is_err() {
+                                incr(&COUNTERS.udp_local_failed);
+                            }
+                            buffer.advance(packet.offset);
+                        }
+                        UdpParseResult::InvalidProtocol => {
+                            incr(&COUNTERS.udp_invalid_protocol);
+                            return Err(TrojanError::Forward("invalid udp frame from server"));
+                        }
+                    }
+                }
+                deadline = Instant::now() + OPTIONS.udp_idle_duration;
+            }
+            _ = sleep_until(deadline) => {
+                log::info!("udp forward of {} idle", client);
+                break;
+            }
+        }
+    }
+    let _ = remote_write.shutdown().await;
+    Ok(())
+}
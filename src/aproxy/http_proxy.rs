@@ -0,0 +1,545 @@
+use std::net::{IpAddr, SocketAddr};
+
+use rustls_pki_types::ServerName;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::timeout,
+};
+use tokio_rustls::TlsConnector;
+
+use crate::{
+    aproxy::{
+        inbound::{locate, Policy, MAX_HTTP_HEADER},
+        tcp::{open_tunnel, Tunnel},
+    },
+    config::{Outbound, OPTIONS},
+    memory::{self, Subsystem},
+    nat64,
+    proto::Sock5Address,
+    types::{Result, TrojanError},
+};
+
+/// Headers for the proxy itself, dropped along with the ones Connection names.
+/// Transfer-Encoding stays, as bodies are passed on as they are.
+const HOP_BY_HOP: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+    "expect",
+];
+
+/// A connection to an origin server, kept for the next request to the same host.
+type Upstream = BufReader<Box<dyn Tunnel>>;
+
+/// How the end of a body is found.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Body {
+    None,
+    Length(u64),
+    Chunked,
+    UntilClose,
+}
+
+/// A client request rewritten for the origin server.
+#[derive(Debug)]
+struct Request {
+    head: Vec<u8>,
+    host: String,
+    port: u16,
+    body: Body,
+    /// the client wants the connection kept open
+    keep_alive: bool,
+    /// the client waits for a 100 Continue before sending the body
+    expect_continue: bool,
+    head_method: bool,
+}
+
+/// An origin server response rewritten for the client.
+#[derive(Debug)]
+struct Response {
+    head: Vec<u8>,
+    body: Body,
+    /// a 1xx response, the final one follows
+    interim: bool,
+    /// the client connection stays open for the next request
+    client_alive: bool,
+    /// the server connection may take the next request to the same host
+    server_alive: bool,
+}
+
+/// Proxies plain http requests of a client one after another, `head` is the first one. The
+/// credentials are checked on the first request only, the connection is trusted after it.
+pub(super) async fn serve(
+    client: TcpStream,
+    mut head: Vec<u8>,
+    policy: Policy,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+) -> Result<()> {
+    let mut client = BufReader::new(client);
+    let Some(_memory) = memory::reserve(Subsystem::Tls, memory::TLS_SESSION) else {
+        client
+            .write_all(b"HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\n\r\n")
+            .await?;
+        return Ok(());
+    };
+    let mut server: Option<(String, u16, Upstream)> = None;
+    loop {
+        let request = match parse_request(head.as_slice()) {
+            Ok(request) => request,
+            Err(err) => {
+                client
+                    .write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n")
+                    .await?;
+                return Err(err);
+            }
+        };
+        log::info!("inbound http request to {}:{}", request.host, request.port);
+        let mut remote = match server.take() {
+            Some((host, port, remote)) if host == request.host && port == request.port => remote,
+            _ => {
+                let policy = policy.route(&to_address(request.host.clone(), request.port));
+                if policy.outbound == Outbound::Block {
+                    client
+                        .write_all(b"HTTP/1.1 403 Forbidden\r\nConnection: close\r\n\r\n")
+                        .await?;
+                    return Err(TrojanError::Inbound("blocked by routing rule"));
+                }
+                match open(&request, policy, &server_name, &connector).await {
+                    Ok(remote) => BufReader::new(remote),
+                    Err(err) => {
+                        client
+                            .write_all(b"HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\n\r\n")
+                            .await?;
+                        return Err(err);
+                    }
+                }
+            }
+        };
+        remote.write_all(request.head.as_slice()).await?;
+        if request.expect_continue && request.body != Body::None {
+            client.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+        }
+        copy_body(&mut client, &mut remote, request.body).await?;
+        remote.flush().await?;
+
+        let response = loop {
+            let head = timeout(OPTIONS.tcp_idle_duration, read_head(&mut remote))
+                .await
+                .map_err(|_| TrojanError::Inbound("http response timeout"))??
+                .ok_or(TrojanError::Inbound("http server closed"))?;
+            let response = parse_response(head.as_slice(), &request)?;
+            client.write_all(response.head.as_slice()).await?;
+            if !response.interim {
+                break response;
+            }
+        };
+        copy_body(&mut remote, &mut client, response.body).await?;
+        client.flush().await?;
+        if !response.client_alive {
+            let _ = client.shutdown().await;
+            return Ok(());
+        }
+        if response.server_alive {
+            server = Some((request.host, request.port, remote));
+        }
+        head = match timeout(OPTIONS.tcp_idle_duration, read_head(&mut client)).await {
+            Ok(Ok(Some(head))) => head,
+            Ok(Ok(None)) | Err(_) => return Ok(()),
+            Ok(Err(err)) => return Err(err),
+        };
+    }
+}
+
+async fn open(
+    request: &Request,
+    policy: Policy,
+    server_name: &ServerName<'static>,
+    connector: &TlsConnector,
+) -> Result<Box<dyn Tunnel>> {
+    let address = to_address(request.host.clone(), request.port);
+    match (policy.outbound, locate(address, policy).await?) {
+        (Outbound::Direct, Sock5Address::Socket(addr)) => {
+            let remote: Box<dyn Tunnel> =
+                Box::new(TcpStream::connect(nat64::translate(addr)).await?);
+            Ok(remote)
+        }
+        (_, address) => open_tunnel(server_name.clone(), connector.clone(), &address).await,
+    }
+}
+
+/// Reads a head up to the empty line, None if the peer closed before sending one.
+async fn read_head<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut head = Vec::new();
+    loop {
+        let start = head.len();
+        let limit = (MAX_HTTP_HEADER + 1 - start) as u64;
+        let n = (&mut *reader)
+            .take(limit)
+            .read_until(b'\n', &mut head)
+            .await?;
+        if head.len() > MAX_HTTP_HEADER {
+            return Err(TrojanError::Inbound("http head too large"));
+        }
+        if n == 0 {
+            return if head.is_empty() {
+                Ok(None)
+            } else {
+                Err(TrojanError::Inbound("http head truncated"))
+            };
+        }
+        if matches!(&head[start..], b"\r\n" | b"\n") {
+            // empty lines before a request are allowed
+            if start == 0 {
+                head.clear();
+                continue;
+            }
+            return Ok(Some(head));
+        }
+    }
+}
+
+async fn copy_body<R, W>(reader: &mut R, writer: &mut W, body: Body) -> Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match body {
+        Body::None => {}
+        Body::Length(length) => copy_exact(reader, writer, length).await?,
+        Body::UntilClose => {
+            tokio::io::copy_buf(reader, writer).await?;
+        }
+        Body::Chunked => loop {
+            let line = read_line(reader).await?;
+            writer.write_all(line.as_slice()).await?;
+            let size = std::str::from_utf8(line.as_slice())
+                .ok()
+                .and_then(|line| line.split(';').next())
+                .and_then(|size| u64::from_str_radix(size.trim(), 16).ok())
+                .ok_or(TrojanError::Inbound("invalid http chunk size"))?;
+            if size > 0 {
+                // the chunk and its CRLF
+                copy_exact(reader, writer, size + 2).await?;
+                continue;
+            }
+            // trailers up to the empty line
+            loop {
+                let line = read_line(reader).await?;
+                writer.write_all(line.as_slice()).await?;
+                if matches!(line.as_slice(), b"\r\n" | b"\n") {
+                    return Ok(());
+                }
+            }
+        },
+    }
+    Ok(())
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut line = Vec::new();
+    (&mut *reader)
+        .take(MAX_HTTP_HEADER as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if !line.ends_with(b"\n") {
+        return Err(TrojanError::Inbound("invalid http chunk"));
+    }
+    Ok(line)
+}
+
+async fn copy_exact<R, W>(reader: &mut R, writer: &mut W, length: u64) -> Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let copied = tokio::io::copy_buf(&mut (&mut *reader).take(length), writer).await?;
+    if copied != length {
+        return Err(TrojanError::Inbound("http body truncated"));
+    }
+    Ok(())
+}
+
+/// Rewrites an absolute-form request for the origin server as the last one on its connection,
+/// for relays passing everything after it as it is. Returns the head and the origin's address.
+pub fn rewrite_once(raw: &[u8]) -> Result<(Vec<u8>, Sock5Address)> {
+    let Request {
+        mut head,
+        host,
+        port,
+        ..
+    } = parse_request(raw)?;
+    head.truncate(head.len() - 2);
+    head.extend_from_slice(b"Connection: close\r\n\r\n");
+    Ok((head, to_address(host, port)))
+}
+
+pub fn to_address(host: String, port: u16) -> Sock5Address {
+    match host.parse::<IpAddr>() {
+        Ok(ip) => Sock5Address::Socket(SocketAddr::new(ip, port)),
+        Err(_) => Sock5Address::Domain(host, port),
+    }
+}
+
+fn parse_request(raw: &[u8]) -> Result<Request> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut request = httparse::Request::new(&mut headers);
+    let Ok(httparse::Status::Complete(_)) = request.parse(raw) else {
+        return Err(TrojanError::Inbound("invalid http request"));
+    };
+    let (Some(method), Some(target), Some(version)) =
+        (request.method, request.path, request.version)
+    else {
+        return Err(TrojanError::Inbound("invalid http request"));
+    };
+    let (authority, path) =
+        split_absolute(target).ok_or(TrojanError::Inbound("http request not in absolute form"))?;
+    let (host, port) =
+        split_authority(authority).ok_or(TrojanError::Inbound("invalid http request host"))?;
+    let body = body_of(request.headers, Body::None)?;
+    if body == Body::UntilClose {
+        return Err(TrojanError::Inbound(
+            "invalid http request transfer encoding",
+        ));
+    }
+    let tokens = connection_tokens(request.headers);
+    let mut head = format!(
+        "{} {} HTTP/1.{}\r\nHost: {}\r\n",
+        method, path, version, authority
+    )
+    .into_bytes();
+    // Host is replaced by the authority of the target
+    put_headers(&mut head, request.headers, &tokens, &["host"]);
+    head.extend_from_slice(b"\r\n");
+    Ok(Request {
+        head,
+        host,
+        port,
+        body,
+        keep_alive: persistent(version, &tokens),
+        expect_continue: header(request.headers, "Expect")
+            .is_some_and(|value| value.trim().eq_ignore_ascii_case("100-continue")),
+        head_method: method == "HEAD",
+    })
+}
+
+fn parse_response(raw: &[u8], request: &Request) -> Result<Response> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut response = httparse::Response::new(&mut headers);
+    let Ok(httparse::Status::Complete(_)) = response.parse(raw) else {
+        return Err(TrojanError::Inbound("invalid http response"));
+    };
+    let (Some(code), Some(version)) = (response.code, response.version) else {
+        return Err(TrojanError::Inbound("invalid http response"));
+    };
+    if code == 101 {
+        // Upgrade is never passed on, so a server switching protocols is broken
+        return Err(TrojanError::Inbound("unexpected http protocol switch"));
+    }
+    let interim = (100..200).contains(&code);
+    let body = if interim || code == 204 || code == 304 || request.head_method {
+        Body::None
+    } else {
+        body_of(response.headers, Body::UntilClose)?
+    };
+    let tokens = connection_tokens(response.headers);
+    let client_alive = request.keep_alive && body != Body::UntilClose;
+    let mut head = format!(
+        "HTTP/1.{} {} {}\r\n",
+        version,
+        code,
+        response.reason.unwrap_or_default()
+    )
+    .into_bytes();
+    put_headers(&mut head, response.headers, &tokens, &[]);
+    if !interim {
+        head.extend_from_slice(if client_alive {
+            b"Connection: keep-alive\r\n".as_slice()
+        } else {
+            b"Connection: close\r\n".as_slice()
+        });
+    }
+    head.extend_from_slice(b"\r\n");
+    Ok(Response {
+        head,
+        body,
+        interim,
+        client_alive,
+        server_alive: persistent(version, &tokens) && body != Body::UntilClose,
+    })
+}
+
+/// Authority and origin-form path of an absolute-form target like http://host:port/path.
+fn split_absolute(target: &str) -> Option<(&str, String)> {
+    let scheme = target.get(..7)?;
+    if !scheme.eq_ignore_ascii_case("http://") {
+        return None;
+    }
+    let rest = &target[7..];
+    let (authority, path) = rest.split_at(rest.find(['/', '?']).unwrap_or(rest.len()));
+    let authority = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    if authority.is_empty() {
+        return None;
+    }
+    let path = if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("/{}", path)
+    };
+    Some((authority, path))
+}
+
+fn split_authority(authority: &str) -> Option<(String, u16)> {
+    let (host, port) = if let Some(rest) = authority.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']')?;
+        match rest.strip_prefix(':') {
+            Some(port) => (host, port.parse().ok()?),
+            None if rest.is_empty() => (host, 80),
+            None => return None,
+        }
+    } else {
+        match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().ok()?),
+            None => (authority, 80),
+        }
+    };
+    (!host.is_empty() && host.len() <= 255).then(|| (host.to_string(), port))
+}
+
+/// Framing of a message by its headers, `otherwise` if it has neither chunks nor a length.
+fn body_of(headers: &[httparse::Header], otherwise: Body) -> Result<Body> {
+    if let Some(encoding) = header(headers, "Transfer-Encoding") {
+        let chunked = encoding
+            .rsplit(',')
+            .next()
+            .is_some_and(|last| last.trim().eq_ignore_ascii_case("chunked"));
+        return Ok(if chunked {
+            Body::Chunked
+        } else {
+            Body::UntilClose
+        });
+    }
+    match header(headers, "Content-Length") {
+        Some(length) => length
+            .trim()
+            .parse()
+            .map(Body::Length)
+            .map_err(|_| TrojanError::Inbound("invalid http content length")),
+        None => Ok(otherwise),
+    }
+}
+
+fn header<'a>(headers: &[httparse::Header<'a>], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case(name))
+        .and_then(|header| std::str::from_utf8(header.value).ok())
+}
+
+/// Lowercase options of the Connection and Proxy-Connection headers.
+fn connection_tokens(headers: &[httparse::Header]) -> Vec<String> {
+    headers
+        .iter()
+        .filter(|header| {
+            header.name.eq_ignore_ascii_case("Connection")
+                || header.name.eq_ignore_ascii_case("Proxy-Connection")
+        })
+        .filter_map(|header| std::str::from_utf8(header.value).ok())
+        .flat_map(|value| value.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .collect()
+}
+
+/// HTTP/1.1 connections persist unless closed, HTTP/1.0 ones only when asked to.
+fn persistent(version: u8, tokens: &[String]) -> bool {
+    if version >= 1 {
+        !tokens.iter().any(|token| token == "close")
+    } else {
+        tokens.iter().any(|token| token == "keep-alive")
+    }
+}
+
+fn put_headers(head: &mut Vec<u8>, headers: &[httparse::Header], tokens: &[String], skip: &[&str]) {
+    for header in headers {
+        let name = header.name.to_ascii_lowercase();
+        if HOP_BY_HOP.contains(&name.as_str())
+            || tokens.contains(&name)
+            || skip.contains(&name.as_str())
+        {
+            continue;
+        }
+        head.extend_from_slice(header.name.as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(header.value);
+        head.extend_from_slice(b"\r\n");
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_rewrite_request() {
+        use crate::aproxy::http_proxy::{parse_request, parse_response, Body};
+
+        let request = parse_request(
+            b"POST http://user@example.com:8080?q=1 HTTP/1.1\r\nHost: other\r\n\
+            Proxy-Authorization: Basic eDp5\r\nConnection: keep-alive, X-Hop\r\nX-Hop: 1\r\n\
+            Content-Length: 3\r\nAccept: */*\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(request.head.clone()).unwrap(),
+            "POST /?q=1 HTTP/1.1\r\nHost: example.com:8080\r\nContent-Length: 3\r\nAccept: */*\r\n\r\n"
+        );
+        assert_eq!((request.host.as_str(), request.port), ("example.com", 8080));
+        assert_eq!(request.body, Body::Length(3));
+        assert!(request.keep_alive);
+        assert!(parse_request(b"GET /index.html HTTP/1.1\r\nHost: a\r\n\r\n").is_err());
+        let request = parse_request(b"GET http://[::1]/ HTTP/1.0\r\n\r\n").unwrap();
+        assert_eq!((request.host.as_str(), request.port), ("::1", 80));
+        assert!(!request.keep_alive);
+
+        let request = parse_request(b"GET http://a/ HTTP/1.1\r\n\r\n").unwrap();
+        let response = parse_response(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nKeep-Alive: timeout=5\r\n\r\n",
+            &request,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(response.head).unwrap(),
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: keep-alive\r\n\r\n"
+        );
+        assert_eq!(response.body, Body::Chunked);
+        assert!(response.client_alive && response.server_alive);
+        let response = parse_response(b"HTTP/1.0 200 OK\r\n\r\n", &request).unwrap();
+        assert_eq!(response.body, Body::UntilClose);
+        assert!(!response.client_alive && !response.server_alive);
+        assert!(String::from_utf8(response.head)
+            .unwrap()
+            .ends_with("Connection: close\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_rewrite_once() {
+        use crate::{aproxy::http_proxy::rewrite_once, proto::Sock5Address};
+
+        let (head, address) = rewrite_once(
+            b"GET http://example.com/a?b HTTP/1.1\r\nHost: example.com\r\n\
+            Proxy-Connection: keep-alive\r\nAccept: */*\r\n\r\n",
+        )
+        .unwrap();
+        assert!(matches!(address, Sock5Address::Domain(host, 80) if host == "example.com"));
+        assert_eq!(
+            String::from_utf8(head).unwrap(),
+            "GET /a?b HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\nConnection: close\r\n\r\n"
+        );
+        assert!(rewrite_once(b"GET /a HTTP/1.1\r\nHost: example.com\r\n\r\n").is_err());
+    }
+}
@@ -0,0 +1,600 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use rustls_pki_types::ServerName;
+use tokio::{
+    io::{copy_bidirectional, split, AsyncReadExt, AsyncWriteExt},
+    net::{lookup_host, TcpListener, TcpStream, UdpSocket},
+    spawn,
+    time::Instant,
+};
+use tokio_rustls::TlsConnector;
+
+use crate::{
+    aproxy::{http_proxy, init_tls_conn, tcp::start_tcp_proxy},
+    async_utils::recv_from,
+    config::{Outbound, OPTIONS},
+    inbound::{
+        accepted, allowed, auth_method, parse_auth, parse_greeting, parse_http, parse_request,
+        reply, sniff, to_trojan_frame, Sniffed, CMD_CONNECT, CMD_UDP_ASSOCIATE,
+        NO_ACCEPTABLE_METHOD, SOCKS_VERSION, USER_PASS_AUTH,
+    },
+    limiter::{Priority, DOWNLOAD, UPLOAD},
+    memory::{self, Subsystem},
+    metrics::{incr, COUNTERS},
+    nat64,
+    proto::{Sock5Address, TrojanRequest, UdpAssociate, UdpParseResult, UDP_ASSOCIATE},
+    routing,
+    types::{Result, TrojanError},
+};
+
+const SOCKS4_GRANTED: u8 = 0x5a;
+const SOCKS4_REJECTED: u8 = 0x5b;
+pub(super) const MAX_HTTP_HEADER: usize = 8192;
+
+/// What the rules say about the requests of a user.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Policy {
+    pub(super) outbound: Outbound,
+    local_dns: bool,
+}
+
+impl Policy {
+    /// The last rule of the user or `*` applies, clients without credentials only match `*`.
+    fn of(user: Option<&str>) -> Policy {
+        OPTIONS
+            .proxy_args()
+            .inbound_rule
+            .iter()
+            .rev()
+            .find(|rule| rule.user == "*" || Some(rule.user.as_str()) == user)
+            .map_or(
+                Policy {
+                    outbound: Outbound::Proxy,
+                    local_dns: false,
+                },
+                |rule| Policy {
+                    outbound: rule.outbound,
+                    local_dns: rule.local_dns,
+                },
+            )
+    }
+
+    /// The routing rules decide by the domain of `address`, blocked users stay blocked.
+    pub(super) fn route(self, address: &Sock5Address) -> Policy {
+        let outbound = match address {
+            Sock5Address::Domain(domain, _) if self.outbound != Outbound::Block => {
+                routing::route(domain)
+            }
+            _ => None,
+        };
+        outbound.map_or(self, |outbound| Policy { outbound, ..self })
+    }
+
+    /// Direct connections always resolve on this host.
+    fn resolves_locally(&self) -> bool {
+        self.local_dns || self.outbound == Outbound::Direct
+    }
+}
+
+/// What a client asked for in its handshake.
+enum InboundRequest {
+    /// A domain is left unresolved for the server to resolve
+    Connect(Sock5Address),
+    /// Relay datagrams until the control connection closes, the reply is not sent yet.
+    UdpAssociate,
+    /// A plain http request with this head, the proxy forwards it and the ones following.
+    Forward(Vec<u8>),
+}
+
+/// Local SOCKS4/SOCKS5/HTTP listener, which may be bound to the LAN so other devices
+/// can use this tunnel, guarded by an optional allowlist and credentials.
+pub async fn run_inbound(
+    listener: TcpListener,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+    udp_connector: TlsConnector,
+) -> Result<()> {
+    let args = OPTIONS.proxy_args();
+    if args.inbound_auth.is_empty()
+        && args.inbound_allow.is_empty()
+        && !listener.local_addr()?.ip().is_loopback()
+    {
+        log::warn!("inbound listener is open to the network without auth or allowlist");
+    }
+    loop {
+        let (mut client, peer) = listener.accept().await?;
+        if !allowed(peer.ip()) {
+            log::warn!("inbound client {} not in allowlist", peer);
+            continue;
+        }
+        client.set_nodelay(true)?;
+        let server_name = server_name.clone();
+        let connector = connector.clone();
+        let udp_connector = udp_connector.clone();
+        spawn(async move {
+            let ret = match handshake(&mut client).await {
+                Ok((InboundRequest::Connect(Sock5Address::Socket(dst_addr)), policy))
+                    if policy.outbound == Outbound::Direct =>
+                {
+                    log::info!("inbound {} to {} directly", peer, dst_addr);
+                    direct_tcp(client, dst_addr).await
+                }
+                Ok((InboundRequest::Connect(dst_addr), _)) => {
+                    log::info!("inbound {} to {} through the server", peer, dst_addr);
+                    start_tcp_proxy(client, server_name, connector, dst_addr).await
+                }
+                Ok((InboundRequest::UdpAssociate, policy))
+                    if policy.outbound == Outbound::Direct =>
+                {
+                    udp_direct(client).await
+                }
+                Ok((InboundRequest::UdpAssociate, _)) => {
+                    udp_associate(client, server_name, udp_connector).await
+                }
+                Ok((InboundRequest::Forward(head), policy)) => {
+                    http_proxy::serve(client, head, policy, server_name, connector).await
+                }
+                Err(err) => {
+                    log::warn!("inbound handshake from {} failed:{:?}", peer, err);
+                    return;
+                }
+            };
+            if let Err(err) = ret {
+                log::error!("inbound proxy of {} failed:{:?}", peer, err);
+            }
+        });
+    }
+}
+
+/// Policy of the user, None if the credentials are wrong.
+fn check_auth(user: &str, pass: &str) -> Option<Policy> {
+    if !accepted(user, pass) {
+        return None;
+    }
+    let policy = Policy::of(Some(user));
+    log::info!("inbound user:{} {:?}", user, policy);
+    Some(policy)
+}
+
+async fn handshake(client: &mut TcpStream) -> Result<(InboundRequest, Policy)> {
+    let mut first = [0u8; 1];
+    client.peek(&mut first).await?;
+    match sniff(first[0]) {
+        Some(Sniffed::Socks5) => socks5_handshake(client).await,
+        Some(Sniffed::Socks4) => socks4_handshake(client).await,
+        Some(Sniffed::Http) => http_handshake(client).await,
+        None => Err(TrojanError::Inbound("unknown inbound protocol")),
+    }
+}
+
+/// Domains go to the server untouched unless the policy resolves them on this host.
+pub(super) async fn locate(address: Sock5Address, policy: Policy) -> Result<Sock5Address> {
+    match address {
+        Sock5Address::Domain(domain, port) if policy.resolves_locally() => {
+            Ok(Sock5Address::Socket(resolve(domain.as_str(), port).await?))
+        }
+        address => Ok(address),
+    }
+}
+
+async fn resolve(host: &str, port: u16) -> Result<SocketAddr> {
+    lookup_host((host, port))
+        .await?
+        .next()
+        .ok_or(TrojanError::Resolve)
+}
+
+/// Reads from the client until `parse` finds a complete message, a byte at a time so nothing
+/// following it is taken from the stream.
+async fn read_message<T>(
+    client: &mut TcpStream,
+    buffer: &mut Vec<u8>,
+    parse: impl Fn(&[u8]) -> Result<Option<T>>,
+) -> Result<T> {
+    buffer.clear();
+    loop {
+        if let Some(message) = parse(buffer.as_slice())? {
+            return Ok(message);
+        }
+        if buffer.len() > MAX_HTTP_HEADER {
+            return Err(TrojanError::Inbound("inbound handshake too long"));
+        }
+        buffer.push(client.read_u8().await?);
+    }
+}
+
+async fn socks5_handshake(client: &mut TcpStream) -> Result<(InboundRequest, Policy)> {
+    let mut buffer = Vec::new();
+    let methods = read_message(client, &mut buffer, |buffer| {
+        Ok(parse_greeting(buffer)?.map(|(_, methods)| methods.to_vec()))
+    })
+    .await?;
+    let method = auth_method();
+    if !methods.contains(&method) {
+        client
+            .write_all(&[SOCKS_VERSION, NO_ACCEPTABLE_METHOD])
+            .await?;
+        return Err(TrojanError::Inbound("no acceptable socks5 method"));
+    }
+    client.write_all(&[SOCKS_VERSION, method]).await?;
+    let mut policy = Policy::of(None);
+    if method == USER_PASS_AUTH {
+        let (_, user, pass) =
+            read_message(client, &mut buffer, |buffer| Ok(parse_auth(buffer))).await?;
+        let user_policy = check_auth(user.as_str(), pass.as_str());
+        client
+            .write_all(&[1, if user_policy.is_some() { 0 } else { 1 }])
+            .await?;
+        policy = user_policy.ok_or(TrojanError::Inbound("invalid socks5 credentials"))?;
+    }
+
+    let (_, cmd, address) = read_message(client, &mut buffer, parse_request).await?;
+    if cmd != CMD_CONNECT && cmd != CMD_UDP_ASSOCIATE {
+        client.write_all(&reply(7, None)).await?;
+        return Err(TrojanError::Inbound("unsupported socks5 command"));
+    }
+    let policy = policy.route(&address);
+    if policy.outbound == Outbound::Block {
+        client.write_all(&reply(2, None)).await?;
+        return Err(TrojanError::Inbound("blocked by inbound or routing rule"));
+    }
+    // the address of an association is where the client may send from, usually left empty
+    if cmd == CMD_UDP_ASSOCIATE {
+        return Ok((InboundRequest::UdpAssociate, policy));
+    }
+    let dst_addr = locate(address, policy).await?;
+    client.write_all(&reply(0, None)).await?;
+    Ok((InboundRequest::Connect(dst_addr), policy))
+}
+
+/// SOCKS4 and its SOCKS4a domain extension, CONNECT only. The user id carries no password, so
+/// listeners requiring credentials reject it.
+async fn socks4_handshake(client: &mut TcpStream) -> Result<(InboundRequest, Policy)> {
+    let mut request = [0u8; 8];
+    client.read_exact(&mut request).await?;
+    read_null_terminated(client).await?;
+    let port = u16::from_be_bytes([request[2], request[3]]);
+    let ip = Ipv4Addr::new(request[4], request[5], request[6], request[7]);
+    // 0.0.0.x with a non zero x is followed by the domain
+    let address = if ip.octets()[..3] == [0, 0, 0] && ip.octets()[3] != 0 {
+        let domain = read_null_terminated(client).await?;
+        Sock5Address::Domain(String::from_utf8_lossy(&domain).into(), port)
+    } else {
+        Sock5Address::Socket(SocketAddr::new(ip.into(), port))
+    };
+    let policy = Policy::of(None).route(&address);
+    let rejected = if request[1] != CMD_CONNECT {
+        Some("unsupported socks4 command")
+    } else if !OPTIONS.proxy_args().inbound_auth.is_empty() {
+        Some("socks4 can't carry credentials")
+    } else if policy.outbound == Outbound::Block {
+        Some("blocked by inbound or routing rule")
+    } else {
+        None
+    };
+    if let Some(message) = rejected {
+        client.write_all(&socks4_reply(SOCKS4_REJECTED)).await?;
+        return Err(TrojanError::Inbound(message));
+    }
+    let dst_addr = locate(address, policy).await?;
+    client.write_all(&socks4_reply(SOCKS4_GRANTED)).await?;
+    Ok((InboundRequest::Connect(dst_addr), policy))
+}
+
+/// Reads the user id or domain of a socks4 request, without the terminating zero.
+async fn read_null_terminated(client: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    loop {
+        match client.read_u8().await? {
+            0 => return Ok(buffer),
+            _ if buffer.len() >= 255 => {
+                return Err(TrojanError::Inbound("socks4 request field too long"))
+            }
+            byte => buffer.push(byte),
+        }
+    }
+}
+
+/// Socks4 reply, the port and address are ignored by clients.
+fn socks4_reply(code: u8) -> [u8; 8] {
+    [0, code, 0, 0, 0, 0, 0, 0]
+}
+
+/// Relays the datagrams of a socks5 UDP ASSOCIATE through one trojan UDP_ASSOCIATE connection,
+/// until the control connection closes or the association is idle for the udp timeout.
+async fn udp_associate(
+    mut client: TcpStream,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+) -> Result<()> {
+    let peer = client.peer_addr()?;
+    let Some(_memory) = memory::reserve(Subsystem::Udp, memory::UDP_SESSION)
+        .and_then(|memory| memory.and(Subsystem::Tls, memory::TLS_SESSION))
+    else {
+        client.write_all(&reply(1, None)).await?;
+        return Ok(());
+    };
+    let mut remote = match init_tls_conn(connector, server_name).await {
+        Ok(remote) => remote,
+        Err(err) => {
+            client.write_all(&reply(1, None)).await?;
+            return Err(err);
+        }
+    };
+    let mut request = BytesMut::new();
+    TrojanRequest::generate(
+        &mut request,
+        UDP_ASSOCIATE,
+        &SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+    );
+    remote.write_all(request.as_ref()).await?;
+    let socket = bind_relay(&mut client).await?;
+
+    let (mut remote_read, mut remote_write) = split(remote);
+    let idle = Duration::from_secs(OPTIONS.udp_idle_timeout);
+    let mut deadline = Instant::now() + idle;
+    let mut client_addr: Option<SocketAddr> = None;
+    let mut datagram = vec![0u8; u16::MAX as usize];
+    let mut control = [0u8; 1];
+    let mut frame = BytesMut::new();
+    let mut buffer = BytesMut::new();
+    loop {
+        tokio::select! {
+            ret = socket.recv_from(datagram.as_mut_slice()) => {
+                let (size, src_addr) = ret?;
+                if !from_client(peer, &mut client_addr, src_addr) {
+                    continue;
+                }
+                frame.clear();
+                let Some(port) = to_trojan_frame(&datagram[..size], &mut frame) else {
+                    log::warn!("invalid socks5 datagram from {} dropped", src_addr);
+                    continue;
+                };
+                UPLOAD
+                    .acquire(size, Priority::of_packet(port, size))
+                    .await;
+                if remote_write.write_all(frame.as_ref()).await.is_err() {
+                    incr(&COUNTERS.udp_remote_failed);
+                    break;
+                }
+                deadline = Instant::now() + idle;
+            }
+            ret = remote_read.read_buf(&mut buffer) => {
+                if !matches!(ret, Ok(n) if n > 0) {
+                    log::info!("udp associate of {} closed by server", peer);
+                    break;
+                }
+                loop {
+                    match UdpAssociate::parse(buffer.as_ref()) {
+                        UdpParseResult::Continued => break,
+                        UdpParseResult::Packet(packet) => {
+                            let payload = &packet.payload[..packet.length];
+                            // the address bytes are passed on as they are
+                            let address = &buffer[..packet.offset - packet.length - 4];
+                            frame.clear();
+                            frame.put_slice(&[0, 0, 0]);
+                            frame.put_slice(address);
+                            frame.put_slice(payload);
+                            DOWNLOAD
+                                .acquire(
+                                    payload.len(),
+                                    Priority::of_packet(packet.address.port(), payload.len()),
+                                )
+                                .await;
+                            if let Some(addr) = client_addr {
+                                if socket.send_to(frame.as_ref(), addr).await.is_err() {
+                                    incr(&COUNTERS.udp_local_failed);
+                                }
+                            }
+                            buffer.advance(packet.offset);
+                        }
+                        UdpParseResult::InvalidProtocol => {
+                            incr(&COUNTERS.udp_invalid_protocol);
+                            return Err(TrojanError::Inbound("invalid udp frame from server"));
+                        }
+                    }
+                }
+                deadline = Instant::now() + idle;
+            }
+            ret = client.read(&mut control) => {
+                if !matches!(ret, Ok(n) if n > 0) {
+                    log::info!("udp associate of {} closed by client", peer);
+                    break;
+                }
+            }
+            _ = tokio::time::sleep_until(deadline) => {
+                log::info!("udp associate of {} timeout", peer);
+                break;
+            }
+        }
+    }
+    let _ = remote_write.shutdown().await;
+    Ok(())
+}
+
+/// Relays the datagrams of an association from this host, for users with the direct outbound.
+async fn udp_direct(mut client: TcpStream) -> Result<()> {
+    let peer = client.peer_addr()?;
+    let socket = bind_relay(&mut client).await?;
+    let outbound_v4 = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    // hosts without ipv6 still relay to ipv4 targets
+    let outbound_v6 = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await.ok();
+
+    let idle = Duration::from_secs(OPTIONS.udp_idle_timeout);
+    let mut deadline = Instant::now() + idle;
+    let mut client_addr: Option<SocketAddr> = None;
+    let mut datagram = vec![0u8; u16::MAX as usize];
+    let mut response_v4 = vec![0u8; u16::MAX as usize];
+    let mut response_v6 = vec![0u8; u16::MAX as usize];
+    let mut control = [0u8; 1];
+    let mut frame = BytesMut::new();
+    loop {
+        tokio::select! {
+            ret = socket.recv_from(datagram.as_mut_slice()) => {
+                let (size, src_addr) = ret?;
+                if !from_client(peer, &mut client_addr, src_addr) {
+                    continue;
+                }
+                frame.clear();
+                let (Some(_), UdpParseResult::Packet(packet)) = (
+                    to_trojan_frame(&datagram[..size], &mut frame),
+                    UdpAssociate::parse(frame.as_ref()),
+                ) else {
+                    log::warn!("invalid socks5 datagram from {} dropped", src_addr);
+                    continue;
+                };
+                let target = match packet.address {
+                    Sock5Address::Socket(addr) => addr,
+                    Sock5Address::Domain(domain, port) => match resolve(domain.as_str(), port).await {
+                        Ok(addr) => addr,
+                        Err(err) => {
+                            log::warn!("resolve {} failed:{:?}", domain, err);
+                            continue;
+                        }
+                    },
+                    _ => continue,
+                };
+                let payload = &packet.payload[..packet.length];
+                let ret = match (target, &outbound_v6) {
+                    (SocketAddr::V4(_), _) => outbound_v4.send_to(payload, target).await,
+                    (SocketAddr::V6(_), Some(outbound_v6)) => outbound_v6.send_to(payload, target).await,
+                    (SocketAddr::V6(_), None) => continue,
+                };
+                if ret.is_err() {
+                    incr(&COUNTERS.udp_remote_failed);
+                }
+                deadline = Instant::now() + idle;
+            }
+            ret = outbound_v4.recv_from(response_v4.as_mut_slice()) => {
+                let (size, src_addr) = ret?;
+                send_to_client(&socket, client_addr, src_addr, &response_v4[..size], &mut frame).await;
+                deadline = Instant::now() + idle;
+            }
+            ret = recv_from(outbound_v6.as_ref(), response_v6.as_mut_slice()) => {
+                let (size, src_addr) = ret?;
+                send_to_client(&socket, client_addr, src_addr, &response_v6[..size], &mut frame).await;
+                deadline = Instant::now() + idle;
+            }
+            ret = client.read(&mut control) => {
+                if !matches!(ret, Ok(n) if n > 0) {
+                    log::info!("udp associate of {} closed by client", peer);
+                    break;
+                }
+            }
+            _ = tokio::time::sleep_until(deadline) => {
+                log::info!("udp associate of {} timeout", peer);
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Connects to `dst_addr` from this host, for users with the direct outbound.
+async fn direct_tcp(mut client: TcpStream, dst_addr: SocketAddr) -> Result<()> {
+    let mut remote = TcpStream::connect(nat64::translate(dst_addr)).await?;
+    copy_bidirectional(&mut client, &mut remote).await?;
+    Ok(())
+}
+
+/// Binds the relay socket of an association on the listener's address and replies with it.
+async fn bind_relay(client: &mut TcpStream) -> Result<UdpSocket> {
+    let socket = UdpSocket::bind(SocketAddr::new(client.local_addr()?.ip(), 0)).await?;
+    client
+        .write_all(&reply(0, Some(socket.local_addr()?)))
+        .await?;
+    log::info!(
+        "udp associate of {} on {}",
+        client.peer_addr()?,
+        socket.local_addr()?
+    );
+    Ok(socket)
+}
+
+/// Only the control connection's ip may send, the port is learned from the first datagram.
+fn from_client(
+    peer: SocketAddr,
+    client_addr: &mut Option<SocketAddr>,
+    src_addr: SocketAddr,
+) -> bool {
+    if src_addr.ip() != peer.ip() || client_addr.is_some_and(|addr| addr != src_addr) {
+        log::warn!("udp datagram from unknown {} dropped", src_addr);
+        return false;
+    }
+    *client_addr = Some(src_addr);
+    true
+}
+
+/// Sends a datagram of `src_addr` to the client behind a socks5 UDP header.
+async fn send_to_client(
+    socket: &UdpSocket,
+    client_addr: Option<SocketAddr>,
+    src_addr: SocketAddr,
+    payload: &[u8],
+    frame: &mut BytesMut,
+) {
+    let Some(client_addr) = client_addr else {
+        return;
+    };
+    frame.clear();
+    frame.put_slice(&[0, 0, 0]);
+    Sock5Address::generate(frame, &src_addr);
+    frame.put_slice(payload);
+    if socket.send_to(frame.as_ref(), client_addr).await.is_err() {
+        incr(&COUNTERS.udp_local_failed);
+    }
+}
+
+/// Handles CONNECT here, other requests are forwarded by `http_proxy`.
+async fn http_handshake(client: &mut TcpStream) -> Result<(InboundRequest, Policy)> {
+    let mut buffer = Vec::new();
+    while !buffer.ends_with(b"\r\n\r\n") {
+        if buffer.len() > MAX_HTTP_HEADER {
+            return Err(TrojanError::Inbound("http request header too large"));
+        }
+        buffer.push(client.read_u8().await?);
+    }
+    let Ok(Some(request)) = parse_http(buffer.as_slice()) else {
+        client
+            .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")
+            .await?;
+        return Err(TrojanError::Inbound("invalid http request"));
+    };
+    let mut policy = Policy::of(None);
+    if !OPTIONS.proxy_args().inbound_auth.is_empty() {
+        let user_policy = request
+            .credentials
+            .and_then(|(user, pass)| check_auth(user.as_str(), pass.as_str()));
+        let Some(user_policy) = user_policy else {
+            client
+                .write_all(
+                    b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+                    Proxy-Authenticate: Basic realm=\"trojan\"\r\n\r\n",
+                )
+                .await?;
+            return Err(TrojanError::Inbound("invalid http proxy credentials"));
+        };
+        policy = user_policy;
+    }
+    if policy.outbound == Outbound::Block {
+        client.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n").await?;
+        return Err(TrojanError::Inbound("blocked by inbound rule"));
+    }
+    let Some(address) = request.connect else {
+        return Ok((InboundRequest::Forward(buffer), policy));
+    };
+    let policy = policy.route(&address);
+    if policy.outbound == Outbound::Block {
+        client.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n").await?;
+        return Err(TrojanError::Inbound("blocked by routing rule"));
+    }
+    let dst_addr = locate(address, policy).await?;
+    client
+        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+        .await?;
+    Ok((InboundRequest::Connect(dst_addr), policy))
+}
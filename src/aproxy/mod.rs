@@ -1,38 +1,61 @@
 use std::{
     net::{IpAddr, SocketAddr},
     sync::{atomic::AtomicBool, Arc},
+    time::{Duration, Instant},
 };
 
+use futures::{future::select_all, FutureExt};
 use rustls::{
     client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
     crypto::ring::default_provider,
     ClientConfig, DigitallySignedStruct, Error, RootCertStore, SignatureScheme,
 };
 use rustls_pki_types::{CertificateDer, ServerName, UnixTime};
+use socket2::Domain;
 use tokio::{
-    net::{lookup_host, TcpListener, TcpStream, UdpSocket},
+    net::{lookup_host, TcpListener, TcpSocket, TcpStream, UdpSocket},
     runtime::Runtime,
+    spawn,
     sync::mpsc::unbounded_channel,
 };
 use tokio_rustls::{client::TlsStream, TlsConnector};
 
 use crate::{
     aproxy::{
+        discovery::{advertise_mdns, UpnpMapping},
+        inbound::run_inbound,
         profiler::{run_profiler, start_check_server},
-        tcp::run_tcp,
+        tcp::{connect, run_tcp},
         udp::run_udp,
     },
     config::OPTIONS,
-    proxy::new_socket,
+    events::start_event_server,
+    metrics::{record_rtt, server_result},
+    nat64, pacing,
+    pinning::pin_certificates,
+    plugin,
+    proxy::{new_listeners, new_socket, start_gateway, start_route_table},
+    reverse::run_reverse,
+    sys,
+    tls_client::client_config,
     types,
     types::Result,
+    upstream, watchdog,
 };
 
+mod discovery;
+pub mod forward;
+pub mod http_proxy;
+pub mod inbound;
 mod profiler;
-mod tcp;
+pub mod redundant;
+mod selector;
+pub mod tcp;
 mod udp;
 
 pub fn run() -> Result<()> {
+    start_gateway()?;
+    let _route_table = start_route_table()?;
     let runtime = Runtime::new()?;
     runtime.block_on(async_run())
 }
@@ -80,9 +103,8 @@ impl ServerCertVerifier for InsecureAuth {
 fn prepare_tls_config() -> Arc<ClientConfig> {
     let mut root_store = RootCertStore::empty();
     root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
-    let mut config = ClientConfig::builder()
-        .with_root_certificates(root_store)
-        .with_no_client_auth();
+    let mut config = client_config(root_store);
+    pin_certificates(&mut config);
     if OPTIONS.proxy_args().insecure {
         log::info!("insecure settings");
         config
@@ -94,12 +116,75 @@ fn prepare_tls_config() -> Arc<ClientConfig> {
 
 async fn async_run() -> Result<()> {
     log::info!("insecure:{}", OPTIONS.proxy_args().insecure);
-    let addr: SocketAddr = OPTIONS.local_addr.parse()?;
-    let tcp_listener = TcpListener::from_std(new_socket(addr, false)?.into())?;
-    let udp_listener = UdpSocket::from_std(new_socket(addr, true)?.into())?;
+    let tcp_listeners = new_listeners(false)?
+        .into_iter()
+        .map(|socket| TcpListener::from_std(socket.into()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let udp_listeners = new_listeners(true)?
+        .into_iter()
+        .map(|socket| UdpSocket::from_std(socket.into()))
+        .collect::<std::io::Result<Vec<_>>>()?;
     let server_name: ServerName = OPTIONS.proxy_args().hostname.as_str().try_into()?;
     let config = prepare_tls_config();
-    let connector = TlsConnector::from(config);
+    let connector = TlsConnector::from(config.clone());
+    // tcp streams go through grpc if configured, which needs h2 negotiated
+    let stream_connector = if OPTIONS.grpc_service.is_some() {
+        let mut config = (*config).clone();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        TlsConnector::from(Arc::new(config))
+    } else {
+        // early data waits for a flush, which only the request of a tcp stream gets
+        connector
+            .clone()
+            .early_data(OPTIONS.early_data && OPTIONS.mux == 0)
+    };
+    if let Some(addr) = &OPTIONS.events_addr {
+        start_event_server(addr.clone());
+    }
+    if OPTIONS.pacing {
+        spawn(pacing::run());
+    }
+    // keeps the mdns advertisement alive until exit
+    let mut _mdns = None;
+    let mut upnp = None;
+    if let Some(addr) = &OPTIONS.proxy_args().inbound_addr {
+        let listener = TcpListener::bind(addr.as_str()).await?;
+        log::warn!("socks5/http listener started on {}", addr);
+        let listen = listener.local_addr()?;
+        if OPTIONS.proxy_args().upnp {
+            upnp = Some(UpnpMapping::start(listen)?);
+        }
+        if OPTIONS.proxy_args().mdns {
+            _mdns = Some(advertise_mdns(listen)?);
+        }
+        let (server_name, stream_connector, connector) = (
+            server_name.clone(),
+            stream_connector.clone(),
+            connector.clone(),
+        );
+        spawn(async move {
+            if let Err(err) = run_inbound(listener, server_name, stream_connector, connector).await
+            {
+                log::error!("inbound routine exit with:{:?}", err);
+            }
+        });
+    }
+    if !OPTIONS.proxy_args().reverse.is_empty() {
+        let (server_name, connector) = (server_name.clone(), connector.clone());
+        spawn(run_reverse(move || {
+            init_tls_conn(connector.clone(), server_name.clone())
+        }));
+    }
+    let (stream_server_name, forward_connector) = (server_name.clone(), stream_connector.clone());
+    let (tls_server_name, tls_connector) = (server_name.clone(), connector.clone());
+    forward::start(
+        &OPTIONS.proxy_args().forward,
+        Box::new(move || connect(forward_connector.clone(), stream_server_name.clone()).boxed()),
+        Box::new(move || init_tls_conn(tls_connector.clone(), tls_server_name.clone()).boxed()),
+    );
+    if !OPTIONS.proxy_args().server.is_empty() {
+        spawn(selector::run(connector.clone()));
+    }
     start_check_server(
         OPTIONS.proxy_args().hostname.clone(),
         150,
@@ -113,31 +198,66 @@ async fn async_run() -> Result<()> {
         (None, None)
     };
 
+    // every local address has its own tcp and udp routines, the first one exiting stops all
+    let tcp = select_all(tcp_listeners.into_iter().map(|listener| {
+        Box::pin(run_tcp(
+            listener,
+            server_name.clone(),
+            stream_connector.clone(),
+            sender.clone(),
+        ))
+    }));
+    let udp = select_all(udp_listeners.into_iter().map(|listener| {
+        Box::pin(run_udp(
+            listener,
+            server_name.clone(),
+            connector.clone(),
+            sender.clone(),
+        ))
+    }));
     if sender.is_none() {
         tokio::select! {
-            ret = run_tcp(tcp_listener, server_name.clone(), connector.clone(), sender.clone()) => {
+            (ret, _, _) = tcp => {
                 log::error!("tcp routine exit with:{:?}", ret);
             },
-            ret = run_udp(udp_listener, server_name.clone(), connector.clone(), sender.clone()) => {
+            (ret, _, _) = udp => {
                 log::error!("udp routine exit with:{:?}", ret);
             }
+            _ = wait_terminated() => {
+                log::warn!("SIGTERM received, exit now");
+            }
         }
     } else {
         tokio::select! {
-            ret = run_tcp(tcp_listener, server_name.clone(), connector.clone(), sender.clone()) => {
+            (ret, _, _) = tcp => {
                 log::error!("tcp routine exit with:{:?}", ret);
             },
-            ret = run_udp(udp_listener, server_name.clone(), connector.clone(), sender.clone()) => {
+            (ret, _, _) = udp => {
                 log::error!("udp routine exit with:{:?}", ret);
             }
+            _ = wait_terminated() => {
+                log::warn!("SIGTERM received, exit now");
+            }
             ret = run_profiler(receiver, sender, server_name.clone(), connector) => {
                 log::error!("profiler routine exit with:{:?}", ret);
             }
         }
     }
+    if let Some(upnp) = upnp {
+        upnp.remove().await;
+    }
     Ok(())
 }
 
+async fn wait_terminated() {
+    let mut check = tokio::time::interval(Duration::from_secs(1));
+    let heartbeat = watchdog::register("aproxy");
+    while !sys::terminated() {
+        heartbeat.beat();
+        check.tick().await;
+    }
+}
+
 #[cfg(target_os = "windows")]
 async fn wait_until_stop(_running: Arc<AtomicBool>, _ip: IpAddr) {}
 
@@ -191,12 +311,77 @@ pub async fn init_tls_conn(
     connector: TlsConnector,
     server_name: ServerName<'static>,
 ) -> types::Result<TlsStream<TcpStream>> {
-    let ips: Vec<_> = lookup_host((
+    let conn = connect_server(connector, server_name).await;
+    server_result(&conn);
+    conn
+}
+
+/// Connects to the first reachable address, with the socket marked before the SYN is sent if
+/// `mark` given, over MPTCP if --mptcp.
+async fn connect_addrs(addrs: &[SocketAddr], mark: Option<u32>) -> std::io::Result<TcpStream> {
+    let mut last_err = None;
+    for addr in addrs {
+        let socket = sys::stream_socket(Domain::for_address(*addr), OPTIONS.mptcp)?;
+        if let Some(mark) = mark {
+            sys::set_mark(&socket, mark)?;
+        }
+        socket.set_nonblocking(true)?;
+        let socket = TcpSocket::from_std_stream(socket.into());
+        match socket.connect(*addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| std::io::ErrorKind::AddrNotAvailable.into()))
+}
+
+async fn connect_server(
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+) -> types::Result<TlsStream<TcpStream>> {
+    if !OPTIONS.proxy_args().server.is_empty() {
+        let (index, host, port) = selector::current();
+        let result = match tokio::time::timeout(
+            selector::HANDSHAKE_TIMEOUT,
+            connect_to(connector, host.try_into()?, host, port, None),
+        )
+        .await
+        {
+            Ok(result) => result,
+            Err(_) => Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into()),
+        };
+        selector::connected(index, result.is_ok());
+        return result;
+    }
+    connect_to(
+        connector,
+        server_name,
         OPTIONS.proxy_args().hostname.as_str(),
         OPTIONS.proxy_args().port,
-    ))
-    .await?
-    .collect();
+        plugin::local_addr(),
+    )
+    .await
+}
+
+/// Connects to the trojan server at `host`, kept out of the proxy like the one of --hostname,
+/// through the --plugin listening on `via` or the --upstream-proxy if given.
+async fn connect_to(
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+    host: &str,
+    port: u16,
+    via: Option<SocketAddr>,
+) -> types::Result<TlsStream<TcpStream>> {
+    let upstream = OPTIONS.upstream_proxy.as_ref().filter(|_| via.is_none());
+    let ips: Vec<_> = match upstream {
+        // resolved on startup, the server name is left to the proxy
+        Some(_) => vec![OPTIONS.back_addr.unwrap()],
+        None => lookup_host((host, port))
+            .await?
+            .map(nat64::translate)
+            .collect(),
+    };
+    let server_mark = OPTIONS.proxy_args().server_mark;
     #[cfg(target_os = "linux")]
     {
         let mut proxy_data = OPTIONS
@@ -209,13 +394,30 @@ pub async fn init_tls_conn(
         for ip in &ips {
             if !proxy_data.server_ips.contains(&ip.ip()) {
                 proxy_data.server_ips.push(ip.ip());
+                if server_mark.is_some() {
+                    continue;
+                }
                 if let Err(err) = proxy_data.bypass_session.add(ip.ip(), None) {
                     log::error!("add ip:{} to session failed:{}", ip, err);
                 }
             }
         }
     }
-    let stream = tokio::net::TcpStream::connect(ips.as_slice()).await?;
+    let start = Instant::now();
+    let mut stream = match via {
+        Some(addr) => connect_addrs(&[addr], None).await?,
+        None => connect_addrs(ips.as_slice(), server_mark).await?,
+    };
+    if let Some(proxy) = upstream {
+        upstream::handshake(&mut stream, proxy, host, port).await?;
+    }
+    record_rtt(start.elapsed());
+    pacing::watch(&stream);
+    if let Some(dscp) = OPTIONS.dscp {
+        if let Err(err) = sys::set_dscp(&stream, stream.peer_addr()?.is_ipv4(), dscp) {
+            log::warn!("set dscp failed:{}", err);
+        }
+    }
     let conn = connector.connect(server_name, stream).await?;
     Ok(conn)
 }
@@ -0,0 +1,18 @@
+use rustls_pki_types::ServerName;
+use tokio::net::TcpStream;
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+use crate::{aproxy::connect_to, config::OPTIONS, types::Result};
+
+/// Connects to --redundant-server, None if it's not set.
+pub async fn connect(connector: TlsConnector) -> Option<Result<TlsStream<TcpStream>>> {
+    let server = OPTIONS.proxy_args().redundant_server.as_ref()?;
+    // validated by the argument parser
+    let (host, port) = server.rsplit_once(':').unwrap();
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let server_name = match ServerName::try_from(host.to_string()) {
+        Ok(server_name) => server_name,
+        Err(err) => return Some(Err(err.into())),
+    };
+    Some(connect_to(connector, server_name, host, port.parse().unwrap(), None).await)
+}
@@ -0,0 +1,317 @@
+//! Picks the trojan server new connections go to among --hostname and the --server ones, by the
+//! TLS handshake time probed every --probe-interval seconds. A faster server is only switched to
+//! when it beats the one in use by --switch-margin after a few rounds, while the one in use is
+//! failed over from at once when probes or connections to it keep failing.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::future::join_all;
+use rustls_pki_types::ServerName;
+use tokio::io::AsyncWriteExt;
+use tokio_rustls::TlsConnector;
+
+use crate::{aproxy::connect_to, config::OPTIONS, events};
+
+/// Weight of a new probe in the smoothed handshake time.
+const WEIGHT: f64 = 0.3;
+/// Failed probes or connections in a row after which a server is taken as down.
+const MAX_FAILURES: u32 = 3;
+/// Rounds a server is kept at least, unless it goes down.
+const MIN_ROUNDS: u32 = 3;
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Index of the server in use, 0 for --hostname.
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static::lazy_static! {
+    static ref SELECTOR: Mutex<Selector> = Mutex::new(Selector::new(
+        OPTIONS.proxy_args().server.len() + 1,
+        OPTIONS.proxy_args().switch_margin,
+    ));
+}
+
+/// Index, host and port of the server in use.
+pub fn current() -> (usize, &'static str, u16) {
+    let index = CURRENT.load(Ordering::Relaxed);
+    let (host, port) = server(index);
+    (index, host, port)
+}
+
+fn server(index: usize) -> (&'static str, u16) {
+    let args = OPTIONS.proxy_args();
+    match index.checked_sub(1) {
+        Some(index) => split(args.server[index].as_str()),
+        None => (args.hostname.as_str(), args.port),
+    }
+}
+
+fn split(server: &str) -> (&str, u16) {
+    // validated by the argument parser
+    let (host, port) = server.rsplit_once(':').unwrap();
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    (host, port.parse().unwrap())
+}
+
+/// Records the result of a connection to server `index`, failing over to another server if the
+/// one in use is down.
+pub fn connected(index: usize, ok: bool) {
+    let next = match SELECTOR.lock() {
+        Ok(mut selector) => selector.connected(index, ok),
+        Err(err) => {
+            log::error!("lock server selector failed:{}", err);
+            return;
+        }
+    };
+    if let Some(next) = next {
+        switch(index, next, true);
+    }
+}
+
+fn switch(from: usize, to: usize, failover: bool) {
+    let (from_host, from_port) = server(from);
+    let (host, port) = server(to);
+    if failover {
+        log::error!(
+            "server {}:{} is down, fail over to {}:{}",
+            from_host,
+            from_port,
+            host,
+            port
+        );
+    } else {
+        log::warn!("switch to faster server {}:{}", host, port);
+    }
+    CURRENT.store(to, Ordering::Relaxed);
+    events::server_switched(format!("{}:{}", host, port), failover);
+}
+
+#[derive(Clone, Default)]
+struct Health {
+    rtt: Option<f64>,
+    failures: u32,
+}
+
+struct Selector {
+    servers: Vec<Health>,
+    current: usize,
+    rounds: u32,
+    margin: f64,
+}
+
+impl Selector {
+    fn new(count: usize, margin: u64) -> Self {
+        Self {
+            servers: vec![Health::default(); count],
+            current: 0,
+            // the first round may leave --hostname already
+            rounds: MIN_ROUNDS,
+            margin: margin as f64,
+        }
+    }
+
+    /// Records the handshake time of server `index`, None if the probe failed.
+    fn record(&mut self, index: usize, rtt: Option<Duration>) {
+        let health = &mut self.servers[index];
+        match rtt {
+            Some(rtt) => {
+                let rtt = rtt.as_secs_f64() * 1000.0;
+                health.rtt = Some(health.rtt.map_or(rtt, |old| old + (rtt - old) * WEIGHT));
+                health.failures = 0;
+            }
+            None => health.failures += 1,
+        }
+    }
+
+    fn is_down(&self, index: usize) -> bool {
+        self.servers[index].failures >= MAX_FAILURES
+    }
+
+    fn rtt(&self, index: usize) -> Option<f64> {
+        self.servers[index].rtt.filter(|_| !self.is_down(index))
+    }
+
+    /// The fastest server up with its smoothed handshake time.
+    fn fastest(&self) -> Option<(usize, f64)> {
+        (0..self.servers.len())
+            .filter_map(|index| Some((index, self.rtt(index)?)))
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+    }
+
+    /// Picks the server after a round of probes, returns it if it changed.
+    fn select(&mut self) -> Option<usize> {
+        self.rounds += 1;
+        let (best, rtt) = self.fastest()?;
+        if best == self.current {
+            return None;
+        }
+        let switch = match self.rtt(self.current) {
+            Some(current) => self.rounds >= MIN_ROUNDS && rtt + self.margin < current,
+            None => true,
+        };
+        if switch {
+            self.current = best;
+            self.rounds = 0;
+            Some(best)
+        } else {
+            None
+        }
+    }
+
+    /// Records a connection to server `index`, returns the server failed over to if it is the
+    /// one in use and just went down. The fastest server up is taken, or the next one not down
+    /// before any probe succeeded.
+    fn connected(&mut self, index: usize, ok: bool) -> Option<usize> {
+        let health = &mut self.servers[index];
+        if ok {
+            health.failures = 0;
+            return None;
+        }
+        health.failures += 1;
+        if index != self.current || health.failures != MAX_FAILURES {
+            return None;
+        }
+        let count = self.servers.len();
+        let next = self.fastest().map(|(index, _)| index).or_else(|| {
+            (1..count)
+                .map(|step| (index + step) % count)
+                .find(|index| !self.is_down(*index))
+        })?;
+        self.current = next;
+        self.rounds = 0;
+        Some(next)
+    }
+}
+
+/// Time of a TLS handshake with the server at `host`.
+async fn probe(connector: TlsConnector, host: &'static str, port: u16) -> Option<Duration> {
+    let server_name = ServerName::try_from(host).ok()?;
+    let start = Instant::now();
+    match tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        connect_to(connector, server_name, host, port, None),
+    )
+    .await
+    {
+        Ok(Ok(mut conn)) => {
+            let elapsed = start.elapsed();
+            let _ = conn.shutdown().await;
+            Some(elapsed)
+        }
+        Ok(Err(err)) => {
+            log::warn!("probe server {}:{} failed:{:?}", host, port, err);
+            None
+        }
+        Err(_) => {
+            log::warn!("probe server {}:{} timeout", host, port);
+            None
+        }
+    }
+}
+
+pub async fn run(connector: TlsConnector) {
+    let args = OPTIONS.proxy_args();
+    let servers: Vec<_> = (0..=args.server.len()).map(server).collect();
+    let mut interval = tokio::time::interval(Duration::from_secs(args.probe_interval.max(1)));
+    loop {
+        interval.tick().await;
+        let probes = servers
+            .iter()
+            .map(|(host, port)| probe(connector.clone(), host, *port));
+        let rtts = join_all(probes).await;
+        let switched = match SELECTOR.lock() {
+            Ok(mut selector) => {
+                let from = selector.current;
+                for (index, rtt) in rtts.into_iter().enumerate() {
+                    selector.record(index, rtt);
+                }
+                selector
+                    .select()
+                    .map(|to| (from, to, selector.is_down(from)))
+            }
+            Err(err) => {
+                log::error!("lock server selector failed:{}", err);
+                continue;
+            }
+        };
+        if let Some((from, to, failover)) = switched {
+            switch(from, to, failover);
+        }
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_select() {
+        use std::time::Duration;
+
+        use super::Selector;
+
+        fn round(selector: &mut Selector, rtts: &[Option<u64>]) -> Option<usize> {
+            for (index, rtt) in rtts.iter().enumerate() {
+                selector.record(index, rtt.map(Duration::from_millis));
+            }
+            selector.select()
+        }
+
+        let mut selector = Selector::new(3, 20);
+        assert_eq!(round(&mut selector, &[Some(100), Some(50), None]), Some(1));
+        // kept for a few rounds however fast the others are
+        assert_eq!(round(&mut selector, &[Some(10), Some(50), Some(10)]), None);
+        assert_eq!(round(&mut selector, &[Some(10), Some(50), Some(10)]), None);
+        assert_eq!(
+            round(&mut selector, &[Some(10), Some(50), Some(10)]),
+            Some(2)
+        );
+        // down after three failures, and left at once
+        for _ in 0..2 {
+            assert_eq!(round(&mut selector, &[None, Some(50), None]), None);
+        }
+        assert_eq!(round(&mut selector, &[None, Some(50), None]), Some(1));
+        for _ in 0..3 {
+            assert_eq!(round(&mut selector, &[None, None, None]), None);
+        }
+        assert_eq!(round(&mut selector, &[Some(30), None, None]), Some(0));
+
+        // the smoothed time has to beat the one in use by the margin
+        let mut selector = Selector::new(2, 20);
+        assert_eq!(round(&mut selector, &[Some(50), Some(40)]), None);
+        assert_eq!(round(&mut selector, &[Some(50), Some(20)]), None);
+        assert_eq!(round(&mut selector, &[Some(50), Some(10)]), Some(1));
+    }
+
+    #[test]
+    fn test_failover() {
+        use std::time::Duration;
+
+        use super::Selector;
+
+        let mut selector = Selector::new(3, 20);
+        assert_eq!(selector.connected(0, false), None);
+        assert_eq!(selector.connected(0, true), None);
+        assert_eq!(selector.connected(0, false), None);
+        assert_eq!(selector.connected(0, false), None);
+        // servers not in use going down change nothing
+        for _ in 0..3 {
+            assert_eq!(selector.connected(2, false), None);
+        }
+        // the next server not down before any probe
+        assert_eq!(selector.connected(0, false), Some(1));
+        assert_eq!(selector.connected(0, false), None);
+        // the fastest one up after
+        selector.record(2, Some(Duration::from_millis(80)));
+        assert_eq!(selector.connected(1, false), None);
+        assert_eq!(selector.connected(1, false), None);
+        assert_eq!(selector.connected(1, false), Some(2));
+        // kept if all are down
+        for _ in 0..3 {
+            assert_eq!(selector.connected(2, false), None);
+        }
+        assert_eq!(selector.current, 2);
+    }
+}
@@ -1,5 +1,5 @@
 use std::{
-    net::{IpAddr, SocketAddr},
+    net::IpAddr,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
@@ -9,18 +9,24 @@ use std::{
 use bytes::BytesMut;
 use rustls_pki_types::ServerName;
 use tokio::{
-    io::{split, AsyncWriteExt, WriteHalf},
+    io::{split, AsyncRead, AsyncWrite, AsyncWriteExt, WriteHalf},
     net::{tcp::OwnedReadHalf, TcpListener, TcpStream},
     spawn,
     sync::mpsc::UnboundedSender,
 };
-use tokio_rustls::{client::TlsStream, TlsConnector};
+use tokio_rustls::TlsConnector;
 
 use crate::{
-    aproxy::{init_tls_conn, wait_until_stop},
-    async_utils::copy,
+    aproxy::{init_tls_conn, selector, wait_until_stop},
+    async_utils::copy_with,
     config::OPTIONS,
-    proto::{TrojanRequest, CONNECT},
+    events::ConnTracker,
+    grpc,
+    limiter::{Priority, DOWNLOAD, UPLOAD},
+    memory::{self, Subsystem},
+    mux::open_stream,
+    padding,
+    proto::{Sock5Address, TrojanRequest, CONNECT},
     sys,
     types::Result,
 };
@@ -42,25 +48,95 @@ pub async fn run_tcp(
             client,
             server_name.clone(),
             connector.clone(),
-            dst_addr,
+            Sock5Address::Socket(dst_addr),
         ));
     }
 }
 
-async fn start_tcp_proxy(
-    mut local: TcpStream,
+/// A stream to the server carrying one proxied connection, whatever the transport.
+pub trait Tunnel: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Tunnel for S {}
+
+pub async fn start_tcp_proxy(
+    local: TcpStream,
     server_name: ServerName<'static>,
     connector: TlsConnector,
-    dst_addr: SocketAddr,
+    dst_addr: Sock5Address,
 ) -> Result<()> {
-    let mut remote = init_tls_conn(connector, server_name).await?;
+    let priority = Priority::of_stream(dst_addr.port());
+    let remote = connect(connector, server_name, priority).await?;
+    relay(local, remote, dst_addr).await
+}
+
+/// Opens a stream to the server over the configured transport and requests `dst_addr` on it.
+pub async fn open_tunnel(
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+    dst_addr: &Sock5Address,
+) -> Result<Box<dyn Tunnel>> {
+    let mut remote = connect(connector, server_name, Priority::of_stream(dst_addr.port())).await?;
     let mut request = BytesMut::new();
-    TrojanRequest::generate(&mut request, CONNECT, &dst_addr);
-    if let Err(err) = remote.write_all(request.as_ref()).await {
+    TrojanRequest::generate_stream(&mut request, CONNECT, dst_addr);
+    remote.write_all(request.as_ref()).await?;
+    remote.flush().await?;
+    Ok(remote)
+}
+
+/// Connects to the server, with `--mux` a stream of `priority` on the shared connection.
+pub async fn connect(
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+    priority: Priority,
+) -> Result<Box<dyn Tunnel>> {
+    if OPTIONS.grpc_service.is_some() {
+        let (_, authority, _) = selector::current();
+        return Ok(Box::new(
+            grpc::open_stream(init_tls_conn(connector, server_name), authority).await?,
+        ));
+    }
+    let conn = async {
+        let conn = init_tls_conn(connector, server_name).await?;
+        let conn: Box<dyn Tunnel> = if OPTIONS.padding {
+            Box::new(padding::open(conn))
+        } else {
+            Box::new(conn)
+        };
+        Ok(conn)
+    };
+    let remote: Box<dyn Tunnel> = if OPTIONS.mux > 0 {
+        Box::new(open_stream(conn, priority).await?)
+    } else {
+        conn.await?
+    };
+    Ok(remote)
+}
+
+pub async fn relay<S>(mut local: TcpStream, mut remote: S, dst_addr: Sock5Address) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let Some(memory) = memory::reserve(Subsystem::Tls, memory::TLS_SESSION) else {
+        let _ = remote.shutdown().await;
+        let _ = local.shutdown().await;
+        return Ok(());
+    };
+    let mut request = BytesMut::new();
+    TrojanRequest::generate_stream(&mut request, CONNECT, &dst_addr);
+    let sent = match remote.write_all(request.as_ref()).await {
+        Ok(_) => remote.flush().await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = sent {
         log::error!("send request to remote server failed:{}", err);
         let _ = remote.shutdown().await;
         let _ = local.shutdown().await;
     } else {
+        let tracker = Arc::new(
+            ConnTracker::new("tcp", local.peer_addr()?.to_string(), dst_addr.to_string())
+                .holding(memory),
+        );
+        let priority = Priority::of_stream(dst_addr.port());
         let (remote_read, remote_write) = split(remote);
         let (local_read, local_write) = local.into_split();
         let running = Arc::new(AtomicBool::new(true));
@@ -70,25 +146,48 @@ async fn start_tcp_proxy(
             remote_write,
             format!("tcp local to remote:{}", dst_addr),
             OPTIONS.tcp_idle_timeout,
+            priority,
+            tracker.clone(),
         ));
-        spawn(copy(
-            remote_read,
-            local_write,
-            format!("tcp remote:{} to local", dst_addr),
-            OPTIONS.tcp_idle_timeout,
-        ));
-        wait_until_stop(running, dst_addr.ip()).await;
+        let remote_to_local = format!("tcp remote:{} to local", dst_addr);
+        spawn(async move {
+            copy_with(
+                remote_read,
+                local_write,
+                remote_to_local,
+                OPTIONS.tcp_idle_timeout,
+                Some((&DOWNLOAD, priority)),
+                None,
+                |n| tracker.add_rx(n),
+            )
+            .await
+        });
+        // domains are resolved by the server, there is no ip to keep in the ipset then
+        if let Some(addr) = dst_addr.as_socket() {
+            wait_until_stop(running, addr.ip()).await;
+        }
     }
     Ok(())
 }
 
-async fn local_to_remote(
+async fn local_to_remote<S: AsyncWrite>(
     running: Arc<AtomicBool>,
     local: OwnedReadHalf,
-    remote: WriteHalf<TlsStream<TcpStream>>,
+    remote: WriteHalf<S>,
     message: String,
     timeout: u64,
+    priority: Priority,
+    tracker: Arc<ConnTracker>,
 ) {
-    copy(local, remote, message, timeout).await;
+    copy_with(
+        local,
+        remote,
+        message,
+        timeout,
+        Some((&UPLOAD, priority)),
+        Some(priority),
+        |n| tracker.add_tx(n),
+    )
+    .await;
     running.store(false, Ordering::SeqCst);
 }
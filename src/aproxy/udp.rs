@@ -5,7 +5,7 @@ use std::{
     net::{IpAddr, SocketAddr},
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, Mutex,
     },
     time::{Duration, Instant},
 };
@@ -21,9 +21,15 @@ use tokio::{
 use tokio_rustls::{client::TlsStream, TlsConnector};
 
 use crate::{
-    aproxy::{init_tls_conn, new_socket, wait_until_stop},
+    aproxy::{init_tls_conn, new_socket, redundant, wait_until_stop},
     config::OPTIONS,
-    proto::{TrojanRequest, UdpAssociate, UdpParseResult, UDP_ASSOCIATE},
+    limiter::{Priority, DOWNLOAD, UPLOAD},
+    memory::{self, Subsystem},
+    metrics::{incr, COUNTERS},
+    proto::{
+        Sock5Address, TrojanRequest, UdpAssociate, UdpParseResult, MAX_PACKET_SIZE, UDP_ASSOCIATE,
+    },
+    redundancy::{self, Dedup, Direction, Opener, RelayId, RelayKey, Sealer, REGISTER_TARGET},
     sys,
     types::Result,
 };
@@ -82,9 +88,14 @@ pub async fn run_udp(
                     sender.send(dst_addr.ip())?;
                 }
                 let remote = match remotes.get(&src_addr) {
-                    Some(ret) => ret,
+                    Some((ret, _)) => ret,
                     None => {
                         log::info!("remote not found for {}", src_addr);
+                        let Some(memory) = memory::reserve(Subsystem::Udp, memory::UDP_SESSION)
+                            .and_then(|memory| memory.and(Subsystem::Tls, memory::TLS_SESSION))
+                        else {
+                            continue;
+                        };
                         let local = locals.entry(dst_addr).or_insert_with(|| {
                             log::info!("local not found for {}", dst_addr);
                             let local = new_socket(dst_addr, true).unwrap();
@@ -92,7 +103,8 @@ pub async fn run_udp(
                             Arc::new(local)
                         });
                         let (req_sender, req_receiver) = channel(1024);
-                        remotes.insert(src_addr, req_sender);
+                        // the memory of the session is given back when it's removed
+                        remotes.insert(src_addr, (req_sender, memory));
                         spawn(local_to_remote(
                             req_receiver,
                             local.clone(),
@@ -102,10 +114,15 @@ pub async fn run_udp(
                             src_addr,
                             sender.clone(),
                         ));
-                        remotes.get(&src_addr).unwrap()
+                        &remotes.get(&src_addr).unwrap().0
                     }
                 };
-                let _ = remote.send((dst_addr, recv_buffer)).await;
+                if remote.capacity() == 0 {
+                    incr(&COUNTERS.backpressure);
+                }
+                if remote.send((dst_addr, recv_buffer)).await.is_err() {
+                    incr(&COUNTERS.udp_session_closed);
+                }
 
                 if last_check.elapsed().as_secs() > 3600 {
                     let addrs: Vec<_> = locals
@@ -141,8 +158,23 @@ async fn local_to_remote(
     src_addr: SocketAddr,
     sender: Sender<SocketAddr>,
 ) {
-    let mut remote = if let Ok(mut remote) = init_tls_conn(connector, server_name).await {
-        if let Err(err) = remote.write_all(request.as_ref()).await {
+    let (remote, redundant) = tokio::join!(
+        init_tls_conn(connector.clone(), server_name),
+        redundant::connect(connector)
+    );
+    let dedup = Arc::new(Mutex::new(Dedup::default()));
+    let id: RelayId = rand::random();
+    let key: RelayKey = rand::random();
+    let mut remote = if let Ok(mut remote) = remote {
+        let mut handshake = BytesMut::from(request.as_ref().as_ref());
+        // the copies through the redundant server are matched to this association by the id
+        if let Some(Ok(_)) = &redundant {
+            let registration =
+                redundancy::registration(&id, &key, OPTIONS.proxy_args().redundant_size);
+            UdpAssociate::generate(&mut handshake, &REGISTER_TARGET, registration.len() as u16);
+            handshake.extend_from_slice(registration.as_slice());
+        }
+        if let Err(err) = remote.write_all(handshake.as_ref()).await {
             let _ = remote.shutdown().await;
             let _ = sender.send(src_addr).await;
             log::error!("send handshake to remote failed:{}", err);
@@ -150,7 +182,11 @@ async fn local_to_remote(
         }
         let (read_half, write_half) = split(remote);
         spawn(remote_to_local_with_wait(
-            read_half, socket, src_addr, sender,
+            read_half,
+            socket.clone(),
+            src_addr,
+            sender,
+            redundant.is_some().then(|| dedup.clone()),
         ));
         write_half
     } else {
@@ -158,14 +194,52 @@ async fn local_to_remote(
         let _ = sender.send(src_addr).await;
         return;
     };
+    // the association goes on without the redundant path if it fails
+    let mut redundant = match redundant {
+        Some(Ok(mut redundant)) => match redundant.write_all(request.as_ref()).await {
+            Ok(_) => {
+                let (read_half, write_half) = split(redundant);
+                spawn(remote_to_local(
+                    read_half,
+                    socket,
+                    src_addr,
+                    Some((dedup, 1)),
+                    Some(Opener::new(&key, Direction::Down)),
+                ));
+                Some(write_half)
+            }
+            Err(err) => {
+                log::error!("send handshake to redundant server failed:{}", err);
+                None
+            }
+        },
+        Some(Err(err)) => {
+            log::error!("connect to redundant server failed:{:?}", err);
+            None
+        }
+        None => None,
+    };
 
+    let relay = Sock5Address::Domain(
+        OPTIONS.proxy_args().hostname.clone(),
+        OPTIONS
+            .proxy_args()
+            .redundant_relay_port
+            .unwrap_or_default(),
+    );
     let mut header = BytesMut::new();
+    let mut copy = BytesMut::new();
+    let mut sealer = Sealer::new(&key, Direction::Up);
     while let Some((target, data)) = local.recv().await {
         header.clear();
         UdpAssociate::generate(&mut header, &target, data.len() as u16);
+        UPLOAD
+            .acquire(data.len(), Priority::of_packet(target.port(), data.len()))
+            .await;
         if remote.write_all(header.as_ref()).await.is_err()
             || remote.write_all(data.as_slice()).await.is_err()
         {
+            incr(&COUNTERS.udp_remote_failed);
             log::error!(
                 "local:{} to remote:{} send failed, remote closed",
                 src_addr,
@@ -173,17 +247,46 @@ async fn local_to_remote(
             );
             break;
         }
+        if let Some(writer) = redundant.as_mut() {
+            if data.len() > OPTIONS.proxy_args().redundant_size {
+                continue;
+            }
+            sealer.wrap(&mut copy, Some(&id), &target, data.as_slice());
+            if copy.len() > MAX_PACKET_SIZE {
+                continue;
+            }
+            header.clear();
+            UdpAssociate::generate_address(&mut header, &relay, copy.len() as u16);
+            UPLOAD
+                .acquire(data.len(), Priority::of_packet(target.port(), data.len()))
+                .await;
+            if writer.write_all(header.as_ref()).await.is_err()
+                || writer.write_all(copy.as_ref()).await.is_err()
+            {
+                log::error!(
+                    "local:{} to redundant server send failed, go on without it",
+                    src_addr
+                );
+                redundant.take();
+            }
+        }
     }
     local.close();
     let _ = remote.shutdown().await;
+    if let Some(mut writer) = redundant {
+        let _ = writer.shutdown().await;
+    }
 }
 
+/// Sends the packets read from `remote` to the local client, those of a redundant association
+/// go through `dedup` with the index of their path. Path 1 carries the answers the relay point
+/// copied back, with the address of the target inside, checked by `opener`.
 async fn remote_to_local(
     mut remote: ReadHalf<TlsStream<TcpStream>>,
     local: Arc<UdpSocket>,
     src_addr: SocketAddr,
-    sender: Sender<SocketAddr>,
-    running: Arc<AtomicBool>,
+    dedup: Option<(Arc<Mutex<Dedup>>, usize)>,
+    mut opener: Option<Opener>,
 ) {
     let mut buffer = BytesMut::new();
     'main: loop {
@@ -201,16 +304,44 @@ async fn remote_to_local(
                     }
                     UdpParseResult::Packet(packet) => {
                         let payload = &packet.payload[..packet.length];
-                        let _ = local.send_to(payload, src_addr).await;
+                        let (address, payload) = match &dedup {
+                            Some((_, 1)) => {
+                                match opener.as_mut().and_then(|opener| opener.unwrap(payload)) {
+                                    Some(copy) => copy,
+                                    None => {
+                                        log::info!("invalid copy from {:?}", packet.address);
+                                        buffer.advance(packet.offset);
+                                        continue;
+                                    }
+                                }
+                            }
+                            _ => (packet.address, payload),
+                        };
+                        if let Some((dedup, path)) = &dedup {
+                            if !dedup.lock().unwrap().admit(*path, &address, payload) {
+                                buffer.advance(packet.offset);
+                                continue;
+                            }
+                        }
+                        DOWNLOAD
+                            .acquire(
+                                payload.len(),
+                                Priority::of_packet(address.port(), payload.len()),
+                            )
+                            .await;
+                        if local.send_to(payload, src_addr).await.is_err() {
+                            incr(&COUNTERS.udp_local_failed);
+                        }
                         log::info!(
                             "{:?} - {} get one packet with size:{}",
-                            packet.address,
+                            address,
                             src_addr,
                             payload.len()
                         );
                         buffer.advance(packet.offset);
                     }
                     UdpParseResult::InvalidProtocol => {
+                        incr(&COUNTERS.udp_invalid_protocol);
                         log::info!("invalid protocol close now");
                         break 'main;
                     }
@@ -229,8 +360,6 @@ async fn remote_to_local(
             }
         }
     }
-    let _ = sender.send(src_addr).await;
-    running.store(false, Ordering::SeqCst);
 }
 
 async fn remote_to_local_with_wait(
@@ -238,15 +367,22 @@ async fn remote_to_local_with_wait(
     socket: Arc<UdpSocket>,
     src_addr: SocketAddr,
     sender: Sender<SocketAddr>,
+    dedup: Option<Arc<Mutex<Dedup>>>,
 ) {
     let addr = socket.local_addr().unwrap();
     let running = Arc::new(AtomicBool::new(true));
-    spawn(remote_to_local(
-        read_half,
-        socket,
-        src_addr,
-        sender,
-        running.clone(),
-    ));
+    let stopped = running.clone();
+    spawn(async move {
+        remote_to_local(
+            read_half,
+            socket,
+            src_addr,
+            dedup.map(|dedup| (dedup, 0)),
+            None,
+        )
+        .await;
+        let _ = sender.send(src_addr).await;
+        stopped.store(false, Ordering::SeqCst);
+    });
     wait_until_stop(running, addr.ip()).await;
 }
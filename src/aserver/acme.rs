@@ -0,0 +1,608 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rcgen::{Certificate, CertificateParams, CustomExtension, DistinguishedName, DnType};
+use ring::{
+    digest::{digest, SHA256},
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use rustls::{server::Acceptor, ClientConfig, RootCertStore, ServerConfig};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    spawn,
+    sync::watch::Sender,
+    time::{sleep, timeout},
+};
+use tokio_rustls::{server::TlsStream, LazyConfigAcceptor, TlsAcceptor, TlsConnector};
+use yasna::{
+    models::{GeneralizedTime, UTCTime},
+    tags::{TAG_GENERALIZEDTIME, TAG_UTCTIME},
+    ASN1Error, ASN1ErrorKind, ASN1Result, BERReader, Tag,
+};
+
+use crate::{
+    config::OPTIONS,
+    server::{build_config, init_config, load_certs},
+    types::{Result, TrojanError},
+};
+
+/// ALPN protocol of the validation connections of tls-alpn-01.
+const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+const RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+/// How often and how many times pending authorizations and orders are polled.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_TIMES: usize = 60;
+
+lazy_static::lazy_static! {
+    /// Config answering the validation connections while an authorization is pending.
+    static ref CHALLENGE: Mutex<Option<Arc<ServerConfig>>> = Mutex::new(None);
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+struct Response {
+    status: u16,
+    location: Option<String>,
+    nonce: Option<String>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(&self) -> Result<Value> {
+        Ok(serde_json::from_slice(self.body.as_slice())?)
+    }
+}
+
+/// ACME client of one issuance, requests are signed with the account key.
+struct Client {
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    connector: TlsConnector,
+    directory: Directory,
+    nonce: Option<String>,
+    /// Account url, the public key is sent in its place until it's known
+    kid: Option<String>,
+}
+
+/// Completes the TLS handshake of a client. Validation connections of the ACME server get the
+/// challenge certificate and are closed, None is returned for them.
+pub async fn accept(
+    conn: TcpStream,
+    config: Arc<ServerConfig>,
+) -> Result<Option<TlsStream<TcpStream>>> {
+    if OPTIONS.server_args().acme_domain.is_none() {
+        return Ok(Some(TlsAcceptor::from(config).accept(conn).await?));
+    }
+    let start = LazyConfigAcceptor::new(Acceptor::default(), conn).await?;
+    let validation = start
+        .client_hello()
+        .alpn()
+        .is_some_and(|mut protocols| protocols.any(|protocol| protocol == ACME_TLS_ALPN));
+    if !validation {
+        return Ok(Some(start.into_stream(config).await?));
+    }
+    let challenge = CHALLENGE.lock().unwrap().clone();
+    match challenge {
+        Some(challenge) => {
+            let mut conn = start.into_stream(challenge).await?;
+            log::warn!("acme validation connection answered");
+            let _ = conn.shutdown().await;
+        }
+        None => log::warn!("acme validation connection without pending challenge"),
+    }
+    Ok(None)
+}
+
+/// Config to start with, a self-signed certificate stands in until the first one is issued.
+pub fn initial_config(domain: &str) -> Result<Arc<ServerConfig>> {
+    if Path::new(OPTIONS.server_args().cert.as_str()).exists() {
+        return init_config();
+    }
+    log::warn!(
+        "no certificate of {} yet, a self-signed one is used until it's issued",
+        domain
+    );
+    let cert = self_signed(domain, vec![])?;
+    build_config(
+        vec![CertificateDer::from(cert.serialize_der()?)],
+        PrivateKeyDer::Pkcs8(cert.serialize_private_key_der().into()),
+        Vec::new(),
+    )
+}
+
+/// Issues the certificate when it's missing or old from now on, new connections get it right away.
+pub fn start(domain: String, config: Arc<Sender<Arc<ServerConfig>>>) {
+    spawn(async move {
+        loop {
+            let wait = if !needs_renewal() {
+                CHECK_INTERVAL
+            } else {
+                log::warn!("issue certificate of {}", domain);
+                match issue(domain.as_str()).await.and_then(|_| init_config()) {
+                    Ok(new_config) => {
+                        config.send_replace(new_config);
+                        log::warn!("certificate of {} issued", domain);
+                        CHECK_INTERVAL
+                    }
+                    Err(err) => {
+                        log::error!("issue certificate of {} failed:{:?}", domain, err);
+                        RETRY_INTERVAL
+                    }
+                }
+            };
+            sleep(wait).await;
+        }
+    });
+}
+
+/// Whether the certificate is missing, unreadable or past its renewal time.
+fn needs_renewal() -> bool {
+    let renew_at = load_certs(OPTIONS.server_args().cert.as_str()).and_then(|certs| {
+        let leaf = certs.first().ok_or(acme_error("no certificate"))?;
+        renew_at(leaf.as_ref())
+    });
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs() as i64)
+        .unwrap_or_default();
+    match renew_at {
+        Ok(renew_at) => now >= renew_at,
+        Err(err) => {
+            log::warn!("check certificate failed:{:?}", err);
+            true
+        }
+    }
+}
+
+/// Unix time when 2/3 of the lifetime of the certificate `der` passed, 30 days before the
+/// 90 days Let's Encrypt ones expire.
+fn renew_at(der: &[u8]) -> Result<i64> {
+    let (not_before, not_after) = yasna::parse_der(der, |reader| {
+        reader.read_sequence(|reader| {
+            let validity = reader.next().read_sequence(|reader| {
+                reader.read_optional(|reader| {
+                    reader.read_tagged(Tag::context(0), |r| r.read_der())
+                })?;
+                // serial number, signature algorithm and issuer
+                for _ in 0..3 {
+                    reader.next().read_der()?;
+                }
+                let validity = reader.next().read_sequence(|reader| {
+                    Ok((read_time(reader.next())?, read_time(reader.next())?))
+                })?;
+                // subject and public key
+                reader.next().read_der()?;
+                reader.next().read_der()?;
+                // unique ids and extensions
+                loop {
+                    let value = reader.read_optional(|reader| reader.read_tagged_der())?;
+                    if value.is_none() {
+                        break;
+                    }
+                }
+                Ok(validity)
+            })?;
+            // signature algorithm and value
+            reader.next().read_der()?;
+            reader.next().read_der()?;
+            Ok(validity)
+        })
+    })
+    .map_err(|err| acme_error(format!("invalid certificate:{}", err)))?;
+    Ok(not_before + (not_after - not_before) * 2 / 3)
+}
+
+/// Unix time of an X.509 Time, either UTCTime or GeneralizedTime.
+fn read_time(reader: BERReader) -> ASN1Result<i64> {
+    let value = reader.read_tagged_der()?;
+    let time = if value.tag() == TAG_UTCTIME {
+        UTCTime::parse(value.value()).map(|time| time.datetime().unix_timestamp())
+    } else if value.tag() == TAG_GENERALIZEDTIME {
+        GeneralizedTime::parse(value.value()).map(|time| time.datetime().unix_timestamp())
+    } else {
+        None
+    };
+    time.ok_or(ASN1Error::new(ASN1ErrorKind::Invalid))
+}
+
+async fn issue(domain: &str) -> Result<()> {
+    let args = OPTIONS.server_args();
+    let mut client = Client::new(args.acme_directory.as_str()).await?;
+    let contact: Vec<_> = args
+        .acme_email
+        .iter()
+        .map(|email| format!("mailto:{}", email))
+        .collect();
+    let url = client.directory.new_account.clone();
+    let account = client
+        .post(
+            url.as_str(),
+            Some(json!({"termsOfServiceAgreed": true, "contact": contact})),
+        )
+        .await?;
+    client.kid = Some(account.location.ok_or(acme_error("no account url"))?);
+
+    let url = client.directory.new_order.clone();
+    let order = client
+        .post(
+            url.as_str(),
+            Some(json!({"identifiers": [{"type": "dns", "value": domain}]})),
+        )
+        .await?;
+    let order_url = order.location.clone().ok_or(acme_error("no order url"))?;
+    let order = order.json()?;
+    for authorization in order["authorizations"].as_array().into_iter().flatten() {
+        let url = authorization.as_str().ok_or(acme_error("invalid order"))?;
+        client.authorize(url).await?;
+    }
+
+    let mut params = CertificateParams::new(vec![domain.to_string()]);
+    params.distinguished_name = DistinguishedName::new();
+    params.distinguished_name.push(DnType::CommonName, domain);
+    let cert = Certificate::from_params(params)?;
+    let csr = cert.serialize_request_der()?;
+    let finalize = order["finalize"]
+        .as_str()
+        .ok_or(acme_error("invalid order"))?;
+    client
+        .post(finalize, Some(json!({"csr": URL_SAFE_NO_PAD.encode(csr)})))
+        .await?;
+    let order = client.poll(order_url.as_str()).await?;
+    let url = order["certificate"]
+        .as_str()
+        .ok_or(acme_error("no certificate url"))?;
+    let chain = client.post(url, None).await?.body;
+    write_file(
+        args.key.as_str(),
+        cert.serialize_private_key_pem().as_bytes(),
+    )?;
+    write_file(args.cert.as_str(), chain.as_slice())?;
+    Ok(())
+}
+
+impl Client {
+    async fn new(directory: &str) -> Result<Client> {
+        let rng = SystemRandom::new();
+        let key = account_key(&rng)?;
+        let mut root_store = RootCertStore::empty();
+        root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let config = ClientConfig::builder()
+            .with_root_certificates(root_store)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+        let response = request(&connector, "GET", directory, &[]).await?;
+        let directory = serde_json::from_slice(response.body.as_slice())?;
+        Ok(Client {
+            key,
+            rng,
+            connector,
+            directory,
+            nonce: None,
+            kid: None,
+        })
+    }
+
+    fn jwk(&self) -> Value {
+        // uncompressed point, 0x04 followed by x and y
+        let public = self.key.public_key().as_ref();
+        json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": URL_SAFE_NO_PAD.encode(&public[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&public[33..65]),
+        })
+    }
+
+    /// RFC 7638 thumbprint of the account key, the members are in lexicographic order already.
+    fn thumbprint(&self) -> String {
+        URL_SAFE_NO_PAD.encode(digest(&SHA256, self.jwk().to_string().as_bytes()))
+    }
+
+    /// Posts `payload` as a JWS signed with the account key, None for POST-as-GET.
+    async fn post(&mut self, url: &str, payload: Option<Value>) -> Result<Response> {
+        let payload = payload
+            .map(|payload| URL_SAFE_NO_PAD.encode(payload.to_string()))
+            .unwrap_or_default();
+        // a rejected nonce is retried once with the fresh one of the error response
+        for retry in [true, false] {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => request(&self.connector, "HEAD", &self.directory.new_nonce, &[])
+                    .await?
+                    .nonce
+                    .ok_or(acme_error("no nonce"))?,
+            };
+            let mut protected = json!({"alg": "ES256", "nonce": nonce, "url": url});
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.jwk(),
+            }
+            let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+            let signature = self
+                .key
+                .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+                .map_err(|_| acme_error("sign request failed"))?;
+            let body = json!({
+                "protected": protected,
+                "payload": payload,
+                "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+            });
+            let response =
+                request(&self.connector, "POST", url, body.to_string().as_bytes()).await?;
+            self.nonce = response.nonce.clone();
+            if response.status < 400 {
+                return Ok(response);
+            }
+            let problem = String::from_utf8_lossy(response.body.as_slice()).to_string();
+            if !retry || !problem.contains("badNonce") {
+                return Err(acme_error(format!(
+                    "{} answered {}:{}",
+                    url, response.status, problem
+                )));
+            }
+        }
+        unreachable!()
+    }
+
+    /// POST-as-GET `url` until its status is valid.
+    async fn poll(&mut self, url: &str) -> Result<Value> {
+        for _ in 0..POLL_TIMES {
+            let object = self.post(url, None).await?.json()?;
+            match object["status"].as_str() {
+                Some("valid") => return Ok(object),
+                Some("invalid") => return Err(acme_error(format!("{} invalid:{}", url, object))),
+                _ => sleep(POLL_INTERVAL).await,
+            }
+        }
+        Err(acme_error(format!("{} still pending", url)))
+    }
+
+    /// Proves control of the domain of an authorization with tls-alpn-01.
+    async fn authorize(&mut self, url: &str) -> Result<()> {
+        let authorization = self.post(url, None).await?.json()?;
+        if authorization["status"] == "valid" {
+            return Ok(());
+        }
+        let domain = authorization["identifier"]["value"]
+            .as_str()
+            .ok_or(acme_error("invalid authorization"))?;
+        let challenge = authorization["challenges"]
+            .as_array()
+            .and_then(|challenges| {
+                challenges
+                    .iter()
+                    .find(|challenge| challenge["type"] == "tls-alpn-01")
+            })
+            .ok_or(acme_error("tls-alpn-01 is not offered"))?;
+        let (Some(token), Some(challenge_url)) =
+            (challenge["token"].as_str(), challenge["url"].as_str())
+        else {
+            return Err(acme_error("invalid challenge"));
+        };
+        let key_authorization = format!("{}.{}", token, self.thumbprint());
+        let cert = self_signed(
+            domain,
+            vec![CustomExtension::new_acme_identifier(
+                digest(&SHA256, key_authorization.as_bytes()).as_ref(),
+            )],
+        )?;
+        let mut config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![CertificateDer::from(cert.serialize_der()?)],
+                PrivateKeyDer::Pkcs8(cert.serialize_private_key_der().into()),
+            )?;
+        config.alpn_protocols = vec![ACME_TLS_ALPN.to_vec()];
+        *CHALLENGE.lock().unwrap() = Some(Arc::new(config));
+        let challenge_url = challenge_url.to_string();
+        let ret = match self.post(challenge_url.as_str(), Some(json!({}))).await {
+            Ok(_) => self.poll(url).await.map(|_| ()),
+            Err(err) => Err(err),
+        };
+        *CHALLENGE.lock().unwrap() = None;
+        ret
+    }
+}
+
+fn acme_error(message: impl ToString) -> TrojanError {
+    TrojanError::Acme(message.to_string())
+}
+
+fn self_signed(domain: &str, extensions: Vec<CustomExtension>) -> Result<Certificate> {
+    let mut params = CertificateParams::new(vec![domain.to_string()]);
+    params.custom_extensions = extensions;
+    Ok(Certificate::from_params(params)?)
+}
+
+/// The account key is kept next to the certificate, so renewals use the same account.
+fn account_key(rng: &SystemRandom) -> Result<EcdsaKeyPair> {
+    let path =
+        PathBuf::from(OPTIONS.server_args().cert.as_str()).with_file_name("acme-account.key");
+    let pkcs8 = match std::fs::read(&path) {
+        Ok(pkcs8) => pkcs8,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, rng)
+                .map_err(|_| acme_error("generate account key failed"))?;
+            write_file(path.as_path(), pkcs8.as_ref())?;
+            log::warn!("acme account key created at {}", path.display());
+            pkcs8.as_ref().to_vec()
+        }
+        Err(err) => return Err(err.into()),
+    };
+    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_slice(), rng)
+        .map_err(|err| acme_error(format!("invalid account key {}:{}", path.display(), err)))
+}
+
+/// Writes through a temporary file readable by the owner only, as keys are written too.
+fn write_file<P: AsRef<Path>>(path: P, data: &[u8]) -> Result<()> {
+    let path = path.as_ref();
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&temp)?.write_all(data)?;
+    std::fs::rename(&temp, path)?;
+    Ok(())
+}
+
+/// Sends one https request on a connection of its own.
+async fn request(
+    connector: &TlsConnector,
+    method: &str,
+    url: &str,
+    body: &[u8],
+) -> Result<Response> {
+    let (host, port, path) = parse_url(url).ok_or(acme_error(format!("invalid url {}", url)))?;
+    let stream = TcpStream::connect((host, port)).await?;
+    let server_name = ServerName::try_from(host.to_string())?;
+    let mut stream = connector.connect(server_name, stream).await?;
+    let head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: trojan-rs\r\nContent-Type: application/jose+json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        method,
+        path,
+        host,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    let mut data = Vec::new();
+    // servers may close without close_notify, the response is complete then anyway
+    if let Err(err) = timeout(Duration::from_secs(30), stream.read_to_end(&mut data)).await? {
+        if data.is_empty() {
+            return Err(err.into());
+        }
+    }
+    parse_response(data.as_slice(), method == "HEAD")
+}
+
+/// Splits an https url into host, port and path.
+fn parse_url(url: &str) -> Option<(&str, u16, &str)> {
+    let rest = url.strip_prefix("https://")?;
+    let (authority, path) = rest.find('/').map_or((rest, "/"), |i| rest.split_at(i));
+    match authority.rsplit_once(':') {
+        Some((host, port)) => Some((host, port.parse().ok()?, path)),
+        None => Some((authority, 443, path)),
+    }
+}
+
+fn parse_response(data: &[u8], head: bool) -> Result<Response> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut response = httparse::Response::new(&mut headers);
+    let httparse::Status::Complete(offset) = response
+        .parse(data)
+        .map_err(|err| acme_error(format!("invalid response:{}", err)))?
+    else {
+        return Err(acme_error("incomplete response"));
+    };
+    let header = |name: &str| {
+        response
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .and_then(|header| std::str::from_utf8(header.value).ok())
+            .map(|value| value.trim().to_string())
+    };
+    let body = if head {
+        Vec::new()
+    } else if header("Transfer-Encoding").is_some_and(|value| value.eq_ignore_ascii_case("chunked"))
+    {
+        dechunk(&data[offset..]).ok_or(acme_error("invalid chunked response"))?
+    } else {
+        let mut body = data[offset..].to_vec();
+        if let Some(length) = header("Content-Length").and_then(|value| value.parse().ok()) {
+            body.truncate(length);
+        }
+        body
+    };
+    Ok(Response {
+        status: response.code.unwrap_or_default(),
+        location: header("Location"),
+        nonce: header("Replay-Nonce"),
+        body,
+    })
+}
+
+fn dechunk(mut data: &[u8]) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let end = data.windows(2).position(|window| window == b"\r\n")?;
+        let size = std::str::from_utf8(&data[..end]).ok()?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        data = &data[end + 2..];
+        if size == 0 {
+            return Some(body);
+        }
+        body.extend_from_slice(data.get(..size)?);
+        data = data.get(size + 2..)?;
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_parse_response() {
+        use crate::aserver::acme::{parse_response, parse_url};
+
+        assert_eq!(
+            parse_url("https://acme-v02.api.letsencrypt.org/directory"),
+            Some(("acme-v02.api.letsencrypt.org", 443, "/directory"))
+        );
+        assert_eq!(
+            parse_url("https://localhost:14000"),
+            Some(("localhost", 14000, "/"))
+        );
+        assert_eq!(parse_url("http://localhost/dir"), None);
+
+        let data = b"HTTP/1.1 201 Created\r\nReplay-Nonce: abc\r\nLocation: https://a/acct/1\r\n\
+            Transfer-Encoding: chunked\r\n\r\n3\r\n{\"a\r\n4;x=y\r\n\":1}\r\n0\r\n\r\n";
+        let response = parse_response(data, false).unwrap();
+        assert_eq!(response.status, 201);
+        assert_eq!(response.nonce.as_deref(), Some("abc"));
+        assert_eq!(response.location.as_deref(), Some("https://a/acct/1"));
+        assert_eq!(response.body, b"{\"a\":1}");
+
+        let data = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}extra";
+        assert_eq!(parse_response(data, false).unwrap().body, b"{}");
+        let data = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nReplay-Nonce: n\r\n\r\n";
+        assert_eq!(
+            parse_response(data, true).unwrap().nonce.as_deref(),
+            Some("n")
+        );
+    }
+
+    #[test]
+    fn test_renew_at() {
+        use rcgen::{date_time_ymd, Certificate, CertificateParams};
+
+        use crate::aserver::acme::renew_at;
+
+        let mut params = CertificateParams::new(vec!["example.com".to_string()]);
+        params.not_before = date_time_ymd(2024, 1, 1);
+        params.not_after = date_time_ymd(2024, 3, 31);
+        let der = Certificate::from_params(params)
+            .unwrap()
+            .serialize_der()
+            .unwrap();
+        // 2024-03-01, 60 of the 90 days
+        assert_eq!(renew_at(der.as_slice()).unwrap(), 1709251200);
+        assert!(renew_at(b"not a certificate").is_err());
+    }
+}
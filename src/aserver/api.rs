@@ -0,0 +1,298 @@
+use std::{sync::Arc, time::Duration};
+
+use bytes::BytesMut;
+use rustls::ServerConfig;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    spawn,
+    sync::watch::Sender,
+    time::timeout,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{
+    aserver::{clients, geoip, sessions},
+    config::{sha224_hex, ReverseService, OPTIONS},
+    server::{init_config, usage},
+    types::Result,
+    utils::secret_eq,
+};
+
+/// Requests larger than this are refused.
+const MAX_REQUEST: usize = 65536;
+
+/// A request to the control api.
+struct ApiRequest {
+    method: String,
+    path: String,
+    token: Option<String>,
+    body: Vec<u8>,
+}
+
+/// Body of `POST /users`, either the password or its hash is given.
+#[derive(Deserialize)]
+struct NewUser {
+    label: String,
+    password: Option<String>,
+    hash: Option<String>,
+    /// bytes in both directions
+    quota: Option<u64>,
+    /// bytes/s in each direction, in place of --limit-rate
+    rate: Option<u64>,
+}
+
+/// Starts the JSON control api panels use to manage users and sessions, `config` is replaced
+/// when the certificates are reloaded. Off the loopback it is served over TLS with the
+/// certificate of the server, so the token and the users don't cross the network in clear.
+pub async fn start(addr: &str, config: Arc<Sender<Arc<ServerConfig>>>) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    let tls = !listener.local_addr()?.ip().is_loopback();
+    log::warn!(
+        "control api listening on {}{}",
+        addr,
+        if tls { " over tls" } else { "" }
+    );
+    spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) if tls => {
+                    spawn(accept_tls(stream, config.clone()));
+                }
+                Ok((stream, _)) => {
+                    spawn(serve(stream, config.clone()));
+                }
+                Err(err) => log::error!("accept control api connection failed:{}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn accept_tls(stream: TcpStream, config: Arc<Sender<Arc<ServerConfig>>>) {
+    let mut tls_config = config.borrow().as_ref().clone();
+    // the api only speaks http/1.1
+    tls_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let acceptor = TlsAcceptor::from(Arc::new(tls_config));
+    match timeout(Duration::from_secs(5), acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => serve(stream, config).await,
+        Ok(Err(err)) => log::error!("control api tls handshake failed:{}", err),
+        Err(_) => log::error!("control api tls handshake timeout"),
+    }
+}
+
+async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    config: Arc<Sender<Arc<ServerConfig>>>,
+) {
+    let (status, body) = match timeout(Duration::from_secs(5), read_request(&mut stream)).await {
+        Ok(Some(request)) if authorized(request.token.as_deref()) => {
+            log::warn!("control api {} {}", request.method, request.path);
+            handle(&request, config.as_ref())
+        }
+        Ok(Some(_)) => (401, json!({"error": "unauthorized"})),
+        Ok(None) => (400, json!({"error": "bad request"})),
+        Err(_) => return,
+    };
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        _ => "Internal Server Error",
+    };
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    );
+    if let Err(err) = stream.write_all(response.as_bytes()).await {
+        log::error!("write control api response failed:{}", err);
+    }
+    let _ = stream.shutdown().await;
+}
+
+/// Reads a request with its body, None if it isn't valid http.
+async fn read_request<S: AsyncRead + Unpin>(stream: &mut S) -> Option<ApiRequest> {
+    let mut buffer = BytesMut::new();
+    loop {
+        if buffer.len() > MAX_REQUEST || stream.read_buf(&mut buffer).await.ok()? == 0 {
+            return None;
+        }
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut request = httparse::Request::new(&mut headers);
+        let httparse::Status::Complete(offset) = request.parse(buffer.as_ref()).ok()? else {
+            continue;
+        };
+        let header = |name: &str| {
+            request
+                .headers
+                .iter()
+                .find(|header| header.name.eq_ignore_ascii_case(name))
+                .and_then(|header| std::str::from_utf8(header.value).ok())
+        };
+        let length: usize = match header("Content-Length") {
+            Some(length) => length.trim().parse().ok()?,
+            None => 0,
+        };
+        let token = header("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_string());
+        let method = request.method?.to_string();
+        let path = request.path?.to_string();
+        if offset + length > MAX_REQUEST {
+            return None;
+        }
+        while buffer.len() < offset + length {
+            if stream.read_buf(&mut buffer).await.ok()? == 0 {
+                return None;
+            }
+        }
+        return Some(ApiRequest {
+            method,
+            path,
+            token,
+            body: buffer[offset..offset + length].to_vec(),
+        });
+    }
+}
+
+fn authorized(token: Option<&str>) -> bool {
+    let expected = OPTIONS
+        .server_args()
+        .api_token
+        .as_deref()
+        .unwrap_or_default();
+    token.is_some_and(|token| secret_eq(token, expected))
+}
+
+fn handle(request: &ApiRequest, config: &Sender<Arc<ServerConfig>>) -> (u16, Value) {
+    let path: Vec<_> = request.path.trim_matches('/').split('/').collect();
+    match (request.method.as_str(), path.as_slice()) {
+        ("GET", ["users"]) => (200, list_users()),
+        ("POST", ["users"]) => add_user(request.body.as_slice()),
+        ("DELETE", ["users", label]) => {
+            if OPTIONS.remove_user(label) {
+                let kicked = sessions::kick_user(label);
+                log::warn!("user:{} removed, {} sessions closed", label, kicked);
+                (200, json!({"removed": label, "kicked": kicked}))
+            } else {
+                (404, json!({"error": "no such user"}))
+            }
+        }
+        ("GET", ["reverse-ports"]) => (200, json!(OPTIONS.reverse_ports())),
+        ("POST", ["reverse-ports"]) => add_reverse_port(request.body.as_slice()),
+        ("DELETE", ["reverse-ports", name]) => {
+            if OPTIONS.set_reverse_port(name, None) {
+                log::warn!("reverse port of service {} removed", name);
+                (200, json!({"removed": name}))
+            } else {
+                (404, json!({"error": "no such service"}))
+            }
+        }
+        ("GET", ["sessions"]) => (200, json!(sessions::list())),
+        ("GET", ["bans"]) => (200, json!(clients::bans())),
+        ("DELETE", ["bans", ip]) => match ip.parse() {
+            Ok(ip) if clients::unban(ip) => {
+                log::warn!("ban of {} lifted", ip);
+                (200, json!({"unbanned": ip}))
+            }
+            _ => (404, json!({"error": "no such ban"})),
+        },
+        ("DELETE", ["sessions", id]) => match id.parse() {
+            Ok(id) if sessions::kick(id) => (200, json!({"kicked": id})),
+            _ => (404, json!({"error": "no such session"})),
+        },
+        ("POST", ["reload-certs"]) => match init_config() {
+            Ok(new_config) => {
+                config.send_replace(new_config);
+                log::warn!("certificates reloaded");
+                (200, json!({"reloaded": true}))
+            }
+            Err(err) => (500, json!({"error": format!("{:?}", err)})),
+        },
+        ("GET", ["geo"]) => (200, json!({"block_geoip": geoip::version()})),
+        ("POST", ["geo", "reload"]) => match geoip::reload() {
+            Some(Ok(version)) => (200, json!({"block_geoip": version})),
+            Some(Err(err)) => (500, json!({"error": format!("{:?}", err)})),
+            None => (404, json!({"error": "no geo database"})),
+        },
+        _ => (404, json!({"error": "not found"})),
+    }
+}
+
+fn list_users() -> Value {
+    let users: Vec<_> = OPTIONS
+        .user_list()
+        .into_iter()
+        .map(|(label, quota)| {
+            let record = usage::of(Some(label)).unwrap().record();
+            json!({
+                "label": label,
+                "quota": quota,
+                "rate": OPTIONS.rate(label),
+                "upload": record.upload,
+                "download": record.download,
+            })
+        })
+        .collect();
+    json!(users)
+}
+
+fn add_user(body: &[u8]) -> (u16, Value) {
+    let user: NewUser = match serde_json::from_slice(body) {
+        Ok(user) => user,
+        Err(err) => return (400, json!({"error": err.to_string()})),
+    };
+    if user.label.is_empty() || user.label.contains(char::is_whitespace) {
+        return (400, json!({"error": "label is empty or has spaces"}));
+    }
+    let hash = match (user.password, user.hash) {
+        (Some(password), None) if !password.is_empty() => sha224_hex(password.as_str()),
+        (None, Some(hash)) if hash.len() == 56 && hash.bytes().all(|c| c.is_ascii_hexdigit()) => {
+            hash.to_lowercase()
+        }
+        _ => {
+            return (
+                400,
+                json!({"error": "either password or a sha224 hash is required"}),
+            )
+        }
+    };
+    OPTIONS.add_user(hash, user.label.as_str(), user.quota, user.rate);
+    usage::refresh(user.label.as_str());
+    log::warn!("user:{} added", user.label);
+    (200, json!({"added": user.label}))
+}
+
+/// Clients publishing the service from now on get the address, a moved one is closed.
+fn add_reverse_port(body: &[u8]) -> (u16, Value) {
+    let service: ReverseService = match serde_json::from_slice(body) {
+        Ok(service) => service,
+        Err(err) => return (400, json!({"error": err.to_string()})),
+    };
+    if service.name.is_empty()
+        || service.name.len() > 255
+        || service.addr.is_empty()
+        || service.user.as_deref().is_none_or(str::is_empty)
+    {
+        return (
+            400,
+            json!({"error": "name, addr or user is empty or too long"}),
+        );
+    }
+    log::warn!(
+        "reverse port of service {} set to {} for user:{}",
+        service.name,
+        service.addr,
+        service.user.as_deref().unwrap_or_default()
+    );
+    let name = service.name.clone();
+    OPTIONS.set_reverse_port(name.as_str(), Some(service));
+    (200, json!({"added": name}))
+}
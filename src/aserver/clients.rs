@@ -0,0 +1,229 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::config::OPTIONS;
+
+lazy_static::lazy_static! {
+    static ref CLIENTS: Mutex<Clients> = Mutex::new(Clients::new(
+        OPTIONS.server_args().max_conns_per_ip,
+        OPTIONS.server_args().max_failures,
+        Duration::from_secs(OPTIONS.server_args().ban_time),
+    ));
+}
+
+/// Connections and failed handshakes of the client ips, so scanners can't exhaust the server.
+struct Clients {
+    /// concurrent connections of an ip, 0 for unlimited
+    max_conns: usize,
+    /// failed handshakes within `ban_time` an ip is banned after, 0 to never ban
+    max_failures: usize,
+    ban_time: Duration,
+    clients: HashMap<IpAddr, Client>,
+}
+
+#[derive(Default)]
+struct Client {
+    connections: usize,
+    failures: usize,
+    /// when the first of the failures counted happened
+    first_failure: Option<Instant>,
+    banned_until: Option<Instant>,
+}
+
+impl Client {
+    fn banned(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|until| until > now)
+    }
+
+    /// Entries of ips without connections, failures and ban are dropped.
+    fn idle(&self, now: Instant, ban_time: Duration) -> bool {
+        self.connections == 0
+            && !self.banned(now)
+            && self
+                .first_failure
+                .is_none_or(|time| now.duration_since(time) >= ban_time)
+    }
+}
+
+/// A banned ip as listed by the control api.
+#[derive(Serialize)]
+pub struct BanInfo {
+    pub ip: IpAddr,
+    /// seconds until the ban is lifted
+    pub remaining: u64,
+}
+
+impl Clients {
+    fn new(max_conns: usize, max_failures: usize, ban_time: Duration) -> Clients {
+        Clients {
+            max_conns,
+            max_failures,
+            ban_time,
+            clients: HashMap::new(),
+        }
+    }
+
+    fn admit(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let client = self.clients.entry(ip).or_default();
+        if client.banned(now) {
+            log::info!("banned client {} rejected", ip);
+            return false;
+        }
+        if self.max_conns > 0 && client.connections >= self.max_conns {
+            log::warn!(
+                "client {} has {} connections, rejected",
+                ip,
+                client.connections
+            );
+            return false;
+        }
+        client.connections += 1;
+        true
+    }
+
+    fn release(&mut self, ip: IpAddr) {
+        if let Some(client) = self.clients.get_mut(&ip) {
+            client.connections = client.connections.saturating_sub(1);
+        }
+    }
+
+    fn failed(&mut self, ip: IpAddr, now: Instant) {
+        if self.max_failures == 0 {
+            return;
+        }
+        let client = self.clients.entry(ip).or_default();
+        if client
+            .first_failure
+            .is_none_or(|time| now.duration_since(time) >= self.ban_time)
+        {
+            client.first_failure = Some(now);
+            client.failures = 0;
+        }
+        client.failures += 1;
+        if client.failures >= self.max_failures {
+            log::warn!(
+                "client {} banned for {}s after {} failed handshakes",
+                ip,
+                self.ban_time.as_secs(),
+                client.failures
+            );
+            client.banned_until = Some(now + self.ban_time);
+            client.first_failure = None;
+            client.failures = 0;
+        }
+    }
+
+    fn purge(&mut self, now: Instant) {
+        let ban_time = self.ban_time;
+        self.clients.retain(|_, client| !client.idle(now, ban_time));
+    }
+}
+
+/// Counts a connection of the client ip until dropped.
+pub struct ClientGuard(IpAddr);
+
+impl ClientGuard {
+    /// The connection failed its handshake, which counts towards a ban.
+    pub fn failed(&self) {
+        CLIENTS.lock().unwrap().failed(self.0, Instant::now());
+    }
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        CLIENTS.lock().unwrap().release(self.0);
+    }
+}
+
+/// None if the ip is banned or has too many connections already.
+pub fn admit(ip: IpAddr) -> Option<ClientGuard> {
+    let ip = ip.to_canonical();
+    // a guard built for nothing would release a connection when dropped
+    let admitted = CLIENTS.lock().unwrap().admit(ip, Instant::now());
+    admitted.then(|| ClientGuard(ip))
+}
+
+/// Drops the entries of ips which are done, called periodically.
+pub fn purge() {
+    CLIENTS.lock().unwrap().purge(Instant::now());
+}
+
+pub fn bans() -> Vec<BanInfo> {
+    let now = Instant::now();
+    let mut bans: Vec<_> = CLIENTS
+        .lock()
+        .unwrap()
+        .clients
+        .iter()
+        .filter_map(|(ip, client)| {
+            let until = client.banned_until.filter(|until| *until > now)?;
+            Some(BanInfo {
+                ip: *ip,
+                remaining: until.duration_since(now).as_secs(),
+            })
+        })
+        .collect();
+    bans.sort_unstable_by_key(|ban| ban.ip);
+    bans
+}
+
+/// Lifts the ban of the ip, false if it isn't banned.
+pub fn unban(ip: IpAddr) -> bool {
+    let now = Instant::now();
+    let mut clients = CLIENTS.lock().unwrap();
+    match clients.clients.get_mut(&ip.to_canonical()) {
+        Some(client) if client.banned(now) => {
+            client.banned_until = None;
+            true
+        }
+        _ => false,
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_clients() {
+        use std::{
+            net::IpAddr,
+            time::{Duration, Instant},
+        };
+
+        use super::Clients;
+
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        let mut clients = Clients::new(2, 3, Duration::from_secs(60));
+        let now = Instant::now();
+        assert!(clients.admit(ip, now));
+        assert!(clients.admit(ip, now));
+        assert!(!clients.admit(ip, now));
+        assert!(clients.admit(other, now));
+        clients.release(ip);
+        assert!(clients.admit(ip, now));
+
+        // failures older than the ban time are forgotten
+        clients.failed(other, now);
+        clients.failed(other, now);
+        let later = now + Duration::from_secs(61);
+        clients.failed(other, later);
+        clients.failed(other, later);
+        assert!(clients.admit(other, later));
+        clients.release(other);
+        clients.failed(other, later);
+        assert!(!clients.admit(other, later));
+        assert!(clients.admit(other, later + Duration::from_secs(60)));
+
+        clients.release(ip);
+        clients.release(ip);
+        clients.release(other);
+        clients.release(other);
+        clients.purge(later + Duration::from_secs(60));
+        assert!(clients.clients.is_empty());
+    }
+}
@@ -0,0 +1,38 @@
+//! Target ips refused by --block-geoip, swapped by the control api without a restart.
+
+use std::{net::IpAddr, sync::OnceLock};
+
+use crate::{
+    config::OPTIONS,
+    geo::{GeoDatabase, GeoIp},
+    types::Result,
+};
+
+static BLOCKED: OnceLock<GeoDatabase<GeoIp>> = OnceLock::new();
+
+/// Loads --block-geoip if given, the server refuses to start with an unreadable one.
+pub fn init() -> Result<()> {
+    if let Some(path) = &OPTIONS.server_args().block_geoip {
+        let database = BLOCKED.get_or_init(|| GeoDatabase::new(path.as_str()));
+        database.reload()?;
+    }
+    Ok(())
+}
+
+pub fn is_blocked(ip: IpAddr) -> bool {
+    BLOCKED
+        .get()
+        .is_some_and(|database| database.snapshot().data.contains(ip))
+}
+
+/// Version of the database in use, None without --block-geoip.
+pub fn version() -> Option<String> {
+    BLOCKED
+        .get()
+        .map(|database| database.snapshot().version.clone())
+}
+
+/// Reads the file again, the old data stays in use if that fails.
+pub fn reload() -> Option<Result<String>> {
+    BLOCKED.get().map(GeoDatabase::reload)
+}
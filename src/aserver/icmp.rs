@@ -0,0 +1,296 @@
+//! ICMP echo requests of clients sent from a raw socket of the server, so that ping and
+//! traceroute inside the tunnel see the path from the server. Only ipv4 is relayed.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use bytes::{Buf, BytesMut};
+use smoltcp::wire::{Icmpv4Message, Icmpv4Packet, IpProtocol, Ipv4Packet};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::UdpSocket,
+    time::{sleep_until, Instant},
+};
+
+use crate::{
+    aserver::geoip,
+    config::OPTIONS,
+    proto::{IcmpEcho, IcmpParseResult, MAX_PACKET_SIZE},
+    server::usage::Usage,
+    types::Result,
+    utils::is_private,
+};
+
+/// Probes waiting for an answer, the oldest are dropped beyond this.
+const MAX_PENDING: usize = 256;
+/// Probes not answered by then never will be.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Every association sees all ICMP messages of the host, and tells its own by the identifier.
+static NEXT_IDENT: AtomicU16 = AtomicU16::new(1);
+
+/// A request sent on behalf of the client, under the association identifier and its own
+/// sequence number.
+struct Probe {
+    target: Ipv4Addr,
+    ident: u16,
+    seq: u16,
+    sent: Instant,
+}
+
+/// Relays the echo requests of a trojan ICMP association until the client closes it or it's
+/// idle for the udp timeout.
+pub async fn start_icmp<S>(
+    source: S,
+    mut buffer: BytesMut,
+    src_addr: SocketAddr,
+    usage: Option<Arc<Usage>>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut source_read, mut source_write) = split(source);
+    if !OPTIONS.server_args().relay_icmp {
+        log::warn!("icmp relay is not enabled, reject {}", src_addr);
+        let _ = source_write.shutdown().await;
+        return Ok(());
+    }
+    let socket = raw_socket()?;
+    let ident = NEXT_IDENT.fetch_add(1, Ordering::Relaxed);
+    let mut next_seq = 0u16;
+    let mut probes: HashMap<u16, Probe> = HashMap::new();
+    let mut request = Vec::new();
+    let mut response = vec![0u8; MAX_PACKET_SIZE];
+    let mut frame = BytesMut::new();
+    let mut deadline = Instant::now() + OPTIONS.udp_idle_duration;
+    'main: loop {
+        loop {
+            let echo = match IcmpEcho::parse(buffer.as_ref()) {
+                IcmpParseResult::Packet(echo, size) => {
+                    buffer.advance(size);
+                    echo
+                }
+                IcmpParseResult::InvalidProtocol => {
+                    log::error!("invalid icmp frame from {}", src_addr);
+                    break 'main;
+                }
+                IcmpParseResult::Continued => break,
+            };
+            if let Some(usage) = &usage {
+                if usage.exceeded() {
+                    log::warn!("quota exceeded, close icmp from {}", src_addr);
+                    break 'main;
+                }
+                usage.add_upload(echo.data.len());
+            }
+            let target = match echo.target {
+                IpAddr::V4(target) => target,
+                IpAddr::V6(target) => {
+                    log::warn!("skip icmp to {}, only ipv4 is relayed", target);
+                    continue;
+                }
+            };
+            let target_addr = SocketAddr::new(echo.target, 0);
+            if !OPTIONS.server_args().allow_private && is_private(&target_addr) {
+                log::error!("address:{} is private which is not allowed", target);
+                continue;
+            }
+            if geoip::is_blocked(echo.target) {
+                log::error!("address:{} is blocked by geoip", target);
+                continue;
+            }
+            if probes.len() >= MAX_PENDING {
+                probes.retain(|_, probe| probe.sent.elapsed() < PROBE_TIMEOUT);
+                if probes.len() >= MAX_PENDING {
+                    log::warn!("too many icmp probes of {} pending", src_addr);
+                    continue;
+                }
+            }
+            let seq = next_seq;
+            next_seq = next_seq.wrapping_add(1);
+            echo_request(&mut request, ident, seq, echo.data.as_slice());
+            let sent = match socket.set_ttl(echo.ttl.max(1) as u32) {
+                Ok(_) => socket.send_to(request.as_slice(), target_addr).await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = sent {
+                log::warn!("send icmp to {} failed:{}", target, err);
+                continue;
+            }
+            probes.insert(
+                seq,
+                Probe {
+                    target,
+                    ident: echo.ident,
+                    seq: echo.seq,
+                    sent: Instant::now(),
+                },
+            );
+        }
+        let size = tokio::select! {
+            ret = source_read.read_buf(&mut buffer) => {
+                match ret {
+                    Ok(0) => break,
+                    Ok(_) => {}
+                    Err(err) => {
+                        log::warn!("read from source failed:{}", err);
+                        break;
+                    }
+                }
+                deadline = Instant::now() + OPTIONS.udp_idle_duration;
+                continue;
+            }
+            ret = socket.recv(response.as_mut_slice()) => match ret {
+                Ok(size) => size,
+                Err(err) => {
+                    log::warn!("receive icmp failed:{}", err);
+                    break;
+                }
+            },
+            _ = sleep_until(deadline) => {
+                log::info!("icmp association of {} idle", src_addr);
+                break;
+            }
+        };
+        let Some((from, kind, code, seq)) = match_answer(&response[..size], ident) else {
+            continue;
+        };
+        let Some(probe) = probes.remove(&seq) else {
+            continue;
+        };
+        if let Some(usage) = &usage {
+            usage.add_download(size);
+        }
+        frame.clear();
+        IcmpEcho {
+            target: probe.target.into(),
+            from: from.into(),
+            kind,
+            code,
+            ttl: 0,
+            ident: probe.ident,
+            seq: probe.seq,
+            data: vec![],
+        }
+        .generate(&mut frame);
+        if let Err(err) = source_write.write_all(frame.as_ref()).await {
+            log::warn!("send icmp answer to {} failed:{}", src_addr, err);
+            break;
+        }
+        deadline = Instant::now() + OPTIONS.udp_idle_duration;
+    }
+    let _ = source_write.shutdown().await;
+    Ok(())
+}
+
+/// Opening raw sockets needs root or CAP_NET_RAW.
+fn raw_socket() -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4))?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+fn echo_request(buffer: &mut Vec<u8>, ident: u16, seq: u16, data: &[u8]) {
+    buffer.clear();
+    buffer.resize(8 + data.len(), 0);
+    let mut packet = Icmpv4Packet::new_unchecked(buffer.as_mut_slice());
+    packet.set_msg_type(Icmpv4Message::EchoRequest);
+    packet.set_msg_code(0);
+    packet.set_echo_ident(ident);
+    packet.set_echo_seq_no(seq);
+    packet.data_mut().copy_from_slice(data);
+    packet.fill_checksum();
+}
+
+/// Returns the sender, type, code and sequence number of an echo reply or error answering a
+/// request of the association with `ident`, from a packet read by the raw socket.
+fn match_answer(packet: &[u8], ident: u16) -> Option<(Ipv4Addr, u8, u8, u16)> {
+    let ip = Ipv4Packet::new_checked(packet).ok()?;
+    let icmp = Icmpv4Packet::new_checked(ip.payload()).ok()?;
+    let (kind, seq) = match icmp.msg_type() {
+        Icmpv4Message::EchoReply if icmp.echo_ident() == ident => {
+            (Icmpv4Message::EchoReply, icmp.echo_seq_no())
+        }
+        kind @ (Icmpv4Message::TimeExceeded | Icmpv4Message::DstUnreachable) => {
+            // the quoted request, its header and the first 8 bytes of its icmp message
+            let quoted = Ipv4Packet::new_unchecked(icmp.data());
+            let header_len = *icmp.data().first()? as usize & 0x0f;
+            let request = icmp.data().get(header_len * 4..header_len * 4 + 8)?;
+            let request = Icmpv4Packet::new_unchecked(request);
+            if quoted.next_header() != IpProtocol::Icmp
+                || request.msg_type() != Icmpv4Message::EchoRequest
+                || request.echo_ident() != ident
+            {
+                return None;
+            }
+            (kind, request.echo_seq_no())
+        }
+        _ => return None,
+    };
+    Some((ip.src_addr().into(), kind.into(), icmp.msg_code(), seq))
+}
+
+mod tests {
+    #[test]
+    fn test_match_answer() {
+        use std::net::Ipv4Addr;
+
+        use async_smoltcp::EchoRequest;
+        use smoltcp::{
+            phy::ChecksumCapabilities,
+            wire::{Icmpv4Packet, IpProtocol, Ipv4Packet, Ipv4Repr},
+        };
+
+        use super::{echo_request, match_answer};
+
+        let mut icmp = Vec::new();
+        echo_request(&mut icmp, 0x4321, 9, b"probe");
+        let repr = Ipv4Repr {
+            src_addr: Ipv4Addr::new(10, 0, 0, 2).into(),
+            dst_addr: Ipv4Addr::new(1, 1, 1, 1).into(),
+            next_header: IpProtocol::Icmp,
+            payload_len: icmp.len(),
+            hop_limit: 2,
+        };
+        let mut packet = vec![0u8; 20 + icmp.len()];
+        repr.emit(
+            &mut Ipv4Packet::new_unchecked(&mut packet),
+            &ChecksumCapabilities::default(),
+        );
+        packet[20..].copy_from_slice(icmp.as_slice());
+        assert!(Icmpv4Packet::new_checked(&packet[20..])
+            .unwrap()
+            .verify_checksum());
+
+        let request = EchoRequest::parse(packet.as_slice()).unwrap();
+        assert_eq!((request.ttl, request.ident, request.seq), (2, 0x4321, 9));
+        assert_eq!(request.data, b"probe");
+
+        let reply = request.reply();
+        assert_eq!(
+            match_answer(reply.as_slice(), 0x4321),
+            Some((Ipv4Addr::new(1, 1, 1, 1), 0, 0, 9))
+        );
+        assert_eq!(match_answer(reply.as_slice(), 0x4322), None);
+
+        let router = Ipv4Addr::new(192, 0, 2, 1);
+        let exceeded = request.error(router, 11, 0);
+        assert!(Icmpv4Packet::new_checked(&exceeded[20..])
+            .unwrap()
+            .verify_checksum());
+        assert_eq!(
+            match_answer(exceeded.as_slice(), 0x4321),
+            Some((router, 11, 0, 9))
+        );
+        assert_eq!(match_answer(exceeded.as_slice(), 1), None);
+        assert_eq!(match_answer(&exceeded[..30], 0x4321), None);
+    }
+}
@@ -1,4 +1,6 @@
 use std::{
+    future::Future,
+    io::Read,
     net::{IpAddr, SocketAddr},
     sync::{
         atomic::{AtomicU32, Ordering},
@@ -8,29 +10,61 @@ use std::{
 };
 
 use bytes::{Buf, BytesMut};
+use futures::future::select_all;
+use quinn::Endpoint;
+use rustls::ServerConfig;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::{lookup_host, TcpListener, TcpStream},
     runtime::{Handle, Runtime},
     spawn,
-    sync::mpsc::{unbounded_channel, UnboundedSender},
+    sync::{
+        mpsc::{channel, unbounded_channel, UnboundedSender},
+        watch::{self, Receiver},
+    },
     time::timeout,
 };
-use tokio_rustls::TlsAcceptor;
+use tokio_rustls::server::TlsStream;
 
 use crate::{
+    allocator,
     aserver::{
-        ping::{start_check_routine, start_ping},
+        clients::ClientGuard,
+        icmp::start_icmp,
+        ping::{start_check_routine, start_ping, PingResult},
+        sessions::SessionGuard,
         tcp::start_tcp,
         udp::start_udp,
     },
     config::OPTIONS,
-    proto::{RequestParseResult, Sock5Address, TrojanRequest, CONNECT, PING, UDP_ASSOCIATE},
-    server::{init_config, ping_backend::PingResult},
+    grpc,
+    mux::Session,
+    padding,
+    proto::{
+        RequestParseResult, Sock5Address, TrojanRequest, CONNECT, ICMP, MUX, PADDED, PING, REVERSE,
+        RULES, UDP_ASSOCIATE,
+    },
+    quic::{self, QuicStream},
+    reverse::serve_reverse,
+    rules::{serve_rules, start_publisher, Frames},
+    server::{
+        bind_listeners, bind_quic_sockets, certs, health, init_config, prepare_service,
+        quic_config, reload, stat,
+        usage::{self, Usage},
+    },
+    sys,
     types::{Result, TrojanError},
+    watchdog,
 };
 
+mod acme;
+mod api;
+mod clients;
+mod geoip;
+mod icmp;
 mod ping;
+mod relay_point;
+mod sessions;
 mod tcp;
 mod udp;
 
@@ -40,21 +74,105 @@ pub fn run() -> Result<()> {
 }
 
 async fn async_run() -> Result<()> {
-    let config = init_config()?;
-    let acceptor = TlsAcceptor::from(config);
-    let listener = TcpListener::bind(OPTIONS.local_addr.as_str()).await?;
+    let config = match &OPTIONS.server_args().acme_domain {
+        Some(domain) => acme::initial_config(domain.as_str())?,
+        None => init_config()?,
+    };
+    prepare_service()?;
+    geoip::init()?;
+    if let Some(path) = &OPTIONS.server_args().usage_file {
+        usage::start(path.as_str())?;
+    }
+    let (config_sender, config) = watch::channel(config);
+    let config_sender = Arc::new(config_sender);
+    if let Some(addr) = &OPTIONS.server_args().api_addr {
+        api::start(addr.as_str(), config_sender.clone()).await?;
+    }
+    if let Some(port) = OPTIONS.server_args().relay_port {
+        relay_point::start(port)?;
+    }
+    if let Some(domain) = &OPTIONS.server_args().acme_domain {
+        acme::start(domain.clone(), config_sender.clone());
+    }
+    let endpoints = if OPTIONS.quic() {
+        let config = match quic_config() {
+            Ok(config) => Some(config),
+            // set once the certificate is issued and written
+            Err(err) if OPTIONS.server_args().acme_domain.is_some() => {
+                log::warn!("no quic config without a certificate yet:{:?}", err);
+                None
+            }
+            Err(err) => return Err(err),
+        };
+        bind_quic_sockets()?
+            .into_iter()
+            .map(|socket| quic::endpoint(socket, config.clone()))
+            .collect::<Result<Vec<_>>>()?
+    } else {
+        Vec::new()
+    };
+    let quic_endpoints = endpoints.clone();
+    if let Err(err) = certs::watch(move |config| {
+        config_sender.send_replace(config);
+        if quic_endpoints.is_empty() {
+            return;
+        }
+        match quic_config() {
+            Ok(config) => {
+                for endpoint in &quic_endpoints {
+                    endpoint.set_server_config(Some(config.clone()));
+                }
+            }
+            Err(err) => log::error!("reload quic config failed:{:?}", err),
+        }
+    }) {
+        log::error!("watch certificates failed:{:?}", err);
+    }
+    let listeners = bind_listeners()?
+        .into_iter()
+        .map(TcpListener::from_std)
+        .collect::<std::io::Result<Vec<_>>>()?;
     let (req_sender, req_receiver) = unbounded_channel();
     let task_count = Arc::new(AtomicU32::new(0));
     spawn(start_check_routine(req_receiver));
+    for endpoint in &endpoints {
+        spawn(run_quic(endpoint.clone(), task_count.clone()));
+    }
+    let rules = start_publisher()?;
+    let mut check = tokio::time::interval(Duration::from_secs(1));
+    let mut save_stat = tokio::time::interval(Duration::from_secs(60));
+    let heartbeat = watchdog::register("server");
     loop {
-        let (client, src_addr) = listener.accept().await?;
+        heartbeat.beat();
+        let (client, src_addr) = tokio::select! {
+            (ret, _, _) = select_all(listeners.iter().map(|listener| Box::pin(listener.accept()))) => ret?,
+            _ = check.tick() => {
+                if sys::terminated() {
+                    break;
+                }
+                reload::check();
+                allocator::check();
+                clients::purge();
+                continue;
+            }
+            _ = save_stat.tick() => {
+                stat::save();
+                continue;
+            }
+        };
+        // dropped before the handshake, which is the expensive part
+        let Some(guard) = clients::admit(src_addr.ip()) else {
+            continue;
+        };
         log::info!("accept {}", src_addr);
         task_count.fetch_add(1, Ordering::Relaxed);
         spawn(start_proxy(
             client,
-            acceptor.clone(),
+            config.borrow().clone(),
             req_sender.clone(),
+            rules.clone(),
             src_addr,
+            guard,
             task_count.clone(),
         ));
         log::error!(
@@ -63,32 +181,208 @@ async fn async_run() -> Result<()> {
             Handle::current().metrics().active_tasks_count()
         );
     }
+    log::warn!("SIGTERM received, stop accepting new connections");
+    health::set_draining();
+    drop(listeners);
+    for endpoint in &endpoints {
+        endpoint.set_server_config(None);
+    }
+    let _ = timeout(
+        Duration::from_secs(OPTIONS.server_args().shutdown_timeout),
+        async {
+            while task_count.load(Ordering::Relaxed) > 0 {
+                check.tick().await;
+            }
+        },
+    )
+    .await;
+    if let Some(path) = &OPTIONS.server_args().usage_file {
+        usage::save(path.as_str());
+    }
+    stat::save();
+    log::warn!("server drained, exit now");
+    Ok(())
 }
 
 async fn start_proxy(
     conn: TcpStream,
-    acceptor: TlsAcceptor,
+    config: Arc<ServerConfig>,
     sender: UnboundedSender<(IpAddr, UnboundedSender<PingResult>)>,
+    rules: Option<Receiver<Arc<Frames>>>,
     src_addr: SocketAddr,
+    guard: ClientGuard,
     task_count: Arc<AtomicU32>,
 ) {
-    if let Err(err) = start_proxy_internal(conn, acceptor, sender, src_addr).await {
+    if let Err(err) = start_proxy_internal(conn, config, sender, rules, src_addr, &guard).await {
         log::error!("run proxy failed:{:?}", err);
     }
     task_count.fetch_sub(1, Ordering::Relaxed);
 }
 
+/// Accepts the QUIC connections of `endpoint`, admitted like those of the tcp listeners.
+async fn run_quic(endpoint: Endpoint, task_count: Arc<AtomicU32>) {
+    while let Some(incoming) = endpoint.accept().await {
+        let src_addr = incoming.remote_address();
+        let Some(guard) = clients::admit(src_addr.ip()) else {
+            incoming.refuse();
+            continue;
+        };
+        log::info!("accept quic {}", src_addr);
+        task_count.fetch_add(1, Ordering::Relaxed);
+        let task_count = task_count.clone();
+        spawn(async move {
+            match incoming.await {
+                Ok(conn) => {
+                    let conn = quic::Accepted::new(conn);
+                    while let Some(stream) = conn.accept().await {
+                        spawn(serve_quic_stream(stream, conn.clone(), src_addr));
+                    }
+                }
+                Err(err) => {
+                    guard.failed();
+                    log::error!("quic handshake with {} failed:{}", src_addr, err);
+                }
+            }
+            drop(guard);
+            task_count.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+}
+
 async fn start_proxy_internal(
     conn: TcpStream,
-    acceptor: TlsAcceptor,
+    config: Arc<ServerConfig>,
     sender: UnboundedSender<(IpAddr, UnboundedSender<PingResult>)>,
+    rules: Option<Receiver<Arc<Frames>>>,
     src_addr: SocketAddr,
+    guard: &ClientGuard,
 ) -> Result<()> {
-    let mut conn = acceptor.accept(conn).await?;
-    let mut buffer = BytesMut::new();
+    let accepted = acme::accept(conn, config).await;
+    // clients closing pooled connections unused aren't counted, only failed tls handshakes
+    if accepted.is_err() {
+        guard.failed();
+    }
+    let Some(mut conn) = accepted? else {
+        return Ok(());
+    };
+    if OPTIONS.grpc_service.is_some() && conn.get_ref().1.alpn_protocol() == Some(b"h2") {
+        let path = grpc::path();
+        grpc::serve(conn, path.as_str(), |stream| serve_stream(stream, src_addr)).await?;
+        log::info!("grpc connection from {} closed", src_addr);
+        return Ok(());
+    }
+    let early = early_data(&mut conn);
+    let Some(Request {
+        cmd,
+        target_addr,
+        buffer,
+        user,
+    }) = read_request(&mut conn, early, src_addr).await?
+    else {
+        let _ = conn.shutdown().await;
+        return Ok(());
+    };
+    log::info!(
+        "cmd:{} {} - {} user:{}",
+        cmd,
+        src_addr,
+        target_addr,
+        user.unwrap_or("-")
+    );
+    let Some(usage) = check_quota(user) else {
+        let _ = conn.shutdown().await;
+        return Ok(());
+    };
+    let session = sessions::register(user, src_addr, target_addr, cmd);
+    let serve = async {
+        match cmd {
+            CONNECT => start_tcp(conn, target_addr, buffer, src_addr, usage).await,
+            UDP_ASSOCIATE => start_udp(conn, buffer, src_addr, usage).await,
+            PING => start_ping(conn, buffer, sender.clone()).await,
+            RULES => match rules {
+                Some(rules) => serve_rules(conn, rules).await,
+                None => {
+                    log::warn!("rule push is not configured, reject {}", src_addr);
+                    let _ = conn.shutdown().await;
+                    Ok(())
+                }
+            },
+            MUX => {
+                start_mux(conn, buffer, src_addr).await;
+                Ok(())
+            }
+            REVERSE => serve_reverse(conn, buffer, src_addr, user, usage).await,
+            ICMP => start_icmp(conn, buffer, src_addr, usage).await,
+            PADDED => start_padded(conn, buffer, src_addr).await,
+            _ => {
+                unreachable!()
+            }
+        }
+    };
+    until_kicked(session, serve).await
+}
+
+/// Serves a request until it's done or its session is kicked through the control api.
+async fn until_kicked<F: Future<Output = Result<()>>>(
+    session: SessionGuard,
+    serve: F,
+) -> Result<()> {
+    tokio::select! {
+        ret = serve => ret,
+        _ = session.kicked() => {
+            log::warn!("session {} kicked", session.id());
+            Ok(())
+        }
+    }
+}
+
+/// A trojan request read from a client.
+struct Request {
+    cmd: u8,
+    target_addr: SocketAddr,
+    /// Data following the request
+    buffer: BytesMut,
+    /// Label of the user, None for connections passed to the backend
+    user: Option<&'static str>,
+}
+
+/// Takes the data sent as tls early data by a client resuming its session, the request and
+/// what follows it usually.
+fn early_data(conn: &mut TlsStream<TcpStream>) -> BytesMut {
+    let mut data = Vec::new();
+    if let Some(mut early) = conn.get_mut().1.early_data() {
+        if let Err(err) = early.read_to_end(&mut data) {
+            log::warn!("read early data failed:{}", err);
+        }
+    }
+    if !data.is_empty() {
+        log::info!("{} bytes of early data received", data.len());
+    }
+    data.as_slice().into()
+}
+
+/// Reads the trojan request of a client.
+async fn read_request<S: AsyncRead + Unpin>(
+    conn: &mut S,
+    mut buffer: BytesMut,
+    src_addr: SocketAddr,
+) -> Result<Option<Request>> {
+    // received as tls early data already
+    let mut early = buffer.len();
     let now = Instant::now();
     let ret = loop {
-        match timeout(Duration::from_secs(10), conn.read_buf(&mut buffer)).await {
+        // clients may keep connections ready in a pool, the request follows quickly once it starts
+        let wait = if buffer.is_empty() {
+            Duration::from_secs(OPTIONS.tcp_idle_timeout)
+        } else {
+            Duration::from_secs(10)
+        };
+        let read = if early > 0 {
+            Ok(Ok(std::mem::take(&mut early)))
+        } else {
+            timeout(wait, conn.read_buf(&mut buffer)).await
+        };
+        match read {
             Ok(Ok(0)) => {
                 log::error!("source:{} shutdown connection", src_addr);
                 break None;
@@ -97,11 +391,12 @@ async fn start_proxy_internal(
                 log::info!("read {} bytes from client {}", n, src_addr);
                 match TrojanRequest::parse(buffer.as_ref()) {
                     RequestParseResult::PassThrough => {
-                        break Some((CONNECT, *OPTIONS.back_addr.as_ref().unwrap()));
+                        break Some((CONNECT, OPTIONS.fallback_addr(), None));
                     }
                     RequestParseResult::Request(request) => {
                         let offset = request.offset;
                         let cmd = request.command;
+                        let user = request.user;
                         let address = request.address;
                         buffer.advance(offset);
                         break Some((
@@ -112,9 +407,10 @@ async fn start_proxy_internal(
                                     .await?
                                     .next()
                                     .ok_or(TrojanError::Resolve)?,
-                                Sock5Address::None => *OPTIONS.back_addr.as_ref().unwrap(),
+                                Sock5Address::None => OPTIONS.fallback_addr(),
                                 _ => unreachable!(),
                             },
+                            Some(user),
                         ));
                     }
                     RequestParseResult::InvalidProtocol => {
@@ -130,31 +426,153 @@ async fn start_proxy_internal(
                 log::error!("read from source:{} failed with {}", src_addr, err);
                 break None;
             }
-            Err(err) => {
+            Err(err) if buffer.is_empty() => {
                 log::error!("read from source:{} timeout {}", src_addr, err);
                 break None;
             }
-        }
-    };
-    if let Some((cmd, target_addr)) = ret {
-        log::info!("cmd:{} {} - {}", cmd, src_addr, target_addr);
-        match cmd {
-            CONNECT => start_tcp(conn, target_addr, buffer, src_addr).await,
-            UDP_ASSOCIATE => start_udp(conn, buffer).await,
-            PING => start_ping(conn, buffer, sender.clone()).await,
-            _ => {
-                unreachable!()
+            Err(_) => {
+                // a trojan client sends the request at once, a stalled one is a prober
+                log::error!("incomplete request from {}, pass through", src_addr);
+                break Some((CONNECT, OPTIONS.fallback_addr(), None));
             }
         }
-    } else {
-        let time = now.elapsed().as_millis();
+    };
+    if ret.is_none() {
         log::error!(
             "read request from {} failed with {} bytes after {} ms",
             src_addr,
             buffer.len(),
-            time
+            now.elapsed().as_millis()
         );
-        let _ = conn.shutdown().await;
-        Ok(())
+    }
+    Ok(ret.map(|(cmd, target_addr, user)| Request {
+        cmd,
+        target_addr,
+        buffer,
+        user,
+    }))
+}
+
+/// Counters of the user, None if the user has used up the quota.
+fn check_quota(user: Option<&str>) -> Option<Option<Arc<Usage>>> {
+    let usage = usage::of(user);
+    if usage.as_ref().is_some_and(|usage| usage.exceeded()) {
+        log::warn!("user:{} exceeded the quota, rejected", user.unwrap());
+        return None;
+    }
+    Some(usage)
+}
+
+/// Serves the streams of a multiplexed connection, each of them like a connection of its own.
+async fn start_mux<S>(conn: S, buffer: BytesMut, src_addr: SocketAddr)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (accept, mut accepted) = channel(16);
+    // the session closes when it is dropped and all of its streams are done.
+    let _session = Session::start(conn, buffer, Some(accept), OPTIONS.tcp_idle_timeout);
+    while let Some(stream) = accepted.recv().await {
+        spawn(serve_stream(stream, src_addr));
+    }
+    log::info!("mux session from {} closed", src_addr);
+}
+
+/// Serves the request carried by a padded connection, which may be a multiplexed one as well.
+async fn start_padded<S>(conn: S, buffer: BytesMut, src_addr: SocketAddr) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let mut stream = padding::start(conn, buffer, BytesMut::new());
+    let Some(request) = read_request(&mut stream, BytesMut::new(), src_addr).await? else {
+        return Ok(());
+    };
+    if request.cmd == MUX {
+        start_mux(stream, request.buffer, src_addr).await;
+        return Ok(());
+    }
+    let Some(usage) = check_quota(request.user) else {
+        return Ok(());
+    };
+    let session = sessions::register(request.user, src_addr, request.target_addr, request.cmd);
+    until_kicked(session, serve_request(stream, request, src_addr, usage)).await
+}
+
+/// Serves a stream of a multiplexed or gRPC connection like a connection of its own.
+async fn serve_stream<S>(mut stream: S, src_addr: SocketAddr)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let ret = match read_request(&mut stream, BytesMut::new(), src_addr).await {
+        Ok(Some(request)) => match check_quota(request.user) {
+            Some(usage) => {
+                let session =
+                    sessions::register(request.user, src_addr, request.target_addr, request.cmd);
+                until_kicked(session, serve_request(stream, request, src_addr, usage)).await
+            }
+            None => Ok(()),
+        },
+        Ok(None) => Ok(()),
+        Err(err) => Err(err),
+    };
+    if let Err(err) = ret {
+        log::error!("stream from {} failed:{:?}", src_addr, err);
+    }
+}
+
+/// Serves a stream of a QUIC connection like [`serve_stream`], the packets of a UDP_ASSOCIATE go
+/// as datagrams of the connection instead.
+async fn serve_quic_stream(mut stream: QuicStream, conn: quic::Accepted, src_addr: SocketAddr) {
+    let ret = match read_request(&mut stream, BytesMut::new(), src_addr).await {
+        Ok(Some(request)) => match check_quota(request.user) {
+            Some(usage) => {
+                let session =
+                    sessions::register(request.user, src_addr, request.target_addr, request.cmd);
+                if request.cmd == UDP_ASSOCIATE {
+                    let association = conn.associate(stream);
+                    let serve = start_udp(association, request.buffer, src_addr, usage);
+                    until_kicked(session, serve).await
+                } else {
+                    until_kicked(session, serve_request(stream, request, src_addr, usage)).await
+                }
+            }
+            None => Ok(()),
+        },
+        Ok(None) => Ok(()),
+        Err(err) => Err(err),
+    };
+    if let Err(err) = ret {
+        log::error!("quic stream from {} failed:{:?}", src_addr, err);
+    }
+}
+
+async fn serve_request<S>(
+    stream: S,
+    request: Request,
+    src_addr: SocketAddr,
+    usage: Option<Arc<Usage>>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    match request {
+        Request {
+            cmd: CONNECT,
+            target_addr,
+            buffer,
+            ..
+        } => start_tcp(stream, target_addr, buffer, src_addr, usage).await,
+        Request {
+            cmd: UDP_ASSOCIATE,
+            buffer,
+            ..
+        } => start_udp(stream, buffer, src_addr, usage).await,
+        Request { cmd, .. } => {
+            log::error!(
+                "command {} from {} is not allowed in a stream",
+                cmd,
+                src_addr
+            );
+            Ok(())
+        }
     }
 }
@@ -14,7 +14,26 @@ use tokio::{
 };
 use tokio_rustls::server::TlsStream;
 
-use crate::{config::OPTIONS, proto, server::ping_backend::PingResult, types::Result};
+use crate::{config::OPTIONS, proto, types::Result};
+
+#[derive(Debug, Clone)]
+pub struct PingResult {
+    pub time: Instant,
+    pub ip: IpAddr,
+    pub lost: u8,
+    pub ping: u16,
+}
+
+impl Default for PingResult {
+    fn default() -> Self {
+        Self {
+            time: Instant::now(),
+            ip: IpAddr::V4(Ipv4Addr::from(0)),
+            lost: u8::MAX,
+            ping: 0,
+        }
+    }
+}
 
 enum SelectResult {
     Request(Option<(IpAddr, UnboundedSender<PingResult>)>),
@@ -0,0 +1,151 @@
+//! Relay point of --relay-port. aproxy sends the small datagrams of an association through its
+//! --redundant-server as well, addressed to this port of the server at --hostname with the id
+//! the association registered, so only the first copy reaches the target, from one address.
+//! Copies are checked with the key registered along, the forged and replayed ones are dropped
+//! before the association learns the address of the redundant server from them.
+
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Mutex, OnceLock},
+};
+
+use bytes::BytesMut;
+use socket2::{Domain, Socket, Type};
+use tokio::{
+    net::UdpSocket,
+    spawn,
+    sync::mpsc::{channel, error::TrySendError, Receiver, Sender},
+};
+
+use crate::{
+    proto::{Sock5Address, MAX_PACKET_SIZE},
+    redundancy::{self, Direction, Opener, RelayId, Sealer},
+    types::Result,
+};
+
+/// Copies queued for an association, more are dropped like on a congested link.
+const QUEUE_SIZE: usize = 64;
+
+lazy_static::lazy_static! {
+    static ref ASSOCIATIONS: Mutex<HashMap<RelayId, (Sender<Relayed>, Opener)>> =
+        Mutex::new(HashMap::new());
+}
+
+static SOCKETS: OnceLock<(UdpSocket, Option<UdpSocket>)> = OnceLock::new();
+
+/// Copy of a datagram received from the redundant server at `from`.
+pub struct Relayed {
+    pub address: Sock5Address,
+    pub payload: Vec<u8>,
+    pub from: SocketAddr,
+}
+
+/// Association registered at the relay point, it's unregistered when dropped.
+pub struct Registration {
+    id: RelayId,
+    /// largest answer copied back through the redundant server
+    pub size: usize,
+    pub receiver: Receiver<Relayed>,
+    sealer: Sealer,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        ASSOCIATIONS.lock().unwrap().remove(&self.id);
+    }
+}
+
+pub fn start(port: u16) -> Result<()> {
+    let v4 = bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))?;
+    // hosts without ipv6 still relay for redundant servers reaching them over ipv4
+    let v6 = bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port))).ok();
+    let (v4, v6) = SOCKETS.get_or_init(|| (v4, v6));
+    spawn(receive(v4));
+    if let Some(v6) = v6 {
+        spawn(receive(v6));
+    }
+    log::warn!("relay point listening on port {}", port);
+    Ok(())
+}
+
+fn bind(addr: SocketAddr) -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+async fn receive(socket: &'static UdpSocket) {
+    let mut buffer = vec![0u8; MAX_PACKET_SIZE];
+    loop {
+        let (n, from) = match socket.recv_from(buffer.as_mut_slice()).await {
+            Ok(ret) => ret,
+            Err(err) => {
+                log::warn!("receive from relay port failed:{}", err);
+                continue;
+            }
+        };
+        let Some((id, copy)) = redundancy::split_id(&buffer[..n]) else {
+            continue;
+        };
+        let mut associations = ASSOCIATIONS.lock().unwrap();
+        let Some((sender, opener)) = associations.get_mut(&id) else {
+            log::info!("copy from {} for no association", from);
+            continue;
+        };
+        let Some((address, payload)) = opener.unwrap(copy) else {
+            log::info!("invalid copy from {}", from);
+            continue;
+        };
+        let relayed = Relayed {
+            address,
+            payload: payload.to_vec(),
+            from,
+        };
+        if let Err(TrySendError::Full(_)) = sender.try_send(relayed) {
+            log::warn!("relay queue of association full, drop copy from {}", from);
+        }
+    }
+}
+
+/// Registers an association with the payload of its registration, None if --relay-port is not
+/// set, the payload is invalid or the id taken.
+pub fn register(payload: &[u8]) -> Option<Registration> {
+    SOCKETS.get()?;
+    let (id, key, size) = redundancy::parse_registration(payload)?;
+    let mut associations = ASSOCIATIONS.lock().unwrap();
+    if associations.contains_key(&id) {
+        log::error!("relay id registered twice");
+        return None;
+    }
+    let (sender, receiver) = channel(QUEUE_SIZE);
+    associations.insert(id, (sender, Opener::new(&key, Direction::Up)));
+    Some(Registration {
+        id,
+        size,
+        receiver,
+        sealer: Sealer::new(&key, Direction::Down),
+    })
+}
+
+impl Registration {
+    /// Sends a copy of the answer from `source` back through the redundant server at `to`.
+    pub async fn send_back(&mut self, to: SocketAddr, source: SocketAddr, body: &[u8]) {
+        let Some((v4, v6)) = SOCKETS.get() else {
+            return;
+        };
+        let socket = match (to, v6) {
+            (SocketAddr::V6(_), Some(v6)) => v6,
+            _ => v4,
+        };
+        let mut buffer = BytesMut::new();
+        self.sealer.wrap(&mut buffer, None, &source, body);
+        if let Err(err) = socket.send_to(buffer.as_ref(), to).await {
+            log::warn!("send copy to redundant server {} failed:{}", to, err);
+        }
+    }
+}
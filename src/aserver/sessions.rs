@@ -0,0 +1,127 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use serde::Serialize;
+use tokio::sync::Notify;
+
+lazy_static::lazy_static! {
+    static ref SESSIONS: Mutex<HashMap<u64, Session>> = Mutex::new(HashMap::new());
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+struct Session {
+    user: Option<&'static str>,
+    src_addr: SocketAddr,
+    target_addr: SocketAddr,
+    cmd: u8,
+    start: Instant,
+    kick: Arc<Notify>,
+}
+
+/// A session as listed by the control api.
+#[derive(Serialize)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub user: Option<&'static str>,
+    pub src_addr: SocketAddr,
+    pub target_addr: SocketAddr,
+    pub cmd: u8,
+    /// seconds since the request was read
+    pub duration: u64,
+}
+
+/// Keeps a served request in the session list until dropped.
+pub struct SessionGuard {
+    id: u64,
+    kick: Arc<Notify>,
+}
+
+impl SessionGuard {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Completes once the session is kicked.
+    pub async fn kicked(&self) {
+        self.kick.notified().await
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        SESSIONS.lock().unwrap().remove(&self.id);
+    }
+}
+
+pub fn register(
+    user: Option<&'static str>,
+    src_addr: SocketAddr,
+    target_addr: SocketAddr,
+    cmd: u8,
+) -> SessionGuard {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let kick = Arc::new(Notify::new());
+    SESSIONS.lock().unwrap().insert(
+        id,
+        Session {
+            user,
+            src_addr,
+            target_addr,
+            cmd,
+            start: Instant::now(),
+            kick: kick.clone(),
+        },
+    );
+    SessionGuard { id, kick }
+}
+
+pub fn list() -> Vec<SessionInfo> {
+    let mut sessions: Vec<_> = SESSIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(id, session)| SessionInfo {
+            id: *id,
+            user: session.user,
+            src_addr: session.src_addr,
+            target_addr: session.target_addr,
+            cmd: session.cmd,
+            duration: session.start.elapsed().as_secs(),
+        })
+        .collect();
+    sessions.sort_unstable_by_key(|session| session.id);
+    sessions
+}
+
+/// Closes the session `id`, false if there is no such session.
+pub fn kick(id: u64) -> bool {
+    match SESSIONS.lock().unwrap().get(&id) {
+        Some(session) => {
+            // a permit is stored if the session isn't waiting yet
+            session.kick.notify_one();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Closes all the sessions of the user labeled `user`, returns how many there were.
+pub fn kick_user(user: &str) -> usize {
+    let sessions = SESSIONS.lock().unwrap();
+    let mut count = 0;
+    for session in sessions.values() {
+        if session.user == Some(user) {
+            session.kick.notify_one();
+            count += 1;
+        }
+    }
+    count
+}
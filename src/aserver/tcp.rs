@@ -1,22 +1,38 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
 
-use bytes::BytesMut;
+use bytes::{BufMut, BytesMut};
 use tokio::{
-    io::{split, AsyncReadExt, AsyncWriteExt},
+    io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
     spawn,
 };
-use tokio_rustls::server::TlsStream;
 
-use crate::{async_utils::copy, config::OPTIONS, types::Result, utils::is_private};
+use crate::{
+    aserver::geoip,
+    async_utils::{copy_with, AbortOnDrop},
+    config::{ProxyProtocol, OPTIONS},
+    limiter::{Priority, DOWNLOAD, UPLOAD},
+    server::{stat, usage::Usage},
+    types::Result,
+    utils::is_private,
+};
 
-pub async fn start_tcp(
-    mut source: TlsStream<TcpStream>,
+pub async fn start_tcp<S>(
+    mut source: S,
     target_addr: SocketAddr,
     mut buffer: BytesMut,
     src_addr: SocketAddr,
-) -> Result<()> {
-    if target_addr == *OPTIONS.back_addr.as_ref().unwrap() {
+    usage: Option<Arc<Usage>>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let fallback = target_addr == OPTIONS.fallback_addr();
+    if fallback {
         let mut proxy_added = false;
         for _ in 0..10 {
             let mut headers = [httparse::EMPTY_HEADER; 100];
@@ -32,21 +48,27 @@ pub async fn start_tcp(
                     proxy_added = true;
                     break;
                 }
-                _ => {
-                    if tokio::time::timeout(Duration::from_secs(1), source.read_buf(&mut buffer))
-                        .await??
-                        == 0
-                    {
-                        log::error!("read http header failed");
-                        let _ = source.shutdown().await;
-                        return Ok(());
+                Ok(httparse::Status::Partial) => {
+                    let read = source.read_buf(&mut buffer);
+                    match tokio::time::timeout(Duration::from_secs(1), read).await {
+                        Ok(Ok(0)) => {
+                            log::error!("read http header failed");
+                            let _ = source.shutdown().await;
+                            return Ok(());
+                        }
+                        Ok(Ok(_)) => {}
+                        Ok(Err(err)) => return Err(err.into()),
+                        // probers may stall on purpose, the backend answers whatever came
+                        Err(_) => break,
                     }
                 }
+                // not http at all, forwarded as it is
+                Err(_) => break,
             }
         }
         if !proxy_added {
             log::error!(
-                "[{}] header not completed after 10 retries:{}",
+                "[{}] header not completed, forward as it is:{}",
                 src_addr,
                 String::from_utf8_lossy(buffer.as_ref())
             );
@@ -55,14 +77,32 @@ pub async fn start_tcp(
         log::error!("address:{} is private which is not allowed", target_addr);
         let _ = source.shutdown().await;
         return Ok(());
+    } else if geoip::is_blocked(target_addr.ip()) {
+        log::error!("address:{} is blocked by geoip", target_addr);
+        let _ = source.shutdown().await;
+        return Ok(());
     }
 
     log::info!("tcp backend:{}", target_addr);
     let mut target = TcpStream::connect(target_addr).await?;
+    // the header isn't the user's traffic
+    let data_len = buffer.len();
+    let proxy_protocol = match OPTIONS.server_args().proxy_protocol {
+        Some(ProxyProtocol::All) => true,
+        Some(ProxyProtocol::Fallback) => fallback,
+        None => false,
+    };
+    if proxy_protocol {
+        buffer = proxy_header(src_addr, target_addr, buffer);
+    }
     if let Ok(Ok(_)) =
         tokio::time::timeout(Duration::from_secs(5), target.write_all(buffer.as_ref())).await
     {
         log::info!("tcp send data to target:{} ok", target_addr);
+        stat::add_tcp_rx(data_len, target_addr.ip(), Some(src_addr.ip()));
+        if let Some(usage) = &usage {
+            usage.add_upload(data_len);
+        }
     } else {
         log::error!("tcp send data to target:{} failed", target_addr);
         let _ = target.shutdown().await;
@@ -71,18 +111,125 @@ pub async fn start_tcp(
     }
     let (source_read, source_write) = split(source);
     let (target_read, target_write) = target.into_split();
-    spawn(copy(
-        source_read,
-        target_write,
-        format!("tcp {} to {}", src_addr, target_addr),
-        OPTIONS.tcp_idle_timeout,
-    ));
-    copy(
+    let priority = Priority::of_stream(target_addr.port());
+    let upload_usage = usage.clone();
+    // stopped along with the download when the session is kicked or over its quota
+    let upload = AbortOnDrop::new(spawn(async move {
+        copy_with(
+            source_read,
+            target_write,
+            format!("tcp {} to {}", src_addr, target_addr),
+            OPTIONS.tcp_idle_timeout,
+            Some((
+                upload_usage
+                    .as_ref()
+                    .map_or(&*UPLOAD, |usage| &usage.upload_limit),
+                priority,
+            )),
+            None,
+            |n| {
+                stat::add_tcp_rx(n, target_addr.ip(), None);
+                if let Some(usage) = &upload_usage {
+                    usage.add_upload(n);
+                }
+            },
+        )
+        .await
+    }));
+    let download = copy_with(
         target_read,
         source_write,
         format!("tcp {} to {}", target_addr, src_addr),
         OPTIONS.tcp_idle_timeout,
-    )
-    .await;
+        Some((
+            usage
+                .as_ref()
+                .map_or(&*DOWNLOAD, |usage| &usage.download_limit),
+            priority,
+        )),
+        Some(priority),
+        |n| {
+            stat::add_tcp_tx(n, target_addr.ip(), None);
+            if let Some(usage) = &usage {
+                usage.add_download(n);
+            }
+        },
+    );
+    let Some(quota) = usage.as_ref() else {
+        download.await;
+        upload.detach();
+        return Ok(());
+    };
+    tokio::select! {
+        _ = download => upload.detach(),
+        _ = quota.wait_exceeded() => {
+            log::warn!("quota exceeded, close tcp {} to {}", src_addr, target_addr);
+        }
+    }
     Ok(())
 }
+
+/// PROXY protocol v2 header of a TCP connection from `src_addr` to `dst_addr` followed by the
+/// data, IPv4 addresses are mapped when the families differ.
+fn proxy_header(src_addr: SocketAddr, dst_addr: SocketAddr, data: BytesMut) -> BytesMut {
+    let mut header = BytesMut::new();
+    header.put_slice(b"\r\n\r\n\0\r\nQUIT\n");
+    // version 2, PROXY command
+    header.put_u8(0x21);
+    match (src_addr.ip(), dst_addr.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            header.put_u8(0x11);
+            header.put_u16(12);
+            header.put_slice(&src.octets());
+            header.put_slice(&dst.octets());
+        }
+        (src, dst) => {
+            let v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            header.put_u8(0x21);
+            header.put_u16(36);
+            header.put_slice(&v6(src).octets());
+            header.put_slice(&v6(dst).octets());
+        }
+    }
+    header.put_u16(src_addr.port());
+    header.put_u16(dst_addr.port());
+    header.unsplit(data);
+    header
+}
+
+mod tests {
+    #[test]
+    fn test_proxy_header() {
+        use bytes::BytesMut;
+
+        use crate::aserver::tcp::proxy_header;
+
+        let header = proxy_header(
+            "1.2.3.4:5678".parse().unwrap(),
+            "127.0.0.1:80".parse().unwrap(),
+            BytesMut::from(&b"GET"[..]),
+        );
+        assert_eq!(&header[..12], b"\r\n\r\n\0\r\nQUIT\n");
+        assert_eq!(
+            &header[12..],
+            &[0x21, 0x11, 0, 12, 1, 2, 3, 4, 127, 0, 0, 1, 0x16, 0x2e, 0, 80, b'G', b'E', b'T']
+        );
+        let header = proxy_header(
+            "[::1]:5678".parse().unwrap(),
+            "127.0.0.1:80".parse().unwrap(),
+            BytesMut::new(),
+        );
+        assert_eq!(&header[12..16], &[0x21, 0x21, 0, 36]);
+        assert_eq!(
+            &header[32..48],
+            &"::ffff:127.0.0.1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets()
+        );
+        assert_eq!(header.len(), 52);
+    }
+}
@@ -1,71 +1,125 @@
-use std::{collections::HashMap, io, net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    future::pending,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
 
 use bytes::{Buf, BytesMut};
 use tokio::{
-    io::{split, AsyncReadExt, AsyncWriteExt, WriteHalf},
-    net::{lookup_host, TcpStream, UdpSocket},
-    spawn,
-    sync::mpsc::{channel, Receiver},
-    time::{timeout, Instant},
+    io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, WriteHalf},
+    net::{lookup_host, UdpSocket},
+    time::{sleep_until, Instant},
 };
-use tokio_rustls::server::TlsStream;
 
 use crate::{
+    aserver::{
+        geoip,
+        relay_point::{self, Registration, Relayed},
+    },
+    async_utils::recv_from,
     config::OPTIONS,
-    proto::{Sock5Address, UdpAssociate, UdpParseResult},
+    limiter::{Priority, RateLimiter, DOWNLOAD, UPLOAD},
+    proto::{Sock5Address, UdpAssociate, UdpParseResult, MAX_PACKET_SIZE},
+    redundancy::{Dedup, REGISTER_TARGET},
+    server::{stat, usage::Usage},
     types::Result,
     utils::is_private,
 };
 
-pub async fn start_udp(source: TlsStream<TcpStream>, mut buffer: BytesMut) -> Result<()> {
-    let src_addr = source.get_ref().0.peer_addr()?;
-    let target = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
-    let (mut source, source_write) = split(source);
-    let (sender, receiver) = channel(1024);
+/// Expired endpoints are dropped once an association has sent to this many.
+const MAX_NAT_ENTRIES: usize = 4096;
+
+/// Remote endpoints of an association and when it last sent to each. All of them see the same
+/// outbound port of the association, and with `--disable-udp-hole` only they may answer.
+#[derive(Default)]
+struct NatTable(HashMap<SocketAddr, Instant>);
+
+impl NatTable {
+    fn sent_to(&mut self, addr: SocketAddr) {
+        if self.0.len() >= MAX_NAT_ENTRIES {
+            self.0
+                .retain(|_, time| time.elapsed() < OPTIONS.udp_idle_duration);
+        }
+        self.0.insert(addr, Instant::now());
+    }
+
+    /// Full cone by default, anyone knowing the port may answer.
+    fn accepts(&self, addr: &SocketAddr) -> bool {
+        !OPTIONS.server_args().disable_udp_hole
+            || self
+                .0
+                .get(addr)
+                .is_some_and(|time| time.elapsed() < OPTIONS.udp_idle_duration)
+    }
+}
+
+/// Relays the datagrams of a trojan UDP_ASSOCIATE until the client closes it or it's idle for
+/// the udp timeout in both directions.
+pub async fn start_udp<S>(
+    source: S,
+    mut buffer: BytesMut,
+    src_addr: SocketAddr,
+    usage: Option<Arc<Usage>>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let target_v4 = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    // hosts without ipv6 still relay to ipv4 targets
+    let target_v6 = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await.ok();
+    let (mut source_read, mut source_write) = split(source);
+    let mut nat = NatTable::default();
     let mut dns_cache_store = HashMap::new();
-    spawn(target_to_source(target.clone(), source_write, receiver));
+    // one byte more to tell datagrams too large for the protocol
+    let mut response_v4 = vec![0u8; MAX_PACKET_SIZE + 1];
+    let mut response_v6 = vec![0u8; MAX_PACKET_SIZE + 1];
+    let mut header = BytesMut::new();
+    let mut deadline = Instant::now() + OPTIONS.udp_idle_duration;
     let mut count = 0;
+    // set once the client registers a redundant path
+    let mut relay = None;
+    let mut relay_peer = None;
+    let mut dedup = Dedup::default();
+    let upload_limit = usage.as_ref().map_or(&*UPLOAD, |usage| &usage.upload_limit);
+    let download_limit = usage
+        .as_ref()
+        .map_or(&*DOWNLOAD, |usage| &usage.download_limit);
     'main: loop {
         loop {
             match UdpAssociate::parse(buffer.as_ref()) {
+                UdpParseResult::Packet(packet)
+                    if packet.address.as_socket() == Some(REGISTER_TARGET) =>
+                {
+                    if relay.is_none() {
+                        relay = relay_point::register(&packet.payload[..packet.length]);
+                    }
+                    buffer.advance(packet.offset);
+                }
                 UdpParseResult::Packet(packet) => {
-                    let address = match packet.address {
-                        Sock5Address::Socket(addr) => addr,
-                        Sock5Address::Domain(domain, port) => {
-                            if let Some(ip) = dns_cache_store.get(&domain) {
-                                SocketAddr::new(*ip, port)
-                            } else if let Ok(mut ret) = lookup_host((domain.clone(), port)).await {
-                                if let Some(addr) = ret.next() {
-                                    dns_cache_store.insert(domain, addr.ip());
-                                    addr
-                                } else {
-                                    log::error!("query {} failed", domain);
-                                    continue;
-                                }
-                            } else {
-                                log::error!("query {} failed", domain);
-                                continue;
-                            }
-                        }
-                        _ => {
-                            unreachable!()
+                    if let Some(usage) = &usage {
+                        if usage.exceeded() {
+                            log::warn!("quota exceeded, close udp from {}", src_addr);
+                            break 'main;
                         }
-                    };
-                    if !OPTIONS.server_args().allow_private && is_private(&address) {
-                        log::error!("address:{} is private which is not allowed", address);
-                        break 'main;
-                    }
-
-                    if OPTIONS.server_args().disable_udp_hole {
-                        let _ = sender.send(address).await;
+                        usage.add_upload(packet.length);
                     }
-                    log::info!("udp request to {}", address);
-                    if let Err(err) = target
-                        .send_to(&packet.payload[..packet.length], address)
-                        .await
-                    {
-                        log::warn!("send request to target failed:{}", err);
-                        break 'main;
+                    let payload = &packet.payload[..packet.length];
+                    if relay.is_some() && !dedup.admit(0, &packet.address, payload) {
+                        log::info!("skip udp request relayed before");
+                    } else {
+                        send_to_target(
+                            (&target_v4, target_v6.as_ref()),
+                            &mut nat,
+                            &mut dns_cache_store,
+                            packet.address,
+                            payload,
+                            upload_limit,
+                            src_addr,
+                        )
+                        .await;
                     }
                     buffer.advance(packet.offset);
                     count += 1;
@@ -80,104 +134,197 @@ pub async fn start_udp(source: TlsStream<TcpStream>, mut buffer: BytesMut) -> Re
                 }
             }
         }
-        match timeout(
-            Duration::from_secs(OPTIONS.udp_idle_timeout),
-            source.read_buf(&mut buffer),
-        )
-        .await
-        {
-            Ok(Ok(0)) => {
-                log::warn!("read from source with 0 bytes");
-                break;
+        let received = tokio::select! {
+            ret = source_read.read_buf(&mut buffer) => {
+                match ret {
+                    Ok(0) => {
+                        log::warn!("read from source with 0 bytes");
+                        break;
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        log::warn!("read from source failed:{}", err);
+                        break;
+                    }
+                }
+                deadline = Instant::now() + OPTIONS.udp_idle_duration;
+                continue;
+            }
+            ret = target_v4.recv_from(response_v4.as_mut_slice()) => {
+                ret.map(|(n, addr)| (&response_v4[..n], addr))
             }
-            Ok(Err(err)) => {
-                log::warn!("read from source failed:{}", err);
+            ret = recv_from(target_v6.as_ref(), response_v6.as_mut_slice()) => {
+                ret.map(|(n, addr)| (&response_v6[..n], addr))
+            }
+            Some(relayed) = next_relayed(&mut relay) => {
+                if dedup.admit(1, &relayed.address, &relayed.payload) {
+                    send_to_target(
+                        (&target_v4, target_v6.as_ref()),
+                        &mut nat,
+                        &mut dns_cache_store,
+                        relayed.address,
+                        &relayed.payload,
+                        upload_limit,
+                        src_addr,
+                    )
+                    .await;
+                }
+                relay_peer = Some(relayed.from);
+                continue;
+            }
+            _ = sleep_until(deadline) => {
+                log::info!("udp association of {} idle", src_addr);
                 break;
             }
+        };
+        let (body, target_addr) = match received {
+            Ok(received) => received,
             Err(err) => {
-                log::warn!("read timeout after {}", err);
+                log::warn!("receive from target failed:{}", err);
                 break;
             }
-            Ok(Ok(_)) => {}
+        };
+        // ipv4 peers reaching the dual stack socket show up mapped
+        let target_addr = SocketAddr::new(target_addr.ip().to_canonical(), target_addr.port());
+        if !nat.accepts(&target_addr) {
+            log::error!("skip udp packet from {}", target_addr);
+            continue;
+        }
+        if body.len() > MAX_PACKET_SIZE {
+            log::warn!(
+                "skip udp packet from {} larger than {}",
+                target_addr,
+                MAX_PACKET_SIZE
+            );
+            continue;
         }
+        if let Some(usage) = &usage {
+            if usage.exceeded() {
+                break;
+            }
+            usage.add_download(body.len());
+        }
+        log::info!("get udp {} bytes response from {}", body.len(), target_addr);
+        stat::add_udp_tx(body.len(), target_addr.ip(), None);
+        download_limit
+            .acquire(
+                body.len(),
+                Priority::of_packet(target_addr.port(), body.len()),
+            )
+            .await;
+        if !to_source(&mut source_write, &mut header, target_addr, body).await {
+            break;
+        }
+        if let (Some(relay), Some(peer)) = (&mut relay, relay_peer) {
+            if body.len() <= relay.size {
+                relay.send_back(peer, target_addr, body).await;
+            }
+        }
+        deadline = Instant::now() + OPTIONS.udp_idle_duration;
     }
-    log::warn!("udp read from proxy exit after {} packets", count);
+    let _ = source_write.shutdown().await;
+    log::warn!(
+        "udp association of {} exit after {} packets to {} endpoints",
+        src_addr,
+        count,
+        nat.0.len()
+    );
     Ok(())
 }
 
-enum SelectResult {
-    Sleep,
-    Receiver(Option<SocketAddr>),
-    RemoteRecv(io::Result<(usize, SocketAddr)>),
+/// Sends a datagram of the association of `src_addr` to `address` unless it's private.
+async fn send_to_target(
+    (target_v4, target_v6): (&UdpSocket, Option<&UdpSocket>),
+    nat: &mut NatTable,
+    dns_cache_store: &mut HashMap<String, (IpAddr, Instant)>,
+    address: Sock5Address,
+    payload: &[u8],
+    upload_limit: &RateLimiter,
+    src_addr: SocketAddr,
+) {
+    match resolve(dns_cache_store, address).await {
+        Some(address) if !OPTIONS.server_args().allow_private && is_private(&address) => {
+            log::error!("address:{} is private which is not allowed", address);
+        }
+        Some(address) if geoip::is_blocked(address.ip()) => {
+            log::error!("address:{} is blocked by geoip", address);
+        }
+        Some(address) => {
+            log::info!("udp request to {}", address);
+            upload_limit
+                .acquire(
+                    payload.len(),
+                    Priority::of_packet(address.port(), payload.len()),
+                )
+                .await;
+            let ret = match (address, target_v6) {
+                (SocketAddr::V4(_), _) => target_v4.send_to(payload, address).await,
+                (SocketAddr::V6(_), Some(target_v6)) => target_v6.send_to(payload, address).await,
+                (SocketAddr::V6(_), None) => Err(io::ErrorKind::AddrNotAvailable.into()),
+            };
+            match ret {
+                Ok(n) => {
+                    stat::add_udp_rx(n, address.ip(), Some(src_addr.ip()));
+                    nat.sent_to(address)
+                }
+                Err(err) => {
+                    log::warn!("send request to {} failed:{}", address, err)
+                }
+            }
+        }
+        None => {}
+    }
 }
 
-async fn target_to_source(
-    target: Arc<UdpSocket>,
-    mut source: WriteHalf<TlsStream<TcpStream>>,
-    mut receiver: Receiver<SocketAddr>,
-) -> Result<()> {
-    let mut header = BytesMut::new();
-    let mut body = vec![0u8; 1500];
-    let mut sources = HashMap::new();
-    loop {
-        let ret = tokio::select! {
-            _ = tokio::time::sleep(std::time::Duration::from_secs(OPTIONS.udp_idle_timeout)) => {
-                SelectResult::Sleep
-            },
-            ret = receiver.recv() => {
-                SelectResult::Receiver(ret)
-            },
-            ret = target.recv_from(body.as_mut_slice()) => {
-                SelectResult::RemoteRecv(ret)
-            }
-        };
-        match ret {
-            SelectResult::Sleep => {
-                log::info!("udp receive timeout");
-                break;
-            }
-            SelectResult::Receiver(ret) => {
-                if ret.is_none() {
-                    log::warn!("udp channel is closed");
-                    break;
+/// Next copy relayed for the association, pending until it registers.
+async fn next_relayed(relay: &mut Option<Registration>) -> Option<Relayed> {
+    match relay {
+        Some(relay) => relay.receiver.recv().await,
+        None => pending().await,
+    }
+}
+
+/// Domains are looked up again after --dns-cache-time, None if the lookup failed.
+async fn resolve(
+    dns_cache_store: &mut HashMap<String, (IpAddr, Instant)>,
+    address: Sock5Address,
+) -> Option<SocketAddr> {
+    match address {
+        Sock5Address::Socket(addr) => Some(addr),
+        Sock5Address::Domain(domain, port) => {
+            let cache_time = Duration::from_secs(OPTIONS.server_args().dns_cache_time);
+            if let Some((ip, time)) = dns_cache_store.get(&domain) {
+                if time.elapsed() < cache_time {
+                    return Some(SocketAddr::new(*ip, port));
                 }
-                *sources.entry(ret.unwrap()).or_insert_with(Instant::now) = Instant::now();
             }
-            SelectResult::RemoteRecv(ret) => {
-                if let Ok((n, target_addr)) = ret {
-                    log::info!("get udp {} bytes response from {}", n, target_addr);
-                    if OPTIONS.server_args().disable_udp_hole
-                        && sources
-                            .get(&target_addr)
-                            .map(|timeout| {
-                                if timeout.elapsed().as_secs() < 60 {
-                                    Some(true)
-                                } else {
-                                    None
-                                }
-                            })
-                            .unwrap_or_default()
-                            .is_none()
-                    {
-                        log::error!("skip udp packet from {}", target_addr);
-                        continue;
-                    }
-                    header.clear();
-                    UdpAssociate::generate(&mut header, &target_addr, n as u16);
-                    if source.write_all(header.as_ref()).await.is_err()
-                        || source.write_all(&body.as_slice()[..n]).await.is_err()
-                    {
-                        log::error!("write to source from:{} failed", target_addr);
-                        break;
-                    }
-                } else {
-                    log::warn!("receive from target failed");
-                    break;
+            let addr = lookup_host((domain.as_str(), port))
+                .await
+                .ok()
+                .and_then(|mut ret| ret.next());
+            match addr {
+                Some(addr) => {
+                    dns_cache_store.insert(domain, (addr.ip(), Instant::now()));
                 }
+                None => log::error!("query {} failed", domain),
             }
+            addr
         }
+        _ => unreachable!(),
     }
-    let _ = source.shutdown().await;
-    log::info!("udp read from target exit");
-    Ok(())
+}
+
+async fn to_source<S: AsyncWrite>(
+    source: &mut WriteHalf<S>,
+    header: &mut BytesMut,
+    target_addr: SocketAddr,
+    body: &[u8],
+) -> bool {
+    header.clear();
+    UdpAssociate::generate(header, &target_addr, body.len() as u16);
+    if source.write_all(header.as_ref()).await.is_err() || source.write_all(body).await.is_err() {
+        log::error!("write to source from:{} failed", target_addr);
+        return false;
+    }
+    true
 }
@@ -1,35 +1,104 @@
-use std::time::Duration;
+use std::{net::SocketAddr, time::Duration};
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use relay::{Hooks, Options};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::UdpSocket,
+    task::JoinHandle,
+};
 
-pub async fn copy<R: AsyncReadExt + Unpin, W: AsyncWriteExt + Unpin>(
-    mut read: R,
-    mut write: W,
+use crate::{
+    limiter::{Priority, RateLimiter},
+    timing::coalesce,
+};
+
+/// Rate limiting, timing jitter and traffic accounting of a relay direction.
+struct CopyHooks<'a, F> {
+    limiter: Option<(&'a RateLimiter, Priority)>,
+    jitter: Option<Priority>,
+    on_data: F,
+}
+
+impl<'a, F: Fn(usize) + Sync> Hooks for CopyHooks<'a, F> {
+    async fn gather<R: AsyncRead + Unpin + Send>(
+        &self,
+        read: &mut R,
+        buffer: &mut [u8],
+        n: usize,
+    ) -> usize {
+        match self.jitter {
+            Some(priority) => coalesce(read, buffer, n, priority).await,
+            None => n,
+        }
+    }
+
+    async fn acquire(&self, size: usize) {
+        if let Some((limiter, priority)) = self.limiter {
+            limiter.acquire(size, priority).await;
+        }
+    }
+
+    fn on_data(&self, size: usize) {
+        (self.on_data)(size)
+    }
+}
+
+/// Copies until either side fails or `read` is idle for `timeout` seconds, throttled by `limiter`
+/// if any, `on_data` is called with the size of every chunk written. Writes of the `jitter`
+/// priority are held back at random when `--timing-jitter` is set.
+pub async fn copy_with<R, W, F>(
+    read: R,
+    write: W,
     message: String,
     timeout: u64,
-) {
-    let mut buffer = vec![0u8; 4096];
-
-    while let Ok(Ok(n)) = tokio::time::timeout(
-        Duration::from_secs(timeout),
-        read.read(buffer.as_mut_slice()),
-    )
-    .await
-    {
-        if n > 0 {
-            if let Ok(Ok(())) = tokio::time::timeout(
-                Duration::from_secs(10),
-                write.write_all(&buffer.as_slice()[..n]),
-            )
-            .await
-            {
-                continue;
-            } else {
-                log::error!("{} write failed", message);
-            }
+    limiter: Option<(&RateLimiter, Priority)>,
+    jitter: Option<Priority>,
+    on_data: F,
+) where
+    R: AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin,
+    F: Fn(usize) + Sync,
+{
+    let hooks = CopyHooks {
+        limiter,
+        jitter,
+        on_data,
+    };
+    let options = Options::new(Duration::from_secs(timeout)).write_timeout(Duration::from_secs(10));
+    let closed = relay::copy(read, write, options, &hooks).await;
+    log::warn!("{} closed, {}", message, closed);
+}
+
+/// Aborts the task when dropped unless detached, so a relay cancelled halfway stops both of
+/// its directions.
+pub struct AbortOnDrop<T>(Option<JoinHandle<T>>);
+
+impl<T> AbortOnDrop<T> {
+    pub fn new(handle: JoinHandle<T>) -> AbortOnDrop<T> {
+        AbortOnDrop(Some(handle))
+    }
+
+    /// Lets the task run on by itself.
+    pub fn detach(mut self) {
+        self.0.take();
+    }
+}
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        if let Some(handle) = self.0.take() {
+            handle.abort();
         }
-        break;
     }
-    log::warn!("{} read failed", message);
-    let _ = write.shutdown().await;
+}
+
+/// Receives from the socket, or never for a missing one, so optional sockets fit in a select.
+pub async fn recv_from(
+    socket: Option<&UdpSocket>,
+    buffer: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buffer).await,
+        None => std::future::pending().await,
+    }
 }
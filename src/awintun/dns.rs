@@ -0,0 +1,208 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, BytesMut};
+use rand::random;
+use rustls_pki_types::ServerName;
+use smoltcp::wire::IpEndpoint;
+use tokio::{
+    io::{split, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::TcpStream,
+    spawn,
+    sync::mpsc::{channel, Receiver, Sender},
+};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+use async_smoltcp::UdpWriteHalf;
+
+use crate::{
+    awintun::init_tls_conn,
+    metrics::{incr, COUNTERS},
+    proto::{UdpAssociate, UdpParseResultEndpoint},
+};
+
+/// Queries without an answer for this long are forgotten.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Pending queries, more are dropped until some are answered or time out.
+const MAX_QUERIES: usize = 4096;
+/// Random ids tried for a query before it is dropped.
+const ID_ATTEMPTS: usize = 8;
+
+struct Query {
+    id: u16,
+    source: IpEndpoint,
+    /// DNS server the query went to, answers from elsewhere are dropped
+    target: IpEndpoint,
+    local: Arc<UdpWriteHalf>,
+    time: Instant,
+}
+
+enum RelayEvent {
+    Query(Option<(IpEndpoint, Arc<UdpWriteHalf>, BytesMut)>),
+    Answer(Option<(usize, Option<(IpEndpoint, BytesMut)>)>),
+}
+
+/// Carries the DNS queries of all local sockets over one long lived UDP associate connection
+/// instead of a connection per source port. Query ids are rewritten to random ones so the
+/// answers can be told apart and are hard to spoof, the connection is made again on the next
+/// query after it breaks.
+pub async fn run_dns_relay(
+    mut query_receiver: Receiver<(IpEndpoint, Arc<UdpWriteHalf>, BytesMut)>,
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+    request: Arc<BytesMut>,
+) {
+    let (answer_sender, mut answer_receiver) = channel(128);
+    let mut remote: Option<WriteHalf<TlsStream<TcpStream>>> = None;
+    // answers are tagged with the connection they came from, None once it is closed
+    let mut conn = 0;
+    let mut queries: HashMap<u16, Query> = HashMap::new();
+    let mut header = BytesMut::new();
+    loop {
+        let event = tokio::select! {
+            ret = query_receiver.recv() => RelayEvent::Query(ret),
+            ret = answer_receiver.recv() => RelayEvent::Answer(ret),
+        };
+        match event {
+            RelayEvent::Query(None) => break,
+            RelayEvent::Query(Some((source, local, mut data))) => {
+                if data.len() < 12 {
+                    log::warn!("dns query from {} is too short", source);
+                    continue;
+                }
+                if remote.is_none() {
+                    conn += 1;
+                    let sender = answer_sender.clone();
+                    remote = connect(&connector, &server_name, &request, conn, sender).await;
+                }
+                let Some(writer) = remote.as_mut() else {
+                    incr(&COUNTERS.udp_remote_failed);
+                    continue;
+                };
+                if queries.len() >= MAX_QUERIES / 4 {
+                    queries.retain(|_, query| query.time.elapsed() < QUERY_TIMEOUT);
+                }
+                if queries.len() >= MAX_QUERIES {
+                    log::warn!(
+                        "too many pending dns queries, query from {} dropped",
+                        source
+                    );
+                    continue;
+                }
+                let Some(id) = (0..ID_ATTEMPTS)
+                    .map(|_| random::<u16>())
+                    .find(|id| !queries.contains_key(id))
+                else {
+                    log::warn!("no free dns query id, query from {} dropped", source);
+                    continue;
+                };
+                let target = local.peer_addr();
+                queries.insert(
+                    id,
+                    Query {
+                        id: u16::from_be_bytes([data[0], data[1]]),
+                        source,
+                        target,
+                        local,
+                        time: Instant::now(),
+                    },
+                );
+                data[..2].copy_from_slice(&id.to_be_bytes());
+                header.clear();
+                UdpAssociate::generate_endpoint(&mut header, &target, data.len() as u16);
+                if writer.write_all(header.as_ref()).await.is_err()
+                    || writer.write_all(data.as_ref()).await.is_err()
+                {
+                    incr(&COUNTERS.udp_remote_failed);
+                    log::warn!("dns relay write to {} failed", target);
+                    remote.take();
+                }
+            }
+            RelayEvent::Answer(None) => {}
+            RelayEvent::Answer(Some((closed, None))) => {
+                if closed == conn {
+                    remote.take();
+                }
+            }
+            RelayEvent::Answer(Some((_, Some((endpoint, mut data))))) => {
+                let id = u16::from_be_bytes([data[0], data[1]]);
+                if queries
+                    .get(&id)
+                    .is_none_or(|query| query.target != endpoint)
+                {
+                    log::info!("dns answer {} from {} has no query, dropped", id, endpoint);
+                    continue;
+                }
+                let query = queries.remove(&id).unwrap();
+                data[..2].copy_from_slice(&query.id.to_be_bytes());
+                let sent = query.local.send_to(data.as_ref(), query.source).await;
+                if sent.is_err() {
+                    incr(&COUNTERS.udp_local_failed);
+                }
+            }
+        }
+    }
+}
+
+async fn connect(
+    connector: &TlsConnector,
+    server_name: &ServerName<'static>,
+    request: &BytesMut,
+    conn: usize,
+    sender: Sender<(usize, Option<(IpEndpoint, BytesMut)>)>,
+) -> Option<WriteHalf<TlsStream<TcpStream>>> {
+    let client = match init_tls_conn(connector.clone(), server_name.clone()).await {
+        Ok(client) => client,
+        Err(err) => {
+            log::error!("dns relay connect to remote server failed:{:?}", err);
+            return None;
+        }
+    };
+    let (read_half, mut write_half) = split(client);
+    if let Err(err) = write_half.write_all(request).await {
+        log::error!("dns relay send handshake failed:{}", err);
+        return None;
+    }
+    log::info!("dns relay connection created");
+    spawn(read_answers(read_half, conn, sender));
+    Some(write_half)
+}
+
+async fn read_answers(
+    mut remote: ReadHalf<TlsStream<TcpStream>>,
+    conn: usize,
+    sender: Sender<(usize, Option<(IpEndpoint, BytesMut)>)>,
+) {
+    let mut buffer = BytesMut::new();
+    'main: loop {
+        match remote.read_buf(&mut buffer).await {
+            Ok(0) | Err(_) => break,
+            _ => {}
+        }
+        loop {
+            match UdpAssociate::parse_endpoint(buffer.as_ref()) {
+                UdpParseResultEndpoint::Continued => break,
+                UdpParseResultEndpoint::Packet(packet) => {
+                    let payload = &packet.payload[..packet.length];
+                    if payload.len() >= 12 {
+                        let answer = (packet.endpoint, BytesMut::from(payload));
+                        if sender.send((conn, Some(answer))).await.is_err() {
+                            break 'main;
+                        }
+                    }
+                    buffer.advance(packet.offset);
+                }
+                UdpParseResultEndpoint::InvalidProtocol => {
+                    incr(&COUNTERS.udp_invalid_protocol);
+                    log::error!("invalid protocol from dns relay connection");
+                    break 'main;
+                }
+            }
+        }
+    }
+    log::info!("dns relay connection closed");
+    let _ = sender.send((conn, None)).await;
+}
@@ -0,0 +1,168 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, BytesMut};
+use rustls_pki_types::ServerName;
+use tokio::{
+    io::{split, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::TcpStream,
+    spawn,
+    sync::mpsc::{channel, Receiver, Sender},
+};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+use async_smoltcp::EchoRequest;
+
+use crate::{
+    awintun::init_tls_conn,
+    proto::{IcmpEcho, IcmpParseResult, TrojanRequest, ICMP},
+};
+
+/// Probes without an answer for this long are forgotten.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+enum RelayEvent {
+    Request(Option<EchoRequest>),
+    Answer(Option<(usize, Option<IcmpEcho>)>),
+}
+
+/// Sends the echo requests read from the device over one ICMP connection to the server, and
+/// writes the replies and errors, like time exceeded of the routers on the path, back to the
+/// device. The connection is made again on the next request after it breaks.
+pub async fn run_icmp_relay(
+    mut request_receiver: Receiver<EchoRequest>,
+    reply_sender: Sender<Vec<u8>>,
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+) {
+    let (answer_sender, mut answer_receiver) = channel(128);
+    let mut remote: Option<WriteHalf<TlsStream<TcpStream>>> = None;
+    // answers are tagged with the connection they came from, None once it is closed
+    let mut conn = 0;
+    let mut probes: HashMap<(Ipv4Addr, u16, u16), (EchoRequest, Instant)> = HashMap::new();
+    let mut frame = BytesMut::new();
+    loop {
+        let event = tokio::select! {
+            ret = request_receiver.recv() => RelayEvent::Request(ret),
+            ret = answer_receiver.recv() => RelayEvent::Answer(ret),
+        };
+        match event {
+            RelayEvent::Request(None) => break,
+            RelayEvent::Request(Some(request)) => {
+                if remote.is_none() {
+                    conn += 1;
+                    let sender = answer_sender.clone();
+                    remote = connect(&connector, &server_name, conn, sender).await;
+                }
+                let Some(writer) = remote.as_mut() else {
+                    continue;
+                };
+                if probes.len() > 1024 {
+                    probes.retain(|_, (_, time)| time.elapsed() < PROBE_TIMEOUT);
+                }
+                frame.clear();
+                IcmpEcho {
+                    target: request.dst.into(),
+                    from: request.dst.into(),
+                    kind: 8,
+                    code: 0,
+                    ttl: request.ttl,
+                    ident: request.ident,
+                    seq: request.seq,
+                    data: request.data.clone(),
+                }
+                .generate(&mut frame);
+                if let Err(err) = writer.write_all(frame.as_ref()).await {
+                    log::warn!("icmp relay write to {} failed:{}", request.dst, err);
+                    remote.take();
+                    continue;
+                }
+                let key = (request.dst, request.ident, request.seq);
+                probes.insert(key, (request, Instant::now()));
+            }
+            RelayEvent::Answer(None) => {}
+            RelayEvent::Answer(Some((closed, None))) => {
+                if closed == conn {
+                    remote.take();
+                }
+            }
+            RelayEvent::Answer(Some((_, Some(answer)))) => {
+                let (IpAddr::V4(target), IpAddr::V4(from)) = (answer.target, answer.from) else {
+                    continue;
+                };
+                let Some((request, _)) = probes.remove(&(target, answer.ident, answer.seq)) else {
+                    log::info!("icmp answer from {} has no request, dropped", from);
+                    continue;
+                };
+                let packet = if answer.kind == 0 {
+                    request.reply()
+                } else {
+                    request.error(from, answer.kind, answer.code)
+                };
+                if reply_sender.send(packet).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+async fn connect(
+    connector: &TlsConnector,
+    server_name: &ServerName<'static>,
+    conn: usize,
+    sender: Sender<(usize, Option<IcmpEcho>)>,
+) -> Option<WriteHalf<TlsStream<TcpStream>>> {
+    let client = match init_tls_conn(connector.clone(), server_name.clone()).await {
+        Ok(client) => client,
+        Err(err) => {
+            log::error!("icmp relay connect to remote server failed:{:?}", err);
+            return None;
+        }
+    };
+    let (read_half, mut write_half) = split(client);
+    let mut request = BytesMut::new();
+    let empty: SocketAddr = "0.0.0.0:0".parse().unwrap();
+    TrojanRequest::generate(&mut request, ICMP, &empty);
+    if let Err(err) = write_half.write_all(request.as_ref()).await {
+        log::error!("icmp relay send handshake failed:{}", err);
+        return None;
+    }
+    log::info!("icmp relay connection created");
+    spawn(read_answers(read_half, conn, sender));
+    Some(write_half)
+}
+
+async fn read_answers(
+    mut remote: ReadHalf<TlsStream<TcpStream>>,
+    conn: usize,
+    sender: Sender<(usize, Option<IcmpEcho>)>,
+) {
+    let mut buffer = BytesMut::new();
+    'main: loop {
+        match remote.read_buf(&mut buffer).await {
+            Ok(0) | Err(_) => break,
+            _ => {}
+        }
+        loop {
+            match IcmpEcho::parse(buffer.as_ref()) {
+                IcmpParseResult::Continued => break,
+                IcmpParseResult::Packet(answer, size) => {
+                    buffer.advance(size);
+                    if sender.send((conn, Some(answer))).await.is_err() {
+                        break 'main;
+                    }
+                }
+                IcmpParseResult::InvalidProtocol => {
+                    log::error!("invalid protocol from icmp relay connection");
+                    break 'main;
+                }
+            }
+        }
+    }
+    log::info!("icmp relay connection closed");
+    let _ = sender.send((conn, None)).await;
+}
@@ -1,35 +1,60 @@
 use std::{
     fs::OpenOptions,
     io::Write,
-    net::{Ipv4Addr, SocketAddr},
+    net::SocketAddr,
     sync::Arc,
     time::{Duration, Instant},
 };
 
 use bytes::BytesMut;
-use rustls::{ClientConfig, RootCertStore};
+use futures::FutureExt;
+use rustls::RootCertStore;
 use rustls_pki_types::ServerName;
-use tokio::{net::TcpStream, runtime::Runtime, spawn, sync::mpsc::channel};
+use tokio::{
+    net::{lookup_host, TcpStream},
+    runtime::Runtime,
+    spawn,
+    sync::mpsc::channel,
+    task::{block_in_place, spawn_blocking},
+};
 use tokio_rustls::{client::TlsStream, TlsConnector};
-use wintun::Adapter;
 
 use async_smoltcp::TunDevice;
 use types::Result;
-use wintool::adapter::get_main_adapter_gwif;
 
 use crate::{
+    aproxy::{forward, tcp::Tunnel},
     awintun::{
+        dns::run_dns_relay,
+        icmp::run_icmp_relay,
+        pool::TlsPool,
+        rules::subscribe,
         tcp::start_tcp,
         tun::Wintun,
         udp::{run_udp_dispatch, start_udp},
     },
     config::OPTIONS,
+    events::start_event_server,
+    memory::{self, Subsystem},
+    metrics::{record_rtt, server_result},
+    nat64,
+    pinning::pin_certificates,
+    plugin,
     proto::{TrojanRequest, UDP_ASSOCIATE},
+    quic,
+    rules::{DOMAINS, IPSET},
+    sys,
+    tls_client::client_config,
     types,
     types::TrojanError,
-    wintun::{apply_ipset, route_add_with_if},
+    upstream, watchdog,
+    wintun::{apply_ipset, preflight, route_add_with_if},
 };
 
+mod dns;
+mod icmp;
+mod pool;
+mod rules;
 mod tcp;
 mod tun;
 mod udp;
@@ -38,11 +63,40 @@ pub async fn init_tls_conn(
     connector: TlsConnector,
     server_name: ServerName<'static>,
 ) -> types::Result<TlsStream<TcpStream>> {
-    let stream = tokio::net::TcpStream::connect((
-        OPTIONS.wintun_args().hostname.as_str(),
-        OPTIONS.wintun_args().port,
-    ))
-    .await?;
+    let conn = connect_server(connector, server_name).await;
+    server_result(&conn);
+    conn
+}
+
+async fn connect_server(
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+) -> types::Result<TlsStream<TcpStream>> {
+    let start = Instant::now();
+    let upstream = OPTIONS.upstream_proxy.as_ref();
+    let addrs: Vec<_> = match plugin::local_addr() {
+        Some(addr) => vec![addr],
+        // resolved on startup, the server name is left to the proxy
+        None if upstream.is_some() => vec![OPTIONS.back_addr.unwrap()],
+        None => lookup_host((
+            OPTIONS.wintun_args().hostname.as_str(),
+            OPTIONS.wintun_args().port,
+        ))
+        .await?
+        .map(nat64::translate)
+        .collect(),
+    };
+    let mut stream = TcpStream::connect(addrs.as_slice()).await?;
+    if let Some(proxy) = upstream {
+        let args = OPTIONS.wintun_args();
+        upstream::handshake(&mut stream, proxy, args.hostname.as_str(), args.port).await?;
+    }
+    record_rtt(start.elapsed());
+    if let Some(dscp) = OPTIONS.dscp {
+        if let Err(err) = sys::set_dscp(&stream, stream.peer_addr()?.is_ipv4(), dscp) {
+            log::warn!("set dscp failed:{}", err);
+        }
+    }
     let conn = connector.connect(server_name, stream).await?;
     Ok(conn)
 }
@@ -53,40 +107,32 @@ pub fn run() -> Result<()> {
 }
 
 async fn async_run() -> Result<()> {
-    log::info!("dll:{}", OPTIONS.wintun_args().wintun);
-    let wintun = unsafe { wintun::load_from_path(&OPTIONS.wintun_args().wintun)? };
-    let adapter = Adapter::create(&wintun, "trojan", OPTIONS.wintun_args().name.as_str(), None)?;
+    let wintun = preflight::check()?;
+    let adapter = preflight::create_adapter(&wintun)?;
     let session = Arc::new(adapter.start_session(wintun::MAX_RING_CAPACITY)?);
-    if let Some((main_gw, main_index)) = get_main_adapter_gwif() {
-        log::warn!(
-            "main adapter gateway is {}, main adapter index is :{}",
-            main_gw,
-            main_index
-        );
-        let gw: Ipv4Addr = main_gw.parse()?;
-        if let Some(SocketAddr::V4(v4)) = &OPTIONS.back_addr {
-            let index: u32 = (*v4.ip()).into();
-            route_add_with_if(index, !0, gw.into(), main_index)?;
-        }
-    } else {
-        log::error!("main adapter gateway not found");
-        return Err(TrojanError::MainAdapterNotFound);
+    let (gw, main_index) = block_in_place(preflight::main_gateway)?;
+    let mut gateway = (gw, main_index);
+    if let Some(SocketAddr::V4(v4)) = &OPTIONS.back_addr {
+        let index: u32 = (*v4.ip()).into();
+        route_add_with_if(index, !0, gw.into(), main_index)?;
     }
     let index = adapter.get_adapter_index()?;
-    if let Some(file) = &OPTIONS.wintun_args().route_ipset {
-        apply_ipset(file, index, OPTIONS.wintun_args().inverse_route)?;
+    if let Some(addr) = &OPTIONS.events_addr {
+        start_event_server(addr.clone());
     }
+    // large ipsets take a while, traffic is served while the routes are added.
+    let mut routes = OPTIONS.wintun_args().route_ipset.as_ref().map(|file| {
+        spawn_blocking(move || apply_ipset(file, index, OPTIONS.wintun_args().inverse_route))
+    });
 
     let server_name: ServerName = OPTIONS.wintun_args().hostname.as_str().try_into()?;
 
     let mut root_store = RootCertStore::empty();
     root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
 
-    let config = Arc::new(
-        ClientConfig::builder()
-            .with_root_certificates(root_store)
-            .with_no_client_auth(),
-    );
+    let mut config = client_config(root_store);
+    pin_certificates(&mut config);
+    let config = Arc::new(config);
 
     let server_addr = *OPTIONS.back_addr.as_ref().unwrap();
     let mtu = OPTIONS.wintun_args().mtu;
@@ -101,7 +147,29 @@ async fn async_run() -> Result<()> {
     let (data_sender, data_receiver) = channel(128);
     let (socket_sender, socket_receiver) = channel(128);
     let (close_sender, close_receiver) = channel(128);
+    let (dns_sender, dns_receiver) = channel(128);
     let connector = TlsConnector::from(config);
+    // early data waits for a flush, which only the request of a tcp stream gets, and pooled
+    // connections are handshaken ahead of use anyway
+    // streams are opened on the quic connection, nothing to pool
+    let pool_size = if OPTIONS.quic() {
+        0
+    } else {
+        OPTIONS.wintun_args().pool_size
+    };
+    let pool = TlsPool::new(
+        connector
+            .clone()
+            .early_data(OPTIONS.early_data && pool_size == 0),
+        server_name.clone(),
+        pool_size,
+    );
+    spawn(run_dns_relay(
+        dns_receiver,
+        connector.clone(),
+        server_name.clone(),
+        udp_header.clone(),
+    ));
     spawn(run_udp_dispatch(
         data_receiver,
         socket_receiver,
@@ -111,10 +179,59 @@ async fn async_run() -> Result<()> {
         udp_header.clone(),
         close_receiver,
         close_sender.clone(),
+        dns_sender,
     ));
+    if OPTIONS.wintun_args().relay_icmp {
+        let (requests, replies) = device.relay_icmp();
+        spawn(run_icmp_relay(
+            requests,
+            replies,
+            connector.clone(),
+            server_name.clone(),
+        ));
+    }
+    let stream_connector = connector.clone().early_data(OPTIONS.early_data);
+    let stream_server_name = server_name.clone();
+    let (tls_connector, tls_server_name) = (connector.clone(), server_name.clone());
+    forward::start(
+        &OPTIONS.wintun_args().forward,
+        Box::new(move || {
+            let conn = init_tls_conn(stream_connector.clone(), stream_server_name.clone());
+            async move { Ok(Box::new(conn.await?) as Box<dyn Tunnel>) }.boxed()
+        }),
+        Box::new(move || init_tls_conn(tls_connector.clone(), tls_server_name.clone()).boxed()),
+    );
+    if let Some(public_key) = &OPTIONS.wintun_args().rules_public_key {
+        let args = OPTIONS.wintun_args();
+        let targets = [
+            (IPSET, &args.route_ipset),
+            (DOMAINS, &args.pushed_domain_list),
+        ]
+        .into_iter()
+        .filter_map(|(kind, file)| file.clone().map(|file| (kind, file)))
+        .collect();
+        spawn(subscribe(
+            connector.clone(),
+            server_name.clone(),
+            public_key.clone(),
+            targets,
+            move |kind, file| {
+                // routes of the old list stay until restart, the new ones are added on top.
+                if kind == IPSET {
+                    if let Err(err) = apply_ipset(file, index, args.inverse_route) {
+                        log::error!("apply pushed ipset failed:{:?}", err);
+                    }
+                }
+            },
+        ));
+    }
     let mut last_speed_time = Instant::now();
+    let tcp_buffer_size = device.tcp_buffer_size() as u64;
+    let udp_buffer_size = device.udp_buffer_size() as u64;
+    let heartbeat = watchdog::register("awintun");
 
     loop {
+        heartbeat.beat();
         let (tcp_streams, udp_sockets) = device.poll();
         for stream in tcp_streams {
             log::info!(
@@ -122,13 +239,34 @@ async fn async_run() -> Result<()> {
                 stream.local_addr(),
                 stream.peer_addr()
             );
-            spawn(start_tcp(stream, connector.clone(), server_name.clone()));
+            // dropping the stream makes the device close the connection
+            let Some(memory) = memory::reserve(Subsystem::Tun, tcp_buffer_size)
+                .and_then(|memory| memory.and(Subsystem::Tls, memory::TLS_SESSION))
+            else {
+                continue;
+            };
+            spawn(start_tcp(stream, pool.clone(), memory));
         }
         for socket in udp_sockets {
             log::info!("accept udp to:{}", socket.peer_addr());
+            let Some(memory) = memory::reserve(Subsystem::Tun, udp_buffer_size) else {
+                continue;
+            };
             let writer = Arc::new(socket.writer());
             let _ = socket_sender.send(writer).await;
-            spawn(start_udp(socket, data_sender.clone(), close_sender.clone()));
+            spawn(start_udp(
+                socket,
+                data_sender.clone(),
+                close_sender.clone(),
+                memory,
+            ));
+        }
+        if routes.as_ref().is_some_and(|routes| routes.is_finished()) {
+            routes
+                .take()
+                .unwrap()
+                .await
+                .map_err(|_| TrojanError::Winapi("route add task failed".into()))??;
         }
         if last_speed_time.elapsed().as_millis() > 1000 {
             let (rx_speed, tx_speed) = device.calculate_speed();
@@ -144,6 +282,12 @@ async fn async_run() -> Result<()> {
                 .open(OPTIONS.wintun_args().status_file.as_str())?;
             write!(&mut file, "{:.4} {:.4}", rx_speed, tx_speed)?;
             last_speed_time = Instant::now();
+            // pooled and quic connections went through the old network
+            if preflight::follow_gateway(&mut gateway) {
+                pool.flush();
+                quic::reset().await;
+            }
+            pool.expire();
         }
         tokio::time::sleep(Duration::from_millis(1)).await;
     }
@@ -0,0 +1,103 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use rustls_pki_types::ServerName;
+use tokio::{net::TcpStream, sync::Notify};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+use crate::{
+    awintun::init_tls_conn, backoff::Backoff, config::OPTIONS, events::pool_progress, types::Result,
+};
+
+/// TLS connections to the server made ahead of use, so new streams don't wait for a handshake.
+/// A task keeps the pool full, connections idle for half of the tcp idle timeout are replaced
+/// since the server may have closed them by then.
+pub struct TlsPool {
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+    size: usize,
+    conns: Mutex<VecDeque<(Instant, TlsStream<TcpStream>)>>,
+    changed: Notify,
+    max_idle: Duration,
+}
+
+impl TlsPool {
+    pub fn new(
+        connector: TlsConnector,
+        server_name: ServerName<'static>,
+        size: usize,
+    ) -> Arc<TlsPool> {
+        let pool = Arc::new(TlsPool {
+            connector,
+            server_name,
+            size,
+            conns: Mutex::new(VecDeque::with_capacity(size)),
+            changed: Notify::new(),
+            max_idle: Duration::from_secs(OPTIONS.tcp_idle_timeout / 2),
+        });
+        if size > 0 {
+            tokio::spawn(fill(pool.clone()));
+        }
+        pool
+    }
+
+    /// The newest pooled connection if there is a fresh one, a new connection otherwise.
+    pub async fn get(&self) -> Result<TlsStream<TcpStream>> {
+        let conn = self.conns.lock().unwrap().pop_back();
+        if let Some((time, conn)) = conn {
+            self.changed.notify_one();
+            if time.elapsed() < self.max_idle {
+                return Ok(conn);
+            }
+        }
+        init_tls_conn(self.connector.clone(), self.server_name.clone()).await
+    }
+
+    /// Replaces the connections idle for too long.
+    pub fn expire(&self) {
+        let mut conns = self.conns.lock().unwrap();
+        let size = conns.len();
+        conns.retain(|(time, _)| time.elapsed() < self.max_idle);
+        if conns.len() < size {
+            log::info!("{} pooled connections expired", size - conns.len());
+            self.changed.notify_one();
+        }
+    }
+
+    /// Drops the pooled connections, they are made again through the current network.
+    pub fn flush(&self) {
+        let mut conns = self.conns.lock().unwrap();
+        log::warn!("{} pooled connections dropped", conns.len());
+        conns.clear();
+        self.changed.notify_one();
+    }
+}
+
+async fn fill(pool: Arc<TlsPool>) {
+    let mut backoff = Backoff::default();
+    loop {
+        let ready = pool.conns.lock().unwrap().len();
+        if ready >= pool.size {
+            pool.changed.notified().await;
+            continue;
+        }
+        match init_tls_conn(pool.connector.clone(), pool.server_name.clone()).await {
+            Ok(conn) => {
+                let mut conns = pool.conns.lock().unwrap();
+                conns.push_back((Instant::now(), conn));
+                if conns.len() == pool.size {
+                    log::info!("connection pool ready with {} connections", pool.size);
+                }
+                pool_progress(conns.len(), pool.size);
+                backoff.succeeded();
+            }
+            Err(err) => {
+                log::error!("pooled connection failed:{:?}", err);
+                tokio::time::sleep(backoff.failed()).await;
+            }
+        }
+    }
+}
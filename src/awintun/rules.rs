@@ -0,0 +1,194 @@
+//! Subscribing side of the rule lists pushed by the server, see [`crate::rules`].
+
+use std::{fs, net::SocketAddr, path::Path};
+
+use bytes::{Buf, BytesMut};
+use ring::signature::{UnparsedPublicKey, ED25519};
+use rustls_pki_types::ServerName;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+use tokio_rustls::TlsConnector;
+
+use crate::{
+    config::OPTIONS,
+    proto::{TrojanRequest, RULES},
+    rules::{HEADER_LEN, KEEPALIVE, PUSH_INTERVAL, SIGNATURE_LEN},
+    types::{Result, TrojanError},
+    upstream,
+};
+
+const MAX_PAYLOAD: usize = 16 * 1024 * 1024;
+
+struct Frame {
+    kind: u8,
+    version: u64,
+    signed: BytesMut,
+    signature: BytesMut,
+}
+
+impl Frame {
+    /// Takes a complete frame out of `buffer`, `None` if more data is needed.
+    fn parse(buffer: &mut BytesMut) -> Result<Option<Frame>> {
+        if buffer.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let length = (&buffer[9..HEADER_LEN]).get_u32() as usize;
+        if length > MAX_PAYLOAD {
+            return Err(TrojanError::Rules("rule list too large"));
+        }
+        if buffer.len() < HEADER_LEN + length + SIGNATURE_LEN {
+            return Ok(None);
+        }
+        let signed = buffer.split_to(HEADER_LEN + length);
+        let signature = buffer.split_to(SIGNATURE_LEN);
+        let kind = signed[0];
+        let version = (&signed[1..9]).get_u64();
+        Ok(Some(Frame {
+            kind,
+            version,
+            signed,
+            signature,
+        }))
+    }
+
+    fn payload(&self) -> &[u8] {
+        &self.signed[HEADER_LEN..]
+    }
+
+    fn verify(&self, public_key: &[u8]) -> bool {
+        UnparsedPublicKey::new(&ED25519, public_key)
+            .verify(self.signed.as_ref(), self.signature.as_ref())
+            .is_ok()
+    }
+}
+
+/// Writes a verified list, leaving the target untouched if the content is the same.
+fn save_list(path: &str, payload: &[u8]) -> Result<bool> {
+    if fs::read(path).is_ok_and(|current| current == payload) {
+        return Ok(false);
+    }
+    let tmp = format!("{}.tmp", path);
+    fs::write(tmp.as_str(), payload)?;
+    fs::rename(tmp.as_str(), Path::new(path))?;
+    Ok(true)
+}
+
+async fn subscribe_once<F: FnMut(u8, &str)>(
+    connector: &TlsConnector,
+    server_name: &ServerName<'static>,
+    public_key: &[u8],
+    targets: &[(u8, String)],
+    versions: &mut [u64; 3],
+    applied: &mut F,
+) -> Result<()> {
+    let server_addr: SocketAddr = *OPTIONS.back_addr.as_ref().unwrap();
+    let mut stream = TcpStream::connect(server_addr).await?;
+    if let Some(proxy) = &OPTIONS.upstream_proxy {
+        let args = OPTIONS.wintun_args();
+        upstream::handshake(&mut stream, proxy, args.hostname.as_str(), args.port).await?;
+    }
+    let mut conn = connector.connect(server_name.clone(), stream).await?;
+    let mut request = BytesMut::new();
+    TrojanRequest::generate(&mut request, RULES, OPTIONS.empty_addr.as_ref().unwrap());
+    conn.write_all(request.as_ref()).await?;
+    log::info!("subscribed to rule lists pushed by {}", server_addr);
+    let mut buffer = BytesMut::new();
+    loop {
+        while let Some(frame) = Frame::parse(&mut buffer)? {
+            if frame.kind == KEEPALIVE {
+                continue;
+            }
+            if !frame.verify(public_key) {
+                return Err(TrojanError::Rules("invalid rule list signature"));
+            }
+            let Some((_, path)) = targets.iter().find(|(kind, _)| *kind == frame.kind) else {
+                continue;
+            };
+            // versions are content hashes, any other one is an update
+            let current = &mut versions[frame.kind as usize];
+            if frame.version == *current {
+                continue;
+            }
+            *current = frame.version;
+            if save_list(path, frame.payload())? {
+                log::warn!(
+                    "rule list {} updated to version {:016x}",
+                    path,
+                    frame.version
+                );
+                applied(frame.kind, path.as_str());
+            }
+        }
+        if timeout(PUSH_INTERVAL * 3, conn.read_buf(&mut buffer)).await?? == 0 {
+            return Err(TrojanError::Rules("rule subscription closed by server"));
+        }
+    }
+}
+
+/// Keeps a rule subscription to the server, writing verified lists to the `targets` files
+/// and calling `applied` after a file changes.
+pub async fn subscribe<F: FnMut(u8, &str)>(
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+    public_key: String,
+    targets: Vec<(u8, String)>,
+    mut applied: F,
+) {
+    let Ok(public_key) = hex::decode(public_key.as_str()) else {
+        log::error!("rules public key {} is not hex encoded", public_key);
+        return;
+    };
+    let mut versions = [0; 3];
+    loop {
+        if let Err(err) = subscribe_once(
+            &connector,
+            &server_name,
+            public_key.as_slice(),
+            targets.as_slice(),
+            &mut versions,
+            &mut applied,
+        )
+        .await
+        {
+            log::error!("rule subscription failed:{:?}", err);
+        }
+        tokio::time::sleep(PUSH_INTERVAL).await;
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_frame() {
+        use bytes::BytesMut;
+        use ring::signature::{Ed25519KeyPair, KeyPair};
+
+        use crate::{
+            awintun::rules::Frame,
+            rules::{encode, DOMAINS},
+        };
+
+        let key = Ed25519KeyPair::from_seed_unchecked(&[7u8; 32]).unwrap();
+        let encoded = encode(DOMAINS, 42, b"google.com\n", &key);
+
+        let mut buffer = BytesMut::from(&encoded[..20]);
+        assert!(Frame::parse(&mut buffer).unwrap().is_none());
+
+        let mut buffer = BytesMut::from(encoded.as_slice());
+        buffer.extend_from_slice(&[1, 2]);
+        let frame = Frame::parse(&mut buffer).unwrap().unwrap();
+        assert_eq!(buffer.as_ref(), &[1, 2]);
+        assert_eq!((frame.kind, frame.version), (DOMAINS, 42));
+        assert_eq!(frame.payload(), b"google.com\n");
+        assert!(frame.verify(key.public_key().as_ref()));
+
+        let mut tampered = encoded.clone();
+        tampered[14] = b'G';
+        let frame = Frame::parse(&mut BytesMut::from(tampered.as_slice()))
+            .unwrap()
+            .unwrap();
+        assert!(!frame.verify(key.public_key().as_ref()));
+    }
+}
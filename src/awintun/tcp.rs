@@ -1,93 +1,119 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{net::SocketAddr, sync::Arc};
 
 use bytes::BytesMut;
-use rustls_pki_types::ServerName;
 use tokio::{
-    io::{split, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+    io::{split, AsyncRead, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
     spawn,
 };
-use tokio_rustls::{client::TlsStream, TlsConnector};
 
 use async_smoltcp::{TcpReadHalf, TcpStream, TcpWriteHalf};
 
 use crate::{
-    awintun::init_tls_conn,
+    async_utils::copy_with,
+    awintun::pool::TlsPool,
     config::OPTIONS,
-    proto::{TrojanRequest, CONNECT},
+    events::ConnTracker,
+    limiter::{Priority, DOWNLOAD, UPLOAD},
+    memory::Reservation,
+    mux::open_stream,
+    proto::{Sock5Address, TrojanRequest, CONNECT},
+    quic,
 };
 
-pub async fn start_tcp(
-    local: TcpStream,
-    connector: TlsConnector,
-    server_name: ServerName<'static>,
-) {
-    let client = init_tls_conn(connector, server_name).await;
-    if let Ok(client) = client {
+pub async fn start_tcp(local: TcpStream, pool: Arc<TlsPool>, memory: Reservation) {
+    if OPTIONS.quic() {
+        match quic::open_stream().await {
+            Ok(client) => relay(local, client, *OPTIONS.back_addr.as_ref().unwrap(), memory),
+            Err(err) => log::error!("open quic stream failed:{:?}", err),
+        }
+    } else if OPTIONS.mux > 0 {
+        let priority = Priority::of_stream(local.peer_addr().port);
+        match open_stream(pool.get(), priority).await {
+            Ok(client) => relay(local, client, *OPTIONS.back_addr.as_ref().unwrap(), memory),
+            Err(err) => log::error!("open mux stream failed:{:?}", err),
+        }
+    } else if let Ok(client) = pool.get().await {
         let dst_addr = client.get_ref().0.peer_addr().unwrap();
-        let (read_half, write_half) = split(client);
-        let (reader, writer) = local.into_split();
-        spawn(local_to_remote(reader, write_half));
-        spawn(remote_to_local(dst_addr, read_half, writer));
+        relay(local, client, dst_addr, memory);
     }
 }
 
-pub async fn local_to_remote(
+fn relay<S>(local: TcpStream, client: S, dst_addr: SocketAddr, memory: Reservation)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let tracker = Arc::new(
+        ConnTracker::new(
+            "tcp",
+            local.local_addr().to_string(),
+            local.peer_addr().to_string(),
+        )
+        .holding(memory),
+    );
+    let priority = Priority::of_stream(local.peer_addr().port);
+    let (read_half, write_half) = split(client);
+    let (reader, writer) = local.into_split();
+    spawn(local_to_remote(
+        reader,
+        write_half,
+        priority,
+        tracker.clone(),
+    ));
+    spawn(remote_to_local(
+        dst_addr, read_half, writer, priority, tracker,
+    ));
+}
+
+pub async fn local_to_remote<S: AsyncWrite>(
     mut local: TcpReadHalf,
-    mut remote: WriteHalf<TlsStream<tokio::net::TcpStream>>,
+    mut remote: WriteHalf<S>,
+    priority: Priority,
+    tracker: Arc<ConnTracker>,
 ) {
     let mut request = BytesMut::new();
-    TrojanRequest::generate(&mut request, CONNECT, &local.peer_addr());
-    if let Err(err) = remote.write_all(request.as_ref()).await {
+    TrojanRequest::generate_stream(
+        &mut request,
+        CONNECT,
+        &Sock5Address::Socket(local.peer_addr()),
+    );
+    let sent = match remote.write_all(request.as_ref()).await {
+        Ok(_) => remote.flush().await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = sent {
         log::error!("send request to remote server failed:{}", err);
         let _ = remote.shutdown().await;
         return;
     }
     let dst_addr = local.peer_addr();
-    let _ = copy_stream(
+    copy_with(
         &mut local,
-        &mut remote,
-        format!("local to remote:{}", dst_addr),
+        remote,
+        format!("tcp local to remote:{}", dst_addr),
+        OPTIONS.tcp_idle_timeout,
+        Some((&UPLOAD, priority)),
+        Some(priority),
+        |n| tracker.add_tx(n),
     )
     .await;
     local.close();
-    let _ = remote.shutdown().await;
-    log::info!("local to remote closed");
 }
 
-pub async fn remote_to_local(
+pub async fn remote_to_local<S: AsyncRead + Send>(
     dst_addr: SocketAddr,
-    mut remote: ReadHalf<TlsStream<tokio::net::TcpStream>>,
-    mut local: TcpWriteHalf,
+    remote: ReadHalf<S>,
+    local: TcpWriteHalf,
+    priority: Priority,
+    tracker: Arc<ConnTracker>,
 ) {
-    let _ = copy_stream(
-        &mut remote,
-        &mut local,
-        format!("remote:{:?} to local", dst_addr),
+    copy_with(
+        remote,
+        local,
+        format!("tcp remote:{} to local", dst_addr),
+        OPTIONS.tcp_idle_timeout,
+        Some((&DOWNLOAD, priority)),
+        None,
+        |n| tracker.add_rx(n),
     )
     .await;
-    log::info!("remote to local closed");
-    let _ = local.shutdown().await;
-}
-
-async fn copy_stream<R, W>(reader: &mut R, writer: &mut W, message: String)
-where
-    R: AsyncReadExt + Unpin,
-    W: AsyncWriteExt + Unpin,
-{
-    let mut buffer = vec![0u8; 4096];
-    while let Ok(Ok(n)) = tokio::time::timeout(
-        Duration::from_secs(OPTIONS.tcp_idle_timeout),
-        reader.read(buffer.as_mut_slice()),
-    )
-    .await
-    {
-        if n == 0 {
-            log::warn!("tcp {} failed, read shutdown", message);
-            break;
-        }
-        if writer.write_all(&buffer.as_slice()[..n]).await.is_err() {
-            log::warn!("tcp {} failed, write shutdown", message);
-            break;
-        }
-    }
 }
@@ -5,17 +5,24 @@ use rustls_pki_types::ServerName;
 use smoltcp::wire::IpEndpoint;
 use tokio::{
     io::{split, AsyncReadExt, AsyncWriteExt, ReadHalf},
-    net::TcpStream,
     spawn,
     sync::mpsc::{channel, Receiver, Sender},
 };
-use tokio_rustls::{client::TlsStream, TlsConnector};
+use tokio_rustls::TlsConnector;
 
 use async_smoltcp::{UdpSocket, UdpWriteHalf};
 
 use crate::{
+    aproxy::tcp::Tunnel,
     awintun::init_tls_conn,
+    config::OPTIONS,
+    events::ConnTracker,
+    limiter::{Priority, DOWNLOAD, UPLOAD},
+    memory::{self, Reservation, Subsystem},
+    metrics::{incr, COUNTERS},
     proto::{UdpAssociate, UdpParseResultEndpoint},
+    quic,
+    types::Result,
 };
 
 enum DispatchReturn {
@@ -33,6 +40,7 @@ pub async fn run_udp_dispatch(
     request: Arc<BytesMut>,
     mut close_receiver: Receiver<(IpEndpoint, bool)>,
     close_sender: Sender<(IpEndpoint, bool)>,
+    dns_sender: Sender<(IpEndpoint, Arc<UdpWriteHalf>, BytesMut)>,
 ) {
     let mut locals: HashMap<IpEndpoint, Arc<UdpWriteHalf>> = HashMap::new();
     let mut req_senders = HashMap::new();
@@ -58,9 +66,17 @@ pub async fn run_udp_dispatch(
                     data.len()
                 );
                 if !locals.contains_key(&dst_addr) {
+                    incr(&COUNTERS.udp_no_socket);
                     log::error!("socket:{} not found in cache", dst_addr);
                     continue;
                 }
+                if dst_addr.port == 53 {
+                    let local = locals.get(&dst_addr).unwrap().clone();
+                    if dns_sender.send((src_addr, local, data)).await.is_err() {
+                        incr(&COUNTERS.udp_session_closed);
+                    }
+                    continue;
+                }
                 let sender = match req_senders.get(&src_addr) {
                     Some(sender) => sender,
                     None => {
@@ -81,7 +97,12 @@ pub async fn run_udp_dispatch(
                         req_senders.get(&src_addr).unwrap()
                     }
                 };
-                let _ = sender.send((dst_addr, data)).await;
+                if sender.capacity() == 0 {
+                    incr(&COUNTERS.backpressure);
+                }
+                if sender.send((dst_addr, data)).await.is_err() {
+                    incr(&COUNTERS.udp_session_closed);
+                }
             }
             DispatchReturn::Socket(ret) => {
                 let socket = ret.unwrap();
@@ -107,6 +128,7 @@ pub async fn start_udp(
     mut local: UdpSocket,
     data_sender: Sender<(IpEndpoint, IpEndpoint, BytesMut)>,
     close_sender: Sender<(IpEndpoint, bool)>,
+    _memory: Reservation,
 ) {
     let target: IpEndpoint = local.peer_addr();
     log::info!("start udp listening for {}", target);
@@ -114,6 +136,9 @@ pub async fn start_udp(
         match tokio::time::timeout(Duration::from_secs(120), local.recv_from()).await {
             Ok(Ok((source, data))) => {
                 log::info!("receive {} bytes from {} to {}", data.len(), source, target);
+                if data_sender.capacity() == 0 {
+                    incr(&COUNTERS.backpressure);
+                }
                 let _ = data_sender.send((source, target, data)).await;
             }
             Err(_) | Ok(Err(_)) => {
@@ -137,26 +162,37 @@ async fn local_to_remote(
     request: Arc<BytesMut>,
 ) {
     let dst_addr = local.peer_addr();
-    let (mut remote, remote_local_addr) =
-        if let Ok(client) = init_tls_conn(connector, server_name.clone()).await {
-            let local_addr = client.get_ref().0.local_addr().unwrap();
-            let (read_half, mut write_half) = split(client);
-            if let Err(err) = write_half.write_all(request.as_ref()).await {
-                log::error!("udp send handshake failed:{}", err);
-                let _ = write_half.shutdown().await;
+    let Some(memory) = memory::reserve(Subsystem::Udp, memory::UDP_SESSION)
+        .and_then(|memory| memory.and(Subsystem::Tls, memory::TLS_SESSION))
+    else {
+        let _ = sender.send((src_addr, true)).await;
+        return;
+    };
+    let (mut remote, remote_local_addr, tracker) =
+        match open_remote(connector, server_name, request.as_ref()).await {
+            Ok((client, local_addr)) => {
+                let (read_half, write_half) = split(client);
+                log::info!("remote:{:?} created for source:{}", local_addr, src_addr);
+
+                let tracker = Arc::new(
+                    ConnTracker::new("udp", src_addr.to_string(), dst_addr.to_string())
+                        .holding(memory),
+                );
+                spawn(remote_to_local(
+                    read_half,
+                    local_addr,
+                    local,
+                    src_addr,
+                    sender,
+                    tracker.clone(),
+                ));
+                (write_half, local_addr, tracker)
+            }
+            Err(err) => {
+                log::error!("{} connect to remote server failed:{:?}", src_addr, err);
                 let _ = sender.send((src_addr, true)).await;
                 return;
             }
-            log::info!("remote:{:?} created for source:{}", local_addr, src_addr);
-
-            spawn(remote_to_local(
-                read_half, local_addr, local, src_addr, sender,
-            ));
-            (write_half, local_addr)
-        } else {
-            log::error!("{} connect to remote server failed", src_addr);
-            let _ = sender.send((src_addr, true)).await;
-            return;
         };
 
     log::info!("local to remote started");
@@ -169,12 +205,17 @@ async fn local_to_remote(
         log::info!("send {} bytes data to {}", data.len(), target);
         header.clear();
         UdpAssociate::generate_endpoint(&mut header, &target, data.len() as u16);
+        UPLOAD
+            .acquire(data.len(), Priority::of_packet(target.port, data.len()))
+            .await;
         if remote.write_all(header.as_ref()).await.is_err()
             || remote.write_all(data.as_ref()).await.is_err()
         {
+            incr(&COUNTERS.udp_remote_failed);
             log::warn!("udp write to {} failed", dst_addr);
             break;
         }
+        tracker.add_tx(data.len());
     }
     let _ = remote.shutdown().await;
     log::info!(
@@ -184,12 +225,34 @@ async fn local_to_remote(
     );
 }
 
+/// Starts an association with the UDP_ASSOCIATE `request` on a connection of its own or, over
+/// QUIC, on a stream with its packets as datagrams. Returns it with the local address of its
+/// connection, None over QUIC where the connection is shared.
+async fn open_remote(
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+    request: &[u8],
+) -> Result<(Box<dyn Tunnel>, Option<SocketAddr>)> {
+    if OPTIONS.quic() {
+        let association = quic::open_association(request).await?;
+        return Ok((Box::new(association), None));
+    }
+    let mut client = init_tls_conn(connector, server_name).await?;
+    let local_addr = client.get_ref().0.local_addr()?;
+    if let Err(err) = client.write_all(request).await {
+        let _ = client.shutdown().await;
+        return Err(err.into());
+    }
+    Ok((Box::new(client), Some(local_addr)))
+}
+
 async fn remote_to_local(
-    mut remote: ReadHalf<TlsStream<TcpStream>>,
-    remote_local_addr: SocketAddr,
+    mut remote: ReadHalf<Box<dyn Tunnel>>,
+    remote_local_addr: Option<SocketAddr>,
     local: Arc<UdpWriteHalf>,
     source: IpEndpoint,
     sender: Sender<(IpEndpoint, bool)>,
+    tracker: Arc<ConnTracker>,
 ) {
     log::info!("remote to local started");
     let mut buffer = BytesMut::new();
@@ -210,7 +273,16 @@ async fn remote_to_local(
                 }
                 UdpParseResultEndpoint::Packet(packet) => {
                     let payload = &packet.payload[..packet.length];
-                    let _ = local.send_to(payload, source).await;
+                    DOWNLOAD
+                        .acquire(
+                            payload.len(),
+                            Priority::of_packet(packet.endpoint.port, payload.len()),
+                        )
+                        .await;
+                    if local.send_to(payload, source).await.is_err() {
+                        incr(&COUNTERS.udp_local_failed);
+                    }
+                    tracker.add_rx(payload.len());
                     log::info!(
                         "{} - {} get one packet with size:{}",
                         packet.endpoint,
@@ -220,6 +292,7 @@ async fn remote_to_local(
                     buffer.advance(packet.offset);
                 }
                 UdpParseResultEndpoint::InvalidProtocol => {
+                    incr(&COUNTERS.udp_invalid_protocol);
                     log::error!(
                         "invalid protocol from {:?} to {}",
                         remote_local_addr,
@@ -0,0 +1,97 @@
+use std::{
+    sync::atomic::Ordering,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use rand::Rng;
+
+use crate::metrics::COUNTERS;
+
+const BASE: Duration = Duration::from_secs(1);
+const MAX: Duration = Duration::from_secs(60);
+
+/// Delays between reconnect attempts to the server. The delay doubles after every failed
+/// attempt and is picked at random from its upper half, so clients that lost the server at
+/// the same moment, like on a server restart, don't come back all at once.
+#[derive(Default)]
+pub struct Backoff {
+    failures: u32,
+    until: Option<Instant>,
+}
+
+impl Backoff {
+    /// True if a new attempt may be made now.
+    pub fn ready(&self) -> bool {
+        self.until.is_none_or(|until| Instant::now() >= until)
+    }
+
+    /// Records a failed attempt and returns the delay before the next one. Failures reported
+    /// while still waiting belong to the same attempt and are ignored.
+    pub fn failed(&mut self) -> Duration {
+        let now = Instant::now();
+        if let Some(until) = self.until.filter(|until| *until > now) {
+            return until - now;
+        }
+        self.failures += 1;
+        let delay = jitter(self.failures);
+        self.until = Some(now + delay);
+        log::warn!(
+            "reconnect attempt {} failed, retry in {} ms",
+            self.failures,
+            delay.as_millis()
+        );
+        publish(self.failures, delay);
+        delay
+    }
+
+    pub fn succeeded(&mut self) {
+        if self.failures > 0 {
+            log::warn!("reconnected after {} failed attempts", self.failures);
+            self.failures = 0;
+            publish(0, Duration::ZERO);
+        }
+        self.until = None;
+    }
+}
+
+fn jitter(failures: u32) -> Duration {
+    let cap = BASE.saturating_mul(1 << (failures - 1).min(16)).min(MAX);
+    rand::thread_rng().gen_range(cap / 2..=cap)
+}
+
+/// Shares the state with `trojan status`.
+fn publish(failures: u32, delay: Duration) {
+    let retry_at = if failures == 0 {
+        0
+    } else {
+        (SystemTime::now() + delay)
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_millis() as u64)
+            .unwrap_or_default()
+    };
+    COUNTERS
+        .reconnect_failures
+        .store(failures as u64, Ordering::Relaxed);
+    COUNTERS.retry_at.store(retry_at, Ordering::Relaxed);
+}
+
+mod tests {
+    #[test]
+    fn test_backoff() {
+        use std::time::Duration;
+
+        use crate::backoff::{jitter, Backoff};
+
+        assert!((Duration::from_millis(500)..=Duration::from_secs(1)).contains(&jitter(1)));
+        assert!((Duration::from_secs(4)..=Duration::from_secs(8)).contains(&jitter(4)));
+        assert!((Duration::from_secs(30)..=Duration::from_secs(60)).contains(&jitter(30)));
+        let mut backoff = Backoff::default();
+        assert!(backoff.ready());
+        backoff.failed();
+        backoff.failed();
+        assert_eq!(backoff.failures, 1);
+        assert!(!backoff.ready());
+        backoff.succeeded();
+        assert!(backoff.ready());
+    }
+}
@@ -0,0 +1,63 @@
+//! Browser orders of the cipher suites and key exchange groups the clients offer.
+//!
+//! This is not fingerprint resistance: the extensions, their order and GREASE values of the
+//! ClientHello stay those of rustls, which tell it apart from any browser.
+
+use rustls::crypto::{
+    ring::{cipher_suite::*, default_provider, kx_group},
+    CryptoProvider,
+};
+
+/// Crypto provider offering cipher suites and groups in the `--cipher-order` named, rustls
+/// defaults otherwise. The rest of the ClientHello is the one of rustls.
+pub fn provider(order: &str) -> CryptoProvider {
+    let mut provider = default_provider();
+    match order {
+        "chrome" => {
+            provider.cipher_suites = vec![
+                TLS13_AES_128_GCM_SHA256,
+                TLS13_AES_256_GCM_SHA384,
+                TLS13_CHACHA20_POLY1305_SHA256,
+                TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+                TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+                TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+                TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+                TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+                TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+            ];
+        }
+        "firefox" => {
+            provider.cipher_suites = vec![
+                TLS13_AES_128_GCM_SHA256,
+                TLS13_CHACHA20_POLY1305_SHA256,
+                TLS13_AES_256_GCM_SHA384,
+                TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+                TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+                TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+                TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+                TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+                TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+            ];
+        }
+        _ => return provider,
+    }
+    provider.kx_groups = vec![kx_group::X25519, kx_group::SECP256R1, kx_group::SECP384R1];
+    provider
+}
+
+mod tests {
+    #[test]
+    fn test_provider() {
+        use rustls::crypto::ring::cipher_suite::*;
+
+        use super::provider;
+
+        let chrome = provider("chrome");
+        assert_eq!(chrome.cipher_suites[0], TLS13_AES_128_GCM_SHA256);
+        assert_eq!(chrome.cipher_suites[1], TLS13_AES_256_GCM_SHA384);
+        let firefox = provider("firefox");
+        assert_eq!(firefox.cipher_suites[1], TLS13_CHACHA20_POLY1305_SHA256);
+        let rustls = provider("rustls");
+        assert_eq!(rustls.cipher_suites[0], TLS13_AES_256_GCM_SHA384);
+    }
+}
@@ -1,13 +1,26 @@
 use std::collections::HashSet;
 
+use crate::{
+    config::OPTIONS,
+    geo::GeoData,
+    geosite::{DomainKind, GeoSites},
+};
+
+/// Lines like geosite:geolocation-!cn add the domains of a --geosite category.
+const GEOSITE_PREFIX: &str = "geosite:";
+
+#[derive(Default)]
 pub struct DomainMap {
     domains: HashSet<String>,
+    /// Read on the first geosite line, dropped once the list is loaded.
+    sites: Option<GeoSites>,
 }
 
 impl DomainMap {
     pub fn new() -> Self {
         Self {
             domains: HashSet::new(),
+            sites: None,
         }
     }
 
@@ -15,6 +28,10 @@ impl DomainMap {
         self.domains.insert(domain.into());
     }
 
+    pub fn domains(&self) -> impl Iterator<Item = &String> {
+        self.domains.iter()
+    }
+
     pub fn contains(&self, domain: &str) -> bool {
         let items: Vec<_> = domain.split('.').collect();
         let end_index = if domain.ends_with('.') {
@@ -32,6 +49,33 @@ impl DomainMap {
     }
 }
 
+impl GeoData for DomainMap {
+    fn add_line(&mut self, line: &str) {
+        let Some(category) = line.strip_prefix(GEOSITE_PREFIX) else {
+            self.add_domain(line);
+            return;
+        };
+        let sites = self
+            .sites
+            .get_or_insert_with(|| GeoSites::new(OPTIONS.geosite.as_str()));
+        match sites.domains(category) {
+            Ok(domains) => {
+                // full domains match their subdomains too, keywords and regexes are skipped
+                for domain in domains {
+                    if matches!(domain.kind, DomainKind::Full | DomainKind::Suffix) {
+                        self.domains.insert(domain.value.clone());
+                    }
+                }
+            }
+            Err(err) => log::error!("load {} failed:{:?}", line, err),
+        }
+    }
+
+    fn finish(&mut self) {
+        self.sites.take();
+    }
+}
+
 mod tests {
     #![allow(unused_imports)]
     extern crate test;
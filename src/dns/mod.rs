@@ -15,16 +15,19 @@ use winapi::{
 
 use server::DnsServer;
 pub use wintool::adapter::{
-    get_adapter_index, get_adapter_ip, get_main_adapter_gwif, set_dns_server,
+    get_adapter_gwif, get_adapter_index, get_adapter_ip, get_gateway_tunnel_adapters,
+    get_main_adapter_gwif, get_main_adapter_subnets,
 };
 
 use crate::{
     types::{Result, TrojanError},
+    watchdog,
     wintun::route_add_with_if,
     OPTIONS,
 };
 
-mod domain;
+pub mod domain;
+mod pac;
 mod server;
 
 /// Token for trusted DNS server
@@ -34,6 +37,15 @@ const DNS_POISONED: usize = 3;
 /// Token for local DNS server
 const DNS_LOCAL: usize = 4;
 
+pub fn set_dns_server(name_server: String) -> bool {
+    if OPTIONS.dry_run() {
+        log::warn!("[dry-run] set dns server to '{}'", name_server);
+        true
+    } else {
+        wintool::adapter::set_dns_server(name_server)
+    }
+}
+
 extern "system" fn console_callback(ctrl_type: DWORD) -> BOOL {
     log::warn!("console_callback called:{}", ctrl_type);
     match ctrl_type {
@@ -108,6 +120,13 @@ pub fn run() -> Result<()> {
     let mut events = Events::with_capacity(1024);
     let mut dns_server = DnsServer::new(index);
     dns_server.setup(&poll);
+    if let Some(addr) = &OPTIONS.dns_args().pac_addr {
+        pac::start(
+            addr.as_str(),
+            OPTIONS.dns_args().pac_proxy.clone(),
+            dns_server.blocked_domains(),
+        )?;
+    }
     if !set_dns_server(dns_server.name_server()) {
         log::error!("set dns server failed");
         return Ok(());
@@ -115,7 +134,9 @@ pub fn run() -> Result<()> {
 
     log::warn!("dns server is ready");
     let timeout = Duration::from_secs(1);
+    let heartbeat = watchdog::register("dns");
     loop {
+        heartbeat.beat();
         let mut update_domain = false;
         let mut update_hosts = false;
         for event in receiver.try_iter() {
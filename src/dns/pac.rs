@@ -0,0 +1,221 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io::{Read, Write},
+    net::TcpListener,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    config::{Outbound, OPTIONS},
+    dns::domain::DomainMap,
+    geo::GeoDatabase,
+    routing::{self, Rules},
+    types::Result,
+    wintun::IPSet,
+};
+
+/// Nothing listens on the discard port, so rejected hosts fail right away.
+const REJECT: &str = "PROXY 127.0.0.1:9";
+
+fn action(outbound: Outbound) -> &'static str {
+    match outbound {
+        Outbound::Proxy => "PROXY",
+        Outbound::Direct => "DIRECT",
+        Outbound::Block => "REJECT",
+    }
+}
+
+fn write_table(script: &mut String, name: &str, table: &HashMap<String, Outbound>) {
+    let _ = writeln!(script, "var {} = {{", name);
+    let mut entries: Vec<_> = table.iter().collect();
+    entries.sort_unstable_by_key(|(domain, _)| *domain);
+    for (domain, outbound) in entries {
+        let domain = serde_json::to_string(domain).unwrap();
+        let _ = writeln!(script, "  {}: \"{}\",", domain, action(*outbound));
+    }
+    script.push_str("};\n");
+}
+
+/// Generates a PAC script deciding like the DNS server: the routing `rules` first, then blocked
+/// domains (and their subdomains) go to `proxy`. Other hosts go to `proxy` too if they resolve
+/// into one of the sorted, disjoint address `ranges`, direct otherwise.
+pub fn generate_pac(
+    rules: Option<Rules>,
+    domains: &DomainMap,
+    ranges: &[(u32, u32)],
+    proxy: &str,
+) -> String {
+    let mut script = String::new();
+    let proxy = format!("{}; DIRECT", proxy);
+    let _ = writeln!(
+        script,
+        "var proxy = {};",
+        serde_json::to_string(&proxy).unwrap()
+    );
+    let _ = writeln!(script, "var reject = \"{}\";", REJECT);
+    let empty = HashMap::new();
+    let (full, suffix, keyword) = rules.unwrap_or((&empty, &empty, &[]));
+    write_table(&mut script, "full", full);
+    write_table(&mut script, "suffixes", suffix);
+    script.push_str("var keywords = [\n");
+    for (word, outbound) in keyword {
+        let word = serde_json::to_string(word).unwrap();
+        let _ = writeln!(script, "  [{}, \"{}\"],", word, action(*outbound));
+    }
+    script.push_str("];\nvar domains = {\n");
+    let mut domains: Vec<_> = domains.domains().collect();
+    domains.sort();
+    for domain in domains {
+        let domain = serde_json::to_string(domain.trim_end_matches('.')).unwrap();
+        let _ = writeln!(script, "  {}: 1,", domain);
+    }
+    script.push_str("};\nvar ranges = [\n");
+    for (start, end) in ranges {
+        let _ = writeln!(script, "  [{}, {}],", start, end);
+    }
+    script.push_str(
+        "];\n\
+        function inRanges(ip) {\n\
+        \x20 var parts = ip.split(\".\");\n\
+        \x20 if (parts.length != 4) return false;\n\
+        \x20 var n = ((+parts[0] * 256 + +parts[1]) * 256 + +parts[2]) * 256 + +parts[3];\n\
+        \x20 var low = 0, high = ranges.length - 1;\n\
+        \x20 while (low <= high) {\n\
+        \x20   var mid = (low + high) >> 1;\n\
+        \x20   if (n < ranges[mid][0]) high = mid - 1;\n\
+        \x20   else if (n > ranges[mid][1]) low = mid + 1;\n\
+        \x20   else return true;\n\
+        \x20 }\n\
+        \x20 return false;\n\
+        }\n\
+        function outbound(action) {\n\
+        \x20 if (action == \"PROXY\") return proxy;\n\
+        \x20 return action == \"DIRECT\" ? \"DIRECT\" : reject;\n\
+        }\n\
+        function route(host) {\n\
+        \x20 if (full.hasOwnProperty(host)) return full[host];\n\
+        \x20 var suffix = host;\n\
+        \x20 while (true) {\n\
+        \x20   if (suffixes.hasOwnProperty(suffix)) return suffixes[suffix];\n\
+        \x20   var pos = suffix.indexOf(\".\");\n\
+        \x20   if (pos < 0) break;\n\
+        \x20   suffix = suffix.substring(pos + 1);\n\
+        \x20 }\n\
+        \x20 for (var i = 0; i < keywords.length; i++) {\n\
+        \x20   if (host.indexOf(keywords[i][0]) >= 0) return keywords[i][1];\n\
+        \x20 }\n\
+        \x20 return null;\n\
+        }\n\
+        function FindProxyForURL(url, host) {\n\
+        \x20 host = host.toLowerCase();\n\
+        \x20 var action = route(host);\n\
+        \x20 if (action) return outbound(action);\n\
+        \x20 var suffix = host;\n\
+        \x20 var pos = suffix.indexOf(\".\");\n\
+        \x20 while (pos >= 0) {\n\
+        \x20   if (domains.hasOwnProperty(suffix)) return proxy;\n\
+        \x20   suffix = suffix.substring(pos + 1);\n\
+        \x20   pos = suffix.indexOf(\".\");\n\
+        \x20 }\n\
+        \x20 if (ranges.length == 0) return \"DIRECT\";\n\
+        \x20 var ip = dnsResolve(host);\n\
+        \x20 return ip && inRanges(ip) ? proxy : \"DIRECT\";\n\
+        }\n",
+    );
+    script
+}
+
+/// Address ranges of --pac-ipset, empty if not set.
+fn load_ranges() -> Result<Vec<(u32, u32)>> {
+    let args = OPTIONS.dns_args();
+    let Some(file) = &args.pac_ipset else {
+        return Ok(Vec::new());
+    };
+    let mut ipset = IPSet::with_file(file, args.pac_inverse)?;
+    ipset.aggregate();
+    Ok(ipset.ranges().collect())
+}
+
+/// Serves the PAC script generated from the routing rules and the latest blocked domain list on
+/// `addr`.
+pub fn start(addr: &str, proxy: String, domains: Arc<GeoDatabase<DomainMap>>) -> Result<()> {
+    let ranges = load_ranges()?;
+    let listener = TcpListener::bind(addr)?;
+    log::warn!("pac server listening on {}", addr);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                continue;
+            };
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+            // The request itself does not matter, every path returns the script.
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request);
+            let body = generate_pac(
+                routing::router().map(routing::Router::rules),
+                &domains.snapshot().data,
+                &ranges,
+                proxy.as_str(),
+            );
+            let _ = write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/x-ns-proxy-autoconfig\r\n\
+                Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+        }
+    });
+    Ok(())
+}
+
+mod tests {
+    #[test]
+    fn test_generate_pac() {
+        use crate::{
+            dns::{domain::DomainMap, pac::generate_pac},
+            geosite::GeoSites,
+            routing::Router,
+        };
+
+        let mut domains = DomainMap::new();
+        domains.add_domain("google.com");
+        let script = generate_pac(None, &domains, &[], "SOCKS5 127.0.0.1:1080");
+        assert!(script.starts_with("var proxy = \"SOCKS5 127.0.0.1:1080; DIRECT\";"));
+        assert!(script.contains("  \"google.com\": 1,\n"));
+        assert!(script.contains("var full = {\n};\nvar suffixes = {\n};\nvar keywords = [\n];"));
+        assert!(
+            script.contains("  var action = route(host);\n  if (action) return outbound(action);")
+        );
+        assert!(script.contains("var ranges = [\n];"));
+
+        let script = generate_pac(
+            None,
+            &domains,
+            &[(16777216, 16777471)],
+            "PROXY 127.0.0.1:8080",
+        );
+        assert!(script.contains("var ranges = [\n  [16777216, 16777471],\n];"));
+        assert!(script.contains("return ip && inRanges(ip) ? proxy : \"DIRECT\";"));
+
+        domains.add_domain("a\"b\\c.com");
+        let script = generate_pac(None, &domains, &[], "PROXY 127.0.0.1:8080");
+        assert!(script.contains("  \"a\\\"b\\\\c.com\": 1,\n"));
+
+        let mut router = Router::default();
+        let rules = "DOMAIN,login.example.com,PROXY\n\
+            DOMAIN-SUFFIX,example.com,DIRECT\n\
+            DOMAIN-KEYWORD,ads,REJECT\n";
+        router
+            .add_rules("rules.txt", rules, &mut GeoSites::new(""))
+            .unwrap();
+        let script = generate_pac(Some(router.rules()), &domains, &[], "PROXY 127.0.0.1:8080");
+        assert!(script.contains("var reject = \"PROXY 127.0.0.1:9\";"));
+        assert!(script.contains("var full = {\n  \"login.example.com\": \"PROXY\",\n};"));
+        assert!(script.contains("var suffixes = {\n  \"example.com\": \"DIRECT\",\n};"));
+        assert!(script.contains("var keywords = [\n  [\"ads\", \"REJECT\"],\n];"));
+    }
+}
@@ -4,10 +4,10 @@ use std::{
     io::{BufRead, BufReader, ErrorKind},
     net::{IpAddr, SocketAddr},
     str::FromStr,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
-use itertools::Itertools;
 use mio::{event::Event, net::UdpSocket, Interest, Poll, Token};
 use trust_dns_proto::{
     op::{Message, MessageType, Query, ResponseCode},
@@ -16,8 +16,11 @@ use trust_dns_proto::{
 };
 
 use crate::{
+    config::Outbound,
     dns::{domain::DomainMap, DNS_LOCAL, DNS_POISONED, DNS_TRUSTED},
+    geo::GeoDatabase,
     proto::MAX_PACKET_SIZE,
+    routing,
     wintun::route_add_with_if,
     OPTIONS,
 };
@@ -28,7 +31,7 @@ pub struct DnsServer {
     poisoned: UdpSocket,
     buffer: Vec<u8>,
     arp_data: Vec<u8>,
-    blocked_domains: DomainMap,
+    blocked_domains: Arc<GeoDatabase<DomainMap>>,
     store: HashMap<String, QueryResult>,
     ptr_name: String,
     trusted_addr: SocketAddr,
@@ -75,7 +78,9 @@ impl DnsServer {
             trusted: UdpSocket::bind(default_addr.as_str().parse().unwrap()).unwrap(),
             poisoned: UdpSocket::bind(default_addr.as_str().parse().unwrap()).unwrap(),
             buffer: vec![0; MAX_PACKET_SIZE],
-            blocked_domains: DomainMap::new(),
+            blocked_domains: Arc::new(GeoDatabase::new(
+                OPTIONS.dns_args().blocked_domain_list.as_str(),
+            )),
             arp_data: vec![],
             store: HashMap::new(),
             ptr_name: String::new(),
@@ -162,19 +167,14 @@ impl DnsServer {
         }
     }
 
+    pub fn blocked_domains(&self) -> Arc<GeoDatabase<DomainMap>> {
+        self.blocked_domains.clone()
+    }
+
     pub fn update_domain(&mut self) {
-        let mut domain_map = DomainMap::new();
-        let file = File::open(OPTIONS.dns_args().blocked_domain_list.as_str()).unwrap();
-        let reader = BufReader::new(file);
-        let lines: Vec<_> = reader
-            .lines()
-            .filter_map(|line| line.ok().map(|line| line.trim().to_string()))
-            .sorted()
-            .collect();
-        for line in lines {
-            domain_map.add_domain(line)
+        if let Err(err) = self.blocked_domains.reload() {
+            log::error!("reload blocked domains failed:{:?}", err);
         }
-        self.blocked_domains = domain_map;
     }
 
     pub fn setup(&mut self, poll: &Poll) {
@@ -323,7 +323,26 @@ impl DnsServer {
                             };
 
                             if renew {
-                                if self.is_blocked(&name) {
+                                // routing rules go before the blocked domain list
+                                let outbound = routing::route(&name).unwrap_or_else(|| {
+                                    if self.is_blocked(&name) {
+                                        Outbound::Proxy
+                                    } else {
+                                        Outbound::Direct
+                                    }
+                                });
+                                if outbound == Outbound::Block {
+                                    message.set_message_type(MessageType::Response);
+                                    message.set_response_code(ResponseCode::NXDomain);
+                                    if let Err(err) = self
+                                        .listener
+                                        .send_to(message.to_vec().unwrap().as_slice(), from)
+                                    {
+                                        log::error!("send response to {} failed:{}", from, err);
+                                    }
+                                    log::info!("domain:{} is rejected", name);
+                                    continue;
+                                } else if outbound == Outbound::Proxy {
                                     if let Err(err) = self.trusted.send_to(data, self.trusted_addr)
                                     {
                                         log::error!("send to trusted dns failed:{}", err);
@@ -494,7 +513,7 @@ impl DnsServer {
     }
 
     fn is_blocked(&self, name: &str) -> bool {
-        self.blocked_domains.contains(name)
+        self.blocked_domains.snapshot().data.contains(name)
     }
     fn add_request(&mut self, name: String, address: SocketAddr, id: u16) {
         let result = if let Some(result) = self.store.get_mut(&name) {
@@ -0,0 +1,444 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    spawn,
+    sync::broadcast::{self, error::RecvError, Sender},
+};
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{ErrorResponse, Request, Response},
+    http::StatusCode,
+    Message,
+};
+
+use crate::{
+    aproxy::forward,
+    config::{parse_forward, Forward, OPTIONS},
+    limiter::{DOWNLOAD, UPLOAD},
+    memory::Reservation,
+    metrics::{add, incr, CountersSnapshot, COUNTERS},
+    peer_stats::PeerStats,
+    types::Result,
+    utils::secret_eq,
+};
+
+/// Minimum interval between two traffic events of the same connection.
+const TRAFFIC_INTERVAL_MS: u64 = 1000;
+
+/// Origins of the desktop client webview, other pages in a browser are refused.
+const ALLOWED_ORIGINS: &[&str] = &[
+    "tauri://localhost",
+    "https://tauri.localhost",
+    "http://tauri.localhost",
+];
+
+lazy_static::lazy_static! {
+    static ref EVENTS: Sender<ConnEvent> = broadcast::channel(1024).0;
+    static ref START: Instant = Instant::now();
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum ConnEvent {
+    Open {
+        id: u64,
+        protocol: &'static str,
+        source: String,
+        target: String,
+        time: u64,
+    },
+    Traffic {
+        id: u64,
+        rx: u64,
+        tx: u64,
+    },
+    Close {
+        id: u64,
+        rx: u64,
+        tx: u64,
+        time: u64,
+    },
+    Stats(CountersSnapshot),
+    /// Progress of adding the routes of an ipset
+    #[cfg(target_os = "windows")]
+    Routes {
+        added: usize,
+        total: usize,
+    },
+    /// Reply to the status command, only sent to the client asking
+    Status(PeerStats),
+    /// Reply to the forward commands, with the error of the command if it failed
+    Forwards {
+        forwards: Vec<Forward>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    /// Server connections established ahead of use
+    #[cfg(target_os = "windows")]
+    Pool {
+        ready: usize,
+        size: usize,
+    },
+    /// The trojan server new connections go to changed, after the one in use went down if
+    /// `failover`
+    Server {
+        server: String,
+        failover: bool,
+    },
+}
+
+/// Commands accepted from websocket clients as json text messages, only those presenting the
+/// token get to send any.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Command {
+    /// Bandwidth limits in KB/s, 0 for unlimited, changing the limits of the whole client
+    SetLimit {
+        upload: u64,
+        download: u64,
+    },
+    /// Peer statistics shown by `trojan status`
+    Status,
+    /// Starts a port forward of aproxy or awintun, in the format of --forward
+    AddForward {
+        forward: String,
+    },
+    RemoveForward {
+        listen: String,
+        #[serde(default)]
+        udp: bool,
+    },
+    Forwards,
+}
+
+/// Runs a client command, returns the reply for this client if there is one.
+fn handle_command(text: &str) -> Option<ConnEvent> {
+    match serde_json::from_str(text) {
+        Ok(Command::SetLimit { upload, download }) => {
+            // kib/s from the client, a huge value is as good as unlimited
+            UPLOAD.set_rate(upload.saturating_mul(1024));
+            DOWNLOAD.set_rate(download.saturating_mul(1024));
+            None
+        }
+        Ok(Command::Status) => Some(ConnEvent::Status(PeerStats::collect(START.elapsed()))),
+        Ok(Command::AddForward { forward }) => {
+            let error = parse_forward(forward.as_str())
+                .and_then(|forward| forward::add(forward).map_err(|err| format!("{:?}", err)))
+                .err();
+            Some(ConnEvent::Forwards {
+                forwards: forward::list(),
+                error,
+            })
+        }
+        Ok(Command::RemoveForward { listen, udp }) => {
+            let error =
+                (!forward::remove(listen.as_str(), udp)).then(|| "no such forward".to_string());
+            Some(ConnEvent::Forwards {
+                forwards: forward::list(),
+                error,
+            })
+        }
+        Ok(Command::Forwards) => Some(ConnEvent::Forwards {
+            forwards: forward::list(),
+            error: None,
+        }),
+        Err(err) => {
+            log::error!("invalid event client command {}:{}", text, err);
+            None
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn emit(event: ConnEvent) {
+    // No subscriber is not an error, events are just dropped.
+    let _ = EVENTS.send(event);
+}
+
+#[cfg(target_os = "windows")]
+pub fn route_progress(added: usize, total: usize) {
+    emit(ConnEvent::Routes { added, total });
+}
+
+#[cfg(target_os = "windows")]
+pub fn pool_progress(ready: usize, size: usize) {
+    emit(ConnEvent::Pool { ready, size });
+}
+
+pub fn server_switched(server: String, failover: bool) {
+    emit(ConnEvent::Server { server, failover });
+}
+
+/// Per connection traffic tracker, shared by both directions of a connection.
+/// An open event is emitted on creation and a close event when dropped.
+pub struct ConnTracker {
+    id: u64,
+    rx: AtomicU64,
+    tx: AtomicU64,
+    last_report: AtomicU64,
+    /// memory of the connection, given back when it's closed
+    memory: Option<Reservation>,
+}
+
+impl ConnTracker {
+    pub fn new(protocol: &'static str, source: String, target: String) -> ConnTracker {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        incr(&COUNTERS.active_flows);
+        emit(ConnEvent::Open {
+            id,
+            protocol,
+            source,
+            target,
+            time: now(),
+        });
+        ConnTracker {
+            id,
+            rx: AtomicU64::new(0),
+            tx: AtomicU64::new(0),
+            last_report: AtomicU64::new(START.elapsed().as_millis() as u64),
+            memory: None,
+        }
+    }
+
+    pub fn holding(mut self, memory: Reservation) -> ConnTracker {
+        self.memory = Some(memory);
+        self
+    }
+
+    pub fn add_rx(&self, size: usize) {
+        self.rx.fetch_add(size as u64, Ordering::Relaxed);
+        add(&COUNTERS.rx_bytes, size as u64);
+        self.report();
+    }
+
+    pub fn add_tx(&self, size: usize) {
+        self.tx.fetch_add(size as u64, Ordering::Relaxed);
+        add(&COUNTERS.tx_bytes, size as u64);
+        self.report();
+    }
+
+    fn report(&self) {
+        if EVENTS.receiver_count() == 0 {
+            return;
+        }
+        let now = START.elapsed().as_millis() as u64;
+        let last = self.last_report.load(Ordering::Relaxed);
+        if now < last + TRAFFIC_INTERVAL_MS
+            || self
+                .last_report
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return;
+        }
+        emit(ConnEvent::Traffic {
+            id: self.id,
+            rx: self.rx.load(Ordering::Relaxed),
+            tx: self.tx.load(Ordering::Relaxed),
+        });
+    }
+}
+
+impl Drop for ConnTracker {
+    fn drop(&mut self) {
+        COUNTERS.active_flows.fetch_sub(1, Ordering::Relaxed);
+        emit(ConnEvent::Close {
+            id: self.id,
+            rx: self.rx.load(Ordering::Relaxed),
+            tx: self.tx.load(Ordering::Relaxed),
+            time: now(),
+        });
+    }
+}
+
+/// Serve connection events as json text messages to websocket clients on `addr`,
+/// clients may send back `Command`s.
+pub fn start_event_server(addr: String) {
+    // uptime reported by the status command counts from here
+    lazy_static::initialize(&START);
+    spawn(async move {
+        if let Err(err) = run_event_server(addr, OPTIONS.events_token.clone()).await {
+            log::error!("event server exit with:{:?}", err);
+        }
+    });
+}
+
+async fn run_event_server(addr: String, token: String) -> Result<()> {
+    let listener = TcpListener::bind(addr.as_str()).await?;
+    log::warn!("event server listening on {}", addr);
+    spawn(async {
+        let mut tick = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tick.tick().await;
+            if EVENTS.receiver_count() > 0 {
+                emit(ConnEvent::Stats(COUNTERS.snapshot()));
+            }
+        }
+    });
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        log::info!("event client {} connected", peer);
+        spawn(serve_client(stream, token.clone()));
+    }
+}
+
+/// Accepts the websocket clients presenting the token, in an `Authorization: Bearer` header or
+/// the query as browsers can't set headers, and of those running in a browser only the desktop
+/// client.
+fn authorize(request: &Request, token: &str) -> bool {
+    let origin_allowed = match request.headers().get("Origin") {
+        Some(origin) => ALLOWED_ORIGINS
+            .iter()
+            .any(|allowed| origin.as_bytes() == allowed.as_bytes()),
+        None => true,
+    };
+    let given = match request.headers().get("Authorization") {
+        Some(value) => value
+            .to_str()
+            .ok()
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or_default(),
+        None => request
+            .uri()
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .find_map(|param| param.strip_prefix("token="))
+            .unwrap_or_default(),
+    };
+    origin_allowed && secret_eq(given, token)
+}
+
+async fn serve_client(stream: TcpStream, token: String) {
+    // the error response type is given by tungstenite
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, response: Response| {
+        if authorize(request, token.as_str()) {
+            Ok(response)
+        } else {
+            let mut response = ErrorResponse::new(None);
+            *response.status_mut() = StatusCode::FORBIDDEN;
+            Err(response)
+        }
+    };
+    let mut ws = match tokio_tungstenite::accept_hdr_async(stream, callback).await {
+        Ok(ws) => ws,
+        Err(err) => {
+            log::error!("event client handshake failed:{}", err);
+            return;
+        }
+    };
+    let mut receiver = EVENTS.subscribe();
+    loop {
+        tokio::select! {
+            event = receiver.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(count)) => {
+                        log::warn!("event client lagged, {} events dropped", count);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                let text = serde_json::to_string(&event).unwrap();
+                if ws.send(Message::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+            msg = ws.next() => {
+                match msg {
+                    Some(Ok(Message::Text(text))) => {
+                        if let Some(reply) = handle_command(&text) {
+                            let text = serde_json::to_string(&reply).unwrap();
+                            if ws.send(Message::Text(text)).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+    log::info!("event client disconnected");
+}
+
+mod tests {
+    #[test]
+    fn test_tracker_events() {
+        use super::{ConnEvent, ConnTracker, EVENTS};
+
+        let mut receiver = EVENTS.subscribe();
+        let tracker = ConnTracker::new("tcp", "1.1.1.1:1".into(), "2.2.2.2:2".into());
+        tracker.add_rx(10);
+        tracker.add_tx(20);
+        drop(tracker);
+        let ConnEvent::Open { id, protocol, .. } = receiver.try_recv().unwrap() else {
+            panic!("open event expected");
+        };
+        assert_eq!(protocol, "tcp");
+        match receiver.try_recv().unwrap() {
+            ConnEvent::Close {
+                id: close_id,
+                rx,
+                tx,
+                ..
+            } => {
+                assert_eq!(id, close_id);
+                assert_eq!((rx, tx), (10, 20));
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_authorize() {
+        use tokio_tungstenite::tungstenite::handshake::server::Request;
+
+        use super::authorize;
+
+        let request = |uri: &str, origin: Option<&str>| {
+            let mut request = Request::builder().uri(uri);
+            if let Some(origin) = origin {
+                request = request.header("Origin", origin);
+            }
+            request.body(()).unwrap()
+        };
+        assert!(authorize(&request("/?token=secret", None), "secret"));
+        assert!(authorize(
+            &request("/?a=1&token=secret", Some("https://tauri.localhost")),
+            "secret"
+        ));
+        assert!(!authorize(&request("/", None), "secret"));
+        assert!(!authorize(&request("/?token=secre", None), "secret"));
+        assert!(!authorize(&request("/?token=", None), ""));
+        assert!(!authorize(
+            &request("/?token=secret", Some("https://example.com")),
+            "secret"
+        ));
+        let bearer = |value: &str| {
+            Request::builder()
+                .uri("/")
+                .header("Authorization", value)
+                .body(())
+                .unwrap()
+        };
+        assert!(authorize(&bearer("Bearer a&b=c d"), "a&b=c d"));
+        assert!(!authorize(&bearer("Bearer wrong"), "secret"));
+        assert!(!authorize(&bearer("secret"), "secret"));
+    }
+}
@@ -0,0 +1,207 @@
+//! Rule databases (domain lists, GeoIP CIDR lists) which can be reloaded at runtime.
+//!
+//! A database file is a plain text file with one rule per line, lines starting with `#` are
+//! comments. An optional `# version: xxx` header gives the version reported after loading,
+//! otherwise the modification time of the file is used.
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    net::IpAddr,
+    sync::{Arc, RwLock},
+    time::UNIX_EPOCH,
+};
+
+use crate::types::Result;
+
+const VERSION_PREFIX: &str = "# version:";
+
+/// Data parsed from a rule database file.
+pub trait GeoData: Default {
+    fn add_line(&mut self, line: &str);
+
+    /// Called once all lines are added.
+    fn finish(&mut self) {}
+}
+
+/// GeoIP data, lines like `1.0.1.0/24`, `2001:250::/35` or a single ip.
+#[derive(Default)]
+pub struct GeoIp {
+    /// Sorted and merged ranges of ipv6 addresses, ipv4 ones are mapped into `::ffff:0:0/96`.
+    ranges: Vec<(u128, u128)>,
+}
+
+impl GeoIp {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = to_u128(ip);
+        let index = self.ranges.partition_point(|(low, _)| *low <= ip);
+        index > 0 && self.ranges[index - 1].1 >= ip
+    }
+}
+
+fn to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+impl GeoData for GeoIp {
+    fn add_line(&mut self, line: &str) {
+        let (ip, prefix) = match line.split_once('/') {
+            Some((ip, prefix)) => (ip, prefix.parse().ok()),
+            None => (line, None),
+        };
+        let Ok(ip) = ip.parse::<IpAddr>() else {
+            log::error!("invalid geoip line:{}", line);
+            return;
+        };
+        let bits = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) if prefix <= bits => prefix,
+            None => bits,
+            Some(_) => {
+                log::error!("invalid geoip line:{}", line);
+                return;
+            }
+        };
+        let network = 128 - bits + prefix;
+        let mask = u128::MAX.checked_shr(network).unwrap_or(0);
+        let low = to_u128(ip) & !mask;
+        self.ranges.push((low, low | mask));
+    }
+
+    fn finish(&mut self) {
+        self.ranges.sort_unstable();
+        let mut merged: Vec<(u128, u128)> = Vec::with_capacity(self.ranges.len());
+        for (low, high) in self.ranges.drain(..) {
+            match merged.last_mut() {
+                Some(last) if low <= last.1.saturating_add(1) => last.1 = last.1.max(high),
+                _ => merged.push((low, high)),
+            }
+        }
+        self.ranges = merged;
+    }
+}
+
+pub struct GeoSnapshot<T> {
+    pub version: String,
+    pub data: T,
+}
+
+pub struct GeoDatabase<T> {
+    path: String,
+    current: RwLock<Arc<GeoSnapshot<T>>>,
+}
+
+impl<T: GeoData> GeoDatabase<T> {
+    pub fn new(path: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            current: RwLock::new(Arc::new(GeoSnapshot {
+                version: String::new(),
+                data: T::default(),
+            })),
+        }
+    }
+
+    /// Parses the database file and swaps it in, returns the new version.
+    /// The current data stays untouched if the file could not be read.
+    pub fn reload(&self) -> Result<String> {
+        let file = File::open(self.path.as_str())?;
+        let mut version = file
+            .metadata()?
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs().to_string())
+            .unwrap_or_default();
+        let mut data = T::default();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            let line = line.trim();
+            if let Some(v) = line.strip_prefix(VERSION_PREFIX) {
+                version = v.trim().to_string();
+            } else if !line.is_empty() && !line.starts_with('#') {
+                data.add_line(line);
+            }
+        }
+        data.finish();
+        let snapshot = Arc::new(GeoSnapshot {
+            version: version.clone(),
+            data,
+        });
+        match self.current.write() {
+            Ok(mut current) => *current = snapshot,
+            Err(err) => log::error!("swap database {} failed:{}", self.path, err),
+        }
+        log::warn!("database {} loaded with version:{}", self.path, version);
+        Ok(version)
+    }
+
+    /// Returns the current data, which stays valid even if a reload happens meanwhile.
+    pub fn snapshot(&self) -> Arc<GeoSnapshot<T>> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+mod tests {
+    #![allow(unused_imports, dead_code)]
+
+    use std::{fs::File, io::Write};
+
+    use crate::geo::{GeoData, GeoDatabase};
+
+    #[derive(Default)]
+    struct Lines(Vec<String>);
+
+    impl GeoData for Lines {
+        fn add_line(&mut self, line: &str) {
+            self.0.push(line.to_string());
+        }
+    }
+
+    #[test]
+    fn test_reload() {
+        let path = std::env::temp_dir().join("trojan_geo_test.txt");
+        let mut file = File::create(&path).unwrap();
+        write!(
+            file,
+            "# version: 20240101\n# comment\n\ngoogle.com\nyoutube.com\n"
+        )
+        .unwrap();
+        let database: GeoDatabase<Lines> = GeoDatabase::new(path.to_str().unwrap());
+        let old = database.snapshot();
+        assert_eq!(database.reload().unwrap(), "20240101");
+        assert!(old.data.0.is_empty());
+        assert_eq!(
+            database.snapshot().data.0,
+            vec!["google.com", "youtube.com"]
+        );
+        assert_eq!(database.snapshot().version, "20240101");
+    }
+
+    #[test]
+    fn test_geoip() {
+        use crate::geo::GeoIp;
+
+        let mut geoip = GeoIp::default();
+        for line in [
+            "1.0.1.0/24",
+            "1.0.2.0/23",
+            "10.0.0.1",
+            "2001:250::/35",
+            "bad/8",
+        ] {
+            geoip.add_line(line);
+        }
+        geoip.finish();
+        assert!(geoip.contains("1.0.1.0".parse().unwrap()));
+        assert!(geoip.contains("1.0.3.255".parse().unwrap()));
+        assert!(!geoip.contains("1.0.4.0".parse().unwrap()));
+        assert!(!geoip.contains("1.0.0.255".parse().unwrap()));
+        assert!(geoip.contains("10.0.0.1".parse().unwrap()));
+        assert!(!geoip.contains("10.0.0.2".parse().unwrap()));
+        assert!(geoip.contains("2001:250:1fff::1".parse().unwrap()));
+        assert!(!geoip.contains("2001:250:2000::".parse().unwrap()));
+        assert!(!geoip.contains("::1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,259 @@
+//! Reader of the v2ray geosite.dat, a protobuf GeoSiteList holding the domains of categories like
+//! `cn` or `geolocation-!cn`. A category is narrowed to the domains carrying attributes with
+//! `@`, like `google@cn`. The file is only read when a category is first asked for.
+
+use std::{collections::HashMap, fs};
+
+use crate::types::{Result, TrojanError};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DomainKind {
+    /// Domains containing the value.
+    Keyword,
+    /// Domains matching the regular expression, unsupported.
+    Regex,
+    /// The domain and its subdomains.
+    Suffix,
+    /// The domain itself.
+    Full,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SiteDomain {
+    pub kind: DomainKind,
+    pub value: String,
+    attributes: Vec<String>,
+}
+
+pub struct GeoSites {
+    path: String,
+    sites: Option<HashMap<String, Vec<SiteDomain>>>,
+}
+
+impl GeoSites {
+    pub fn new(path: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            sites: None,
+        }
+    }
+
+    /// Domains of a category like `cn` or `google@cn`.
+    pub fn domains(&mut self, name: &str) -> Result<Vec<&SiteDomain>> {
+        if self.sites.is_none() {
+            let data = fs::read(self.path.as_str()).map_err(|err| {
+                log::error!("read geosite file {} failed:{}", self.path, err);
+                err
+            })?;
+            let sites = parse_sites(data.as_slice()).ok_or_else(|| {
+                log::error!("invalid geosite file {}", self.path);
+                TrojanError::GeoSite("invalid geosite file")
+            })?;
+            self.sites.replace(sites);
+        }
+        let mut parts = name.split('@');
+        let code = parts.next().unwrap_or_default().to_ascii_lowercase();
+        let attributes: Vec<_> = parts.map(str::to_ascii_lowercase).collect();
+        let domains = self
+            .sites
+            .as_ref()
+            .and_then(|sites| sites.get(code.as_str()))
+            .ok_or_else(|| {
+                log::error!("geosite category {} not found in {}", code, self.path);
+                TrojanError::GeoSite("unknown geosite category")
+            })?;
+        Ok(domains
+            .iter()
+            .filter(|domain| {
+                attributes
+                    .iter()
+                    .all(|attribute| domain.attributes.contains(attribute))
+            })
+            .collect())
+    }
+}
+
+/// Fields of a protobuf message, varints and length delimited ones.
+struct Fields<'a>(&'a [u8]);
+
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+impl<'a> Fields<'a> {
+    fn varint(&mut self) -> Option<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let (byte, rest) = self.0.split_first()?;
+            self.0 = rest;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn skip(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.0.len() {
+            return None;
+        }
+        let (data, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(data)
+    }
+
+    fn field(&mut self) -> Option<(u64, Value<'a>)> {
+        let tag = self.varint()?;
+        let value = match tag & 0x7 {
+            0 => Value::Varint(self.varint()?),
+            1 => Value::Bytes(self.skip(8)?),
+            2 => {
+                let len = self.varint()? as usize;
+                Value::Bytes(self.skip(len)?)
+            }
+            5 => Value::Bytes(self.skip(4)?),
+            _ => return None,
+        };
+        Some((tag >> 3, value))
+    }
+
+    /// The next field, None at the end, Some(None) if the message is broken.
+    fn next_field(&mut self) -> Option<Option<(u64, Value<'a>)>> {
+        if self.0.is_empty() {
+            None
+        } else {
+            Some(self.field())
+        }
+    }
+}
+
+fn parse_string(data: &[u8]) -> Option<String> {
+    String::from_utf8(data.to_vec()).ok()
+}
+
+fn parse_sites(data: &[u8]) -> Option<HashMap<String, Vec<SiteDomain>>> {
+    let mut sites = HashMap::new();
+    let mut fields = Fields(data);
+    while let Some(field) = fields.next_field() {
+        if let (1, Value::Bytes(site)) = field? {
+            let (code, domains) = parse_site(site)?;
+            sites.insert(code.to_ascii_lowercase(), domains);
+        }
+    }
+    Some(sites)
+}
+
+fn parse_site(data: &[u8]) -> Option<(String, Vec<SiteDomain>)> {
+    let mut code = String::new();
+    let mut domains = Vec::new();
+    let mut fields = Fields(data);
+    while let Some(field) = fields.next_field() {
+        match field? {
+            (1, Value::Bytes(value)) => code = parse_string(value)?,
+            (2, Value::Bytes(domain)) => domains.push(parse_domain(domain)?),
+            _ => {}
+        }
+    }
+    Some((code, domains))
+}
+
+fn parse_domain(data: &[u8]) -> Option<SiteDomain> {
+    let mut domain = SiteDomain {
+        kind: DomainKind::Keyword,
+        value: String::new(),
+        attributes: Vec::new(),
+    };
+    let mut fields = Fields(data);
+    while let Some(field) = fields.next_field() {
+        match field? {
+            (1, Value::Varint(kind)) => {
+                domain.kind = match kind {
+                    0 => DomainKind::Keyword,
+                    1 => DomainKind::Regex,
+                    2 => DomainKind::Suffix,
+                    3 => DomainKind::Full,
+                    _ => return None,
+                }
+            }
+            (2, Value::Bytes(value)) => domain.value = parse_string(value)?.to_ascii_lowercase(),
+            (3, Value::Bytes(attribute)) => {
+                let mut fields = Fields(attribute);
+                while let Some(field) = fields.next_field() {
+                    if let (1, Value::Bytes(key)) = field? {
+                        domain
+                            .attributes
+                            .push(parse_string(key)?.to_ascii_lowercase());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(domain)
+}
+
+mod tests {
+    #[test]
+    fn test_geosite() {
+        use std::io::Write;
+
+        use super::{DomainKind, GeoSites};
+
+        fn field(tag: u8, data: &[u8]) -> Vec<u8> {
+            let mut field = vec![tag << 3 | 2, data.len() as u8];
+            field.extend_from_slice(data);
+            field
+        }
+        fn domain(kind: u8, value: &str, attributes: &[&str]) -> Vec<u8> {
+            let mut domain = vec![1 << 3, kind];
+            domain.extend(field(2, value.as_bytes()));
+            for attribute in attributes {
+                let mut typed = field(1, attribute.as_bytes());
+                typed.extend([2 << 3, 1]);
+                domain.extend(field(3, &typed));
+            }
+            domain
+        }
+
+        let mut google = field(1, b"GOOGLE");
+        google.extend(field(2, &domain(2, "google.com", &[])));
+        google.extend(field(2, &domain(2, "google.cn", &["cn"])));
+        google.extend(field(2, &domain(3, "www.Google.cn", &["cn", "ads"])));
+        let mut other = field(1, b"geolocation-!cn");
+        other.extend(field(2, &domain(0, "youtube", &[])));
+        other.extend(field(2, &domain(1, "^ads\\.", &[])));
+        let mut data = field(1, &google);
+        data.extend(field(1, &other));
+
+        let path = std::env::temp_dir().join("trojan_geosite_test.dat");
+        std::fs::File::create(&path)
+            .unwrap()
+            .write_all(&data)
+            .unwrap();
+        let mut sites = GeoSites::new(path.to_str().unwrap());
+        let values = |domains: Vec<&super::SiteDomain>| -> Vec<(DomainKind, String)> {
+            domains
+                .into_iter()
+                .map(|domain| (domain.kind, domain.value.clone()))
+                .collect()
+        };
+        assert_eq!(sites.domains("google").unwrap().len(), 3);
+        assert_eq!(
+            values(sites.domains("google@cn@ads").unwrap()),
+            [(DomainKind::Full, "www.google.cn".to_string())]
+        );
+        assert_eq!(
+            values(sites.domains("Geolocation-!CN").unwrap()),
+            [
+                (DomainKind::Keyword, "youtube".to_string()),
+                (DomainKind::Regex, "^ads\\.".to_string())
+            ]
+        );
+        assert!(sites.domains("cn").is_err());
+
+        std::fs::write(&path, [0x0a, 0x05, 0x0a]).unwrap();
+        assert!(GeoSites::new(path.to_str().unwrap()).domains("cn").is_err());
+    }
+}
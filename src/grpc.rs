@@ -0,0 +1,433 @@
+//! Trojan streams carried by gRPC, compatible with the `gun` transport of v2ray and Xray.
+//!
+//! Every stream is a call of `/<service>/<method>` on a shared HTTP/2 connection, the data is
+//! sent as protobuf `Hunk { bytes data = 1; }` messages, each after the 5 bytes gRPC prefix:
+//!
+//! `compressed(u8) | length(u32 be) | 0x0a | varint length | data`
+
+use std::{
+    future::Future,
+    io,
+    io::ErrorKind,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use h2::{client::ResponseFuture, RecvStream, SendStream};
+use http::{header::CONTENT_TYPE, HeaderMap, Method, Request, Response, StatusCode};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    spawn,
+};
+use tokio_rustls::client::TlsStream;
+
+use crate::{config::OPTIONS, types::Result};
+
+const GRPC_CONTENT_TYPE: &str = "application/grpc";
+const PREFIX_LEN: usize = 5;
+/// protobuf tag of `Hunk.data`, field 1 with length delimited wire type
+const HUNK_TAG: u8 = 0x0a;
+/// Largest data size of a message.
+const MAX_HUNK: usize = 16384;
+/// Largest message accepted, a hunk with its tag and length. Calls are read before the trojan
+/// request is authenticated, so larger ones are refused rather than buffered.
+const MAX_MESSAGE: usize = MAX_HUNK + 16;
+/// HTTP/2 flow control windows, the defaults of 64KB limit a stream to a few MB/s.
+const STREAM_WINDOW: u32 = 1 << 20;
+const CONNECTION_WINDOW: u32 = 1 << 24;
+
+type SendRequest = h2::client::SendRequest<Bytes>;
+
+lazy_static::lazy_static! {
+    static ref CLIENT: tokio::sync::Mutex<Option<SendRequest>> = Default::default();
+}
+
+/// Path of the gRPC method carrying the streams.
+pub fn path() -> String {
+    format!(
+        "/{}/{}",
+        OPTIONS.grpc_service.as_deref().unwrap_or_default(),
+        OPTIONS.grpc_method
+    )
+}
+
+fn to_io(err: h2::Error) -> io::Error {
+    if err.is_io() {
+        err.into_io().unwrap()
+    } else {
+        io::Error::other(err)
+    }
+}
+
+fn varint_len(mut value: usize) -> usize {
+    let mut len = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        len += 1;
+    }
+    len
+}
+
+fn encode(data: &[u8]) -> Bytes {
+    let len = 1 + varint_len(data.len()) + data.len();
+    let mut buffer = BytesMut::with_capacity(PREFIX_LEN + len);
+    buffer.put_u8(0);
+    buffer.put_u32(len as u32);
+    buffer.put_u8(HUNK_TAG);
+    let mut value = data.len();
+    while value >= 0x80 {
+        buffer.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.put_u8(value as u8);
+    buffer.extend_from_slice(data);
+    buffer.freeze()
+}
+
+fn read_varint(message: &mut BytesMut) -> io::Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        if message.is_empty() {
+            break;
+        }
+        let byte = message.get_u8();
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(
+        ErrorKind::InvalidData,
+        "invalid grpc varint",
+    ))
+}
+
+/// Moves the data of the complete messages at the front of `pending` into `data`.
+fn decode(pending: &mut BytesMut, data: &mut BytesMut) -> io::Result<()> {
+    while pending.len() >= PREFIX_LEN {
+        let len = u32::from_be_bytes([pending[1], pending[2], pending[3], pending[4]]) as usize;
+        if len > MAX_MESSAGE {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "grpc message too large",
+            ));
+        }
+        if pending.len() < PREFIX_LEN + len {
+            break;
+        }
+        if pending[0] != 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "compressed grpc message",
+            ));
+        }
+        let mut message = pending.split_to(PREFIX_LEN + len);
+        message.advance(PREFIX_LEN);
+        // the multi mode of Xray repeats field 1, other fields are skipped
+        while !message.is_empty() {
+            let tag = read_varint(&mut message)?;
+            match tag & 0x7 {
+                0 => {
+                    read_varint(&mut message)?;
+                }
+                2 => {
+                    let len = read_varint(&mut message)? as usize;
+                    if len > message.len() {
+                        return Err(io::Error::new(ErrorKind::InvalidData, "invalid grpc field"));
+                    }
+                    let field = message.split_to(len);
+                    if tag >> 3 == 1 {
+                        data.extend_from_slice(field.as_ref());
+                    }
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "invalid grpc wire type",
+                    ))
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+enum Receiving {
+    Response(ResponseFuture),
+    Body(RecvStream),
+}
+
+/// One gRPC call used as a byte stream.
+pub struct GrpcStream {
+    send: SendStream<Bytes>,
+    recv: Receiving,
+    /// messages not completely received yet
+    pending: BytesMut,
+    /// data decoded but not read yet
+    data: BytesMut,
+    /// bytes received whose flow control capacity is not released yet
+    unreleased: usize,
+    server: bool,
+    closed: bool,
+}
+
+impl GrpcStream {
+    fn new(send: SendStream<Bytes>, recv: Receiving, server: bool) -> Self {
+        Self {
+            send,
+            recv,
+            pending: BytesMut::new(),
+            data: BytesMut::new(),
+            unreleased: 0,
+            server,
+            closed: false,
+        }
+    }
+
+    /// Gives the peer back the window of the messages read out, so it can't send more than
+    /// the window ahead of the reader. A partial message keeps holding its part.
+    fn release(&mut self) {
+        let Receiving::Body(body) = &mut self.recv else {
+            return;
+        };
+        let consumed = self.unreleased - self.pending.len();
+        if consumed > 0 {
+            let _ = body.flow_control().release_capacity(consumed);
+            self.unreleased -= consumed;
+        }
+    }
+}
+
+impl AsyncRead for GrpcStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.data.is_empty() {
+                let n = this.data.len().min(buf.remaining());
+                buf.put_slice(&this.data[..n]);
+                this.data.advance(n);
+                if this.data.is_empty() {
+                    this.release();
+                }
+                return Poll::Ready(Ok(()));
+            }
+            let body = match &mut this.recv {
+                Receiving::Response(response) => {
+                    let response = ready!(Pin::new(response).poll(cx)).map_err(to_io)?;
+                    if response.status() != StatusCode::OK {
+                        let err = format!("grpc call failed with status {}", response.status());
+                        return Poll::Ready(Err(io::Error::other(err)));
+                    }
+                    this.recv = Receiving::Body(response.into_body());
+                    continue;
+                }
+                Receiving::Body(body) => body,
+            };
+            match ready!(body.poll_data(cx)) {
+                Some(Ok(chunk)) => {
+                    this.unreleased += chunk.len();
+                    this.pending.extend_from_slice(chunk.as_ref());
+                    decode(&mut this.pending, &mut this.data)?;
+                    // messages without data are used up right away
+                    if this.data.is_empty() {
+                        this.release();
+                    }
+                }
+                Some(Err(err)) => return Poll::Ready(Err(to_io(err))),
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for GrpcStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let len = buf.len().min(MAX_HUNK);
+        // waits for the peer's window, at most one message is buffered beyond it
+        this.send.reserve_capacity(PREFIX_LEN + 4 + len);
+        while this.send.capacity() == 0 {
+            match ready!(this.send.poll_capacity(cx)) {
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Poll::Ready(Err(to_io(err))),
+                None => return Poll::Ready(Err(ErrorKind::BrokenPipe.into())),
+            }
+        }
+        this.send
+            .send_data(encode(&buf[..len]), false)
+            .map_err(to_io)?;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.closed {
+            return Poll::Ready(Ok(()));
+        }
+        this.closed = true;
+        let ret = if this.server {
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", "0".parse().unwrap());
+            this.send.send_trailers(trailers)
+        } else {
+            this.send.send_data(Bytes::new(), true)
+        };
+        Poll::Ready(ret.map_err(to_io))
+    }
+}
+
+async fn connect<S>(conn: S) -> Result<SendRequest>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (client, connection) = h2::client::Builder::new()
+        .initial_window_size(STREAM_WINDOW)
+        .initial_connection_window_size(CONNECTION_WINDOW)
+        .handshake(conn)
+        .await?;
+    spawn(async move {
+        if let Err(err) = connection.await {
+            log::error!("grpc connection failed:{}", err);
+        }
+    });
+    Ok(client)
+}
+
+fn call(client: &mut SendRequest, authority: &str, path: &str) -> Result<GrpcStream> {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(format!("https://{}{}", authority, path))
+        .header(CONTENT_TYPE, GRPC_CONTENT_TYPE)
+        .header("te", "trailers")
+        .body(())?;
+    let (response, send) = client.send_request(request, false)?;
+    Ok(GrpcStream::new(send, Receiving::Response(response), false))
+}
+
+/// Opens a stream as a call on the shared server connection, a new one is made with `connect`
+/// when there is none yet or it is closed. `authority` is the host the calls are sent to.
+pub async fn open_stream<F>(connect_server: F, authority: &str) -> Result<GrpcStream>
+where
+    F: Future<Output = Result<TlsStream<TcpStream>>>,
+{
+    let mut current = CLIENT.lock().await;
+    let client = match current.take() {
+        Some(client) => client.ready().await.ok(),
+        None => None,
+    };
+    let mut client = match client {
+        Some(client) => client,
+        None => {
+            let client = connect(connect_server.await?).await?;
+            log::info!("new grpc connection to server");
+            client
+        }
+    };
+    let stream = call(&mut client, authority, path().as_str())?;
+    current.replace(client);
+    Ok(stream)
+}
+
+/// Serves the calls on `conn`, those of `path` are passed to `handler`, others are refused.
+pub async fn serve<S, F, R>(conn: S, path: &str, handler: F) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: Fn(GrpcStream) -> R,
+    R: Future<Output = ()> + Send + 'static,
+{
+    let mut connection = h2::server::Builder::new()
+        .initial_window_size(STREAM_WINDOW)
+        .initial_connection_window_size(CONNECTION_WINDOW)
+        .handshake(conn)
+        .await?;
+    while let Some(ret) = connection.accept().await {
+        let (request, mut respond) = ret?;
+        if request.uri().path() != path {
+            log::error!("invalid grpc call:{}", request.uri().path());
+            let response = Response::builder().status(StatusCode::NOT_FOUND).body(())?;
+            let _ = respond.send_response(response, true);
+            continue;
+        }
+        let response = Response::builder()
+            .header(CONTENT_TYPE, GRPC_CONTENT_TYPE)
+            .body(())?;
+        let send = respond.send_response(response, false)?;
+        spawn(handler(GrpcStream::new(
+            send,
+            Receiving::Body(request.into_body()),
+            true,
+        )));
+    }
+    Ok(())
+}
+
+mod tests {
+    #[test]
+    fn test_decode() {
+        use bytes::{BufMut, BytesMut};
+
+        use crate::grpc::{decode, encode, MAX_HUNK, MAX_MESSAGE};
+
+        let mut pending = BytesMut::from(encode(&[7u8; MAX_HUNK]).as_ref());
+        pending.extend_from_slice(&encode(b"abc")[..4]);
+        let mut data = BytesMut::new();
+        decode(&mut pending, &mut data).unwrap();
+        assert_eq!(data.len(), MAX_HUNK);
+        assert_eq!(pending.len(), 4);
+
+        let mut pending = BytesMut::new();
+        pending.put_u8(0);
+        pending.put_u32(MAX_MESSAGE as u32 + 1);
+        assert!(decode(&mut pending, &mut data).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_grpc_stream() {
+        use tokio::{
+            io::{duplex, split, AsyncReadExt, AsyncWriteExt},
+            spawn,
+        };
+
+        use crate::grpc::{call, connect, serve};
+
+        let (client, server) = duplex(65536);
+        spawn(async move {
+            serve(server, "/GunService/Tun", |stream| async move {
+                let (mut read, mut write) = split(stream);
+                let _ = tokio::io::copy(&mut read, &mut write).await;
+                let _ = write.shutdown().await;
+            })
+            .await
+        });
+        let mut client = connect(client).await.unwrap();
+
+        let mut refused = call(&mut client, "localhost", "/GunService/Other").unwrap();
+        let mut data = [0u8; 5];
+        assert!(refused.read(&mut data).await.is_err());
+
+        let mut stream = call(&mut client, "localhost", "/GunService/Tun").unwrap();
+        let payload: Vec<u8> = (0..100000).map(|i| i as u8).collect();
+        stream.write_all(payload.as_slice()).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut echo = Vec::new();
+        stream.read_to_end(&mut echo).await.unwrap();
+        assert_eq!(echo, payload);
+    }
+}
@@ -9,8 +9,8 @@ use rustls::{ClientConfig, ClientConnection, Connection};
 use rustls_pki_types::ServerName;
 
 use crate::{
-    config::OPTIONS, resolver::DnsResolver, status::StatusProvider, tls_conn::TlsConn,
-    types::Result,
+    backoff::Backoff, config::OPTIONS, nat64, resolver::DnsResolver, status::StatusProvider, sys,
+    tls_conn::TlsConn, types::Result,
 };
 
 pub struct IdlePool {
@@ -26,6 +26,8 @@ pub struct IdlePool {
     channel_idle: usize,
     min_index: usize,
     max_index: usize,
+    mark: Option<u32>,
+    backoff: Backoff,
 }
 
 impl IdlePool {
@@ -49,9 +51,16 @@ impl IdlePool {
             addr: OPTIONS.back_addr.unwrap(),
             pool: Vec::new(),
             next_index: 0,
+            mark: None,
+            backoff: Backoff::default(),
         }
     }
 
+    /// Sets the firewall mark of the connections made afterwards.
+    pub fn set_mark(&mut self, mark: Option<u32>) {
+        self.mark = mark;
+    }
+
     pub fn init_index(
         &mut self,
         channel_cnt: usize,
@@ -110,10 +119,18 @@ impl IdlePool {
     }
 
     fn new_conn(&mut self) -> Result<TlsConn> {
-        let server = TcpStream::connect(self.addr)?;
-        //sys::set_mark(&server, self.marker)?;
+        let server = TcpStream::from_std(sys::connect_nonblocking(
+            self.addr,
+            self.mark,
+            OPTIONS.mptcp,
+        )?);
         #[cfg(not(target_os = "windows"))]
         server.set_nodelay(true)?;
+        if let Some(dscp) = OPTIONS.dscp {
+            if let Err(err) = sys::set_dscp(&server, self.addr.is_ipv4(), dscp) {
+                log::warn!("set dscp failed:{}", err);
+            }
+        }
 
         let mut session = ClientConnection::new(self.config.clone(), self.hostname.clone())?;
         session.set_buffer_limit(Some(4096));
@@ -134,8 +151,7 @@ impl IdlePool {
     pub fn resolve(&mut self, ip: Option<IpAddr>) {
         if let Some(address) = ip {
             log::debug!("idle_pool got resolve result {} = {}", self.domain, address);
-            let addr = SocketAddr::new(address, self.port);
-            self.addr = addr;
+            self.addr = nat64::translate(SocketAddr::new(address, self.port));
         } else {
             log::error!("idle_pool resolve host:{} failed", self.domain);
         }
@@ -157,13 +173,25 @@ impl IdlePool {
             conn.check_status(poll);
             if conn.deregistered() {
                 self.pool.swap_remove(index);
+                self.backoff.failed();
+            } else if event.is_writable() && !event.is_error() {
+                self.backoff.succeeded();
             }
         } else {
             log::error!("idle token:{} not found", event.token().0);
         }
     }
 
-    pub fn check_timeout(&mut self, poll: &Poll) {
+    /// Drops the cached connections, they are made again through the current network.
+    #[cfg(target_os = "windows")]
+    pub fn flush(&mut self, poll: &Poll, resolver: &DnsResolver) {
+        log::warn!("{} idle connections dropped", self.pool.len());
+        self.pool.clear();
+        self.init(poll, resolver);
+    }
+
+    /// Closes the broken connections and makes new ones in their place once the backoff allows.
+    pub fn check_timeout(&mut self, poll: &Poll, resolver: &DnsResolver) {
         let mut closed: Vec<_> = self
             .pool
             .iter_mut()
@@ -184,5 +212,11 @@ impl IdlePool {
         for index in closed.iter().rev() {
             self.pool.swap_remove(*index);
         }
+        if !closed.is_empty() {
+            self.backoff.failed();
+        }
+        if self.backoff.ready() {
+            self.init(poll, resolver);
+        }
     }
 }
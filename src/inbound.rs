@@ -0,0 +1,304 @@
+//! SOCKS5 and HTTP proxy handshakes shared by the inbound listeners of the proxy and aproxy
+//! modes. The parsers take what a client sent so far and return None until a message is
+//! complete, so the blocking and the async listener read the same way they always did.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::{BufMut, BytesMut};
+use smoltcp::wire::IpAddress;
+
+use crate::{
+    aproxy::http_proxy::to_address,
+    config::OPTIONS,
+    proto::{parse_address, AddressParseResult, Sock5Address, MAX_PACKET_SIZE},
+    types::{Result, TrojanError},
+    utils::secret_eq,
+};
+
+pub const SOCKS_VERSION: u8 = 5;
+pub const SOCKS4_VERSION: u8 = 4;
+pub const NO_AUTH: u8 = 0;
+pub const USER_PASS_AUTH: u8 = 2;
+pub const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+pub const CMD_CONNECT: u8 = 1;
+pub const CMD_UDP_ASSOCIATE: u8 = 3;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Protocol of a client of the mixed inbound port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sniffed {
+    Socks4,
+    Socks5,
+    Http,
+}
+
+/// Tells the protocol of a client by the first byte it sends, SOCKS starts with its version and
+/// http with the method name. Anything else, like a TLS ClientHello, is None.
+pub fn sniff(first: u8) -> Option<Sniffed> {
+    match first {
+        SOCKS_VERSION => Some(Sniffed::Socks5),
+        SOCKS4_VERSION => Some(Sniffed::Socks4),
+        first if first.is_ascii_alphabetic() => Some(Sniffed::Http),
+        _ => None,
+    }
+}
+
+/// A complete http proxy request head.
+pub struct HttpRequest {
+    pub size: usize,
+    /// target of a CONNECT, None for the other methods
+    pub connect: Option<Sock5Address>,
+    pub credentials: Option<(String, String)>,
+}
+
+pub fn allowed(ip: IpAddr) -> bool {
+    let allow = &OPTIONS.proxy_args().inbound_allow;
+    allow.is_empty()
+        || allow
+            .iter()
+            .any(|cidr| cidr.contains_addr(&IpAddress::from(ip)))
+}
+
+/// Whether the user and password are one of --inbound-auth. Every entry is compared in constant
+/// time, so the timing tells neither the password nor which users exist.
+pub fn accepted(user: &str, pass: &str) -> bool {
+    OPTIONS
+        .proxy_args()
+        .inbound_auth
+        .iter()
+        .fold(false, |found, auth| {
+            let (u, p) = auth.split_once(':').unwrap_or_default();
+            found | (secret_eq(user, u) & secret_eq(pass, p))
+        })
+}
+
+/// The socks5 method the listener asks for.
+pub fn auth_method() -> u8 {
+    if OPTIONS.proxy_args().inbound_auth.is_empty() {
+        NO_AUTH
+    } else {
+        USER_PASS_AUTH
+    }
+}
+
+/// Version, method count and methods, with their length.
+pub fn parse_greeting(buffer: &[u8]) -> Result<Option<(usize, &[u8])>> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+    if buffer[0] != SOCKS_VERSION {
+        return Err(TrojanError::Inbound("invalid socks5 version"));
+    }
+    let size = 2 + buffer[1] as usize;
+    Ok(buffer.get(2..size).map(|methods| (size, methods)))
+}
+
+/// RFC1929 version byte followed by the length prefixed username and password.
+pub fn parse_auth(buffer: &[u8]) -> Option<(usize, String, String)> {
+    let user_len = *buffer.get(1)? as usize;
+    let pass_len = *buffer.get(2 + user_len)? as usize;
+    let size = 3 + user_len + pass_len;
+    let pass = buffer.get(3 + user_len..size)?;
+    Some((
+        size,
+        String::from_utf8_lossy(&buffer[2..2 + user_len]).into(),
+        String::from_utf8_lossy(pass).into(),
+    ))
+}
+
+/// Version, command, reserved byte and the address.
+pub fn parse_request(buffer: &[u8]) -> Result<Option<(usize, u8, Sock5Address)>> {
+    if buffer.len() < 5 {
+        return Ok(None);
+    }
+    match parse_address(buffer[3], &buffer[4..]) {
+        AddressParseResult::Address((size, address)) => Ok(Some((4 + size, buffer[1], address))),
+        AddressParseResult::Continue => Ok(None),
+        AddressParseResult::InvalidProtocol => {
+            Err(TrojanError::Inbound("invalid socks5 address type"))
+        }
+    }
+}
+
+/// An http proxy request at the start of `buffer`, None until its head is complete.
+pub fn parse_http(buffer: &[u8]) -> Result<Option<HttpRequest>> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut request = httparse::Request::new(&mut headers);
+    let size = match request.parse(buffer) {
+        Ok(httparse::Status::Complete(size)) => size,
+        Ok(httparse::Status::Partial) => return Ok(None),
+        Err(_) => return Err(TrojanError::Inbound("invalid http request")),
+    };
+    let credentials = request
+        .headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("Proxy-Authorization"))
+        .and_then(|header| std::str::from_utf8(header.value).ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|value| STANDARD.decode(value.trim()).ok())
+        .and_then(|value| String::from_utf8(value).ok())
+        .and_then(|value| {
+            value
+                .split_once(':')
+                .map(|(user, pass)| (user.to_string(), pass.to_string()))
+        });
+    if request.method != Some("CONNECT") {
+        return Ok(Some(HttpRequest {
+            size,
+            connect: None,
+            credentials,
+        }));
+    }
+    let (host, port) = request
+        .path
+        .and_then(|target| target.rsplit_once(':'))
+        .map(|(host, port)| (host.trim_start_matches('[').trim_end_matches(']'), port))
+        .filter(|(host, _)| !host.is_empty() && host.len() <= 255)
+        .ok_or(TrojanError::Inbound("invalid http CONNECT target"))?;
+    Ok(Some(HttpRequest {
+        size,
+        connect: Some(to_address(host.to_string(), port.parse()?)),
+        credentials,
+    }))
+}
+
+/// Socks5 reply with the bound address, zeros if there is none.
+pub fn reply(code: u8, bound: Option<SocketAddr>) -> BytesMut {
+    let mut buffer = BytesMut::new();
+    buffer.put_slice(&[SOCKS_VERSION, code, 0]);
+    Sock5Address::generate(
+        &mut buffer,
+        &bound.unwrap_or_else(|| SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)),
+    );
+    buffer
+}
+
+/// Turns a socks5 UDP request datagram into a trojan UDP frame, returns the target port, None if
+/// it is invalid or fragmented, which isn't supported.
+pub fn to_trojan_frame(datagram: &[u8], frame: &mut BytesMut) -> Option<u16> {
+    if datagram.len() < 4 || datagram[2] != 0 {
+        return None;
+    }
+    let address_len = match datagram[3] {
+        ATYP_IPV4 => 1 + 4 + 2,
+        ATYP_IPV6 => 1 + 16 + 2,
+        ATYP_DOMAIN => 1 + 1 + *datagram.get(4)? as usize + 2,
+        _ => return None,
+    };
+    let address = datagram.get(3..3 + address_len)?;
+    let payload = &datagram[3 + address_len..];
+    if payload.len() > MAX_PACKET_SIZE {
+        return None;
+    }
+    frame.put_slice(address);
+    frame.put_u16(payload.len() as u16);
+    frame.put_slice(b"\r\n");
+    frame.put_slice(payload);
+    Some(u16::from_be_bytes([
+        address[address_len - 2],
+        address[address_len - 1],
+    ]))
+}
+
+mod tests {
+    #[test]
+    fn test_sniff() {
+        use crate::inbound::{sniff, Sniffed};
+
+        assert_eq!(sniff(5), Some(Sniffed::Socks5));
+        assert_eq!(sniff(4), Some(Sniffed::Socks4));
+        assert_eq!(sniff(b'C'), Some(Sniffed::Http));
+        assert_eq!(sniff(b'g'), Some(Sniffed::Http));
+        // a TLS ClientHello
+        assert_eq!(sniff(0x16), None);
+    }
+
+    #[test]
+    fn test_parse_handshake() {
+        use crate::{
+            inbound::{parse_auth, parse_greeting, parse_request},
+            proto::Sock5Address,
+        };
+
+        assert!(parse_greeting(&[5]).unwrap().is_none());
+        assert!(parse_greeting(&[5, 2, 0]).unwrap().is_none());
+        assert_eq!(
+            parse_greeting(&[5, 2, 0, 2]).unwrap(),
+            Some((4, &[0, 2][..]))
+        );
+        assert!(parse_greeting(&[4, 1, 0]).is_err());
+
+        assert!(parse_auth(&[1, 5, b'a', b'l']).is_none());
+        let (size, user, pass) = parse_auth(b"\x01\x05alice\x03pwdrest").unwrap();
+        assert_eq!((size, user.as_str(), pass.as_str()), (11, "alice", "pwd"));
+
+        assert!(parse_request(&[5, 1, 0, 1, 127, 0]).unwrap().is_none());
+        let (size, cmd, address) = parse_request(&[5, 1, 0, 1, 127, 0, 0, 1, 0, 80])
+            .unwrap()
+            .unwrap();
+        assert_eq!((size, cmd), (10, 1));
+        assert_eq!(address.as_socket(), "127.0.0.1:80".parse().ok());
+        let (size, cmd, address) = parse_request(b"\x05\x03\x00\x03\x07example\x01\xbb")
+            .unwrap()
+            .unwrap();
+        assert_eq!((size, cmd), (14, 3));
+        assert!(matches!(address, Sock5Address::Domain(domain, 443) if domain == "example"));
+        assert!(parse_request(&[5, 1, 0, 9, 0]).is_err());
+    }
+
+    #[test]
+    fn test_parse_http() {
+        use crate::inbound::parse_http;
+
+        assert!(parse_http(b"CONNECT example.com:443 HTTP/1.1\r\n")
+            .unwrap()
+            .is_none());
+        let raw = b"CONNECT [::1]:8443 HTTP/1.1\r\nHost: x\r\n\
+            Proxy-Authorization: Basic Ym9iOnB3\r\n\r\n\x16\x03";
+        let request = parse_http(raw).unwrap().unwrap();
+        assert_eq!(request.size, raw.len() - 2);
+        assert_eq!(
+            request.connect.unwrap().as_socket(),
+            "[::1]:8443".parse().ok()
+        );
+        assert_eq!(
+            request.credentials,
+            Some(("bob".to_string(), "pw".to_string()))
+        );
+        assert!(parse_http(b"CONNECT example.com HTTP/1.1\r\n\r\n").is_err());
+
+        let raw = b"GET http://example.com/a?b HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        let request = parse_http(raw).unwrap().unwrap();
+        assert_eq!(request.size, raw.len());
+        assert!(request.connect.is_none());
+        assert!(request.credentials.is_none());
+    }
+
+    #[test]
+    fn test_to_trojan_frame() {
+        use bytes::BytesMut;
+
+        use crate::inbound::to_trojan_frame;
+
+        let mut frame = BytesMut::new();
+        let datagram = [0, 0, 0, 1, 8, 8, 8, 8, 0, 53, b'h', b'i'];
+        assert_eq!(to_trojan_frame(&datagram, &mut frame), Some(53));
+        assert_eq!(
+            frame.as_ref(),
+            &[1, 8, 8, 8, 8, 0, 53, 0, 2, b'\r', b'\n', b'h', b'i']
+        );
+        frame.clear();
+        let datagram = [0, 0, 0, 3, 1, b'a', 1, 187];
+        assert_eq!(to_trojan_frame(&datagram, &mut frame), Some(443));
+        assert_eq!(frame.as_ref(), &[3, 1, b'a', 1, 187, 0, 0, b'\r', b'\n']);
+        // fragments and truncated addresses are dropped
+        assert_eq!(
+            to_trojan_frame(&[0, 0, 1, 1, 8, 8, 8, 8, 0, 53], &mut frame),
+            None
+        );
+        assert_eq!(to_trojan_frame(&[0, 0, 0, 4, 1, 2], &mut frame), None);
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::OPTIONS,
+    metrics::{add, incr, COUNTERS},
+};
+
+lazy_static::lazy_static! {
+    /// Paces the uploads at the rate the estimator of --pacing allows, unlimited otherwise. It
+    /// sends at most 10 ms of the rate at once.
+    pub static ref PACER: RateLimiter = RateLimiter::with_burst(0, 0.01);
+    pub static ref UPLOAD: RateLimiter = if OPTIONS.pacing {
+        RateLimiter::with_parent(OPTIONS.upload_limit.saturating_mul(1024), &PACER)
+    } else {
+        RateLimiter::new(OPTIONS.upload_limit.saturating_mul(1024))
+    };
+    pub static ref DOWNLOAD: RateLimiter =
+        RateLimiter::new(OPTIONS.download_limit.saturating_mul(1024));
+}
+
+/// UDP packets up to this size are treated as interactive regardless of port.
+const SMALL_PACKET: usize = 128;
+/// Debt interactive traffic may run into without waiting, in time to pay it back.
+const INTERACTIVE_DEBT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Priority {
+    /// Consumes tokens like bulk traffic, which backs off, but is only delayed once the bucket
+    /// is more than `INTERACTIVE_DEBT` in debt, so the limit holds for interactive floods too.
+    Interactive,
+    Bulk,
+}
+
+impl Priority {
+    pub fn of_stream(port: u16) -> Priority {
+        if OPTIONS.interactive_ports.contains(&port) {
+            Priority::Interactive
+        } else {
+            Priority::Bulk
+        }
+    }
+
+    pub fn of_packet(port: u16, size: usize) -> Priority {
+        if size <= SMALL_PACKET {
+            Priority::Interactive
+        } else {
+            Priority::of_stream(port)
+        }
+    }
+
+    /// How long traffic of this priority waits for the bucket to pay back its debt `wait`.
+    fn delay(self, wait: Duration) -> Duration {
+        match self {
+            Priority::Interactive => wait.saturating_sub(INTERACTIVE_DEBT),
+            Priority::Bulk => wait,
+        }
+    }
+}
+
+/// Token bucket shared by all the relay loops of one direction, with a burst of one second by default.
+/// Consumers may go into debt and sleep until it is paid back, so large reads are never stuck.
+pub struct RateLimiter {
+    /// bytes per second, 0 for unlimited
+    rate: AtomicU64,
+    /// seconds of the rate the bucket holds
+    burst: f64,
+    bucket: Mutex<(f64, Instant)>,
+    /// tokens are taken from it too, like the server's limiter for the limiter of a user
+    parent: Option<&'static RateLimiter>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> RateLimiter {
+        RateLimiter::with_burst(rate, 1.0)
+    }
+
+    pub fn with_burst(rate: u64, burst: f64) -> RateLimiter {
+        RateLimiter {
+            rate: AtomicU64::new(rate),
+            burst,
+            bucket: Mutex::new((rate as f64 * burst, Instant::now())),
+            parent: None,
+        }
+    }
+
+    pub fn with_parent(rate: u64, parent: &'static RateLimiter) -> RateLimiter {
+        RateLimiter {
+            parent: Some(parent),
+            ..RateLimiter::new(rate)
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    pub fn set_rate(&self, rate: u64) {
+        log::warn!("rate limit changed to {} bytes/s", rate);
+        self.rate.store(rate, Ordering::Relaxed);
+        *self.bucket.lock().unwrap() = (rate as f64 * self.burst, Instant::now());
+    }
+
+    /// Changes the rate without resetting the bucket, as the pacer does all the time.
+    pub fn adjust_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    /// Take `size` tokens here and from the parent and return how long the caller should wait.
+    fn take(&self, size: usize, now: Instant) -> Duration {
+        let wait = self.take_own(size, now);
+        self.parent
+            .map_or(wait, |parent| wait.max(parent.take(size, now)))
+    }
+
+    fn take_own(&self, size: usize, now: Instant) -> Duration {
+        let rate = self.rate();
+        if rate == 0 {
+            return Duration::ZERO;
+        }
+        let rate = rate as f64;
+        let mut bucket = self.bucket.lock().unwrap();
+        let elapsed = now.saturating_duration_since(bucket.1).as_secs_f64();
+        bucket.0 = (bucket.0 + elapsed * rate).min(rate * self.burst) - size as f64;
+        bucket.1 = now;
+        if bucket.0 >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.0 / rate)
+        }
+    }
+
+    pub async fn acquire(&self, size: usize, priority: Priority) {
+        let wait = priority.delay(self.take(size, Instant::now()));
+        if !wait.is_zero() {
+            incr(&COUNTERS.shaped);
+            add(&COUNTERS.shaped_ms, wait.as_millis() as u64);
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_token_bucket() {
+        use std::time::{Duration, Instant};
+
+        use super::RateLimiter;
+
+        let limiter = RateLimiter::new(0);
+        assert!(limiter.take(1 << 30, Instant::now()).is_zero());
+
+        limiter.set_rate(1000);
+        let now = Instant::now();
+        assert!(limiter.take(1000, now).is_zero());
+        assert_eq!(limiter.take(500, now), Duration::from_millis(500));
+        let now = now + Duration::from_secs(1);
+        assert!(limiter.take(400, now).is_zero());
+        let now = now + Duration::from_secs(10);
+        assert_eq!(limiter.take(2000, now), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_parent() {
+        use std::time::{Duration, Instant};
+
+        use super::RateLimiter;
+
+        let parent = Box::leak(Box::new(RateLimiter::new(1000)));
+        let first = RateLimiter::with_parent(500, parent);
+        let second = RateLimiter::with_parent(0, parent);
+        let now = Instant::now();
+        // the child is the tighter limit, then the parent shared by both
+        assert_eq!(first.take(600, now), Duration::from_millis(200));
+        assert_eq!(second.take(600, now), Duration::from_millis(200));
+        assert!(second.take(0, now + Duration::from_millis(200)).is_zero());
+    }
+
+    #[test]
+    fn test_interactive_debt() {
+        use std::time::{Duration, Instant};
+
+        use super::{Priority, RateLimiter};
+
+        let limiter = RateLimiter::new(1000);
+        let now = Instant::now();
+        // interactive bytes are charged, they wait only once the debt is too deep
+        let wait = limiter.take(1500, now);
+        assert!(Priority::Interactive.delay(wait).is_zero());
+        assert_eq!(Priority::Bulk.delay(wait), Duration::from_millis(500));
+        let wait = limiter.take(1000, now);
+        assert_eq!(
+            Priority::Interactive.delay(wait),
+            Duration::from_millis(500)
+        );
+    }
+}
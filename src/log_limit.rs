@@ -0,0 +1,241 @@
+//! Logger wrapper keeping hot paths from flooding the disk. Each call site writes at most
+//! --log-rate lines a second, the rest are counted and reported once the second is over, and
+//! lines repeating the previous one are folded into "last message repeated N times".
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::{Level, Log, Metadata, Record};
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Lines of a call site in the current window.
+struct Site {
+    start: Instant,
+    logged: u32,
+    suppressed: u32,
+    level: Level,
+    target: String,
+}
+
+/// The line written last, which later identical lines fold into.
+struct Last {
+    site: (&'static str, u32),
+    message: String,
+    repeats: u32,
+    level: Level,
+    target: String,
+}
+
+struct State {
+    sites: HashMap<(&'static str, u32), Site>,
+    last: Option<Last>,
+    swept: Instant,
+}
+
+pub struct RateLimited<L> {
+    inner: L,
+    rate: u32,
+    state: Mutex<State>,
+}
+
+impl<L: Log> RateLimited<L> {
+    /// Passes at most `rate` lines a second of each call site to `inner`.
+    pub fn new(inner: L, rate: u32) -> RateLimited<L> {
+        RateLimited {
+            inner,
+            rate,
+            state: Mutex::new(State {
+                sites: HashMap::new(),
+                last: None,
+                swept: Instant::now(),
+            }),
+        }
+    }
+
+    fn log_at(&self, record: &Record, now: Instant) {
+        if !self.inner.enabled(record.metadata()) {
+            return;
+        }
+        let site = (
+            record.file_static().unwrap_or("unknown"),
+            record.line().unwrap_or(0),
+        );
+        let message = record.args().to_string();
+        let mut state = self.state.lock().unwrap();
+        if now.duration_since(state.swept) >= WINDOW {
+            self.sweep(&mut state, now);
+        }
+        if let Some(last) = &mut state.last {
+            if last.site == site && last.message == message {
+                last.repeats += 1;
+                return;
+            }
+        }
+        self.report_repeats(&mut state);
+        let entry = state.sites.entry(site).or_insert_with(|| Site {
+            start: now,
+            logged: 0,
+            suppressed: 0,
+            level: record.level(),
+            target: record.target().to_string(),
+        });
+        if now.duration_since(entry.start) >= WINDOW {
+            let suppressed = std::mem::take(&mut entry.suppressed);
+            entry.start = now;
+            entry.logged = 0;
+            self.report_suppressed(site, entry, suppressed);
+        }
+        if entry.logged >= self.rate {
+            entry.suppressed += 1;
+            return;
+        }
+        entry.logged += 1;
+        self.inner.log(record);
+        state.last.replace(Last {
+            site,
+            message,
+            repeats: 0,
+            level: record.level(),
+            target: record.target().to_string(),
+        });
+    }
+
+    /// Reports what was held back by sites gone quiet and drops them.
+    fn sweep(&self, state: &mut State, now: Instant) {
+        state.swept = now;
+        self.report_repeats(state);
+        state.sites.retain(|site, entry| {
+            if now.duration_since(entry.start) < WINDOW {
+                return true;
+            }
+            self.report_suppressed(*site, entry, entry.suppressed);
+            false
+        });
+    }
+
+    fn report_repeats(&self, state: &mut State) {
+        let Some(last) = &mut state.last else {
+            return;
+        };
+        if last.repeats == 0 {
+            return;
+        }
+        self.inner.log(
+            &Record::builder()
+                .args(format_args!("last message repeated {} times", last.repeats))
+                .level(last.level)
+                .target(last.target.as_str())
+                .file_static(Some(last.site.0))
+                .line(Some(last.site.1))
+                .build(),
+        );
+        last.repeats = 0;
+    }
+
+    fn report_suppressed(&self, site: (&'static str, u32), entry: &Site, suppressed: u32) {
+        if suppressed == 0 {
+            return;
+        }
+        self.inner.log(
+            &Record::builder()
+                .args(format_args!("{} messages suppressed", suppressed))
+                .level(entry.level)
+                .target(entry.target.as_str())
+                .file_static(Some(site.0))
+                .line(Some(site.1))
+                .build(),
+        );
+    }
+}
+
+impl<L: Log> Log for RateLimited<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.log_at(record, Instant::now());
+    }
+
+    fn flush(&self) {
+        let mut state = self.state.lock().unwrap();
+        self.sweep(&mut state, Instant::now() + WINDOW);
+        self.inner.flush();
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_rate_limited() {
+        use std::{
+            sync::Mutex,
+            time::{Duration, Instant},
+        };
+
+        use log::{Level, Log, Metadata, Record};
+
+        use super::RateLimited;
+
+        #[derive(Default)]
+        struct Lines(Mutex<Vec<(u32, String)>>);
+
+        impl Log for &Lines {
+            fn enabled(&self, metadata: &Metadata) -> bool {
+                metadata.level() <= Level::Info
+            }
+
+            fn log(&self, record: &Record) {
+                let line = (record.line().unwrap(), record.args().to_string());
+                self.0.lock().unwrap().push(line);
+            }
+
+            fn flush(&self) {}
+        }
+
+        let lines = Lines::default();
+        let logger = RateLimited::new(&lines, 2);
+        let start = Instant::now();
+        let log = |line: u32, level: Level, message: &str, ms: u64| {
+            logger.log_at(
+                &Record::builder()
+                    .args(format_args!("{}", message))
+                    .level(level)
+                    .file_static(Some("udp.rs"))
+                    .line(Some(line))
+                    .build(),
+                start + Duration::from_millis(ms),
+            );
+        };
+        for (i, packet) in ["a", "b", "c", "d"].into_iter().enumerate() {
+            log(10, Level::Info, packet, i as u64);
+        }
+        log(10, Level::Debug, "hidden", 5);
+        log(20, Level::Warn, "slow", 6);
+        log(20, Level::Warn, "slow", 7);
+        log(20, Level::Warn, "slow", 8);
+        log(10, Level::Info, "e", 1001);
+        log(30, Level::Info, "quiet", 2500);
+        log(30, Level::Info, "quiet", 2600);
+        logger.flush();
+        let expected = [
+            (10, "a"),
+            (10, "b"),
+            (20, "slow"),
+            (20, "last message repeated 2 times"),
+            (10, "2 messages suppressed"),
+            (10, "e"),
+            (30, "quiet"),
+            (30, "last message repeated 1 times"),
+        ];
+        let lines = lines.0.lock().unwrap();
+        let lines: Vec<_> = lines
+            .iter()
+            .map(|(line, message)| (*line, message.as_str()))
+            .collect();
+        assert_eq!(lines, expected);
+    }
+}
@@ -12,24 +12,55 @@ mod config;
 cfg_if::cfg_if! {
     if #[cfg(windows)] {
         mod dns;
+        mod route_test;
         mod wintun;
         mod awintun;
     }
 }
+mod allocator;
 mod aproxy;
 mod aserver;
 mod async_utils;
+mod backoff;
+mod cipher_order;
+mod events;
+mod geo;
+mod geosite;
+mod grpc;
 mod idle_pool;
+mod inbound;
+mod limiter;
+mod log_limit;
+mod memory;
+mod metrics;
+mod mux;
+mod mux_conn;
+mod nat64;
+mod pacing;
+mod padding;
+mod peer_stats;
+mod pinning;
+mod plugin;
 mod proto;
 mod proxy;
+mod quic;
+mod redundancy;
+mod replay;
 mod resolver;
+mod reverse;
+mod routing;
+mod rules;
 mod server;
 mod status;
 mod sys;
 mod tcp_util;
+mod timing;
+mod tls_client;
 mod tls_conn;
 mod types;
+mod upstream;
 mod utils;
+mod watchdog;
 
 fn main() {
     #[cfg(debug_assertions)]
@@ -37,7 +68,13 @@ fn main() {
     unsafe {
         backtrace_on_stack_overflow::enable()
     };
-    config::setup_logger(&OPTIONS.log_file, OPTIONS.log_level).unwrap();
+    config::setup_logger(
+        &OPTIONS.log_file,
+        OPTIONS.log_level.level,
+        &OPTIONS.log_modules(),
+        OPTIONS.log_rate,
+    )
+    .unwrap();
     panic::set_hook(Box::new(|info| {
         let trace = Backtrace::new();
         let message = info.to_string();
@@ -50,7 +87,16 @@ fn main() {
             }
         }
     }));
-    if let Err(err) = match OPTIONS.mode {
+    sys::install_crash_handler(&OPTIONS.log_file, OPTIONS.minidump_keep);
+    if let Err(err) = plugin::start() {
+        log::error!("start plugin failed:{:?}", err);
+        std::process::exit(err.exit_code());
+    }
+    if let Err(err) = routing::init() {
+        log::error!("load routing rules failed:{:?}", err);
+        std::process::exit(err.exit_code());
+    }
+    let ret = match OPTIONS.mode {
         Mode::Proxy(_) => {
             log::warn!(
                 "trojan started in proxy mode with server:{}",
@@ -65,12 +111,8 @@ fn main() {
             );
             aproxy::run()
         }
-        Mode::Server(_) => {
-            log::warn!("trojan started in synchronous server mode");
-            server::run()
-        }
-        Mode::Aserver(_) => {
-            log::warn!("trojan started in asynchronous server mode");
+        Mode::Server(_) | Mode::Aserver(_) => {
+            log::warn!("trojan started in server mode");
             aserver::run()
         }
         Mode::Wintun(_) => {
@@ -105,7 +147,24 @@ fn main() {
                 }
             }
         }
-    } {
+        Mode::Status(_) => peer_stats::run(),
+        Mode::Replay(_) => replay::run(),
+        Mode::RouteTest(_) => {
+            cfg_if::cfg_if! {
+                if #[cfg(windows)] {
+                    route_test::run()
+                } else {
+                    panic!("trojan in route test mode not supported on non-windows platform");
+                }
+            }
+        }
+    };
+    plugin::stop();
+    if let Err(err) = ret {
         log::error!("trojan exited with error:{:?}", err);
+        log::logger().flush();
+        std::process::exit(err.exit_code());
     }
+    // reports what the rate limit held back
+    log::logger().flush();
 }
@@ -0,0 +1,207 @@
+//! Budget of the connection buffers with the usage of each subsystem, so a client on a router
+//! with little RAM refuses new sessions instead of getting killed.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::OPTIONS;
+
+/// rustls buffers up to 64 KiB of records to send and a record each way, plus the copy buffers
+/// of the relay.
+pub const TLS_SESSION: u64 = 64 * 1024 + 2 * 18 * 1024 + 2 * 4096;
+/// Datagrams queued for the server, with the frames being parsed from it.
+pub const UDP_SESSION: u64 = 64 * 1500 + 2 * 1500;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Subsystem {
+    /// socket buffers of the user space tcp/ip stack of the TUN device
+    Tun,
+    Udp,
+    Tls,
+}
+
+impl Subsystem {
+    const ALL: [Subsystem; 3] = [Subsystem::Tun, Subsystem::Udp, Subsystem::Tls];
+
+    fn name(self) -> &'static str {
+        match self {
+            Subsystem::Tun => "tun",
+            Subsystem::Udp => "udp",
+            Subsystem::Tls => "tls",
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref BUDGET: Budget = Budget::new(OPTIONS.memory_limit.saturating_mul(1024 * 1024));
+}
+
+pub struct Budget {
+    /// bytes, 0 for unlimited
+    limit: u64,
+    total: AtomicU64,
+    used: [AtomicU64; 3],
+    sessions: [AtomicU64; 3],
+    refused: [AtomicU64; 3],
+}
+
+impl Budget {
+    fn new(limit: u64) -> Budget {
+        Budget {
+            limit,
+            total: AtomicU64::new(0),
+            used: Default::default(),
+            sessions: Default::default(),
+            refused: Default::default(),
+        }
+    }
+
+    fn reserve(&'static self, subsystem: Subsystem, size: u64) -> Option<Reservation> {
+        let mut reservation = Reservation {
+            budget: self,
+            sizes: [0; 3],
+        };
+        reservation.add(subsystem, size).then_some(reservation)
+    }
+
+    fn stats(&self) -> MemoryStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        MemoryStats {
+            limit: self.limit,
+            used: load(&self.total),
+            subsystems: Subsystem::ALL
+                .iter()
+                .map(|subsystem| {
+                    let index = *subsystem as usize;
+                    SubsystemStats {
+                        name: subsystem.name().into(),
+                        used: load(&self.used[index]),
+                        sessions: load(&self.sessions[index]),
+                        refused: load(&self.refused[index]),
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Memory of a session, given back to the budget when dropped.
+pub struct Reservation {
+    budget: &'static Budget,
+    sizes: [u64; 3],
+}
+
+impl Reservation {
+    /// Reserves `size` bytes more for `subsystem`, refused if that goes over the limit.
+    pub fn and(mut self, subsystem: Subsystem, size: u64) -> Option<Reservation> {
+        self.add(subsystem, size).then_some(self)
+    }
+
+    fn add(&mut self, subsystem: Subsystem, size: u64) -> bool {
+        let budget = self.budget;
+        let index = subsystem as usize;
+        let reserved = budget
+            .total
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |total| {
+                (budget.limit == 0 || total + size <= budget.limit).then_some(total + size)
+            })
+            .is_ok();
+        if !reserved {
+            budget.refused[index].fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        budget.used[index].fetch_add(size, Ordering::Relaxed);
+        if self.sizes[index] == 0 {
+            budget.sessions[index].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sizes[index] += size;
+        true
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        for (index, size) in self.sizes.iter().enumerate() {
+            if *size == 0 {
+                continue;
+            }
+            self.budget.total.fetch_sub(*size, Ordering::Relaxed);
+            self.budget.used[index].fetch_sub(*size, Ordering::Relaxed);
+            self.budget.sessions[index].fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Reserves `size` bytes for a new session of `subsystem`, None if the budget is used up.
+pub fn reserve(subsystem: Subsystem, size: u64) -> Option<Reservation> {
+    let reservation = BUDGET.reserve(subsystem, size);
+    if reservation.is_none() {
+        log::warn!(
+            "memory limit of {} MiB reached, new {} session refused",
+            OPTIONS.memory_limit,
+            subsystem.name()
+        );
+    }
+    reservation
+}
+
+pub fn stats() -> MemoryStats {
+    BUDGET.stats()
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MemoryStats {
+    /// bytes, 0 for unlimited
+    pub limit: u64,
+    pub used: u64,
+    pub subsystems: Vec<SubsystemStats>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SubsystemStats {
+    pub name: String,
+    pub used: u64,
+    pub sessions: u64,
+    /// sessions refused over the limit
+    pub refused: u64,
+}
+
+mod tests {
+    #[test]
+    fn test_budget() {
+        use super::{Budget, Subsystem};
+
+        let budget: &'static Budget = Box::leak(Box::new(Budget::new(1000)));
+        let tun = budget.reserve(Subsystem::Tun, 600).unwrap();
+        let session = budget
+            .reserve(Subsystem::Udp, 100)
+            .and_then(|reservation| reservation.and(Subsystem::Tls, 200))
+            .unwrap();
+        // the tls part is refused, the udp part given back
+        assert!(budget
+            .reserve(Subsystem::Udp, 50)
+            .and_then(|reservation| reservation.and(Subsystem::Tls, 100))
+            .is_none());
+
+        let stats = budget.stats();
+        assert_eq!(stats.used, 900);
+        assert_eq!(
+            stats
+                .subsystems
+                .iter()
+                .map(|subsystem| (subsystem.used, subsystem.sessions, subsystem.refused))
+                .collect::<Vec<_>>(),
+            vec![(600, 1, 0), (100, 1, 0), (200, 1, 1)]
+        );
+
+        drop(tun);
+        drop(session);
+        let stats = budget.stats();
+        assert_eq!(stats.used, 0);
+        assert!(stats
+            .subsystems
+            .iter()
+            .all(|subsystem| subsystem.sessions == 0));
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Client side shaping and drop counters, so users can tell network loss from local drops,
+/// along with the server and traffic figures shown by `trojan status`.
+pub struct Counters {
+    /// tls connections established to the trojan server
+    pub server_connected: AtomicU64,
+    /// failed attempts connecting to the trojan server
+    pub server_failed: AtomicU64,
+    /// unix time in milliseconds of the last tls handshake with the trojan server, 0 for none
+    pub last_handshake: AtomicU64,
+    /// tcp connect time to the trojan server in milliseconds, about one round trip
+    pub rtt_ms: AtomicU64,
+    /// failed reconnect attempts in a row, 0 while the server is reachable
+    pub reconnect_failures: AtomicU64,
+    /// unix time in milliseconds of the next reconnect attempt, 0 for none
+    pub retry_at: AtomicU64,
+    /// connections and udp sessions currently relayed
+    pub active_flows: AtomicU64,
+    /// bytes received from the tunnel
+    pub rx_bytes: AtomicU64,
+    /// bytes sent into the tunnel
+    pub tx_bytes: AtomicU64,
+    /// bulk transfers delayed by the rate limiter
+    pub shaped: AtomicU64,
+    /// total delay in milliseconds caused by the rate limiter
+    pub shaped_ms: AtomicU64,
+    /// packets waiting because a relay queue was full
+    pub backpressure: AtomicU64,
+    /// udp packets for a local socket that no longer exists
+    pub udp_no_socket: AtomicU64,
+    /// udp packets sent to a session which is already closed
+    pub udp_session_closed: AtomicU64,
+    /// udp packets failed writing to the remote server
+    pub udp_remote_failed: AtomicU64,
+    /// udp packets failed writing back to the local socket
+    pub udp_local_failed: AtomicU64,
+    /// udp streams dropped on invalid protocol from server
+    pub udp_invalid_protocol: AtomicU64,
+    /// bytes per second uploads are paced at by --pacing, 0 while unpaced
+    pub pacing_rate: AtomicU64,
+    /// bottleneck bandwidth of the uplink in bytes per second estimated by --pacing
+    pub bottleneck_bw: AtomicU64,
+    /// minimum round trip time to the server in microseconds measured by --pacing
+    pub min_rtt_us: AtomicU64,
+    /// phase of the --pacing estimator
+    pub pacing_phase: AtomicU64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct CountersSnapshot {
+    pub server_connected: u64,
+    pub server_failed: u64,
+    pub last_handshake: u64,
+    pub rtt_ms: u64,
+    pub reconnect_failures: u64,
+    pub retry_at: u64,
+    pub active_flows: u64,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub shaped: u64,
+    pub shaped_ms: u64,
+    pub backpressure: u64,
+    pub udp_no_socket: u64,
+    pub udp_session_closed: u64,
+    pub udp_remote_failed: u64,
+    pub udp_local_failed: u64,
+    pub udp_invalid_protocol: u64,
+    #[serde(default)]
+    pub pacing_rate: u64,
+    #[serde(default)]
+    pub bottleneck_bw: u64,
+    #[serde(default)]
+    pub min_rtt_us: u64,
+    #[serde(default)]
+    pub pacing_phase: u64,
+}
+
+pub static COUNTERS: Counters = Counters {
+    server_connected: AtomicU64::new(0),
+    server_failed: AtomicU64::new(0),
+    last_handshake: AtomicU64::new(0),
+    rtt_ms: AtomicU64::new(0),
+    reconnect_failures: AtomicU64::new(0),
+    retry_at: AtomicU64::new(0),
+    active_flows: AtomicU64::new(0),
+    rx_bytes: AtomicU64::new(0),
+    tx_bytes: AtomicU64::new(0),
+    shaped: AtomicU64::new(0),
+    shaped_ms: AtomicU64::new(0),
+    backpressure: AtomicU64::new(0),
+    udp_no_socket: AtomicU64::new(0),
+    udp_session_closed: AtomicU64::new(0),
+    udp_remote_failed: AtomicU64::new(0),
+    udp_local_failed: AtomicU64::new(0),
+    udp_invalid_protocol: AtomicU64::new(0),
+    pacing_rate: AtomicU64::new(0),
+    bottleneck_bw: AtomicU64::new(0),
+    min_rtt_us: AtomicU64::new(0),
+    pacing_phase: AtomicU64::new(0),
+};
+
+pub fn incr(counter: &AtomicU64) {
+    add(counter, 1);
+}
+
+pub fn add(counter: &AtomicU64, value: u64) {
+    counter.fetch_add(value, Ordering::Relaxed);
+}
+
+/// Counts a connection attempt to the trojan server, failures without successes mean trouble.
+pub fn server_result<T, E>(result: &Result<T, E>) {
+    if result.is_ok() {
+        incr(&COUNTERS.server_connected);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_millis() as u64)
+            .unwrap_or_default();
+        COUNTERS.last_handshake.store(now, Ordering::Relaxed);
+    } else {
+        incr(&COUNTERS.server_failed);
+    }
+}
+
+pub fn record_rtt(elapsed: Duration) {
+    COUNTERS
+        .rtt_ms
+        .store(elapsed.as_millis() as u64, Ordering::Relaxed);
+}
+
+impl Counters {
+    pub fn snapshot(&self) -> CountersSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        CountersSnapshot {
+            server_connected: load(&self.server_connected),
+            server_failed: load(&self.server_failed),
+            last_handshake: load(&self.last_handshake),
+            rtt_ms: load(&self.rtt_ms),
+            reconnect_failures: load(&self.reconnect_failures),
+            retry_at: load(&self.retry_at),
+            active_flows: load(&self.active_flows),
+            rx_bytes: load(&self.rx_bytes),
+            tx_bytes: load(&self.tx_bytes),
+            shaped: load(&self.shaped),
+            shaped_ms: load(&self.shaped_ms),
+            backpressure: load(&self.backpressure),
+            udp_no_socket: load(&self.udp_no_socket),
+            udp_session_closed: load(&self.udp_session_closed),
+            udp_remote_failed: load(&self.udp_remote_failed),
+            udp_local_failed: load(&self.udp_local_failed),
+            udp_invalid_protocol: load(&self.udp_invalid_protocol),
+            pacing_rate: load(&self.pacing_rate),
+            bottleneck_bw: load(&self.bottleneck_bw),
+            min_rtt_us: load(&self.min_rtt_us),
+            pacing_phase: load(&self.pacing_phase),
+        }
+    }
+}
@@ -0,0 +1,588 @@
+//! Many proxy streams over one server connection.
+//!
+//! A client opens the connection with the `MUX` command, after that both sides exchange smux
+//! style frames:
+//!
+//! `version(u8) | command(u8) | length(u16 le) | stream id(u32 le) | data`
+//!
+//! Every stream then starts with a regular trojan request, so the server handles it the same
+//! way as a connection of its own.
+//!
+//! With `--trojan-go-mux` the client talks to a trojan-go server instead: the connection is
+//! opened with the `TROJAN_GO_MUX` command, and streams start with the command and address
+//! only, the "simple socks" request of trojan-go.
+//!
+//! A stream that doesn't read what the peer sends is reset instead of holding back the others:
+//! smux has no reset frame, so the peer gets a FIN and the local side reads an error.
+//!
+//! Streams opened with `Priority::Interactive` queue their frames apart from the bulk ones,
+//! and the writer takes them first, so a large transfer doesn't delay them on a shared
+//! connection.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex, Weak,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::{
+    io::{
+        duplex, split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf,
+        ReadHalf, WriteHalf,
+    },
+    select, spawn,
+    sync::mpsc::{channel, error::TrySendError, Receiver, Sender, WeakSender},
+    task::JoinHandle,
+    time::timeout,
+};
+
+use crate::{
+    config::OPTIONS,
+    limiter::Priority,
+    proto::{TrojanRequest, MUX, TROJAN_GO_MUX},
+    types::{Result, TrojanError},
+};
+
+const VERSION: u8 = 1;
+/// opens a stream
+pub const SYN: u8 = 0;
+/// closes the sending side of a stream
+pub const FIN: u8 = 1;
+/// carries stream data
+pub const PSH: u8 = 2;
+/// keepalive
+pub const NOP: u8 = 3;
+const HEADER_LEN: usize = 8;
+/// Largest data size of a frame.
+pub const MAX_DATA: usize = 16384;
+/// Bytes buffered by a stream in each direction.
+const STREAM_BUFFER: usize = 65536;
+/// Frames queued for a stream besides its buffer, a stream falling further behind is reset.
+const STREAM_QUEUE: usize = 16;
+/// Bulk data sent in one write, interactive frames arriving meanwhile wait for no more than it.
+const COALESCE_LIMIT: usize = 4 * MAX_DATA;
+/// trojan-go closes a session it hears nothing from for 30 seconds.
+const TROJAN_GO_KEEPALIVE: Duration = Duration::from_secs(10);
+
+pub type Frame = (u8, u32, Bytes);
+
+/// Data queue and reset flag of a stream.
+type StreamEntry = (Sender<Bytes>, Arc<AtomicBool>);
+
+/// State shared by a session and its reading and writing tasks.
+#[derive(Default)]
+struct Shared {
+    streams: Mutex<HashMap<u32, StreamEntry>>,
+    closed: AtomicBool,
+}
+
+lazy_static::lazy_static! {
+    static ref CLIENT: tokio::sync::Mutex<Option<Arc<Session>>> = Default::default();
+}
+
+pub fn encode(buffer: &mut BytesMut, cmd: u8, sid: u32, data: &[u8]) {
+    buffer.put_u8(VERSION);
+    buffer.put_u8(cmd);
+    buffer.put_u16_le(data.len() as u16);
+    buffer.put_u32_le(sid);
+    buffer.extend_from_slice(data);
+}
+
+/// Takes a frame from the front of `buffer`, `None` if it is not complete yet.
+pub fn parse(buffer: &mut BytesMut) -> Result<Option<Frame>> {
+    if buffer.len() < HEADER_LEN {
+        return Ok(None);
+    }
+    if buffer[0] != VERSION {
+        return Err(TrojanError::Mux("invalid frame version"));
+    }
+    let len = u16::from_le_bytes([buffer[2], buffer[3]]) as usize;
+    if buffer.len() < HEADER_LEN + len {
+        return Ok(None);
+    }
+    let cmd = buffer[1];
+    let sid = u32::from_le_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]);
+    buffer.advance(HEADER_LEN);
+    Ok(Some((cmd, sid, buffer.split_to(len).freeze())))
+}
+
+/// A multiplexed connection, it closes once the session and all of its streams are dropped.
+pub struct Session {
+    frames: Sender<Frame>,
+    urgent: Sender<Frame>,
+    shared: Arc<Shared>,
+    next_id: AtomicU32,
+}
+
+impl Session {
+    /// Runs a session over `conn`, `data` is what has been read from it already. Streams opened
+    /// by the peer are sent to `accept`, without it they are refused. The connection is closed
+    /// when there is no stream and nothing is read for `idle_timeout` seconds.
+    pub fn start<S>(
+        conn: S,
+        data: BytesMut,
+        accept: Option<Sender<MuxStream>>,
+        idle_timeout: u64,
+    ) -> Arc<Self>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (frames, receiver) = channel(256);
+        let (urgent, urgent_receiver) = channel(256);
+        let shared = Arc::new(Shared::default());
+        let (read, write) = split(conn);
+        let writer = spawn(write_frames(
+            write,
+            urgent_receiver,
+            receiver,
+            shared.clone(),
+        ));
+        spawn(read_frames(
+            read,
+            data,
+            frames.downgrade(),
+            shared.clone(),
+            accept,
+            writer,
+            idle_timeout,
+        ));
+        Arc::new(Self {
+            frames,
+            urgent,
+            shared,
+            next_id: AtomicU32::new(1),
+        })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Relaxed)
+    }
+
+    pub fn stream_count(&self) -> usize {
+        self.shared.streams.lock().unwrap().len()
+    }
+
+    /// Sends a keepalive every `interval`, returns once the session is closed.
+    pub async fn keep_alive(&self, interval: Duration) {
+        while !self.is_closed() {
+            tokio::time::sleep(interval).await;
+            if self.frames.send((NOP, 0, Bytes::new())).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Opens a stream to the peer, its frames are sent ahead of bulk ones if `priority` is
+    /// interactive.
+    pub async fn open(&self, priority: Priority) -> Result<MuxStream> {
+        // odd ids for the opening side like smux, the accepting side never opens streams.
+        let sid = self.next_id.fetch_add(2, Ordering::Relaxed);
+        // the SYN goes the same way as the data, which must not overtake it
+        let frames = match priority {
+            Priority::Interactive => &self.urgent,
+            Priority::Bulk => &self.frames,
+        };
+        frames
+            .send((SYN, sid, Bytes::new()))
+            .await
+            .map_err(|_| TrojanError::Mux("session closed"))?;
+        Ok(new_stream(sid, frames.clone(), &self.shared))
+    }
+}
+
+/// Opens a stream of `priority` on the shared server connection, a new one is made with
+/// `connect` when there is none yet, it is closed or it carries `--mux` streams already.
+pub async fn open_stream<F, S>(connect: F, priority: Priority) -> Result<MuxStream>
+where
+    F: Future<Output = Result<S>>,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut current = CLIENT.lock().await;
+    let session = match current.as_ref() {
+        Some(session) if !session.is_closed() && session.stream_count() < OPTIONS.mux => {
+            session.clone()
+        }
+        _ => {
+            let mut conn = connect.await?;
+            let mut request = BytesMut::new();
+            let cmd = if OPTIONS.trojan_go_mux {
+                TROJAN_GO_MUX
+            } else {
+                MUX
+            };
+            TrojanRequest::generate(&mut request, cmd, OPTIONS.empty_addr.as_ref().unwrap());
+            conn.write_all(request.as_ref()).await?;
+            log::info!("new mux session to server");
+            let session = Session::start(conn, BytesMut::new(), None, OPTIONS.tcp_idle_timeout);
+            if OPTIONS.trojan_go_mux {
+                spawn(keep_alive(Arc::downgrade(&session)));
+            }
+            current.replace(session.clone());
+            session
+        }
+    };
+    drop(current);
+    session.open(priority).await
+}
+
+/// Keeps a trojan-go session alive as long as it's in use, that is until it is closed or
+/// replaced by a new one and its last stream is done.
+async fn keep_alive(session: Weak<Session>) {
+    loop {
+        tokio::time::sleep(TROJAN_GO_KEEPALIVE).await;
+        let Some(session) = session.upgrade() else {
+            break;
+        };
+        if session.is_closed() || session.frames.send((NOP, 0, Bytes::new())).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// One stream of a session, reading it fails once the stream is reset.
+pub struct MuxStream {
+    inner: DuplexStream,
+    reset: Arc<AtomicBool>,
+}
+
+impl MuxStream {
+    fn reset_error() -> io::Error {
+        io::Error::new(io::ErrorKind::ConnectionReset, "mux stream reset")
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            // the data received before the reset is read first, then the error instead of the end
+            Poll::Ready(Ok(()))
+                if buf.filled().len() == filled
+                    && buf.remaining() > 0
+                    && this.reset.load(Ordering::Relaxed) =>
+            {
+                Poll::Ready(Err(Self::reset_error()))
+            }
+            poll => poll,
+        }
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.reset.load(Ordering::Relaxed) {
+            return Poll::Ready(Err(Self::reset_error()));
+        }
+        Pin::new(&mut this.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+fn new_stream(sid: u32, frames: Sender<Frame>, shared: &Shared) -> MuxStream {
+    let (stream, inner) = duplex(STREAM_BUFFER);
+    let (sender, receiver) = channel(STREAM_QUEUE);
+    let reset = Arc::new(AtomicBool::new(false));
+    shared
+        .streams
+        .lock()
+        .unwrap()
+        .insert(sid, (sender, reset.clone()));
+    let (read, write) = split(inner);
+    spawn(stream_to_peer(sid, read, frames));
+    spawn(peer_to_stream(write, receiver));
+    MuxStream {
+        inner: stream,
+        reset,
+    }
+}
+
+async fn stream_to_peer(sid: u32, mut read: ReadHalf<DuplexStream>, frames: Sender<Frame>) {
+    let mut buffer = vec![0u8; MAX_DATA];
+    while let Ok(n) = read.read(buffer.as_mut_slice()).await {
+        if n == 0 {
+            break;
+        }
+        let data = Bytes::copy_from_slice(&buffer[..n]);
+        if frames.send((PSH, sid, data)).await.is_err() {
+            return;
+        }
+    }
+    let _ = frames.send((FIN, sid, Bytes::new())).await;
+}
+
+async fn peer_to_stream(mut write: WriteHalf<DuplexStream>, mut receiver: Receiver<Bytes>) {
+    while let Some(data) = receiver.recv().await {
+        if write.write_all(data.as_ref()).await.is_err() {
+            return;
+        }
+    }
+    let _ = write.shutdown().await;
+}
+
+async fn write_frames<S: AsyncWrite>(
+    mut write: WriteHalf<S>,
+    mut urgent: Receiver<Frame>,
+    mut receiver: Receiver<Frame>,
+    shared: Arc<Shared>,
+) {
+    let mut buffer = BytesMut::new();
+    loop {
+        let (cmd, sid, data) = select! {
+            biased;
+            Some(frame) = urgent.recv() => frame,
+            Some(frame) = receiver.recv() => frame,
+            else => break,
+        };
+        encode(&mut buffer, cmd, sid, data.as_ref());
+        // frames of other streams which are ready go out in the same write, the interactive
+        // ones first and the bulk ones only up to the limit
+        loop {
+            let frame = match urgent.try_recv() {
+                Ok(frame) => frame,
+                Err(_) if buffer.len() < COALESCE_LIMIT => match receiver.try_recv() {
+                    Ok(frame) => frame,
+                    Err(_) => break,
+                },
+                Err(_) => break,
+            };
+            let (cmd, sid, data) = frame;
+            encode(&mut buffer, cmd, sid, data.as_ref());
+        }
+        if let Err(err) = write.write_all(buffer.as_ref()).await {
+            log::error!("write mux frames failed:{}", err);
+            break;
+        }
+        buffer.clear();
+    }
+    shared.closed.store(true, Ordering::Relaxed);
+    let _ = write.shutdown().await;
+}
+
+async fn read_frames<S: AsyncRead>(
+    mut read: ReadHalf<S>,
+    mut buffer: BytesMut,
+    frames: WeakSender<Frame>,
+    shared: Arc<Shared>,
+    accept: Option<Sender<MuxStream>>,
+    writer: JoinHandle<()>,
+    idle_timeout: u64,
+) {
+    'main: loop {
+        loop {
+            let (cmd, sid, data) = match parse(&mut buffer) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(err) => {
+                    log::error!("mux session failed:{:?}", err);
+                    break 'main;
+                }
+            };
+            match cmd {
+                SYN => {
+                    let (Some(accept), Some(frames)) = (&accept, frames.upgrade()) else {
+                        log::error!("stream {} opened by peer is refused", sid);
+                        break 'main;
+                    };
+                    if accept.send(new_stream(sid, frames, &shared)).await.is_err() {
+                        break 'main;
+                    }
+                }
+                PSH => {
+                    let stream = shared.streams.lock().unwrap().get(&sid).cloned();
+                    // the stream may be dropped already, data is discarded then.
+                    let Some((sender, reset)) = stream else {
+                        continue;
+                    };
+                    if let Err(TrySendError::Full(_)) = sender.try_send(data) {
+                        // waiting for it would hold back all the other streams of the session
+                        log::warn!("mux stream {} is not reading, reset it", sid);
+                        reset.store(true, Ordering::Relaxed);
+                        shared.streams.lock().unwrap().remove(&sid);
+                        if let Some(frames) = frames.upgrade() {
+                            spawn(async move {
+                                let _ = frames.send((FIN, sid, Bytes::new())).await;
+                            });
+                        }
+                    }
+                }
+                FIN => {
+                    shared.streams.lock().unwrap().remove(&sid);
+                }
+                NOP => {}
+                _ => {
+                    log::error!("invalid mux command:{}", cmd);
+                    break 'main;
+                }
+            }
+        }
+        match timeout(
+            Duration::from_secs(idle_timeout),
+            read.read_buf(&mut buffer),
+        )
+        .await
+        {
+            Ok(Ok(0)) | Ok(Err(_)) => break,
+            Ok(Ok(_)) => {}
+            Err(_) => {
+                if shared.streams.lock().unwrap().is_empty() {
+                    log::info!("mux session idle, close it");
+                    break;
+                }
+            }
+        }
+    }
+    shared.closed.store(true, Ordering::Relaxed);
+    // dropping the senders ends all the streams
+    shared.streams.lock().unwrap().clear();
+    writer.abort();
+}
+
+mod tests {
+    #[tokio::test]
+    async fn test_session() {
+        use tokio::{
+            io::{duplex, AsyncReadExt, AsyncWriteExt},
+            sync::mpsc::channel,
+        };
+
+        use crate::{limiter::Priority, mux::Session};
+
+        let (client, server) = duplex(1024);
+        let (accept, mut accepted) = channel(4);
+        let _server = Session::start(server, Default::default(), Some(accept), 60);
+        let client = Session::start(client, Default::default(), None, 60);
+
+        let mut first = client.open(Priority::Bulk).await.unwrap();
+        let mut second = client.open(Priority::Bulk).await.unwrap();
+        first.write_all(b"hello").await.unwrap();
+        second.write_all(b"world").await.unwrap();
+        let mut peer_first = accepted.recv().await.unwrap();
+        let mut peer_second = accepted.recv().await.unwrap();
+        let mut data = [0u8; 5];
+        peer_second.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"world");
+        peer_first.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"hello");
+
+        // closing one side ends the other, the session stays usable
+        drop(first);
+        assert_eq!(peer_first.read(&mut data).await.unwrap(), 0);
+        peer_second.write_all(b"again").await.unwrap();
+        second.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"again");
+    }
+
+    #[tokio::test]
+    async fn test_stalled_stream() {
+        use std::{io::ErrorKind, time::Duration};
+
+        use tokio::{
+            io::{duplex, split, AsyncReadExt, AsyncWriteExt},
+            spawn,
+            sync::mpsc::channel,
+            time::sleep,
+        };
+
+        use crate::{limiter::Priority, mux::Session};
+
+        let (client, server) = duplex(1024);
+        let (accept, mut accepted) = channel(4);
+        let _server = Session::start(server, Default::default(), Some(accept), 60);
+        let client = Session::start(client, Default::default(), None, 60);
+
+        let mut stalled = client.open(Priority::Bulk).await.unwrap();
+        let mut other = client.open(Priority::Bulk).await.unwrap();
+        stalled.write_all(b"hello").await.unwrap();
+        other.write_all(b"world").await.unwrap();
+        let peer_stalled = accepted.recv().await.unwrap();
+        let mut peer_other = accepted.recv().await.unwrap();
+        let (mut peer_read, mut peer_write) = split(peer_stalled);
+        let sent = 1 << 20;
+        spawn(async move { peer_write.write_all(&vec![0u8; sent]).await });
+        while client.stream_count() > 1 {
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        // the stream nobody reads is reset instead of blocking the session
+        let mut data = [0u8; 5];
+        peer_other.write_all(b"again").await.unwrap();
+        other.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"again");
+
+        // the reset is an error for the stalled side and the end of the stream for the peer
+        let mut received = Vec::new();
+        let err = stalled.read_to_end(&mut received).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+        assert!(received.len() < sent);
+        received.clear();
+        peer_read.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_priority() {
+        use std::time::Duration;
+
+        use bytes::BytesMut;
+        use tokio::{
+            io::{duplex, AsyncReadExt, AsyncWriteExt},
+            spawn,
+            time::sleep,
+        };
+
+        use crate::{
+            limiter::Priority,
+            mux::{parse, Session, PSH},
+        };
+
+        let (client, mut server) = duplex(1024);
+        let client = Session::start(client, Default::default(), None, 60);
+        let mut bulk = client.open(Priority::Bulk).await.unwrap();
+        let sent = 1 << 20;
+        spawn(async move { bulk.write_all(&vec![0u8; sent]).await });
+        sleep(Duration::from_millis(50)).await;
+        let mut interactive = client.open(Priority::Interactive).await.unwrap();
+        interactive.write_all(b"ping").await.unwrap();
+
+        // the ping overtakes the bulk data queued before it
+        let mut buffer = BytesMut::new();
+        let mut bulk_received = 0;
+        loop {
+            match parse(&mut buffer).unwrap() {
+                Some((PSH, 3, data)) => {
+                    assert_eq!(data.as_ref(), b"ping");
+                    break;
+                }
+                Some((PSH, _, data)) => bulk_received += data.len(),
+                Some(_) => {}
+                None => assert!(server.read_buf(&mut buffer).await.unwrap() > 0),
+            }
+        }
+        assert!(bulk_received < sent / 2);
+    }
+}
@@ -0,0 +1,701 @@
+//! The --mux sessions of the mio based proxy and wintun modes, many streams over one TlsConn
+//! from the pool, with the frames of [`crate::mux`].
+//!
+//! Frames wait in the session until its TLS buffer takes them, and the streams stop writing
+//! while too many wait. Like in [`crate::mux`], a stream that doesn't read what the server
+//! sends is reset once it holds too much: the server gets a FIN and the reader an error.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io::{Error, ErrorKind, Read, Write},
+    rc::Rc,
+};
+
+use bytes::{Buf, BytesMut};
+use mio::{event::Event, Poll, Token};
+
+use crate::{
+    config::OPTIONS,
+    idle_pool::IdlePool,
+    mux::{encode, parse, FIN, MAX_DATA, NOP, PSH, SYN},
+    proto::{TrojanRequest, MUX},
+    resolver::DnsResolver,
+    status::{ConnStatus, StatusProvider},
+    tls_conn::TlsConn,
+    types::{Result, TrojanError},
+};
+
+/// Data buffered by the TLS session of a session connection.
+const SESSION_BUFFER: usize = 4 * MAX_DATA;
+/// Frames waiting for the TLS session, the streams are not writable while more do.
+const QUEUE_LIMIT: usize = 4 * MAX_DATA;
+/// Data a stream holds for its reader, one falling further behind is reset.
+const STREAM_BUFFER: usize = 16 * MAX_DATA;
+
+/// Server side of a proxied connection, a TlsConn of its own or a stream of a mux session.
+pub enum ServerConn {
+    Tls(Box<TlsConn>),
+    Mux(MuxStream),
+}
+
+/// The sessions of a mode, new streams go to the current one until it carries --mux streams.
+pub struct MuxSessions {
+    sessions: HashMap<Token, Rc<RefCell<Session>>>,
+    current: Option<Rc<RefCell<Session>>>,
+    channel_cnt: usize,
+    channel_mux: usize,
+    min_index: usize,
+    max_index: usize,
+    next_index: usize,
+}
+
+struct Session {
+    conn: TlsConn,
+    frames: Frames,
+}
+
+/// The frames and streams of a session, apart from its connection.
+#[derive(Default)]
+struct Frames {
+    streams: HashMap<u32, Stream>,
+    next_sid: u32,
+    /// frames read but not parsed yet
+    received: BytesMut,
+    /// frames not taken by the TLS session yet
+    queued: BytesMut,
+}
+
+struct Stream {
+    /// index of the connection the stream belongs to
+    index: usize,
+    data: BytesMut,
+    fin: bool,
+    /// reset for not reading, what the server sends after is discarded
+    reset: bool,
+}
+
+/// A stream of a mux session, the peer is sent FIN when it's shut down or dropped.
+pub struct MuxStream {
+    sid: u32,
+    session: Rc<RefCell<Session>>,
+    status: ConnStatus,
+}
+
+impl MuxSessions {
+    /// Session connections get the tokens of channel `channel_mux` like the IdlePool does.
+    pub fn new(channel_cnt: usize, channel_mux: usize, min_index: usize, max_index: usize) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            current: None,
+            channel_cnt,
+            channel_mux,
+            min_index,
+            max_index,
+            next_index: min_index,
+        }
+    }
+
+    fn next_index(&mut self) -> usize {
+        let index = self.next_index;
+        self.next_index += 1;
+        if self.next_index >= self.max_index {
+            self.next_index = self.min_index;
+        }
+        index
+    }
+
+    /// Opens a stream for the connection `index`, a new session is started on a connection of
+    /// the `pool` when there is none yet, it is closed or full.
+    pub fn open(
+        &mut self,
+        index: usize,
+        poll: &Poll,
+        pool: &mut IdlePool,
+        resolver: &DnsResolver,
+    ) -> Option<MuxStream> {
+        let session = match &self.current {
+            Some(session) if session.borrow().usable() => session.clone(),
+            _ => {
+                let mut conn = pool.get(poll, resolver)?;
+                let session_index = self.next_index();
+                let token = Token(session_index * self.channel_cnt + self.channel_mux);
+                if !conn.reset_index(session_index, token, poll) {
+                    conn.check_status(poll);
+                    return None;
+                }
+                // frames beyond it wait in the session, see Session::writable
+                conn.set_buffer_limit(Some(SESSION_BUFFER));
+                let mut request = BytesMut::new();
+                TrojanRequest::generate(&mut request, MUX, OPTIONS.empty_addr.as_ref().unwrap());
+                if !conn.write_session(request.as_ref()) {
+                    conn.check_status(poll);
+                    return None;
+                }
+                log::info!("new mux session:{} to server", session_index);
+                let session = Rc::new(RefCell::new(Session {
+                    conn,
+                    frames: Frames::new(),
+                }));
+                self.sessions.insert(token, session.clone());
+                self.current.replace(session.clone());
+                session
+            }
+        };
+        let sid = {
+            let mut session = session.borrow_mut();
+            let sid = session.frames.open(index);
+            session.do_send();
+            sid
+        };
+        Some(MuxStream {
+            sid,
+            session,
+            status: ConnStatus::Connecting,
+        })
+    }
+
+    /// Handles an event of a session connection, returns the streams to be woken up by the
+    /// index of their connection, whether they may have data or EOF to read and whether they may
+    /// write.
+    pub fn ready(&mut self, event: &Event, poll: &Poll) -> Vec<(usize, bool, bool)> {
+        let Some(session) = self.sessions.get(&event.token()).cloned() else {
+            log::error!("mux session token:{} not found", event.token().0);
+            return Vec::new();
+        };
+        let mut session = session.borrow_mut();
+        let mut woken = HashMap::new();
+        if event.is_readable() {
+            if let Some(data) = session.conn.do_read() {
+                session.frames.received.extend_from_slice(data.as_slice());
+            }
+            for index in session.dispatch() {
+                woken.insert(index, (true, false));
+            }
+        }
+        if event.is_writable() {
+            session.conn.established();
+        }
+        // the FIN of a reset goes out as well
+        session.do_send();
+        if event.is_writable() && session.writable() {
+            for stream in session.frames.streams.values() {
+                woken.entry(stream.index).or_insert((false, false)).1 = true;
+            }
+        }
+        if session.conn.is_shutdown() {
+            // they all read EOF now
+            for stream in session.frames.streams.values() {
+                woken.entry(stream.index).or_insert((false, false)).0 = true;
+            }
+        }
+        session.conn.check_status(poll);
+        drop(session);
+        self.remove_closed(poll);
+        woken
+            .into_iter()
+            .map(|(index, (readable, writable))| (index, readable, writable))
+            .collect()
+    }
+
+    /// Closes the sessions no stream is left on, but the current one, and forgets the closed.
+    pub fn remove_closed(&mut self, poll: &Poll) {
+        let current = self.current.clone();
+        self.sessions.retain(|_, session| {
+            let idle = !current
+                .as_ref()
+                .is_some_and(|current| Rc::ptr_eq(current, session));
+            let mut session = session.borrow_mut();
+            if idle && session.frames.streams.is_empty() {
+                session.conn.shutdown();
+            }
+            session.conn.check_status(poll);
+            !session.conn.deregistered()
+        });
+        if current.is_some_and(|current| current.borrow().conn.deregistered()) {
+            self.current.take();
+        }
+    }
+}
+
+impl Session {
+    /// Takes new streams until it carries --mux of them.
+    fn usable(&self) -> bool {
+        matches!(
+            self.conn.get_status(),
+            ConnStatus::Connecting | ConnStatus::Established
+        ) && self.frames.streams.len() < OPTIONS.mux
+    }
+
+    /// The streams may write while the connection may and not too many frames wait.
+    fn writable(&self) -> bool {
+        self.conn.writable() && self.frames.queued.len() < QUEUE_LIMIT
+    }
+
+    fn send(&mut self, cmd: u8, sid: u32, data: &[u8]) -> bool {
+        self.frames.send(cmd, sid, data);
+        self.do_send();
+        !self.conn.is_shutdown()
+    }
+
+    /// Moves the queued frames through the TLS session to the server until one of them blocks.
+    fn do_send(&mut self) {
+        while !self.conn.is_shutdown() {
+            self.conn.do_send();
+            let queued = self.frames.queued.len();
+            while !self.frames.queued.is_empty() {
+                match self.conn.write(self.frames.queued.as_ref()) {
+                    Ok(0) => break,
+                    Ok(n) => self.frames.queued.advance(n),
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                    Err(err) => {
+                        log::info!("mux session write failed:{}", err);
+                        self.conn.shutdown();
+                        return;
+                    }
+                }
+            }
+            if self.frames.queued.len() == queued {
+                break;
+            }
+        }
+    }
+
+    /// Sends FIN to the peer, the stream is forgotten.
+    fn close(&mut self, sid: u32) {
+        if self.frames.close(sid) && !self.conn.is_shutdown() {
+            self.do_send();
+        }
+    }
+
+    /// Hands the frames read to their streams, returns the indexes of those with news.
+    fn dispatch(&mut self) -> Vec<usize> {
+        match self.frames.dispatch() {
+            Ok(woken) => woken,
+            Err(err) => {
+                // the streams are woken by the shutdown
+                log::error!("mux session failed:{:?}", err);
+                self.conn.shutdown();
+                Vec::new()
+            }
+        }
+    }
+}
+
+impl Frames {
+    fn new() -> Self {
+        Self {
+            next_sid: 1,
+            ..Default::default()
+        }
+    }
+
+    fn open(&mut self, index: usize) -> u32 {
+        // odd ids for the opening side like smux
+        let sid = self.next_sid;
+        self.next_sid = self.next_sid.wrapping_add(2);
+        self.streams.insert(
+            sid,
+            Stream {
+                index,
+                data: BytesMut::new(),
+                fin: false,
+                reset: false,
+            },
+        );
+        self.send(SYN, sid, &[]);
+        sid
+    }
+
+    fn send(&mut self, cmd: u8, sid: u32, data: &[u8]) {
+        if data.is_empty() {
+            encode(&mut self.queued, cmd, sid, data);
+        }
+        for chunk in data.chunks(MAX_DATA) {
+            encode(&mut self.queued, cmd, sid, chunk);
+        }
+    }
+
+    /// Forgets a stream, true if the peer is to be sent FIN, which a reset one got already.
+    fn close(&mut self, sid: u32) -> bool {
+        match self.streams.remove(&sid) {
+            Some(stream) if !stream.reset => {
+                self.send(FIN, sid, &[]);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Hands the frames received to their streams, returns the indexes of those with news.
+    fn dispatch(&mut self) -> Result<Vec<usize>> {
+        let mut woken = Vec::new();
+        while let Some((cmd, sid, data)) = parse(&mut self.received)? {
+            match cmd {
+                PSH | FIN => {
+                    // the stream may be closed already, data is discarded then.
+                    let Some(stream) = self.streams.get_mut(&sid) else {
+                        continue;
+                    };
+                    if stream.reset {
+                        continue;
+                    }
+                    if cmd == FIN {
+                        stream.fin = true;
+                    } else if stream.data.len() + data.len() > STREAM_BUFFER {
+                        // waiting for it would hold back all the other streams of the session
+                        log::warn!("mux stream {} is not reading, reset it", sid);
+                        stream.reset = true;
+                        encode(&mut self.queued, FIN, sid, &[]);
+                    } else {
+                        stream.data.extend_from_slice(data.as_ref());
+                    }
+                    woken.push(stream.index);
+                }
+                NOP => {}
+                SYN => {
+                    log::error!("stream {} opened by server is refused", sid);
+                    return Err(TrojanError::Mux("stream opened by server"));
+                }
+                _ => {
+                    log::error!("invalid mux command:{}", cmd);
+                    return Err(TrojanError::Mux("invalid mux command"));
+                }
+            }
+        }
+        Ok(woken)
+    }
+
+    /// Takes all the data received for `sid`, with whether the stream is at its end.
+    fn take(&mut self, sid: u32, closed: bool) -> (Vec<u8>, bool) {
+        match self.streams.get_mut(&sid) {
+            Some(stream) => (
+                stream.data.split().to_vec(),
+                closed || stream.fin || stream.reset,
+            ),
+            None => (Vec::new(), true),
+        }
+    }
+
+    /// Reads the data received for `sid`, the end of a reset stream is an error.
+    fn read(&mut self, sid: u32, buf: &mut [u8], closed: bool) -> std::io::Result<usize> {
+        match self.streams.get_mut(&sid) {
+            Some(stream) if !stream.data.is_empty() => {
+                let n = buf.len().min(stream.data.len());
+                buf[..n].copy_from_slice(&stream.data[..n]);
+                stream.data.advance(n);
+                Ok(n)
+            }
+            Some(stream) if stream.reset => {
+                Err(Error::new(ErrorKind::ConnectionReset, "mux stream reset"))
+            }
+            Some(stream) if !stream.fin && !closed => Err(ErrorKind::WouldBlock.into()),
+            _ => Ok(0),
+        }
+    }
+}
+
+impl MuxStream {
+    /// Takes what the peer sent, the stream is shut down once it meets EOF.
+    pub fn do_read(&mut self) -> Option<Vec<u8>> {
+        let mut session = self.session.borrow_mut();
+        let closed = session.conn.is_shutdown();
+        let (data, eof) = session.frames.take(self.sid, closed);
+        drop(session);
+        if eof {
+            self.shutdown();
+        }
+        if data.is_empty() {
+            None
+        } else {
+            Some(data)
+        }
+    }
+
+    pub fn do_send(&mut self) {
+        self.session.borrow_mut().do_send();
+    }
+
+    pub fn write_session(&mut self, data: &[u8]) -> bool {
+        let sent = {
+            let mut session = self.session.borrow_mut();
+            !session.conn.is_shutdown()
+                && session.frames.streams.contains_key(&self.sid)
+                && session.send(PSH, self.sid, data)
+        };
+        if !sent {
+            log::info!("mux stream:{} write to session failed", self.sid);
+            self.shutdown();
+        }
+        sent
+    }
+
+    /// Writable while the session is.
+    pub fn writable(&self) -> bool {
+        self.alive() && self.session.borrow().writable()
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl StatusProvider for MuxStream {
+    fn set_status(&mut self, status: ConnStatus) {
+        self.status = status;
+    }
+
+    fn get_status(&self) -> ConnStatus {
+        self.status
+    }
+
+    fn close_conn(&mut self) -> bool {
+        self.session.borrow_mut().close(self.sid);
+        true
+    }
+
+    fn deregister(&mut self, _: &Poll) -> bool {
+        true
+    }
+
+    fn finish_send(&mut self) -> bool {
+        // FIN follows the data in the session anyway
+        true
+    }
+}
+
+impl Read for MuxStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut session = self.session.borrow_mut();
+        let closed = session.conn.is_shutdown();
+        session.frames.read(self.sid, buf, closed)
+    }
+}
+
+impl Write for MuxStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut session = self.session.borrow_mut();
+        if session.conn.is_shutdown() || !session.frames.streams.contains_key(&self.sid) {
+            return Err(ErrorKind::BrokenPipe.into());
+        }
+        // blocked as well while the session is connecting
+        if !session.writable() {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        if !session.send(PSH, self.sid, buf) {
+            return Err(ErrorKind::BrokenPipe.into());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let mut session = self.session.borrow_mut();
+        session.do_send();
+        if session.conn.is_shutdown() {
+            Err(ErrorKind::BrokenPipe.into())
+        } else if !session.writable() {
+            Err(ErrorKind::WouldBlock.into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl ServerConn {
+    pub fn do_read(&mut self) -> Option<Vec<u8>> {
+        match self {
+            ServerConn::Tls(conn) => conn.do_read(),
+            ServerConn::Mux(stream) => stream.do_read(),
+        }
+    }
+
+    pub fn do_send(&mut self) {
+        match self {
+            ServerConn::Tls(conn) => conn.do_send(),
+            ServerConn::Mux(stream) => stream.do_send(),
+        }
+    }
+
+    pub fn write_session(&mut self, data: &[u8]) -> bool {
+        match self {
+            ServerConn::Tls(conn) => conn.write_session(data),
+            ServerConn::Mux(stream) => stream.write_session(data),
+        }
+    }
+
+    pub fn writable(&self) -> bool {
+        match self {
+            ServerConn::Tls(conn) => conn.writable(),
+            ServerConn::Mux(stream) => stream.writable(),
+        }
+    }
+}
+
+impl StatusProvider for ServerConn {
+    fn set_status(&mut self, status: ConnStatus) {
+        match self {
+            ServerConn::Tls(conn) => conn.set_status(status),
+            ServerConn::Mux(stream) => stream.set_status(status),
+        }
+    }
+
+    fn get_status(&self) -> ConnStatus {
+        match self {
+            ServerConn::Tls(conn) => conn.get_status(),
+            ServerConn::Mux(stream) => stream.get_status(),
+        }
+    }
+
+    fn close_conn(&mut self) -> bool {
+        match self {
+            ServerConn::Tls(conn) => conn.close_conn(),
+            ServerConn::Mux(stream) => stream.close_conn(),
+        }
+    }
+
+    fn deregister(&mut self, poll: &Poll) -> bool {
+        match self {
+            ServerConn::Tls(conn) => StatusProvider::deregister(conn.as_mut(), poll),
+            ServerConn::Mux(stream) => stream.deregister(poll),
+        }
+    }
+
+    fn finish_send(&mut self) -> bool {
+        match self {
+            ServerConn::Tls(conn) => conn.finish_send(),
+            ServerConn::Mux(stream) => stream.finish_send(),
+        }
+    }
+}
+
+impl Read for ServerConn {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            ServerConn::Tls(conn) => conn.read(buf),
+            ServerConn::Mux(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for ServerConn {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            ServerConn::Tls(conn) => conn.write(buf),
+            ServerConn::Mux(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            ServerConn::Tls(conn) => conn.flush(),
+            ServerConn::Mux(stream) => stream.flush(),
+        }
+    }
+}
+
+impl relay::Sink for ServerConn {
+    fn push(&mut self, data: &[u8]) -> bool {
+        if !self.write_session(data) {
+            return false;
+        }
+        self.do_send();
+        true
+    }
+
+    fn blocked(&self) -> bool {
+        !self.writable()
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_frames() {
+        use std::io::ErrorKind;
+
+        use bytes::Bytes;
+
+        use crate::{
+            mux::{encode, parse, FIN, PSH, SYN},
+            mux_conn::Frames,
+        };
+
+        // streams get odd ids, each announced with a SYN
+        let mut frames = Frames::new();
+        assert_eq!(frames.open(7), 1);
+        assert_eq!(frames.open(8), 3);
+        let frame = parse(&mut frames.queued).unwrap().unwrap();
+        assert_eq!(frame, (SYN, 1, Bytes::new()));
+        let frame = parse(&mut frames.queued).unwrap().unwrap();
+        assert_eq!(frame, (SYN, 3, Bytes::new()));
+
+        // data goes to its stream, that of unknown ones is dropped
+        let mut buf = [0u8; 16];
+        let err = frames.read(1, &mut buf, false).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        encode(&mut frames.received, PSH, 3, b"hello");
+        encode(&mut frames.received, PSH, 5, b"unknown");
+        assert_eq!(frames.dispatch().unwrap(), vec![8]);
+        assert_eq!(frames.read(3, &mut buf, false).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+
+        // a FIN ends the stream after its data
+        encode(&mut frames.received, PSH, 1, b"bye");
+        encode(&mut frames.received, FIN, 1, &[]);
+        assert_eq!(frames.dispatch().unwrap(), vec![7, 7]);
+        assert_eq!(frames.take(1, false), (b"bye".to_vec(), true));
+        assert_eq!(frames.read(1, &mut buf, false).unwrap(), 0);
+
+        // closing a stream sends a FIN, closing the session ends the others
+        assert!(frames.close(1));
+        let frame = parse(&mut frames.queued).unwrap().unwrap();
+        assert_eq!(frame, (FIN, 1, Bytes::new()));
+        assert_eq!(frames.read(3, &mut buf, true).unwrap(), 0);
+
+        // the server may not open streams
+        encode(&mut frames.received, SYN, 2, &[]);
+        assert!(frames.dispatch().is_err());
+    }
+
+    #[test]
+    fn test_stalled_stream() {
+        use std::io::ErrorKind;
+
+        use bytes::Bytes;
+
+        use crate::{
+            mux::{encode, parse, FIN, MAX_DATA, PSH},
+            mux_conn::{Frames, STREAM_BUFFER},
+        };
+
+        let mut frames = Frames::new();
+        let stalled = frames.open(1);
+        let other = frames.open(2);
+        frames.queued.clear();
+        for _ in 0..STREAM_BUFFER / MAX_DATA + 2 {
+            encode(&mut frames.received, PSH, stalled, &[0u8; MAX_DATA]);
+        }
+        encode(&mut frames.received, PSH, other, b"hello");
+        frames.dispatch().unwrap();
+
+        // the stream holding too much is reset, the server gets a FIN and the others go on
+        let frame = parse(&mut frames.queued).unwrap().unwrap();
+        assert_eq!(frame, (FIN, stalled, Bytes::new()));
+        let mut buf = vec![0u8; MAX_DATA];
+        assert_eq!(frames.read(other, &mut buf, false).unwrap(), 5);
+
+        // what it held is read first, then the error
+        let mut received = 0;
+        let err = loop {
+            match frames.read(stalled, &mut buf, false) {
+                Ok(n) => received += n,
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+        assert_eq!(received, STREAM_BUFFER);
+        assert!(!frames.close(stalled));
+        assert!(frames.queued.is_empty());
+    }
+}
@@ -0,0 +1,93 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+
+use crate::config::OPTIONS;
+
+/// Name with only A records, the DNS64 resolver of the network answers its AAAA query with
+/// them synthesized under the NAT64 prefix, RFC 7050.
+const IPV4ONLY_ARPA: &str = "ipv4only.arpa";
+/// The well-known addresses of `ipv4only.arpa`.
+const IPV4ONLY_ADDRS: [Ipv4Addr; 2] =
+    [Ipv4Addr::new(192, 0, 0, 170), Ipv4Addr::new(192, 0, 0, 171)];
+
+/// Returns the NAT64 prefix of the network, looked up through DNS64 if the host has no ipv4
+/// route. Only /96 prefixes are supported.
+pub fn discover() -> Option<Ipv6Addr> {
+    if has_ipv4_route() {
+        return None;
+    }
+    let addrs = match dns_lookup::lookup_host(IPV4ONLY_ARPA) {
+        Ok(addrs) => addrs,
+        Err(err) => {
+            log::warn!("no ipv4 route, and lookup {} failed:{}", IPV4ONLY_ARPA, err);
+            return None;
+        }
+    };
+    let prefix = prefix_of(addrs.as_slice());
+    match prefix {
+        Some(prefix) => log::info!("no ipv4 route, NAT64 prefix {} found", prefix),
+        None => log::warn!("no ipv4 route, and the network has no DNS64"),
+    }
+    prefix
+}
+
+/// The prefix some address of `ipv4only.arpa` is synthesized under.
+fn prefix_of(addrs: &[IpAddr]) -> Option<Ipv6Addr> {
+    addrs.iter().find_map(|addr| match addr {
+        IpAddr::V6(v6) => {
+            let octets = v6.octets();
+            let embedded = Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]);
+            IPV4ONLY_ADDRS
+                .contains(&embedded)
+                .then(|| synthesize(*v6, Ipv4Addr::UNSPECIFIED))
+        }
+        IpAddr::V4(_) => None,
+    })
+}
+
+/// Connecting a udp socket sends nothing, it fails if there's no route.
+fn has_ipv4_route() -> bool {
+    UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .and_then(|socket| socket.connect((Ipv4Addr::new(8, 8, 8, 8), 53)))
+        .is_ok()
+}
+
+/// The ipv6 address `v4` is reached at through NAT64 with `prefix`.
+pub fn synthesize(prefix: Ipv6Addr, v4: Ipv4Addr) -> Ipv6Addr {
+    let mut octets = prefix.octets();
+    octets[12..].copy_from_slice(&v4.octets());
+    octets.into()
+}
+
+/// Maps ipv4 addresses through NAT64 if the network needs it, others are returned as is.
+pub fn translate(addr: SocketAddr) -> SocketAddr {
+    match (OPTIONS.nat64_prefix, addr) {
+        (Some(prefix), SocketAddr::V4(v4)) => {
+            SocketAddr::new(synthesize(prefix, *v4.ip()).into(), v4.port())
+        }
+        _ => addr,
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_nat64_prefix() {
+        use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+        use super::{prefix_of, synthesize};
+
+        let wka: Ipv6Addr = "64:ff9b::".parse().unwrap();
+        let prefix: Ipv6Addr = "2001:db8:64::".parse().unwrap();
+        assert_eq!(
+            synthesize(wka, Ipv4Addr::new(192, 0, 2, 33)),
+            "64:ff9b::c000:221".parse::<Ipv6Addr>().unwrap()
+        );
+
+        let answers: Vec<IpAddr> = vec![
+            Ipv4Addr::new(192, 0, 0, 170).into(),
+            "2001:db8:1::1".parse().unwrap(),
+            "2001:db8:64::c000:ab".parse().unwrap(),
+        ];
+        assert_eq!(prefix_of(&answers[..2]), None);
+        assert_eq!(prefix_of(answers.as_slice()), Some(prefix));
+    }
+}
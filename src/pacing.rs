@@ -0,0 +1,303 @@
+//! BBR style pacing of the uploads into the tunnel, so they don't fill the buffer of the uplink.
+//! The bottleneck bandwidth and round trip time are taken from the kernel's measurements of the
+//! connections to the server.
+
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::{atomic::Ordering, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::net::TcpStream;
+
+use crate::{config::OPTIONS, limiter::PACER, metrics::COUNTERS, sys};
+
+/// Bandwidth samples are kept for this many rounds, the estimate is the highest of them.
+const BW_ROUNDS: u32 = 10;
+/// Rounds at most spent draining, in case the queue never gets down to the minimum round trip.
+const DRAIN_ROUNDS: u32 = 3;
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+/// 2/ln2, doubles the sending rate every round trip until the bandwidth stops growing.
+const STARTUP_GAIN: f64 = 2.885;
+/// Probes for more bandwidth for a round trip, drains the queue built by it in the next.
+const PROBE_GAINS: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Phase {
+    Startup,
+    Drain,
+    ProbeBandwidth,
+}
+
+impl Phase {
+    pub fn name(index: u64) -> &'static str {
+        match index {
+            0 => "startup",
+            1 => "drain",
+            _ => "probe bandwidth",
+        }
+    }
+}
+
+/// Sample of the connections which got data acknowledged since the last one.
+pub struct Sample {
+    /// sum of their delivery rates in bytes per second
+    pub bandwidth: u64,
+    pub app_limited: bool,
+    pub min_rtt: Duration,
+    pub rtt: Duration,
+}
+
+pub struct Estimator {
+    bandwidths: VecDeque<(Instant, u64)>,
+    min_rtt: Duration,
+    rtt: Duration,
+    phase: Phase,
+    /// bandwidth of the last round trip in startup that grew by a quarter
+    full_bandwidth: u64,
+    /// rounds in startup without growth, or in drain
+    rounds: u32,
+    round_start: Instant,
+    cycle: usize,
+}
+
+impl Estimator {
+    pub fn new(now: Instant) -> Estimator {
+        Estimator {
+            bandwidths: VecDeque::new(),
+            min_rtt: Duration::ZERO,
+            rtt: Duration::ZERO,
+            phase: Phase::Startup,
+            full_bandwidth: 0,
+            rounds: 0,
+            round_start: now,
+            cycle: 0,
+        }
+    }
+
+    pub fn bandwidth(&self) -> u64 {
+        self.bandwidths
+            .iter()
+            .map(|(_, bandwidth)| *bandwidth)
+            .max()
+            .unwrap_or_default()
+    }
+
+    pub fn min_rtt(&self) -> Duration {
+        self.min_rtt
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// Bytes per second to send at, 0 until the bandwidth is known.
+    pub fn pacing_rate(&self) -> u64 {
+        let gain = match self.phase {
+            Phase::Startup => STARTUP_GAIN,
+            Phase::Drain => 1.0 / STARTUP_GAIN,
+            Phase::ProbeBandwidth => PROBE_GAINS[self.cycle],
+        };
+        (self.bandwidth() as f64 * gain) as u64
+    }
+
+    /// A round trip, at least the sampling interval.
+    fn round(&self) -> Duration {
+        self.min_rtt.max(SAMPLE_INTERVAL)
+    }
+
+    pub fn update(&mut self, now: Instant, sample: Option<Sample>) {
+        let window = self.round() * BW_ROUNDS;
+        while matches!(self.bandwidths.front(), Some((time, _)) if now - *time > window) {
+            self.bandwidths.pop_front();
+        }
+        if let Some(sample) = sample {
+            self.min_rtt = sample.min_rtt;
+            self.rtt = sample.rtt;
+            // the sender was short of data, a lower rate says nothing about the path
+            if !sample.app_limited || sample.bandwidth >= self.bandwidth() {
+                self.bandwidths.push_back((now, sample.bandwidth));
+            }
+        }
+        if self.bandwidths.is_empty() {
+            // idle for the whole window, start over like a new connection
+            *self = Estimator::new(now);
+            return;
+        }
+        if now - self.round_start < self.round() {
+            return;
+        }
+        self.round_start = now;
+        match self.phase {
+            Phase::Startup => {
+                let bandwidth = self.bandwidth();
+                if bandwidth as f64 >= self.full_bandwidth as f64 * 1.25 {
+                    self.full_bandwidth = bandwidth;
+                    self.rounds = 0;
+                } else {
+                    self.rounds += 1;
+                    if self.rounds >= 3 {
+                        self.phase = Phase::Drain;
+                        self.rounds = 0;
+                    }
+                }
+            }
+            Phase::Drain => {
+                // the queue built in startup is gone once the round trip is back to its minimum
+                self.rounds += 1;
+                if self.rtt.as_secs_f64() <= self.min_rtt.as_secs_f64() * 1.25
+                    || self.rounds >= DRAIN_ROUNDS
+                {
+                    self.phase = Phase::ProbeBandwidth;
+                    self.cycle = 2;
+                }
+            }
+            Phase::ProbeBandwidth => {
+                self.cycle = (self.cycle + 1) % PROBE_GAINS.len();
+            }
+        }
+    }
+}
+
+struct Watched {
+    socket: sys::SocketId,
+    peer: SocketAddr,
+    bytes_acked: u64,
+}
+
+static CONNECTIONS: Mutex<Vec<Watched>> = Mutex::new(Vec::new());
+
+/// Measures the connection to the server from now on if --pacing.
+pub fn watch(stream: &TcpStream) {
+    if !OPTIONS.pacing {
+        return;
+    }
+    if let Ok(peer) = stream.peer_addr() {
+        CONNECTIONS.lock().unwrap().push(Watched {
+            socket: sys::socket_id(stream),
+            peer,
+            bytes_acked: 0,
+        });
+    }
+}
+
+/// Connections closed are dropped, those without data acknowledged are left out.
+fn sample() -> Option<Sample> {
+    let mut connections = CONNECTIONS.lock().unwrap();
+    let mut sample: Option<Sample> = None;
+    let mut rtt_sum = Duration::ZERO;
+    let mut sending = 0;
+    connections.retain_mut(|watched| {
+        let Ok(info) = sys::tcp_info(watched.socket, watched.peer) else {
+            return false;
+        };
+        let acked = info.bytes_acked > watched.bytes_acked;
+        watched.bytes_acked = info.bytes_acked;
+        if acked && info.delivery_rate > 0 {
+            let sample = sample.get_or_insert(Sample {
+                bandwidth: 0,
+                app_limited: true,
+                min_rtt: info.min_rtt,
+                rtt: Duration::ZERO,
+            });
+            sample.bandwidth += info.delivery_rate;
+            sample.app_limited &= info.app_limited;
+            sample.min_rtt = sample.min_rtt.min(info.min_rtt);
+            rtt_sum += info.rtt;
+            sending += 1;
+        }
+        true
+    });
+    if let Some(sample) = sample.as_mut() {
+        sample.rtt = rtt_sum / sending;
+    }
+    sample
+}
+
+/// Adjusts the pacing rate from the measurements of the connections until exit.
+pub async fn run() {
+    let mut estimator = Estimator::new(Instant::now());
+    let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+    loop {
+        interval.tick().await;
+        estimator.update(Instant::now(), sample());
+        let rate = estimator.pacing_rate();
+        PACER.adjust_rate(rate);
+        COUNTERS.pacing_rate.store(rate, Ordering::Relaxed);
+        COUNTERS
+            .bottleneck_bw
+            .store(estimator.bandwidth(), Ordering::Relaxed);
+        COUNTERS
+            .min_rtt_us
+            .store(estimator.min_rtt().as_micros() as u64, Ordering::Relaxed);
+        COUNTERS
+            .pacing_phase
+            .store(estimator.phase() as u64, Ordering::Relaxed);
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_estimator() {
+        use std::time::{Duration, Instant};
+
+        use super::{Estimator, Phase, Sample};
+
+        let sample = |bandwidth, app_limited, rtt| {
+            Some(Sample {
+                bandwidth,
+                app_limited,
+                min_rtt: Duration::from_millis(20),
+                rtt: Duration::from_millis(rtt),
+            })
+        };
+        let round = Duration::from_millis(100);
+        let mut now = Instant::now();
+        let mut estimator = Estimator::new(now);
+        assert_eq!(estimator.pacing_rate(), 0);
+
+        // grows through startup until the bandwidth stays for three rounds
+        for bandwidth in [1000, 2000, 4000, 4000, 4000] {
+            now += round;
+            estimator.update(now, sample(bandwidth, false, 80));
+            assert_eq!(estimator.phase(), Phase::Startup);
+        }
+        now += round;
+        estimator.update(now, sample(4000, false, 80));
+        assert_eq!(estimator.phase(), Phase::Drain);
+        assert_eq!(estimator.pacing_rate(), (4000.0 / 2.885) as u64);
+
+        // app limited samples don't lower the estimate
+        now += round;
+        estimator.update(now, sample(100, true, 22));
+        assert_eq!(estimator.phase(), Phase::ProbeBandwidth);
+        assert_eq!(estimator.bandwidth(), 4000);
+        assert_eq!(estimator.pacing_rate(), 4000);
+        // probes above the estimate once in eight rounds, then drains below it
+        for _ in 0..6 {
+            now += round;
+            estimator.update(now, None);
+        }
+        assert_eq!(estimator.pacing_rate(), 5000);
+        now += round;
+        estimator.update(now, None);
+        assert_eq!(estimator.pacing_rate(), 3000);
+
+        // nothing sent for the whole window
+        now += Duration::from_secs(11);
+        estimator.update(now, None);
+        assert_eq!(estimator.phase(), Phase::Startup);
+        assert_eq!(estimator.pacing_rate(), 0);
+
+        // the round trip never gets back to the minimum
+        for _ in 0..6 {
+            now += round;
+            estimator.update(now, sample(1000, false, 80));
+        }
+        assert_eq!(estimator.phase(), Phase::Drain);
+        now += round;
+        estimator.update(now, sample(1000, false, 80));
+        assert_eq!(estimator.phase(), Phase::ProbeBandwidth);
+    }
+}
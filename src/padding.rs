@@ -0,0 +1,175 @@
+//! Padding of the connections to the server against packet length fingerprinting.
+//!
+//! A client opens the connection with the `PADDED` command, after that both sides exchange
+//! frames padded up to one of their `--padding-buckets` sizes:
+//!
+//! `data length(u16 be) | padding length(u16 be) | data | padding`
+//!
+//! Frames without data are dummies sent while the connection is idle, they are dropped. The
+//! data carries a regular trojan request, so the server handles it the same way as a
+//! connection of its own.
+
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, BufMut, BytesMut};
+use rand::Rng;
+use tokio::{
+    io::{duplex, split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream},
+    spawn,
+    time::timeout,
+};
+
+use crate::{
+    config::OPTIONS,
+    proto::{TrojanRequest, PADDED},
+};
+
+const HEADER_LEN: usize = 4;
+/// Bytes buffered in each direction.
+const STREAM_BUFFER: usize = 65536;
+/// Dummy frames are sent for this long after the last data only, idle connections stay quiet.
+const DUMMY_WINDOW: Duration = Duration::from_secs(10);
+
+/// Opens a padded connection over `conn`, the request goes out with the first frame.
+pub fn open<S>(conn: S) -> DuplexStream
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let mut request = BytesMut::new();
+    TrojanRequest::generate(&mut request, PADDED, OPTIONS.empty_addr.as_ref().unwrap());
+    start(conn, BytesMut::new(), request)
+}
+
+/// Runs a padded connection over `conn`, `data` is what has been read from it already and
+/// `prefix` is sent ahead of the first frame.
+pub fn start<S>(conn: S, data: BytesMut, prefix: BytesMut) -> DuplexStream
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (stream, inner) = duplex(STREAM_BUFFER);
+    let (read, write) = split(conn);
+    let (inner_read, inner_write) = split(inner);
+    spawn(pad(inner_read, write, prefix));
+    spawn(unpad(read, data, inner_write));
+    stream
+}
+
+/// Smallest bucket a frame of `size` bytes of data fits in, `buckets` are sorted.
+fn bucket(size: usize, buckets: &[u16]) -> usize {
+    buckets
+        .iter()
+        .map(|bucket| *bucket as usize)
+        .find(|bucket| *bucket >= size + HEADER_LEN)
+        .unwrap_or(size + HEADER_LEN)
+}
+
+fn encode(buffer: &mut BytesMut, data: &[u8], bucket: usize) {
+    let padding = bucket - HEADER_LEN - data.len();
+    buffer.put_u16(data.len() as u16);
+    buffer.put_u16(padding as u16);
+    buffer.extend_from_slice(data);
+    buffer.resize(buffer.len() + padding, 0);
+}
+
+/// Takes the data of a frame from the front of `buffer`, `None` if it is not complete yet.
+fn parse(buffer: &mut BytesMut) -> Option<BytesMut> {
+    if buffer.len() < HEADER_LEN {
+        return None;
+    }
+    let len = u16::from_be_bytes([buffer[0], buffer[1]]) as usize;
+    let padding = u16::from_be_bytes([buffer[2], buffer[3]]) as usize;
+    if buffer.len() < HEADER_LEN + len + padding {
+        return None;
+    }
+    buffer.advance(HEADER_LEN);
+    let data = buffer.split_to(len);
+    buffer.advance(padding);
+    Some(data)
+}
+
+async fn pad<R, W>(mut read: R, mut write: W, mut frame: BytesMut)
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let buckets = OPTIONS.padding_buckets.as_slice();
+    let mut data = vec![0u8; *buckets.last().unwrap() as usize - HEADER_LEN];
+    let mut last_data = Instant::now();
+    loop {
+        let read = if OPTIONS.padding_idle > 0 && last_data.elapsed() < DUMMY_WINDOW {
+            let wait = rand::thread_rng().gen_range(1..=OPTIONS.padding_idle);
+            timeout(Duration::from_millis(wait), read.read(data.as_mut_slice())).await
+        } else {
+            Ok(read.read(data.as_mut_slice()).await)
+        };
+        match read {
+            Ok(Ok(0)) | Ok(Err(_)) => break,
+            Ok(Ok(n)) => {
+                last_data = Instant::now();
+                encode(&mut frame, &data[..n], bucket(n, buckets));
+            }
+            Err(_) => {
+                let dummy = buckets[rand::thread_rng().gen_range(0..buckets.len())];
+                encode(&mut frame, &[], dummy as usize);
+            }
+        }
+        let written = match write.write_all(frame.as_ref()).await {
+            Ok(_) => write.flush().await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = written {
+            log::error!("write padded frame failed:{}", err);
+            break;
+        }
+        frame.clear();
+    }
+    let _ = write.shutdown().await;
+}
+
+async fn unpad<R, W>(mut read: R, mut buffer: BytesMut, mut write: W)
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    'main: loop {
+        while let Some(data) = parse(&mut buffer) {
+            if !data.is_empty() && write.write_all(data.as_ref()).await.is_err() {
+                break 'main;
+            }
+        }
+        match read.read_buf(&mut buffer).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+    }
+    let _ = write.shutdown().await;
+}
+
+mod tests {
+    #[test]
+    fn test_frames() {
+        use bytes::BytesMut;
+
+        use super::{bucket, encode, parse};
+
+        let buckets = [64, 512, 4096];
+        assert_eq!(bucket(0, &buckets), 64);
+        assert_eq!(bucket(60, &buckets), 64);
+        assert_eq!(bucket(61, &buckets), 512);
+        assert_eq!(bucket(4092, &buckets), 4096);
+
+        let mut buffer = BytesMut::new();
+        encode(&mut buffer, b"hello", bucket(5, &buckets));
+        encode(&mut buffer, b"", 512);
+        encode(&mut buffer, b"world", bucket(5, &buckets));
+        assert_eq!(buffer.len(), 64 + 512 + 64);
+
+        let mut partial = buffer.split_to(63);
+        assert!(parse(&mut partial).is_none());
+        partial.unsplit(buffer);
+        assert_eq!(parse(&mut partial).unwrap().as_ref(), b"hello");
+        assert!(parse(&mut partial).unwrap().is_empty());
+        assert_eq!(parse(&mut partial).unwrap().as_ref(), b"world");
+        assert!(partial.is_empty());
+    }
+}
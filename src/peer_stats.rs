@@ -0,0 +1,284 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::{runtime::Runtime, time::timeout};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{client::IntoClientRequest, Message},
+};
+
+use crate::{
+    config::{Mode, OPTIONS},
+    memory::{self, MemoryStats},
+    metrics::COUNTERS,
+    pacing::Phase,
+    types::{Result, TrojanError},
+};
+
+/// Summary of the tunnel to the trojan server, like `wg show` does for a peer.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PeerStats {
+    /// server as configured, like example.com:443
+    pub server: String,
+    /// resolved server address
+    pub endpoint: Option<String>,
+    /// seconds since the client started
+    pub uptime: u64,
+    /// seconds since the last tls handshake with the server
+    pub last_handshake: Option<u64>,
+    pub rtt_ms: Option<u64>,
+    /// failed reconnect attempts in a row
+    #[serde(default)]
+    pub reconnect_failures: u64,
+    /// milliseconds until the next reconnect attempt
+    #[serde(default)]
+    pub retry_in_ms: Option<u64>,
+    /// idle connections kept to the server, 0 if the pool is disabled
+    pub pool_size: usize,
+    pub connected: u64,
+    pub failed: u64,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub active_flows: u64,
+    /// estimator of --pacing, None if disabled
+    #[serde(default)]
+    pub pacing: Option<PacingStats>,
+    #[serde(default)]
+    pub memory: Option<MemoryStats>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PacingStats {
+    pub phase: String,
+    /// bytes per second, 0 until the bandwidth is measured
+    pub rate: u64,
+    pub bottleneck_bw: u64,
+    pub min_rtt_us: u64,
+}
+
+impl PeerStats {
+    pub fn collect(uptime: Duration) -> PeerStats {
+        let (server, pool_size) = match &OPTIONS.mode {
+            Mode::Proxy(args) | Mode::Aproxy(args) => {
+                (format!("{}:{}", args.hostname, args.port), args.pool_size)
+            }
+            Mode::Wintun(args) | Mode::Awintun(args) => {
+                (format!("{}:{}", args.hostname, args.port), args.pool_size)
+            }
+            _ => (String::new(), 0),
+        };
+        let counters = COUNTERS.snapshot();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_millis() as u64)
+            .unwrap_or_default();
+        PeerStats {
+            server,
+            endpoint: OPTIONS.back_addr.map(|addr| addr.to_string()),
+            uptime: uptime.as_secs(),
+            last_handshake: (counters.last_handshake > 0)
+                .then(|| now.saturating_sub(counters.last_handshake) / 1000),
+            rtt_ms: (counters.server_connected > 0).then_some(counters.rtt_ms),
+            reconnect_failures: counters.reconnect_failures,
+            retry_in_ms: (counters.retry_at > 0).then(|| counters.retry_at.saturating_sub(now)),
+            pool_size,
+            connected: counters.server_connected,
+            failed: counters.server_failed,
+            rx_bytes: counters.rx_bytes,
+            tx_bytes: counters.tx_bytes,
+            active_flows: counters.active_flows,
+            pacing: OPTIONS.pacing.then(|| PacingStats {
+                phase: Phase::name(counters.pacing_phase).into(),
+                rate: counters.pacing_rate,
+                bottleneck_bw: counters.bottleneck_bw,
+                min_rtt_us: counters.min_rtt_us,
+            }),
+            memory: Some(memory::stats()),
+        }
+    }
+
+    /// Human readable form, in the style of `wg show`.
+    pub fn format(&self) -> String {
+        let mut lines = vec![format!("server: {}", self.server)];
+        if let Some(endpoint) = &self.endpoint {
+            lines.push(format!("  endpoint: {}", endpoint));
+        }
+        lines.push(format!("  uptime: {}", human_duration(self.uptime)));
+        lines.push(format!(
+            "  latest handshake: {}",
+            self.last_handshake
+                .map(|secs| format!("{} ago", human_duration(secs)))
+                .unwrap_or_else(|| "none".into())
+        ));
+        if let Some(rtt) = self.rtt_ms {
+            lines.push(format!("  rtt: {} ms", rtt));
+        }
+        if let Some(retry_in) = self.retry_in_ms.filter(|_| self.reconnect_failures > 0) {
+            lines.push(format!(
+                "  reconnecting: {} attempts failed, next in {}",
+                self.reconnect_failures,
+                human_duration(retry_in.div_ceil(1000))
+            ));
+        }
+        lines.push(format!(
+            "  connections: {} established, {} failed, pool size {}",
+            self.connected, self.failed, self.pool_size
+        ));
+        lines.push(format!(
+            "  transfer: {} received, {} sent",
+            human_bytes(self.rx_bytes),
+            human_bytes(self.tx_bytes)
+        ));
+        lines.push(format!("  active flows: {}", self.active_flows));
+        if let Some(pacing) = self.pacing.as_ref() {
+            if pacing.rate == 0 {
+                lines.push("  pacing: not measured yet".into());
+            } else {
+                lines.push(format!(
+                    "  pacing: {}/s in {}, bottleneck {}/s, min rtt {:.1} ms",
+                    human_bytes(pacing.rate),
+                    pacing.phase,
+                    human_bytes(pacing.bottleneck_bw),
+                    pacing.min_rtt_us as f64 / 1000.0
+                ));
+            }
+        }
+        lines.join("\n")
+    }
+}
+
+/// Memory used by each subsystem, against the limit.
+fn format_memory(memory: &MemoryStats) -> String {
+    let mut lines = vec![if memory.limit == 0 {
+        format!("memory: {} used, unlimited", human_bytes(memory.used))
+    } else {
+        format!(
+            "memory: {} used of {}",
+            human_bytes(memory.used),
+            human_bytes(memory.limit)
+        )
+    }];
+    for subsystem in &memory.subsystems {
+        lines.push(format!(
+            "  {}: {} in {} sessions, {} refused",
+            subsystem.name,
+            human_bytes(subsystem.used),
+            subsystem.sessions,
+            subsystem.refused
+        ));
+    }
+    lines.join("\n")
+}
+
+fn human_duration(secs: u64) -> String {
+    let units = [
+        (86400, "day"),
+        (3600, "hour"),
+        (60, "minute"),
+        (1, "second"),
+    ];
+    let mut parts = Vec::new();
+    let mut left = secs;
+    for (size, name) in units {
+        let count = left / size;
+        left %= size;
+        if count > 0 {
+            parts.push(format!(
+                "{} {}{}",
+                count,
+                name,
+                if count > 1 { "s" } else { "" }
+            ));
+        }
+    }
+    if parts.is_empty() {
+        "0 seconds".into()
+    } else {
+        parts.join(", ")
+    }
+}
+
+fn human_bytes(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.2} {}", value, units[unit])
+    }
+}
+
+async fn query(addr: &str, token: &str) -> Result<PeerStats> {
+    // in a header the token needs no escaping
+    let mut request = format!("ws://{}/", addr)
+        .into_client_request()
+        .map_err(|_| TrojanError::Status("invalid event stream address"))?;
+    let authorization = format!("Bearer {}", token)
+        .parse()
+        .map_err(|_| TrojanError::Status("invalid event stream token"))?;
+    request.headers_mut().insert("Authorization", authorization);
+    let (mut ws, _) = connect_async(request)
+        .await
+        .map_err(|_| TrojanError::Status("connect event stream failed"))?;
+    ws.send(Message::Text(r#"{"command":"status"}"#.into()))
+        .await
+        .map_err(|_| TrojanError::Status("send status command failed"))?;
+    // other events are streamed too, wait for the reply
+    while let Some(Ok(message)) = ws.next().await {
+        let Message::Text(text) = message else {
+            continue;
+        };
+        let mut value: serde_json::Value = serde_json::from_str(text.as_str())?;
+        if value["event"] == "status" {
+            value.as_object_mut().unwrap().remove("event");
+            return Ok(serde_json::from_value(value)?);
+        }
+    }
+    Err(TrojanError::Status("event stream closed"))
+}
+
+pub fn run() -> Result<()> {
+    let args = OPTIONS.status_args();
+    let runtime = Runtime::new()?;
+    let stats = runtime.block_on(async {
+        timeout(
+            Duration::from_secs(5),
+            query(args.events_addr.as_str(), OPTIONS.events_token.as_str()),
+        )
+        .await
+    })??;
+    if args.memory {
+        let memory = stats
+            .memory
+            .ok_or(TrojanError::Status("the client reports no memory usage"))?;
+        if args.json {
+            println!("{}", serde_json::to_string_pretty(&memory)?);
+        } else {
+            println!("{}", format_memory(&memory));
+        }
+    } else if args.json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+    } else {
+        println!("{}", stats.format());
+    }
+    Ok(())
+}
+
+mod tests {
+    #[test]
+    fn test_format() {
+        use crate::peer_stats::{human_bytes, human_duration};
+
+        assert_eq!(human_duration(0), "0 seconds");
+        assert_eq!(human_duration(3725), "1 hour, 2 minutes, 5 seconds");
+        assert_eq!(human_bytes(512), "512 B");
+        assert_eq!(human_bytes(1536), "1.50 KiB");
+        assert_eq!(human_bytes(3 * 1024 * 1024 * 1024), "3.00 GiB");
+    }
+}
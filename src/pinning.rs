@@ -0,0 +1,88 @@
+use std::sync::Arc;
+
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring::default_provider, verify_tls12_signature, verify_tls13_signature},
+    ClientConfig, DigitallySignedStruct, Error, SignatureScheme,
+};
+use rustls_pki_types::{CertificateDer, ServerName, UnixTime};
+use sha2::{Digest, Sha256};
+
+use crate::config::OPTIONS;
+
+/// Accepts only server certificates whose sha256 fingerprint is pinned, self signed ones
+/// included, instead of trusting the web PKI.
+#[derive(Debug)]
+pub struct PinnedCert {
+    fingerprints: Vec<String>,
+}
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, Error> {
+        let fingerprint = hex::encode(Sha256::digest(end_entity.as_ref()));
+        if self
+            .fingerprints
+            .iter()
+            .any(|pin| pin.eq_ignore_ascii_case(fingerprint.as_str()))
+        {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            log::error!("server certificate {} is not pinned", fingerprint);
+            Err(Error::General("server certificate not pinned".into()))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &default_provider().signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &default_provider().signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        default_provider()
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Replaces the certificate verifier of `config` if any certificate is pinned.
+pub fn pin_certificates(config: &mut ClientConfig) {
+    if OPTIONS.pin_cert.is_empty() {
+        return;
+    }
+    log::info!("server certificate pinned to {:?}", OPTIONS.pin_cert);
+    config
+        .dangerous()
+        .set_certificate_verifier(Arc::new(PinnedCert {
+            fingerprints: OPTIONS.pin_cert.clone(),
+        }));
+}
@@ -0,0 +1,88 @@
+//! SIP003 plugins like v2ray-plugin, run as a child process between a local port and the
+//! server. The addresses are passed in the environment:
+//!
+//! * client: the plugin listens on `SS_LOCAL_HOST:SS_LOCAL_PORT` for the connections to the
+//!   server at `SS_REMOTE_HOST:SS_REMOTE_PORT`
+//! * server: the plugin listens on `SS_REMOTE_HOST:SS_REMOTE_PORT`, the --local-addr, and
+//!   passes the connections on to the server at `SS_LOCAL_HOST:SS_LOCAL_PORT`
+
+use std::{
+    net::{Ipv4Addr, SocketAddr, TcpListener},
+    process::{Child, Command},
+    sync::{Mutex, OnceLock},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    config::{Mode, OPTIONS},
+    sys,
+    types::Result,
+};
+
+static LOCAL_ADDR: OnceLock<SocketAddr> = OnceLock::new();
+static CHILD: Mutex<Option<Child>> = Mutex::new(None);
+
+/// Local address of the running plugin, clients connect to it and the server listens on it.
+pub fn local_addr() -> Option<SocketAddr> {
+    LOCAL_ADDR.get().copied()
+}
+
+/// Starts --plugin if given, the process exits when the plugin does.
+pub fn start() -> Result<()> {
+    let Some(plugin) = &OPTIONS.plugin else {
+        return Ok(());
+    };
+    // a port nobody listens on, the plugin or the server takes it right away
+    let local = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?.local_addr()?;
+    let remote = match OPTIONS.mode {
+        Mode::Server(_) | Mode::Aserver(_) => OPTIONS.local_addr[0].parse()?,
+        _ => OPTIONS.back_addr.unwrap(),
+    };
+    let mut command = Command::new(plugin);
+    command
+        .env("SS_REMOTE_HOST", remote.ip().to_string())
+        .env("SS_REMOTE_PORT", remote.port().to_string())
+        .env("SS_LOCAL_HOST", local.ip().to_string())
+        .env("SS_LOCAL_PORT", local.port().to_string())
+        .env("SS_PLUGIN_OPTIONS", OPTIONS.plugin_opts.as_str());
+    sys::kill_with_parent(&mut command);
+    let child = command.spawn()?;
+    log::warn!(
+        "plugin {} started with pid:{}, local:{}, remote:{}",
+        plugin,
+        child.id(),
+        local,
+        remote
+    );
+    CHILD.lock().unwrap().replace(child);
+    let _ = LOCAL_ADDR.set(local);
+    thread::spawn(watch);
+    Ok(())
+}
+
+fn watch() {
+    loop {
+        thread::sleep(Duration::from_secs(1));
+        let mut child = CHILD.lock().unwrap();
+        let Some(child) = child.as_mut() else {
+            break;
+        };
+        match child.try_wait() {
+            Ok(Some(status)) => {
+                log::error!("plugin exited with {}, exit now", status);
+                std::process::exit(2);
+            }
+            Ok(None) => {}
+            Err(err) => log::error!("check plugin failed:{}", err),
+        }
+    }
+}
+
+/// Stops the plugin at exit.
+pub fn stop() {
+    if let Some(mut child) = CHILD.lock().unwrap().take() {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
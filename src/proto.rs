@@ -1,6 +1,9 @@
 #![allow(dead_code)]
 
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::{
+    fmt::{Display, Formatter},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+};
 
 use bytes::{BufMut, BytesMut};
 use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address, Ipv6Address};
@@ -13,6 +16,18 @@ pub const CONNECT: u8 = 0x01;
 pub const PING: u8 = 0x2;
 /// protocol code for UDP_ASSOCIATE command
 pub const UDP_ASSOCIATE: u8 = 0x03;
+/// protocol code for subscribing to the rule lists pushed by the server
+pub const RULES: u8 = 0x10;
+/// protocol code for a connection carrying multiplexed streams
+pub const MUX: u8 = 0x11;
+/// protocol code for a connection the server opens streams to published services through
+pub const REVERSE: u8 = 0x12;
+/// protocol code for relaying ICMP echo requests, and the replies and errors they get
+pub const ICMP: u8 = 0x13;
+/// protocol code for a connection carrying padded frames
+pub const PADDED: u8 = 0x14;
+/// protocol code of trojan-go for a connection carrying multiplexed streams
+pub const TROJAN_GO_MUX: u8 = 0x7f;
 /// max packet size for udp, MTU = 1500 minus IP head size
 pub const MAX_PACKET_SIZE: usize = 1480;
 /// protocol code for IPV4 type
@@ -23,7 +38,7 @@ const DOMAIN: u8 = 0x03;
 pub const IPV6: u8 = 0x04;
 
 /// Trojan Socks5 address enum
-#[derive(Debug)]
+#[derive(Debug, Hash)]
 pub enum Sock5Address {
     Endpoint(IpEndpoint),
     Socket(SocketAddr),
@@ -41,10 +56,32 @@ impl Sock5Address {
             None
         }
     }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            Sock5Address::Endpoint(endpoint) => endpoint.port,
+            Sock5Address::Socket(addr) => addr.port(),
+            Sock5Address::Domain(_, port) => *port,
+            Sock5Address::None => 0,
+        }
+    }
+}
+
+impl Display for Sock5Address {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Sock5Address::Endpoint(endpoint) => write!(f, "{}", endpoint),
+            Sock5Address::Socket(addr) => write!(f, "{}", addr),
+            Sock5Address::Domain(domain, port) => write!(f, "{}:{}", domain, port),
+            Sock5Address::None => write!(f, "none"),
+        }
+    }
 }
 
 /// Trojan protocol for a request
 pub struct TrojanRequest<'a> {
+    /// Label of the user the password belongs to
+    pub user: &'static str,
     pub command: u8,
     pub address: Sock5Address,
     pub payload: &'a [u8],
@@ -65,7 +102,8 @@ impl<'a> TrojanRequest<'a> {
                 "data length:{} is too short for a trojan request",
                 buffer.len()
             );
-            return if String::from_utf8_lossy(buffer).contains("HTTP") {
+            // a password hash is hex, anything else is a probe or a browser
+            return if !buffer.iter().all(u8::is_ascii_hexdigit) {
                 RequestParseResult::PassThrough
             } else {
                 RequestParseResult::Continue
@@ -73,12 +111,11 @@ impl<'a> TrojanRequest<'a> {
         }
 
         let pass = String::from_utf8_lossy(&buffer[..OPTIONS.pass_len]);
-        if let Some(orig) = OPTIONS.check_pass(&pass) {
-            log::debug!("request using password:{}", &orig);
-        } else {
+        let Some(user) = OPTIONS.check_pass(&pass) else {
             log::debug!("request didn't find matched password");
             return RequestParseResult::PassThrough;
-        }
+        };
+        log::debug!("request of user:{}", user);
 
         buffer = &buffer[OPTIONS.pass_len..];
         let mut offset = OPTIONS.pass_len;
@@ -100,7 +137,18 @@ impl<'a> TrojanRequest<'a> {
             log::error!("unknown protocol, invalid size");
             return RequestParseResult::Continue;
         }
-        if buffer[0] != CONNECT && buffer[0] != UDP_ASSOCIATE && buffer[0] != PING {
+        if ![
+            CONNECT,
+            UDP_ASSOCIATE,
+            PING,
+            RULES,
+            MUX,
+            REVERSE,
+            ICMP,
+            PADDED,
+        ]
+        .contains(&buffer[0])
+        {
             log::error!(
                 "unknown protocol, expected valid command, found:{}",
                 buffer[0]
@@ -123,6 +171,7 @@ impl<'a> TrojanRequest<'a> {
                 }
                 offset += 2;
                 RequestParseResult::Request(TrojanRequest {
+                    user,
                     command,
                     offset,
                     address,
@@ -143,6 +192,33 @@ impl<'a> TrojanRequest<'a> {
         buffer.put_u8(b'\n');
     }
 
+    /// Request of any kind of address, domains are resolved by the server.
+    pub fn generate_address(buffer: &mut BytesMut, cmd: u8, address: &Sock5Address) {
+        buffer.extend_from_slice(OPTIONS.get_pass().as_bytes());
+        buffer.put_u8(b'\r');
+        buffer.put_u8(b'\n');
+        buffer.put_u8(cmd);
+        Sock5Address::generate_address(buffer, address);
+        buffer.put_u8(b'\r');
+        buffer.put_u8(b'\n');
+    }
+
+    /// Request opening a stream to the server. Streams of a trojan-go mux session are
+    /// authenticated by the session, they start with the command and address only.
+    pub fn generate_stream(buffer: &mut BytesMut, cmd: u8, address: &Sock5Address) {
+        if OPTIONS.trojan_go_mux {
+            TrojanRequest::generate_simple(buffer, cmd, address);
+        } else {
+            TrojanRequest::generate_address(buffer, cmd, address);
+        }
+    }
+
+    /// "simple socks" request of trojan-go.
+    pub fn generate_simple(buffer: &mut BytesMut, cmd: u8, address: &Sock5Address) {
+        buffer.put_u8(cmd);
+        Sock5Address::generate_address(buffer, address);
+    }
+
     pub fn generate_endpoint(buffer: &mut BytesMut, cmd: u8, addr: &IpEndpoint) {
         buffer.extend_from_slice(OPTIONS.get_pass().as_bytes());
         buffer.put_u8(b'\r');
@@ -155,13 +231,14 @@ impl<'a> TrojanRequest<'a> {
     }
 }
 
-enum AddressParseResult {
+pub enum AddressParseResult {
     Address((usize, Sock5Address)),
     InvalidProtocol,
     Continue,
 }
 
-fn parse_address(atyp: u8, buffer: &[u8]) -> AddressParseResult {
+/// Parses the address of type `atyp` at the start of `buffer`, which must not be empty.
+pub fn parse_address(atyp: u8, buffer: &[u8]) -> AddressParseResult {
     match atyp {
         IPV4 => {
             log::debug!("ipv4 address found");
@@ -393,6 +470,13 @@ impl<'a> UdpAssociate<'a> {
         buffer.put_u8(b'\n');
     }
 
+    pub fn generate_address(buffer: &mut BytesMut, address: &Sock5Address, length: u16) {
+        Sock5Address::generate_address(buffer, address);
+        buffer.put_u16(length);
+        buffer.put_u8(b'\r');
+        buffer.put_u8(b'\n');
+    }
+
     pub fn generate_endpoint(buffer: &mut BytesMut, endpoint: &IpEndpoint, length: u16) {
         log::info!("generate endpoint:{}", endpoint);
         Sock5Address::generate_endpoint(buffer, endpoint);
@@ -402,6 +486,88 @@ impl<'a> UdpAssociate<'a> {
     }
 }
 
+/// An echo request of the client, or the reply or error it got from `from`, in an ICMP
+/// association. Frames are `TARGET FROM TYPE CODE TTL IDENT SEQ LEN CRLF DATA`, the addresses in
+/// the socks5 format with port 0.
+#[derive(Debug, PartialEq, Eq)]
+pub struct IcmpEcho {
+    pub target: IpAddr,
+    /// unspecified in requests
+    pub from: IpAddr,
+    /// ICMP message type and code
+    pub kind: u8,
+    pub code: u8,
+    pub ttl: u8,
+    pub ident: u16,
+    pub seq: u16,
+    /// payload of requests, replies carry none
+    pub data: Vec<u8>,
+}
+
+pub enum IcmpParseResult {
+    /// the frame and its length
+    Packet(IcmpEcho, usize),
+    InvalidProtocol,
+    Continued,
+}
+
+impl IcmpEcho {
+    pub fn parse(buffer: &[u8]) -> IcmpParseResult {
+        let mut offset = 0;
+        let mut addresses = [IpAddr::V4(Ipv4Addr::UNSPECIFIED); 2];
+        for address in &mut addresses {
+            // the address type and at least a byte of the address
+            if buffer.len() <= offset + 1 {
+                return IcmpParseResult::Continued;
+            }
+            match parse_address(buffer[offset], &buffer[offset + 1..]) {
+                AddressParseResult::Address((size, Sock5Address::Socket(addr))) => {
+                    *address = addr.ip();
+                    offset += 1 + size;
+                }
+                AddressParseResult::Continue => return IcmpParseResult::Continued,
+                _ => return IcmpParseResult::InvalidProtocol,
+            }
+        }
+        let header = &buffer[offset..];
+        if header.len() < 11 {
+            return IcmpParseResult::Continued;
+        }
+        let length = to_u16(&header[7..]) as usize;
+        if length > MAX_PACKET_SIZE || header[9] != b'\r' || header[10] != b'\n' {
+            log::error!("invalid icmp frame");
+            return IcmpParseResult::InvalidProtocol;
+        }
+        if header.len() < 11 + length {
+            return IcmpParseResult::Continued;
+        }
+        let echo = IcmpEcho {
+            target: addresses[0],
+            from: addresses[1],
+            kind: header[0],
+            code: header[1],
+            ttl: header[2],
+            ident: to_u16(&header[3..]),
+            seq: to_u16(&header[5..]),
+            data: header[11..11 + length].to_vec(),
+        };
+        IcmpParseResult::Packet(echo, offset + 11 + length)
+    }
+
+    pub fn generate(&self, buffer: &mut BytesMut) {
+        Sock5Address::generate(buffer, &SocketAddr::new(self.target, 0));
+        Sock5Address::generate(buffer, &SocketAddr::new(self.from, 0));
+        buffer.put_u8(self.kind);
+        buffer.put_u8(self.code);
+        buffer.put_u8(self.ttl);
+        buffer.put_u16(self.ident);
+        buffer.put_u16(self.seq);
+        buffer.put_u16(self.data.len() as u16);
+        buffer.put_slice(b"\r\n");
+        buffer.put_slice(self.data.as_slice());
+    }
+}
+
 fn to_u16(buffer: &[u8]) -> u16 {
     (buffer[0] as u16) << 8 | buffer[1] as u16
 }
@@ -436,4 +602,70 @@ impl Sock5Address {
         }
         buffer.put_u16(endpoint.port);
     }
+
+    /// Writes nothing for `None`, domains must not be longer than 255 bytes.
+    pub fn generate_address(buffer: &mut BytesMut, address: &Sock5Address) {
+        match address {
+            Sock5Address::Endpoint(endpoint) => Sock5Address::generate_endpoint(buffer, endpoint),
+            Sock5Address::Socket(addr) => Sock5Address::generate(buffer, addr),
+            Sock5Address::Domain(domain, port) => {
+                buffer.put_u8(DOMAIN);
+                buffer.put_u8(domain.len() as u8);
+                buffer.extend_from_slice(domain.as_bytes());
+                buffer.put_u16(*port);
+            }
+            Sock5Address::None => {}
+        }
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_simple_socks() {
+        use super::{Sock5Address, TrojanRequest, CONNECT};
+        use bytes::BytesMut;
+
+        let mut buffer = BytesMut::new();
+        let address = Sock5Address::Domain("example.com".into(), 443);
+        TrojanRequest::generate_simple(&mut buffer, CONNECT, &address);
+        assert_eq!(buffer.as_ref(), b"\x01\x03\x0bexample.com\x01\xbb");
+        buffer.clear();
+        let address = Sock5Address::Socket("1.2.3.4:53".parse().unwrap());
+        TrojanRequest::generate_simple(&mut buffer, CONNECT, &address);
+        assert_eq!(buffer.as_ref(), [1, 1, 1, 2, 3, 4, 0, 53]);
+    }
+
+    #[test]
+    fn test_icmp_echo() {
+        use super::{IcmpEcho, IcmpParseResult};
+        use bytes::BytesMut;
+        use std::net::{IpAddr, Ipv4Addr};
+
+        let echo = IcmpEcho {
+            target: "1.1.1.1".parse().unwrap(),
+            from: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            kind: 8,
+            code: 0,
+            ttl: 3,
+            ident: 0x1234,
+            seq: 7,
+            data: b"abcdefgh".to_vec(),
+        };
+        let mut buffer = BytesMut::new();
+        echo.generate(&mut buffer);
+        for len in 0..buffer.len() {
+            assert!(matches!(
+                IcmpEcho::parse(&buffer[..len]),
+                IcmpParseResult::Continued
+            ));
+        }
+        buffer.extend_from_slice(b"next");
+        match IcmpEcho::parse(buffer.as_ref()) {
+            IcmpParseResult::Packet(parsed, size) => {
+                assert_eq!(parsed, echo);
+                assert_eq!(size, buffer.len() - 4);
+            }
+            _ => panic!("frame not parsed"),
+        }
+    }
 }
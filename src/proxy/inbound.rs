@@ -0,0 +1,558 @@
+//! SOCKS5 and HTTP listener of the proxy mode on --inbound-addr, for apps pointed at it instead
+//! of the transparent proxy, told apart by the first byte. SOCKS5 and HTTP CONNECT are relayed by
+//! the `TcpServer`, so are absolute-form http requests, one per client connection as the relay
+//! doesn't look at the ones after. UDP ASSOCIATE gets a UDP port on the listener's address and
+//! one trojan UDP_ASSOCIATE connection.
+
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use mio::{
+    event::Event,
+    net::{TcpListener, TcpStream, UdpSocket},
+    Interest, Poll, Token,
+};
+
+use crate::{
+    aproxy::http_proxy::rewrite_once,
+    config::OPTIONS,
+    idle_pool::IdlePool,
+    inbound::{
+        accepted, allowed, auth_method, parse_auth, parse_greeting, parse_http, parse_request,
+        reply, sniff, to_trojan_frame, Sniffed, CMD_CONNECT, CMD_UDP_ASSOCIATE,
+        NO_ACCEPTABLE_METHOD, SOCKS_VERSION, USER_PASS_AUTH,
+    },
+    proto::{Sock5Address, TrojanRequest, UdpAssociate, UdpParseResult, UDP_ASSOCIATE},
+    proxy::{
+        next_index, tcp_server::TcpServer, CHANNEL_CNT, CHANNEL_SOCKS, CHANNEL_SOCKS_TLS, MIN_INDEX,
+    },
+    resolver::DnsResolver,
+    status::{ConnStatus, StatusProvider},
+    tls_conn::TlsConn,
+    types::{Result, TrojanError},
+};
+
+/// Clients not done with the handshake by then are dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// An http head is the longest, SOCKS5 handshakes are a few hundred bytes at most.
+const MAX_HANDSHAKE: usize = 8192;
+
+pub struct InboundServer {
+    listener: Option<TcpListener>,
+    handshakes: HashMap<usize, Handshake>,
+    associations: HashMap<usize, Association>,
+    next_id: usize,
+    removed: Option<Vec<usize>>,
+}
+
+#[derive(Copy, Clone)]
+enum Stage {
+    Greeting,
+    Auth,
+    Request,
+    Http,
+}
+
+struct Handshake {
+    stream: TcpStream,
+    peer: SocketAddr,
+    buffer: BytesMut,
+    stage: Stage,
+    since: Instant,
+}
+
+struct Association {
+    index: usize,
+    control: TcpStream,
+    peer: SocketAddr,
+    socket: UdpSocket,
+    client_addr: Option<SocketAddr>,
+    server_conn: TlsConn,
+    status: ConnStatus,
+    recv_buffer: BytesMut,
+    datagram: Vec<u8>,
+    frame: BytesMut,
+    last_active: Instant,
+}
+
+impl InboundServer {
+    pub fn new(listener: Option<TcpListener>) -> InboundServer {
+        if let Some(listener) = &listener {
+            let args = OPTIONS.proxy_args();
+            if args.inbound_auth.is_empty()
+                && args.inbound_allow.is_empty()
+                && !listener
+                    .local_addr()
+                    .is_ok_and(|addr| addr.ip().is_loopback())
+            {
+                log::warn!("inbound listener is open to the network without auth or allowlist");
+            }
+        }
+        InboundServer {
+            listener,
+            handshakes: HashMap::new(),
+            associations: HashMap::new(),
+            next_id: MIN_INDEX,
+            removed: Some(Vec::new()),
+        }
+    }
+
+    pub fn accept(&mut self, poll: &Poll) {
+        let Some(listener) = &self.listener else {
+            return;
+        };
+        loop {
+            let (mut stream, peer) = match listener.accept() {
+                Ok(accepted) => accepted,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::error!("inbound accept failed:{}", err);
+                    break;
+                }
+            };
+            if !allowed(peer.ip()) {
+                log::warn!("inbound client {} not in allowlist", peer);
+                continue;
+            }
+            let _ = stream.set_nodelay(true);
+            let index = next_index(&mut self.next_id);
+            if let Err(err) = poll.registry().register(
+                &mut stream,
+                Token(index * CHANNEL_CNT + CHANNEL_SOCKS),
+                Interest::READABLE,
+            ) {
+                log::error!("register inbound client {} failed:{}", peer, err);
+                continue;
+            }
+            self.handshakes.insert(
+                index,
+                Handshake {
+                    stream,
+                    peer,
+                    buffer: BytesMut::new(),
+                    stage: Stage::Greeting,
+                    since: Instant::now(),
+                },
+            );
+        }
+    }
+
+    pub fn ready(
+        &mut self,
+        event: &Event,
+        poll: &Poll,
+        tcp_server: &mut TcpServer,
+        pool: &mut IdlePool,
+        resolver: &DnsResolver,
+    ) {
+        let index = event.token().0 / CHANNEL_CNT;
+        if let Some(association) = self.associations.get_mut(&index) {
+            association.ready(event, poll);
+            if association.destroyed() {
+                self.removed.as_mut().unwrap().push(index);
+            }
+            return;
+        }
+        let Some(handshake) = self.handshakes.get_mut(&index) else {
+            log::error!("inbound connection:{} not found, check deregister", index);
+            return;
+        };
+        let request = match handshake.advance() {
+            Ok(None) => return,
+            Ok(Some(request)) => request,
+            Err(err) => {
+                log::warn!("inbound handshake of {} failed:{:?}", handshake.peer, err);
+                let mut handshake = self.handshakes.remove(&index).unwrap();
+                let _ = poll.registry().deregister(&mut handshake.stream);
+                return;
+            }
+        };
+        let Handshake {
+            mut stream,
+            peer,
+            buffer,
+            ..
+        } = self.handshakes.remove(&index).unwrap();
+        match request {
+            (CMD_CONNECT, dst_addr) => {
+                log::info!("inbound {} to {} through the server", peer, dst_addr);
+                let _ = poll.registry().deregister(&mut stream);
+                tcp_server.connect(poll, stream, dst_addr, buffer.as_ref(), pool, resolver);
+            }
+            _ => match Association::start(index, stream, peer, poll, pool, resolver) {
+                Ok(association) => {
+                    self.associations.insert(index, association);
+                }
+                Err(err) => log::error!("socks5 udp associate of {} failed:{:?}", peer, err),
+            },
+        }
+    }
+
+    pub fn remove_closed(&mut self) {
+        if self.removed.as_ref().unwrap().is_empty() {
+            return;
+        }
+        for index in self.removed.replace(Vec::new()).unwrap() {
+            self.associations.remove(&index);
+        }
+    }
+
+    pub fn check_timeout(&mut self, poll: &Poll) {
+        self.handshakes.retain(|_, handshake| {
+            if handshake.since.elapsed() < HANDSHAKE_TIMEOUT {
+                return true;
+            }
+            log::warn!("inbound handshake of {} timeout", handshake.peer);
+            let _ = poll.registry().deregister(&mut handshake.stream);
+            false
+        });
+        self.associations.retain(|_, association| {
+            if !association.destroyed()
+                && association.last_active.elapsed() > OPTIONS.udp_idle_duration
+            {
+                log::info!("socks5 udp associate of {} timeout", association.peer);
+                association.shutdown();
+                association.server_conn.shutdown();
+                association.check_status(poll);
+                association.server_conn.check_status(poll);
+            }
+            !association.destroyed()
+        });
+    }
+}
+
+impl Handshake {
+    /// Reads what the client sent and answers it, returns the command and address once the
+    /// request is complete.
+    fn advance(&mut self) -> Result<Option<(u8, Sock5Address)>> {
+        let mut data = [0u8; 1024];
+        loop {
+            match self.stream.read(&mut data) {
+                Ok(0) => return Err(TrojanError::Inbound("inbound client closed")),
+                Ok(n) => self.buffer.extend_from_slice(&data[..n]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err.into()),
+            }
+            if self.buffer.len() > MAX_HANDSHAKE {
+                return Err(TrojanError::Inbound("inbound handshake too long"));
+            }
+        }
+        loop {
+            match self.stage {
+                Stage::Greeting => {
+                    match self.buffer.first().map(|first| sniff(*first)) {
+                        None => return Ok(None),
+                        Some(Some(Sniffed::Http)) => {
+                            self.stage = Stage::Http;
+                            continue;
+                        }
+                        Some(Some(Sniffed::Socks5)) => {}
+                        Some(_) => {
+                            return Err(TrojanError::Inbound("unsupported inbound protocol"))
+                        }
+                    }
+                    let Some((size, methods)) = parse_greeting(self.buffer.as_ref())? else {
+                        return Ok(None);
+                    };
+                    let method = auth_method();
+                    if !methods.contains(&method) {
+                        let _ = self
+                            .stream
+                            .write_all(&[SOCKS_VERSION, NO_ACCEPTABLE_METHOD]);
+                        return Err(TrojanError::Inbound("no acceptable socks5 method"));
+                    }
+                    self.buffer.advance(size);
+                    self.stream.write_all(&[SOCKS_VERSION, method])?;
+                    self.stage = if method == USER_PASS_AUTH {
+                        Stage::Auth
+                    } else {
+                        Stage::Request
+                    };
+                }
+                Stage::Auth => {
+                    let Some((size, user, pass)) = parse_auth(self.buffer.as_ref()) else {
+                        return Ok(None);
+                    };
+                    let accepted = accepted(user.as_str(), pass.as_str());
+                    self.buffer.advance(size);
+                    self.stream.write_all(&[1, if accepted { 0 } else { 1 }])?;
+                    if !accepted {
+                        return Err(TrojanError::Inbound("invalid socks5 credentials"));
+                    }
+                    self.stage = Stage::Request;
+                }
+                Stage::Request => {
+                    let Some((size, cmd, address)) = parse_request(self.buffer.as_ref())? else {
+                        return Ok(None);
+                    };
+                    self.buffer.advance(size);
+                    if cmd != CMD_CONNECT && cmd != CMD_UDP_ASSOCIATE {
+                        let _ = self.stream.write_all(&reply(7, None));
+                        return Err(TrojanError::Inbound("unsupported socks5 command"));
+                    }
+                    // the association's reply carries its port, it's sent once bound
+                    if cmd == CMD_CONNECT {
+                        self.stream.write_all(&reply(0, None))?;
+                    }
+                    return Ok(Some((cmd, address)));
+                }
+                Stage::Http => {
+                    let request = match parse_http(self.buffer.as_ref()) {
+                        Ok(Some(mut request)) => match request.connect.take() {
+                            Some(address) => Ok((request, address, Vec::new())),
+                            // absolute-form, the rewritten head goes to the origin server
+                            None => rewrite_once(&self.buffer[..request.size])
+                                .map(|(head, address)| (request, address, head)),
+                        },
+                        Ok(None) => return Ok(None),
+                        Err(err) => Err(err),
+                    };
+                    let (request, address, head) = match request {
+                        Ok(request) => request,
+                        Err(err) => {
+                            let _ = self.stream.write_all(
+                                b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n",
+                            );
+                            return Err(err);
+                        }
+                    };
+                    let authorized = OPTIONS.proxy_args().inbound_auth.is_empty()
+                        || request
+                            .credentials
+                            .is_some_and(|(user, pass)| accepted(user.as_str(), pass.as_str()));
+                    if !authorized {
+                        let _ = self.stream.write_all(
+                            b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+                            Proxy-Authenticate: Basic realm=\"trojan\"\r\nConnection: close\r\n\r\n",
+                        );
+                        return Err(TrojanError::Inbound("invalid http proxy credentials"));
+                    }
+                    self.buffer.advance(request.size);
+                    if head.is_empty() {
+                        self.stream
+                            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?;
+                    } else {
+                        // the rewritten head goes first, then whatever of the body is here
+                        let mut data = BytesMut::from(head.as_slice());
+                        data.unsplit(self.buffer.split());
+                        self.buffer = data;
+                    }
+                    return Ok(Some((CMD_CONNECT, address)));
+                }
+            }
+        }
+    }
+}
+
+impl Association {
+    fn start(
+        index: usize,
+        mut control: TcpStream,
+        peer: SocketAddr,
+        poll: &Poll,
+        pool: &mut IdlePool,
+        resolver: &DnsResolver,
+    ) -> Result<Association> {
+        let Some(mut server_conn) = pool.get(poll, resolver) else {
+            let _ = control.write_all(&reply(1, None));
+            return Err(TrojanError::Inbound("alloc new connection failed"));
+        };
+        let token = Token(index * CHANNEL_CNT + CHANNEL_SOCKS_TLS);
+        if !server_conn.reset_index(index, token, poll) {
+            server_conn.check_status(poll);
+            let _ = control.write_all(&reply(1, None));
+            return Err(TrojanError::Inbound("register server connection failed"));
+        }
+        let mut request = BytesMut::new();
+        TrojanRequest::generate(
+            &mut request,
+            UDP_ASSOCIATE,
+            OPTIONS.empty_addr.as_ref().unwrap(),
+        );
+        let mut association = Association {
+            index,
+            peer,
+            socket: UdpSocket::bind(SocketAddr::new(control.local_addr()?.ip(), 0))?,
+            control,
+            client_addr: None,
+            server_conn,
+            status: ConnStatus::Established,
+            recv_buffer: BytesMut::new(),
+            datagram: vec![0u8; u16::MAX as usize],
+            frame: BytesMut::new(),
+            last_active: Instant::now(),
+        };
+        let bound = association.socket.local_addr()?;
+        let registered = poll
+            .registry()
+            .register(
+                &mut association.socket,
+                Token(index * CHANNEL_CNT + CHANNEL_SOCKS),
+                Interest::READABLE,
+            )
+            .map_err(TrojanError::from)
+            .and_then(|_| Ok(association.control.write_all(&reply(0, Some(bound)))?));
+        if let Err(err) = registered {
+            association.shutdown();
+            association.server_conn.shutdown();
+            association.do_status(poll);
+            return Err(err);
+        }
+        association.server_conn.write_session(request.as_ref());
+        log::info!("socks5 udp associate of {} on {}", peer, bound);
+        Ok(association)
+    }
+
+    fn destroyed(&self) -> bool {
+        self.deregistered() && self.server_conn.deregistered()
+    }
+
+    fn ready(&mut self, event: &Event, poll: &Poll) {
+        self.last_active = Instant::now();
+        match event.token().0 % CHANNEL_CNT {
+            CHANNEL_SOCKS => {
+                self.read_control();
+                self.read_client();
+                self.server_conn.do_send();
+            }
+            _ => {
+                if event.is_readable() {
+                    self.read_server();
+                    self.server_conn.do_send();
+                }
+                if event.is_writable() {
+                    self.server_conn.established();
+                    self.server_conn.do_send();
+                }
+            }
+        }
+        self.do_status(poll);
+    }
+
+    fn do_status(&mut self, poll: &Poll) {
+        if self.is_shutdown() {
+            self.server_conn.peer_closed();
+        }
+        if self.server_conn.is_shutdown() {
+            self.peer_closed();
+        }
+        self.check_status(poll);
+        self.server_conn.check_status(poll);
+    }
+
+    /// Nothing is expected on the control connection, the association ends when it closes.
+    fn read_control(&mut self) {
+        let mut data = [0u8; 64];
+        loop {
+            match self.control.read(&mut data) {
+                Ok(0) => {
+                    log::info!("socks5 udp associate of {} closed by client", self.peer);
+                    self.shutdown();
+                    break;
+                }
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::warn!("socks5 control of {} failed:{}", self.peer, err);
+                    self.shutdown();
+                    break;
+                }
+            }
+        }
+    }
+
+    fn read_client(&mut self) {
+        loop {
+            let (size, src_addr) = match self.socket.recv_from(self.datagram.as_mut_slice()) {
+                Ok(received) => received,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::warn!("socks5 udp receive failed:{}", err);
+                    break;
+                }
+            };
+            // only the control connection's ip may send, the port is learned from the first
+            if src_addr.ip() != self.peer.ip()
+                || self.client_addr.is_some_and(|addr| addr != src_addr)
+            {
+                log::warn!("udp datagram from unknown {} dropped", src_addr);
+                continue;
+            }
+            self.client_addr = Some(src_addr);
+            if !self.server_conn.is_connecting() && !self.server_conn.writable() {
+                log::warn!("udp packet is too fast, ignore now");
+                continue;
+            }
+            self.frame.clear();
+            if to_trojan_frame(&self.datagram[..size], &mut self.frame).is_none() {
+                log::warn!("invalid socks5 datagram from {} dropped", src_addr);
+                continue;
+            }
+            if !self.server_conn.write_session(self.frame.as_ref()) {
+                break;
+            }
+        }
+    }
+
+    fn read_server(&mut self) {
+        let Some(data) = self.server_conn.do_read() else {
+            return;
+        };
+        self.recv_buffer.extend_from_slice(data.as_slice());
+        loop {
+            match UdpAssociate::parse(self.recv_buffer.as_ref()) {
+                UdpParseResult::Continued => break,
+                UdpParseResult::Packet(packet) => {
+                    // the address bytes are passed on as they are
+                    let address = &self.recv_buffer[..packet.offset - packet.length - 4];
+                    self.frame.clear();
+                    self.frame.put_slice(&[0, 0, 0]);
+                    self.frame.put_slice(address);
+                    self.frame.put_slice(&packet.payload[..packet.length]);
+                    if let Some(addr) = self.client_addr {
+                        if let Err(err) = self.socket.send_to(self.frame.as_ref(), addr) {
+                            log::warn!("send udp data to {} failed:{}", addr, err);
+                        }
+                    }
+                    let offset = packet.offset;
+                    self.recv_buffer.advance(offset);
+                }
+                UdpParseResult::InvalidProtocol => {
+                    log::error!("connection:{} got invalid protocol", self.index);
+                    self.server_conn.shutdown();
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl StatusProvider for Association {
+    fn set_status(&mut self, status: ConnStatus) {
+        self.status = status;
+    }
+
+    fn get_status(&self) -> ConnStatus {
+        self.status
+    }
+
+    fn close_conn(&mut self) -> bool {
+        let _ = self.control.shutdown(Shutdown::Both);
+        true
+    }
+
+    fn deregister(&mut self, poll: &Poll) -> bool {
+        let _ = poll.registry().deregister(&mut self.control);
+        let _ = poll.registry().deregister(&mut self.socket);
+        true
+    }
+
+    fn finish_send(&mut self) -> bool {
+        true
+    }
+}
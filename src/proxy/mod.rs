@@ -1,6 +1,7 @@
 //! This module provides functions used in proxy mod.
 use std::{
     convert::TryInto,
+    io::ErrorKind,
     net::SocketAddr,
     sync::Arc,
     time::{Duration, Instant},
@@ -10,13 +11,15 @@ use mio::{
     net::{TcpListener, UdpSocket},
     Events, Interest, Poll, Token, Waker,
 };
-use rustls::{ClientConfig, RootCertStore};
+use rustls::RootCertStore;
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 
 pub use crate::idle_pool::IdlePool;
 use crate::{
-    config::OPTIONS,
+    config::{MAX_LISTENERS, OPTIONS},
+    pinning::pin_certificates,
     proxy::{
+        inbound::InboundServer,
         net_profiler::{start_check_server, NetProfiler},
         tcp_server::TcpServer,
         udp_cache::UdpSvrCache,
@@ -24,28 +27,33 @@ use crate::{
     },
     resolver::DnsResolver,
     sys,
+    tls_client::client_config,
     types::Result,
+    watchdog,
 };
 
+mod inbound;
 mod net_profiler;
 mod tcp_server;
 mod udp_cache;
 mod udp_server;
 
-/// minimal index used in `IdlePool`, `TcpServer` and `UdpServer`
-const MIN_INDEX: usize = 3;
-/// maximum index used in `IdlePool`, `TcpServer` and `UdpServer`
+/// minimal index used in `IdlePool`, `TcpServer`, `UdpServer` and `InboundServer`
+const MIN_INDEX: usize = (SOCKS_LISTENER + 1).div_ceil(CHANNEL_CNT);
+/// maximum index used in `IdlePool`, `TcpServer`, `UdpServer` and `InboundServer`
 const MAX_INDEX: usize = usize::MAX / CHANNEL_CNT;
-/// Token used for TcpListener
-const TCP_LISTENER: usize = 1;
-/// Token used for main Udp Socket
-const UDP_LISTENER: usize = 2;
 /// Token used for dns resolver
-const RESOLVER: usize = 3;
+const RESOLVER: usize = 1;
 /// Token used for ping
-pub(crate) const PINGER: usize = 4;
+pub(crate) const PINGER: usize = 2;
+/// First token used for TcpListener, one for each local address
+const TCP_LISTENER: usize = 3;
+/// First token used for main Udp Socket, one for each local address
+const UDP_LISTENER: usize = TCP_LISTENER + MAX_LISTENERS;
+/// Token used for the SOCKS5/HTTP listener
+const SOCKS_LISTENER: usize = UDP_LISTENER + MAX_LISTENERS;
 /// total channel count for Poll
-const CHANNEL_CNT: usize = 4;
+const CHANNEL_CNT: usize = 7;
 /// channel index  for `IdlePool`
 const CHANNEL_IDLE: usize = 0;
 /// channel index for client `UdpConnection`
@@ -54,6 +62,12 @@ const CHANNEL_UDP: usize = 1;
 const CHANNEL_CLIENT: usize = 2;
 /// channel index for remote tcp connection
 const CHANNEL_TCP: usize = 3;
+/// channel index for SOCKS5/HTTP client connection and the UDP socket of its association
+const CHANNEL_SOCKS: usize = 4;
+/// channel index for the remote connection of a SOCKS5 UDP association
+const CHANNEL_SOCKS_TLS: usize = 5;
+/// channel index for the connection of a mux session
+const CHANNEL_MUX: usize = 6;
 
 /// Returns next index based on the current one.
 /// If the next index overflows (larger than [`MAX_INDEX`]),
@@ -68,6 +82,19 @@ fn next_index(index: &mut usize) -> usize {
 }
 
 pub fn new_socket(addr: SocketAddr, is_udp: bool) -> Result<Socket> {
+    bind_socket(addr, is_udp, false)
+}
+
+/// Binds the listening sockets of the local addresses.
+pub fn new_listeners(is_udp: bool) -> Result<Vec<Socket>> {
+    OPTIONS
+        .local_addr
+        .iter()
+        .map(|addr| bind_socket(addr.parse()?, is_udp, OPTIONS.v6_only()))
+        .collect()
+}
+
+fn bind_socket(addr: SocketAddr, is_udp: bool, v6_only: bool) -> Result<Socket> {
     let domain = if addr.is_ipv4() {
         Domain::IPV4
     } else {
@@ -79,6 +106,9 @@ pub fn new_socket(addr: SocketAddr, is_udp: bool) -> Result<Socket> {
         (Type::STREAM, Protocol::TCP)
     };
     let socket = Socket::new(domain, typ, Some(protocol))?;
+    if v6_only && addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
     sys::set_socket_opts(addr.is_ipv4(), is_udp, &socket)?;
     socket.set_nonblocking(true)?;
     socket.set_reuse_address(true)?;
@@ -89,30 +119,97 @@ pub fn new_socket(addr: SocketAddr, is_udp: bool) -> Result<Socket> {
     Ok(socket)
 }
 
+/// Answer ARP/NDP for the virtual gateway addresses if LAN gateway mode is enabled.
+pub fn start_gateway() -> Result<()> {
+    if let Some(iface) = &OPTIONS.proxy_args().gateway_iface {
+        sys::start_gateway_responder(iface, &OPTIONS.proxy_args().gateway_ip)?;
+    }
+    Ok(())
+}
+
+/// Removes the fwmark rule of the routing table when dropped.
+pub struct RouteTable {
+    table: u32,
+    mark: u32,
+    server_mark: Option<u32>,
+}
+
+impl Drop for RouteTable {
+    fn drop(&mut self) {
+        match sys::remove_route_table(self.table, self.mark, self.server_mark) {
+            Ok(()) => log::warn!("route table {} rule removed", self.table),
+            Err(err) => log::error!("remove route table {} rule failed:{}", self.table, err),
+        }
+    }
+}
+
+/// Installs the tproxy route into a dedicated routing table if configured, SIGTERM is
+/// watched then so the rule can be removed on exit.
+pub fn start_route_table() -> Result<Option<RouteTable>> {
+    let Some(table) = OPTIONS.proxy_args().route_table else {
+        return Ok(None);
+    };
+    let mark = OPTIONS.proxy_args().route_mark;
+    let server_mark = OPTIONS.proxy_args().server_mark;
+    sys::install_route_table(table, mark, server_mark)?;
+    sys::watch_terminate()?;
+    log::warn!(
+        "tproxy route installed in table {} for mark {:#x}",
+        table,
+        mark
+    );
+    Ok(Some(RouteTable {
+        table,
+        mark,
+        server_mark,
+    }))
+}
+
 pub fn run() -> Result<()> {
-    let addr: SocketAddr = OPTIONS.local_addr.parse()?;
-    let mut tcp_listener = TcpListener::from_std(new_socket(addr, false)?.into());
-    let mut udp_listener = UdpSocket::from_std(new_socket(addr, true)?.into());
+    start_gateway()?;
+    let _route_table = start_route_table()?;
+    let mut tcp_listeners: Vec<_> = new_listeners(false)?
+        .into_iter()
+        .map(|socket| TcpListener::from_std(socket.into()))
+        .collect();
+    let mut udp_listeners: Vec<_> = new_listeners(true)?
+        .into_iter()
+        .map(|socket| UdpSocket::from_std(socket.into()))
+        .collect();
     let mut udp_cache = UdpSvrCache::new();
     let mut poll = Poll::new()?;
     let waker = Arc::new(Waker::new(poll.registry(), Token(RESOLVER))?);
     let mut resolver = DnsResolver::new(waker, Token(RESOLVER), None);
-    poll.registry()
-        .register(&mut tcp_listener, Token(TCP_LISTENER), Interest::READABLE)?;
-    poll.registry()
-        .register(&mut udp_listener, Token(UDP_LISTENER), Interest::READABLE)?;
+    for (i, listener) in tcp_listeners.iter_mut().enumerate() {
+        poll.registry()
+            .register(listener, Token(TCP_LISTENER + i), Interest::READABLE)?;
+    }
+    for (i, listener) in udp_listeners.iter_mut().enumerate() {
+        poll.registry()
+            .register(listener, Token(UDP_LISTENER + i), Interest::READABLE)?;
+    }
+    let inbound_listener = match &OPTIONS.proxy_args().inbound_addr {
+        Some(addr) => {
+            let mut listener = TcpListener::bind(addr.parse()?)?;
+            poll.registry()
+                .register(&mut listener, Token(SOCKS_LISTENER), Interest::READABLE)?;
+            log::warn!("socks5/http listening on {}", addr);
+            Some(listener)
+        }
+        None => None,
+    };
 
     let hostname = OPTIONS.proxy_args().hostname.as_str().try_into()?;
 
     let mut root_store = RootCertStore::empty();
     root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
-    let config = ClientConfig::builder()
-        .with_root_certificates(root_store)
-        .with_no_client_auth();
+    let mut config = client_config(root_store);
+    pin_certificates(&mut config);
     let config = Arc::new(config);
 
-    let mut tcp_server = TcpServer::new(tcp_listener);
-    let mut udp_server = UdpServer::new(udp_listener);
+    let mut tcp_server = TcpServer::new(tcp_listeners);
+    let mut udp_server = UdpServer::new(udp_listeners);
+    let mut inbound_server = InboundServer::new(inbound_listener);
 
     start_check_server(
         OPTIONS.proxy_args().hostname.clone(),
@@ -129,6 +226,7 @@ pub fn run() -> Result<()> {
         OPTIONS.proxy_args().port,
         OPTIONS.proxy_args().hostname.clone(),
     );
+    pool.set_mark(OPTIONS.proxy_args().server_mark);
     pool.init_index(CHANNEL_CNT, CHANNEL_IDLE, MIN_INDEX, MAX_INDEX);
     pool.init(&poll, &resolver);
 
@@ -145,24 +243,45 @@ pub fn run() -> Result<()> {
 
     let mut last_check_time = Instant::now();
     let check_duration = Duration::new(1, 0);
+    let heartbeat = watchdog::register("proxy");
 
     loop {
-        poll.poll(&mut events, Some(check_duration))?;
+        heartbeat.beat();
+        if sys::terminated() {
+            log::warn!("SIGTERM received, exit now");
+            return Ok(());
+        }
+        if let Err(err) = poll.poll(&mut events, Some(check_duration)) {
+            if err.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err.into());
+        }
         for event in &events {
             log::trace!("dispatch token:{}", event.token().0);
             match event.token() {
-                Token(TCP_LISTENER) => {
-                    tcp_server.accept(&poll, &mut pool, &resolver, &mut net_profiler);
+                Token(i) if (TCP_LISTENER..UDP_LISTENER).contains(&i) => {
+                    tcp_server.accept(
+                        &poll,
+                        i - TCP_LISTENER,
+                        &mut pool,
+                        &resolver,
+                        &mut net_profiler,
+                    );
                 }
-                Token(UDP_LISTENER) => {
+                Token(i) if (UDP_LISTENER..UDP_LISTENER + MAX_LISTENERS).contains(&i) => {
                     udp_server.accept(
                         &poll,
+                        i - UDP_LISTENER,
                         &mut pool,
                         &mut udp_cache,
                         &resolver,
                         &mut net_profiler,
                     );
                 }
+                Token(SOCKS_LISTENER) => {
+                    inbound_server.accept(&poll);
+                }
                 Token(RESOLVER) => {
                     resolver.consume(|_, ip| {
                         pool.resolve(ip);
@@ -177,6 +296,14 @@ pub fn run() -> Result<()> {
                 Token(i) if i % CHANNEL_CNT == CHANNEL_UDP => {
                     udp_server.ready(event, &poll, &mut udp_cache);
                 }
+                Token(i) if i % CHANNEL_CNT == CHANNEL_MUX => {
+                    tcp_server.mux_ready(event, &poll);
+                }
+                Token(i)
+                    if i % CHANNEL_CNT == CHANNEL_SOCKS || i % CHANNEL_CNT == CHANNEL_SOCKS_TLS =>
+                {
+                    inbound_server.ready(event, &poll, &mut tcp_server, &mut pool, &resolver);
+                }
                 _ => {
                     tcp_server.ready(event, &poll);
                 }
@@ -184,12 +311,14 @@ pub fn run() -> Result<()> {
         }
         udp_server.remove_closed();
         tcp_server.remove_closed();
+        inbound_server.remove_closed();
         net_profiler.update();
         let now = Instant::now();
         if now - last_check_time > check_duration {
             tcp_server.check_timeout(&poll, now);
             udp_cache.check_timeout();
-            pool.check_timeout(&poll);
+            inbound_server.check_timeout(&poll);
+            pool.check_timeout(&poll, &resolver);
             last_check_time = now;
         }
     }
@@ -1,9 +1,4 @@
-use std::{
-    collections::HashMap,
-    io::ErrorKind,
-    net::{Shutdown, SocketAddr},
-    time::Instant,
-};
+use std::{collections::HashMap, io::ErrorKind, net::Shutdown, time::Instant};
 
 use bytes::BytesMut;
 use mio::{
@@ -15,56 +10,62 @@ use mio::{
 use crate::{
     config::OPTIONS,
     idle_pool::IdlePool,
-    proto::{TrojanRequest, CONNECT, MAX_PACKET_SIZE},
+    mux_conn::{MuxSessions, ServerConn},
+    proto::{Sock5Address, TrojanRequest, CONNECT, MAX_PACKET_SIZE},
     proxy::{
-        net_profiler::NetProfiler, next_index, CHANNEL_CLIENT, CHANNEL_CNT, CHANNEL_TCP, MIN_INDEX,
+        net_profiler::NetProfiler, next_index, CHANNEL_CLIENT, CHANNEL_CNT, CHANNEL_MUX,
+        CHANNEL_TCP, MAX_INDEX, MIN_INDEX,
     },
     resolver::DnsResolver,
     status::{ConnStatus, StatusProvider},
     sys, tcp_util,
-    tls_conn::TlsConn,
     types::{Result, TrojanError},
 };
 
 pub struct TcpServer {
-    tcp_listener: TcpListener,
+    tcp_listeners: Vec<TcpListener>,
     conns: HashMap<usize, Connection>,
     next_id: usize,
     removed: Option<Vec<usize>>,
+    mux: Option<MuxSessions>,
 }
 
 struct Connection {
     index: usize,
-    dst_addr: SocketAddr,
+    dst_addr: Sock5Address,
     client: TcpStream,
     recv_buffer: Vec<u8>,
     send_buffer: BytesMut,
     status: ConnStatus,
-    server_conn: TlsConn,
+    server_conn: ServerConn,
     last_active_time: Instant,
     read_client: bool,
     read_server: bool,
 }
 
 impl TcpServer {
-    pub fn new(tcp_listener: TcpListener) -> TcpServer {
+    pub fn new(tcp_listeners: Vec<TcpListener>) -> TcpServer {
         TcpServer {
-            tcp_listener,
+            tcp_listeners,
             conns: HashMap::new(),
             removed: Some(Vec::new()),
             next_id: MIN_INDEX,
+            mux: (OPTIONS.mux > 0)
+                .then(|| MuxSessions::new(CHANNEL_CNT, CHANNEL_MUX, MIN_INDEX, MAX_INDEX)),
         }
     }
 
+    /// Accepts the pending connections of the `listener`th local address.
     pub fn accept(
         &mut self,
         poll: &Poll,
+        listener: usize,
         pool: &mut IdlePool,
         resolver: &DnsResolver,
         net_profiler: &mut NetProfiler,
     ) {
         loop {
-            if let Err(err) = self.accept_once(poll, pool, resolver, net_profiler) {
+            if let Err(err) = self.accept_once(poll, listener, pool, resolver, net_profiler) {
                 if let TrojanError::StdIo(err) = &err {
                     if err.kind() == ErrorKind::WouldBlock {
                         break;
@@ -78,32 +79,83 @@ impl TcpServer {
     fn accept_once(
         &mut self,
         poll: &Poll,
+        listener: usize,
         pool: &mut IdlePool,
         resolver: &DnsResolver,
         net_profiler: &mut NetProfiler,
     ) -> Result<()> {
-        let (client, src_addr) = self.tcp_listener.accept()?;
+        let (client, src_addr) = self.tcp_listeners[listener].accept()?;
         //sys::set_mark(&client, OPTIONS.marker)?;
         client.set_nodelay(true)?;
         let dst_addr = sys::get_oridst_addr(&client)?;
         net_profiler.check(dst_addr.ip());
         log::info!("got new connection from:{} to:{}", src_addr, dst_addr);
-        if let Some(mut conn) = pool.get(poll, resolver) {
-            let index = next_index(&mut self.next_id);
-            if !conn.reset_index(index, Token(index * CHANNEL_CNT + CHANNEL_TCP), poll) {
-                conn.check_status(poll);
-            } else {
-                let mut conn = Connection::new(index, conn, dst_addr, client);
-                if conn.setup(poll) {
-                    self.conns.insert(conn.index(), conn);
+        self.connect(
+            poll,
+            client,
+            Sock5Address::Socket(dst_addr),
+            &[],
+            pool,
+            resolver,
+        );
+        Ok(())
+    }
+
+    /// Relays `client` to `dst_addr` through the server, `data` read from the client already is
+    /// sent along with the request.
+    pub fn connect(
+        &mut self,
+        poll: &Poll,
+        client: TcpStream,
+        dst_addr: Sock5Address,
+        data: &[u8],
+        pool: &mut IdlePool,
+        resolver: &DnsResolver,
+    ) {
+        let index = next_index(&mut self.next_id);
+        let server_conn = match &mut self.mux {
+            Some(mux) => mux.open(index, poll, pool, resolver).map(ServerConn::Mux),
+            None => pool.get(poll, resolver).and_then(|mut conn| {
+                if conn.reset_index(index, Token(index * CHANNEL_CNT + CHANNEL_TCP), poll) {
+                    Some(ServerConn::Tls(Box::new(conn)))
                 } else {
-                    conn.destroy(poll);
+                    conn.check_status(poll);
+                    None
+                }
+            }),
+        };
+        let Some(server_conn) = server_conn else {
+            log::error!("alloc new connection failed");
+            return;
+        };
+        let mut conn = Connection::new(index, server_conn, dst_addr, client);
+        if !conn.setup(poll, data) {
+            conn.destroy(poll);
+            return;
+        }
+        // no event of its own tells a stream of an established session it's writable
+        if matches!(conn.server_conn, ServerConn::Mux(_)) {
+            conn.server_ready(true, true);
+            conn.update(poll);
+        }
+        self.conns.insert(conn.index(), conn);
+    }
+
+    /// Handles an event of a mux session, its streams with news are woken up.
+    pub fn mux_ready(&mut self, event: &Event, poll: &Poll) {
+        let Some(mux) = self.mux.as_mut() else {
+            return;
+        };
+        for (index, readable, writable) in mux.ready(event, poll) {
+            if let Some(conn) = self.conns.get_mut(&index) {
+                conn.last_active_time = Instant::now();
+                conn.server_ready(readable, writable);
+                conn.update(poll);
+                if conn.destroyed() {
+                    self.removed.as_mut().unwrap().push(index);
                 }
             }
-        } else {
-            log::error!("alloc new connection failed")
         }
-        Ok(())
     }
 
     pub fn ready(&mut self, event: &Event, poll: &Poll) {
@@ -147,14 +199,17 @@ impl TcpServer {
         for index in list {
             let _ = self.conns.remove(&index);
         }
+        if let Some(mux) = self.mux.as_mut() {
+            mux.remove_closed(poll);
+        }
     }
 }
 
 impl Connection {
     fn new(
         index: usize,
-        server_conn: TlsConn,
-        dst_addr: SocketAddr,
+        server_conn: ServerConn,
+        dst_addr: Sock5Address,
         client: TcpStream,
     ) -> Connection {
         Connection {
@@ -186,9 +241,10 @@ impl Connection {
         self.server_conn.check_status(poll);
     }
 
-    fn setup(&mut self, poll: &Poll) -> bool {
+    fn setup(&mut self, poll: &Poll, data: &[u8]) -> bool {
         let mut request = BytesMut::new();
-        TrojanRequest::generate(&mut request, CONNECT, &self.dst_addr);
+        TrojanRequest::generate_address(&mut request, CONNECT, &self.dst_addr);
+        request.extend_from_slice(data);
         let token = self.client_token();
         if !self.server_conn.write_session(request.as_ref()) {
             false
@@ -237,30 +293,37 @@ impl Connection {
                     self.try_send_client(&[]);
                 }
             }
-            CHANNEL_TCP => {
-                if event.is_readable() {
-                    if self.writable() {
-                        self.try_read_server();
-                    } else {
-                        self.read_server = true;
-                        log::trace!(
-                            "client connection:{} is not writable, stop reading from server",
-                            self.index
-                        )
-                    }
-                    self.server_conn.do_send();
-                }
-
-                if event.is_writable() {
-                    self.server_conn.established();
-                    self.try_send_server();
-                }
-            }
+            CHANNEL_TCP => self.server_ready(event.is_readable(), event.is_writable()),
             _ => {
                 log::error!("invalid token found in tcp listener");
                 self.shutdown();
             }
         }
+        self.update(poll);
+    }
+
+    fn server_ready(&mut self, readable: bool, writable: bool) {
+        if readable {
+            if self.writable() {
+                self.try_read_server();
+            } else {
+                self.read_server = true;
+                log::trace!(
+                    "client connection:{} is not writable, stop reading from server",
+                    self.index
+                )
+            }
+            self.server_conn.do_send();
+        }
+
+        if writable {
+            self.server_conn.established();
+            self.try_send_server();
+        }
+    }
+
+    /// Passes the shutdown of one side on to the other.
+    fn update(&mut self, poll: &Poll) {
         if self.is_shutdown() {
             self.server_conn.peer_closed();
         }
@@ -286,7 +349,8 @@ impl Connection {
         {
             self.shutdown();
         }
-        self.read_client = false;
+        // resumed once the server connection takes more
+        self.read_client = !self.server_conn.writable();
 
         self.try_send_server();
     }
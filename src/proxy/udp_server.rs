@@ -19,7 +19,7 @@ use crate::{
 };
 
 pub struct UdpServer {
-    udp_listener: UdpSocket,
+    udp_listeners: Vec<UdpSocket>,
     conns: HashMap<usize, Arc<Connection>>,
     src_map: HashMap<SocketAddr, Arc<Connection>>,
     removed: Option<Vec<usize>>,
@@ -42,9 +42,9 @@ struct Connection {
 }
 
 impl UdpServer {
-    pub fn new(udp_listener: UdpSocket) -> UdpServer {
+    pub fn new(udp_listeners: Vec<UdpSocket>) -> UdpServer {
         UdpServer {
-            udp_listener,
+            udp_listeners,
             conns: HashMap::new(),
             src_map: HashMap::new(),
             removed: Some(Vec::new()),
@@ -53,16 +53,20 @@ impl UdpServer {
         }
     }
 
+    /// Receives the pending datagrams of the `listener`th local address.
     pub fn accept(
         &mut self,
         poll: &Poll,
+        listener: usize,
         pool: &mut IdlePool,
         udp_cache: &mut UdpSvrCache,
         resolver: &DnsResolver,
         net_profiler: &mut NetProfiler,
     ) {
         loop {
-            if let Err(err) = self.accept_once(poll, pool, udp_cache, resolver, net_profiler) {
+            if let Err(err) =
+                self.accept_once(poll, listener, pool, udp_cache, resolver, net_profiler)
+            {
                 if let TrojanError::StdIo(err) = &err {
                     if err.kind() == ErrorKind::WouldBlock {
                         break;
@@ -76,13 +80,16 @@ impl UdpServer {
     fn accept_once(
         &mut self,
         poll: &Poll,
+        listener: usize,
         pool: &mut IdlePool,
         udp_cache: &mut UdpSvrCache,
         resolver: &DnsResolver,
         net_profiler: &mut NetProfiler,
     ) -> Result<()> {
-        let (size, src_addr, dst_addr) =
-            sys::recv_from_with_destination(&self.udp_listener, self.recv_buffer.as_mut_slice())?;
+        let (size, src_addr, dst_addr) = sys::recv_from_with_destination(
+            &self.udp_listeners[listener],
+            self.recv_buffer.as_mut_slice(),
+        )?;
         net_profiler.check(dst_addr.ip());
         log::debug!(
             "udp received {} byte from {} to {}",
@@ -0,0 +1,444 @@
+//! Trojan over QUIC for `--transport quic` of awintun and aserver, so a lost packet only stalls
+//! the stream it belongs to instead of every stream of a TLS connection.
+//!
+//! Every TCP stream and UDP association is a bidirectional stream starting with its trojan
+//! request. The packets of an association don't go on its stream, which only lives as long as the
+//! association, but as datagrams each holding one trojan UDP packet after the index of the stream:
+//!
+//! `stream index(u64 be) | address | length(u16 be) | CRLF | payload`
+// the client side is only used by awintun
+#![cfg_attr(not(windows), allow(dead_code))]
+
+use std::{
+    collections::HashMap,
+    io,
+    io::ErrorKind,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use quinn::{
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    rustls::{self, crypto::ring::default_provider, version::TLS13, RootCertStore},
+    ClientConfig, Connection, Endpoint, EndpointConfig, RecvStream, SendDatagramError, SendStream,
+    ServerConfig, TokioRuntime, TransportConfig,
+};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    spawn,
+    sync::mpsc::{channel, Receiver, Sender},
+};
+
+use crate::{
+    config::OPTIONS,
+    metrics::{incr, record_rtt, server_result, COUNTERS},
+    proto::{UdpAssociate, UdpParseResult},
+    types::Result,
+};
+
+/// ALPN of the QUIC connections, the --alpn options only apply to TLS.
+const ALPN: &[u8] = b"trojan";
+/// Associations a connection may receive datagrams for before their request is read.
+const MAX_EARLY_ASSOCIATIONS: usize = 16;
+/// Datagrams queued for an association before more of them are dropped.
+const ASSOCIATION_QUEUE: usize = 256;
+const MAX_STREAMS: u32 = 1024;
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+lazy_static::lazy_static! {
+    static ref CLIENT: tokio::sync::Mutex<Option<(Connection, Associations)>> = Default::default();
+}
+
+fn transport_config() -> Arc<TransportConfig> {
+    let mut transport = TransportConfig::default();
+    transport
+        .max_concurrent_bidi_streams(MAX_STREAMS.into())
+        .max_idle_timeout(
+            Duration::from_secs(OPTIONS.tcp_idle_timeout)
+                .try_into()
+                .ok(),
+        )
+        .keep_alive_interval(Some(KEEP_ALIVE));
+    Arc::new(transport)
+}
+
+/// Client config trusting the web PKI, like the TLS clients without --pin-cert.
+fn client_config() -> Result<ClientConfig> {
+    let mut root_store = RootCertStore::empty();
+    root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let mut config = rustls::ClientConfig::builder_with_provider(default_provider().into())
+        .with_protocol_versions(&[&TLS13])?
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![ALPN.to_vec()];
+    let mut config = ClientConfig::new(Arc::new(QuicClientConfig::try_from(config)?));
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+/// Server config of the certificate chain `certs`, QUIC has no client auth or early data here.
+pub fn server_config(
+    certs: Vec<CertificateDer<'static>>,
+    private_key: PrivateKeyDer<'static>,
+) -> Result<ServerConfig> {
+    let mut config = rustls::ServerConfig::builder_with_provider(default_provider().into())
+        .with_protocol_versions(&[&TLS13])?
+        .with_no_client_auth()
+        .with_single_cert(certs, private_key)?;
+    config.alpn_protocols = vec![ALPN.to_vec()];
+    let mut config = ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(config)?));
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+/// Server endpoint on the bound udp `socket`, it refuses connections until it has a config.
+pub fn endpoint(socket: std::net::UdpSocket, config: Option<ServerConfig>) -> Result<Endpoint> {
+    Ok(Endpoint::new(
+        EndpointConfig::default(),
+        config,
+        socket,
+        Arc::new(TokioRuntime),
+    )?)
+}
+
+/// The associations of a connection by the index of their stream.
+#[derive(Clone, Default)]
+pub struct Associations(Arc<Mutex<HashMap<u64, Entry>>>);
+
+struct Entry {
+    sender: Sender<Bytes>,
+    /// taken when the association starts
+    receiver: Option<Receiver<Bytes>>,
+}
+
+impl Entry {
+    fn new() -> Self {
+        let (sender, receiver) = channel(ASSOCIATION_QUEUE);
+        Self {
+            sender,
+            receiver: Some(receiver),
+        }
+    }
+}
+
+impl Associations {
+    /// Datagrams of the association of stream `id`, those arriving ahead of it included.
+    fn register(&self, id: u64) -> Receiver<Bytes> {
+        let mut entries = self.0.lock().unwrap();
+        let entry = entries.entry(id).or_insert_with(Entry::new);
+        match entry.receiver.take() {
+            Some(receiver) => receiver,
+            None => {
+                *entry = Entry::new();
+                entry.receiver.take().unwrap()
+            }
+        }
+    }
+
+    fn remove(&self, id: u64) {
+        self.0.lock().unwrap().remove(&id);
+    }
+
+    fn dispatch(&self, id: u64, datagram: Bytes) {
+        let mut entries = self.0.lock().unwrap();
+        if !entries.contains_key(&id) {
+            let early = entries
+                .values()
+                .filter(|entry| entry.receiver.is_some())
+                .count();
+            if early >= MAX_EARLY_ASSOCIATIONS {
+                log::warn!("too many early associations, drop datagram of {}", id);
+                return;
+            }
+            entries.insert(id, Entry::new());
+        }
+        if entries[&id].sender.try_send(datagram).is_err() {
+            incr(&COUNTERS.backpressure);
+            log::warn!("association {} is full, drop datagram", id);
+        }
+    }
+}
+
+/// Hands the datagrams of `conn` to their associations until it closes, which ends them.
+async fn dispatch(conn: Connection, associations: Associations) {
+    loop {
+        match conn.read_datagram().await {
+            Ok(mut datagram) if datagram.len() > 8 => {
+                let id = datagram.get_u64();
+                associations.dispatch(id, datagram);
+            }
+            Ok(_) => log::warn!("invalid datagram from {}", conn.remote_address()),
+            Err(err) => {
+                log::info!("quic connection {} closed:{}", conn.remote_address(), err);
+                break;
+            }
+        }
+    }
+    associations.0.lock().unwrap().clear();
+}
+
+/// A bidirectional stream used as a byte stream.
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().send).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_shutdown(cx)
+    }
+}
+
+/// A UDP association, the trojan UDP packets written to it are sent as datagrams and those
+/// received are read from it. It ends when its stream or the connection does.
+pub struct Association {
+    stream: QuicStream,
+    conn: Connection,
+    id: u64,
+    associations: Associations,
+    receiver: Receiver<Bytes>,
+    /// datagram not read completely yet
+    received: Bytes,
+    /// packets not written completely yet
+    sending: BytesMut,
+}
+
+impl Association {
+    fn new(stream: QuicStream, conn: Connection, associations: Associations) -> Self {
+        let id = stream.recv.id().index();
+        let receiver = associations.register(id);
+        Self {
+            stream,
+            conn,
+            id,
+            associations,
+            receiver,
+            received: Bytes::new(),
+            sending: BytesMut::new(),
+        }
+    }
+}
+
+impl Drop for Association {
+    fn drop(&mut self) {
+        self.associations.remove(self.id);
+    }
+}
+
+impl AsyncRead for Association {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.received.is_empty() {
+                let n = this.received.len().min(buf.remaining());
+                buf.put_slice(&this.received[..n]);
+                this.received.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+            match this.receiver.poll_recv(cx) {
+                Poll::Ready(Some(datagram)) => {
+                    this.received = datagram;
+                    continue;
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => {}
+            }
+            // nothing follows the request on the stream, it's only watched for its end
+            let mut data = [0u8; 64];
+            let mut data = ReadBuf::new(&mut data);
+            ready!(Pin::new(&mut this.stream).poll_read(cx, &mut data))?;
+            if data.filled().is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Association {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.sending.extend_from_slice(buf);
+        loop {
+            let offset = match UdpAssociate::parse(this.sending.as_ref()) {
+                UdpParseResult::Packet(packet) => packet.offset,
+                UdpParseResult::Continued => break,
+                UdpParseResult::InvalidProtocol => {
+                    let err = io::Error::new(ErrorKind::InvalidData, "invalid udp packet");
+                    return Poll::Ready(Err(err));
+                }
+            };
+            let packet = this.sending.split_to(offset);
+            let mut datagram = BytesMut::with_capacity(8 + packet.len());
+            datagram.put_u64(this.id);
+            datagram.extend_from_slice(packet.as_ref());
+            match this.conn.send_datagram(datagram.freeze()) {
+                Ok(()) => {}
+                // like a packet dropped for the mtu
+                Err(SendDatagramError::TooLarge) => {
+                    log::warn!("skip udp packet of {} bytes over datagram size", offset);
+                }
+                Err(err) => return Poll::Ready(Err(io::Error::other(err))),
+            }
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.associations.remove(this.id);
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+/// A connection accepted by the server.
+#[derive(Clone)]
+pub struct Accepted {
+    conn: Connection,
+    associations: Associations,
+}
+
+impl Accepted {
+    pub fn new(conn: Connection) -> Self {
+        let associations = Associations::default();
+        spawn(dispatch(conn.clone(), associations.clone()));
+        Self { conn, associations }
+    }
+
+    /// The next stream opened by the client, None once the connection is closed.
+    pub async fn accept(&self) -> Option<QuicStream> {
+        match self.conn.accept_bi().await {
+            Ok((send, recv)) => Some(QuicStream { send, recv }),
+            Err(err) => {
+                log::info!("quic connection closed:{}", err);
+                None
+            }
+        }
+    }
+
+    /// Turns a stream whose UDP_ASSOCIATE request was read into the association.
+    pub fn associate(&self, stream: QuicStream) -> Association {
+        Association::new(stream, self.conn.clone(), self.associations.clone())
+    }
+}
+
+async fn connect() -> Result<Connection> {
+    let start = Instant::now();
+    let server_addr = *OPTIONS.back_addr.as_ref().unwrap();
+    let local_addr: SocketAddr = if server_addr.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let mut endpoint = Endpoint::client(local_addr)?;
+    endpoint.set_default_client_config(client_config()?);
+    // the endpoint keeps running as long as the connection does
+    let conn = endpoint
+        .connect(server_addr, OPTIONS.wintun_args().hostname.as_str())?
+        .await?;
+    record_rtt(start.elapsed());
+    Ok(conn)
+}
+
+/// The connection to the server, a new one is made when there is none yet or it is closed.
+async fn connection() -> Result<(Connection, Associations)> {
+    let mut current = CLIENT.lock().await;
+    if let Some((conn, associations)) = current.as_ref() {
+        if conn.close_reason().is_none() {
+            return Ok((conn.clone(), associations.clone()));
+        }
+    }
+    let conn = connect().await;
+    server_result(&conn);
+    let conn = conn?;
+    log::info!("new quic connection to server");
+    let associations = Associations::default();
+    spawn(dispatch(conn.clone(), associations.clone()));
+    current.replace((conn.clone(), associations.clone()));
+    Ok((conn, associations))
+}
+
+/// Opens a stream on the connection to the server.
+pub async fn open_stream() -> Result<QuicStream> {
+    let (conn, _) = connection().await?;
+    let (send, recv) = conn.open_bi().await?;
+    Ok(QuicStream { send, recv })
+}
+
+/// Opens a stream sending the UDP_ASSOCIATE `request` and returns the association.
+pub async fn open_association(request: &[u8]) -> Result<Association> {
+    let (conn, associations) = connection().await?;
+    let (send, recv) = conn.open_bi().await?;
+    let mut stream = QuicStream { send, recv };
+    stream.write_all(request).await?;
+    Ok(Association::new(stream, conn, associations))
+}
+
+/// Closes the connection to the server, the next stream connects through the current network.
+pub async fn reset() {
+    if let Some((conn, _)) = CLIENT.lock().await.take() {
+        log::warn!("quic connection to server dropped");
+        conn.close(0u32.into(), b"network changed");
+    }
+}
+
+mod tests {
+    #[tokio::test]
+    async fn test_association() {
+        use crate::quic::{Associations, MAX_EARLY_ASSOCIATIONS};
+
+        let associations = Associations::default();
+        // datagrams may arrive ahead of the request of their stream
+        associations.dispatch(4, "early".into());
+        let mut receiver = associations.register(4);
+        assert_eq!(receiver.recv().await.unwrap().as_ref(), b"early");
+        associations.dispatch(4, "late".into());
+        assert_eq!(receiver.recv().await.unwrap().as_ref(), b"late");
+        associations.remove(4);
+        assert!(receiver.recv().await.is_none());
+
+        // streams never starting an association can't hold datagrams without a limit
+        for id in 0..MAX_EARLY_ASSOCIATIONS as u64 + 1 {
+            associations.dispatch(id, "early".into());
+        }
+        assert_eq!(associations.0.lock().unwrap().len(), MAX_EARLY_ASSOCIATIONS);
+    }
+}
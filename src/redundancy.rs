@@ -0,0 +1,295 @@
+//! Copies of the datagrams of a redundant UDP association, which aproxy sends through its
+//! --redundant-server to the --relay-port of the server at --hostname and back. They travel
+//! between the servers as cleartext UDP, so each one is authenticated with a key the
+//! association registered over its TLS connection:
+//!
+//! `[id(16), upstream only] | sequence(u64 be) | tag(16) | address | payload`
+//!
+//! The tag is the truncated HMAC-SHA256 of the direction, the sequence, the address and the
+//! payload. Sequences are checked against a sliding window, so a captured copy can't be replayed.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::{Duration, Instant},
+};
+
+use bytes::{BufMut, BytesMut};
+use ring::hmac;
+
+use crate::proto::{parse_address, AddressParseResult, Sock5Address};
+
+/// Copies of a packet arriving further apart are taken as different packets.
+const DEDUP_WINDOW: Duration = Duration::from_secs(2);
+const TAG_LEN: usize = 16;
+/// Sequences this far behind the highest one received are dropped as replays.
+const REPLAY_WINDOW: u64 = 64;
+
+/// Random id of an association at the --relay-port of the server.
+pub type RelayId = [u8; 16];
+/// Random key authenticating the copies of an association.
+pub type RelayKey = [u8; 32];
+
+/// Target of the datagram registering an association at the relay point, no real target.
+pub const REGISTER_TARGET: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
+/// Direction of a copy, part of its tag so copies of one direction can't be sent back.
+#[derive(Clone, Copy)]
+pub enum Direction {
+    /// from aproxy to the relay point
+    Up = 0,
+    /// from the relay point back to aproxy
+    Down = 1,
+}
+
+/// Payload of the registration sent through the association to --hostname, its id, its key and
+/// --redundant-size.
+pub fn registration(id: &RelayId, key: &RelayKey, size: usize) -> Vec<u8> {
+    let mut payload = id.to_vec();
+    payload.extend_from_slice(&(size.min(u16::MAX as usize) as u16).to_be_bytes());
+    payload.extend_from_slice(key);
+    payload
+}
+
+/// Id, key and largest size of copies of a registration.
+pub fn parse_registration(payload: &[u8]) -> Option<(RelayId, RelayKey, usize)> {
+    let id = payload.get(..16)?.try_into().unwrap();
+    let size = payload.get(16..18)?;
+    let key = payload.get(18..50)?.try_into().unwrap();
+    Some((id, key, u16::from_be_bytes([size[0], size[1]]) as usize))
+}
+
+/// Splits an upstream copy into the id of its association and the rest.
+pub fn split_id(buffer: &[u8]) -> Option<(RelayId, &[u8])> {
+    let id = buffer.get(..16)?.try_into().unwrap();
+    Some((id, &buffer[16..]))
+}
+
+fn tag(key: &hmac::Key, direction: Direction, sequence: u64, body: &[u8]) -> hmac::Tag {
+    let mut context = hmac::Context::with_key(key);
+    context.update(&[direction as u8]);
+    context.update(&sequence.to_be_bytes());
+    context.update(body);
+    context.sign()
+}
+
+/// Writes the copies of one direction of an association.
+pub struct Sealer {
+    key: hmac::Key,
+    direction: Direction,
+    sequence: u64,
+}
+
+impl Sealer {
+    pub fn new(key: &RelayKey, direction: Direction) -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, key),
+            direction,
+            sequence: 0,
+        }
+    }
+
+    /// Writes a copy of `payload` to `address`, prefixed by the id of the association if it goes
+    /// upstream.
+    pub fn wrap(
+        &mut self,
+        buffer: &mut BytesMut,
+        id: Option<&RelayId>,
+        address: &SocketAddr,
+        payload: &[u8],
+    ) {
+        self.sequence += 1;
+        let mut body = BytesMut::new();
+        Sock5Address::generate(&mut body, address);
+        body.put_slice(payload);
+        let tag = tag(&self.key, self.direction, self.sequence, body.as_ref());
+        buffer.clear();
+        if let Some(id) = id {
+            buffer.put_slice(id);
+        }
+        buffer.put_u64(self.sequence);
+        buffer.put_slice(&tag.as_ref()[..TAG_LEN]);
+        buffer.put_slice(body.as_ref());
+    }
+}
+
+/// Checks the copies of one direction of an association.
+pub struct Opener {
+    key: hmac::Key,
+    direction: Direction,
+    /// highest sequence accepted
+    highest: u64,
+    /// bit n set if `highest - n` was accepted
+    seen: u64,
+}
+
+impl Opener {
+    pub fn new(key: &RelayKey, direction: Direction) -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, key),
+            direction,
+            highest: 0,
+            seen: 0,
+        }
+    }
+
+    /// Splits a copy without the id into the address of the target and the payload, None if it's
+    /// invalid, forged or a replay.
+    pub fn unwrap<'a>(&mut self, buffer: &'a [u8]) -> Option<(Sock5Address, &'a [u8])> {
+        let sequence = u64::from_be_bytes(buffer.get(..8)?.try_into().unwrap());
+        let given = buffer.get(8..8 + TAG_LEN)?;
+        let body = &buffer[8 + TAG_LEN..];
+        let expected = tag(&self.key, self.direction, sequence, body);
+        let diff = given
+            .iter()
+            .zip(expected.as_ref())
+            .fold(0, |diff, (a, b)| diff | (a ^ b));
+        if diff != 0 || !self.accept(sequence) {
+            return None;
+        }
+        let (&atyp, body) = body.split_first()?;
+        if body.is_empty() {
+            return None;
+        }
+        match parse_address(atyp, body) {
+            AddressParseResult::Address((size, address)) => Some((address, &body[size..])),
+            _ => None,
+        }
+    }
+
+    fn accept(&mut self, sequence: u64) -> bool {
+        if sequence > self.highest {
+            let shift = sequence - self.highest;
+            self.seen = if shift >= REPLAY_WINDOW {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.highest = sequence;
+            return true;
+        }
+        let behind = self.highest - sequence;
+        if sequence == 0 || behind >= REPLAY_WINDOW || self.seen & (1 << behind) != 0 {
+            return false;
+        }
+        self.seen |= 1 << behind;
+        true
+    }
+}
+
+/// Drops the copy of a packet already received through the other path. Packets are counted per
+/// path, so one really sent twice by the peer still gets through twice.
+#[derive(Default)]
+pub struct Dedup {
+    seen: HashMap<u64, (Instant, [u32; 2])>,
+    last_purge: Option<Instant>,
+}
+
+impl Dedup {
+    /// Returns true if the packet from `addr` received through `path`, 0 or 1, is to be delivered.
+    pub fn admit(&mut self, path: usize, addr: &impl Hash, payload: &[u8]) -> bool {
+        let now = Instant::now();
+        if self.last_purge.is_none_or(|time| now - time > DEDUP_WINDOW) {
+            self.seen.retain(|_, (time, _)| now - *time < DEDUP_WINDOW);
+            self.last_purge = Some(now);
+        }
+        let mut hasher = DefaultHasher::new();
+        addr.hash(&mut hasher);
+        payload.hash(&mut hasher);
+        let (time, counts) = self.seen.entry(hasher.finish()).or_insert((now, [0, 0]));
+        if now - *time >= DEDUP_WINDOW {
+            *time = now;
+            *counts = [0, 0];
+        }
+        let fresh = counts[path] >= counts[1 - path];
+        counts[path] += 1;
+        fresh
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_dedup() {
+        use std::net::SocketAddr;
+
+        use super::Dedup;
+
+        let addr: SocketAddr = "8.8.8.8:53".parse().unwrap();
+        let other: SocketAddr = "1.1.1.1:53".parse().unwrap();
+        let mut dedup = Dedup::default();
+        assert!(dedup.admit(0, &addr, b"answer"));
+        assert!(!dedup.admit(1, &addr, b"answer"));
+        assert!(dedup.admit(1, &other, b"answer"));
+        assert!(!dedup.admit(0, &other, b"answer"));
+
+        // sent twice by the peer, and the first copy through path 0 lost
+        assert!(dedup.admit(1, &addr, b"again"));
+        assert!(dedup.admit(1, &addr, b"again"));
+        assert!(!dedup.admit(0, &addr, b"again"));
+        assert!(dedup.admit(0, &addr, b"answer"));
+    }
+
+    #[test]
+    fn test_relay_format() {
+        use std::net::SocketAddr;
+
+        use bytes::BytesMut;
+
+        use super::{parse_registration, registration, split_id, Direction, Opener, Sealer};
+        use crate::proto::Sock5Address;
+
+        let (id, key) = ([7u8; 16], [9u8; 32]);
+        assert_eq!(
+            parse_registration(&registration(&id, &key, 256)),
+            Some((id, key, 256))
+        );
+        assert_eq!(
+            parse_registration(&registration(&id, &key, 100000)),
+            Some((id, key, 65535))
+        );
+        assert_eq!(parse_registration(&id), None);
+
+        let addr: SocketAddr = "[2001:db8::1]:53".parse().unwrap();
+        let mut sealer = Sealer::new(&key, Direction::Up);
+        let mut opener = Opener::new(&key, Direction::Up);
+        let mut buffer = BytesMut::new();
+        sealer.wrap(&mut buffer, Some(&id), &addr, b"query");
+        let (copy_id, copy) = split_id(&buffer).unwrap();
+        assert_eq!(copy_id, id);
+        let Some((Sock5Address::Socket(target), payload)) = opener.unwrap(copy) else {
+            panic!("copy not parsed");
+        };
+        assert_eq!((target, payload), (addr, &b"query"[..]));
+        // replayed
+        assert!(opener.unwrap(copy).is_none());
+
+        // forged, of the other direction or of another key
+        let mut forged = copy.to_vec();
+        *forged.last_mut().unwrap() ^= 1;
+        assert!(Opener::new(&key, Direction::Up).unwrap(&forged).is_none());
+        assert!(Opener::new(&key, Direction::Down).unwrap(copy).is_none());
+        assert!(Opener::new(&[1u8; 32], Direction::Up)
+            .unwrap(copy)
+            .is_none());
+
+        // reordered within the window
+        let mut sealer = Sealer::new(&key, Direction::Down);
+        let mut opener = Opener::new(&key, Direction::Down);
+        let copies: Vec<_> = (0..3)
+            .map(|_| {
+                sealer.wrap(&mut buffer, None, &addr, b"");
+                buffer.to_vec()
+            })
+            .collect();
+        assert!(opener.unwrap(&copies[2]).is_some());
+        assert!(matches!(
+            opener.unwrap(&copies[0]),
+            Some((Sock5Address::Socket(target), b"")) if target == addr
+        ));
+        assert!(opener.unwrap(&copies[0]).is_none());
+        assert!(opener.unwrap(&copies[1]).is_some());
+        assert!(opener.unwrap(&copies[1][..20]).is_none());
+    }
+}
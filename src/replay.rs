@@ -0,0 +1,592 @@
+//! Developer mode replaying a captured pcap through the smoltcp stack of the tun modes. The
+//! packets the client sent are fed one per poll in capture order, and the trojan frames the
+//! accepted streams and datagrams would go to the server with are written to a file instead of
+//! a server, so a capture of a user reported issue gives the same frames on every run.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+use async_smoltcp::{Packet, TcpStream, Tun, TunDevice, UdpSocket};
+use bytes::BytesMut;
+use smoltcp::wire::{
+    IpAddress, IpProtocol, IpVersion, Ipv4Packet, Ipv6Packet, TcpPacket, UdpPacket,
+};
+use tokio::{io::AsyncReadExt, runtime::Builder, spawn, task::yield_now};
+
+use crate::{
+    config::OPTIONS,
+    proto::{Sock5Address, TrojanRequest, UdpAssociate, CONNECT, UDP_ASSOCIATE},
+    types::{Result, TrojanError},
+};
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+
+/// polls a tcp packet waits for the stack to answer the syn of its connection
+const MAX_SYN_WAIT: u32 = 100;
+/// polls after the last packet for the streams to pass on what they got
+const SETTLE_POLLS: u32 = 1000;
+
+/// A connection of the capture, a udp socket of the stack takes everything sent to its target.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum Conn {
+    Tcp(SocketAddr, SocketAddr),
+    Udp(SocketAddr),
+}
+
+/// Frames of each connection, in the order they got them.
+type Records = Arc<Mutex<HashMap<Conn, BytesMut>>>;
+
+pub fn run() -> Result<()> {
+    let args = OPTIONS.replay_args();
+    let packets = read_pcap(fs::read(&args.pcap)?.as_slice())?;
+    let (packets, conns) = client_packets(packets);
+    log::warn!(
+        "replay {} packets of {} connections from {}",
+        packets.len(),
+        conns.len(),
+        args.pcap
+    );
+    let runtime = Builder::new_current_thread().enable_all().build()?;
+    let records = runtime.block_on(replay(packets));
+    let records = records.lock().unwrap();
+    let mut output = Vec::new();
+    for conn in conns {
+        let Some(frames) = records.get(&conn) else {
+            continue;
+        };
+        match conn {
+            Conn::Tcp(src, dst) => print!("tcp {} -> {}", src, dst),
+            Conn::Udp(dst) => print!("udp -> {}", dst),
+        }
+        println!(" offset:{} length:{}", output.len(), frames.len());
+        output.extend_from_slice(frames.as_ref());
+    }
+    fs::write(&args.output, output)?;
+    Ok(())
+}
+
+async fn replay(packets: Vec<Vec<u8>>) -> Records {
+    let mtu = packets.iter().map(Vec::len).max().unwrap_or(0).max(1500);
+    let tun = PcapTun::new(packets, mtu);
+    let mut device = TunDevice::new(tun.clone());
+    device.allow_private(true);
+    let records = Records::default();
+    let mut settled = 0;
+    while settled < SETTLE_POLLS {
+        tun.tick();
+        let (tcp, udp) = device.poll();
+        for stream in tcp {
+            spawn(record_tcp(stream, records.clone()));
+        }
+        for socket in udp {
+            spawn(record_udp(socket, records.clone()));
+        }
+        if tun.is_done() {
+            settled += 1;
+        }
+        // the recording tasks run on this thread, in the same order every time
+        yield_now().await;
+    }
+    records
+}
+
+async fn record_tcp(mut stream: TcpStream, records: Records) {
+    let conn = Conn::Tcp(stream.local_addr(), stream.peer_addr());
+    let mut request = BytesMut::new();
+    TrojanRequest::generate_stream(
+        &mut request,
+        CONNECT,
+        &Sock5Address::Socket(stream.peer_addr()),
+    );
+    records.lock().unwrap().insert(conn, request);
+    let mut buffer = vec![0u8; 4096];
+    while let Ok(n) = stream.read(buffer.as_mut_slice()).await {
+        if n == 0 {
+            break;
+        }
+        if let Some(frames) = records.lock().unwrap().get_mut(&conn) {
+            frames.extend_from_slice(&buffer[..n]);
+        }
+    }
+}
+
+async fn record_udp(mut socket: UdpSocket, records: Records) {
+    let conn = Conn::Udp(socket.peer_addr_std());
+    let empty = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+    let mut request = BytesMut::new();
+    TrojanRequest::generate(&mut request, UDP_ASSOCIATE, &empty);
+    records.lock().unwrap().insert(conn, request);
+    while let Ok((_, data)) = socket.recv_from().await {
+        if data.is_empty() {
+            continue;
+        }
+        if let Some(frames) = records.lock().unwrap().get_mut(&conn) {
+            UdpAssociate::generate_endpoint(frames, &socket.peer_addr(), data.len() as u16);
+            frames.extend_from_slice(data.as_ref());
+        }
+    }
+}
+
+/// The ip packets of a classic pcap file, in either byte order and timestamp precision.
+fn read_pcap(data: &[u8]) -> Result<Vec<Vec<u8>>> {
+    if data.len() < 24 {
+        return Err(TrojanError::Replay("pcap file too short"));
+    }
+    let magic = [data[0], data[1], data[2], data[3]];
+    let big_endian = match magic {
+        [0xa1, 0xb2, 0xc3, 0xd4] | [0xa1, 0xb2, 0x3c, 0x4d] => true,
+        [0xd4, 0xc3, 0xb2, 0xa1] | [0x4d, 0x3c, 0xb2, 0xa1] => false,
+        _ => {
+            return Err(TrojanError::Replay(
+                "not a pcap file, pcapng is not supported",
+            ))
+        }
+    };
+    let read_u32 = |offset: usize| {
+        let bytes = [
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ];
+        if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    };
+    let link_type = read_u32(20) & 0xffff;
+    let mut packets = Vec::new();
+    let mut offset = 24;
+    while offset + 16 <= data.len() {
+        let captured = read_u32(offset + 8) as usize;
+        let original = read_u32(offset + 12) as usize;
+        let start = offset + 16;
+        if start + captured > data.len() {
+            log::warn!("pcap file truncated at {}", offset);
+            break;
+        }
+        offset = start + captured;
+        if captured < original {
+            log::warn!("skip packet truncated by the capture at {}", start);
+            continue;
+        }
+        if let Some(packet) = ip_payload(link_type, &data[start..offset])? {
+            packets.push(packet.to_vec());
+        }
+    }
+    Ok(packets)
+}
+
+/// The ip packet in a frame of `link_type`, None if it carries something else.
+fn ip_payload(link_type: u32, frame: &[u8]) -> Result<Option<&[u8]>> {
+    let (ether_type, payload) = match link_type {
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => return Ok(Some(frame)),
+        // the address family in host byte order, the version of the packet tells as well
+        LINKTYPE_NULL if frame.len() >= 4 => return Ok(Some(&frame[4..])),
+        LINKTYPE_ETHERNET if frame.len() >= 14 => {
+            let ether_type = u16::from_be_bytes([frame[12], frame[13]]);
+            if ether_type == 0x8100 && frame.len() >= 18 {
+                (u16::from_be_bytes([frame[16], frame[17]]), &frame[18..])
+            } else {
+                (ether_type, &frame[14..])
+            }
+        }
+        LINKTYPE_LINUX_SLL if frame.len() >= 16 => {
+            (u16::from_be_bytes([frame[14], frame[15]]), &frame[16..])
+        }
+        LINKTYPE_NULL | LINKTYPE_ETHERNET | LINKTYPE_LINUX_SLL => return Ok(None),
+        _ => return Err(TrojanError::Replay("unsupported pcap link type")),
+    };
+    Ok(matches!(ether_type, 0x0800 | 0x86dd).then_some(payload))
+}
+
+/// Tcp or udp endpoints of an ip packet.
+struct Flow {
+    protocol: IpProtocol,
+    src: SocketAddr,
+    dst: SocketAddr,
+    /// a syn without ack, the start of a tcp connection
+    syn: bool,
+}
+
+fn parse_flow(packet: &[u8]) -> Option<Flow> {
+    let (src, dst, protocol, payload) = match IpVersion::of_packet(packet).ok()? {
+        IpVersion::Ipv4 => {
+            let packet = Ipv4Packet::new_checked(packet).ok()?;
+            if packet.more_frags() || packet.frag_offset() != 0 {
+                return None;
+            }
+            (
+                IpAddr::V4(packet.src_addr().into()),
+                IpAddr::V4(packet.dst_addr().into()),
+                packet.next_header(),
+                packet.payload(),
+            )
+        }
+        IpVersion::Ipv6 => {
+            let packet = Ipv6Packet::new_checked(packet).ok()?;
+            (
+                IpAddr::V6(packet.src_addr().into()),
+                IpAddr::V6(packet.dst_addr().into()),
+                packet.next_header(),
+                packet.payload(),
+            )
+        }
+    };
+    let (src_port, dst_port, syn) = match protocol {
+        IpProtocol::Tcp => {
+            let packet = TcpPacket::new_checked(payload).ok()?;
+            (
+                packet.src_port(),
+                packet.dst_port(),
+                packet.syn() && !packet.ack(),
+            )
+        }
+        IpProtocol::Udp => {
+            let packet = UdpPacket::new_checked(payload).ok()?;
+            (packet.src_port(), packet.dst_port(), false)
+        }
+        _ => return None,
+    };
+    Some(Flow {
+        protocol,
+        src: SocketAddr::new(src, src_port),
+        dst: SocketAddr::new(dst, dst_port),
+        syn,
+    })
+}
+
+/// Keeps the packets the client sent, those a tun adapter gets, and the connections they
+/// belong to in the order they started. The client of a tcp connection is the side of the syn,
+/// connections whose syn is not captured are left out. The client of udp is the side which sent
+/// first.
+fn client_packets(packets: Vec<Vec<u8>>) -> (Vec<Vec<u8>>, Vec<Conn>) {
+    let mut tcp = HashSet::new();
+    let mut udp = HashSet::new();
+    let mut conns = Vec::new();
+    let mut kept = Vec::new();
+    for packet in packets {
+        let Some(flow) = parse_flow(packet.as_slice()) else {
+            continue;
+        };
+        let pair = (flow.src, flow.dst);
+        let client = if flow.protocol == IpProtocol::Tcp {
+            if flow.syn && tcp.insert(pair) {
+                conns.push(Conn::Tcp(flow.src, flow.dst));
+            }
+            tcp.contains(&pair)
+        } else {
+            if !udp.contains(&(flow.dst, flow.src)) && udp.insert(pair) {
+                let conn = Conn::Udp(flow.dst);
+                if !conns.contains(&conn) {
+                    conns.push(conn);
+                }
+            }
+            udp.contains(&pair)
+        };
+        if client {
+            kept.push(packet);
+        }
+    }
+    (kept, conns)
+}
+
+struct Replay {
+    packets: VecDeque<Vec<u8>>,
+    mtu: usize,
+    /// whether a packet may be fed in this poll
+    ready: bool,
+    /// polls the next packet has waited for the syn ack of its connection
+    waited: u32,
+    /// next sequence number of the stack for each tcp connection, the client's acks are
+    /// rewritten to it as the stack picks its own initial sequence number and sends nothing
+    /// but acks
+    next_seq: HashMap<(SocketAddr, SocketAddr), u32>,
+}
+
+/// A [`Tun`] handing out the captured packets, answers of the stack are only used to follow
+/// its sequence numbers.
+#[derive(Clone)]
+struct PcapTun(Arc<Mutex<Replay>>);
+
+impl PcapTun {
+    fn new(packets: Vec<Vec<u8>>, mtu: usize) -> PcapTun {
+        PcapTun(Arc::new(Mutex::new(Replay {
+            packets: packets.into(),
+            mtu,
+            ready: false,
+            waited: 0,
+            next_seq: HashMap::new(),
+        })))
+    }
+
+    /// Lets the next packet in.
+    fn tick(&self) {
+        self.0.lock().unwrap().ready = true;
+    }
+
+    fn is_done(&self) -> bool {
+        self.0.lock().unwrap().packets.is_empty()
+    }
+}
+
+impl Replay {
+    fn next_packet(&mut self) -> Option<Vec<u8>> {
+        if !self.ready {
+            return None;
+        }
+        self.ready = false;
+        let mut packet = self.packets.pop_front()?;
+        match parse_flow(packet.as_slice()) {
+            Some(flow) if flow.protocol == IpProtocol::Tcp && !flow.syn => {
+                match self.next_seq.get(&(flow.src, flow.dst)) {
+                    Some(seq) => rewrite_ack(packet.as_mut_slice(), *seq),
+                    None if self.waited < MAX_SYN_WAIT => {
+                        self.waited += 1;
+                        self.packets.push_front(packet);
+                        return None;
+                    }
+                    // the stack refused the connection, it resets whatever comes next
+                    None => fill_checksums(packet.as_mut_slice()),
+                }
+            }
+            _ => fill_checksums(packet.as_mut_slice()),
+        }
+        self.waited = 0;
+        Some(packet)
+    }
+
+    fn follow(&mut self, packet: &[u8]) {
+        let Some(flow) = parse_flow(packet) else {
+            return;
+        };
+        if flow.protocol != IpProtocol::Tcp {
+            return;
+        }
+        let Some((seq, len, syn, fin)) = tcp_segment(packet) else {
+            return;
+        };
+        let end = seq.wrapping_add(len as u32 + syn as u32 + fin as u32);
+        let next = self.next_seq.entry((flow.dst, flow.src)).or_insert(end);
+        if (end.wrapping_sub(*next) as i32) > 0 {
+            *next = end;
+        }
+    }
+}
+
+/// Sequence number, payload length and flags of the tcp segment in `packet`.
+fn tcp_segment(packet: &[u8]) -> Option<(u32, usize, bool, bool)> {
+    let payload = match IpVersion::of_packet(packet).ok()? {
+        IpVersion::Ipv4 => Ipv4Packet::new_checked(packet).ok()?.payload(),
+        IpVersion::Ipv6 => Ipv6Packet::new_checked(packet).ok()?.payload(),
+    };
+    let segment = TcpPacket::new_checked(payload).ok()?;
+    Some((
+        segment.seq_number().0 as u32,
+        segment.payload().len(),
+        segment.syn(),
+        segment.fin(),
+    ))
+}
+
+/// Sets the ack number of the tcp segment in `packet` and fixes the checksums.
+fn rewrite_ack(packet: &mut [u8], ack: u32) {
+    with_transport(packet, |protocol, src, dst, payload| {
+        if protocol == IpProtocol::Tcp {
+            let mut segment = TcpPacket::new_unchecked(&mut *payload);
+            if segment.ack() {
+                segment.set_ack_number(smoltcp::wire::TcpSeqNumber(ack as i32));
+            }
+        }
+        fill_transport_checksum(protocol, src, dst, payload);
+    });
+}
+
+/// Fixes the checksums, captures of a nic with checksum offload have wrong ones.
+fn fill_checksums(packet: &mut [u8]) {
+    with_transport(packet, fill_transport_checksum);
+}
+
+fn fill_transport_checksum(
+    protocol: IpProtocol,
+    src: &IpAddress,
+    dst: &IpAddress,
+    payload: &mut [u8],
+) {
+    match protocol {
+        IpProtocol::Tcp => TcpPacket::new_unchecked(payload).fill_checksum(src, dst),
+        IpProtocol::Udp => UdpPacket::new_unchecked(payload).fill_checksum(src, dst),
+        _ => {}
+    }
+}
+
+/// Calls `f` with the transport payload of a packet [`parse_flow`] accepted, then fixes the ip
+/// header checksum.
+fn with_transport(
+    packet: &mut [u8],
+    f: impl FnOnce(IpProtocol, &IpAddress, &IpAddress, &mut [u8]),
+) {
+    match IpVersion::of_packet(packet) {
+        Ok(IpVersion::Ipv4) => {
+            let mut packet = Ipv4Packet::new_unchecked(packet);
+            let src = IpAddress::Ipv4(packet.src_addr());
+            let dst = IpAddress::Ipv4(packet.dst_addr());
+            f(packet.next_header(), &src, &dst, packet.payload_mut());
+            packet.fill_checksum();
+        }
+        Ok(IpVersion::Ipv6) => {
+            let mut packet = Ipv6Packet::new_unchecked(packet);
+            let src = IpAddress::Ipv6(packet.src_addr());
+            let dst = IpAddress::Ipv6(packet.dst_addr());
+            f(packet.next_header(), &src, &dst, packet.payload_mut());
+        }
+        Err(_) => {}
+    }
+}
+
+impl Tun for PcapTun {
+    type Packet = ReplayPacket;
+
+    fn receive(&self) -> std::io::Result<Option<Self::Packet>> {
+        Ok(self.0.lock().unwrap().next_packet().map(ReplayPacket))
+    }
+
+    fn send(&self, packet: Self::Packet) -> std::io::Result<()> {
+        self.0.lock().unwrap().follow(packet.as_ref());
+        Ok(())
+    }
+
+    fn allocate_packet(&self, len: usize) -> std::io::Result<Self::Packet> {
+        Ok(ReplayPacket(vec![0; len]))
+    }
+
+    fn mtu(&self) -> usize {
+        self.0.lock().unwrap().mtu
+    }
+}
+
+pub struct ReplayPacket(Vec<u8>);
+
+impl Packet for ReplayPacket {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self.0.as_mut_slice()
+    }
+
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_slice()
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_client_packets() {
+        use smoltcp::wire::{IpProtocol, Ipv4Packet, TcpPacket};
+
+        use super::{client_packets, parse_flow, read_pcap, rewrite_ack, tcp_segment, Conn};
+
+        fn ipv4(src: [u8; 4], dst: [u8; 4], protocol: u8, payload: &[u8]) -> Vec<u8> {
+            let mut packet = vec![
+                0x45,
+                0,
+                0,
+                20 + payload.len() as u8,
+                0,
+                1,
+                0,
+                0,
+                64,
+                protocol,
+            ];
+            packet.extend_from_slice(&[0, 0]);
+            packet.extend_from_slice(&src);
+            packet.extend_from_slice(&dst);
+            packet.extend_from_slice(payload);
+            packet
+        }
+
+        fn tcp(src: u16, dst: u16, seq: u32, ack: u32, flags: u8) -> Vec<u8> {
+            let mut segment = Vec::new();
+            segment.extend_from_slice(&src.to_be_bytes());
+            segment.extend_from_slice(&dst.to_be_bytes());
+            segment.extend_from_slice(&seq.to_be_bytes());
+            segment.extend_from_slice(&ack.to_be_bytes());
+            segment.extend_from_slice(&[0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+            segment
+        }
+
+        let (client, server, dns) = ([10, 0, 0, 2], [1, 1, 1, 1], [8, 8, 8, 8]);
+        let packets = [
+            ipv4(client, server, 6, &tcp(4000, 443, 100, 0, 0x02)),
+            ipv4(server, client, 6, &tcp(443, 4000, 900, 101, 0x12)),
+            ipv4(client, server, 6, &tcp(4000, 443, 101, 901, 0x10)),
+            ipv4(client, dns, 17, &[0x9c, 0x40, 0, 53, 0, 9, 0, 0, 7]),
+            ipv4(dns, client, 17, &[0, 53, 0x9c, 0x40, 0, 9, 0, 0, 8]),
+            // started before the capture
+            ipv4(client, server, 6, &tcp(4001, 443, 5, 7, 0x10)),
+        ];
+        // big endian pcap of ethernet frames, with a non ip frame
+        let mut pcap = vec![0xa1, 0xb2, 0xc3, 0xd4, 0, 2, 0, 4];
+        pcap.extend_from_slice(&[0; 8]);
+        pcap.extend_from_slice(&[0, 0, 0xff, 0xff, 0, 0, 0, 1]);
+        let frame = |ether_type: [u8; 2], payload: &[u8]| {
+            let mut frame = vec![0u8; 12];
+            frame.extend_from_slice(&ether_type);
+            frame.extend_from_slice(payload);
+            frame
+        };
+        let mut frames: Vec<_> = packets
+            .iter()
+            .map(|packet| frame([0x08, 0x00], packet))
+            .collect();
+        frames.push(frame([0x08, 0x06], &[0, 1]));
+        for frame in frames {
+            pcap.extend_from_slice(&[0; 8]);
+            pcap.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            pcap.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            pcap.extend_from_slice(frame.as_slice());
+        }
+        let read = read_pcap(pcap.as_slice()).unwrap();
+        assert_eq!(read, packets);
+        assert!(read_pcap(&[0x0a, 0x0d, 0x0d, 0x0a]).is_err());
+
+        let (kept, conns) = client_packets(read);
+        assert_eq!(kept.len(), 3);
+        assert!(kept
+            .iter()
+            .all(|packet| parse_flow(packet).unwrap().src.ip().to_string() == "10.0.0.2"));
+        assert_eq!(
+            conns,
+            vec![
+                Conn::Tcp(
+                    "10.0.0.2:4000".parse().unwrap(),
+                    "1.1.1.1:443".parse().unwrap()
+                ),
+                Conn::Udp("8.8.8.8:53".parse().unwrap()),
+            ]
+        );
+        assert_eq!(parse_flow(&kept[2]).unwrap().protocol, IpProtocol::Udp);
+
+        let mut ack = kept[1].clone();
+        rewrite_ack(ack.as_mut_slice(), 12345);
+        let packet = Ipv4Packet::new_checked(ack.as_slice()).unwrap();
+        assert!(packet.verify_checksum());
+        let segment = TcpPacket::new_checked(packet.payload()).unwrap();
+        assert_eq!(segment.ack_number().0, 12345);
+        assert!(segment.verify_checksum(&packet.src_addr().into(), &packet.dst_addr().into()));
+        assert_eq!(tcp_segment(ack.as_slice()), Some((101, 0, false, false)));
+    }
+}
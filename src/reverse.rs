@@ -0,0 +1,351 @@
+//! Services of a client published on ports of the server, like frp.
+//!
+//! A client opens the connection with the `REVERSE` command followed by the names of its
+//! services, `count(u8) | (length(u8) | name)*`, and the server answers a status byte for each
+//! of them; only the user a service is bound to may publish it. The connection then carries a mux session, in which the server opens a stream for
+//! every visitor of a published service, starting with `length(u8) | name`.
+
+use std::{future::Future, net::SocketAddr, sync::Arc, time::Duration};
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::{
+    io::{copy_bidirectional, split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    spawn,
+    sync::mpsc::channel,
+    time::timeout,
+};
+use tokio_rustls::client::TlsStream;
+
+use crate::{
+    async_utils::{copy_with, AbortOnDrop},
+    backoff::Backoff,
+    config::{ReverseService, OPTIONS},
+    limiter::Priority,
+    mux::Session,
+    proto::{TrojanRequest, REVERSE},
+    server::usage::Usage,
+    types::{Result, TrojanError},
+};
+
+/// the service is published
+pub const PUBLISHED: u8 = 0;
+/// the server has no `--reverse-port` for the service
+pub const UNKNOWN: u8 = 1;
+/// the port is taken, usually by another client publishing the service
+pub const UNAVAILABLE: u8 = 2;
+/// the service is bound to another user
+pub const FORBIDDEN: u8 = 3;
+
+/// Both sides send a keepalive this often, so sessions without visitors stay open.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(10);
+const PORT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+fn put_name(buffer: &mut BytesMut, name: &str) {
+    buffer.put_u8(name.len() as u8);
+    buffer.put_slice(name.as_bytes());
+}
+
+/// The names a client registers and their size, None if they are not complete yet.
+fn parse_names(buffer: &[u8]) -> Option<(Vec<String>, usize)> {
+    let (&count, mut rest) = buffer.split_first()?;
+    let mut names = Vec::new();
+    for _ in 0..count {
+        let (&len, tail) = rest.split_first()?;
+        let name = tail.get(..len as usize)?;
+        names.push(String::from_utf8_lossy(name).into_owned());
+        rest = &tail[len as usize..];
+    }
+    Some((names, buffer.len() - rest.len()))
+}
+
+async fn read_name<S: AsyncRead + Unpin>(stream: &mut S) -> Result<String> {
+    let mut name = vec![0u8; stream.read_u8().await? as usize];
+    stream.read_exact(name.as_mut_slice()).await?;
+    Ok(String::from_utf8_lossy(name.as_slice()).into_owned())
+}
+
+/// Publishes the services a client registers on their `--reverse-port` addresses, if they are
+/// bound to `user`, until the client goes away. Traffic of the visitors counts as the user's.
+pub async fn serve_reverse<S>(
+    mut conn: S,
+    mut buffer: BytesMut,
+    src_addr: SocketAddr,
+    user: Option<&str>,
+    usage: Option<Arc<Usage>>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let names = loop {
+        if let Some((names, size)) = parse_names(buffer.as_ref()) {
+            buffer.advance(size);
+            break names;
+        }
+        if timeout(REGISTER_TIMEOUT, conn.read_buf(&mut buffer)).await?? == 0 {
+            return Err(TrojanError::Reverse("closed before registering services"));
+        }
+    };
+    let mut listeners = Vec::new();
+    let mut status = BytesMut::new();
+    for name in names {
+        let Some(service) = OPTIONS.reverse_port(name.as_str()) else {
+            log::warn!("service {} of {} has no reverse port", name, src_addr);
+            status.put_u8(UNKNOWN);
+            continue;
+        };
+        if service.user.as_deref() != user {
+            log::warn!(
+                "user:{} of {} may not publish service {}",
+                user.unwrap_or("-"),
+                src_addr,
+                name
+            );
+            status.put_u8(FORBIDDEN);
+            continue;
+        }
+        // the port being bound keeps others from publishing the service too
+        match TcpListener::bind(service.addr.as_str()).await {
+            Ok(listener) => {
+                log::warn!(
+                    "service {} of {} published on {}",
+                    name,
+                    src_addr,
+                    service.addr
+                );
+                listeners.push((service, listener));
+                status.put_u8(PUBLISHED);
+            }
+            Err(err) => {
+                log::warn!("bind {} for service {} failed:{}", service.addr, name, err);
+                status.put_u8(UNAVAILABLE);
+            }
+        }
+    }
+    conn.write_all(status.as_ref()).await?;
+    if listeners.is_empty() {
+        let _ = conn.shutdown().await;
+        return Ok(());
+    }
+    let session = Session::start(conn, buffer, None, OPTIONS.tcp_idle_timeout);
+    // the listeners close along with the session
+    let _accepting: Vec<_> = listeners
+        .into_iter()
+        .map(|(service, listener)| {
+            AbortOnDrop::new(spawn(accept_visitors(
+                service,
+                listener,
+                session.clone(),
+                usage.clone(),
+            )))
+        })
+        .collect();
+    let exceeded = async {
+        match &usage {
+            Some(usage) => usage.wait_exceeded().await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        _ = session.keep_alive(KEEPALIVE_INTERVAL) => {
+            log::warn!("services of {} closed", src_addr);
+        }
+        _ = exceeded => {
+            log::warn!("quota exceeded, close services of {}", src_addr);
+        }
+    }
+    Ok(())
+}
+
+/// Stops once the control api removes the port of the service, moves it elsewhere or binds it
+/// to another user.
+async fn accept_visitors(
+    service: ReverseService,
+    listener: TcpListener,
+    session: Arc<Session>,
+    usage: Option<Arc<Usage>>,
+) {
+    let mut check = tokio::time::interval(PORT_CHECK_INTERVAL);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = check.tick() => {
+                if OPTIONS.reverse_port(service.name.as_str()).as_ref() != Some(&service) {
+                    log::warn!("service {} unpublished from {}", service.name, service.addr);
+                    return;
+                }
+                continue;
+            }
+        };
+        let (visitor, visitor_addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                log::error!("accept visitors of {} failed:{}", service.name, err);
+                continue;
+            }
+        };
+        log::info!("visitor {} of service {}", visitor_addr, service.name);
+        let mut head = BytesMut::new();
+        put_name(&mut head, service.name.as_str());
+        let (session, usage) = (session.clone(), usage.clone());
+        spawn(async move {
+            if let Err(err) = visit(visitor, visitor_addr, head, session, usage).await {
+                log::error!("visitor {} failed:{:?}", visitor_addr, err);
+            }
+        });
+    }
+}
+
+/// The visitor's data is the user's download, the service's answers its upload.
+async fn visit(
+    visitor: TcpStream,
+    visitor_addr: SocketAddr,
+    head: BytesMut,
+    session: Arc<Session>,
+    usage: Option<Arc<Usage>>,
+) -> Result<()> {
+    let mut stream = session.open(Priority::Bulk).await?;
+    stream.write_all(head.as_ref()).await?;
+    let (visitor_read, visitor_write) = visitor.into_split();
+    let (stream_read, stream_write) = split(stream);
+    let download_usage = usage.clone();
+    tokio::join!(
+        copy_with(
+            visitor_read,
+            stream_write,
+            format!("visitor {} to service", visitor_addr),
+            OPTIONS.tcp_idle_timeout,
+            download_usage
+                .as_ref()
+                .map(|usage| (&usage.download_limit, Priority::Bulk)),
+            None,
+            |n| {
+                if let Some(usage) = &download_usage {
+                    usage.add_download(n);
+                }
+            },
+        ),
+        copy_with(
+            stream_read,
+            visitor_write,
+            format!("service to visitor {}", visitor_addr),
+            OPTIONS.tcp_idle_timeout,
+            usage
+                .as_ref()
+                .map(|usage| (&usage.upload_limit, Priority::Bulk)),
+            None,
+            |n| {
+                if let Some(usage) = &usage {
+                    usage.add_upload(n);
+                }
+            },
+        )
+    );
+    Ok(())
+}
+
+/// Registers the `--reverse` services and connects the visitors the server sends to them.
+async fn publish_once<F, Fut>(connect: &F, backoff: &mut Backoff) -> Result<()>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<TlsStream<TcpStream>>>,
+{
+    let services = &OPTIONS.proxy_args().reverse;
+    let mut conn = connect().await?;
+    let mut request = BytesMut::new();
+    TrojanRequest::generate(&mut request, REVERSE, OPTIONS.empty_addr.as_ref().unwrap());
+    request.put_u8(services.len() as u8);
+    for service in services {
+        put_name(&mut request, service.name.as_str());
+    }
+    conn.write_all(request.as_ref()).await?;
+    let mut status = vec![0u8; services.len()];
+    timeout(REGISTER_TIMEOUT, conn.read_exact(status.as_mut_slice())).await??;
+    for (service, status) in services.iter().zip(&status) {
+        match *status {
+            PUBLISHED => log::warn!("service {} published by the server", service.name),
+            UNKNOWN => log::error!("server has no port for service {}", service.name),
+            FORBIDDEN => log::error!("service {} belongs to another user", service.name),
+            _ => log::error!("port of service {} is taken on the server", service.name),
+        }
+    }
+    if !status.contains(&PUBLISHED) {
+        return Err(TrojanError::Reverse("no service published"));
+    }
+    backoff.succeeded();
+
+    let (accept, mut accepted) = channel(16);
+    let session = Session::start(
+        conn,
+        BytesMut::new(),
+        Some(accept),
+        OPTIONS.tcp_idle_timeout,
+    );
+    let serve = async {
+        while let Some(stream) = accepted.recv().await {
+            spawn(async move {
+                if let Err(err) = forward(stream).await {
+                    log::error!("forward visitor failed:{:?}", err);
+                }
+            });
+        }
+    };
+    tokio::select! {
+        _ = serve => {}
+        _ = session.keep_alive(KEEPALIVE_INTERVAL) => {}
+    }
+    Err(TrojanError::Reverse("reverse tunnel closed"))
+}
+
+async fn forward<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S) -> Result<()> {
+    let name = read_name(&mut stream).await?;
+    let service = OPTIONS
+        .proxy_args()
+        .reverse
+        .iter()
+        .find(|service| service.name == name)
+        .ok_or(TrojanError::Reverse("visitor of an unknown service"))?;
+    let mut target = TcpStream::connect(service.addr.as_str()).await?;
+    log::info!("visitor of {} forwarded to {}", name, service.addr);
+    copy_bidirectional(&mut stream, &mut target).await?;
+    Ok(())
+}
+
+/// Keeps the `--reverse` services published through the server, connecting again after
+/// failures.
+pub async fn run_reverse<F, Fut>(connect: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<TlsStream<TcpStream>>>,
+{
+    let mut backoff = Backoff::default();
+    loop {
+        if let Err(err) = publish_once(&connect, &mut backoff).await {
+            log::error!("reverse tunnel failed:{:?}", err);
+        }
+        tokio::time::sleep(backoff.failed()).await;
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_parse_names() {
+        use bytes::BytesMut;
+
+        use crate::reverse::{parse_names, put_name};
+
+        let mut buffer = BytesMut::new();
+        buffer.extend_from_slice(&[2]);
+        put_name(&mut buffer, "ssh");
+        assert_eq!(parse_names(buffer.as_ref()), None);
+        put_name(&mut buffer, "nas");
+        buffer.extend_from_slice(&[1, 0]);
+        assert_eq!(
+            parse_names(buffer.as_ref()),
+            Some((vec!["ssh".to_string(), "nas".to_string()], 9))
+        );
+        assert_eq!(parse_names(&[0]), Some((vec![], 1)));
+        assert_eq!(parse_names(&[]), None);
+    }
+}
@@ -0,0 +1,108 @@
+//! Prints how a target would be handled by dns and wintun mode, helps debugging rule files. The
+//! --route-rules files are loaded at the start like for the other modes.
+use std::net::{IpAddr, SocketAddr};
+
+use crate::{
+    config::{Outbound, OPTIONS},
+    dns::domain::DomainMap,
+    geo::GeoDatabase,
+    routing,
+    types::{Result, TrojanError},
+    utils::resolve,
+    wintun::{exclude_local, IPSet},
+};
+
+pub fn run() -> Result<()> {
+    let args = OPTIONS.route_test_args();
+    let (host, port) = match args.target.parse::<SocketAddr>() {
+        Ok(addr) => (addr.ip().to_string(), addr.port()),
+        Err(_) => {
+            let (host, port) = args
+                .target
+                .rsplit_once(':')
+                .unwrap_or((args.target.as_str(), "443"));
+            (host.to_string(), port.parse()?)
+        }
+    };
+    println!("target: {}:{}", host, port);
+
+    // routing rules go before the blocked domain list, like in dns mode and the proxy listener
+    let mut outbound = None;
+    let ips = if let Ok(ip) = host.parse::<IpAddr>() {
+        println!("rule: skipped, target is an ip address");
+        println!("dns: skipped, target is an ip address");
+        vec![ip]
+    } else {
+        match routing::matched(host.as_str()) {
+            Some((kind, value, rule_outbound)) => {
+                println!("rule: {},{} -> {:?}", kind, value, rule_outbound);
+                outbound = Some(rule_outbound);
+            }
+            None => println!("rule: no --route-rules rule matches"),
+        }
+        let blocked = match outbound {
+            Some(Outbound::Block) => {
+                println!("outbound: rejected, the domain is answered with NXDOMAIN");
+                return Ok(());
+            }
+            Some(outbound) => outbound == Outbound::Proxy,
+            None => {
+                let domains: GeoDatabase<DomainMap> =
+                    GeoDatabase::new(args.blocked_domain_list.as_str());
+                let version = domains.reload()?;
+                let blocked = domains.snapshot().data.contains(host.as_str());
+                println!(
+                    "dns: {} {} blocked domain list (version {})",
+                    host,
+                    if blocked { "matches" } else { "does not match" },
+                    version,
+                );
+                blocked
+            }
+        };
+        let dns = if blocked {
+            &args.trusted_dns
+        } else {
+            &args.poisoned_dns
+        };
+        println!(
+            "dns: resolve with {} dns {}",
+            if blocked { "trusted" } else { "poisoned" },
+            dns
+        );
+        resolve(host.as_str(), (dns.clone() + ":53").as_str())?
+    };
+    if ips.is_empty() {
+        println!("resolve: no address found");
+        return Err(TrojanError::Resolve);
+    }
+
+    let ipset = if let Some(file) = &args.route_ipset {
+        let mut ipset = IPSet::with_file(file, args.inverse_route)?;
+        exclude_local(
+            &mut ipset,
+            args.inverse_route,
+            args.no_local_exempt,
+            &args.route_exclude,
+        )?;
+        Some(ipset)
+    } else {
+        None
+    };
+    for ip in ips {
+        let (through_tunnel, reason) = match (outbound, &ipset, ip) {
+            // dns --add-route routes the answers of the trusted dns through the tunnel
+            (Some(Outbound::Proxy), _, IpAddr::V4(_)) => (true, "proxy rule"),
+            (_, None, _) => (true, "no ipset"),
+            (_, Some(ipset), IpAddr::V4(v4)) => (ipset.contains(v4.into()), "ipset"),
+            (_, Some(_), IpAddr::V6(_)) => (false, "ipv6 not in ipset"),
+        };
+        println!(
+            "route: {} -> {} ({})",
+            ip,
+            if through_tunnel { "tunnel" } else { "direct" },
+            reason
+        );
+    }
+    Ok(())
+}
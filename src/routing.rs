@@ -0,0 +1,233 @@
+//! Domain rules deciding whether a destination goes through the server, directly or nowhere,
+//! loaded from the --route-rules files. A rule is a line like `DOMAIN-SUFFIX,example.com,DIRECT`
+//! with one of the types
+//!
+//! * `DOMAIN` the domain itself,
+//! * `DOMAIN-SUFFIX` the domain and its subdomains,
+//! * `DOMAIN-KEYWORD` domains containing the word,
+//! * `GEOSITE` the domains of a --geosite category like `cn` or `google@cn`, except regexes,
+//!
+//! and one of the outbounds `PROXY`, `DIRECT` or `REJECT`. A full match goes first, then the
+//! longest suffix, then the first keyword, and among the same rules the first one loaded.
+//! Empty lines and lines starting with `#` are skipped.
+
+use std::{collections::HashMap, fs, sync::OnceLock};
+
+use crate::{
+    config::{Outbound, OPTIONS},
+    geosite::{DomainKind, GeoSites},
+    types::{Result, TrojanError},
+};
+
+static ROUTER: OnceLock<Router> = OnceLock::new();
+
+pub type Rules<'a> = (
+    &'a HashMap<String, Outbound>,
+    &'a HashMap<String, Outbound>,
+    &'a [(String, Outbound)],
+);
+
+#[derive(Default)]
+pub struct Router {
+    full: HashMap<String, Outbound>,
+    suffix: HashMap<String, Outbound>,
+    keyword: Vec<(String, Outbound)>,
+}
+
+impl Router {
+    /// Adds the rules of `content`, `name` is only used in errors.
+    pub fn add_rules(&mut self, name: &str, content: &str, sites: &mut GeoSites) -> Result<()> {
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let rule = parse_rule(line).ok_or_else(|| {
+                log::error!("invalid rule at {}:{}, {}", name, index + 1, line);
+                TrojanError::Routing("invalid routing rule")
+            })?;
+            match rule {
+                (Kind::Full, domain, outbound) => {
+                    self.full.entry(domain).or_insert(outbound);
+                }
+                (Kind::Suffix, domain, outbound) => {
+                    self.suffix.entry(domain).or_insert(outbound);
+                }
+                (Kind::Keyword, word, outbound) => self.keyword.push((word, outbound)),
+                (Kind::GeoSite, category, outbound) => {
+                    let mut regexes = 0;
+                    for domain in sites.domains(category.as_str())? {
+                        let value = domain.value.clone();
+                        match domain.kind {
+                            DomainKind::Full => {
+                                self.full.entry(value).or_insert(outbound);
+                            }
+                            DomainKind::Suffix => {
+                                self.suffix.entry(value).or_insert(outbound);
+                            }
+                            DomainKind::Keyword => self.keyword.push((value, outbound)),
+                            DomainKind::Regex => regexes += 1,
+                        }
+                    }
+                    if regexes > 0 {
+                        log::warn!("{} regex domains of geosite:{} skipped", regexes, category);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.full.is_empty() && self.suffix.is_empty() && self.keyword.is_empty()
+    }
+
+    /// Rules of the `DOMAIN` and `DOMAIN-SUFFIX` types by domain, and the `DOMAIN-KEYWORD` ones
+    /// in the order they are tried.
+    pub fn rules(&self) -> Rules {
+        (&self.full, &self.suffix, self.keyword.as_slice())
+    }
+
+    /// Outbound of `domain`, None if no rule matches.
+    pub fn route(&self, domain: &str) -> Option<Outbound> {
+        self.matched(domain).map(|(_, _, outbound)| outbound)
+    }
+
+    /// Type and value of the rule matching `domain` with its outbound, None if no rule matches.
+    pub fn matched(&self, domain: &str) -> Option<(&'static str, &str, Outbound)> {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        if let Some((value, outbound)) = self.full.get_key_value(domain.as_str()) {
+            return Some(("DOMAIN", value.as_str(), *outbound));
+        }
+        let mut suffix = domain.as_str();
+        loop {
+            if let Some((value, outbound)) = self.suffix.get_key_value(suffix) {
+                return Some(("DOMAIN-SUFFIX", value.as_str(), *outbound));
+            }
+            match suffix.split_once('.') {
+                Some((_, parent)) => suffix = parent,
+                None => break,
+            }
+        }
+        self.keyword
+            .iter()
+            .find(|(word, _)| domain.contains(word.as_str()))
+            .map(|(word, outbound)| ("DOMAIN-KEYWORD", word.as_str(), *outbound))
+    }
+}
+
+enum Kind {
+    Full,
+    Suffix,
+    Keyword,
+    GeoSite,
+}
+
+fn parse_rule(line: &str) -> Option<(Kind, String, Outbound)> {
+    let mut parts = line.split(',').map(str::trim);
+    let kind = match parts.next()?.to_ascii_uppercase().as_str() {
+        "DOMAIN" => Kind::Full,
+        "DOMAIN-SUFFIX" => Kind::Suffix,
+        "DOMAIN-KEYWORD" => Kind::Keyword,
+        "GEOSITE" => Kind::GeoSite,
+        _ => return None,
+    };
+    let value = parts
+        .next()?
+        .trim_start_matches('.')
+        .trim_end_matches('.')
+        .to_ascii_lowercase();
+    let outbound = match parts.next()?.to_ascii_uppercase().as_str() {
+        "PROXY" => Outbound::Proxy,
+        "DIRECT" => Outbound::Direct,
+        "REJECT" => Outbound::Block,
+        _ => return None,
+    };
+    if value.is_empty() || parts.next().is_some() {
+        return None;
+    }
+    Some((kind, value, outbound))
+}
+
+/// Loads the --route-rules files, an invalid rule fails the start.
+pub fn init() -> Result<()> {
+    let mut router = Router::default();
+    let mut sites = GeoSites::new(OPTIONS.geosite.as_str());
+    for file in &OPTIONS.route_rules {
+        router.add_rules(file, fs::read_to_string(file)?.as_str(), &mut sites)?;
+    }
+    if !router.is_empty() {
+        log::warn!(
+            "routing rules loaded, {} domains, {} suffixes, {} keywords",
+            router.full.len(),
+            router.suffix.len(),
+            router.keyword.len()
+        );
+    }
+    let _ = ROUTER.set(router);
+    Ok(())
+}
+
+/// Rules of the --route-rules files, None before they are loaded.
+pub fn router() -> Option<&'static Router> {
+    ROUTER.get()
+}
+
+/// Outbound the rules decide for `domain`, None without a matching rule.
+pub fn route(domain: &str) -> Option<Outbound> {
+    ROUTER.get().and_then(|router| router.route(domain))
+}
+
+/// Rule of the --route-rules files deciding for `domain`, see `Router::matched`.
+#[allow(dead_code)]
+pub fn matched(domain: &str) -> Option<(&'static str, &'static str, Outbound)> {
+    ROUTER.get().and_then(|router| router.matched(domain))
+}
+
+mod tests {
+    #[test]
+    fn test_route() {
+        use crate::{config::Outbound, geosite::GeoSites, routing::Router};
+
+        let mut sites = GeoSites::new("");
+        let mut router = Router::default();
+        let rules = "# comment\n\
+            DOMAIN-SUFFIX,example.com,DIRECT\n\
+            domain-suffix,.cdn.example.com,proxy\n\
+            DOMAIN,login.example.com,PROXY\n\
+            DOMAIN-KEYWORD,ads,REJECT\n\
+            DOMAIN-KEYWORD,google,PROXY\n\
+            \n\
+            DOMAIN-SUFFIX,example.com,REJECT\n";
+        router.add_rules("rules.txt", rules, &mut sites).unwrap();
+        assert_eq!(router.route("example.com"), Some(Outbound::Direct));
+        assert_eq!(router.route("www.Example.com."), Some(Outbound::Direct));
+        assert_eq!(router.route("a.cdn.example.com"), Some(Outbound::Proxy));
+        assert_eq!(router.route("login.example.com"), Some(Outbound::Proxy));
+        assert_eq!(router.route("ads.example.com"), Some(Outbound::Direct));
+        assert_eq!(router.route("googleads.net"), Some(Outbound::Block));
+        assert_eq!(router.route("www.google.com"), Some(Outbound::Proxy));
+        assert_eq!(router.route("example.org"), None);
+        assert_eq!(router.route("notexample.com"), None);
+        assert_eq!(
+            router.matched("a.cdn.example.com"),
+            Some(("DOMAIN-SUFFIX", "cdn.example.com", Outbound::Proxy))
+        );
+        assert_eq!(
+            router.matched("googleads.net"),
+            Some(("DOMAIN-KEYWORD", "ads", Outbound::Block))
+        );
+
+        for invalid in [
+            "IP-CIDR,10.0.0.0/8,DIRECT",
+            "DOMAIN,example.com",
+            "DOMAIN,example.com,BLOCK",
+            "DOMAIN,,DIRECT",
+            "DOMAIN,example.com,DIRECT,extra",
+        ] {
+            assert!(Router::default()
+                .add_rules("rules.txt", invalid, &mut sites)
+                .is_err());
+        }
+    }
+}
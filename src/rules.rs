@@ -0,0 +1,190 @@
+//! Rule lists (route ipset, blocked domains) pushed by the server to subscribed clients.
+//!
+//! A client subscribes with the `RULES` command, the server then sends a frame for every list
+//! it publishes, again whenever a list file changes, and a keepalive frame in between:
+//!
+//! `kind(u8) | version(u64) | length(u32) | payload | ed25519 signature(64)`
+//!
+//! The version is the start of the SHA-256 of the payload, so servers publishing the same list
+//! agree on it whatever the file times are. The signature covers everything before it, so
+//! clients only trust lists signed by the key of the server operator, whichever server they
+//! happen to be connected to. The subscribing side is in `awintun::rules`.
+
+use std::{
+    fs,
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use bytes::{BufMut, BytesMut};
+use ring::{
+    digest::{digest, SHA256},
+    signature::{Ed25519KeyPair, KeyPair},
+};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::watch::{channel, Receiver},
+    time::timeout,
+};
+
+use crate::{
+    config::OPTIONS,
+    types::{Result, TrojanError},
+};
+
+/// frame sent in between updates, so both sides notice a dead connection
+pub const KEEPALIVE: u8 = 0;
+/// CIDR list of the routes through the tunnel
+pub const IPSET: u8 = 1;
+/// domain list which should be resolved through safe DNS
+pub const DOMAINS: u8 = 2;
+
+pub const HEADER_LEN: usize = 13;
+pub const SIGNATURE_LEN: usize = 64;
+pub const PUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+pub fn encode(kind: u8, version: u64, payload: &[u8], key: &Ed25519KeyPair) -> Vec<u8> {
+    let mut buffer = BytesMut::with_capacity(HEADER_LEN + payload.len() + SIGNATURE_LEN);
+    buffer.put_u8(kind);
+    buffer.put_u64(version);
+    buffer.put_u32(payload.len() as u32);
+    buffer.extend_from_slice(payload);
+    let signature = key.sign(buffer.as_ref());
+    buffer.extend_from_slice(signature.as_ref());
+    buffer.to_vec()
+}
+
+/// Version of a list, the first 8 bytes of its SHA-256.
+pub fn version(payload: &[u8]) -> u64 {
+    let hash = digest(&SHA256, payload);
+    u64::from_be_bytes(hash.as_ref()[..8].try_into().unwrap())
+}
+
+/// Frames currently published, every subscription sends them again after a change.
+#[derive(Default)]
+pub struct Frames {
+    lists: Vec<Vec<u8>>,
+    keepalive: Vec<u8>,
+}
+
+fn load_key(path: &str) -> Result<Ed25519KeyPair> {
+    let seed = hex::decode(fs::read_to_string(path)?.trim())
+        .map_err(|_| TrojanError::Rules("rules key is not hex encoded"))?;
+    Ed25519KeyPair::from_seed_unchecked(seed.as_slice())
+        .map_err(|_| TrojanError::Rules("rules key is not a 32 bytes ed25519 seed"))
+}
+
+fn modified(path: &str) -> u64 {
+    fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+/// Watches the pushed list files of the server, `None` if rule pushing is not configured.
+pub fn start_publisher() -> Result<Option<Receiver<Arc<Frames>>>> {
+    let args = OPTIONS.server_args();
+    let Some(key_file) = &args.rules_key_file else {
+        return Ok(None);
+    };
+    let key = load_key(key_file)?;
+    log::warn!(
+        "pushing rule lists signed by public key {}",
+        hex::encode(key.public_key().as_ref())
+    );
+    let files: Vec<_> = [(IPSET, &args.push_ipset), (DOMAINS, &args.push_domain_list)]
+        .into_iter()
+        .filter_map(|(kind, file)| file.clone().map(|file| (kind, file)))
+        .collect();
+    let (sender, receiver) = channel(Arc::new(Frames::default()));
+    tokio::spawn(async move {
+        let mut modified_times = vec![0; files.len()];
+        let mut versions = Vec::new();
+        let mut interval = tokio::time::interval(PUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let current: Vec<_> = files.iter().map(|(_, file)| modified(file)).collect();
+            if current == modified_times {
+                continue;
+            }
+            modified_times = current;
+            let mut lists = Vec::new();
+            let mut current = Vec::new();
+            for (kind, file) in &files {
+                match fs::read(file) {
+                    Ok(payload) => {
+                        let version = version(&payload);
+                        lists.push(encode(*kind, version, &payload, &key));
+                        current.push(version);
+                    }
+                    Err(err) => log::error!("read pushed rule list {} failed:{}", file, err),
+                }
+            }
+            // touched but not changed
+            if current == versions {
+                continue;
+            }
+            log::warn!("rule lists changed, pushing {} lists", lists.len());
+            versions = current;
+            let keepalive = encode(KEEPALIVE, 0, &[], &key);
+            if sender.send(Arc::new(Frames { lists, keepalive })).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(Some(receiver))
+}
+
+/// Serves a rule subscription until the client goes away.
+pub async fn serve_rules<S: AsyncWrite + Unpin>(
+    mut conn: S,
+    mut frames: Receiver<Arc<Frames>>,
+) -> Result<()> {
+    frames.mark_changed();
+    loop {
+        let changed = match timeout(PUSH_INTERVAL, frames.changed()).await {
+            Ok(Ok(())) => true,
+            Ok(Err(_)) => break,
+            Err(_) => false,
+        };
+        let current = frames.borrow_and_update().clone();
+        if changed {
+            for frame in &current.lists {
+                conn.write_all(frame.as_slice()).await?;
+            }
+        } else if !current.keepalive.is_empty() {
+            conn.write_all(current.keepalive.as_slice()).await?;
+        }
+    }
+    let _ = conn.shutdown().await;
+    Ok(())
+}
+
+mod tests {
+    #[test]
+    fn test_encode() {
+        use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+
+        use crate::rules::{encode, version, DOMAINS, HEADER_LEN, SIGNATURE_LEN};
+
+        let key = Ed25519KeyPair::from_seed_unchecked(&[7u8; 32]).unwrap();
+        let encoded = encode(DOMAINS, 42, b"google.com\n", &key);
+        assert_eq!(encoded.len(), HEADER_LEN + 11 + SIGNATURE_LEN);
+        assert_eq!(
+            &encoded[..HEADER_LEN],
+            &[2, 0, 0, 0, 0, 0, 0, 0, 42, 0, 0, 0, 11]
+        );
+        assert_eq!(&encoded[HEADER_LEN..HEADER_LEN + 11], b"google.com\n");
+        let (signed, signature) = encoded.split_at(HEADER_LEN + 11);
+        let public_key = UnparsedPublicKey::new(&ED25519, key.public_key().as_ref());
+        assert!(public_key.verify(signed, signature).is_ok());
+
+        assert_eq!(version(b"google.com\n"), version(b"google.com\n"));
+        assert_ne!(
+            version(b"google.com\n"),
+            version(b"google.com\nyoutube.com\n")
+        );
+    }
+}
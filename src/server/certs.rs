@@ -0,0 +1,77 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{mpsc::channel, Arc},
+    thread,
+    time::Duration,
+};
+
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use rustls::ServerConfig;
+
+use crate::{config::OPTIONS, server::init_config, types::Result};
+
+/// Renewals write the certificate and the key one after another, changes settle this long
+/// before the config is rebuilt.
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// Rebuilds the server config whenever the certificate, the key or the OCSP file changes and hands
+/// it to `on_change`, connections open already keep the config they started with.
+pub fn watch(on_change: impl Fn(Arc<ServerConfig>) + Send + 'static) -> Result<()> {
+    let args = OPTIONS.server_args();
+    let mut files = vec![absolute(args.cert.as_str())?, absolute(args.key.as_str())?];
+    if let Some(path) = &args.ocsp_file {
+        files.push(absolute(path.as_str())?);
+    }
+    let (sender, receiver) = channel();
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+            Ok(event) => {
+                let _ = sender.send(event);
+            }
+            Err(err) => log::error!("watch certificates failed:{}", err),
+        })?;
+    // the directories are watched, as renewals replace the files or the symlinks to them
+    let mut dirs: Vec<&Path> = files.iter().filter_map(|file| file.parent()).collect();
+    dirs.sort();
+    dirs.dedup();
+    for dir in dirs {
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    }
+    log::warn!("watching {:?} for certificate changes", files);
+    thread::spawn(move || {
+        let _watcher = watcher;
+        while let Ok(event) = receiver.recv() {
+            if !matches!(
+                event.kind,
+                EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+            ) || !event.paths.iter().any(|path| files.contains(path))
+            {
+                continue;
+            }
+            thread::sleep(SETTLE_TIME);
+            while receiver.try_recv().is_ok() {}
+            match init_config() {
+                Ok(config) => {
+                    on_change(config);
+                    log::warn!("certificates reloaded");
+                }
+                Err(err) => log::error!("reload certificates failed:{:?}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Events carry absolute paths, so the file is joined to its canonical directory.
+fn absolute(file: &str) -> Result<PathBuf> {
+    let file = Path::new(file);
+    let dir = match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut path = std::fs::canonicalize(dir)?;
+    if let Some(name) = file.file_name() {
+        path.push(name);
+    }
+    Ok(path)
+}
@@ -0,0 +1,45 @@
+use std::{
+    io::{Read, Write},
+    net::TcpListener,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+use crate::types::Result;
+
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// Marks the server as shutting down, health check responds 503 from now on.
+pub fn set_draining() {
+    DRAINING.store(true, Ordering::SeqCst);
+}
+
+/// Starts a plain http health check endpoint in a standalone thread.
+pub fn start(addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    log::warn!("health check listening on {}", addr);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    log::error!("accept health check failed:{}", err);
+                    continue;
+                }
+            };
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(1)));
+            let mut buffer = [0u8; 1024];
+            let _ = stream.read(&mut buffer);
+            let response: &[u8] = if DRAINING.load(Ordering::SeqCst) {
+                b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 8\r\nConnection: close\r\n\r\ndraining"
+            } else {
+                b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"
+            };
+            if let Err(err) = stream.write_all(response) {
+                log::error!("write health check response failed:{}", err);
+            }
+        }
+    });
+    Ok(())
+}
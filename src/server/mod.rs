@@ -1,66 +1,76 @@
-use std::{
-    fs::File,
-    io::BufReader,
-    sync::Arc,
-    time::{Duration, Instant},
-};
+use std::{fs::File, io::BufReader, net::SocketAddr, sync::Arc};
 
-use mio::{net::TcpListener, Events, Interest, Poll, Token, Waker};
-use rustls::{server::WebPkiClientVerifier, KeyLogFile, RootCertStore, ServerConfig};
+use rustls::{
+    crypto::ring::Ticketer,
+    server::{NoServerSessionStorage, ServerSessionMemoryCache, WebPkiClientVerifier},
+    KeyLogFile, RootCertStore, ServerConfig,
+};
 use rustls_pemfile::{certs, read_one, Item};
 use rustls_pki_types::{CertificateDer, PrivateKeyDer};
-
-pub use tls_server::TlsServer;
+use socket2::{Domain, Socket, Type};
 
 use crate::{
+    allocator,
     config::OPTIONS,
-    resolver::DnsResolver,
-    server::{stat::Statistics, tls_server::PollEvent},
-    types::Result,
+    plugin, quic, sys,
+    types::{Result, TrojanError},
 };
 
-mod connection;
-pub mod ping_backend;
-mod stat;
-mod tcp_backend;
-mod tls_server;
-mod udp_backend;
-
-const MIN_INDEX: usize = 3;
-const MAX_INDEX: usize = usize::MAX / CHANNEL_CNT;
-const CHANNEL_CNT: usize = 2;
-const CHANNEL_PROXY: usize = 0;
-const CHANNEL_BACKEND: usize = 1;
-const RESOLVER: usize = 2;
-const LISTENER: usize = 1;
+pub mod certs;
+pub mod health;
+mod ocsp;
+pub mod reload;
+pub mod stat;
+pub mod usage;
 
-fn load_certs(filename: &str) -> Vec<CertificateDer<'static>> {
-    let cert_file = File::open(filename).unwrap();
+pub fn load_certs(filename: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let cert_file = File::open(filename)?;
     let mut buff_reader = BufReader::new(cert_file);
-    certs(&mut buff_reader)
+    Ok(certs(&mut buff_reader)
         .filter_map(|v| v.ok().map(|v| v.into_owned()))
-        .collect()
+        .collect())
 }
 
-fn load_private_key(filename: &str) -> PrivateKeyDer {
-    let key_file = File::open(filename).unwrap();
+fn load_private_key(filename: &str) -> Result<PrivateKeyDer<'static>> {
+    let key_file = File::open(filename)?;
     let mut buff_reader = BufReader::new(key_file);
     loop {
-        match read_one(&mut buff_reader).unwrap() {
-            Some(Item::Pkcs8Key(key)) => return PrivateKeyDer::Pkcs8(key),
-            Some(Item::Pkcs1Key(key)) => return PrivateKeyDer::Pkcs1(key),
+        match read_one(&mut buff_reader)? {
+            Some(Item::Pkcs8Key(key)) => return Ok(PrivateKeyDer::Pkcs8(key)),
+            Some(Item::Pkcs1Key(key)) => return Ok(PrivateKeyDer::Pkcs1(key)),
             None => break,
             _ => {}
         }
     }
-    panic!(
+    log::error!(
         "no keys found in {:?} (encrypted keys not supported)",
         filename
-    )
+    );
+    Err(TrojanError::Certificate("no private key found"))
 }
 
 pub fn init_config() -> Result<Arc<ServerConfig>> {
-    let certs = load_certs(OPTIONS.server_args().cert.as_str());
+    let certs = load_certs(OPTIONS.server_args().cert.as_str())?;
+    let private_key = load_private_key(OPTIONS.server_args().key.as_str())?;
+    let ocsp = match &OPTIONS.server_args().ocsp_file {
+        Some(path) => match std::fs::read(path) {
+            Ok(ocsp) => ocsp,
+            // not fetched yet
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        },
+        None => Vec::new(),
+    };
+    build_config(certs, private_key, ocsp)
+}
+
+/// Server config of the certificate chain `certs` with the client auth and ALPN options applied,
+/// `ocsp` is stapled unless it's empty.
+pub fn build_config(
+    certs: Vec<CertificateDer<'static>>,
+    private_key: PrivateKeyDer<'static>,
+    ocsp: Vec<u8>,
+) -> Result<Arc<ServerConfig>> {
     let mut root_store = RootCertStore::empty();
     root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
     for cert in &certs {
@@ -73,11 +83,18 @@ pub fn init_config() -> Result<Arc<ServerConfig>> {
     } else {
         WebPkiClientVerifier::no_client_auth()
     };
-    let private_key = load_private_key(OPTIONS.server_args().key.as_str());
     let mut config = ServerConfig::builder()
         .with_client_cert_verifier(verifier)
-        .with_single_cert_with_ocsp(certs, private_key, vec![])?;
+        .with_single_cert_with_ocsp(certs, private_key, ocsp)?;
     config.key_log = Arc::new(KeyLogFile::new());
+    config.session_storage = match OPTIONS.tls_session_cache {
+        0 => Arc::new(NoServerSessionStorage {}),
+        size => ServerSessionMemoryCache::new(size),
+    };
+    if OPTIONS.server_args().session_tickets {
+        config.ticketer = Ticketer::new()?;
+    }
+    config.max_early_data_size = OPTIONS.server_args().max_early_data;
 
     let mut protocols: Vec<Vec<u8>> = Vec::new();
     for protocol in &OPTIONS.server_args().alpn {
@@ -89,58 +106,73 @@ pub fn init_config() -> Result<Arc<ServerConfig>> {
     Ok(Arc::new(config))
 }
 
-pub fn run() -> Result<()> {
-    let config = init_config()?;
-    let mut poll = Poll::new()?;
-    let waker = Arc::new(Waker::new(poll.registry(), Token(RESOLVER))?);
-    let mut resolver = DnsResolver::new(waker, Token(RESOLVER), None);
-    resolver.set_cache_timeout(OPTIONS.server_args().dns_cache_time);
-    let addr = OPTIONS.local_addr.parse()?;
-    let mut listener = TcpListener::bind(addr)?;
-    poll.registry()
-        .register(&mut listener, Token(LISTENER), Interest::READABLE)?;
-    let mut server = TlsServer::new(listener, config);
-    let mut events = Events::with_capacity(1024);
-    let mut last_check_time = Instant::now();
-    let check_duration = Duration::new(1, 0);
-    let mut last_status_time = Instant::now();
-    let status_check = Duration::new(60, 0);
-    let mut stats = Statistics::new();
-    loop {
-        poll.poll(&mut events, Some(check_duration))?;
-        for event in &events {
-            match event.token() {
-                Token(LISTENER) => {
-                    server.accept(&poll);
-                }
-                Token(RESOLVER) => {
-                    resolver.consume(|token, ip| {
-                        server.do_conn_event(&poll, PollEvent::Dns((token, ip)), None, &mut stats);
-                    });
-                }
-                _ => {
-                    server.do_conn_event(
-                        &poll,
-                        PollEvent::Network(event),
-                        Some(&mut resolver),
-                        &mut stats,
-                    );
-                }
+/// Config of the QUIC endpoints with the certificate and the key of the TLS one.
+pub fn quic_config() -> Result<quinn::ServerConfig> {
+    let certs = load_certs(OPTIONS.server_args().cert.as_str())?;
+    let private_key = load_private_key(OPTIONS.server_args().key.as_str())?;
+    quic::server_config(certs, private_key)
+}
+
+/// Binds the udp sockets of the QUIC endpoints on the local addresses.
+pub fn bind_quic_sockets() -> Result<Vec<std::net::UdpSocket>> {
+    OPTIONS
+        .local_addr
+        .iter()
+        .map(|addr| {
+            let addr: SocketAddr = addr.parse()?;
+            let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+            if addr.is_ipv6() {
+                socket.set_only_v6(OPTIONS.v6_only())?;
             }
-        }
-        server.remove_closed();
-        server.poll_ping(&mut stats);
-        let now = Instant::now();
-        if now - last_check_time > check_duration {
-            server.check_timeout(now, &poll);
-            last_check_time = now;
-        }
-        if now - last_status_time > status_check {
-            stats.save(
-                OPTIONS.server_args().status_file.as_str(),
-                OPTIONS.server_args().status_limit,
-            );
-            last_status_time = now;
-        }
+            socket.set_nonblocking(true)?;
+            socket.bind(&addr.into())?;
+            log::warn!("listen quic on {}", addr);
+            Ok(socket.into())
+        })
+        .collect()
+}
+
+/// Returns the inherited listener if `--listen-fd` set, otherwise binds the local addresses.
+pub fn bind_listeners() -> Result<Vec<std::net::TcpListener>> {
+    if let Some(addr) = plugin::local_addr() {
+        Ok(vec![bind_listener(addr)?])
+    } else if let Some(fd) = OPTIONS.server_args().listen_fd {
+        log::warn!("listen on inherited fd:{}", fd);
+        Ok(vec![sys::listener_from_fd(fd)?])
+    } else {
+        OPTIONS
+            .local_addr
+            .iter()
+            .map(|addr| bind_listener(addr.parse()?))
+            .collect()
+    }
+}
+
+fn bind_listener(addr: SocketAddr) -> Result<std::net::TcpListener> {
+    let socket = sys::stream_socket(Domain::for_address(addr), OPTIONS.mptcp)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(OPTIONS.v6_only())?;
+    }
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    log::warn!("listen on {}", addr);
+    Ok(socket.into())
+}
+
+/// Installs SIGTERM, SIGHUP and SIGUSR1 handlers and starts health check and OCSP fetching if
+/// configured.
+pub fn prepare_service() -> Result<()> {
+    sys::watch_terminate()?;
+    reload::start()?;
+    allocator::start()?;
+    if let Some(addr) = &OPTIONS.server_args().health_addr {
+        health::start(addr.as_str())?;
+    }
+    if OPTIONS.server_args().ocsp_fetch {
+        ocsp::start();
     }
+    Ok(())
 }
@@ -0,0 +1,325 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
+use yasna::{models::ObjectIdentifier, ASN1Error, Tag};
+
+use crate::{
+    config::OPTIONS,
+    server::load_certs,
+    types::{Result, TrojanError},
+};
+
+/// Responders answer with responses valid for days, a fresh one is fetched this often.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+const RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+const OID_AUTHORITY_INFO_ACCESS: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 1, 1];
+const OID_OCSP: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 48, 1];
+const OID_SHA1: &[u64] = &[1, 3, 14, 3, 2, 26];
+
+/// Parts of a certificate an OCSP request is made of.
+struct CertInfo {
+    /// DER of the serial number INTEGER
+    serial: Vec<u8>,
+    /// DER of the issuer Name
+    issuer: Vec<u8>,
+    /// DER of the subject Name
+    subject: Vec<u8>,
+    /// content of the subjectPublicKey BIT STRING
+    public_key: Vec<u8>,
+    ocsp_url: Option<String>,
+}
+
+/// Fetches the OCSP response of the certificate from now on, right away and then when it's due
+/// or the certificate changed. The file is written and reloaded like the certificate.
+pub fn start() {
+    thread::spawn(|| {
+        let mut fetched: Option<(Instant, Option<SystemTime>)> = None;
+        loop {
+            let modified = std::fs::metadata(OPTIONS.server_args().cert.as_str())
+                .and_then(|metadata| metadata.modified())
+                .ok();
+            let due = fetched.is_none_or(|(time, cert_modified)| {
+                time.elapsed() > REFRESH_INTERVAL || cert_modified != modified
+            });
+            if due {
+                match fetch().and_then(save) {
+                    Ok(_) => {
+                        log::warn!("ocsp response fetched");
+                        fetched = Some((Instant::now(), modified));
+                    }
+                    Err(err) => log::error!("fetch ocsp response failed:{:?}", err),
+                }
+            }
+            thread::sleep(RETRY_INTERVAL);
+        }
+    });
+}
+
+fn fetch() -> Result<Vec<u8>> {
+    let certs = load_certs(OPTIONS.server_args().cert.as_str())?;
+    let (Some(leaf), Some(issuer)) = (certs.first(), certs.get(1)) else {
+        return Err(ocsp_error(
+            "the issuer certificate must follow the certificate",
+        ));
+    };
+    let leaf = parse_cert(leaf.as_ref())?;
+    let issuer = parse_cert(issuer.as_ref())?;
+    if leaf.issuer != issuer.subject {
+        return Err(ocsp_error(
+            "the second certificate didn't issue the first one",
+        ));
+    }
+    let url = leaf
+        .ocsp_url
+        .as_deref()
+        .ok_or(ocsp_error("no ocsp responder in the certificate"))?;
+    let response = post(url, build_request(&leaf, &issuer).as_slice())?;
+    check_response(response.as_slice())?;
+    Ok(response)
+}
+
+/// Writes through a temporary file, so the file is never reloaded half written.
+fn save(response: Vec<u8>) -> Result<()> {
+    let path = OPTIONS.server_args().ocsp_file.as_deref().unwrap();
+    let temp = format!("{}.tmp", path);
+    std::fs::write(temp.as_str(), response)?;
+    std::fs::rename(temp, path)?;
+    Ok(())
+}
+
+fn parse_cert(der: &[u8]) -> Result<CertInfo> {
+    yasna::parse_der(der, |reader| {
+        reader.read_sequence(|reader| {
+            let info = reader.next().read_sequence(|reader| {
+                reader.read_optional(|reader| {
+                    reader.read_tagged(Tag::context(0), |r| r.read_der())
+                })?;
+                let serial = reader.next().read_der()?;
+                // signature algorithm
+                reader.next().read_der()?;
+                let issuer = reader.next().read_der()?;
+                // validity
+                reader.next().read_der()?;
+                let subject = reader.next().read_der()?;
+                let public_key = reader.next().read_sequence(|reader| {
+                    reader.next().read_der()?;
+                    let key = reader.next().read_tagged_der()?;
+                    // the first byte counts the unused bits
+                    Ok(key.value().get(1..).unwrap_or_default().to_vec())
+                })?;
+                let mut ocsp_url = None;
+                // unique ids and extensions
+                while let Some(value) = reader.read_optional(|reader| reader.read_tagged_der())? {
+                    if value.tag() == Tag::context(3) {
+                        ocsp_url = yasna::parse_der(value.value(), read_ocsp_url)?;
+                    }
+                }
+                Ok(CertInfo {
+                    serial,
+                    issuer,
+                    subject,
+                    public_key,
+                    ocsp_url,
+                })
+            })?;
+            // signature algorithm and value
+            reader.next().read_der()?;
+            reader.next().read_der()?;
+            Ok(info)
+        })
+    })
+    .map_err(asn1_error)
+}
+
+/// The responder url of the AuthorityInfoAccess extension.
+fn read_ocsp_url(reader: yasna::BERReader) -> std::result::Result<Option<String>, ASN1Error> {
+    let mut url = None;
+    reader.read_sequence_of(|reader| {
+        reader.read_sequence(|reader| {
+            let id = reader.next().read_oid()?;
+            reader.read_optional(|reader| reader.read_bool())?;
+            let value = reader.next().read_bytes()?;
+            if id == ObjectIdentifier::from_slice(OID_AUTHORITY_INFO_ACCESS) {
+                url = yasna::parse_der(value.as_slice(), |reader| {
+                    let mut url = None;
+                    reader.read_sequence_of(|reader| {
+                        reader.read_sequence(|reader| {
+                            let method = reader.next().read_oid()?;
+                            // uniformResourceIdentifier is [6] IA5String
+                            let location = reader.next().read_tagged_der()?;
+                            if method == ObjectIdentifier::from_slice(OID_OCSP)
+                                && location.tag() == Tag::context(6)
+                            {
+                                url = String::from_utf8(location.value().to_vec()).ok();
+                            }
+                            Ok(())
+                        })
+                    })?;
+                    Ok(url)
+                })?;
+            }
+            Ok(())
+        })
+    })?;
+    Ok(url)
+}
+
+/// OCSPRequest of a single certificate, with SHA-1 hashes as responders expect.
+fn build_request(leaf: &CertInfo, issuer: &CertInfo) -> Vec<u8> {
+    let sha1 = |data: &[u8]| digest(&SHA1_FOR_LEGACY_USE_ONLY, data).as_ref().to_vec();
+    yasna::construct_der(|writer| {
+        // OCSPRequest, TBSRequest, requestList, Request, CertID
+        writer.write_sequence(|writer| {
+            writer.next().write_sequence(|writer| {
+                writer.next().write_sequence(|writer| {
+                    writer.next().write_sequence(|writer| {
+                        writer.next().write_sequence(|writer| {
+                            writer.next().write_sequence(|writer| {
+                                writer
+                                    .next()
+                                    .write_oid(&ObjectIdentifier::from_slice(OID_SHA1));
+                                writer.next().write_null();
+                            });
+                            writer
+                                .next()
+                                .write_bytes(sha1(leaf.issuer.as_slice()).as_slice());
+                            writer
+                                .next()
+                                .write_bytes(sha1(issuer.public_key.as_slice()).as_slice());
+                            writer.next().write_der(leaf.serial.as_slice());
+                        });
+                    });
+                });
+            });
+        });
+    })
+}
+
+/// Only successful responses are stapled, clients check the rest.
+fn check_response(response: &[u8]) -> Result<()> {
+    let (status, bytes) = yasna::parse_der(response, |reader| {
+        reader.read_sequence(|reader| {
+            let status = reader.next().read_enum()?;
+            let bytes = reader.read_optional(|reader| reader.read_der())?;
+            Ok((status, bytes))
+        })
+    })
+    .map_err(asn1_error)?;
+    if status != 0 || bytes.is_none() {
+        return Err(ocsp_error(format!("responder answered status {}", status)));
+    }
+    Ok(())
+}
+
+/// Posts the request to a plain http responder, HTTP/1.0 so the response isn't chunked.
+fn post(url: &str, request: &[u8]) -> Result<Vec<u8>> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or(ocsp_error(format!("unsupported responder url {}", url)))?;
+    let (authority, path) = rest.find('/').map_or((rest, "/"), |i| rest.split_at(i));
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse()?),
+        None => (authority, 80),
+    };
+    let mut stream = TcpStream::connect((host, port))?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let head = format!(
+        "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/ocsp-request\r\nContent-Length: {}\r\n\r\n",
+        path,
+        authority,
+        request.len()
+    );
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(request)?;
+    let mut data = Vec::new();
+    stream.read_to_end(&mut data)?;
+
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut response = httparse::Response::new(&mut headers);
+    let Ok(httparse::Status::Complete(offset)) = response.parse(data.as_slice()) else {
+        return Err(ocsp_error("invalid responder response"));
+    };
+    if response.code != Some(200) {
+        return Err(ocsp_error(format!(
+            "responder answered {}",
+            response.code.unwrap_or_default()
+        )));
+    }
+    let mut body = data[offset..].to_vec();
+    let length = response
+        .headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("Content-Length"))
+        .and_then(|header| std::str::from_utf8(header.value).ok())
+        .and_then(|value| value.trim().parse().ok());
+    if let Some(length) = length {
+        body.truncate(length);
+    }
+    Ok(body)
+}
+
+fn ocsp_error(message: impl ToString) -> TrojanError {
+    TrojanError::Ocsp(message.to_string())
+}
+
+fn asn1_error(err: ASN1Error) -> TrojanError {
+    ocsp_error(format!("invalid der:{}", err))
+}
+
+mod tests {
+    #[test]
+    fn test_build_request() {
+        use rcgen::{BasicConstraints, Certificate, CertificateParams, CustomExtension, IsCa};
+
+        use crate::server::ocsp::{build_request, check_response, parse_cert};
+
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(params).unwrap();
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]);
+        // AuthorityInfoAccess with an ocsp responder
+        let url = b"http://127.0.0.1:8888";
+        let aia = yasna::construct_der(|writer| {
+            writer.write_sequence(|writer| {
+                writer.next().write_sequence(|writer| {
+                    writer
+                        .next()
+                        .write_oid(&yasna::models::ObjectIdentifier::from_slice(
+                            super::OID_OCSP,
+                        ));
+                    writer
+                        .next()
+                        .write_tagged_implicit(yasna::Tag::context(6), |writer| {
+                            writer.write_bytes(url)
+                        });
+                });
+            });
+        });
+        params.custom_extensions = vec![CustomExtension::from_oid_content(
+            super::OID_AUTHORITY_INFO_ACCESS,
+            aia,
+        )];
+        let leaf = Certificate::from_params(params).unwrap();
+
+        let leaf = parse_cert(leaf.serialize_der_with_signer(&ca).unwrap().as_slice()).unwrap();
+        let ca = parse_cert(ca.serialize_der().unwrap().as_slice()).unwrap();
+        assert_eq!(leaf.ocsp_url.as_deref(), Some("http://127.0.0.1:8888"));
+        assert_eq!(leaf.issuer, ca.subject);
+        assert_eq!(ca.ocsp_url, None);
+        let request = build_request(&leaf, &ca);
+        assert_eq!(request[0], 0x30);
+        assert!(request.ends_with(leaf.serial.as_slice()));
+
+        // successful with responseBytes, then malformedRequest
+        assert!(check_response(&[0x30, 0x07, 0x0a, 0x01, 0x00, 0xa0, 0x02, 0x30, 0x00]).is_ok());
+        assert!(check_response(&[0x30, 0x03, 0x0a, 0x01, 0x01]).is_err());
+    }
+}
@@ -0,0 +1,91 @@
+use std::net::SocketAddr;
+
+use serde::Deserialize;
+
+use crate::{
+    config::OPTIONS,
+    limiter::{DOWNLOAD, UPLOAD},
+    server::usage,
+    sys,
+    types::Result,
+};
+
+/// Content of the settings file, settings left out keep their values.
+#[derive(Deserialize, Default, Debug, PartialEq)]
+struct Settings {
+    /// fallback address of connections failing authentication
+    remote_addr: Option<SocketAddr>,
+    /// KB/s, 0 for unlimited
+    upload_limit: Option<u64>,
+    /// KB/s, 0 for unlimited
+    download_limit: Option<u64>,
+}
+
+/// Reloads on SIGHUP from now on and applies the settings file if there is one.
+pub fn start() -> Result<()> {
+    sys::watch_reload()?;
+    apply_settings();
+    Ok(())
+}
+
+/// Reloads the users and the settings if asked to since the last call. Connections open
+/// already keep going, only the quotas and the rate limits of the users apply to them.
+pub fn check() {
+    if !sys::reload_requested() {
+        return;
+    }
+    log::warn!("reload requested");
+    match OPTIONS.reload_users() {
+        Ok(count) => {
+            for (user, _) in OPTIONS.user_list() {
+                usage::refresh(user);
+            }
+            log::warn!("{} users reloaded", count);
+        }
+        Err(err) => log::error!("reload users failed:{}", err),
+    }
+    apply_settings();
+}
+
+fn apply_settings() {
+    let Some(path) = &OPTIONS.server_args().settings_file else {
+        return;
+    };
+    let settings: Settings = match std::fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|content| serde_json::from_str(content.as_str()).map_err(|err| err.to_string()))
+    {
+        Ok(settings) => settings,
+        Err(err) => {
+            log::error!("read settings from {} failed:{}", path, err);
+            return;
+        }
+    };
+    if let Some(addr) = settings.remote_addr {
+        log::warn!("fallback address changed to {}", addr);
+        OPTIONS.set_fallback_addr(addr);
+    }
+    if let Some(limit) = settings.upload_limit {
+        UPLOAD.set_rate(limit.saturating_mul(1024));
+    }
+    if let Some(limit) = settings.download_limit {
+        DOWNLOAD.set_rate(limit.saturating_mul(1024));
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_settings() {
+        use crate::server::reload::Settings;
+
+        let settings: Settings =
+            serde_json::from_str(r#"{"remote_addr":"127.0.0.1:80","upload_limit":10}"#).unwrap();
+        assert_eq!(settings.remote_addr, Some("127.0.0.1:80".parse().unwrap()));
+        assert_eq!(settings.upload_limit, Some(10));
+        assert_eq!(settings.download_limit, None);
+        assert_eq!(
+            serde_json::from_str::<Settings>("{}").unwrap(),
+            Settings::default()
+        );
+    }
+}
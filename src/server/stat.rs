@@ -1,12 +1,17 @@
+//! Traffic of the server per target ip, saved to --status-file every minute.
+
 use std::{
     collections::{HashMap, HashSet},
     fs::OpenOptions,
     io::Write,
     net::IpAddr,
+    sync::Mutex,
 };
 
-pub struct Statistics {
-    conns: HashMap<IpAddr, TrafficData>,
+use crate::config::OPTIONS;
+
+lazy_static::lazy_static! {
+    static ref STATISTICS: Mutex<HashMap<IpAddr, TrafficData>> = Default::default();
 }
 
 #[derive(Default)]
@@ -25,16 +30,12 @@ impl TrafficData {
 }
 
 macro_rules! add {
-    ($self:ident, $field:ident, $bytes:ident, $dst:ident, $source:ident) => {
-        match $dst {
-            Some(ip) => {
-                if ip.is_loopback() {
-                    return;
-                }
-            }
-            None => return,
+    ($field:ident, $bytes:ident, $dst:ident, $source:ident) => {
+        if $dst.is_loopback() {
+            return;
         }
-        let data = $self.conns.entry($dst.unwrap()).or_default();
+        let mut conns = STATISTICS.lock().unwrap();
+        let data = conns.entry($dst).or_default();
         if let Some(source) = $source {
             data.sources.insert(source);
         }
@@ -42,60 +43,60 @@ macro_rules! add {
     };
 }
 
-impl Statistics {
-    pub fn new() -> Statistics {
-        Self {
-            conns: Default::default(),
-        }
-    }
-
-    pub fn add_tcp_rx(&mut self, bytes: usize, dst: Option<IpAddr>, source: Option<IpAddr>) {
-        add!(self, tcp_rx, bytes, dst, source);
-    }
+/// Bytes sent to `dst` over tcp for the client at `source`.
+pub fn add_tcp_rx(bytes: usize, dst: IpAddr, source: Option<IpAddr>) {
+    add!(tcp_rx, bytes, dst, source);
+}
 
-    pub fn add_tcp_tx(&mut self, bytes: usize, dst: Option<IpAddr>, source: Option<IpAddr>) {
-        add!(self, tcp_tx, bytes, dst, source);
-    }
+/// Bytes received from `dst` over tcp.
+pub fn add_tcp_tx(bytes: usize, dst: IpAddr, source: Option<IpAddr>) {
+    add!(tcp_tx, bytes, dst, source);
+}
 
-    pub fn add_udp_rx(&mut self, bytes: usize, dst: Option<IpAddr>, source: Option<IpAddr>) {
-        add!(self, udp_rx, bytes, dst, source);
-    }
+/// Bytes sent to `dst` over udp for the client at `source`.
+pub fn add_udp_rx(bytes: usize, dst: IpAddr, source: Option<IpAddr>) {
+    add!(udp_rx, bytes, dst, source);
+}
 
-    pub fn add_udp_tx(&mut self, bytes: usize, dst: Option<IpAddr>, source: Option<IpAddr>) {
-        add!(self, udp_tx, bytes, dst, source);
-    }
+/// Bytes received from `dst` over udp.
+pub fn add_udp_tx(bytes: usize, dst: IpAddr, source: Option<IpAddr>) {
+    add!(udp_tx, bytes, dst, source);
+}
 
-    pub fn save(&self, file: &str, limit: usize) {
-        let mut oo = OpenOptions::new();
-        oo.write(true);
-        oo.truncate(true);
-        oo.create(true);
-        match oo.open(file).map(|mut file| -> Result<(), std::io::Error> {
-            let mut conns: Vec<_> = self.conns.iter().collect();
-            let limit = if limit == 0 { conns.len() } else { limit };
-            conns.sort_by(|(_, data1), (_, data2)| data1.all().cmp(&data2.all()).reverse());
-            for (ip, data) in conns.iter().take(limit) {
-                write!(
-                    &mut file,
-                    "{} {} {} {} {} {}",
-                    data.all(),
-                    data.tcp_rx,
-                    data.tcp_tx,
-                    data.udp_rx,
-                    data.udp_tx,
-                    ip,
-                )?;
-                for ip in &data.sources {
-                    write!(&mut file, " {}", ip)?;
-                }
-                writeln!(&mut file)?;
+/// Writes the targets with the most traffic, one per line.
+pub fn save() {
+    let file = OPTIONS.server_args().status_file.as_str();
+    let limit = OPTIONS.server_args().status_limit;
+    let conns = STATISTICS.lock().unwrap();
+    let mut oo = OpenOptions::new();
+    oo.write(true);
+    oo.truncate(true);
+    oo.create(true);
+    match oo.open(file).map(|mut file| -> Result<(), std::io::Error> {
+        let mut conns: Vec<_> = conns.iter().collect();
+        let limit = if limit == 0 { conns.len() } else { limit };
+        conns.sort_by(|(_, data1), (_, data2)| data1.all().cmp(&data2.all()).reverse());
+        for (ip, data) in conns.iter().take(limit) {
+            write!(
+                &mut file,
+                "{} {} {} {} {} {}",
+                data.all(),
+                data.tcp_rx,
+                data.tcp_tx,
+                data.udp_rx,
+                data.udp_tx,
+                ip,
+            )?;
+            for ip in &data.sources {
+                write!(&mut file, " {}", ip)?;
             }
-            Ok(())
-        }) {
-            Ok(Err(err)) | Err(err) => {
-                log::error!("save file:{} failed:{}", file, err);
-            }
-            _ => {}
+            writeln!(&mut file)?;
+        }
+        Ok(())
+    }) {
+        Ok(Err(err)) | Err(err) => {
+            log::error!("save file:{} failed:{}", file, err);
         }
+        _ => {}
     }
 }
@@ -0,0 +1,195 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::OPTIONS,
+    limiter::{RateLimiter, DOWNLOAD, UPLOAD},
+    types::Result,
+};
+
+/// How often the usage file is saved.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+lazy_static::lazy_static! {
+    static ref USAGE: Mutex<HashMap<String, Arc<Usage>>> = Mutex::new(HashMap::new());
+}
+
+/// Traffic of a user since the usage file was created.
+pub struct Usage {
+    /// bytes from the user to targets
+    pub upload: AtomicU64,
+    /// bytes from targets to the user
+    pub download: AtomicU64,
+    /// bytes allowed in both directions, u64::MAX for unlimited
+    quota: AtomicU64,
+    /// rate of the user to targets, under the server's limit
+    pub upload_limit: RateLimiter,
+    /// rate of the user from targets, under the server's limit
+    pub download_limit: RateLimiter,
+}
+
+/// Figures of a user as saved in the usage file and answered to queries.
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct UsageRecord {
+    pub upload: u64,
+    pub download: u64,
+    #[serde(skip_deserializing)]
+    pub quota: Option<u64>,
+}
+
+impl Usage {
+    fn new(quota: Option<u64>, upload_limit: RateLimiter, download_limit: RateLimiter) -> Usage {
+        Usage {
+            upload: AtomicU64::new(0),
+            download: AtomicU64::new(0),
+            quota: AtomicU64::new(quota.unwrap_or(u64::MAX)),
+            upload_limit,
+            download_limit,
+        }
+    }
+
+    fn quota(&self) -> Option<u64> {
+        let quota = self.quota.load(Ordering::Relaxed);
+        (quota != u64::MAX).then_some(quota)
+    }
+
+    pub fn add_upload(&self, n: usize) {
+        self.upload.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_download(&self, n: usize) {
+        self.download.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// True once the traffic in both directions reaches the quota of the user.
+    pub fn exceeded(&self) -> bool {
+        self.quota().is_some_and(|quota| {
+            self.upload.load(Ordering::Relaxed) + self.download.load(Ordering::Relaxed) >= quota
+        })
+    }
+
+    /// Completes once the quota is exceeded.
+    pub async fn wait_exceeded(&self) {
+        let mut check = tokio::time::interval(Duration::from_secs(1));
+        while !self.exceeded() {
+            check.tick().await;
+        }
+    }
+
+    pub fn record(&self) -> UsageRecord {
+        UsageRecord {
+            upload: self.upload.load(Ordering::Relaxed),
+            download: self.download.load(Ordering::Relaxed),
+            quota: self.quota(),
+        }
+    }
+}
+
+/// Counters of the user labeled `user`, None for connections without a user.
+pub fn of(user: Option<&str>) -> Option<Arc<Usage>> {
+    let user = user?;
+    let mut usage = USAGE.lock().unwrap();
+    let usage = usage.entry(user.to_string()).or_insert_with(|| {
+        let rate = OPTIONS.rate(user);
+        Arc::new(Usage::new(
+            OPTIONS.quota(user),
+            RateLimiter::with_parent(rate, &UPLOAD),
+            RateLimiter::with_parent(rate, &DOWNLOAD),
+        ))
+    });
+    Some(usage.clone())
+}
+
+/// Applies a changed quota and rate limit of the user labeled `user` to the connections open
+/// already.
+pub fn refresh(user: &str) {
+    let Some(usage) = USAGE.lock().unwrap().get(user).cloned() else {
+        return;
+    };
+    usage
+        .quota
+        .store(OPTIONS.quota(user).unwrap_or(u64::MAX), Ordering::Relaxed);
+    let rate = OPTIONS.rate(user);
+    // setting the rate refills the buckets, so unchanged ones are left alone
+    if usage.upload_limit.rate() != rate {
+        usage.upload_limit.set_rate(rate);
+        usage.download_limit.set_rate(rate);
+    }
+}
+
+/// Figures of all users seen so far, as JSON.
+pub fn to_json() -> String {
+    let records: BTreeMap<_, _> = USAGE
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(user, usage)| (user.clone(), usage.record()))
+        .collect();
+    serde_json::to_string(&records).unwrap()
+}
+
+/// Loads the usage file if there is one and saves it periodically from now on.
+pub fn start(path: &str) -> Result<()> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => {
+            let records: HashMap<String, UsageRecord> = serde_json::from_str(content.as_str())?;
+            log::warn!("usage of {} users loaded from {}", records.len(), path);
+            for (user, record) in records {
+                let usage = of(Some(user.as_str())).unwrap();
+                usage.upload.store(record.upload, Ordering::Relaxed);
+                usage.download.store(record.download, Ordering::Relaxed);
+            }
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+    let path = path.to_string();
+    thread::spawn(move || loop {
+        thread::sleep(SAVE_INTERVAL);
+        save(path.as_str());
+    });
+    Ok(())
+}
+
+/// Writes the usage file through a temporary file, so it is never left half written.
+pub fn save(path: &str) {
+    let temp = format!("{}.tmp", path);
+    if let Err(err) =
+        std::fs::write(temp.as_str(), to_json()).and_then(|_| std::fs::rename(&temp, path))
+    {
+        log::error!("save usage to {} failed:{}", path, err);
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_quota() {
+        use std::sync::atomic::Ordering;
+
+        use crate::{
+            limiter::RateLimiter,
+            server::usage::{Usage, UsageRecord},
+        };
+
+        let unlimited = || RateLimiter::new(0);
+        let usage = Usage::new(Some(100), unlimited(), unlimited());
+        usage.add_upload(60);
+        assert!(!usage.exceeded());
+        usage.add_download(40);
+        assert!(usage.exceeded());
+        assert_eq!(usage.download.load(Ordering::Relaxed), 40);
+        let record: UsageRecord =
+            serde_json::from_str(r#"{"upload":1,"download":2,"quota":3}"#).unwrap();
+        assert_eq!(record.quota, None);
+        assert!(!Usage::new(None, unlimited(), unlimited()).exceeded());
+    }
+}
@@ -9,3 +9,15 @@ cfg_if! {
         pub use self::windows::*;
     }
 }
+
+/// Round trip times and delivery rate the kernel measured for a tcp connection.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TcpInfo {
+    pub rtt: std::time::Duration,
+    pub min_rtt: std::time::Duration,
+    pub bytes_acked: u64,
+    /// bytes per second
+    pub delivery_rate: u64,
+    /// whether the rate was measured while the sender had nothing more to send
+    pub app_limited: bool,
+}
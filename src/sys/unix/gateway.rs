@@ -0,0 +1,214 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    net::IpAddr,
+    os::unix::io::AsRawFd,
+    str::FromStr,
+};
+
+use smoltcp::{
+    phy::{self, ChecksumCapabilities, Device, Medium, RawSocket, RxToken, TxToken},
+    time::Instant,
+    wire::{
+        ArpOperation, ArpPacket, ArpRepr, EthernetAddress, EthernetFrame, EthernetProtocol,
+        EthernetRepr, Icmpv6Packet, Icmpv6Repr, IpAddress, IpProtocol, Ipv6Address, Ipv6Packet,
+        Ipv6Repr, NdiscNeighborFlags, NdiscRepr,
+    },
+};
+
+/// Answers ARP and neighbor solicitations for the virtual gateway `ips` on `iface`,
+/// so LAN devices can use them as default route while this machine does the proxying.
+pub fn start_gateway_responder(iface: &str, ips: &[IpAddr]) -> Result<()> {
+    let mac = std::fs::read_to_string(format!("/sys/class/net/{}/address", iface))?;
+    let mac = EthernetAddress::from_str(mac.trim())
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid interface mac address"))?;
+    let ips = ips.to_vec();
+    let iface = iface.to_owned();
+    let (sender, receiver) = std::sync::mpsc::channel();
+    // the raw socket is not Send, create it in the responder thread and report back.
+    std::thread::spawn(move || {
+        let mut socket = match RawSocket::new(iface.as_str(), Medium::Ethernet) {
+            Ok(socket) => {
+                let _ = sender.send(Ok(()));
+                socket
+            }
+            Err(err) => {
+                let _ = sender.send(Err(err));
+                return;
+            }
+        };
+        log::warn!("answering arp/ndp for {:?} on {}({})", ips, iface, mac);
+        loop {
+            if let Err(err) = phy::wait(socket.as_raw_fd(), None) {
+                log::error!("gateway responder wait failed:{}", err);
+                break;
+            }
+            while let Some((rx, tx)) = socket.receive(Instant::now()) {
+                if let Some(reply) = rx.consume(|frame| reply_frame(frame, mac, &ips)) {
+                    tx.consume(reply.len(), |buffer| {
+                        buffer.copy_from_slice(reply.as_slice())
+                    });
+                }
+            }
+        }
+    });
+    receiver
+        .recv()
+        .map_err(|_| Error::other("gateway responder exited"))?
+}
+
+fn reply_frame(frame: &[u8], mac: EthernetAddress, ips: &[IpAddr]) -> Option<Vec<u8>> {
+    let frame = EthernetFrame::new_checked(frame).ok()?;
+    let request = EthernetRepr::parse(&frame).ok()?;
+    match request.ethertype {
+        EthernetProtocol::Arp => reply_arp(frame.payload(), mac, ips),
+        EthernetProtocol::Ipv6 => reply_ndp(frame.payload(), mac, ips),
+        _ => None,
+    }
+}
+
+fn reply_arp(payload: &[u8], mac: EthernetAddress, ips: &[IpAddr]) -> Option<Vec<u8>> {
+    let packet = ArpPacket::new_checked(payload).ok()?;
+    let ArpRepr::EthernetIpv4 {
+        operation: ArpOperation::Request,
+        source_hardware_addr,
+        source_protocol_addr,
+        target_protocol_addr,
+        ..
+    } = ArpRepr::parse(&packet).ok()?
+    else {
+        return None;
+    };
+    if !ips.contains(&IpAddr::V4(target_protocol_addr.into())) {
+        return None;
+    }
+    let arp = ArpRepr::EthernetIpv4 {
+        operation: ArpOperation::Reply,
+        source_hardware_addr: mac,
+        source_protocol_addr: target_protocol_addr,
+        target_hardware_addr: source_hardware_addr,
+        target_protocol_addr: source_protocol_addr,
+    };
+    let ethernet = EthernetRepr {
+        src_addr: mac,
+        dst_addr: source_hardware_addr,
+        ethertype: EthernetProtocol::Arp,
+    };
+    let mut buffer = vec![0u8; ethernet.buffer_len() + arp.buffer_len()];
+    let mut frame = EthernetFrame::new_unchecked(buffer.as_mut_slice());
+    ethernet.emit(&mut frame);
+    arp.emit(&mut ArpPacket::new_unchecked(frame.payload_mut()));
+    Some(buffer)
+}
+
+fn reply_ndp(payload: &[u8], mac: EthernetAddress, ips: &[IpAddr]) -> Option<Vec<u8>> {
+    let packet = Ipv6Packet::new_checked(payload).ok()?;
+    let request = Ipv6Repr::parse(&packet).ok()?;
+    if request.next_header != IpProtocol::Icmpv6 {
+        return None;
+    }
+    let icmp = Icmpv6Packet::new_checked(packet.payload()).ok()?;
+    let checksum = ChecksumCapabilities::default();
+    let src_addr = IpAddress::Ipv6(request.src_addr);
+    let dst_addr = IpAddress::Ipv6(request.dst_addr);
+    let Icmpv6Repr::Ndisc(NdiscRepr::NeighborSolicit {
+        target_addr,
+        lladdr,
+    }) = Icmpv6Repr::parse(&src_addr, &dst_addr, &icmp, &checksum).ok()?
+    else {
+        return None;
+    };
+    if !ips.contains(&IpAddr::V6(target_addr.into())) {
+        return None;
+    }
+    // Unspecified source is duplicate address detection, answer to all nodes.
+    let (reply_addr, reply_mac) = match lladdr.and_then(|addr| addr.parse(Medium::Ethernet).ok()) {
+        Some(smoltcp::wire::HardwareAddress::Ethernet(peer))
+            if !request.src_addr.is_unspecified() =>
+        {
+            (request.src_addr, peer)
+        }
+        _ => (
+            Ipv6Address::LINK_LOCAL_ALL_NODES,
+            EthernetAddress([0x33, 0x33, 0, 0, 0, 1]),
+        ),
+    };
+    let advert = Icmpv6Repr::Ndisc(NdiscRepr::NeighborAdvert {
+        flags: NdiscNeighborFlags::ROUTER
+            | NdiscNeighborFlags::SOLICITED
+            | NdiscNeighborFlags::OVERRIDE,
+        target_addr,
+        lladdr: Some(mac.into()),
+    });
+    let ip = Ipv6Repr {
+        src_addr: target_addr,
+        dst_addr: reply_addr,
+        next_header: IpProtocol::Icmpv6,
+        payload_len: advert.buffer_len(),
+        hop_limit: 255,
+    };
+    let ethernet = EthernetRepr {
+        src_addr: mac,
+        dst_addr: reply_mac,
+        ethertype: EthernetProtocol::Ipv6,
+    };
+    let mut buffer = vec![0u8; ethernet.buffer_len() + ip.buffer_len() + ip.payload_len];
+    let mut frame = EthernetFrame::new_unchecked(buffer.as_mut_slice());
+    ethernet.emit(&mut frame);
+    let mut packet = Ipv6Packet::new_unchecked(frame.payload_mut());
+    ip.emit(&mut packet);
+    advert.emit(
+        &IpAddress::Ipv6(target_addr),
+        &IpAddress::Ipv6(reply_addr),
+        &mut Icmpv6Packet::new_unchecked(packet.payload_mut()),
+        &checksum,
+    );
+    Some(buffer)
+}
+
+mod tests {
+    #[test]
+    fn test_arp_reply() {
+        use std::net::IpAddr;
+
+        use smoltcp::wire::{
+            ArpOperation, ArpPacket, ArpRepr, EthernetAddress, EthernetFrame, EthernetProtocol,
+            EthernetRepr, Ipv4Address,
+        };
+
+        let mac = EthernetAddress([2, 0, 0, 0, 0, 1]);
+        let peer = EthernetAddress([2, 0, 0, 0, 0, 2]);
+        let gateway: IpAddr = "192.168.1.254".parse().unwrap();
+        let arp = ArpRepr::EthernetIpv4 {
+            operation: ArpOperation::Request,
+            source_hardware_addr: peer,
+            source_protocol_addr: Ipv4Address::new(192, 168, 1, 10),
+            target_hardware_addr: EthernetAddress([0; 6]),
+            target_protocol_addr: Ipv4Address::new(192, 168, 1, 254),
+        };
+        let ethernet = EthernetRepr {
+            src_addr: peer,
+            dst_addr: EthernetAddress::BROADCAST,
+            ethertype: EthernetProtocol::Arp,
+        };
+        let mut buffer = vec![0u8; ethernet.buffer_len() + arp.buffer_len()];
+        let mut frame = EthernetFrame::new_unchecked(buffer.as_mut_slice());
+        ethernet.emit(&mut frame);
+        arp.emit(&mut ArpPacket::new_unchecked(frame.payload_mut()));
+
+        assert!(super::reply_frame(&buffer, mac, &["10.0.0.1".parse().unwrap()]).is_none());
+        let reply = super::reply_frame(&buffer, mac, &[gateway]).unwrap();
+        let frame = EthernetFrame::new_checked(reply.as_slice()).unwrap();
+        assert_eq!(frame.dst_addr(), peer);
+        let packet = ArpPacket::new_checked(frame.payload()).unwrap();
+        assert_eq!(
+            ArpRepr::parse(&packet).unwrap(),
+            ArpRepr::EthernetIpv4 {
+                operation: ArpOperation::Reply,
+                source_hardware_addr: mac,
+                source_protocol_addr: Ipv4Address::new(192, 168, 1, 254),
+                target_hardware_addr: peer,
+                target_protocol_addr: Ipv4Address::new(192, 168, 1, 10),
+            }
+        );
+    }
+}
@@ -1,12 +1,119 @@
 use std::{
     convert::TryFrom,
     io::{Error, ErrorKind, Result},
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
-    os::unix::io::AsRawFd,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpListener},
+    os::unix::{
+        io::{AsRawFd, FromRawFd},
+        process::CommandExt,
+    },
+    process::Command,
+    sync::atomic::{AtomicBool, Ordering},
 };
 
-#[allow(dead_code)]
-pub fn set_mark<T: AsRawFd>(socket: &T, mark: u8) -> Result<()> {
+use socket2::{Domain, Protocol, Socket, Type};
+
+pub use gateway::start_gateway_responder;
+pub use route_table::{install_route_table, remove_route_table};
+
+mod gateway;
+mod route_table;
+
+static TERMINATED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_terminate(_: libc::c_int) {
+    TERMINATED.store(true, Ordering::SeqCst);
+}
+
+/// Installs a SIGTERM handler, check it with [`terminated`].
+pub fn watch_terminate() -> Result<()> {
+    let ret = unsafe {
+        libc::signal(
+            libc::SIGTERM,
+            on_terminate as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        )
+    };
+    if ret == libc::SIG_ERR {
+        Err(Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+pub fn terminated() -> bool {
+    TERMINATED.load(Ordering::SeqCst)
+}
+
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_reload(_: libc::c_int) {
+    RELOAD.store(true, Ordering::SeqCst);
+}
+
+/// Installs a SIGHUP handler, check it with [`reload_requested`].
+pub fn watch_reload() -> Result<()> {
+    let handler = on_reload as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    let ret = unsafe { libc::signal(libc::SIGHUP, handler) };
+    if ret == libc::SIG_ERR {
+        Err(Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// True once after each reload request.
+pub fn reload_requested() -> bool {
+    RELOAD.swap(false, Ordering::SeqCst)
+}
+
+static HEAP_DUMP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_heap_dump(_: libc::c_int) {
+    HEAP_DUMP.store(true, Ordering::SeqCst);
+}
+
+/// Installs a SIGUSR1 handler, check it with [`heap_dump_requested`].
+pub fn watch_heap_dump() -> Result<()> {
+    let handler = on_heap_dump as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    let ret = unsafe { libc::signal(libc::SIGUSR1, handler) };
+    if ret == libc::SIG_ERR {
+        Err(Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// True once after each heap dump request.
+pub fn heap_dump_requested() -> bool {
+    HEAP_DUMP.swap(false, Ordering::SeqCst)
+}
+
+/// Native crashes leave a core dump on unix if the system is set up for it.
+pub fn install_crash_handler(_log_file: &str, _keep: usize) {}
+
+/// Has the child spawned by `command` terminated along with this process, Linux only.
+pub fn kill_with_parent(command: &mut Command) {
+    #[cfg(target_os = "linux")]
+    unsafe {
+        command.pre_exec(|| {
+            if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) == -1 {
+                Err(Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        });
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = command;
+}
+
+/// Takes over a listening socket inherited from the parent process.
+pub fn listener_from_fd(fd: i32) -> Result<TcpListener> {
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+pub fn set_mark<T: AsRawFd>(socket: &T, mark: u32) -> Result<()> {
     let fd = socket.as_raw_fd();
     unsafe {
         let mark = mark as libc::c_int;
@@ -25,6 +132,178 @@ pub fn set_mark<T: AsRawFd>(socket: &T, mark: u8) -> Result<()> {
     }
 }
 
+/// Sets the DSCP of the packets sent through `socket`, the upper 6 bits of IP_TOS or IPV6_TCLASS.
+pub fn set_dscp<T: AsRawFd>(socket: &T, v4: bool, dscp: u8) -> Result<()> {
+    let fd = socket.as_raw_fd();
+    let (level, name) = if v4 {
+        (libc::IPPROTO_IP, libc::IP_TOS)
+    } else {
+        (libc::IPPROTO_IPV6, libc::IPV6_TCLASS)
+    };
+    unsafe {
+        let tos = (dscp << 2) as libc::c_int;
+        let ret = libc::setsockopt(
+            fd,
+            level,
+            name,
+            &tos as *const _ as *const _,
+            std::mem::size_of_val(&tos) as libc::socklen_t,
+        );
+        if ret != 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+static NO_MPTCP: AtomicBool = AtomicBool::new(false);
+
+/// A stream socket of `domain`, MPTCP if `mptcp` and the kernel has it, TCP otherwise.
+pub fn stream_socket(domain: Domain, mptcp: bool) -> Result<Socket> {
+    #[cfg(target_os = "linux")]
+    if mptcp && !NO_MPTCP.load(Ordering::Relaxed) {
+        match Socket::new(domain, Type::STREAM, Some(Protocol::MPTCP)) {
+            Ok(socket) => return Ok(socket),
+            // kernels before 5.6, or net.mptcp.enabled is 0
+            Err(err)
+                if matches!(
+                    err.raw_os_error(),
+                    Some(libc::EPROTONOSUPPORT | libc::ENOPROTOOPT | libc::EINVAL)
+                ) =>
+            {
+                log::warn!("MPTCP is not available, fall back to TCP:{}", err);
+                NO_MPTCP.store(true, Ordering::Relaxed);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = mptcp;
+    Socket::new(domain, Type::STREAM, Some(Protocol::TCP))
+}
+
+/// Starts a non-blocking connection, with SO_MARK set before the SYN is sent if `mark` given.
+pub fn connect_nonblocking(
+    addr: SocketAddr,
+    mark: Option<u32>,
+    mptcp: bool,
+) -> Result<std::net::TcpStream> {
+    let socket = stream_socket(Domain::for_address(addr), mptcp)?;
+    if let Some(mark) = mark {
+        set_mark(&socket, mark)?;
+    }
+    socket.set_nonblocking(true)?;
+    match socket.connect(&addr.into()) {
+        Ok(()) => {}
+        Err(err) if err.raw_os_error() == Some(libc::EINPROGRESS) => {}
+        Err(err) => return Err(err),
+    }
+    Ok(socket.into())
+}
+
+/// Identifies a socket for `tcp_info` after the caller let go of it.
+pub type SocketId = std::os::unix::io::RawFd;
+
+pub fn socket_id<T: AsRawFd>(socket: &T) -> SocketId {
+    socket.as_raw_fd()
+}
+
+/// Leading fields of the kernel's struct tcp_info, up to tcpi_delivery_rate of Linux 4.9.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default)]
+struct RawTcpInfo {
+    /// tcpi_state up to the byte of tcpi_delivery_rate_app_limited
+    head: [u8; 8],
+    _before_rtt: [u32; 15],
+    rtt: u32,
+    _rttvar: u32,
+    _before_pacing: [u32; 7],
+    _pacing_rate: [u64; 2],
+    bytes_acked: u64,
+    _bytes_received: u64,
+    _segs: [u32; 3],
+    min_rtt: u32,
+    _data_segs: [u32; 2],
+    delivery_rate: u64,
+}
+
+/// Kernel measurements of the tcp connection `socket`, failing if it's no longer connected to
+/// `peer`, like after the fd was closed and reused.
+#[cfg(target_os = "linux")]
+pub fn tcp_info(socket: SocketId, peer: SocketAddr) -> Result<crate::sys::TcpInfo> {
+    // borrowed, the socket is closed by its owner
+    let borrowed = std::mem::ManuallyDrop::new(unsafe { Socket::from_raw_fd(socket) });
+    if borrowed.peer_addr()?.as_socket() != Some(peer) {
+        return Err(ErrorKind::NotConnected.into());
+    }
+    let mut info = RawTcpInfo::default();
+    let mut len = std::mem::size_of::<RawTcpInfo>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            socket,
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut _ as *mut _,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    if (len as usize) < std::mem::size_of::<RawTcpInfo>() {
+        return Err(ErrorKind::Unsupported.into());
+    }
+    Ok(crate::sys::TcpInfo {
+        rtt: std::time::Duration::from_micros(info.rtt as u64),
+        min_rtt: std::time::Duration::from_micros(info.min_rtt as u64),
+        bytes_acked: info.bytes_acked,
+        delivery_rate: info.delivery_rate,
+        app_limited: info.head[7] & 1 != 0,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn tcp_info(_socket: SocketId, _peer: SocketAddr) -> Result<crate::sys::TcpInfo> {
+    Err(ErrorKind::Unsupported.into())
+}
+
+/// A line with the id, name, state and wait channel of each thread of this process.
+#[cfg(target_os = "linux")]
+pub fn thread_dump() -> String {
+    use std::fmt::Write;
+
+    let Ok(tasks) = std::fs::read_dir("/proc/self/task") else {
+        return "threads not readable".into();
+    };
+    let mut dump = String::new();
+    for task in tasks.flatten() {
+        let path = task.path();
+        let read = |file| std::fs::read_to_string(path.join(file)).unwrap_or_default();
+        let stat = read("stat");
+        // the state follows the name, which may contain spaces and parentheses
+        let state = stat
+            .rsplit_once(") ")
+            .and_then(|(_, rest)| rest.split(' ').next())
+            .unwrap_or("?");
+        let _ = writeln!(
+            dump,
+            "{} {} {} {}",
+            task.file_name().to_string_lossy(),
+            read("comm").trim(),
+            state,
+            read("wchan").trim()
+        );
+    }
+    dump
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn thread_dump() -> String {
+    "thread dump not supported".into()
+}
+
 pub fn set_socket_opts<T: AsRawFd>(v4: bool, is_udp: bool, socket: &T) -> Result<()> {
     let fd = socket.as_raw_fd();
 
@@ -66,13 +345,15 @@ pub fn set_socket_opts<T: AsRawFd>(v4: bool, is_udp: bool, socket: &T) -> Result
     Ok(())
 }
 
+/// Destination a client connected to, the original one of a connection the nat table
+/// redirected, else the local address, which is the destination with TPROXY.
 pub fn get_oridst_addr<T>(s: &T) -> Result<SocketAddr>
 where
     T: AsRawFd,
 {
     let fd = s.as_raw_fd();
 
-    unsafe {
+    let local = unsafe {
         let mut target_addr: libc::sockaddr_storage = std::mem::zeroed();
         let mut target_addr_len = std::mem::size_of_val(&target_addr) as libc::socklen_t;
 
@@ -83,11 +364,40 @@ where
         );
 
         if ret != 0 {
-            Err(Error::last_os_error())
-        } else {
-            // Convert sockaddr_storage to SocketAddr
-            sockaddr_to_std(&target_addr)
+            return Err(Error::last_os_error());
+        }
+        // Convert sockaddr_storage to SocketAddr
+        sockaddr_to_std(&target_addr)?
+    };
+    // ipv4 clients of a dual stack listener have their conntrack entry in ipv4
+    let v4 = match local {
+        SocketAddr::V4(_) => true,
+        SocketAddr::V6(v6) => v6.ip().to_ipv4_mapped().is_some(),
+    };
+    Ok(get_redirected_addr(fd, v4).unwrap_or(local))
+}
+
+/// SO_ORIGINAL_DST of the connection, None without a conntrack entry.
+fn get_redirected_addr(fd: libc::c_int, v4: bool) -> Option<SocketAddr> {
+    let (sol, opt) = if v4 {
+        (libc::SOL_IP, libc::SO_ORIGINAL_DST)
+    } else {
+        (libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST)
+    };
+    unsafe {
+        let mut target_addr: libc::sockaddr_storage = std::mem::zeroed();
+        let mut target_addr_len = std::mem::size_of_val(&target_addr) as libc::socklen_t;
+        let ret = libc::getsockopt(
+            fd,
+            sol,
+            opt,
+            &mut target_addr as *mut _ as *mut _,
+            &mut target_addr_len,
+        );
+        if ret != 0 {
+            return None;
         }
+        sockaddr_to_std(&target_addr).ok()
     }
 }
 
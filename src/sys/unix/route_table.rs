@@ -0,0 +1,64 @@
+use std::{
+    io::{Error, Result},
+    process::Command,
+};
+
+fn ip(args: &[&str]) -> Result<()> {
+    let output = Command::new("ip").args(args).output()?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::other(format!(
+            "ip {} failed:{}",
+            args.join(" "),
+            String::from_utf8_lossy(output.stderr.as_slice()).trim()
+        )))
+    }
+}
+
+fn replace_rule(family: &str, mark: &str, table: &str) -> Result<()> {
+    // a rule left by a killed process would be duplicated otherwise
+    while ip(&[family, "rule", "del", "fwmark", mark, "table", table]).is_ok() {}
+    ip(&[family, "rule", "add", "fwmark", mark, "table", table])
+}
+
+/// Routes packets marked with `mark` to the local stack through `table`, the same as
+/// `ip rule add fwmark 0xff table 100` and `ip route add local 0.0.0.0/0 dev lo table 100`.
+/// The main table is left untouched, IPv6 is skipped with a warning if not available.
+///
+/// Packets marked with `server_mark` are sent to the main table by a rule added after, so
+/// taking precedence over, the tproxy one. They never loop back into the proxy then.
+pub fn install_route_table(table: u32, mark: u32, server_mark: Option<u32>) -> Result<()> {
+    let table = table.to_string();
+    let mark = format!("{:#x}", mark);
+    let server_mark = server_mark.map(|mark| format!("{:#x}", mark));
+    for (family, dst) in [("-4", "0.0.0.0/0"), ("-6", "::/0")] {
+        let ret = ip(&[
+            family, "route", "replace", "local", dst, "dev", "lo", "table", &table,
+        ])
+        .and_then(|_| replace_rule(family, &mark, &table))
+        .and_then(|_| match &server_mark {
+            Some(server_mark) => replace_rule(family, server_mark, "main"),
+            None => Ok(()),
+        });
+        match ret {
+            Err(err) if family == "-6" => log::warn!("ipv6 route table skipped:{}", err),
+            ret => ret?,
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the rules added by [`install_route_table`], the table itself is no longer used then.
+pub fn remove_route_table(table: u32, mark: u32, server_mark: Option<u32>) -> Result<()> {
+    let table = table.to_string();
+    let mark = format!("{:#x}", mark);
+    if let Some(server_mark) = server_mark {
+        let server_mark = format!("{:#x}", server_mark);
+        let _ = ip(&["-4", "rule", "del", "fwmark", &server_mark, "table", "main"]);
+        let _ = ip(&["-6", "rule", "del", "fwmark", &server_mark, "table", "main"]);
+    }
+    ip(&["-4", "rule", "del", "fwmark", &mark, "table", &table])?;
+    let _ = ip(&["-6", "rule", "del", "fwmark", &mark, "table", &table]);
+    Ok(())
+}
@@ -1,10 +1,155 @@
-use std::{any::Any, io::Result, net::SocketAddr};
+use std::{
+    any::Any,
+    io::Result,
+    mem::ManuallyDrop,
+    net::{IpAddr, SocketAddr, TcpListener},
+    os::windows::io::{AsRawSocket, FromRawSocket},
+    path::Path,
+    process::Command,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+};
 
-#[allow(dead_code)]
-pub fn set_mark<T: Any>(_socket: &T, _mark: u8) -> Result<()> {
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::windows::named_pipe::ServerOptions;
+
+pub fn watch_terminate() -> Result<()> {
+    Ok(())
+}
+
+pub fn terminated() -> bool {
+    false
+}
+
+/// Pipe whose clients request a reload, like `echo reload > \\.\pipe\trojan-reload`.
+const RELOAD_PIPE: &str = r"\\.\pipe\trojan-reload";
+
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+/// Windows has no SIGHUP, a connection to the reload pipe requests a reload instead. Check it
+/// with [`reload_requested`].
+pub fn watch_reload() -> Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()?;
+    thread::spawn(move || {
+        runtime.block_on(async {
+            loop {
+                let pipe = match ServerOptions::new().create(RELOAD_PIPE) {
+                    Ok(pipe) => pipe,
+                    Err(err) => {
+                        log::error!("create reload pipe failed:{}", err);
+                        return;
+                    }
+                };
+                if pipe.connect().await.is_ok() {
+                    RELOAD.store(true, Ordering::SeqCst);
+                }
+            }
+        })
+    });
+    Ok(())
+}
+
+/// True once after each reload request.
+pub fn reload_requested() -> bool {
+    RELOAD.swap(false, Ordering::SeqCst)
+}
+
+/// Windows has no SIGUSR1, and jemalloc with its heap profiles is not used there.
+pub fn watch_heap_dump() -> Result<()> {
+    Ok(())
+}
+
+pub fn heap_dump_requested() -> bool {
+    false
+}
+
+/// Writes a minidump next to `log_file`, or into the working directory when logging to stdout,
+/// if the process crashes outside of rust, e.g. inside wintun.dll.
+pub fn install_crash_handler(log_file: &str, keep: usize) {
+    let dir = match Path::new(log_file).parent() {
+        Some(dir) if log_file != "-" => dir,
+        _ => Path::new(""),
+    };
+    wintool::minidump::install(dir, "trojan", keep);
+}
+
+/// Plugins stay behind if the process is killed, it stops them on a normal exit.
+pub fn kill_with_parent(_command: &mut Command) {}
+
+pub fn listener_from_fd(_fd: i32) -> Result<TcpListener> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "listen fd not supported in windows",
+    ))
+}
+
+pub fn start_gateway_responder(_iface: &str, _ips: &[IpAddr]) -> Result<()> {
+    unimplemented!("gateway mode not supported in windows");
+}
+
+pub fn install_route_table(_table: u32, _mark: u32, _server_mark: Option<u32>) -> Result<()> {
+    unimplemented!("route table not supported in windows");
+}
+
+pub fn remove_route_table(_table: u32, _mark: u32, _server_mark: Option<u32>) -> Result<()> {
+    unimplemented!("route table not supported in windows");
+}
+
+pub fn set_mark<T: Any>(_socket: &T, _mark: u32) -> Result<()> {
     Ok(())
 }
 
+/// Sets the DSCP of the packets sent through `socket`. Windows honors IP_TOS for IPv4 only and
+/// only if the group policy allows, DSCP is usually set with a QoS policy there.
+pub fn set_dscp<T: AsRawSocket>(socket: &T, v4: bool, dscp: u8) -> Result<()> {
+    if !v4 {
+        return Ok(());
+    }
+    // borrowed, the socket is closed by its owner
+    let socket = ManuallyDrop::new(unsafe { Socket::from_raw_socket(socket.as_raw_socket()) });
+    socket.set_tos((dscp as u32) << 2)
+}
+
+/// A TCP socket of `domain`, windows has no MPTCP.
+pub fn stream_socket(domain: Domain, _mptcp: bool) -> Result<Socket> {
+    Socket::new(domain, Type::STREAM, Some(Protocol::TCP))
+}
+
+pub fn connect_nonblocking(
+    addr: SocketAddr,
+    mark: Option<u32>,
+    _mptcp: bool,
+) -> Result<std::net::TcpStream> {
+    if mark.is_some() {
+        unimplemented!("socket mark not supported in windows");
+    }
+    let socket = stream_socket(Domain::for_address(addr), false)?;
+    socket.set_nonblocking(true)?;
+    match socket.connect(&addr.into()) {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {}
+        Err(err) => return Err(err),
+    }
+    Ok(socket.into())
+}
+
+pub type SocketId = std::os::windows::io::RawSocket;
+
+pub fn socket_id<T: AsRawSocket>(socket: &T) -> SocketId {
+    socket.as_raw_socket()
+}
+
+pub fn thread_dump() -> String {
+    "thread dump not supported".into()
+}
+
+/// Not measured on windows.
+pub fn tcp_info(_socket: SocketId, _peer: SocketAddr) -> Result<crate::sys::TcpInfo> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
 pub fn set_socket_opts<T: Any>(_v4: bool, _is_udp: bool, _socket: &T) -> Result<()> {
     unimplemented!("proxy mode not supported in windows");
 }
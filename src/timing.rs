@@ -0,0 +1,32 @@
+use std::time::Duration;
+
+use rand::Rng;
+use tokio::{io::AsyncReadExt, time::Instant};
+
+use crate::{config::OPTIONS, limiter::Priority};
+
+/// Holds back a bulk write of `n` bytes at the start of `buffer` for a random part of
+/// `--timing-jitter`, reading whatever else arrives meanwhile into the rest of the buffer so
+/// it goes out in the same write. A full buffer is written at once, so transfers keep their
+/// throughput. Returns the size to write, which is `n` if jitter is off or the traffic is
+/// interactive.
+pub async fn coalesce<R: AsyncReadExt + Unpin>(
+    read: &mut R,
+    buffer: &mut [u8],
+    mut n: usize,
+    priority: Priority,
+) -> usize {
+    if OPTIONS.timing_jitter == 0 || priority == Priority::Interactive {
+        return n;
+    }
+    let delay = rand::thread_rng().gen_range(0..=OPTIONS.timing_jitter);
+    let deadline = Instant::now() + Duration::from_millis(delay);
+    while n < buffer.len() {
+        match tokio::time::timeout_at(deadline, read.read(&mut buffer[n..])).await {
+            Ok(Ok(size)) if size > 0 => n += size,
+            // end of stream and errors show up again on the next read
+            _ => break,
+        }
+    }
+    n
+}
@@ -0,0 +1,30 @@
+use std::sync::Arc;
+
+use rustls::{client::Resumption, ClientConfig, RootCertStore};
+
+use crate::{cipher_order::provider, config::OPTIONS};
+
+/// Client config trusting `root_store`, offering ciphers in the `--cipher-order`.
+pub fn client_config(root_store: RootCertStore) -> ClientConfig {
+    let order = OPTIONS.cipher_order.as_str();
+    let mut config = ClientConfig::builder_with_provider(Arc::new(provider(order)))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+    config.resumption = match OPTIONS.tls_session_cache {
+        0 => Resumption::disabled(),
+        size => Resumption::in_memory_sessions(size),
+    };
+    config.enable_early_data = OPTIONS.early_data;
+    let alpn = OPTIONS.client_alpn();
+    if !alpn.is_empty() {
+        config.alpn_protocols = alpn
+            .iter()
+            .map(|protocol| protocol.as_bytes().to_vec())
+            // a gRPC server takes h2 connections as gRPC, only the streams of aproxy use it
+            .filter(|protocol| OPTIONS.grpc_service.is_none() || protocol != b"h2")
+            .collect();
+    }
+    config
+}
@@ -1,6 +1,6 @@
 use std::{
     io::{Error, ErrorKind, Read, Write},
-    net::{IpAddr, Shutdown},
+    net::Shutdown,
 };
 
 use mio::{net::TcpStream, Interest, Poll, Token};
@@ -106,8 +106,9 @@ impl TlsConn {
         }
     }
 
-    pub fn source(&self) -> Option<IpAddr> {
-        self.stream.peer_addr().map(|addr| addr.ip()).ok()
+    /// Limits the data buffered in the session, None for no limit.
+    pub fn set_buffer_limit(&mut self, limit: Option<usize>) {
+        self.session.set_buffer_limit(limit);
     }
 
     pub fn reset_index(&mut self, index: usize, token: Token, poll: &Poll) -> bool {
@@ -160,6 +161,7 @@ impl TlsConn {
     }
 
     pub fn do_read(&mut self) -> Option<Vec<u8>> {
+        let mut buffer = Vec::new();
         loop {
             match self.session.read_tls(&mut self.stream) {
                 Ok(size) => {
@@ -176,6 +178,10 @@ impl TlsConn {
                         self.index(),
                         size
                     );
+                    // drain the session as we go, or its buffer fills up on large reads
+                    if !self.read_session(&mut buffer) {
+                        return None;
+                    }
                 }
                 Err(err)
                     if err.kind() == ErrorKind::WouldBlock
@@ -197,6 +203,17 @@ impl TlsConn {
             }
         }
 
+        if !self.read_session(&mut buffer) {
+            return None;
+        }
+        if buffer.is_empty() {
+            None
+        } else {
+            Some(buffer)
+        }
+    }
+
+    fn read_session(&mut self, buffer: &mut Vec<u8>) -> bool {
         if let Err(err) = self.session.process_new_packets() {
             log::info!(
                 "connection:{} process new packets failed:{}",
@@ -204,11 +221,9 @@ impl TlsConn {
                 err
             );
             self.shutdown();
-            return None;
+            return false;
         }
-
-        let mut buffer = Vec::new();
-        if let Err(err) = self.session.reader().read_to_end(&mut buffer) {
+        if let Err(err) = self.session.reader().read_to_end(buffer) {
             if err.kind() != ErrorKind::WouldBlock {
                 log::info!(
                     "connection:{} read from session failed:{}",
@@ -218,11 +233,7 @@ impl TlsConn {
                 self.shutdown();
             }
         }
-        if buffer.is_empty() {
-            None
-        } else {
-            Some(buffer)
-        }
+        true
     }
 
     pub fn do_send(&mut self) {
@@ -323,3 +334,17 @@ impl StatusProvider for TlsConn {
         !self.session.wants_write()
     }
 }
+
+impl relay::Sink for TlsConn {
+    fn push(&mut self, data: &[u8]) -> bool {
+        if !self.write_session(data) {
+            return false;
+        }
+        self.do_send();
+        true
+    }
+
+    fn blocked(&self) -> bool {
+        !self.writable()
+    }
+}
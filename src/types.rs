@@ -18,6 +18,7 @@ pub enum TrojanError {
     LibLoading(libloading::Error),
     Dummy(()),
     AddrParse(std::net::AddrParseError),
+    ParseInt(std::num::ParseIntError),
     DnsName(rustls_pki_types::InvalidDnsNameError),
     VerifiedBuilder(rustls::client::VerifierBuilderError),
     Webpki(webpki::Error),
@@ -26,10 +27,6 @@ pub enum TrojanError {
     NonWindowsPlatform,
     #[from(ignore)]
     Winapi(String),
-    #[from(ignore)]
-    TxBreak(Option<std::io::Error>),
-    #[from(ignore)]
-    RxBreak(Option<std::io::Error>),
     DnsProto(trust_dns_proto::error::ProtoError),
     #[from(ignore)]
     MainAdapterNotFound,
@@ -39,14 +36,74 @@ pub enum TrojanError {
     #[from(ignore)]
     Resolve,
     Elapsed(tokio::time::error::Elapsed),
+    #[from(ignore)]
+    Inbound(&'static str),
+    #[from(ignore)]
+    Rules(&'static str),
+    #[from(ignore)]
+    Mux(&'static str),
+    #[from(ignore)]
+    Reverse(&'static str),
+    #[from(ignore)]
+    Forward(&'static str),
+    SerdeJson(serde_json::Error),
+    H2(h2::Error),
+    Http(http::Error),
+    QuicTls(quinn::rustls::Error),
+    QuicCipherSuite(quinn::crypto::rustls::NoInitialCipherSuite),
+    QuicConnect(quinn::ConnectError),
+    QuicConnection(quinn::ConnectionError),
+    #[from(ignore)]
+    Status(&'static str),
+    #[from(ignore)]
+    NotElevated,
+    #[from(ignore)]
+    DriverUnavailable(String),
+    #[from(ignore)]
+    ServerUnresolved(String),
+    #[from(ignore)]
+    Certificate(&'static str),
+    Rcgen(rcgen::Error),
+    #[from(ignore)]
+    Acme(String),
+    #[from(ignore)]
+    Ocsp(String),
+    #[from(ignore)]
+    Replay(&'static str),
+    #[from(ignore)]
+    Routing(&'static str),
+    #[from(ignore)]
+    GeoSite(&'static str),
+    #[from(ignore)]
+    UpstreamProxy(&'static str),
 }
 
 unsafe impl Send for TrojanError {}
 
-#[allow(dead_code)]
-pub enum CopyResult {
-    RxBlock,
-    TxBlock,
+impl TrojanError {
+    /// Process exit code of this error, so launchers like the GUI can tell failures apart.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            TrojanError::StdIo(_) => 2,
+            TrojanError::Rustls(_)
+            | TrojanError::Webpki(_)
+            | TrojanError::VerifiedBuilder(_)
+            | TrojanError::DnsName(_)
+            | TrojanError::Certificate(_)
+            | TrojanError::Rcgen(_)
+            | TrojanError::Acme(_)
+            | TrojanError::Ocsp(_) => 3,
+            #[cfg(target_os = "windows")]
+            TrojanError::Wintun(_) => 4,
+            TrojanError::LibLoading(_) | TrojanError::Winapi(_) => 4,
+            TrojanError::Resolve | TrojanError::AddrParse(_) | TrojanError::ParseInt(_) => 5,
+            TrojanError::MainAdapterNotFound => 6,
+            TrojanError::NotElevated => 7,
+            TrojanError::DriverUnavailable(_) => 8,
+            TrojanError::ServerUnresolved(_) => 10,
+            _ => 1,
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, TrojanError>;
@@ -1,12 +1,11 @@
 use std::{
     fs::File,
-    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    io::{BufRead, BufReader, Read},
     net::{IpAddr, SocketAddr},
     str::FromStr,
     time::Duration,
 };
 
-use bytes::{Buf, BytesMut};
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 use trust_dns_proto::{
     op::{Message, Query},
@@ -16,88 +15,9 @@ use trust_dns_proto::{
 
 use crate::{
     types,
-    types::{
-        CopyResult,
-        CopyResult::{RxBlock, TxBlock},
-        Result, TrojanError,
-    },
+    types::{Result, TrojanError},
 };
 
-#[allow(dead_code)]
-pub fn copy_stream(
-    from: &mut impl Read,
-    to: &mut impl Write,
-    buffer: &mut BytesMut,
-) -> Result<CopyResult> {
-    loop {
-        if !send_all(to, buffer)? {
-            return Ok(TxBlock);
-        }
-        if !read_once(from, buffer)? {
-            return Ok(RxBlock);
-        }
-    }
-}
-
-#[allow(dead_code)]
-pub fn send_all(writer: &mut impl Write, buffer: &mut BytesMut) -> Result<bool> {
-    if buffer.is_empty() {
-        return Ok(true);
-    }
-    log::debug!("start sending {} bytes data", buffer.len());
-    let mut data = buffer.as_ref();
-    let mut offset = 0;
-    let mut ret = Ok(true);
-    while !data.is_empty() {
-        ret = match writer.write(data) {
-            Ok(0) => Err(TrojanError::TxBreak(None)),
-            Ok(n) => {
-                log::debug!("sent {} bytes", n);
-                offset += n;
-                data = &data[n..];
-                continue;
-            }
-            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(false),
-            Err(err) => Err(TrojanError::TxBreak(Some(err))),
-        };
-        break;
-    }
-    if ret.is_err() {
-        buffer.clear();
-    } else if offset != 0 {
-        buffer.advance(offset);
-    }
-    ret
-}
-
-/// This function reads data from a stream and stores it in a buffer.
-#[allow(dead_code)]
-pub fn read_once(reader: &mut impl Read, buffer: &mut BytesMut) -> Result<bool> {
-    buffer.reserve(1500);
-    let mut nb = buffer.split_off(buffer.len());
-    unsafe {
-        nb.set_len(nb.capacity());
-    }
-    assert!(!nb.as_mut().is_empty());
-    let ret = match reader.read(nb.as_mut()) {
-        Ok(0) => Err(TrojanError::RxBreak(None)),
-        Ok(n) => {
-            log::debug!("read {} bytes", n);
-            unsafe {
-                nb.set_len(n);
-            }
-            Ok(true)
-        }
-        Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(false),
-        Err(err) => Err(TrojanError::RxBreak(Some(err))),
-    };
-    if !matches!(ret, Ok(true)) {
-        nb.clear();
-    }
-    buffer.unsplit(nb);
-    ret
-}
-
 /// This function resolves a domain name to a list of IP addresses.
 pub fn resolve(name: &str, dns_server_addr: &str) -> Result<Vec<IpAddr>> {
     let dns_server_addr: SocketAddr = dns_server_addr.parse()?;
@@ -151,6 +71,18 @@ pub fn get_system_dns() -> types::Result<String> {
     Ok("127.0.0.1".to_string())
 }
 
+/// Compares a secret in constant time, so it can't be guessed byte by byte. An empty
+/// `expected` matches nothing.
+pub fn secret_eq(given: &str, expected: &str) -> bool {
+    !expected.is_empty()
+        && given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
 pub fn is_private(addr: &SocketAddr) -> bool {
     match addr.ip() {
         IpAddr::V4(v4) => v4.is_private(),
@@ -0,0 +1,143 @@
+//! Heartbeats of the event loops. A thread checks them every second, a loop which hasn't beaten
+//! for --watchdog-timeout seconds gets the threads of the process logged, and with
+//! --watchdog-restart the process exits with [`STALLED_EXIT_CODE`] for its supervisor to start
+//! it again, as a stuck thread can't be stopped from within.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{config::OPTIONS, sys};
+
+/// Exit code of a process stopped for a stalled loop.
+pub const STALLED_EXIT_CODE: i32 = 11;
+
+struct Watched {
+    name: &'static str,
+    /// milliseconds since `START` of the last beat, gone with the loop
+    beat: Weak<AtomicU64>,
+    stalled: bool,
+}
+
+static START: OnceLock<Instant> = OnceLock::new();
+static LOOPS: Mutex<Vec<Watched>> = Mutex::new(Vec::new());
+
+fn now_millis() -> u64 {
+    START.get_or_init(Instant::now).elapsed().as_millis() as u64
+}
+
+/// Handle of a watched loop, beat it on every iteration. The loop is no longer watched once
+/// it's dropped.
+pub struct Heartbeat(Arc<AtomicU64>);
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        self.0.store(now_millis(), Ordering::Relaxed);
+    }
+}
+
+/// Watches the loop `name` from now on, nothing is watched with --watchdog-timeout 0.
+pub fn register(name: &'static str) -> Heartbeat {
+    let beat = Arc::new(AtomicU64::new(now_millis()));
+    if OPTIONS.watchdog_timeout > 0 {
+        let mut loops = LOOPS.lock().unwrap();
+        if loops.is_empty() {
+            let timeout = Duration::from_secs(OPTIONS.watchdog_timeout);
+            thread::spawn(move || watch(timeout));
+        }
+        loops.push(Watched {
+            name,
+            beat: Arc::downgrade(&beat),
+            stalled: false,
+        });
+    }
+    Heartbeat(beat)
+}
+
+fn watch(timeout: Duration) {
+    loop {
+        thread::sleep(Duration::from_secs(1));
+        let stalled = check(
+            &mut LOOPS.lock().unwrap(),
+            now_millis(),
+            timeout.as_millis() as u64,
+        );
+        if stalled.is_empty() {
+            continue;
+        }
+        for (name, idle) in stalled {
+            log::error!("{} loop stalled for {} ms", name, idle);
+        }
+        log::error!("threads:\n{}", sys::thread_dump());
+        if OPTIONS.watchdog_restart {
+            log::error!("exit to be restarted");
+            std::process::exit(STALLED_EXIT_CODE);
+        }
+    }
+}
+
+/// Loops newly found without a beat for `timeout` ms at `now` with the ms since their last
+/// beat, each one is reported again only after it has beaten since.
+fn check(loops: &mut Vec<Watched>, now: u64, timeout: u64) -> Vec<(&'static str, u64)> {
+    loops.retain(|watched| watched.beat.strong_count() > 0);
+    let mut stalled = Vec::new();
+    for watched in loops {
+        let Some(beat) = watched.beat.upgrade() else {
+            continue;
+        };
+        let idle = now.saturating_sub(beat.load(Ordering::Relaxed));
+        if idle < timeout {
+            if watched.stalled {
+                log::warn!("{} loop resumed", watched.name);
+                watched.stalled = false;
+            }
+        } else if !watched.stalled {
+            watched.stalled = true;
+            stalled.push((watched.name, idle));
+        }
+    }
+    stalled
+}
+
+mod tests {
+    #[test]
+    fn test_check() {
+        use std::sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        };
+
+        use crate::watchdog::{check, Watched};
+
+        let server = Arc::new(AtomicU64::new(1000));
+        let dns = Arc::new(AtomicU64::new(1000));
+        let gone = Arc::new(AtomicU64::new(0));
+        let mut loops: Vec<_> = [("server", &server), ("dns", &dns), ("gone", &gone)]
+            .into_iter()
+            .map(|(name, beat)| Watched {
+                name,
+                beat: Arc::downgrade(beat),
+                stalled: false,
+            })
+            .collect();
+        drop(gone);
+        assert!(check(&mut loops, 20000, 30000).is_empty());
+        assert_eq!(loops.len(), 2);
+        dns.store(30000, Ordering::Relaxed);
+        assert_eq!(check(&mut loops, 32000, 30000), vec![("server", 31000)]);
+        // reported once
+        assert!(check(&mut loops, 33000, 30000).is_empty());
+        server.store(33000, Ordering::Relaxed);
+        assert!(check(&mut loops, 34000, 30000).is_empty());
+        assert!(!loops[0].stalled);
+        assert_eq!(
+            check(&mut loops, 64000, 30000),
+            vec![("server", 31000), ("dns", 34000)]
+        );
+    }
+}
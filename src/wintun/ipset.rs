@@ -4,12 +4,31 @@ use std::{
     io::{BufRead, BufReader},
     net::Ipv4Addr,
     ops::Not,
+    sync::atomic::{AtomicUsize, Ordering as AtomicOrdering},
 };
 
+use rayon::prelude::*;
 use smoltcp::wire::{IpAddress, IpEndpoint};
 
 use crate::{types::Result, wintun::route::route_add_with_if};
 
+/// Routes added by one worker before reporting progress.
+const ROUTE_BATCH: usize = 256;
+
+/// Networks never routed through the tunnel, the same as [`is_private`]. Broadcast and multicast
+/// carry DHCP and NetBIOS name lookups, which must stay on the physical network.
+pub const PRIVATE_NETS: [&str; 9] = [
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "255.255.255.255/32",
+];
+
 //TODO ipv6
 pub fn is_private(endpoint: IpEndpoint) -> bool {
     if let IpAddress::Ipv4(ip) = endpoint.addr {
@@ -47,7 +66,7 @@ impl Cidr {
         (self.ip, self.ip + !self.mask())
     }
     fn mask(&self) -> u32 {
-        !((1 << (32 - self.prefix)) - 1)
+        u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0)
     }
     fn ip_mask(&self) -> (Ipv4Addr, Ipv4Addr) {
         let ip = Ipv4Addr::from(self.ip);
@@ -93,15 +112,7 @@ impl IPSet {
             }
         });
         if inverse {
-            ipset.add_str("0.0.0.0/8");
-            ipset.add_str("10.0.0.0/8");
-            ipset.add_str("127.0.0.0/8");
-            ipset.add_str("169.254.0.0/16");
-            ipset.add_str("172.16.0.0/12");
-            ipset.add_str("192.168.0.0/16");
-            ipset.add_str("224.0.0.0/4");
-            ipset.add_str("240.0.0.0/4");
-            ipset.add_str("255.255.255.255/32");
+            PRIVATE_NETS.iter().for_each(|net| ipset.add_str(net));
             Ok(!ipset)
         } else {
             ipset.build();
@@ -132,15 +143,97 @@ impl IPSet {
         self.data.extend(cidrs);
     }
 
+    /// Removes the addresses of `ip/prefix`, networks partly covered by it are split.
+    pub fn exclude(&mut self, ip: u32, prefix: u32) {
+        let (low, high) = Cidr::new(ip, prefix).range();
+        for item in std::mem::take(&mut self.data) {
+            let (left, right) = item.range();
+            if right < low || left > high {
+                self.data.push(item);
+                continue;
+            }
+            if left < low {
+                self.add_range(left, low - 1);
+            }
+            if right > high {
+                self.add_range(high + 1, right);
+            }
+        }
+        self.data.sort();
+    }
+
     pub fn build(&mut self) {
         self.data.sort();
     }
 
-    pub fn add_route(&self, index: u32) -> Result<()> {
+    /// Merges overlapping and adjacent networks into the fewest CIDRs covering the same addresses.
+    pub fn aggregate(&mut self) {
+        self.data.sort();
+        let mut ranges: Vec<(u32, u32)> = Vec::new();
         for item in &self.data {
-            route_add_with_if(item.ip, item.mask(), 0, index)?;
+            let (left, right) = item.range();
+            match ranges.last_mut() {
+                Some((_, last)) if left <= last.saturating_add(1) => *last = (*last).max(right),
+                _ => ranges.push((left, right)),
+            }
+        }
+        self.data.clear();
+        for (left, right) in ranges {
+            self.add_range(left, right);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Whether the networks cover every address, like a default route or its two halves.
+    pub fn covers_all(&self) -> bool {
+        let mut ranges: Vec<_> = self.ranges().collect();
+        ranges.sort_unstable();
+        // first address not covered yet
+        let mut next = 0u64;
+        for (left, right) in ranges {
+            if left as u64 > next {
+                return false;
+            }
+            next = next.max(right as u64 + 1);
         }
-        Ok(())
+        next > u32::MAX as u64
+    }
+
+    /// First and last address of each network.
+    pub fn ranges(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.data.iter().map(Cidr::range)
+    }
+
+    pub fn contains(&self, ip: u32) -> bool {
+        self.data.iter().any(|item| {
+            let (left, right) = item.range();
+            left <= ip && ip <= right
+        })
+    }
+
+    /// Adds routes in batches on the rayon pool, `progress` is called with the added and total count
+    /// after each batch.
+    pub fn add_route<F>(&self, index: u32, progress: F) -> Result<()>
+    where
+        F: Fn(usize, usize) + Sync,
+    {
+        let total = self.data.len();
+        let added = AtomicUsize::new(0);
+        self.data.par_chunks(ROUTE_BATCH).try_for_each(|batch| {
+            for item in batch {
+                route_add_with_if(item.ip, item.mask(), 0, index)?;
+            }
+            let count = added.fetch_add(batch.len(), AtomicOrdering::Relaxed) + batch.len();
+            progress(count, total);
+            Ok(())
+        })
     }
 }
 
@@ -167,31 +260,20 @@ impl Not for IPSet {
 }
 
 /// check with https://www.ipaddressguide.com/cidr
-fn range_to_cidr(mut left: u32, mut right: u32) -> Vec<Cidr> {
+fn range_to_cidr(left: u32, right: u32) -> Vec<Cidr> {
     let mut cidrs = Vec::new();
-    if left == right {
-        cidrs.push(Cidr::new(left, 32));
-        return cidrs;
-    }
-
-    loop {
-        let shift = right.trailing_ones();
-        let prefix = 32 - shift;
-        let ip = right & !((1 << shift) - 1);
-        if left <= ip {
-            cidrs.push(Cidr::new(ip, prefix));
-            right = ip - 1;
-        } else {
-            break;
-        }
-    }
+    // u64 so that ranges ending at 255.255.255.255 do not overflow
+    let mut left = left as u64;
+    let right = right as u64;
     while left <= right {
-        let shift = left.trailing_zeros();
-        let prefix = 32 - shift;
-        cidrs.push(Cidr::new(left, prefix));
+        // largest block aligned at left which still fits in the range
+        let mut shift = left.trailing_zeros().min(32);
+        while left + (1 << shift) - 1 > right {
+            shift -= 1;
+        }
+        cidrs.push(Cidr::new(left as u32, 32 - shift));
         left += 1 << shift;
     }
-    cidrs.sort();
     cidrs
 }
 
@@ -303,4 +385,55 @@ mod tests {
     fn test_iprange() {
         my_range_to_cidr(190365721, 190365947);
     }
+
+    #[test]
+    fn test_aggregate() {
+        let mut ipset = IPSet::new();
+        for line in [
+            "10.0.0.0/24",
+            "10.0.1.0/24",
+            "10.0.2.0/23",
+            "10.0.1.128/25",
+            "192.168.1.0/24",
+            "240.0.0.0/4",
+        ] {
+            ipset.add_str(line);
+        }
+        ipset.aggregate();
+        let cidrs: Vec<_> = ipset
+            .data
+            .iter()
+            .map(|item| format!("{}/{}", Ipv4Addr::from(item.ip), item.prefix))
+            .collect();
+        assert_eq!(cidrs, ["10.0.0.0/22", "192.168.1.0/24", "240.0.0.0/4"]);
+    }
+
+    #[test]
+    fn test_exclude() {
+        let mut ipset = IPSet::new();
+        ipset.add_str("0.0.0.0/0");
+        ipset.exclude(u32::from(Ipv4Addr::new(192, 168, 1, 0)), 24);
+        ipset.exclude(u32::from(Ipv4Addr::new(8, 8, 8, 8)), 32);
+        assert!(!ipset.contains(u32::from(Ipv4Addr::new(192, 168, 1, 10))));
+        assert!(!ipset.contains(u32::from(Ipv4Addr::new(8, 8, 8, 8))));
+        assert!(ipset.contains(u32::from(Ipv4Addr::new(192, 168, 2, 1))));
+        assert!(ipset.contains(u32::from(Ipv4Addr::new(8, 8, 8, 9))));
+        assert!(ipset.contains(u32::MAX));
+        // the split networks are not merged back over the excluded ones
+        ipset.aggregate();
+        assert!(!ipset.contains(u32::from(Ipv4Addr::new(192, 168, 1, 255))));
+        assert!(ipset.contains(u32::from(Ipv4Addr::new(192, 168, 0, 255))));
+    }
+
+    #[test]
+    fn test_covers_all() {
+        let mut ipset = IPSet::new();
+        ipset.add_str("128.0.0.0/1");
+        assert!(!ipset.covers_all());
+        ipset.add_str("0.0.0.0/1");
+        assert!(ipset.covers_all());
+        ipset.exclude(u32::from(Ipv4Addr::new(8, 8, 8, 8)), 32);
+        assert!(!ipset.covers_all());
+        assert!(!IPSet::new().covers_all());
+    }
 }
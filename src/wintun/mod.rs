@@ -1,7 +1,7 @@
 use std::{
     convert::TryInto,
     fs::OpenOptions,
-    io::Write,
+    io::{ErrorKind, Write},
     net::{Ipv4Addr, SocketAddr},
     sync::Arc,
     thread,
@@ -9,27 +9,32 @@ use std::{
 };
 
 use mio::{Events, Poll, Token, Waker};
-use rustls::{ClientConfig, RootCertStore};
+use rustls::RootCertStore;
 use smoltcp::{
     iface::{Config, Interface, SocketSet},
     socket::Socket,
     time::{Duration, Instant},
     wire::{HardwareAddress, IpAddress, IpCidr, Ipv4Address},
 };
-use wintun::Adapter;
 
+pub use ipset::{IPSet, PRIVATE_NETS};
 pub use route::route_add_with_if;
 
 use crate::{
-    dns::{get_adapter_ip, get_main_adapter_gwif},
+    dns::{get_adapter_ip, get_main_adapter_subnets},
+    events::route_progress,
+    pinning::pin_certificates,
     proxy::IdlePool,
     resolver::DnsResolver,
-    types::{Result, TrojanError},
-    wintun::{ipset::IPSet, tcp::TcpServer, tun::WintunDevice, udp::UdpServer},
+    tls_client::client_config,
+    types::Result,
+    watchdog,
+    wintun::{tcp::TcpServer, tun::WintunDevice, udp::UdpServer},
     OPTIONS,
 };
 
 mod ipset;
+pub mod preflight;
 mod route;
 mod tcp;
 mod tun;
@@ -43,18 +48,68 @@ const MIN_INDEX: usize = 2;
 /// Maximum index
 const MAX_INDEX: usize = usize::MAX / CHANNEL_CNT;
 /// Channel count for index
-const CHANNEL_CNT: usize = 3;
+const CHANNEL_CNT: usize = 4;
 /// Channel index  for `IdlePool`
 const CHANNEL_IDLE: usize = 0;
 /// Channel index for client `UdpConnection`
 const CHANNEL_UDP: usize = 1;
 /// Channel index for remote tcp connection
 const CHANNEL_TCP: usize = 2;
+/// Channel index for the connection of a mux session
+const CHANNEL_MUX: usize = 3;
 
 pub fn apply_ipset(file: &str, index: u32, inverse: bool) -> Result<()> {
-    let ipset = IPSet::with_file(file, inverse)?;
-    ipset.add_route(index)?;
-    log::warn!("route add completed");
+    let mut ipset = IPSet::with_file(file, inverse)?;
+    let args = OPTIONS.wintun_args();
+    exclude_local(
+        &mut ipset,
+        inverse,
+        args.no_local_exempt,
+        &args.route_exclude,
+    )?;
+    if !OPTIONS.wintun_args().no_route_aggregate {
+        let count = ipset.len();
+        ipset.aggregate();
+        log::warn!("ipset aggregated from {} to {} routes", count, ipset.len());
+    }
+    let start = std::time::Instant::now();
+    ipset.add_route(index, |added, total| {
+        log::info!("route add {}/{}", added, total);
+        route_progress(added, total);
+    })?;
+    log::warn!("route add completed in {:?}", start.elapsed());
+    Ok(())
+}
+
+/// Takes the networks which must stay on the physical adapter out of the ipset: those of
+/// `route_exclude`, and the local ones unless `no_local_exempt` if the ipset is `inverse` or
+/// covers every address. A list of networks is routed as given, even if it names local ones.
+pub fn exclude_local(
+    ipset: &mut IPSet,
+    inverse: bool,
+    no_local_exempt: bool,
+    route_exclude: &[String],
+) -> Result<()> {
+    let mut excludes = Vec::new();
+    if !no_local_exempt && (inverse || ipset.covers_all()) {
+        excludes.extend(PRIVATE_NETS.iter().map(|net| net.to_string()));
+        // the LAN may use public addresses as well
+        for (ip, prefix) in get_main_adapter_subnets() {
+            log::info!("local subnet {}/{} exempted", ip, prefix);
+            excludes.push(format!("{}/{}", ip, prefix));
+        }
+    }
+    excludes.extend(route_exclude.iter().cloned());
+    for net in excludes {
+        let (ip, prefix) = net.split_once('/').unwrap_or((net.as_str(), "32"));
+        let ip: Ipv4Addr = ip.parse()?;
+        let prefix: u32 = prefix.parse()?;
+        if prefix > 32 {
+            let err = format!("invalid route exclude {}", net);
+            return Err(std::io::Error::new(ErrorKind::InvalidInput, err).into());
+        }
+        ipset.exclude(ip.into(), prefix);
+    }
     Ok(())
 }
 
@@ -62,9 +117,8 @@ fn prepare_idle_pool(poll: &Poll, resolver: &DnsResolver) -> Result<IdlePool> {
     let hostname = OPTIONS.wintun_args().hostname.as_str().try_into()?;
     let mut root_store = RootCertStore::empty();
     root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
-    let config = ClientConfig::builder()
-        .with_root_certificates(root_store)
-        .with_no_client_auth();
+    let mut config = client_config(root_store);
+    pin_certificates(&mut config);
     let config = Arc::new(config);
     let mut pool = IdlePool::new(
         config,
@@ -98,24 +152,14 @@ fn prepare_device(device: &mut WintunDevice) -> Interface {
 }
 
 pub fn run() -> Result<()> {
-    log::info!("dll:{}", OPTIONS.wintun_args().wintun);
-    let wintun = unsafe { wintun::load_from_path(&OPTIONS.wintun_args().wintun)? };
-    let adapter = Adapter::create(&wintun, "trojan", OPTIONS.wintun_args().name.as_str(), None)?;
+    let wintun = preflight::check()?;
+    let adapter = preflight::create_adapter(&wintun)?;
     let session = Arc::new(adapter.start_session(wintun::MAX_RING_CAPACITY)?);
-    if let Some((main_gw, main_index)) = get_main_adapter_gwif() {
-        log::warn!(
-            "main adapter gateway is {}, main adapter index is :{}",
-            main_gw,
-            main_index
-        );
-        let gw: Ipv4Addr = main_gw.parse()?;
-        if let Some(SocketAddr::V4(v4)) = &OPTIONS.back_addr {
-            let index: u32 = (*v4.ip()).into();
-            route_add_with_if(index, !0, gw.into(), main_index)?;
-        }
-    } else {
-        log::error!("main adapter gateway not found");
-        return Err(TrojanError::MainAdapterNotFound);
+    let (gw, main_index) = preflight::main_gateway()?;
+    let mut gateway = (gw, main_index);
+    if let Some(SocketAddr::V4(v4)) = &OPTIONS.back_addr {
+        let index: u32 = (*v4.ip()).into();
+        route_add_with_if(index, !0, gw.into(), main_index)?;
     }
     let index = adapter.get_adapter_index()?;
     if let Some(file) = &OPTIONS.wintun_args().route_ipset {
@@ -141,8 +185,8 @@ pub fn run() -> Result<()> {
     while get_adapter_ip(OPTIONS.wintun_args().name.as_str()).is_none() {
         thread::sleep(std::time::Duration::new(1, 0));
     }
-    let gateway = get_adapter_ip(OPTIONS.wintun_args().name.as_str()).unwrap();
-    log::warn!("wintun is ready at:{}", gateway);
+    let adapter_ip = get_adapter_ip(OPTIONS.wintun_args().name.as_str()).unwrap();
+    log::warn!("wintun is ready at:{}", adapter_ip);
 
     let mut events = Events::with_capacity(1024);
     let timeout = Some(Duration::from_millis(1));
@@ -150,8 +194,10 @@ pub fn run() -> Result<()> {
     let mut last_speed_time = std::time::Instant::now();
     let check_duration = std::time::Duration::new(60, 0);
     let mut now = Instant::now();
+    let heartbeat = watchdog::register("wintun");
 
     loop {
+        heartbeat.beat();
         let sockets = unsafe { Arc::get_mut_unchecked(&mut sockets) };
         if interface.poll(now, &mut device, sockets) {
             udp_server.do_local(&mut pool, &poll, &resolver, &mut device);
@@ -177,6 +223,9 @@ pub fn run() -> Result<()> {
                 i if i % CHANNEL_CNT == CHANNEL_UDP => {
                     udp_server.do_remote(event, &poll, &mut device);
                 }
+                i if i % CHANNEL_CNT == CHANNEL_MUX => {
+                    tcp_server.mux_ready(event, &poll, &mut device);
+                }
                 _ => {
                     tcp_server.do_remote(event, &poll, &mut device);
                 }
@@ -200,6 +249,10 @@ pub fn run() -> Result<()> {
                 .open(OPTIONS.wintun_args().status_file.as_str())?;
             write!(&mut file, "{:.4} {:.4}", rx_speed, tx_speed)?;
             last_speed_time = std::time::Instant::now();
+            // idle connections went through the old network
+            if preflight::follow_gateway(&mut gateway) {
+                pool.flush(&poll, &resolver);
+            }
         }
 
         let now = std::time::Instant::now();
@@ -232,7 +285,7 @@ pub fn run() -> Result<()> {
             );
             log::info!("total tcp sockets count:{}", tcp_count);
             log::info!("total udp sockets count:{}", udp_count);
-            pool.check_timeout(&poll);
+            pool.check_timeout(&poll, &resolver);
             last_check_time = now;
         }
     }
@@ -0,0 +1,125 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use wintun::{Adapter, Wintun};
+
+use crate::{
+    config::OPTIONS,
+    dns::{get_adapter_gwif, get_gateway_tunnel_adapters, get_main_adapter_gwif},
+    types::{Result, TrojanError},
+    wintun::route_add_with_if,
+};
+
+/// Checks the environment before touching the network configuration, so a
+/// failure comes with an error the GUI can turn into advice.
+pub fn check() -> Result<Wintun> {
+    if !wintool::process::is_elevated() {
+        log::error!("administrator rights are required to create the adapter");
+        return Err(TrojanError::NotElevated);
+    }
+    let args = OPTIONS.wintun_args();
+    log::info!("dll:{}", args.wintun);
+    let wintun = unsafe { wintun::load_from_path(&args.wintun) }.map_err(|err| {
+        log::error!("load {} failed:{}", args.wintun, err);
+        TrojanError::DriverUnavailable(err.to_string())
+    })?;
+    // a split tunnel VPN may coexist with ours, only warn about it
+    let conflicts = get_gateway_tunnel_adapters(args.name.as_str());
+    if !conflicts.is_empty() {
+        log::warn!(
+            "other tunnel adapters hold a gateway and may take the traffic:{}",
+            conflicts.join(", ")
+        );
+    }
+    if OPTIONS.back_addr.is_none() {
+        log::error!("resolve host {} failed", args.hostname);
+        return Err(TrojanError::ServerUnresolved(args.hostname.clone()));
+    }
+    Ok(wintun)
+}
+
+/// Creates the adapter, reporting a failure as the driver being unusable.
+pub fn create_adapter(wintun: &Wintun) -> Result<Arc<Adapter>> {
+    let name = OPTIONS.wintun_args().name.as_str();
+    if OPTIONS.dry_run() {
+        log::warn!(
+            "[dry-run] adapter {} is created anyway, its routes go with it",
+            name
+        );
+    }
+    Adapter::create(wintun, "trojan", name, None).map_err(|err| {
+        log::error!("create adapter {} failed:{}", name, err);
+        TrojanError::DriverUnavailable(err.to_string())
+    })
+}
+
+/// Gateway and index of the physical interface, waiting a while for it to show up since
+/// tethered and freshly connected interfaces may get their gateway late.
+pub fn main_gateway() -> Result<(Ipv4Addr, u32)> {
+    let args = OPTIONS.wintun_args();
+    let deadline = Instant::now() + Duration::from_secs(args.gateway_wait);
+    let mut waiting = false;
+    loop {
+        if let Some((gw, index)) = current_gateway() {
+            log::warn!(
+                "main adapter gateway is {}, main adapter index is :{}",
+                gw,
+                index
+            );
+            return Ok((gw.parse()?, index));
+        }
+        if Instant::now() >= deadline {
+            if let Some(name) = &args.bind_iface {
+                log::error!("gateway of adapter {} not found", name);
+            } else {
+                log::error!("main adapter gateway not found, try --bind-iface");
+            }
+            return Err(TrojanError::MainAdapterNotFound);
+        }
+        if !waiting {
+            log::warn!("main adapter gateway not found, waiting for it");
+            waiting = true;
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// Gateway and index of the physical interface as it is now.
+pub fn current_gateway() -> Option<(String, u32)> {
+    match &OPTIONS.wintun_args().bind_iface {
+        Some(name) => get_adapter_gwif(name),
+        None => get_main_adapter_gwif(),
+    }
+}
+
+/// Follows the physical interface to a new gateway, e.g. after switching networks, and routes
+/// the server through it. Returns whether the gateway changed.
+pub fn follow_gateway(gateway: &mut (Ipv4Addr, u32)) -> bool {
+    let Some((gw, index)) = current_gateway() else {
+        return false;
+    };
+    let Ok(gw) = gw.parse::<Ipv4Addr>() else {
+        return false;
+    };
+    if (gw, index) == *gateway {
+        return false;
+    }
+    log::warn!(
+        "main adapter gateway changed from {}:{} to {}:{}",
+        gateway.0,
+        gateway.1,
+        gw,
+        index
+    );
+    *gateway = (gw, index);
+    if let Some(SocketAddr::V4(v4)) = &OPTIONS.back_addr {
+        if let Err(err) = route_add_with_if((*v4.ip()).into(), !0, gw.into(), index) {
+            log::error!("route server through the new gateway failed:{:?}", err);
+        }
+    }
+    true
+}
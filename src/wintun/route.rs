@@ -12,9 +12,22 @@ use winapi::{
     um::iphlpapi,
 };
 
-use crate::types::{Result, TrojanError};
+use crate::{
+    config::OPTIONS,
+    types::{Result, TrojanError},
+};
 
 pub fn route_add_with_if(dst: u32, mask: u32, gw: u32, if_index: u32) -> Result<()> {
+    if OPTIONS.dry_run() {
+        log::warn!(
+            "[dry-run] route add {} mask {} {} metric 1 if {}",
+            Ipv4Addr::from(dst),
+            Ipv4Addr::from(mask),
+            Ipv4Addr::from(gw),
+            if_index
+        );
+        return Ok(());
+    }
     log::trace!(
         "route add {} mask {} {} metric 1 if {}",
         Ipv4Addr::from(dst),
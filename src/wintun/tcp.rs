@@ -7,19 +7,22 @@ use std::{
 
 use bytes::BytesMut;
 use mio::{event::Event, Poll, Token};
+use relay::{copy_stream, Break, CopyResult};
 use smoltcp::{
     iface::SocketHandle,
     socket::tcp::{Socket, State},
 };
 
 use crate::{
+    config::OPTIONS,
     idle_pool::IdlePool,
+    mux_conn::{MuxSessions, ServerConn},
     proto::{TrojanRequest, CONNECT},
     resolver::DnsResolver,
-    tls_conn::TlsConn,
-    types::{CopyResult, TrojanError},
-    utils::copy_stream,
-    wintun::{tun::WintunDevice, waker::WakerMode, CHANNEL_CNT, CHANNEL_TCP, MAX_INDEX, MIN_INDEX},
+    wintun::{
+        tun::WintunDevice, waker::WakerMode, CHANNEL_CNT, CHANNEL_MUX, CHANNEL_TCP, MAX_INDEX,
+        MIN_INDEX,
+    },
 };
 
 pub struct TcpStreamRef<'a, 'b> {
@@ -70,7 +73,7 @@ impl<'a, 'b> std::io::Write for TcpStreamRef<'a, 'b> {
 pub struct Connection {
     token: Token,
     local: SocketHandle,
-    remote: TlsConn,
+    remote: ServerConn,
     lbuffer: BytesMut,
     rbuffer: BytesMut,
     lclosed: bool,
@@ -80,7 +83,7 @@ pub struct Connection {
 }
 
 impl Connection {
-    pub fn new(token: Token, local: SocketHandle, remote: TlsConn) -> Self {
+    pub fn new(token: Token, local: SocketHandle, remote: ServerConn) -> Self {
         Self {
             token,
             local,
@@ -171,24 +174,29 @@ impl Connection {
         match copy_stream(&mut local, &mut self.remote, &mut self.rbuffer) {
             Ok(CopyResult::TxBlock) => log::info!("remote sending blocked"),
             Ok(CopyResult::RxBlock) => log::info!("local reading blocked"),
-            Err(TrojanError::RxBreak(err)) => {
+            Err(Break::Rx(err)) => {
                 log::info!("local break with error:{:?}", err);
                 self.close_stream(true, device, poll)
             }
-            Err(TrojanError::TxBreak(err)) => {
+            Err(Break::Tx(err)) => {
                 log::info!("remote break with err:{:?}", err);
                 self.close_stream(false, device, poll)
             }
-            _ => unreachable!(),
         }
         if !self.rclosed {
             self.flush_remote(device, poll);
         }
     }
 
-    pub fn do_remote(&mut self, device: &mut WintunDevice, poll: &Poll, event: &Event) {
+    pub fn do_remote(
+        &mut self,
+        device: &mut WintunDevice,
+        poll: &Poll,
+        readable: bool,
+        writable: bool,
+    ) {
         self.last_active = Instant::now();
-        if event.is_writable() {
+        if writable {
             log::info!("remote writable");
             if !self.established {
                 if self.lclosed {
@@ -202,13 +210,21 @@ impl Connection {
                     {
                         TrojanRequest::generate_endpoint(&mut request, CONNECT, &endpoint);
                         log::info!("send trojan request {} bytes", request.len());
-                        if self.remote.write(request.as_ref()).is_ok() {
-                            self.established = true;
-                            log::info!("connection is ready now");
-                        } else {
-                            log::warn!("send trojan request failed");
-                            self.close(device, poll);
-                            return;
+                        match self.remote.write(request.as_ref()) {
+                            Ok(_) => {
+                                self.established = true;
+                                log::info!("connection is ready now");
+                            }
+                            // a mux session still connecting
+                            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                                log::info!("server connection is not ready");
+                                return;
+                            }
+                            Err(err) => {
+                                log::warn!("send trojan request failed:{}", err);
+                                self.close(device, poll);
+                                return;
+                            }
                         }
                     } else {
                         self.close(device, poll);
@@ -219,7 +235,7 @@ impl Connection {
             self.local_to_remote(device, poll);
         }
 
-        if event.is_readable() {
+        if readable {
             log::info!("remote readable");
             self.remote_to_local(device, poll);
             self.flush_remote(device, poll);
@@ -238,15 +254,14 @@ impl Connection {
         match ret {
             Ok(CopyResult::RxBlock) => log::info!("remote reading blocked"),
             Ok(CopyResult::TxBlock) => log::info!("local sending blocked"),
-            Err(TrojanError::RxBreak(err)) => {
+            Err(Break::Rx(err)) => {
                 log::info!("remote connection break with:{:?}", err);
                 self.close_stream(false, device, poll);
             }
-            Err(TrojanError::TxBreak(err)) => {
+            Err(Break::Tx(err)) => {
                 log::info!("local connection break with:{:?}", err);
                 self.close_stream(true, device, poll)
             }
-            _ => unreachable!(),
         }
         //smoltcp sending is asynchronous, so send queue should be checked.
         if self.rclosed && !self.lclosed && self.lbuffer.is_empty() && send_size == 0 {
@@ -304,6 +319,7 @@ pub struct TcpServer {
     token2conns: HashMap<Token, Arc<Connection>>,
     handle2conns: HashMap<SocketHandle, Arc<Connection>>,
     removed: HashSet<SocketHandle>,
+    mux: Option<MuxSessions>,
 }
 
 impl TcpServer {
@@ -312,6 +328,26 @@ impl TcpServer {
             token2conns: Default::default(),
             handle2conns: Default::default(),
             removed: HashSet::new(),
+            mux: (OPTIONS.mux > 0)
+                .then(|| MuxSessions::new(CHANNEL_CNT, CHANNEL_MUX, MIN_INDEX, MAX_INDEX)),
+        }
+    }
+
+    /// A connection of the pool for the connection of `token`, or a stream of a mux session
+    /// keyed by the token.
+    fn connect(
+        &mut self,
+        token: Token,
+        pool: &mut IdlePool,
+        poll: &Poll,
+        resolver: &DnsResolver,
+    ) -> Option<ServerConn> {
+        match &mut self.mux {
+            Some(mux) => mux.open(token.0, poll, pool, resolver).map(ServerConn::Mux),
+            None => pool.get(poll, resolver).map(|mut remote| {
+                remote.set_token(token, poll);
+                ServerConn::Tls(Box::new(remote))
+            }),
         }
     }
 
@@ -333,17 +369,27 @@ impl TcpServer {
                 self.removed.insert(handle);
                 continue;
             }
-            let conn = self.handle2conns.entry(handle).or_insert_with(|| {
+            if !self.handle2conns.contains_key(&handle) {
                 log::info!("found new tcp connection");
                 let token = next_token();
-                let mut remote = pool.get(poll, resolver).unwrap();
-                remote.set_token(token, poll);
-                let conn = Connection::new(token, handle, remote);
-                Arc::new(conn)
-            });
-            self.token2conns
-                .entry(conn.token)
-                .or_insert_with(|| conn.clone());
+                let Some(remote) = self.connect(token, pool, poll, resolver) else {
+                    log::error!("alloc new connection failed");
+                    device.get_tcp_socket_mut(handle, WakerMode::None).abort();
+                    self.removed.insert(handle);
+                    continue;
+                };
+                let is_mux = matches!(remote, ServerConn::Mux(_));
+                let mut conn = Arc::new(Connection::new(token, handle, remote));
+                // no event of its own tells a stream of an established session it's writable
+                if is_mux {
+                    unsafe {
+                        Arc::get_mut_unchecked(&mut conn).do_remote(device, poll, false, true)
+                    };
+                }
+                self.handle2conns.insert(handle, conn.clone());
+                self.token2conns.insert(token, conn);
+            }
+            let conn = self.handle2conns.get_mut(&handle).unwrap();
             unsafe { Arc::get_mut_unchecked(conn).do_local(device, poll, event) };
             if conn.is_closed(device) {
                 self.removed.insert(conn.local);
@@ -354,7 +400,14 @@ impl TcpServer {
 
     pub(crate) fn do_remote(&mut self, event: &Event, poll: &Poll, device: &mut WintunDevice) {
         if let Some(conn) = self.token2conns.get_mut(&event.token()) {
-            unsafe { Arc::get_mut_unchecked(conn).do_remote(device, poll, event) };
+            unsafe {
+                Arc::get_mut_unchecked(conn).do_remote(
+                    device,
+                    poll,
+                    event.is_readable(),
+                    event.is_writable(),
+                )
+            };
             if conn.is_closed(device) {
                 self.removed.insert(conn.local);
             }
@@ -363,6 +416,21 @@ impl TcpServer {
         }
     }
 
+    /// Handles an event of a mux session, its streams with news are woken up.
+    pub(crate) fn mux_ready(&mut self, event: &Event, poll: &Poll, device: &mut WintunDevice) {
+        let Some(mux) = self.mux.as_mut() else {
+            return;
+        };
+        for (token, readable, writable) in mux.ready(event, poll) {
+            if let Some(conn) = self.token2conns.get_mut(&Token(token)) {
+                unsafe { Arc::get_mut_unchecked(conn).do_remote(device, poll, readable, writable) };
+                if conn.is_closed(device) {
+                    self.removed.insert(conn.local);
+                }
+            }
+        }
+    }
+
     pub fn remove_closed(&mut self, device: &mut WintunDevice) {
         for handle in &self.removed {
             if let Some(conn) = self.handle2conns.get(handle) {
@@ -403,5 +471,8 @@ impl TcpServer {
         }
 
         self.remove_closed(device);
+        if let Some(mux) = self.mux.as_mut() {
+            mux.remove_closed(poll);
+        }
     }
 }
@@ -7,6 +7,7 @@ use std::{
 
 use bytes::BytesMut;
 use mio::{event::Event, Poll, Token};
+use relay::{read_once, send_all};
 use smoltcp::{
     iface::SocketHandle,
     socket::udp::{RecvError, SendError, Socket},
@@ -18,7 +19,6 @@ use crate::{
     proto::{TrojanRequest, UdpAssociate, UdpParseResultEndpoint, UDP_ASSOCIATE},
     resolver::DnsResolver,
     tls_conn::TlsConn,
-    utils::{read_once, send_all},
     wintun::{tun::WintunDevice, waker::WakerMode, CHANNEL_CNT, CHANNEL_UDP, MAX_INDEX, MIN_INDEX},
     OPTIONS,
 };
@@ -0,0 +1,108 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection};
+use serde::Serialize;
+
+/// One run of the tunnel, times are unix seconds, bytes counted from the tunnel speed.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Session {
+    pub id: i64,
+    pub start: i64,
+    pub stop: Option<i64>,
+    pub server: String,
+    pub upload: u64,
+    pub download: u64,
+    pub reason: String,
+}
+
+/// Local log of sessions stored in SQLite.
+pub struct History {
+    conn: Connection,
+}
+
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs() as i64)
+}
+
+impl History {
+    pub fn open(path: &str) -> rusqlite::Result<History> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS sessions (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                start INTEGER NOT NULL,
+                stop INTEGER,
+                server TEXT NOT NULL,
+                upload INTEGER NOT NULL DEFAULT 0,
+                download INTEGER NOT NULL DEFAULT 0,
+                reason TEXT NOT NULL DEFAULT ''
+            );
+            CREATE INDEX IF NOT EXISTS sessions_start ON sessions (start);",
+        )?;
+        Ok(History { conn })
+    }
+
+    /// Records a new session, returns its id.
+    pub fn begin(&self, server: &str) -> rusqlite::Result<i64> {
+        self.conn.execute(
+            "INSERT INTO sessions (start, server) VALUES (?1, ?2)",
+            params![now(), server],
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    /// Saves the traffic so far, so a crash of the client loses little.
+    pub fn update(&self, id: i64, upload: u64, download: u64) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "UPDATE sessions SET upload = ?2, download = ?3 WHERE id = ?1",
+            params![id, upload, download],
+        )?;
+        Ok(())
+    }
+
+    pub fn finish(
+        &self,
+        id: i64,
+        upload: u64,
+        download: u64,
+        reason: &str,
+    ) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "UPDATE sessions SET stop = ?2, upload = ?3, download = ?4, reason = ?5 WHERE id = ?1",
+            params![id, now(), upload, download, reason],
+        )?;
+        Ok(())
+    }
+
+    /// Latest sessions started since `since`, newest first.
+    pub fn query(&self, since: i64, limit: u32) -> rusqlite::Result<Vec<Session>> {
+        let mut statement = self.conn.prepare(
+            "SELECT id, start, stop, server, upload, download, reason FROM sessions
+            WHERE start >= ?1 ORDER BY start DESC, id DESC LIMIT ?2",
+        )?;
+        let sessions = statement.query_map(params![since, limit], |row| {
+            Ok(Session {
+                id: row.get(0)?,
+                start: row.get(1)?,
+                stop: row.get(2)?,
+                server: row.get(3)?,
+                upload: row.get(4)?,
+                download: row.get(5)?,
+                reason: row.get(6)?,
+            })
+        })?;
+        sessions.collect()
+    }
+
+    /// Total (upload, download) bytes of sessions started since `since`.
+    pub fn usage(&self, since: i64) -> rusqlite::Result<(u64, u64)> {
+        self.conn.query_row(
+            "SELECT COALESCE(SUM(upload), 0), COALESCE(SUM(download), 0) FROM sessions
+            WHERE start >= ?1",
+            params![since],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+    }
+}
@@ -0,0 +1,114 @@
+use tauri::Icon;
+
+/// color of the badge on the degraded icon
+const BADGE_COLOR: [u8; 3] = [0xff, 0xa0, 0x00];
+
+/// What the tray icon shows.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TrayState {
+    Stopped,
+    Running,
+    /// running, but connections to the server are failing
+    Degraded,
+}
+
+/// Tray icons generated for the current taskbar theme.
+pub struct TrayIcons {
+    light_taskbar: bool,
+    stopped: Icon,
+    running: Icon,
+    degraded: Icon,
+}
+
+struct Image {
+    rgba: Vec<u8>,
+    width: u32,
+    height: u32,
+}
+
+impl Image {
+    fn decode(data: &[u8]) -> Image {
+        let mut reader = png::Decoder::new(data)
+            .read_info()
+            .expect("invalid bundled icon");
+        let mut rgba = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut rgba).expect("invalid bundled icon");
+        assert_eq!(info.color_type, png::ColorType::Rgba);
+        rgba.truncate(info.buffer_size());
+        Image {
+            rgba,
+            width: info.width,
+            height: info.height,
+        }
+    }
+
+    /// Lightens the image, the gray icon is barely visible on a dark taskbar otherwise.
+    fn lighten(&mut self) {
+        for pixel in self.rgba.chunks_exact_mut(4) {
+            for channel in &mut pixel[..3] {
+                *channel += (255 - *channel) / 2;
+            }
+        }
+    }
+
+    /// Draws a round badge in the bottom right corner, outlined with `outline`.
+    fn badge(&mut self, outline: [u8; 3]) {
+        let radius = self.width as f32 / 4.0;
+        let center_x = self.width as f32 - radius - 1.0;
+        let center_y = self.height as f32 - radius - 1.0;
+        let width = self.width as usize;
+        for (index, pixel) in self.rgba.chunks_exact_mut(4).enumerate() {
+            let x = (index % width) as f32 + 0.5 - center_x;
+            let y = (index / width) as f32 + 0.5 - center_y;
+            let distance = (x * x + y * y).sqrt();
+            let color = if distance <= radius - 1.5 {
+                BADGE_COLOR
+            } else if distance <= radius {
+                outline
+            } else {
+                continue;
+            };
+            pixel.copy_from_slice(&[color[0], color[1], color[2], 0xff]);
+        }
+    }
+
+    fn into_icon(self) -> Icon {
+        Icon::Rgba {
+            rgba: self.rgba,
+            width: self.width,
+            height: self.height,
+        }
+    }
+}
+
+impl TrayIcons {
+    pub fn new(light_taskbar: bool) -> TrayIcons {
+        let mut stopped = Image::decode(include_bytes!("../icons/icon_gray.png"));
+        let running = Image::decode(include_bytes!("../icons/icon.png"));
+        let mut degraded = Image::decode(include_bytes!("../icons/icon.png"));
+        if light_taskbar {
+            degraded.badge([0, 0, 0]);
+        } else {
+            stopped.lighten();
+            degraded.badge([0xff, 0xff, 0xff]);
+        }
+        TrayIcons {
+            light_taskbar,
+            stopped: stopped.into_icon(),
+            running: running.into_icon(),
+            degraded: degraded.into_icon(),
+        }
+    }
+
+    pub fn light_taskbar(&self) -> bool {
+        self.light_taskbar
+    }
+
+    pub fn get(&self, state: TrayState) -> Icon {
+        match state {
+            TrayState::Stopped => self.stopped.clone(),
+            TrayState::Running => self.running.clone(),
+            TrayState::Degraded => self.degraded.clone(),
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+/// primary language id of chinese, see `wintool::locale::ui_language`
+const LANG_CHINESE: u16 = 0x04;
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    /// follow the language of the system
+    #[default]
+    Auto,
+    En,
+    Zh,
+}
+
+/// Texts shown by the rust side of the client.
+#[derive(Clone, Copy)]
+pub enum Text {
+    Quit,
+    DevTools,
+    Title,
+    Upload,
+    Download,
+    InvalidListen,
+    InvalidDns,
+    DnsUnreachable,
+    DnsBadResponse,
+    Connected,
+    Disconnected,
+    Reconnecting,
+    FatalError,
+    NotRunning,
+    SpeedTestFailed,
+    BudgetUsed,
+    NotElevated,
+    DriverUnavailable,
+    ServerUnresolved,
+    InvalidForward,
+    HighUsage,
+}
+
+static CURRENT: AtomicU8 = AtomicU8::new(Language::Zh as u8);
+
+pub fn set_language(language: Language) {
+    let language = match language {
+        Language::Auto if wintool::locale::ui_language() == LANG_CHINESE => Language::Zh,
+        Language::Auto => Language::En,
+        language => language,
+    };
+    CURRENT.store(language as u8, Ordering::Relaxed);
+}
+
+pub fn tr(text: Text) -> &'static str {
+    if CURRENT.load(Ordering::Relaxed) == Language::Zh as u8 {
+        match text {
+            Text::Quit => "退出",
+            Text::DevTools => "开发工具",
+            Text::Title => "Trojan客户端",
+            Text::Upload => "上行",
+            Text::Download => "下行",
+            Text::InvalidListen => "非法的监听地址",
+            Text::InvalidDns => "非法的DNS地址",
+            Text::DnsUnreachable => "DNS服务器不可达",
+            Text::DnsBadResponse => "DNS服务器响应异常",
+            Text::Connected => "已连接",
+            Text::Disconnected => "已断开",
+            Text::Reconnecting => "服务器连接失败，正在重连",
+            Text::FatalError => "异常退出，错误码",
+            Text::NotRunning => "代理未运行",
+            Text::SpeedTestFailed => "测速失败",
+            Text::BudgetUsed => "本月流量已用",
+            Text::NotElevated => "请以管理员身份运行",
+            Text::DriverUnavailable => "无法加载wintun驱动，请检查wintun.dll",
+            Text::ServerUnresolved => "无法解析服务器地址，请检查网络或DNS设置",
+            Text::InvalidForward => "非法的转发规则",
+            Text::HighUsage => "子进程资源占用异常",
+        }
+    } else {
+        match text {
+            Text::Quit => "Quit",
+            Text::DevTools => "Developer tools",
+            Text::Title => "Trojan Client",
+            Text::Upload => "Upload",
+            Text::Download => "Download",
+            Text::InvalidListen => "Invalid listen address",
+            Text::InvalidDns => "Invalid DNS address",
+            Text::DnsUnreachable => "DNS server unreachable",
+            Text::DnsBadResponse => "Invalid response from DNS server",
+            Text::Connected => "Connected",
+            Text::Disconnected => "Disconnected",
+            Text::Reconnecting => "Server unreachable, reconnecting",
+            Text::FatalError => "Exited with error code",
+            Text::NotRunning => "Proxy is not running",
+            Text::SpeedTestFailed => "Speed test failed",
+            Text::BudgetUsed => "Monthly data budget used",
+            Text::NotElevated => "Please run as administrator",
+            Text::DriverUnavailable => "Failed to load the wintun driver, check wintun.dll",
+            Text::ServerUnresolved => "Server address not resolved, check network or DNS settings",
+            Text::InvalidForward => "Invalid forward rule",
+            Text::HighUsage => "Abnormal resource usage of sidecar",
+        }
+    }
+}
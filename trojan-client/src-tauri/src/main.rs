@@ -7,36 +7,265 @@ use std::{
     collections::HashMap,
     fs::{File, OpenOptions},
     io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
     path::Path,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, OnceLock},
     thread,
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 
 use backtrace::Backtrace;
+use chrono::Datelike;
 use derive_more::From;
 use log::LevelFilter;
 use serde::{Deserialize, Serialize};
 use tauri::{
-    api::process::{Command, CommandChild, CommandEvent},
-    CustomMenuItem, Icon, Manager, RunEvent, State, SystemTray, SystemTrayEvent, SystemTrayMenu,
-    SystemTrayMenuItem, Window, WindowEvent, Wry,
+    api::{
+        notification::Notification,
+        process::{Command, CommandChild, CommandEvent},
+    },
+    AppHandle, CustomMenuItem, Icon, Manager, RunEvent, State, SystemTray, SystemTrayEvent,
+    SystemTrayMenu, SystemTrayMenuItem, Window, WindowEvent, Wry,
 };
 use tauri_plugin_log::LogTarget;
 
 use wintool::adapter::{get_dns_server, get_main_adapter_ip};
 
+use crate::{
+    history::{History, Session},
+    icons::{TrayIcons, TrayState},
+    locale::{tr, Language, Text},
+    process_metrics::{ProcessMetrics, Usage},
+    provisioning::Policy,
+    speedtest::{SpeedResult, SpeedTestConfig},
+};
+
+mod history;
+mod icons;
+mod locale;
+mod process_metrics;
+mod provisioning;
+mod speedtest;
+
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// websocket address of the sidecar connection event stream
+const EVENTS_ADDR: &str = "127.0.0.1:60081";
+/// pid files of the sidecars, to find them again if the GUI restarts while they are running
+const WINTUN_PID: &str = "logs\\wintun.pid";
+const DNS_PID: &str = "logs\\dns.pid";
+/// secret of the event stream, kept for a sidecar still running when the GUI restarts
+const EVENTS_TOKEN_PATH: &str = "logs\\events.token";
+/// SQLite file of the session history
+const HISTORY_PATH: &str = "config\\history.db";
+/// named profiles for `--connect <profile>`, one config.json like file each
+pub const PROFILE_DIR: &str = "config\\profiles";
+
 #[derive(From, Debug)]
 pub enum Error {
     StdIo(std::io::Error),
     SerdeJson(serde_json::Error),
     SystemTime(std::time::SystemTimeError),
+    TauriApi(tauri::api::Error),
     #[from(ignore)]
     Custom(String),
 }
 
+/// DNS settings of a profile, stored under `dns` in config.json and in each profile
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct DnsConfig {
+    pub enable_dns: bool,
+    pub dns_listen: String,
+    pub trust_dns: String,
+    /// DNS server for direct domains, empty for the one of the main adapter
+    #[serde(default)]
+    pub default_dns: String,
+}
+
+impl DnsConfig {
+    fn validate(&self) -> std::result::Result<(), String> {
+        if !self.enable_dns {
+            return Ok(());
+        }
+        self.dns_listen
+            .parse::<Ipv4Addr>()
+            .map_err(|_| format!("{}:{}", tr(Text::InvalidListen), self.dns_listen))?;
+        check_dns_server(self.trust_dns.as_str())?;
+        if !self.default_dns.is_empty() {
+            check_dns_server(self.default_dns.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks `server` is an ip address answering DNS queries.
+fn check_dns_server(server: &str) -> std::result::Result<(), String> {
+    let ip: IpAddr = server
+        .parse()
+        .map_err(|_| format!("{}:{}", tr(Text::InvalidDns), server))?;
+    // query NS records of the root zone
+    let query = [
+        0x54, 0x52, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02,
+        0x00, 0x01,
+    ];
+    let unreachable = |_| format!("{}:{}", tr(Text::DnsUnreachable), server);
+    let socket = UdpSocket::bind("0.0.0.0:0").map_err(unreachable)?;
+    socket
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .map_err(unreachable)?;
+    socket.send_to(&query, (ip, 53)).map_err(unreachable)?;
+    let mut buffer = [0u8; 512];
+    let (size, _) = socket.recv_from(&mut buffer).map_err(unreachable)?;
+    if size < 2 || buffer[..2] != query[..2] {
+        return Err(format!("{}:{}", tr(Text::DnsBadResponse), server));
+    }
+    Ok(())
+}
+
+/// A local port forwarded through the server, like `ssh -L`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ForwardRule {
+    /// local address like 127.0.0.1:2222
+    pub listen: String,
+    /// host:port reached from the server
+    pub target: String,
+    pub udp: bool,
+    /// forwarded while connected
+    pub enabled: bool,
+}
+
+impl ForwardRule {
+    fn validate(&self) -> std::result::Result<(), String> {
+        let invalid = || {
+            format!(
+                "{}:{}={}",
+                tr(Text::InvalidForward),
+                self.listen,
+                self.target
+            )
+        };
+        self.listen.parse::<SocketAddr>().map_err(|_| invalid())?;
+        match self.target.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(()),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// The rule in the format of `--forward` and the `add_forward` event command.
+    fn arg(&self) -> String {
+        if self.udp {
+            format!("{}={},udp", self.listen, self.target)
+        } else {
+            format!("{}={}", self.listen, self.target)
+        }
+    }
+}
+
+/// Which state changes pop up a desktop notification.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct NotifyConfig {
+    pub connect: bool,
+    pub disconnect: bool,
+    pub reconnect: bool,
+    pub error: bool,
+    pub budget: bool,
+}
+
+impl Default for NotifyConfig {
+    fn default() -> Self {
+        NotifyConfig {
+            connect: true,
+            disconnect: true,
+            reconnect: true,
+            error: true,
+            budget: true,
+        }
+    }
+}
+
+/// Monthly data budget, warns once usage crosses each of `thresholds` percent.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct BudgetConfig {
+    /// 0 for no budget
+    pub monthly_mb: u64,
+    pub thresholds: Vec<u8>,
+}
+
+impl Default for BudgetConfig {
+    fn default() -> Self {
+        BudgetConfig {
+            monthly_mb: 0,
+            thresholds: vec![80, 100],
+        }
+    }
+}
+
+/// Payload of the `budget-alert` event.
+#[derive(Serialize, Debug, Clone)]
+struct BudgetAlert {
+    threshold: u8,
+    used_mb: u64,
+    budget_mb: u64,
+}
+
+/// Unix time of the first second of the current month, in local time.
+fn month_start() -> i64 {
+    chrono::Local::now()
+        .date_naive()
+        .with_day(1)
+        .and_then(|day| day.and_hms_opt(0, 0, 0))
+        .and_then(|time| time.and_local_timezone(chrono::Local).earliest())
+        .map_or(0, |time| time.timestamp())
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Notice {
+    Connected,
+    Disconnected,
+    Reconnecting,
+    /// the data budget threshold in percent is reached
+    Budget(u8),
+    /// sidecar exited on its own, with the exit code of its error
+    Fatal(Option<i32>),
+    /// the named sidecar keeps using too much CPU or memory
+    HighUsage(&'static str),
+}
+
+fn notify(app: &AppHandle<Wry>, config: &NotifyConfig, notice: Notice) {
+    let (enabled, body) = match notice {
+        Notice::Connected => (config.connect, tr(Text::Connected).to_string()),
+        Notice::Disconnected => (config.disconnect, tr(Text::Disconnected).to_string()),
+        Notice::Reconnecting => (config.reconnect, tr(Text::Reconnecting).to_string()),
+        Notice::Budget(threshold) => (
+            config.budget,
+            format!("{}:{}%", tr(Text::BudgetUsed), threshold),
+        ),
+        Notice::Fatal(code) => (
+            config.error,
+            match code {
+                // preflight failures of the wintun mode
+                Some(7) => tr(Text::NotElevated).to_string(),
+                Some(8) => tr(Text::DriverUnavailable).to_string(),
+                Some(10) => tr(Text::ServerUnresolved).to_string(),
+                code => format!("{}:{}", tr(Text::FatalError), code.unwrap_or(-1)),
+            },
+        ),
+        Notice::HighUsage(name) => (config.error, format!("{}:{}", tr(Text::HighUsage), name)),
+    };
+    if !enabled {
+        return;
+    }
+    if let Err(err) = Notification::new(&app.config().tauri.bundle.identifier)
+        .title(tr(Text::Title))
+        .body(body)
+        .show()
+    {
+        log::error!("show notification {:?} failed:{:?}", notice, err);
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Default, Clone)]
 pub struct Config {
     pub iface_name: String,
@@ -46,13 +275,52 @@ pub struct Config {
     pub pool_size: u32,
     pub enable_ipset: bool,
     pub inverse_route: bool,
-    pub enable_dns: bool,
     pub sync_mode: bool,
-    pub dns_listen: String,
-    pub trust_dns: String,
+    #[serde(default)]
+    pub dns: DnsConfig,
+    #[serde(default)]
+    pub language: Language,
+    #[serde(default)]
+    pub notify: NotifyConfig,
+    #[serde(default)]
+    pub speed_test: SpeedTestConfig,
+    #[serde(default)]
+    pub budget: BudgetConfig,
+    #[serde(default)]
+    pub forwards: Vec<ForwardRule>,
+    /// networks in CIDR format never routed through the tunnel, `--route-exclude`
+    #[serde(default)]
+    pub route_exclude: Vec<String>,
+    /// route the local subnets through the tunnel too, `--no-local-exempt`
+    #[serde(default)]
+    pub no_local_exempt: bool,
+    /// schema version of config.json, see `MIGRATIONS`
+    #[serde(default)]
+    pub version: u32,
 }
 
 impl Config {
+    /// Routing options of the wintun sidecar, given to the route test as well so it shows the
+    /// route the tunnel takes.
+    fn route_args(&self, ipset: &str) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.enable_ipset {
+            args.push("--route-ipset".to_string());
+            args.push(ipset.to_string());
+            if self.inverse_route {
+                args.push("--inverse-route".to_string());
+            }
+        }
+        if self.no_local_exempt {
+            args.push("--no-local-exempt".to_string());
+        }
+        for network in &self.route_exclude {
+            args.push("--route-exclude".to_string());
+            args.push(network.clone());
+        }
+        args
+    }
+
     fn log_level_str(&self) -> &'static str {
         match self.log_level.as_str() {
             "Trace" => "0",
@@ -65,32 +333,83 @@ impl Config {
     }
 }
 
+/// Traffic of the current session, integrated from the speeds in the status file.
+struct CurrentSession {
+    id: i64,
+    upload: f64,
+    download: f64,
+    saved: Instant,
+}
+
 pub struct TrojanProxy {
     config: Config,
     wintun: Option<CommandChild>,
     dns: Option<CommandChild>,
+    /// sidecars left running by a previous GUI process
+    orphan_wintun: Option<u32>,
+    orphan_dns: Option<u32>,
     running_icon: Icon,
     stopped_icon: Icon,
+    tray_icons: TrayIcons,
+    tray_state: TrayState,
     rx_speed: f32,
     tx_speed: f32,
     last_update: SystemTime,
     default_dns: String,
     explicit_dns: bool,
+    history: Option<History>,
+    session: Option<CurrentSession>,
+    /// why the sidecar stops, recorded in the session history
+    stop_reason: Option<String>,
+    /// centrally managed settings, see `provisioning`
+    policy: Policy,
+    budget_checked: Instant,
+    /// month start and the highest budget threshold already warned about in it
+    budget_alerted: Option<(i64, u8)>,
+    /// resource usage of the running sidecars, sampled every 2 seconds
+    metrics: Vec<ProcessMetrics>,
+    metrics_sampled: Instant,
+    /// why config.json could not be loaded, nothing is started or saved over it then
+    config_error: Option<String>,
 }
 
 impl TrojanProxy {
     fn new() -> TrojanProxy {
+        let (config, config_error) = match init_config() {
+            Ok(config) => (config, None),
+            Err(err) => {
+                log::error!("init config failed:{:?}", err);
+                (default_config(), Some(format!("{:?}", err)))
+            }
+        };
+        let policy = provisioning::cached_policy().unwrap_or_default();
+        let config = policy.enforce(&config, &config).unwrap_or(config);
         TrojanProxy {
-            config: init_config().unwrap_or_default(),
+            config,
             wintun: None,
             dns: None,
+            orphan_wintun: find_orphan(WINTUN_PID),
+            orphan_dns: find_orphan(DNS_PID),
             running_icon: Icon::Raw(include_bytes!("../icons/icon.ico").to_vec()),
             stopped_icon: Icon::Raw(include_bytes!("../icons/icon_gray.png").to_vec()),
+            tray_icons: TrayIcons::new(wintool::theme::light_taskbar()),
+            tray_state: TrayState::Stopped,
             rx_speed: 0.0,
             tx_speed: 0.0,
             last_update: SystemTime::UNIX_EPOCH,
             default_dns: String::new(),
             explicit_dns: false,
+            history: History::open(HISTORY_PATH)
+                .map_err(|err| log::error!("open session history failed:{:?}", err))
+                .ok(),
+            session: None,
+            stop_reason: None,
+            policy,
+            budget_checked: Instant::now(),
+            budget_alerted: None,
+            metrics: Vec::new(),
+            metrics_sampled: Instant::now(),
+            config_error,
         }
     }
 
@@ -115,6 +434,172 @@ impl TrojanProxy {
         Ok(())
     }
 
+    fn set_tray_state(&mut self, app: &AppHandle<Wry>, tray_state: TrayState) {
+        self.tray_state = tray_state;
+        if let Err(err) = app.tray_handle().set_icon(self.tray_icons.get(tray_state)) {
+            log::error!("set tray icon failed:{:?}", err);
+        }
+    }
+
+    fn add_traffic(&mut self, interval: f64) {
+        let Some(session) = self.session.as_mut() else {
+            return;
+        };
+        session.upload += self.rx_speed as f64 * 1024.0 * interval;
+        session.download += self.tx_speed as f64 * 1024.0 * interval;
+        if session.saved.elapsed() > Duration::from_secs(10) {
+            session.saved = Instant::now();
+            if let Some(history) = &self.history {
+                let (upload, download) = (session.upload as u64, session.download as u64);
+                if let Err(err) = history.update(session.id, upload, download) {
+                    log::error!("update session failed:{:?}", err);
+                }
+            }
+        }
+    }
+
+    fn begin_session(&mut self) {
+        self.stop_reason = None;
+        let Some(history) = &self.history else {
+            return;
+        };
+        match history.begin(self.config.server_domain.as_str()) {
+            Ok(id) => {
+                self.session = Some(CurrentSession {
+                    id,
+                    upload: 0.0,
+                    download: 0.0,
+                    saved: Instant::now(),
+                })
+            }
+            Err(err) => log::error!("begin session failed:{:?}", err),
+        }
+    }
+
+    fn finish_session(&mut self) {
+        let reason = self.stop_reason.take().unwrap_or_else(|| "exited".into());
+        let (Some(history), Some(session)) = (&self.history, self.session.take()) else {
+            return;
+        };
+        let (upload, download) = (session.upload as u64, session.download as u64);
+        if let Err(err) = history.finish(session.id, upload, download, reason.as_str()) {
+            log::error!("finish session failed:{:?}", err);
+        }
+    }
+
+    /// The highest budget threshold newly crossed this month, checked every 10 seconds
+    /// with the traffic of the past and current sessions.
+    fn check_budget(&mut self) -> Option<BudgetAlert> {
+        let budget_mb = self.config.budget.monthly_mb;
+        self.session.as_ref()?;
+        if budget_mb == 0 || self.budget_checked.elapsed() < Duration::from_secs(10) {
+            return None;
+        }
+        self.budget_checked = Instant::now();
+        let since = month_start();
+        let (upload, download) = self
+            .history
+            .as_ref()?
+            .usage(since)
+            .map_err(|err| log::error!("query usage failed:{:?}", err))
+            .ok()?;
+        let used_mb = (upload + download) / 1024 / 1024;
+        let percent = used_mb * 100 / budget_mb;
+        let threshold = self
+            .config
+            .budget
+            .thresholds
+            .iter()
+            .copied()
+            .filter(|threshold| *threshold as u64 <= percent)
+            .max()?;
+        let alerted = self
+            .budget_alerted
+            .filter(|(month, _)| *month == since)
+            .map_or(0, |(_, threshold)| threshold);
+        if threshold <= alerted {
+            return None;
+        }
+        self.budget_alerted = Some((since, threshold));
+        Some(BudgetAlert {
+            threshold,
+            used_mb,
+            budget_mb,
+        })
+    }
+
+    /// Samples the running sidecars, None if it is not time yet. The names are of the ones
+    /// newly found abnormal.
+    fn sample_sidecars(&mut self) -> Option<(Vec<Usage>, Vec<&'static str>)> {
+        if self.metrics_sampled.elapsed() < Duration::from_secs(2) {
+            return None;
+        }
+        self.metrics_sampled = Instant::now();
+        let sidecars = [
+            (
+                "wintun",
+                self.wintun
+                    .as_ref()
+                    .map(CommandChild::pid)
+                    .or(self.orphan_wintun),
+            ),
+            (
+                "dns",
+                self.dns.as_ref().map(CommandChild::pid).or(self.orphan_dns),
+            ),
+        ];
+        self.metrics
+            .retain(|metrics| sidecars.iter().any(|(_, pid)| *pid == Some(metrics.pid())));
+        for (name, pid) in sidecars {
+            let Some(pid) = pid else {
+                continue;
+            };
+            if !self.metrics.iter().any(|metrics| metrics.pid() == pid) {
+                self.metrics.push(ProcessMetrics::new(name, pid));
+            }
+        }
+        let mut usages = Vec::new();
+        let mut abnormal = Vec::new();
+        for metrics in &mut self.metrics {
+            let Some((usage, warn)) = metrics.sample() else {
+                continue;
+            };
+            if warn {
+                log::warn!("abnormal resource usage:{:?}", usage);
+                abnormal.push(usage.name);
+            }
+            usages.push(usage);
+        }
+        Some((usages, abnormal))
+    }
+
+    fn is_running(&self) -> bool {
+        self.wintun.is_some() || self.orphan_wintun.is_some()
+    }
+
+    fn kill_orphans(&mut self) {
+        if let Some(pid) = self.orphan_dns.take() {
+            // the original DNS setting is lost with the previous GUI process
+            set_dns_server(self);
+            wintool::process::kill_process(pid);
+            remove_pid(DNS_PID);
+            thread::sleep(Duration::from_millis(500));
+        }
+        if let Some(pid) = self.orphan_wintun.take() {
+            wintool::process::kill_process(pid);
+            remove_pid(WINTUN_PID);
+        }
+    }
+
+    /// DNS server for direct domains, the profile one takes precedence over the system one.
+    fn direct_dns(&self) -> String {
+        if self.config.dns.default_dns.is_empty() {
+            self.default_dns.clone()
+        } else {
+            self.config.dns.default_dns.clone()
+        }
+    }
+
     fn get_speed(&mut self) -> Result<(f32, f32)> {
         let metadata = std::fs::metadata("logs\\wintun.status")?;
         let mod_time = metadata.modified()?;
@@ -131,7 +616,12 @@ impl TrojanProxy {
                 .next()
                 .map(|s| s.parse().unwrap_or_default())
                 .unwrap_or_default();
+            // the status file is written about every second
+            let interval = mod_time
+                .duration_since(self.last_update)
+                .map_or(1.0, |interval| interval.as_secs_f64().min(2.0));
             self.last_update = mod_time;
+            self.add_traffic(interval);
         } else if self.last_update.elapsed()?.as_secs() > 1 {
             self.rx_speed = 0.0;
             self.tx_speed = 0.0;
@@ -145,6 +635,20 @@ type TrojanState = Arc<Mutex<TrojanProxy>>;
 #[tauri::command]
 fn start(config: Config, state: State<TrojanState>, window: Window<Wry>) {
     log::info!("start trojan now");
+    let config = {
+        let state = state.lock().unwrap();
+        if let Some(err) = &state.config_error {
+            log::error!("config not loaded, refuse to start:{}", err);
+            return;
+        }
+        match state.policy.enforce(&config, &state.config) {
+            Ok(config) => config,
+            Err(err) => {
+                log::error!("enforce policy failed:{:?}", err);
+                return;
+            }
+        }
+    };
     if let Err(err) = save_config(&config) {
         log::error!("save config failed:{:?}", err);
     } else {
@@ -156,13 +660,15 @@ fn start(config: Config, state: State<TrojanState>, window: Window<Wry>) {
 
         emit_state_update_event(true, window.clone());
 
-        if state.lock().unwrap().wintun.is_some() {
+        if state.lock().unwrap().is_running() {
             return;
         }
+        // a dns sidecar left without its wintun one would hold the dns port
+        state.lock().unwrap().kill_orphans();
         let state = state.inner().clone();
         tauri::async_runtime::spawn(async move {
             let config = state.lock().unwrap().config.clone();
-            let default_dns = state.lock().unwrap().default_dns.clone() + ":53";
+            let default_dns = state.lock().unwrap().direct_dns() + ":53";
             let pool_size = config.pool_size.to_string();
             let config_ipset = window
                 .app_handle()
@@ -179,6 +685,7 @@ fn start(config: Config, state: State<TrojanState>, window: Window<Wry>) {
             } else {
                 "awintun"
             };
+            let pinned_certs = state.lock().unwrap().policy.pinned_certs.clone();
             let mut args = vec![
                 "-l",
                 "logs\\wintun.log",
@@ -186,8 +693,14 @@ fn start(config: Config, state: State<TrojanState>, window: Window<Wry>) {
                 config.log_level_str(),
                 "-a",
                 "127.0.0.1:60080",
-                "-p",
-                config.server_auth.as_str(),
+                "--events-addr",
+                EVENTS_ADDR,
+            ];
+            for pin in &pinned_certs {
+                args.push("--pin-cert");
+                args.push(pin.as_str());
+            }
+            args.extend([
                 command,
                 "-n",
                 config.iface_name.as_str(),
@@ -201,18 +714,30 @@ fn start(config: Config, state: State<TrojanState>, window: Window<Wry>) {
                 pool_size.as_str(),
                 "-w",
                 config_wintun.to_str().unwrap(),
-            ];
-            if config.enable_ipset {
-                args.push("--route-ipset");
-                args.push(config_ipset.to_str().unwrap());
-                if config.inverse_route {
-                    args.push("--inverse-route");
-                }
+            ]);
+            // the synchronous mode has no port forwarding
+            let forwards: Vec<_> = config
+                .forwards
+                .iter()
+                .filter(|rule| rule.enabled && !config.sync_mode)
+                .map(ForwardRule::arg)
+                .collect();
+            for forward in &forwards {
+                args.push("--forward");
+                args.push(forward.as_str());
             }
+            let route_args = config.route_args(config_ipset.to_str().unwrap());
+            args.extend(route_args.iter().map(String::as_str));
             log::info!("{:?}", args);
             let mut rxs = HashMap::new();
-            match Command::new_sidecar("trojan").unwrap().args(args).spawn() {
+            match Command::new_sidecar("trojan")
+                .unwrap()
+                .args(args)
+                .envs(sidecar_env(&config))
+                .spawn()
+            {
                 Ok((rx, child)) => {
+                    save_pid(WINTUN_PID, child.pid());
                     state.lock().unwrap().wintun.replace(child);
                     rxs.insert("wintun", rx);
                 }
@@ -222,10 +747,10 @@ fn start(config: Config, state: State<TrojanState>, window: Window<Wry>) {
                     return;
                 }
             };
-            if state.lock().unwrap().config.enable_dns {
+            if state.lock().unwrap().config.dns.enable_dns {
                 tokio::time::sleep(Duration::from_secs(10)).await;
-                let dns_listen = config.dns_listen.clone() + ":53";
-                let default_dns = state.lock().unwrap().default_dns.clone();
+                let dns_listen = config.dns.dns_listen.clone() + ":53";
+                let default_dns = state.lock().unwrap().direct_dns();
                 let config_domains = window
                     .app_handle()
                     .path_resolver()
@@ -243,8 +768,6 @@ fn start(config: Config, state: State<TrojanState>, window: Window<Wry>) {
                     config.log_level_str(),
                     "-a",
                     "127.0.0.1:60080",
-                    "-p",
-                    config.server_auth.as_str(),
                     "dns",
                     "-n",
                     config.iface_name.as_str(),
@@ -253,7 +776,7 @@ fn start(config: Config, state: State<TrojanState>, window: Window<Wry>) {
                     "--poisoned-dns",
                     default_dns.as_str(),
                     "--trusted-dns",
-                    config.trust_dns.as_str(),
+                    config.dns.trust_dns.as_str(),
                     "--dns-listen-address",
                     dns_listen.as_str(),
                     "--hosts",
@@ -263,8 +786,14 @@ fn start(config: Config, state: State<TrojanState>, window: Window<Wry>) {
                     args.push("--add-route");
                 }
                 log::info!("{:?}", args);
-                match Command::new_sidecar("trojan").unwrap().args(args).spawn() {
+                match Command::new_sidecar("trojan")
+                    .unwrap()
+                    .args(args)
+                    .envs(sidecar_env(&config))
+                    .spawn()
+                {
                     Ok((rx, child)) => {
+                        save_pid(DNS_PID, child.pid());
                         state.lock().unwrap().dns.replace(child);
                         rxs.insert("dns", rx);
                     }
@@ -285,42 +814,56 @@ fn start(config: Config, state: State<TrojanState>, window: Window<Wry>) {
                         let exit = match rx.try_recv() {
                             Ok(CommandEvent::Terminated(payload)) => {
                                 log::info!("{} exits with:{:?}", name, payload);
-                                true
+                                Some(payload.code)
                             }
                             Ok(CommandEvent::Error(err)) => {
                                 log::info!("{} got error:{}", name, err);
-                                false
+                                None
                             }
                             Ok(CommandEvent::Stderr(err)) => {
                                 log::info!("{} got stderr:{}", name, err);
-                                false
+                                None
                             }
                             Ok(CommandEvent::Stdout(output)) => {
                                 log::info!("{} got stdout:{}", name, output);
-                                false
+                                None
                             }
-                            Err(_err) => false,
-                            Ok(_) => false,
+                            Err(_err) => None,
+                            Ok(_) => None,
                         };
-                        if exit {
+                        if let Some(code) = exit {
                             let mut state = state.lock().unwrap();
-                            match *name {
+                            // the child is taken already if it is killed by us
+                            let killed = match *name {
                                 "wintun" => {
-                                    state.wintun.take();
+                                    remove_pid(WINTUN_PID);
+                                    let killed = state.wintun.take().is_none();
                                     if let Some(child) = state.dns.take() {
                                         let _ = child.kill();
                                     }
+                                    killed
                                 }
                                 "dns" => {
+                                    remove_pid(DNS_PID);
                                     set_dns_server(&state);
-                                    state.dns.take();
+                                    let killed = state.dns.take().is_none();
                                     if let Some(child) = state.wintun.take() {
                                         let _ = child.kill();
                                     }
+                                    killed
                                 }
                                 _ => {
                                     log::error!("invalid name:{}", name);
+                                    true
                                 }
+                            };
+                            if !killed && code != Some(0) {
+                                state.stop_reason = Some(format!("error:{}", code.unwrap_or(-1)));
+                                notify(
+                                    &window.app_handle(),
+                                    &state.config.notify,
+                                    Notice::Fatal(code),
+                                );
                             }
                             Some(name.to_string())
                         } else {
@@ -343,25 +886,123 @@ fn emit_state_update_event(running: bool, window: Window<Wry>) {
     window.emit("state-update", running).unwrap();
     let app = window.app_handle();
     let state = app.state::<TrojanState>();
-    let state = state.lock().unwrap();
-    let icon = if running {
-        state.running_icon.clone()
+    let mut state = state.lock().unwrap();
+    let (icon, tray_state) = if running {
+        (state.running_icon.clone(), TrayState::Running)
     } else {
-        state.stopped_icon.clone()
+        (state.stopped_icon.clone(), TrayState::Stopped)
     };
-    window.set_icon(icon.clone()).unwrap();
-    window.app_handle().tray_handle().set_icon(icon).unwrap();
+    window.set_icon(icon).unwrap();
+    match (state.tray_state, running) {
+        (TrayState::Stopped, true) => {
+            state.begin_session();
+            notify(&app, &state.config.notify, Notice::Connected);
+        }
+        (TrayState::Running | TrayState::Degraded, false) => {
+            state.finish_session();
+            notify(&app, &state.config.notify, Notice::Disconnected);
+        }
+        _ => {}
+    }
+    state.set_tray_state(&app, tray_state);
 }
 
+/// Health of the running sidecar as seen by the frontend from the event stream.
 #[tauri::command]
-fn init(state: State<TrojanState>) -> Config {
-    state.lock().unwrap().config.clone()
+fn report_health(degraded: bool, state: State<TrojanState>, window: Window<Wry>) {
+    let mut state = state.lock().unwrap();
+    if state.tray_state == TrayState::Stopped {
+        return;
+    }
+    let tray_state = if degraded {
+        TrayState::Degraded
+    } else {
+        TrayState::Running
+    };
+    if tray_state != state.tray_state {
+        log::warn!("sidecar health changed to {:?}", tray_state);
+        if degraded {
+            notify(
+                &window.app_handle(),
+                &state.config.notify,
+                Notice::Reconnecting,
+            );
+        }
+        state.set_tray_state(&window.app_handle(), tray_state);
+    }
+}
+
+#[tauri::command]
+fn init(state: State<TrojanState>) -> std::result::Result<Config, String> {
+    let state = state.lock().unwrap();
+    match &state.config_error {
+        Some(err) => Err(err.clone()),
+        None => Ok(state.config.clone()),
+    }
+}
+
+/// Reports whether the sidecar is running, re-attaching to one left by a previous GUI process.
+#[tauri::command]
+fn running(state: State<TrojanState>, window: Window<Wry>) -> bool {
+    let orphan = {
+        let state = state.lock().unwrap();
+        if !state.is_running() {
+            return false;
+        }
+        state.orphan_wintun
+    };
+    if let Some(pid) = orphan {
+        log::warn!("re-attach to running sidecar {}", pid);
+        emit_state_update_event(true, window.clone());
+        let state = state.inner().clone();
+        tauri::async_runtime::spawn(async move {
+            while wintool::process::process_image(pid).is_some() {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            let mut state = state.lock().unwrap();
+            // otherwise it is stopped by the user, who has been notified already
+            if state.orphan_wintun == Some(pid) {
+                log::info!("orphan sidecar {} exits", pid);
+                state.orphan_wintun.take();
+                remove_pid(WINTUN_PID);
+                state.kill_orphans();
+                drop(state);
+                emit_state_update_event(false, window);
+            }
+        });
+    }
+    true
 }
 
 #[tauri::command]
 fn update_speed(state: State<TrojanState>, window: Window<Wry>) {
     let mut state = state.lock().unwrap();
+    let light_taskbar = wintool::theme::light_taskbar();
+    if light_taskbar != state.tray_icons.light_taskbar() {
+        state.tray_icons = TrayIcons::new(light_taskbar);
+        let tray_state = state.tray_state;
+        state.set_tray_state(&window.app_handle(), tray_state);
+    }
     let (mut rx_speed, mut tx_speed) = state.get_speed().unwrap_or_default();
+    if let Some(alert) = state.check_budget() {
+        log::warn!("data budget alert:{:?}", alert);
+        notify(
+            &window.app_handle(),
+            &state.config.notify,
+            Notice::Budget(alert.threshold),
+        );
+        let _ = window.emit("budget-alert", alert);
+    }
+    if let Some((usages, abnormal)) = state.sample_sidecars() {
+        for name in abnormal {
+            notify(
+                &window.app_handle(),
+                &state.config.notify,
+                Notice::HighUsage(name),
+            );
+        }
+        let _ = window.emit("sidecar-usage", usages);
+    }
     let rx_unit = if rx_speed > 1024.0 {
         rx_speed /= 1024.0;
         "MB"
@@ -377,57 +1018,452 @@ fn update_speed(state: State<TrojanState>, window: Window<Wry>) {
     window
         .set_title(
             format!(
-                "Trojan客户端 - 上行:{:.3}{}/下行:{:.3}{}",
-                rx_speed, rx_unit, tx_speed, tx_unit
+                "{} - {}:{:.3}{}/{}:{:.3}{}",
+                tr(Text::Title),
+                tr(Text::Upload),
+                rx_speed,
+                rx_unit,
+                tr(Text::Download),
+                tx_speed,
+                tx_unit
             )
             .as_str(),
         )
         .unwrap();
 }
 
+#[tauri::command]
+fn route_test(target: String, state: State<TrojanState>, window: Window<Wry>) -> String {
+    let config = state.lock().unwrap().config.clone();
+    let resolver = window.app_handle().path_resolver();
+    let config_ipset = resolver.resolve_resource("config/ipset.txt").unwrap();
+    let config_domains = resolver.resolve_resource("config/domain.txt").unwrap();
+    let default_dns = state.lock().unwrap().direct_dns();
+    let mut args = vec![
+        "route-test",
+        target.as_str(),
+        "--blocked-domain-list",
+        config_domains.to_str().unwrap(),
+        "--trusted-dns",
+        config.dns.trust_dns.as_str(),
+    ];
+    if !default_dns.is_empty() {
+        args.push("--poisoned-dns");
+        args.push(default_dns.as_str());
+    }
+    let route_args = config.route_args(config_ipset.to_str().unwrap());
+    args.extend(route_args.iter().map(String::as_str));
+    match Command::new_sidecar("trojan").unwrap().args(args).output() {
+        Ok(output) => output.stdout + output.stderr.as_str(),
+        Err(err) => {
+            log::error!("run route test failed:{:?}", err);
+            err.to_string()
+        }
+    }
+}
+
+#[tauri::command]
+fn set_language(language: Language, state: State<TrojanState>, window: Window<Wry>) {
+    locale::set_language(language);
+    let mut state = state.lock().unwrap();
+    state.config.language = language;
+    if state.config_error.is_some() {
+        log::warn!("config not loaded, language not saved");
+    } else if let Err(err) = save_config(&state.config) {
+        log::error!("save config failed:{:?}", err);
+    }
+    if let Err(err) = window.app_handle().tray_handle().set_menu(tray_menu()) {
+        log::error!("update tray menu failed:{:?}", err);
+    }
+}
+
+/// Saves the forward rules of the profile, the UI starts and stops them on the running sidecar.
+#[tauri::command]
+fn set_forwards(
+    forwards: Vec<ForwardRule>,
+    state: State<TrojanState>,
+) -> std::result::Result<(), String> {
+    // rules being edited are only checked once enabled
+    forwards
+        .iter()
+        .filter(|rule| rule.enabled)
+        .try_for_each(ForwardRule::validate)?;
+    let mut state = state.lock().unwrap();
+    if let Some(err) = &state.config_error {
+        return Err(err.clone());
+    }
+    state.config.forwards = forwards;
+    save_config(&state.config).map_err(|err| format!("{:?}", err))
+}
+
+/// Measures latency and throughput through the running tunnel.
+#[tauri::command]
+async fn speed_test(state: State<'_, TrojanState>) -> std::result::Result<SpeedResult, String> {
+    let (iface_name, config) = {
+        let state = state.lock().unwrap();
+        if !state.is_running() {
+            return Err(tr(Text::NotRunning).into());
+        }
+        (
+            state.config.iface_name.clone(),
+            state.config.speed_test.clone(),
+        )
+    };
+    let local = wintool::adapter::get_adapter_ip(iface_name.as_str())
+        .and_then(|ip| ip.parse().ok())
+        .ok_or_else(|| tr(Text::NotRunning).to_string())?;
+    log::info!("speed test from {} with {:?}", local, config);
+    speedtest::run(local, config)
+        .await
+        .map_err(|err| format!("{}:{}", tr(Text::SpeedTestFailed), err))
+}
+
+/// Sessions started since `since` in unix seconds, newest first.
+#[tauri::command]
+fn session_history(
+    since: i64,
+    limit: u32,
+    state: State<TrojanState>,
+) -> std::result::Result<Vec<Session>, String> {
+    let state = state.lock().unwrap();
+    let history = state
+        .history
+        .as_ref()
+        .ok_or("session history unavailable")?;
+    history.query(since, limit).map_err(|err| err.to_string())
+}
+
+/// Total (upload, download) bytes of sessions started since `since` in unix seconds.
+#[tauri::command]
+fn session_usage(since: i64, state: State<TrojanState>) -> std::result::Result<(u64, u64), String> {
+    let state = state.lock().unwrap();
+    let history = state
+        .history
+        .as_ref()
+        .ok_or("session history unavailable")?;
+    history.usage(since).map_err(|err| err.to_string())
+}
+
+/// Config fields locked by the provisioning policy.
+#[tauri::command]
+fn locked_fields(state: State<TrojanState>) -> Vec<String> {
+    state.lock().unwrap().policy.locked.clone()
+}
+
+/// Fetches the provisioning policy and applies it to the state and the profiles.
+async fn update_policy(state: TrojanState, window: Window<Wry>) -> Result<()> {
+    let Some(policy) = provisioning::fetch_policy().await? else {
+        return Ok(());
+    };
+    policy.write_profiles()?;
+    let mut state = state.lock().unwrap();
+    if let Some(err) = &state.config_error {
+        return Err(Error::Custom(err.clone()));
+    }
+    let mut config = state.config.clone();
+    if policy != state.policy {
+        log::warn!("provisioning policy changed, apply its settings");
+        config = policy.preseed(&config)?;
+    }
+    state.config = policy.enforce(&config, &config)?;
+    state.policy = policy;
+    save_config(&state.config)?;
+    let _ = window.emit("config-update", state.config.clone());
+    let _ = window.emit("policy-update", state.policy.locked.clone());
+    Ok(())
+}
+
+/// Runs on the blocking pool as probing the servers may wait seconds for answers.
+#[tauri::command]
+async fn validate_dns(dns: DnsConfig) -> std::result::Result<(), String> {
+    tauri::async_runtime::spawn_blocking(move || dns.validate())
+        .await
+        .map_err(|err| err.to_string())?
+}
+
+#[tauri::command]
+fn events_addr() -> String {
+    format!("ws://{}/?token={}", EVENTS_ADDR, events_token())
+}
+
+static EVENTS_TOKEN: OnceLock<String> = OnceLock::new();
+
+/// Secret of the event stream, generated on each launch unless a running sidecar was started
+/// with an earlier one.
+fn events_token() -> &'static str {
+    EVENTS_TOKEN.get_or_init(|| {
+        if find_orphan(WINTUN_PID).is_some() {
+            if let Ok(token) = std::fs::read_to_string(EVENTS_TOKEN_PATH) {
+                return token;
+            }
+        }
+        let token = hex::encode(rand::random::<[u8; 16]>());
+        if let Err(err) = std::fs::write(EVENTS_TOKEN_PATH, token.as_str()) {
+            log::error!("save events token failed:{:?}", err);
+        }
+        token
+    })
+}
+
 #[tauri::command]
 fn stop(state: State<TrojanState>, window: Window<Wry>) {
     log::info!("stop trojan now");
     let mut config = state.lock().unwrap();
+    config.stop_reason = Some("user".into());
     if let Some(child) = config.wintun.take() {
         let _ = child.kill();
         log::info!("trojan stopped");
+    } else if config.orphan_wintun.is_some() {
+        config.kill_orphans();
+        drop(config);
+        log::info!("orphan trojan stopped");
+        emit_state_update_event(false, window);
     } else {
         emit_state_update_event(false, window);
     }
 }
 
+/// prefix of the DPAPI protected `server_auth` field in config.json
+const PROTECTED_PREFIX: &str = "dpapi:";
+
+fn protect_auth(auth: &str) -> Result<String> {
+    let data = wintool::dpapi::protect(auth.as_bytes())
+        .ok_or_else(|| Error::Custom("protect server auth failed".to_string()))?;
+    Ok(PROTECTED_PREFIX.to_string() + hex::encode(data).as_str())
+}
+
+fn unprotect_auth(auth: &str) -> Result<String> {
+    let data = hex::decode(auth).map_err(|err| Error::Custom(err.to_string()))?;
+    let data = wintool::dpapi::unprotect(data.as_slice())
+        .ok_or_else(|| Error::Custom("unprotect server auth failed".to_string()))?;
+    String::from_utf8(data).map_err(|err| Error::Custom(err.to_string()))
+}
+
+const CONFIG_PATH: &str = "config\\config.json";
+
+/// Migrations of the raw config.json, `MIGRATIONS[n]` upgrades version `n` to `n + 1`.
+/// Fields added without a migration get the value of `default_config`.
+const MIGRATIONS: &[fn(&mut serde_json::Map<String, serde_json::Value>)] =
+    &[migrate_v0, migrate_v1];
+
+const CONFIG_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// v0 had no `version` field, and DNS settings were not per profile yet.
+fn migrate_v0(config: &mut serde_json::Map<String, serde_json::Value>) {
+    config
+        .entry("default_dns")
+        .or_insert_with(|| String::new().into());
+}
+
+/// v1 kept the DNS settings at the top level instead of under `dns`.
+fn migrate_v1(config: &mut serde_json::Map<String, serde_json::Value>) {
+    let mut dns = serde_json::Map::new();
+    for key in ["enable_dns", "dns_listen", "trust_dns", "default_dns"] {
+        if let Some(value) = config.remove(key) {
+            dns.insert(key.into(), value);
+        }
+    }
+    if !dns.is_empty() {
+        config.insert("dns".into(), dns.into());
+    }
+}
+
+fn default_config() -> Config {
+    Config {
+        iface_name: "trojan".into(),
+        pool_size: 20,
+        log_level: "Info".into(),
+        dns: DnsConfig {
+            enable_dns: true,
+            dns_listen: "127.0.0.1".into(),
+            trust_dns: "8.8.8.8".into(),
+            default_dns: String::new(),
+        },
+        version: CONFIG_VERSION,
+        ..Config::default()
+    }
+}
+
 fn save_config(config: &Config) -> Result<()> {
+    let mut config = config.clone();
+    config.server_auth = protect_auth(config.server_auth.as_str())?;
+    config.version = CONFIG_VERSION;
     let mut file = OpenOptions::new()
         .create(true)
         .truncate(true)
         .write(true)
-        .open("config\\config.json")?;
-    let data = serde_json::to_string(config)?;
+        .open(CONFIG_PATH)?;
+    let data = serde_json::to_string(&config)?;
     file.write_all(data.as_bytes())?;
     Ok(())
 }
 
+/// Keeps a copy of config.json before it is rewritten or given up on.
+fn backup_config(suffix: &str) -> Result<()> {
+    let backup = format!("{}.{}.bak", CONFIG_PATH, suffix);
+    std::fs::copy(CONFIG_PATH, backup.as_str())?;
+    log::warn!("config backup saved to {}", backup);
+    Ok(())
+}
+
+/// Upgrades the raw config to `CONFIG_VERSION`, returning it with the version it had.
+fn migrate_config(mut value: serde_json::Value) -> Result<(Config, u32)> {
+    let object = value
+        .as_object_mut()
+        .ok_or_else(|| Error::Custom("config is not a json object".into()))?;
+    let version = object
+        .get("version")
+        .and_then(|version| version.as_u64())
+        .unwrap_or_default() as u32;
+    if version > CONFIG_VERSION {
+        return Err(Error::Custom(format!(
+            "config version {} is newer than supported {}",
+            version, CONFIG_VERSION
+        )));
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(object);
+    }
+    if let serde_json::Value::Object(defaults) = serde_json::to_value(default_config())? {
+        for (key, default) in defaults {
+            object.entry(key).or_insert(default);
+        }
+    }
+    object.insert("version".into(), CONFIG_VERSION.into());
+    Ok((serde_json::from_value(value)?, version))
+}
+
 fn init_config() -> Result<Config> {
-    let path = Path::new("config\\config.json");
-    let config = if path.exists() {
-        let file = File::open(path)?;
-        let config: Config = serde_json::from_reader(file)?;
-        config
-    } else {
-        Config {
-            iface_name: "trojan".into(),
-            dns_listen: "127.0.0.1".into(),
-            pool_size: 20,
-            trust_dns: "8.8.8.8".into(),
-            log_level: "Info".into(),
-            enable_dns: true,
-            ..Config::default()
+    let path = Path::new(CONFIG_PATH);
+    if !path.exists() {
+        return Ok(default_config());
+    }
+    let file = File::open(path)?;
+    let parsed = serde_json::from_reader(file)
+        .map_err(Error::from)
+        .and_then(migrate_config);
+    let (mut config, version) = match parsed {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            log::error!("load config failed:{:?}, backed up as invalid", err);
+            backup_config("invalid")?;
+            return Err(err);
         }
     };
+    let mut save = version < CONFIG_VERSION;
+    if save {
+        log::warn!(
+            "config migrated from version {} to {}",
+            version,
+            CONFIG_VERSION
+        );
+        backup_config(format!("v{}", version).as_str())?;
+    }
+    if let Some(auth) = config.server_auth.strip_prefix(PROTECTED_PREFIX) {
+        match unprotect_auth(auth) {
+            Ok(auth) => config.server_auth = auth,
+            Err(err) => {
+                // protected by another user or machine, the rest of the config is still good
+                log::error!("unprotect server auth failed:{:?}, clear it", err);
+                backup_config("auth")?;
+                config.server_auth.clear();
+                save = true;
+            }
+        }
+    } else if !config.server_auth.is_empty() {
+        log::warn!("plaintext server auth found in config, protect it now");
+        save = true;
+    }
+    if save {
+        save_config(&config)?;
+    }
     Ok(config)
 }
 
+fn load_profile(name: &str) -> Result<Config> {
+    if name.contains(['/', '\\', '.']) {
+        return Err(Error::Custom(format!("invalid profile name:{}", name)));
+    }
+    let file = File::open(format!("{}\\{}.json", PROFILE_DIR, name))?;
+    let (mut config, _) = migrate_config(serde_json::from_reader(file)?)?;
+    if let Some(auth) = config.server_auth.strip_prefix(PROTECTED_PREFIX) {
+        config.server_auth = unprotect_auth(auth)?;
+    }
+    Ok(config)
+}
+
+/// Handles `--connect [profile]` and `--disconnect`, given to this instance on startup
+/// or forwarded from a second one, so shortcuts and scripts can drive the client.
+fn handle_args(app: &AppHandle<Wry>, args: &[String]) {
+    let Some(window) = app.get_window("main") else {
+        return;
+    };
+    let mut args = args.iter().skip(1).peekable();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--connect" => {
+                let config = match args.next_if(|arg| !arg.starts_with("--")) {
+                    Some(name) => match load_profile(name) {
+                        Ok(config) => config,
+                        Err(err) => {
+                            log::error!("load profile {} failed:{:?}", name, err);
+                            continue;
+                        }
+                    },
+                    None => app.state::<TrojanState>().lock().unwrap().config.clone(),
+                };
+                if let Err(err) = config.dns.validate() {
+                    log::error!("connect failed:{}", err);
+                    continue;
+                }
+                let _ = window.emit("config-update", config.clone());
+                start(config, app.state(), window.clone());
+            }
+            "--disconnect" => stop(app.state(), window.clone()),
+            _ => log::warn!("unknown argument:{}", arg),
+        }
+    }
+}
+
+/// Environment of the sidecars, the password is kept off their command line.
+fn sidecar_env(config: &Config) -> HashMap<String, String> {
+    HashMap::from([
+        ("TROJAN_PASSWORD".into(), config.server_auth.clone()),
+        ("TROJAN_EVENTS_TOKEN".into(), events_token().into()),
+    ])
+}
+
+fn save_pid(path: &str, pid: u32) {
+    if let Err(err) = std::fs::write(path, pid.to_string()) {
+        log::error!("save pid file {} failed:{:?}", path, err);
+    }
+}
+
+fn remove_pid(path: &str) {
+    let _ = std::fs::remove_file(path);
+}
+
+/// Pid of the sidecar recorded in `path` if it is still running, the pid may be reused
+/// by another process since, so check the executable too.
+fn find_orphan(path: &str) -> Option<u32> {
+    let pid = std::fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    let current = std::env::current_exe().ok()?;
+    let found = wintool::process::process_image(pid).filter(|image| {
+        let image = Path::new(image);
+        image != current
+            && image
+                .file_name()
+                .map_or(false, |name| name.to_string_lossy().starts_with("trojan"))
+    });
+    if found.is_some() {
+        log::warn!("found orphan sidecar {} from {}", pid, path);
+        Some(pid)
+    } else {
+        remove_pid(path);
+        None
+    }
+}
+
 fn set_dns_server(state: &TrojanProxy) {
     if state.explicit_dns {
         wintool::adapter::set_dns_server(state.default_dns.clone());
@@ -436,6 +1472,16 @@ fn set_dns_server(state: &TrojanProxy) {
     }
 }
 
+fn tray_menu() -> SystemTrayMenu {
+    let quit = CustomMenuItem::new("quit".to_string(), tr(Text::Quit));
+    let menu = SystemTrayMenu::new();
+    #[cfg(debug_assertions)]
+    let menu = menu
+        .add_item(CustomMenuItem::new("dev".to_string(), tr(Text::DevTools)))
+        .add_native_item(SystemTrayMenuItem::Separator);
+    menu.add_item(quit)
+}
+
 fn main() {
     let path = Path::new("logs");
     if !path.exists() {
@@ -459,20 +1505,30 @@ fn main() {
             );
         }
     }));
+    wintool::minidump::install("logs", "client", 5);
 
-    let quit = CustomMenuItem::new("quit".to_string(), "退出");
-    let dev = CustomMenuItem::new("dev".to_string(), "开发工具");
-    let menu = SystemTrayMenu::new();
-
-    #[cfg(debug_assertions)]
-    let menu = menu
-        .add_item(dev)
-        .add_native_item(SystemTrayMenuItem::Separator);
-    let menu = menu.add_item(quit);
-    let tray = SystemTray::new().with_menu(menu);
+    let proxy = TrojanProxy::new();
+    locale::set_language(proxy.config.language);
+    let tray = SystemTray::new().with_menu(tray_menu());
 
     tauri::Builder::default()
-        .invoke_handler(tauri::generate_handler![start, init, stop, update_speed])
+        .invoke_handler(tauri::generate_handler![
+            start,
+            init,
+            running,
+            stop,
+            update_speed,
+            route_test,
+            events_addr,
+            set_language,
+            set_forwards,
+            report_health,
+            speed_test,
+            session_history,
+            session_usage,
+            locked_fields,
+            validate_dns
+        ])
         .system_tray(tray)
         .plugin(
             tauri_plugin_log::Builder::default()
@@ -497,8 +1553,9 @@ fn main() {
                 args,
                 cwd
             );
+            handle_args(app, &args);
         }))
-        .manage(Arc::new(Mutex::new(TrojanProxy::new())))
+        .manage(Arc::new(Mutex::new(proxy)))
         .on_system_tray_event(|app, event| match event {
             SystemTrayEvent::MenuItemClick { id, .. } => match id.as_str() {
                 "quit" => {
@@ -512,6 +1569,9 @@ fn main() {
                     if let Some(wintun) = state.wintun.take() {
                         let _ = wintun.kill();
                     }
+                    state.kill_orphans();
+                    state.stop_reason = Some("quit".into());
+                    state.finish_session();
                     std::process::exit(0);
                 }
                 #[cfg(debug_assertions)]
@@ -532,6 +1592,14 @@ fn main() {
         })
         .setup(|app| {
             emit_state_update_event(false, app.get_window("main").unwrap());
+            handle_args(&app.handle(), &std::env::args().collect::<Vec<_>>());
+            let state = app.state::<TrojanState>().inner().clone();
+            let window = app.get_window("main").unwrap();
+            tauri::async_runtime::spawn(async move {
+                if let Err(err) = update_policy(state, window).await {
+                    log::error!("update provisioning policy failed:{:?}", err);
+                }
+            });
             Ok(())
         })
         .build(tauri::generate_context!())
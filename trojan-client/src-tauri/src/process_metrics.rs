@@ -0,0 +1,84 @@
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// CPU usage in percent of all cores above which a sidecar is abnormal
+const CPU_LIMIT: f32 = 50.0;
+/// working set in MB above which a sidecar is abnormal
+const MEMORY_LIMIT_MB: u64 = 512;
+/// samples in a row over a limit before warning, so short bursts are ignored
+const ABNORMAL_SAMPLES: u32 = 15;
+
+/// Resource usage of a sidecar, the payload of the `sidecar-usage` event is a list of them.
+#[derive(Serialize, Debug, Clone)]
+pub struct Usage {
+    pub name: &'static str,
+    pub pid: u32,
+    /// percent of all cores since the previous sample
+    pub cpu: f32,
+    pub memory_mb: u64,
+    pub abnormal: bool,
+}
+
+/// Samples the CPU and memory usage of a sidecar process.
+pub struct ProcessMetrics {
+    name: &'static str,
+    pid: u32,
+    cpu_time: Duration,
+    sampled: Instant,
+    /// samples in a row over a limit
+    over: u32,
+    warned: bool,
+}
+
+impl ProcessMetrics {
+    pub fn new(name: &'static str, pid: u32) -> ProcessMetrics {
+        let cpu_time = wintool::process::process_usage(pid).map_or(Duration::ZERO, |(cpu, _)| cpu);
+        ProcessMetrics {
+            name,
+            pid,
+            cpu_time,
+            sampled: Instant::now(),
+            over: 0,
+            warned: false,
+        }
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Takes a sample, None if the process is gone. The flag is true only for the sample where
+    /// the usage has stayed abnormal long enough to warn, again after it went back to normal.
+    pub fn sample(&mut self) -> Option<(Usage, bool)> {
+        let (cpu_time, memory) = wintool::process::process_usage(self.pid)?;
+        let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+        let elapsed = self.sampled.elapsed().as_secs_f32() * cores as f32;
+        let spent = cpu_time.saturating_sub(self.cpu_time).as_secs_f32();
+        let cpu = if elapsed > 0.0 {
+            (spent * 100.0 / elapsed).min(100.0)
+        } else {
+            0.0
+        };
+        self.cpu_time = cpu_time;
+        self.sampled = Instant::now();
+        let memory_mb = memory as u64 / 1024 / 1024;
+        if cpu > CPU_LIMIT || memory_mb > MEMORY_LIMIT_MB {
+            self.over += 1;
+        } else {
+            self.over = 0;
+            self.warned = false;
+        }
+        let abnormal = self.over >= ABNORMAL_SAMPLES;
+        let warn = abnormal && !self.warned;
+        self.warned |= abnormal;
+        let usage = Usage {
+            name: self.name,
+            pid: self.pid,
+            cpu,
+            memory_mb,
+            abnormal,
+        };
+        Some((usage, warn))
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tauri::api::http::{ClientBuilder, HttpRequestBuilder, ResponseType};
+
+use crate::{history, Config, Error, Result, PROFILE_DIR};
+
+/// deployed by the administrator, tells where the policy comes from and who signs it
+const PROVISIONING_PATH: &str = "config\\provisioning.json";
+/// the last verified policy, used until the policy server is reachable
+const POLICY_CACHE_PATH: &str = "config\\policy.json";
+
+#[derive(Deserialize)]
+struct Provisioning {
+    url: String,
+    /// ed25519 public key in hex
+    public_key: String,
+}
+
+/// Policy as served, `signature` is the ed25519 signature in hex of the `policy` json text.
+#[derive(Deserialize)]
+struct SignedPolicy {
+    policy: String,
+    signature: String,
+}
+
+/// Centrally managed settings of the client.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct Policy {
+    /// raised with every change, an older policy than the one applied is rejected
+    pub version: u64,
+    /// unix time in seconds after which the policy is no longer accepted
+    pub not_after: Option<i64>,
+    /// profiles written to the profile directory for `--connect`
+    pub profiles: HashMap<String, Value>,
+    /// config fields set whenever the policy changes
+    pub settings: Map<String, Value>,
+    /// config fields the user cannot change
+    pub locked: Vec<String>,
+    /// sha256 fingerprints of the accepted server certificates
+    pub pinned_certs: Vec<String>,
+}
+
+impl SignedPolicy {
+    fn verify(&self, public_key: &str) -> Result<Policy> {
+        let invalid = |err: String| Error::Custom(format!("invalid policy signature:{}", err));
+        let key = hex::decode(public_key).map_err(|err| invalid(err.to_string()))?;
+        let key =
+            ed25519_compact::PublicKey::from_slice(&key).map_err(|err| invalid(err.to_string()))?;
+        let signature = hex::decode(&self.signature).map_err(|err| invalid(err.to_string()))?;
+        let signature = ed25519_compact::Signature::from_slice(&signature)
+            .map_err(|err| invalid(err.to_string()))?;
+        key.verify(self.policy.as_bytes(), &signature)
+            .map_err(|err| invalid(err.to_string()))?;
+        Ok(serde_json::from_str(&self.policy)?)
+    }
+}
+
+/// The cached policy with a valid signature, expired or not.
+fn read_cache(public_key: &str) -> Option<Policy> {
+    let data = std::fs::read(POLICY_CACHE_PATH).ok()?;
+    let signed: SignedPolicy = serde_json::from_slice(&data).ok()?;
+    signed
+        .verify(public_key)
+        .map_err(|err| log::error!("cached policy rejected:{:?}", err))
+        .ok()
+}
+
+fn provisioning() -> Option<Provisioning> {
+    let data = std::fs::read_to_string(PROVISIONING_PATH).ok()?;
+    serde_json::from_str(&data)
+        .map_err(|err| log::error!("invalid {}:{:?}", PROVISIONING_PATH, err))
+        .ok()
+}
+
+/// The cached policy verified again, so locks hold before the policy is fetched.
+pub fn cached_policy() -> Option<Policy> {
+    let provisioning = provisioning()?;
+    let policy = read_cache(provisioning.public_key.as_str())?;
+    policy
+        .check_expiry()
+        .map_err(|err| log::error!("cached policy rejected:{:?}", err))
+        .ok()?;
+    Some(policy)
+}
+
+/// Fetches and verifies the policy, None if this client is not provisioned.
+pub async fn fetch_policy() -> Result<Option<Policy>> {
+    let Some(provisioning) = provisioning() else {
+        return Ok(None);
+    };
+    let client = ClientBuilder::new().build()?;
+    let request = HttpRequestBuilder::new("GET", provisioning.url.as_str())?
+        .response_type(ResponseType::Binary);
+    let data = client.send(request).await?.bytes().await?.data;
+    let signed: SignedPolicy = serde_json::from_slice(&data)?;
+    let policy = signed.verify(provisioning.public_key.as_str())?;
+    policy.check_expiry()?;
+    // a replayed older policy must not roll back the one applied
+    if let Some(applied) = read_cache(provisioning.public_key.as_str()) {
+        if policy.version < applied.version {
+            return Err(Error::Custom(format!(
+                "policy version {} is older than the applied {}",
+                policy.version, applied.version
+            )));
+        }
+    }
+    std::fs::write(POLICY_CACHE_PATH, data)?;
+    Ok(Some(policy))
+}
+
+impl Policy {
+    fn check_expiry(&self) -> Result<()> {
+        match self.not_after {
+            Some(not_after) if history::now() > not_after => Err(Error::Custom(format!(
+                "policy version {} expired at {}",
+                self.version, not_after
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn write_profiles(&self) -> Result<()> {
+        std::fs::create_dir_all(PROFILE_DIR)?;
+        for (name, profile) in &self.profiles {
+            if name.contains(['/', '\\', '.']) {
+                log::error!("invalid profile name in policy:{}", name);
+                continue;
+            }
+            let path = format!("{}\\{}.json", PROFILE_DIR, name);
+            std::fs::write(path, serde_json::to_vec(profile)?)?;
+        }
+        Ok(())
+    }
+
+    /// `config` with all the policy settings applied.
+    pub fn preseed(&self, config: &Config) -> Result<Config> {
+        self.merge(config, self.settings.iter())
+    }
+
+    /// `config` with the locked fields reset to the policy settings, or to `current`
+    /// for those the policy has no value.
+    pub fn enforce(&self, config: &Config, current: &Config) -> Result<Config> {
+        let current = serde_json::to_value(current)?;
+        let locked: Vec<_> = self
+            .locked
+            .iter()
+            .filter_map(|field| {
+                let value = self.settings.get(field).or_else(|| current.get(field))?;
+                Some((field, value))
+            })
+            .collect();
+        self.merge(config, locked.into_iter())
+    }
+
+    fn merge<'a>(
+        &self,
+        config: &Config,
+        fields: impl Iterator<Item = (&'a String, &'a Value)>,
+    ) -> Result<Config> {
+        let mut value = serde_json::to_value(config)?;
+        if let Value::Object(object) = &mut value {
+            for (field, field_value) in fields {
+                object.insert(field.clone(), field_value.clone());
+            }
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+}
@@ -0,0 +1,172 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{lookup_host, TcpSocket, TcpStream},
+    time::timeout,
+};
+
+/// longest time spent on each of the download and upload tests
+const TEST_DURATION: Duration = Duration::from_secs(10);
+const UPLOAD_SIZE: usize = 10 * 1024 * 1024;
+const LATENCY_PROBES: usize = 3;
+
+/// Plain http endpoints of the speed test, a big file to download and a page accepting POST.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SpeedTestConfig {
+    pub download_url: String,
+    pub upload_url: String,
+}
+
+impl Default for SpeedTestConfig {
+    fn default() -> Self {
+        SpeedTestConfig {
+            download_url: "http://speedtest.tele2.net/100MB.zip".into(),
+            upload_url: "http://speedtest.tele2.net/upload.php".into(),
+        }
+    }
+}
+
+/// Result of a speed test, speeds in KB/s like the title bar.
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct SpeedResult {
+    pub latency_ms: f32,
+    pub download: f32,
+    pub upload: f32,
+}
+
+struct Endpoint {
+    addr: SocketAddr,
+    host: String,
+    path: String,
+}
+
+async fn parse_url(url: &str) -> std::io::Result<Endpoint> {
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidInput, url.to_string());
+    let url = url.strip_prefix("http://").ok_or_else(invalid)?;
+    let (host, path) = url.split_at(url.find('/').unwrap_or(url.len()));
+    let path = if path.is_empty() { "/" } else { path };
+    let (name, port) = match host.rsplit_once(':') {
+        Some((name, port)) => (name, port.parse().map_err(|_| invalid())?),
+        None => (host, 80),
+    };
+    let addr = lookup_host((name, port))
+        .await?
+        .find(SocketAddr::is_ipv4)
+        .ok_or_else(invalid)?;
+    Ok(Endpoint {
+        addr,
+        host: host.to_string(),
+        path: path.to_string(),
+    })
+}
+
+/// Connects from the tunnel address, so the test goes through the tunnel whatever the routes are.
+async fn connect(local: IpAddr, endpoint: &Endpoint) -> std::io::Result<TcpStream> {
+    let socket = TcpSocket::new_v4()?;
+    socket.bind(SocketAddr::new(local, 0))?;
+    timeout(TEST_DURATION, socket.connect(endpoint.addr)).await?
+}
+
+/// Reads the response header, returns the part of the body read with it.
+async fn read_header(stream: &mut TcpStream) -> std::io::Result<usize> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let size = stream.read(&mut chunk).await?;
+        if size == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        buffer.extend_from_slice(&chunk[..size]);
+        if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            if !buffer.starts_with(b"HTTP/1.1 2") && !buffer.starts_with(b"HTTP/1.0 2") {
+                let line = String::from_utf8_lossy(&buffer[..end]);
+                let status = line.lines().next().unwrap_or_default().to_string();
+                return Err(std::io::Error::other(status));
+            }
+            return Ok(buffer.len() - end - 4);
+        }
+    }
+}
+
+/// Time to the first byte of a HEAD response, the tunnel answers the connect itself so only a
+/// round trip to the server tells the latency.
+async fn latency(local: IpAddr, endpoint: &Endpoint) -> std::io::Result<f32> {
+    let request = format!(
+        "HEAD {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        endpoint.path, endpoint.host
+    );
+    let mut best = Duration::MAX;
+    for _ in 0..LATENCY_PROBES {
+        let mut stream = connect(local, endpoint).await?;
+        let start = Instant::now();
+        stream.write_all(request.as_bytes()).await?;
+        let mut byte = [0u8; 1];
+        if timeout(TEST_DURATION, stream.read(&mut byte)).await?? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        best = best.min(start.elapsed());
+    }
+    Ok(best.as_secs_f32() * 1000.0)
+}
+
+async fn download(local: IpAddr, endpoint: &Endpoint) -> std::io::Result<f32> {
+    let mut stream = connect(local, endpoint).await?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        endpoint.path, endpoint.host
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let start = Instant::now();
+    let mut total = read_header(&mut stream).await?;
+    let mut buffer = vec![0u8; 65536];
+    while start.elapsed() < TEST_DURATION {
+        match timeout(TEST_DURATION - start.elapsed(), stream.read(&mut buffer)).await {
+            Ok(Ok(0)) | Err(_) => break,
+            Ok(Ok(size)) => total += size,
+            Ok(Err(err)) => return Err(err),
+        }
+    }
+    Ok(total as f32 / 1024.0 / start.elapsed().as_secs_f32())
+}
+
+async fn upload(local: IpAddr, endpoint: &Endpoint) -> std::io::Result<f32> {
+    let mut stream = connect(local, endpoint).await?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/octet-stream\r\n\
+        Content-Length: {}\r\nConnection: close\r\n\r\n",
+        endpoint.path, endpoint.host, UPLOAD_SIZE
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let start = Instant::now();
+    let buffer = vec![0u8; 65536];
+    let mut total = 0;
+    while total < UPLOAD_SIZE && start.elapsed() < TEST_DURATION {
+        let size = buffer.len().min(UPLOAD_SIZE - total);
+        stream.write_all(&buffer[..size]).await?;
+        total += size;
+    }
+    if total == UPLOAD_SIZE {
+        // the server answers after receiving the whole body, not just buffering it locally
+        timeout(TEST_DURATION, read_header(&mut stream)).await??;
+    }
+    Ok(total as f32 / 1024.0 / start.elapsed().as_secs_f32())
+}
+
+/// Measures latency, download and upload speed from the tunnel address `local`.
+pub async fn run(local: IpAddr, config: SpeedTestConfig) -> std::io::Result<SpeedResult> {
+    let endpoint = parse_url(config.download_url.as_str()).await?;
+    let latency_ms = latency(local, &endpoint).await?;
+    let download = download(local, &endpoint).await?;
+    let endpoint = parse_url(config.upload_url.as_str()).await?;
+    let upload = upload(local, &endpoint).await?;
+    Ok(SpeedResult {
+        latency_ms,
+        download,
+        upload,
+    })
+}
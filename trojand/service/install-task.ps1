@@ -0,0 +1,10 @@
+# Registers trojand as a startup task running as SYSTEM, run from an elevated PowerShell
+# in the directory holding trojand.exe, trojan.exe, wintun.dll and trojand.json.
+param([string]$Dir = $PSScriptRoot)
+
+$action = New-ScheduledTaskAction -Execute "$Dir\trojand.exe" -Argument "-c `"$Dir\trojand.json`"" -WorkingDirectory $Dir
+$trigger = New-ScheduledTaskTrigger -AtStartup
+$principal = New-ScheduledTaskPrincipal -UserId "SYSTEM" -RunLevel Highest
+$settings = New-ScheduledTaskSettingsSet -RestartCount 3 -RestartInterval (New-TimeSpan -Minutes 1) -ExecutionTimeLimit 0
+Register-ScheduledTask -TaskName "trojand" -Action $action -Trigger $trigger -Principal $principal -Settings $settings -Force
+Start-ScheduledTask -TaskName "trojand"
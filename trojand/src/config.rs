@@ -0,0 +1,312 @@
+use std::{fs::File, net::SocketAddr, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::types::{Error, Result};
+
+/// Client mode the main sidecar runs in, same as the trojan subcommands.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Awintun,
+    Wintun,
+    Aproxy,
+}
+
+impl Default for Mode {
+    fn default() -> Self {
+        if cfg!(windows) {
+            Mode::Awintun
+        } else {
+            Mode::Aproxy
+        }
+    }
+}
+
+impl Mode {
+    pub fn command(&self) -> &'static str {
+        match self {
+            Mode::Awintun => "awintun",
+            Mode::Wintun => "wintun",
+            Mode::Aproxy => "aproxy",
+        }
+    }
+
+    fn is_tun(&self) -> bool {
+        matches!(self, Mode::Awintun | Mode::Wintun)
+    }
+}
+
+/// Safe DNS sidecar, only used with the tun modes like the GUI does.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DnsConfig {
+    #[serde(default = "default_dns_listen")]
+    pub dns_listen: String,
+    #[serde(default = "default_trust_dns")]
+    pub trust_dns: String,
+    #[serde(default = "default_poisoned_dns")]
+    pub poisoned_dns: String,
+    #[serde(default = "default_domain_list")]
+    pub domain_list: String,
+    #[serde(default = "default_hosts")]
+    pub hosts: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Config {
+    /// trojan binary run as sidecar
+    #[serde(default = "default_trojan")]
+    pub trojan: String,
+    pub server_domain: String,
+    pub server_auth: String,
+    #[serde(default = "default_server_port")]
+    pub server_port: u16,
+    #[serde(default)]
+    pub mode: Mode,
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+    /// directory of the sidecar logs and status files
+    #[serde(default = "default_log_dir")]
+    pub log_dir: String,
+    #[serde(default = "default_local_addr")]
+    pub local_addr: String,
+    #[serde(default)]
+    pub pool_size: u32,
+    #[serde(default = "default_iface_name")]
+    pub iface_name: String,
+    #[serde(default = "default_wintun_dll")]
+    pub wintun_dll: String,
+    /// CIDR list routed through the tunnel, all traffic if not set
+    #[serde(default)]
+    pub route_ipset: Option<String>,
+    #[serde(default)]
+    pub inverse_route: bool,
+    #[serde(default)]
+    pub dns_server_addr: Option<String>,
+    #[serde(default)]
+    pub dns: Option<DnsConfig>,
+    #[serde(default)]
+    pub pinned_certs: Vec<String>,
+    #[serde(default)]
+    pub rules_public_key: Option<String>,
+    #[serde(default)]
+    pub events_addr: Option<String>,
+    /// secret of the event stream, required with `events_addr`
+    #[serde(default)]
+    pub events_token: Option<String>,
+    /// arguments appended to the mode subcommand, for options without a config field
+    #[serde(default)]
+    pub extra_args: Vec<String>,
+    /// control socket, a loopback address like 127.0.0.1:60082 or a unix socket path
+    #[serde(default = "default_control")]
+    pub control: String,
+    /// secret of a control socket address, required unless `control` is a unix socket path
+    #[serde(default)]
+    pub control_token: Option<String>,
+    /// seconds to wait before restarting sidecars which exit unexpectedly
+    #[serde(default = "default_restart_delay")]
+    pub restart_delay: u64,
+}
+
+fn default_trojan() -> String {
+    let name = if cfg!(windows) {
+        "trojan.exe"
+    } else {
+        "trojan"
+    };
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.join(name)))
+        .and_then(|path| path.to_str().map(ToString::to_string))
+        .unwrap_or_else(|| name.into())
+}
+
+fn default_server_port() -> u16 {
+    443
+}
+
+fn default_log_level() -> String {
+    "Info".into()
+}
+
+fn default_log_dir() -> String {
+    "logs".into()
+}
+
+fn default_local_addr() -> String {
+    "127.0.0.1:60080".into()
+}
+
+fn default_iface_name() -> String {
+    "trojan".into()
+}
+
+fn default_wintun_dll() -> String {
+    "wintun.dll".into()
+}
+
+fn default_dns_listen() -> String {
+    "127.0.0.1".into()
+}
+
+fn default_trust_dns() -> String {
+    "8.8.8.8".into()
+}
+
+fn default_poisoned_dns() -> String {
+    "114.114.114.114".into()
+}
+
+fn default_domain_list() -> String {
+    "config/domain.txt".into()
+}
+
+fn default_hosts() -> String {
+    "config/hosts.txt".into()
+}
+
+fn default_control() -> String {
+    "127.0.0.1:60082".into()
+}
+
+fn default_restart_delay() -> u64 {
+    5
+}
+
+pub fn load_config(path: &str) -> Result<Config> {
+    let config: Config = serde_json::from_reader(File::open(path)?)?;
+    if config.events_addr.is_some() && config.events_token.is_none() {
+        return Err(Error::Custom("events_addr requires events_token".into()));
+    }
+    if config.control.parse::<SocketAddr>().is_ok()
+        && config.control_token.as_ref().is_none_or(String::is_empty)
+    {
+        return Err(Error::Custom(
+            "control address requires control_token".into(),
+        ));
+    }
+    Ok(config)
+}
+
+impl Config {
+    pub fn log_level_str(&self) -> &'static str {
+        match self.log_level.as_str() {
+            "Trace" => "0",
+            "Debug" => "1",
+            "Info" => "2",
+            "Warn" => "3",
+            "Error" => "4",
+            _ => "5",
+        }
+    }
+
+    fn log_file(&self, name: &str) -> String {
+        Path::new(self.log_dir.as_str())
+            .join(name)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn global_args(&self, name: &str) -> Vec<String> {
+        let mut args = vec![
+            "-l".into(),
+            self.log_file(format!("{}.log", name).as_str()),
+            "-L".into(),
+            self.log_level_str().into(),
+            "-a".into(),
+            self.local_addr.clone(),
+        ];
+        for pin in &self.pinned_certs {
+            args.push("--pin-cert".into());
+            args.push(pin.clone());
+        }
+        args
+    }
+
+    /// Environment of the sidecars, for the secrets kept off their command line.
+    pub fn sidecar_env(&self) -> Vec<(&'static str, String)> {
+        let mut env = vec![("TROJAN_PASSWORD", self.server_auth.clone())];
+        if let Some(token) = &self.events_token {
+            env.push(("TROJAN_EVENTS_TOKEN", token.clone()));
+        }
+        env
+    }
+
+    /// Arguments of the sidecars to run, the same ones the GUI passes.
+    pub fn sidecars(&self) -> Vec<(&'static str, Vec<String>)> {
+        let name = if self.mode.is_tun() {
+            "wintun"
+        } else {
+            "proxy"
+        };
+        let mut args = self.global_args(name);
+        if let Some(addr) = &self.events_addr {
+            args.push("--events-addr".into());
+            args.push(addr.clone());
+        }
+        args.extend([
+            self.mode.command().into(),
+            "-H".into(),
+            self.server_domain.clone(),
+            "-o".into(),
+            self.server_port.to_string(),
+            "-P".into(),
+            self.pool_size.to_string(),
+        ]);
+        if self.mode.is_tun() {
+            args.extend([
+                "-n".into(),
+                self.iface_name.clone(),
+                "-s".into(),
+                self.log_file("wintun.status"),
+                "-w".into(),
+                self.wintun_dll.clone(),
+            ]);
+            if let Some(addr) = &self.dns_server_addr {
+                args.push("--dns-server-addr".into());
+                args.push(addr.clone());
+            }
+            if let Some(ipset) = &self.route_ipset {
+                args.push("--route-ipset".into());
+                args.push(ipset.clone());
+                if self.inverse_route {
+                    args.push("--inverse-route".into());
+                }
+            }
+            if let Some(key) = &self.rules_public_key {
+                args.push("--rules-public-key".into());
+                args.push(key.clone());
+                if let Some(dns) = &self.dns {
+                    args.push("--pushed-domain-list".into());
+                    args.push(dns.domain_list.clone());
+                }
+            }
+        }
+        args.extend(self.extra_args.iter().cloned());
+        let mut sidecars = vec![(name, args)];
+
+        if let (true, Some(dns)) = (self.mode.is_tun(), &self.dns) {
+            let mut args = self.global_args("dns");
+            args.extend([
+                "dns".into(),
+                "-n".into(),
+                self.iface_name.clone(),
+                "--blocked-domain-list".into(),
+                dns.domain_list.clone(),
+                "--poisoned-dns".into(),
+                dns.poisoned_dns.clone(),
+                "--trusted-dns".into(),
+                dns.trust_dns.clone(),
+                "--dns-listen-address".into(),
+                dns.dns_listen.clone() + ":53",
+                "--hosts".into(),
+                dns.hosts.clone(),
+            ]);
+            if self.route_ipset.is_none() {
+                args.push("--add-route".into());
+            }
+            sidecars.push(("dns", args));
+        }
+        sidecars
+    }
+}
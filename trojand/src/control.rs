@@ -0,0 +1,157 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    spawn,
+    sync::{mpsc::UnboundedSender, oneshot},
+};
+
+use crate::{
+    supervisor::Request,
+    types::{Error, Result},
+    Command,
+};
+
+/// Compares a secret in constant time, the time taken only depends on the length.
+fn secret_eq(given: &str, expected: &str) -> bool {
+    !expected.is_empty()
+        && given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Handles a control connection, the token line if `token` is set, then one command line in and
+/// the daemon status as json out.
+async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: S,
+    token: Option<&str>,
+    sender: UnboundedSender<Request>,
+) -> Result<()> {
+    let mut conn = BufReader::new(conn);
+    let mut line = String::new();
+    if let Some(token) = token {
+        conn.read_line(&mut line).await?;
+        if !secret_eq(line.trim_end_matches(['\r', '\n']), token) {
+            log::error!("control connection with invalid token");
+            conn.write_all(b"{\"error\":\"invalid token\"}\n").await?;
+            return Ok(());
+        }
+        line.clear();
+    }
+    conn.read_line(&mut line).await?;
+    let Some(command) = Command::parse(line.trim()) else {
+        conn.write_all(b"{\"error\":\"unknown command\"}\n").await?;
+        return Ok(());
+    };
+    let (reply, receiver) = oneshot::channel();
+    if sender.send(Request { command, reply }).is_err() {
+        return Err(Error::Custom("supervisor exited".into()));
+    }
+    let status = receiver
+        .await
+        .map_err(|_| Error::Custom("supervisor exited".into()))?;
+    let mut response = serde_json::to_vec(&status)?;
+    response.push(b'\n');
+    conn.write_all(response.as_slice()).await?;
+    Ok(())
+}
+
+/// Listens on the control socket, a loopback address whose clients must send `token` or a unix
+/// socket path.
+pub async fn start(
+    control: &str,
+    token: Option<String>,
+    sender: UnboundedSender<Request>,
+) -> Result<()> {
+    if let Ok(addr) = control.parse::<SocketAddr>() {
+        if !addr.ip().is_loopback() {
+            log::warn!("control socket {} is reachable from the network", addr);
+        }
+        // checked by load_config
+        let token: Arc<str> = token.unwrap_or_default().into();
+        let listener = TcpListener::bind(addr).await?;
+        spawn(async move {
+            while let Ok((conn, _)) = listener.accept().await {
+                let sender = sender.clone();
+                let token = token.clone();
+                spawn(async move {
+                    if let Err(err) = serve(conn, Some(&token), sender).await {
+                        log::error!("control connection failed:{}", err);
+                    }
+                });
+            }
+        });
+        return Ok(());
+    }
+    start_unix(control, sender)
+}
+
+#[cfg(unix)]
+fn start_unix(path: &str, sender: UnboundedSender<Request>) -> Result<()> {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    let _ = fs::remove_file(path);
+    let listener = tokio::net::UnixListener::bind(path)?;
+    // only root and the service group may control the daemon
+    fs::set_permissions(path, fs::Permissions::from_mode(0o660))?;
+    spawn(async move {
+        while let Ok((conn, _)) = listener.accept().await {
+            let sender = sender.clone();
+            spawn(async move {
+                if let Err(err) = serve(conn, None, sender).await {
+                    log::error!("control connection failed:{}", err);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn start_unix(path: &str, _sender: UnboundedSender<Request>) -> Result<()> {
+    Err(Error::Custom(format!(
+        "control {} is not a socket address, unix sockets are not supported here",
+        path
+    )))
+}
+
+async fn request<S: AsyncRead + AsyncWrite + Unpin>(
+    mut conn: S,
+    token: Option<&str>,
+    command: Command,
+) -> Result<String> {
+    if let Some(token) = token {
+        conn.write_all(format!("{}\n", token).as_bytes()).await?;
+    }
+    conn.write_all(format!("{}\n", command.as_str()).as_bytes())
+        .await?;
+    let mut response = String::new();
+    conn.read_to_string(&mut response).await?;
+    Ok(response)
+}
+
+/// Sends a command to the running daemon and returns its response, with `token` if the control
+/// socket is an address.
+pub async fn send(control: &str, token: Option<&str>, command: Command) -> Result<String> {
+    if let Ok(addr) = control.parse::<SocketAddr>() {
+        return request(TcpStream::connect(addr).await?, token, command).await;
+    }
+    #[cfg(unix)]
+    {
+        request(
+            tokio::net::UnixStream::connect(control).await?,
+            None,
+            command,
+        )
+        .await
+    }
+    #[cfg(not(unix))]
+    Err(Error::Custom(format!(
+        "invalid control address {}",
+        control
+    )))
+}
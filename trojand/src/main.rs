@@ -0,0 +1,126 @@
+//! Headless trojan client daemon, running the same sidecars as the GUI client without Tauri.
+use clap::{Parser, Subcommand};
+use tokio::{runtime::Runtime, sync::mpsc::unbounded_channel};
+
+use crate::{config::load_config, supervisor::Supervisor, types::Result};
+
+mod config;
+mod control;
+mod supervisor;
+mod types;
+
+#[derive(Parser)]
+#[clap(version, about = "headless trojan client daemon")]
+struct Opts {
+    /// Config file path
+    #[clap(short, long, default_value = "trojand.json")]
+    config: String,
+
+    /// Log level, Trace, Debug, Info, Warn or Error, overrides the one in config
+    #[clap(short = 'L', long)]
+    log_level: Option<String>,
+
+    /// Command sent to the running daemon, runs the daemon if not given
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Clone, Copy, Debug)]
+pub enum Command {
+    /// Print the daemon status as json
+    Status,
+    /// Start the sidecars
+    Start,
+    /// Stop the sidecars, they stay stopped until start
+    Stop,
+    /// Restart the sidecars
+    Restart,
+    /// Reload the config file and restart the sidecars
+    Reload,
+}
+
+impl Command {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Command::Status => "status",
+            Command::Start => "start",
+            Command::Stop => "stop",
+            Command::Restart => "restart",
+            Command::Reload => "reload",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Command> {
+        [
+            Command::Status,
+            Command::Start,
+            Command::Stop,
+            Command::Restart,
+            Command::Reload,
+        ]
+        .into_iter()
+        .find(|command| command.as_str() == name)
+    }
+}
+
+fn setup_logger(level: &str) -> Result<()> {
+    let level = match level {
+        "Trace" => log::LevelFilter::Trace,
+        "Debug" => log::LevelFilter::Debug,
+        "Info" => log::LevelFilter::Info,
+        "Warn" => log::LevelFilter::Warn,
+        "Error" => log::LevelFilter::Error,
+        _ => log::LevelFilter::Off,
+    };
+    // stdout only, the service manager keeps the log
+    fern::Dispatch::new()
+        .format(|out, message, record| {
+            out.finish(format_args!(
+                "{}[{}][{}]{}",
+                chrono::Local::now().format("[%Y-%m-%d %H:%M:%S%.6f]"),
+                record.target(),
+                record.level(),
+                message
+            ))
+        })
+        .level(level)
+        .chain(std::io::stdout())
+        .apply()?;
+    Ok(())
+}
+
+fn run(opts: Opts) -> Result<()> {
+    let config = load_config(opts.config.as_str())?;
+    setup_logger(opts.log_level.as_deref().unwrap_or(&config.log_level))?;
+    let runtime = Runtime::new()?;
+    runtime.block_on(async move {
+        if let Some(command) = opts.command {
+            let response = control::send(
+                config.control.as_str(),
+                config.control_token.as_deref(),
+                command,
+            )
+            .await?;
+            print!("{}", response);
+            return Ok(());
+        }
+        let (sender, receiver) = unbounded_channel();
+        control::start(
+            config.control.as_str(),
+            config.control_token.clone(),
+            sender,
+        )
+        .await?;
+        log::warn!("trojand started, control socket {}", config.control);
+        Supervisor::new(opts.config, config).run(receiver).await;
+        log::warn!("trojand exits");
+        Ok(())
+    })
+}
+
+fn main() {
+    if let Err(err) = run(Opts::parse()) {
+        eprintln!("trojand failed:{}", err);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,289 @@
+use std::{
+    fs, io,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tokio::{
+    process::{Child, Command as Process},
+    sync::{mpsc::UnboundedReceiver, oneshot},
+    time,
+};
+
+use crate::{
+    config::{load_config, Config},
+    Command,
+};
+
+/// A control command and where to send the resulting status.
+pub struct Request {
+    pub command: Command,
+    pub reply: oneshot::Sender<Status>,
+}
+
+#[derive(Serialize, Default, Clone, Debug)]
+pub struct Status {
+    pub running: bool,
+    /// sidecar names and process ids
+    pub sidecars: Vec<(String, u32)>,
+    /// unix time the sidecars were started
+    pub since: Option<u64>,
+    pub restarts: u32,
+    pub last_exit: Option<String>,
+    pub error: Option<String>,
+}
+
+/// How long the sidecars have to clean up, like removing their routes, before being killed.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Sidecar {
+    name: &'static str,
+    child: Child,
+}
+
+pub struct Supervisor {
+    config_path: String,
+    config: Config,
+    sidecars: Vec<Sidecar>,
+    /// whether the sidecars should be running, cleared by stop
+    wanted: bool,
+    retry_at: Option<Instant>,
+    status: Status,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+impl Supervisor {
+    pub fn new(config_path: String, config: Config) -> Self {
+        Self {
+            config_path,
+            config,
+            sidecars: Vec::new(),
+            wanted: true,
+            retry_at: Some(Instant::now()),
+            status: Status::default(),
+        }
+    }
+
+    async fn start(&mut self) {
+        if !self.sidecars.is_empty() {
+            return;
+        }
+        if let Err(err) = fs::create_dir_all(self.config.log_dir.as_str()) {
+            log::error!("create log dir {} failed:{}", self.config.log_dir, err);
+        }
+        for (name, args) in self.config.sidecars() {
+            log::info!("start {}:{:?}", name, args);
+            match Process::new(self.config.trojan.as_str())
+                .args(args)
+                .envs(self.config.sidecar_env())
+                .kill_on_drop(true)
+                .spawn()
+            {
+                Ok(child) => {
+                    log::warn!("{} started with pid {:?}", name, child.id());
+                    self.sidecars.push(Sidecar { name, child });
+                }
+                Err(err) => {
+                    log::error!("start {} failed:{}", name, err);
+                    self.status.error = Some(format!("start {} failed:{}", name, err));
+                    self.stop().await;
+                    self.retry_at =
+                        Some(Instant::now() + Duration::from_secs(self.config.restart_delay));
+                    return;
+                }
+            }
+        }
+        self.retry_at = None;
+        self.status.since = Some(now());
+        self.status.error = None;
+    }
+
+    /// Stops all the sidecars, one of them alone is of no use. They are asked to exit first and
+    /// killed if still running after [`STOP_TIMEOUT`].
+    async fn stop(&mut self) {
+        let dns = self.sidecars.iter().any(|sidecar| sidecar.name == "dns");
+        let mut sidecars: Vec<_> = self.sidecars.drain(..).collect();
+        for sidecar in &mut sidecars {
+            if let Err(err) = terminate(&mut sidecar.child) {
+                log::error!("terminate {} failed:{}", sidecar.name, err);
+            }
+        }
+        let deadline = time::Instant::now() + STOP_TIMEOUT;
+        for mut sidecar in sidecars {
+            match time::timeout_at(deadline, sidecar.child.wait()).await {
+                Ok(Ok(status)) => log::info!("{} exits with {}", sidecar.name, status),
+                Ok(Err(err)) => log::error!("wait {} failed:{}", sidecar.name, err),
+                Err(_) => {
+                    log::warn!(
+                        "{} still running after {:?}, kill it",
+                        sidecar.name,
+                        STOP_TIMEOUT
+                    );
+                    if let Err(err) = sidecar.child.kill().await {
+                        log::error!("kill {} failed:{}", sidecar.name, err);
+                    }
+                }
+            }
+        }
+        self.status.since = None;
+        if dns {
+            restore_dns();
+        }
+    }
+
+    async fn reload(&mut self) {
+        match load_config(self.config_path.as_str()) {
+            Ok(config) => {
+                self.config = config;
+                log::warn!("config {} reloaded", self.config_path);
+                if self.wanted {
+                    self.stop().await;
+                    self.start().await;
+                }
+            }
+            Err(err) => {
+                log::error!("reload config {} failed:{}", self.config_path, err);
+                self.status.error = Some(format!("reload config failed:{}", err));
+            }
+        }
+    }
+
+    async fn handle(&mut self, command: Command) {
+        match command {
+            Command::Status => {}
+            Command::Start => {
+                self.wanted = true;
+                self.start().await;
+            }
+            Command::Stop => {
+                self.wanted = false;
+                self.stop().await;
+            }
+            Command::Restart => {
+                self.wanted = true;
+                self.stop().await;
+                self.start().await;
+            }
+            Command::Reload => self.reload().await,
+        }
+    }
+
+    /// Checks for exited sidecars, restarting all of them after the configured delay.
+    async fn check(&mut self) {
+        let exited = self
+            .sidecars
+            .iter_mut()
+            .find_map(|sidecar| match sidecar.child.try_wait() {
+                Ok(Some(status)) => Some(format!("{} exits with {}", sidecar.name, status)),
+                Ok(None) => None,
+                Err(err) => Some(format!("{} wait failed:{}", sidecar.name, err)),
+            });
+        if let Some(exited) = exited {
+            log::error!(
+                "{}, restart in {} seconds",
+                exited,
+                self.config.restart_delay
+            );
+            self.stop().await;
+            self.status.last_exit = Some(exited);
+            self.status.restarts += 1;
+            self.retry_at = Some(Instant::now() + Duration::from_secs(self.config.restart_delay));
+        }
+        if self.wanted && self.retry_at.is_some_and(|at| at <= Instant::now()) {
+            self.start().await;
+        }
+    }
+
+    fn status(&mut self) -> Status {
+        let mut status = self.status.clone();
+        status.running = !self.sidecars.is_empty();
+        status.sidecars = self
+            .sidecars
+            .iter()
+            .map(|sidecar| {
+                (
+                    sidecar.name.to_string(),
+                    sidecar.child.id().unwrap_or_default(),
+                )
+            })
+            .collect();
+        status
+    }
+
+    pub async fn run(mut self, mut requests: UnboundedReceiver<Request>) {
+        let mut check = tokio::time::interval(Duration::from_millis(500));
+        let terminated = terminated();
+        tokio::pin!(terminated);
+        loop {
+            tokio::select! {
+                request = requests.recv() => {
+                    let Some(request) = request else {
+                        break;
+                    };
+                    self.handle(request.command).await;
+                    let _ = request.reply.send(self.status());
+                }
+                _ = check.tick() => self.check().await,
+                _ = &mut terminated => {
+                    log::warn!("terminate signal received, stop sidecars");
+                    break;
+                }
+            }
+        }
+        self.stop().await;
+    }
+}
+
+/// Asks `child` to exit with SIGTERM, giving it a chance to clean up.
+#[cfg(unix)]
+fn terminate(child: &mut Child) -> io::Result<()> {
+    // already reaped
+    let Some(pid) = child.id() else {
+        return Ok(());
+    };
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// The sidecars don't handle console events on Windows, the routes of the tunnel go away with
+/// its adapter.
+#[cfg(not(unix))]
+fn terminate(child: &mut Child) -> io::Result<()> {
+    child.start_kill()
+}
+
+#[cfg(unix)]
+async fn terminated() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut term) => {
+            tokio::select! {
+                _ = term.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+        }
+        Err(_) => {
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminated() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// The dns sidecar is killed without a chance to restore the system DNS server.
+fn restore_dns() {
+    #[cfg(windows)]
+    wintool::adapter::set_dns_server("".into());
+}
@@ -0,0 +1,25 @@
+use std::fmt::{Display, Formatter};
+
+use derive_more::From;
+
+#[derive(From, Debug)]
+pub enum Error {
+    StdIo(std::io::Error),
+    SerdeJson(serde_json::Error),
+    SetLogger(log::SetLoggerError),
+    #[from(ignore)]
+    Custom(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::StdIo(err) => write!(f, "io error:{}", err),
+            Error::SerdeJson(err) => write!(f, "json error:{}", err),
+            Error::SetLogger(err) => write!(f, "set logger failed:{}", err),
+            Error::Custom(err) => f.write_str(err),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -65,6 +65,13 @@ impl<'a> AdapterAddresses<'a> {
         }
     }
 
+    pub fn friendly_name(&self) -> String {
+        unsafe {
+            let str = U16CStr::from_ptr_str(self.info.FriendlyName);
+            str.to_string_lossy().to_string()
+        }
+    }
+
     pub fn gateway(&self) -> Vec<IpAddr> {
         unsafe {
             let mut gateway = Vec::new();
@@ -111,10 +118,32 @@ impl<'a> AdapterAddresses<'a> {
         }
     }
 
+    /// Unicast addresses with the prefix length of the network they are on.
+    pub fn subnets(&self) -> Vec<(IpAddr, u8)> {
+        unsafe {
+            let mut subnets = Vec::new();
+            let mut addr = self.info.FirstUnicastAddress;
+            while !addr.is_null() {
+                let unicast = addr.as_ref().unwrap();
+                if let Some(ip) = win_sockaddr_to_rust(unicast.Address.lpSockaddr) {
+                    subnets.push((ip, unicast.OnLinkPrefixLength));
+                }
+                addr = (*addr).Next;
+            }
+            subnets
+        }
+    }
+
     pub fn is_up(&self) -> bool {
         self.info.OperStatus == IfOperStatusUp
     }
 
+    /// Virtual adapters of VPNs like wintun and IPsec tunnels, not PPP or WWAN links.
+    pub fn is_tunnel(&self) -> bool {
+        self.info.IfType == ipifcons::IF_TYPE_TUNNEL
+            || self.info.IfType == ipifcons::IF_TYPE_PROP_VIRTUAL
+    }
+
     pub fn is_ethernet(&self) -> bool {
         self.info.IfType == ipifcons::IF_TYPE_ETHERNET_CSMACD
             || self.info.IfType == ipifcons::IF_TYPE_IEEE80211
@@ -250,6 +279,67 @@ pub fn get_main_adapter_gwif() -> Option<(String, u32)> {
     ret
 }
 
+/// Gateway and index of the adapter named `name`, matched against the friendly name
+/// or the description, whatever its type is.
+pub fn get_adapter_gwif(name: &str) -> Option<(String, u32)> {
+    let mut ret = None;
+    unsafe {
+        get_adapters_addresses(|adapter| {
+            if adapter.is_up()
+                && (adapter.friendly_name() == name || adapter.description().contains(name))
+            {
+                let ips = adapter.gateway();
+                for ip in ips {
+                    if ip.is_ipv4() {
+                        ret = Some((ip.to_string(), adapter.if_index()));
+                        return true;
+                    }
+                }
+            }
+            false
+        });
+    }
+    ret
+}
+
+pub fn get_main_adapter_subnets() -> Vec<(Ipv4Addr, u8)> {
+    let mut ret = Vec::new();
+    unsafe {
+        get_adapters_addresses(|adapter| {
+            if adapter.is_main_adapter_v4() {
+                for (ip, prefix) in adapter.subnets() {
+                    if let IpAddr::V4(ip) = ip {
+                        ret.push((ip, prefix));
+                    }
+                }
+                return true;
+            }
+            false
+        });
+    }
+    ret
+}
+
+/// Descriptions of other tunnel adapters that are up and hold an IPv4 gateway,
+/// typically another VPN competing for the default route. `exclude` is the friendly name
+/// of our own adapter.
+pub fn get_gateway_tunnel_adapters(exclude: &str) -> Vec<String> {
+    let mut ret = Vec::new();
+    unsafe {
+        get_adapters_addresses(|adapter| {
+            if adapter.is_up()
+                && adapter.is_tunnel()
+                && adapter.friendly_name() != exclude
+                && adapter.gateway().iter().any(|ip| ip.is_ipv4())
+            {
+                ret.push(adapter.description());
+            }
+            false
+        });
+    }
+    ret
+}
+
 /// # Safety
 pub unsafe fn get_adapters_addresses<F>(mut callback: F) -> bool
     where
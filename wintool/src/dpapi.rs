@@ -0,0 +1,77 @@
+use std::ptr;
+
+use winapi::{
+    shared::minwindef::FALSE,
+    um::{
+        dpapi::{CryptProtectData, CryptUnprotectData, CRYPTPROTECT_UI_FORBIDDEN},
+        winbase::LocalFree,
+        wincrypt::DATA_BLOB,
+    },
+};
+
+fn blob_to_vec(blob: &DATA_BLOB) -> Vec<u8> {
+    let data = unsafe { std::slice::from_raw_parts(blob.pbData, blob.cbData as usize) };
+    let data = data.to_vec();
+    unsafe {
+        LocalFree(blob.pbData as _);
+    }
+    data
+}
+
+/// Encrypts `data` with DPAPI, bound to the current user account.
+pub fn protect(data: &[u8]) -> Option<Vec<u8>> {
+    let mut input = DATA_BLOB {
+        cbData: data.len() as u32,
+        pbData: data.as_ptr() as *mut _,
+    };
+    let mut output = DATA_BLOB {
+        cbData: 0,
+        pbData: ptr::null_mut(),
+    };
+    let ret = unsafe {
+        CryptProtectData(
+            &mut input,
+            ptr::null(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            CRYPTPROTECT_UI_FORBIDDEN,
+            &mut output,
+        )
+    };
+    if ret == FALSE {
+        log::error!("CryptProtectData failed");
+        None
+    } else {
+        Some(blob_to_vec(&output))
+    }
+}
+
+/// Decrypts data produced by [`protect`] for the current user account.
+pub fn unprotect(data: &[u8]) -> Option<Vec<u8>> {
+    let mut input = DATA_BLOB {
+        cbData: data.len() as u32,
+        pbData: data.as_ptr() as *mut _,
+    };
+    let mut output = DATA_BLOB {
+        cbData: 0,
+        pbData: ptr::null_mut(),
+    };
+    let ret = unsafe {
+        CryptUnprotectData(
+            &mut input,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            CRYPTPROTECT_UI_FORBIDDEN,
+            &mut output,
+        )
+    };
+    if ret == FALSE {
+        log::error!("CryptUnprotectData failed");
+        None
+    } else {
+        Some(blob_to_vec(&output))
+    }
+}
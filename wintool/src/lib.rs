@@ -1 +1,6 @@
 pub mod adapter;
+pub mod dpapi;
+pub mod locale;
+pub mod minidump;
+pub mod process;
+pub mod theme;
@@ -0,0 +1,6 @@
+use winapi::um::winnls::GetUserDefaultUILanguage;
+
+/// Primary language id of the user interface, like `LANG_CHINESE`.
+pub fn ui_language() -> u16 {
+    unsafe { GetUserDefaultUILanguage() & 0x3ff }
+}
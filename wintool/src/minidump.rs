@@ -0,0 +1,141 @@
+use std::{
+    fs::File,
+    os::windows::io::AsRawHandle,
+    path::PathBuf,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use winapi::{
+    shared::{
+        minwindef::{BOOL, DWORD, FALSE, LPVOID},
+        ntdef::LONG,
+    },
+    um::{
+        errhandlingapi::SetUnhandledExceptionFilter,
+        processthreadsapi::{GetCurrentProcess, GetCurrentProcessId, GetCurrentThreadId},
+        winnt::{EXCEPTION_POINTERS, HANDLE},
+    },
+};
+
+/// let the default handler report the crash as well
+const EXCEPTION_CONTINUE_SEARCH: LONG = 0;
+/// `MiniDumpWithIndirectlyReferencedMemory | MiniDumpWithThreadInfo`, the stacks and what they
+/// point to, a few MB
+const DUMP_TYPE: DWORD = 0x40 | 0x1000;
+
+#[repr(C, packed(4))]
+#[allow(non_snake_case)]
+struct MINIDUMP_EXCEPTION_INFORMATION {
+    ThreadId: DWORD,
+    ExceptionPointers: *mut EXCEPTION_POINTERS,
+    ClientPointers: BOOL,
+}
+
+#[link(name = "dbghelp")]
+extern "system" {
+    fn MiniDumpWriteDump(
+        hProcess: HANDLE,
+        ProcessId: DWORD,
+        hFile: HANDLE,
+        DumpType: DWORD,
+        ExceptionParam: *const MINIDUMP_EXCEPTION_INFORMATION,
+        UserStreamParam: LPVOID,
+        CallbackParam: LPVOID,
+    ) -> BOOL;
+}
+
+struct Target {
+    dir: PathBuf,
+    prefix: String,
+    keep: usize,
+}
+
+static TARGET: OnceLock<Target> = OnceLock::new();
+
+/// Writes a minidump named `<prefix>-<unix time>-<pid>.dmp` into `dir` when the process dies of
+/// a native exception, like an access violation inside a dll, keeping the newest `keep` dumps
+/// of `prefix` there. Nothing is written with `keep` 0.
+pub fn install(dir: impl Into<PathBuf>, prefix: &str, keep: usize) {
+    if keep == 0 {
+        return;
+    }
+    let target = Target {
+        dir: dir.into(),
+        prefix: prefix.to_string(),
+        keep,
+    };
+    prune(&target);
+    if TARGET.set(target).is_ok() {
+        unsafe {
+            SetUnhandledExceptionFilter(Some(write_dump));
+        }
+    }
+}
+
+/// Removes the oldest dumps of `target` until `keep` are left.
+fn prune(target: &Target) {
+    let Ok(entries) = std::fs::read_dir(&target.dir) else {
+        return;
+    };
+    let prefix = format!("{}-", target.prefix);
+    let mut dumps: Vec<_> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension().is_some_and(|ext| ext == "dmp")
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with(prefix.as_str()))
+        })
+        .filter_map(|path| Some((path.metadata().ok()?.modified().ok()?, path)))
+        .collect();
+    if dumps.len() <= target.keep {
+        return;
+    }
+    dumps.sort();
+    for (_, path) in &dumps[..dumps.len() - target.keep] {
+        if let Err(err) = std::fs::remove_file(path) {
+            log::error!("remove minidump {} failed:{}", path.display(), err);
+        }
+    }
+}
+
+unsafe extern "system" fn write_dump(pointers: *mut EXCEPTION_POINTERS) -> LONG {
+    let Some(target) = TARGET.get() else {
+        return EXCEPTION_CONTINUE_SEARCH;
+    };
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    let pid = GetCurrentProcessId();
+    let path = target
+        .dir
+        .join(format!("{}-{}-{}.dmp", target.prefix, time, pid));
+    let _ = std::fs::create_dir_all(&target.dir);
+    let Ok(file) = File::create(&path) else {
+        return EXCEPTION_CONTINUE_SEARCH;
+    };
+    let exception = MINIDUMP_EXCEPTION_INFORMATION {
+        ThreadId: GetCurrentThreadId(),
+        ExceptionPointers: pointers,
+        ClientPointers: FALSE,
+    };
+    let written = MiniDumpWriteDump(
+        GetCurrentProcess(),
+        pid,
+        file.as_raw_handle() as HANDLE,
+        DUMP_TYPE,
+        &exception,
+        std::ptr::null_mut(),
+        std::ptr::null_mut(),
+    );
+    drop(file);
+    if written == FALSE {
+        let _ = std::fs::remove_file(&path);
+    } else {
+        log::error!("native crash, minidump written to {}", path.display());
+        prune(target);
+    }
+    EXCEPTION_CONTINUE_SEARCH
+}
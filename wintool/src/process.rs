@@ -0,0 +1,113 @@
+use std::{mem::size_of, ptr::null_mut, time::Duration};
+
+use winapi::{
+    shared::minwindef::{DWORD, FALSE, FILETIME, LPVOID},
+    um::{
+        handleapi::CloseHandle,
+        minwinbase::STILL_ACTIVE,
+        processthreadsapi::{
+            GetCurrentProcess, GetExitCodeProcess, GetProcessTimes, OpenProcess, OpenProcessToken,
+            TerminateProcess,
+        },
+        psapi::{K32GetProcessMemoryInfo, PROCESS_MEMORY_COUNTERS},
+        securitybaseapi::GetTokenInformation,
+        winbase::QueryFullProcessImageNameW,
+        winnt::{
+            TokenElevation, HANDLE, PROCESS_QUERY_LIMITED_INFORMATION, PROCESS_TERMINATE,
+            TOKEN_ELEVATION, TOKEN_QUERY,
+        },
+    },
+};
+
+struct Process(HANDLE);
+
+impl Process {
+    fn open(pid: u32, access: DWORD) -> Option<Process> {
+        let handle = unsafe { OpenProcess(access, FALSE, pid) };
+        if handle.is_null() {
+            None
+        } else {
+            Some(Process(handle))
+        }
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        unsafe {
+            CloseHandle(self.0);
+        }
+    }
+}
+
+/// Executable path of process `pid`, or None if it is not running.
+pub fn process_image(pid: u32) -> Option<String> {
+    let process = Process::open(pid, PROCESS_QUERY_LIMITED_INFORMATION)?;
+    let mut code: DWORD = 0;
+    if unsafe { GetExitCodeProcess(process.0, &mut code) } == FALSE || code != STILL_ACTIVE {
+        return None;
+    }
+    let mut buffer = [0u16; 1024];
+    let mut size = buffer.len() as DWORD;
+    if unsafe { QueryFullProcessImageNameW(process.0, 0, buffer.as_mut_ptr(), &mut size) } == FALSE
+    {
+        log::error!("QueryFullProcessImageNameW failed");
+        return None;
+    }
+    Some(String::from_utf16_lossy(&buffer[..size as usize]))
+}
+
+/// CPU time spent so far and working set in bytes of process `pid`, or None if it can't be
+/// queried.
+pub fn process_usage(pid: u32) -> Option<(Duration, usize)> {
+    let process = Process::open(pid, PROCESS_QUERY_LIMITED_INFORMATION)?;
+    let mut creation = FILETIME::default();
+    let mut exit = FILETIME::default();
+    let mut kernel = FILETIME::default();
+    let mut user = FILETIME::default();
+    if unsafe { GetProcessTimes(process.0, &mut creation, &mut exit, &mut kernel, &mut user) }
+        == FALSE
+    {
+        return None;
+    }
+    let mut counters: PROCESS_MEMORY_COUNTERS = unsafe { std::mem::zeroed() };
+    let size = size_of::<PROCESS_MEMORY_COUNTERS>() as DWORD;
+    if unsafe { K32GetProcessMemoryInfo(process.0, &mut counters, size) } == FALSE {
+        return None;
+    }
+    // both times are in 100 nanoseconds
+    let ticks = |time: FILETIME| ((time.dwHighDateTime as u64) << 32) | time.dwLowDateTime as u64;
+    let cpu = Duration::from_nanos((ticks(kernel) + ticks(user)) * 100);
+    Some((cpu, counters.WorkingSetSize))
+}
+
+/// Terminates process `pid`, returns false if it could not be opened or killed.
+pub fn kill_process(pid: u32) -> bool {
+    let Some(process) = Process::open(pid, PROCESS_TERMINATE) else {
+        return false;
+    };
+    unsafe { TerminateProcess(process.0, 1) != FALSE }
+}
+
+/// Whether the current process runs with administrator rights.
+pub fn is_elevated() -> bool {
+    let mut token: HANDLE = null_mut();
+    if unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) } == FALSE {
+        return false;
+    }
+    let mut elevation: TOKEN_ELEVATION = unsafe { std::mem::zeroed() };
+    let mut size: DWORD = 0;
+    let ret = unsafe {
+        GetTokenInformation(
+            token,
+            TokenElevation,
+            &mut elevation as *mut _ as LPVOID,
+            size_of::<TOKEN_ELEVATION>() as DWORD,
+            &mut size,
+        )
+    };
+    unsafe {
+        CloseHandle(token);
+    }
+    ret != FALSE && elevation.TokenIsElevated != 0
+}
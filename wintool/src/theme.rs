@@ -0,0 +1,10 @@
+/// Whether the taskbar uses the light theme, where dark tray icons are easier to see.
+pub fn light_taskbar() -> bool {
+    let hkcu = winreg::RegKey::predef(winreg::enums::HKEY_CURRENT_USER);
+    hkcu.open_subkey_with_flags(
+        "Software\\Microsoft\\Windows\\CurrentVersion\\Themes\\Personalize",
+        winreg::enums::KEY_READ,
+    )
+    .and_then(|key| key.get_value::<u32, _>("SystemUsesLightTheme"))
+    .map_or(false, |value| value != 0)
+}